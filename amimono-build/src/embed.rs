@@ -0,0 +1,78 @@
+use std::path::{Path, PathBuf};
+
+use amimono_schemas::EMBEDDED_CONFIG_MARKER;
+
+/// The name of the generated file in `OUT_DIR`.
+pub const EMBEDDED_CONFIG_FILE: &str = "amimono_config.json";
+
+/// A helper for `build.rs` scripts to embed the app's config in the binary,
+/// so that `ammn` can read it without running the app.
+///
+/// The config is produced by running the app with `--dump-config`, which a
+/// build script can't do, so `ammn` saves the output of the last full dump to
+/// `.amimono/config.json` and this embeds it if it's for the revision being
+/// built. Builds after a source change embed nothing until the next full
+/// dump, and `ammn` falls back to running the app.
+///
+/// ```no_run
+/// let mut digest = amimono_build::AppDigest::new();
+/// let rev = digest
+///     .add_glob("src/**/*.rs")
+///     .add_path("Cargo.toml")
+///     .compute();
+/// amimono_build::BuildInfo::new(&rev).emit();
+/// amimono_build::EmbeddedConfig::new(&rev, "../.amimono/config.json").emit();
+/// digest.rerun_if_changed();
+/// ```
+///
+/// The app picks it up with `amimono::include_config!()`:
+///
+/// ```ignore
+/// AppBuilder::from_build_info(BUILD_INFO)
+///     .with_embedded_config(amimono::include_config!())
+/// ```
+///
+/// The saved dump is watched with a `rerun-if-changed` directive, which
+/// stops cargo from rerunning the build script when the package's sources
+/// change, so the paths covering the revision must be watched too, with
+/// [`AppDigest::rerun_if_changed`][crate::AppDigest::rerun_if_changed].
+pub struct EmbeddedConfig {
+    revision: String,
+    path: PathBuf,
+}
+
+impl EmbeddedConfig {
+    /// Embed the dump saved at `path`, relative to the package's manifest
+    /// directory, if it's for `revision`.
+    pub fn new<P: Into<PathBuf>>(revision: &str, path: P) -> Self {
+        EmbeddedConfig {
+            revision: revision.to_owned(),
+            path: path.into(),
+        }
+    }
+
+    /// Write the generated file.
+    pub fn emit(&self) {
+        let out_dir = std::env::var_os("OUT_DIR").expect("OUT_DIR not set, call from build.rs");
+        let path = PathBuf::from(out_dir).join(EMBEDDED_CONFIG_FILE);
+        std::fs::write(&path, self.render())
+            .unwrap_or_else(|e| panic!("could not write {:?}: {}", path, e));
+        println!("cargo:rerun-if-changed={}", self.path.display());
+    }
+
+    fn render(&self) -> String {
+        match read_dump(&self.path, &self.revision) {
+            Some(dump) => format!("{}{}\0", EMBEDDED_CONFIG_MARKER, dump),
+            None => String::new(),
+        }
+    }
+}
+
+/// Read a saved dump, if it's for the given revision. Dumps are pretty
+/// printed JSON, and the revision is only checked textually, since the app
+/// checks it again when it parses the embedded config.
+fn read_dump(path: &Path, revision: &str) -> Option<String> {
+    let dump = std::fs::read_to_string(path).ok()?;
+    let field = format!("\"revision\": \"{}\"", revision);
+    (dump.contains(&field) && !dump.contains('\0')).then(|| dump.trim().to_owned())
+}
@@ -0,0 +1,136 @@
+use std::{
+    path::PathBuf,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A helper for `build.rs` scripts to embed information about the build in
+/// the app, for `amimono::runtime::build_info()`.
+///
+/// ```no_run
+/// let rev = amimono_build::AppDigest::new()
+///     .add_glob("src/**/*.rs")
+///     .add_path("Cargo.toml")
+///     .compute();
+/// amimono_build::BuildInfo::new(&rev).emit();
+/// ```
+///
+/// This writes a generated file to `OUT_DIR` that the app picks up with
+/// `amimono::include_build_info!()`, and also sets `APP_REVISION` for apps
+/// that only want the revision:
+///
+/// ```ignore
+/// const BUILD_INFO: amimono::runtime::BuildInfo = amimono::include_build_info!();
+///
+/// pub fn configure() -> AppConfig {
+///     AppBuilder::from_build_info(BUILD_INFO)
+///         // ...
+///         .build()
+/// }
+/// ```
+///
+/// The build timestamp honors `SOURCE_DATE_EPOCH`, for reproducible builds.
+pub struct BuildInfo {
+    revision: String,
+}
+
+/// The name of the generated file in `OUT_DIR`.
+pub const BUILD_INFO_FILE: &str = "amimono_build_info.rs";
+
+impl BuildInfo {
+    pub fn new(revision: &str) -> Self {
+        BuildInfo {
+            revision: revision.to_owned(),
+        }
+    }
+
+    /// Write the generated file and set `APP_REVISION`.
+    pub fn emit(&self) {
+        let out_dir = std::env::var_os("OUT_DIR").expect("OUT_DIR not set, call from build.rs");
+        let path = PathBuf::from(out_dir).join(BUILD_INFO_FILE);
+        std::fs::write(&path, self.render())
+            .unwrap_or_else(|e| panic!("could not write {:?}: {}", path, e));
+
+        // no rerun-if directives, since they would stop cargo from rerunning
+        // the build script when the package's sources change
+        println!("cargo:rustc-env=APP_REVISION={}", self.revision);
+    }
+
+    fn render(&self) -> String {
+        let opt = |s: Option<String>| match s {
+            Some(s) => format!("Some({:?})", s),
+            None => "None".to_owned(),
+        };
+        format!(
+            "::amimono::runtime::BuildInfo {{\n    \
+             revision: {:?},\n    \
+             git_sha: {},\n    \
+             built_at: {},\n    \
+             rustc_version: {},\n\
+             }}\n",
+            self.revision,
+            opt(git_sha()),
+            opt(Some(timestamp())),
+            opt(rustc_version()),
+        )
+    }
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let out = Command::new("git").args(args).output().ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    let s = String::from_utf8(out.stdout).ok()?;
+    Some(s.trim().to_owned())
+}
+
+/// The current commit, with `-dirty` appended if there are uncommitted
+/// changes.
+fn git_sha() -> Option<String> {
+    let sha = git(&["rev-parse", "HEAD"])?;
+    match git(&["status", "--porcelain"]) {
+        Some(status) if !status.is_empty() => Some(format!("{}-dirty", sha)),
+        _ => Some(sha),
+    }
+}
+
+fn rustc_version() -> Option<String> {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let out = Command::new(rustc).arg("--version").output().ok()?;
+    let s = String::from_utf8(out.stdout).ok()?;
+    Some(s.trim().to_owned())
+}
+
+/// The build time as an RFC 3339 timestamp in UTC.
+fn timestamp() -> String {
+    let secs = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(s) => s.trim().parse::<u64>().expect("invalid SOURCE_DATE_EPOCH"),
+        Err(_) => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    };
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
@@ -1,13 +1,41 @@
-use std::{hash::Hash, hash::Hasher, path::PathBuf};
+mod embed;
+mod info;
+
+use std::{collections::BTreeMap, hash::Hash, hash::Hasher, path::PathBuf};
+
+pub use embed::{EMBEDDED_CONFIG_FILE, EmbeddedConfig};
+pub use info::{BUILD_INFO_FILE, BuildInfo};
 
 /// A helper for `build.rs` scripts to compute an app revision.
+///
+/// Paths can also be associated with individual jobs, to compute a digest for
+/// each job covering the shared paths plus the job's own. `ammn deploy` uses
+/// these to skip rolling jobs whose sources haven't changed:
+///
+/// ```no_run
+/// let mut digest = amimono_build::AppDigest::new();
+/// digest
+///     .add_glob("src/common/**/*.rs")
+///     .add_job_glob("calc", "src/calc/**/*.rs")
+///     .add_job_glob("driver", "src/driver/**/*.rs");
+///
+/// println!("cargo:rustc-env=APP_REVISION={}", digest.compute());
+/// println!("cargo:rustc-env=APP_JOB_DIGESTS={}", digest.compute_jobs());
+/// ```
+///
+/// The job digests are then passed to
+/// `AppBuilder::with_job_digests(env!("APP_JOB_DIGESTS"))`.
 pub struct AppDigest {
     paths: Vec<PathBuf>,
+    jobs: BTreeMap<String, Vec<PathBuf>>,
 }
 
 impl AppDigest {
     pub fn new() -> Self {
-        AppDigest { paths: Vec::new() }
+        AppDigest {
+            paths: Vec::new(),
+            jobs: BTreeMap::new(),
+        }
     }
 
     pub fn add_path<S: Into<PathBuf>>(&mut self, path: S) -> &mut Self {
@@ -29,17 +57,68 @@ impl AppDigest {
         self
     }
 
+    /// Add a path that only affects the given job's digest. The path is still
+    /// included in the app revision.
+    pub fn add_job_path<S: Into<PathBuf>>(&mut self, job: &str, path: S) -> &mut Self {
+        self.jobs
+            .entry(job.to_owned())
+            .or_default()
+            .push(path.into());
+        self
+    }
+
+    /// Add the paths matching a glob pattern to the given job's digest.
+    pub fn add_job_glob<S: AsRef<str>>(&mut self, job: &str, pattern: S) -> &mut Self {
+        let paths = glob::glob(pattern.as_ref()).expect("failed to read glob pattern");
+        for path in paths {
+            self.add_job_path(job, path.expect("failed to read glob entry"));
+        }
+        self
+    }
+
     pub fn compute(&mut self) -> String {
-        self.paths.sort();
-
-        let mut hasher = fnv::FnvHasher::default();
-        for path in self.paths.iter() {
-            // TODO: include paths in the hash
-            std::fs::read(&path)
-                .unwrap_or_else(|e| panic!("could not read {:?}: {}", path, e))
-                .hash(&mut hasher);
+        let mut paths = self
+            .paths
+            .iter()
+            .chain(self.jobs.values().flatten())
+            .collect::<Vec<_>>();
+        digest(&mut paths)
+    }
+
+    /// Emit `rerun-if-changed` directives for every path in the digest. This
+    /// is only needed once something else in the build script emits one,
+    /// e.g. [`EmbeddedConfig`], since cargo then stops rerunning the script
+    /// whenever the package's sources change.
+    pub fn rerun_if_changed(&self) {
+        for path in self.paths.iter().chain(self.jobs.values().flatten()) {
+            println!("cargo:rerun-if-changed={}", path.display());
         }
+    }
 
-        format!("{:08x}", hasher.finish() & 0xffffffff)
+    /// Compute the digest of every job with job-specific paths, formatted as
+    /// `job=digest` pairs separated by commas.
+    pub fn compute_jobs(&mut self) -> String {
+        self.jobs
+            .iter()
+            .map(|(job, job_paths)| {
+                let mut paths = self.paths.iter().chain(job_paths.iter()).collect();
+                format!("{}={}", job, digest(&mut paths))
+            })
+            .collect::<Vec<_>>()
+            .join(",")
     }
 }
+
+fn digest(paths: &mut Vec<&PathBuf>) -> String {
+    paths.sort();
+
+    let mut hasher = fnv::FnvHasher::default();
+    for path in paths.iter() {
+        // TODO: include paths in the hash
+        std::fs::read(path)
+            .unwrap_or_else(|e| panic!("could not read {:?}: {}", path, e))
+            .hash(&mut hasher);
+    }
+
+    format!("{:08x}", hasher.finish() & 0xffffffff)
+}
@@ -0,0 +1,200 @@
+//! `ammn call`, for making a one-off RPC call to a deployed component.
+//!
+//! The request is posted to a replica of the job the component runs in, as
+//! the component's `Request` enum would be sent by a client. The JSON given
+//! with `--json` is the op's arguments as that enum serializes them: an
+//! array for ops with several arguments, e.g. `[1, 2]`, and the argument
+//! itself for ops with one. The op's return value is printed, or the error
+//! it failed with.
+//!
+//! Kubernetes targets are reached by port-forwarding to a running pod, and
+//! static targets by running curl on a host over SSH, so curl must be
+//! installed wherever the request is sent from. Calls carry no caller token,
+//! so components with an allowlist refuse them, as do jobs that require
+//! tokens or sealed payloads.
+
+use std::io;
+
+use serde::Serialize;
+
+use crate::{
+    output::{self, ErrorKind},
+    target::Target,
+};
+
+pub fn call(target: &Target, component: &str, verb: &str, json: Option<&str>) {
+    let Some(cf) = target.deployed_config() else {
+        crate::fatal!(
+            kind = ErrorKind::Config,
+            "could not find the app deployed to the target. has it been deployed?"
+        );
+    };
+    let Some((job, comp)) = cf
+        .jobs
+        .iter()
+        .find_map(|(name, job)| Some((name, job.components.get(component)?)))
+    else {
+        crate::fatal!(
+            kind = ErrorKind::Config,
+            "the deployed app has no component {}",
+            component
+        );
+    };
+
+    // apps too old to list their ops are sent the call as-is
+    let op = comp
+        .rpc_ops
+        .as_ref()
+        .map(|ops| ops.iter().find(|op| op.name == verb));
+    let args = match (op, json) {
+        (Some(None), _) => {
+            let ops = comp.rpc_ops.iter().flatten();
+            crate::fatal!(
+                kind = ErrorKind::Config,
+                "{} has no op {}; its ops are: {}",
+                component,
+                verb,
+                ops.map(|op| op.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        (_, Some(json)) => match serde_json::from_str::<serde_json::Value>(json) {
+            Ok(args) => args,
+            Err(e) => crate::fatal!(kind = ErrorKind::Config, "invalid --json: {}", e),
+        },
+        (Some(Some(op)), None) if op.args.is_empty() => serde_json::Value::Array(Vec::new()),
+        (Some(Some(op)), None) => crate::fatal!(
+            kind = ErrorKind::Config,
+            "{}.{} takes arguments ({}); pass them with --json",
+            component,
+            verb,
+            op.arg_names.join(", ")
+        ),
+        (None, None) => crate::fatal!(
+            kind = ErrorKind::Config,
+            "pass the arguments of {}.{} with --json",
+            component,
+            verb
+        ),
+    };
+    let request = serde_json::json!({ verb: args }).to_string();
+
+    log::info!("calling {}.{} in job {}...", component, verb, job);
+    let (status, body) = match target.call(job, component, &request) {
+        Ok(res) => res,
+        Err(e) => crate::fatal!(
+            kind = ErrorKind::of(&e),
+            "failed to call {}: {}",
+            component,
+            e
+        ),
+    };
+    let body = match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(body) => body,
+        Err(_) => crate::fatal!(
+            "{} responded with status {}: {}",
+            component,
+            status,
+            String::from_utf8_lossy(&body).trim()
+        ),
+    };
+
+    if status != 200 {
+        if !output::is_json() {
+            println!("{}", pretty(&body));
+        }
+        let kind = match status {
+            503 => ErrorKind::Unreachable,
+            504 => ErrorKind::Timeout,
+            _ => ErrorKind::Failed,
+        };
+        crate::fatal!(
+            kind = kind,
+            "{}.{} failed with status {}: {}",
+            component,
+            verb,
+            status,
+            body
+        );
+    }
+
+    // the response is the op's variant of the component's Response enum
+    let response = match body {
+        serde_json::Value::Object(mut obj) if obj.len() == 1 && obj.contains_key(verb) => {
+            obj.remove(verb).expect("checked above")
+        }
+        body => body,
+    };
+    if !output::is_json() {
+        println!("{}", pretty(&response));
+    }
+    output::result(
+        true,
+        &CallResult {
+            component,
+            verb,
+            job,
+            response: &response,
+        },
+    );
+}
+
+fn pretty(value: &serde_json::Value) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string())
+}
+
+#[derive(Serialize)]
+struct CallResult<'r> {
+    component: &'r str,
+    verb: &'r str,
+    job: &'r str,
+    response: &'r serde_json::Value,
+}
+
+/// The arguments for curl to post an RPC request to a URL, printing the
+/// response body followed by its status on a line of its own.
+pub(crate) fn curl_args(url: &str, request: &str) -> Vec<String> {
+    [
+        "-sS",
+        "-X",
+        "POST",
+        "-H",
+        "content-type: application/json",
+        "--data-binary",
+        request,
+        "-w",
+        "\\n%{http_code}",
+        url,
+    ]
+    .into_iter()
+    .map(|s| s.to_owned())
+    .collect()
+}
+
+/// Split the output of curl run with [`curl_args`] into the response's status
+/// and body.
+pub(crate) fn parse_curl(out: &[u8]) -> io::Result<(u16, Vec<u8>)> {
+    let at = out.iter().rposition(|b| *b == b'\n').unwrap_or(0);
+    let status = std::str::from_utf8(&out[at..])
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .filter(|s| *s != 0)
+        .ok_or_else(|| io::Error::other("no response from curl"))?;
+    Ok((status, out[..at].to_vec()))
+}
+
+/// An error for a failed curl command. Curl exits with 7 when it can't
+/// connect.
+pub(crate) fn curl_error(status: std::process::ExitStatus, stderr: &[u8]) -> io::Error {
+    let kind = match status.code() {
+        Some(7) => io::ErrorKind::ConnectionRefused,
+        Some(28) => io::ErrorKind::TimedOut,
+        _ => io::ErrorKind::Other,
+    };
+    let msg = String::from_utf8_lossy(stderr);
+    io::Error::new(
+        kind,
+        format!("curl exited with status {}: {}", status, msg.trim()),
+    )
+}
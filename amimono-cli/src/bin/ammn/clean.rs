@@ -0,0 +1,113 @@
+//! `ammn clean`, for pruning the local runtime's storage and the project's
+//! build artifacts.
+//!
+//! Components' storage is under `.amimono/storage`, one directory per
+//! component. Without `--component`, all of it is removed. Since removing
+//! storage out from under a running app would corrupt it, `clean` refuses to
+//! run while a local instance of the app is up, unless forced.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{
+    output::{self, ErrorKind},
+    project::Project,
+    target::ADMIN_PORT,
+};
+
+pub fn clean(proj: &Project, components: &[String], build: bool, dry_run: bool, force: bool) {
+    if !force && local_app_running() {
+        crate::fatal!(
+            kind = ErrorKind::Conflict,
+            "the app appears to be running locally. stop it first, or pass --force"
+        );
+    }
+
+    let root = Path::new(".amimono").join("storage");
+    let dirs = match components {
+        [] => match std::fs::read_dir(&root) {
+            Ok(entries) => entries.flatten().map(|e| e.path()).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => crate::fatal!("failed to list {}: {}", root.display(), e),
+        },
+        _ => components
+            .iter()
+            .map(|c| {
+                if c.is_empty() || c.contains('/') || c.contains("..") {
+                    crate::fatal!(kind = ErrorKind::Config, "invalid component name {:?}", c);
+                }
+                root.join(c)
+            })
+            .filter(|p| p.symlink_metadata().is_ok())
+            .collect::<Vec<PathBuf>>(),
+    };
+
+    let mut removed = Vec::new();
+    let mut freed_bytes = 0;
+    for dir in dirs {
+        let size = dir_size(&dir);
+        if dry_run {
+            log::info!("would remove {} ({} bytes)", dir.display(), size);
+        } else {
+            // doesn't follow symlinks, so nothing outside the storage is removed
+            let res = match dir.symlink_metadata() {
+                Ok(md) if md.is_dir() => std::fs::remove_dir_all(&dir),
+                _ => std::fs::remove_file(&dir),
+            };
+            if let Err(e) = res {
+                crate::fatal!("failed to remove {}: {}", dir.display(), e);
+            }
+            log::info!("removed {} ({} bytes)", dir.display(), size);
+        }
+        removed.push(dir.to_string_lossy().into_owned());
+        freed_bytes += size;
+    }
+
+    if build {
+        proj.clean(dry_run);
+    }
+
+    output::result(
+        true,
+        &CleanResult {
+            removed,
+            freed_bytes,
+            build,
+            dry_run,
+        },
+    );
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CleanResult {
+    removed: Vec<String>,
+    freed_bytes: u64,
+    build: bool,
+    dry_run: bool,
+}
+
+/// Whether something is serving the admin port on this machine, which a
+/// local instance of the app does.
+fn local_app_running() -> bool {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, ADMIN_PORT));
+    TcpStream::connect_timeout(&addr, Duration::from_millis(500)).is_ok()
+}
+
+/// The total size of the files under a path, not following symlinks.
+fn dir_size(path: &Path) -> u64 {
+    let Ok(md) = path.symlink_metadata() else {
+        return 0;
+    };
+    if !md.is_dir() {
+        return md.len();
+    }
+    std::fs::read_dir(path)
+        .map(|entries| entries.flatten().map(|e| dir_size(&e.path())).sum())
+        .unwrap_or(0)
+}
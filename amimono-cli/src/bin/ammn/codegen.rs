@@ -0,0 +1,567 @@
+//! `ammn codegen`, for generating typed clients in other languages.
+//!
+//! Clients are generated from the app's config dump, which lists every RPC
+//! component's ops with their args and return types. The clients send the
+//! same JSON as Rust callers to `/rpc/{label}`, i.e. `{"op": arg}` for ops
+//! with one arg and `{"op": [args...]}` otherwise, and read the result from
+//! `{"op": value}`.
+//!
+//! Types are only known by their Rust names, so standard types are mapped to
+//! their JSON equivalents and the app's own types are left untyped, with the
+//! Rust signature in each method's doc comment.
+
+use std::fmt::Write;
+
+use amimono_schemas::{DumpConfig, DumpRpcOp};
+use serde::Serialize;
+
+use crate::output::{self, ErrorKind};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Lang {
+    TypeScript,
+    Python,
+}
+
+impl Lang {
+    pub fn parse(s: &str) -> Option<Lang> {
+        match s {
+            "ts" | "typescript" => Some(Lang::TypeScript),
+            "python" | "py" => Some(Lang::Python),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Lang::TypeScript => "ts",
+            Lang::Python => "python",
+        }
+    }
+}
+
+/// An RPC component found in the config dump.
+struct Component<'c> {
+    label: &'c str,
+    ops: &'c [DumpRpcOp],
+}
+
+fn components<'c>(cf: &'c DumpConfig, only: &[String]) -> Vec<Component<'c>> {
+    let mut comps = cf
+        .jobs
+        .values()
+        .flat_map(|job| job.components.iter())
+        .filter_map(|(label, comp)| {
+            let ops = comp.rpc_ops.as_deref()?;
+            Some(Component { label, ops })
+        })
+        .filter(|c| only.is_empty() || only.iter().any(|l| l == c.label))
+        .collect::<Vec<_>>();
+    comps.sort_by_key(|c| c.label);
+    comps.dedup_by_key(|c| c.label);
+    comps
+}
+
+pub fn codegen(cf: &DumpConfig, lang: Lang, only: &[String], out: Option<&str>) {
+    let comps = components(cf, only);
+    for label in only {
+        if !comps.iter().any(|c| c.label == label) {
+            crate::fatal!(
+                kind = ErrorKind::Config,
+                "the app has no RPC component {}",
+                label
+            );
+        }
+    }
+    if comps.is_empty() {
+        crate::fatal!(kind = ErrorKind::Config, "the app has no RPC components");
+    }
+
+    let code = match lang {
+        Lang::TypeScript => typescript(&cf.revision, &comps),
+        Lang::Python => python(&cf.revision, &comps),
+    };
+
+    if let Some(path) = out {
+        if let Err(e) = std::fs::write(path, &code) {
+            crate::fatal!("failed to write {}: {}", path, e);
+        }
+        log::info!("wrote {} clients to {}", comps.len(), path);
+    } else if !output::is_json() {
+        print!("{}", code);
+    }
+
+    output::result(
+        true,
+        &CodegenResult {
+            lang: lang.name(),
+            components: comps.iter().map(|c| c.label).collect(),
+            out,
+            code: out.is_none().then_some(code.as_str()),
+        },
+    );
+}
+
+#[derive(Serialize)]
+struct CodegenResult<'r> {
+    lang: &'static str,
+    components: Vec<&'r str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    out: Option<&'r str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'r str>,
+}
+
+/// A Rust type, as far as it can be understood from its name.
+enum Type {
+    Bool,
+    Number { float: bool },
+    String,
+    Unit,
+    Option(Box<Type>),
+    List(Box<Type>),
+    Map(Box<Type>),
+    Tuple(Vec<Type>),
+    Unknown,
+}
+
+/// Split a type list at the commas that aren't nested in another type.
+fn split_top(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '<' | '(' | '[' => depth += 1,
+            '>' | ')' | ']' => depth -= 1,
+            c if c == sep && depth == 0 => {
+                parts.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(s[start..].trim());
+    parts.retain(|p| !p.is_empty());
+    parts
+}
+
+impl Type {
+    fn parse(ty: &str) -> Type {
+        let mut ty = ty.trim();
+        // references and lifetimes don't change what's sent
+        loop {
+            if let Some(rest) = ty.strip_prefix('&') {
+                ty = rest.trim_start();
+            } else if let Some(rest) = ty.strip_prefix('\'') {
+                ty = rest
+                    .trim_start_matches(|c: char| c.is_alphanumeric() || c == '_')
+                    .trim_start();
+            } else if let Some(rest) = ty.strip_prefix("mut ") {
+                ty = rest.trim_start();
+            } else {
+                break;
+            }
+        }
+
+        if let Some(inner) = ty.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+            let elems = split_top(inner, ',');
+            return match elems.len() {
+                0 => Type::Unit,
+                _ => Type::Tuple(elems.into_iter().map(Type::parse).collect()),
+            };
+        }
+        if let Some(inner) = ty.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            let elem = split_top(inner, ';').into_iter().next().unwrap_or_default();
+            return Type::List(Box::new(Type::parse(elem)));
+        }
+
+        let (path, args) = match ty.split_once('<') {
+            Some((path, rest)) => (path, split_top(rest.strip_suffix('>').unwrap_or(rest), ',')),
+            None => (ty, Vec::new()),
+        };
+        let name = path.rsplit("::").next().unwrap_or(path).trim();
+        // lifetimes among the args, e.g. in `Cow<'a, str>`
+        let args = args
+            .into_iter()
+            .filter(|a| !a.starts_with('\''))
+            .collect::<Vec<_>>();
+        let arg = |i: usize| Box::new(args.get(i).map_or(Type::Unknown, |a| Type::parse(a)));
+
+        match name {
+            "bool" => Type::Bool,
+            "u8" | "u16" | "u32" | "u64" | "u128" | "usize" | "i8" | "i16" | "i32" | "i64"
+            | "i128" | "isize" => Type::Number { float: false },
+            "f32" | "f64" => Type::Number { float: true },
+            "String" | "str" | "char" | "PathBuf" | "Path" => Type::String,
+            "Option" => Type::Option(arg(0)),
+            "Vec" | "VecDeque" | "HashSet" | "BTreeSet" => Type::List(arg(0)),
+            "HashMap" | "BTreeMap" => Type::Map(arg(1)),
+            "Box" | "Arc" | "Rc" => *arg(0),
+            "Cow" => *arg(args.len().saturating_sub(1)),
+            _ => Type::Unknown,
+        }
+    }
+
+    fn typescript(&self) -> String {
+        match self {
+            Type::Bool => "boolean".to_owned(),
+            Type::Number { .. } => "number".to_owned(),
+            Type::String => "string".to_owned(),
+            Type::Unit => "null".to_owned(),
+            Type::Option(t) => format!("{} | null", t.typescript()),
+            Type::List(t) => format!("Array<{}>", t.typescript()),
+            Type::Map(t) => format!("Record<string, {}>", t.typescript()),
+            Type::Tuple(ts) => format!(
+                "[{}]",
+                ts.iter()
+                    .map(Type::typescript)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Type::Unknown => "unknown".to_owned(),
+        }
+    }
+
+    fn python(&self) -> String {
+        match self {
+            Type::Bool => "bool".to_owned(),
+            Type::Number { float: false } => "int".to_owned(),
+            Type::Number { float: true } => "float".to_owned(),
+            Type::String => "str".to_owned(),
+            Type::Unit => "None".to_owned(),
+            Type::Option(t) => format!("Optional[{}]", t.python()),
+            Type::List(t) => format!("list[{}]", t.python()),
+            Type::Map(t) => format!("dict[str, {}]", t.python()),
+            Type::Tuple(ts) => format!(
+                "tuple[{}]",
+                ts.iter().map(Type::python).collect::<Vec<_>>().join(", ")
+            ),
+            Type::Unknown => "Any".to_owned(),
+        }
+    }
+}
+
+const TS_RESERVED: &[&str] = &[
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "else",
+    "enum",
+    "export",
+    "extends",
+    "false",
+    "finally",
+    "for",
+    "function",
+    "if",
+    "import",
+    "in",
+    "instanceof",
+    "let",
+    "new",
+    "null",
+    "return",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typeof",
+    "var",
+    "void",
+    "while",
+    "with",
+    "yield",
+];
+
+const PY_RESERVED: &[&str] = &[
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class", "continue",
+    "def", "del", "elif", "else", "except", "finally", "for", "from", "global", "if", "import",
+    "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while",
+    "with", "yield", "self",
+];
+
+/// An identifier from the app, with raw identifiers unwrapped and reserved
+/// words escaped.
+fn ident(name: &str, reserved: &[&str]) -> String {
+    let name = name.strip_prefix("r#").unwrap_or(name);
+    match reserved.contains(&name) {
+        true => format!("{}_", name),
+        false => name.to_owned(),
+    }
+}
+
+/// The names of an op's args, or `argN` for dumps that don't have them.
+fn arg_names(op: &DumpRpcOp, reserved: &[&str]) -> Vec<String> {
+    (0..op.args.len())
+        .map(|i| match op.arg_names.get(i) {
+            Some(n) => ident(n, reserved),
+            None => format!("arg{}", i),
+        })
+        .collect()
+}
+
+fn class_name(label: &str) -> String {
+    let mut name = String::new();
+    for word in label.split(|c: char| !c.is_alphanumeric()) {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            name.extend(first.to_uppercase());
+            name.push_str(chars.as_str());
+        }
+    }
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name + "Client"
+}
+
+fn camel_case(name: &str) -> String {
+    let mut out = String::new();
+    let mut upper = false;
+    for c in name.chars() {
+        match c {
+            '_' if !out.is_empty() => upper = true,
+            c if upper => {
+                out.extend(c.to_uppercase());
+                upper = false;
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The op's signature as written in Rust, for doc comments.
+fn signature(op: &DumpRpcOp) -> String {
+    let args = op
+        .args
+        .iter()
+        .enumerate()
+        .map(|(i, ty)| match op.arg_names.get(i) {
+            Some(n) => format!("{}: {}", n, ty),
+            None => ty.clone(),
+        })
+        .collect::<Vec<_>>();
+    format!("{}({}) -> {}", op.name, args.join(", "), op.ret)
+}
+
+const TS_PRELUDE: &str = r#"/** An error returned by a component, or by the server before reaching it. */
+export class RpcError extends Error {
+  constructor(
+    public readonly status: number,
+    public readonly error: unknown,
+  ) {
+    super(`rpc failed with status ${status}: ${JSON.stringify(error)}`);
+  }
+}
+
+export interface ClientOptions {
+  /** Extra headers to send with every request, e.g. a caller token. */
+  headers?: Record<string, string>;
+  /** The fetch implementation to use, if not the global one. */
+  fetch?: typeof fetch;
+}
+
+async function call(
+  baseUrl: string,
+  label: string,
+  op: string,
+  args: unknown[],
+  options: ClientOptions,
+): Promise<unknown> {
+  const body = { [op]: args.length === 1 ? args[0] : args };
+  const resp = await (options.fetch ?? fetch)(`${baseUrl}/rpc/${label}`, {
+    method: "POST",
+    headers: { "content-type": "application/json", ...options.headers },
+    body: JSON.stringify(body),
+  });
+  const text = await resp.text();
+  let data: unknown = text;
+  try {
+    data = JSON.parse(text);
+  } catch {
+    // errors from outside the app may not be JSON
+  }
+  if (!resp.ok) {
+    throw new RpcError(resp.status, data);
+  }
+  return (data as Record<string, unknown>)[op];
+}
+"#;
+
+fn typescript(revision: &str, comps: &[Component]) -> String {
+    let mut s = String::new();
+    writeln!(
+        s,
+        "// Generated by `ammn codegen` from revision {}.",
+        revision
+    )
+    .unwrap();
+    writeln!(
+        s,
+        "// Do not edit; run `ammn codegen --lang ts` again instead."
+    )
+    .unwrap();
+    writeln!(s).unwrap();
+    s.push_str(TS_PRELUDE);
+
+    for comp in comps {
+        writeln!(s).unwrap();
+        writeln!(s, "/** A client for the `{}` component. */", comp.label).unwrap();
+        writeln!(s, "export class {} {{", class_name(comp.label)).unwrap();
+        writeln!(s, "  static readonly LABEL = {:?};", comp.label).unwrap();
+        writeln!(s).unwrap();
+        writeln!(s, "  constructor(").unwrap();
+        writeln!(s, "    private readonly baseUrl: string,").unwrap();
+        writeln!(s, "    private readonly options: ClientOptions = {{}},").unwrap();
+        writeln!(s, "  ) {{}}").unwrap();
+
+        for op in comp.ops {
+            let names = arg_names(op, TS_RESERVED);
+            let params = names
+                .iter()
+                .zip(&op.args)
+                .map(|(n, ty)| format!("{}: {}", n, Type::parse(ty).typescript()))
+                .collect::<Vec<_>>();
+            let ret = Type::parse(&op.ret).typescript();
+            writeln!(s).unwrap();
+            writeln!(s, "  /** `{}` */", signature(op)).unwrap();
+            writeln!(
+                s,
+                "  async {}({}): Promise<{}> {{",
+                ident(&camel_case(&op.name), TS_RESERVED),
+                params.join(", "),
+                ret
+            )
+            .unwrap();
+            writeln!(
+                s,
+                "    const res = await call(this.baseUrl, {:?}, {:?}, [{}], this.options);",
+                comp.label,
+                op.name,
+                names.join(", ")
+            )
+            .unwrap();
+            writeln!(s, "    return res as {};", ret).unwrap();
+            writeln!(s, "  }}").unwrap();
+        }
+        writeln!(s, "}}").unwrap();
+    }
+    s
+}
+
+const PY_PRELUDE: &str = r#"from typing import Any, Optional
+
+import requests
+
+
+class RpcError(Exception):
+    """An error returned by a component, or by the server before reaching it."""
+
+    def __init__(self, status: int, error: Any):
+        super().__init__(f"rpc failed with status {status}: {error!r}")
+        self.status = status
+        self.error = error
+
+
+class _Client:
+    def __init__(
+        self,
+        base_url: str,
+        headers: Optional[dict[str, str]] = None,
+        timeout: Optional[float] = None,
+        session: Optional[requests.Session] = None,
+    ):
+        self.base_url = base_url.rstrip("/")
+        self.headers = headers or {}
+        self.timeout = timeout
+        self.session = session or requests.Session()
+
+    def _call(self, label: str, op: str, args: list) -> Any:
+        body = {op: args[0] if len(args) == 1 else args}
+        resp = self.session.post(
+            f"{self.base_url}/rpc/{label}",
+            json=body,
+            headers=self.headers,
+            timeout=self.timeout,
+        )
+        try:
+            data = resp.json()
+        except ValueError:
+            # errors from outside the app may not be JSON
+            data = resp.text
+        if not resp.ok:
+            raise RpcError(resp.status_code, data)
+        return data[op]
+"#;
+
+fn python(revision: &str, comps: &[Component]) -> String {
+    let mut s = String::new();
+    writeln!(
+        s,
+        "# Generated by `ammn codegen` from revision {}.",
+        revision
+    )
+    .unwrap();
+    writeln!(
+        s,
+        "# Do not edit; run `ammn codegen --lang python` again instead."
+    )
+    .unwrap();
+    writeln!(s).unwrap();
+    s.push_str(PY_PRELUDE);
+
+    for comp in comps {
+        writeln!(s).unwrap();
+        writeln!(s).unwrap();
+        writeln!(s, "class {}(_Client):", class_name(comp.label)).unwrap();
+        writeln!(
+            s,
+            "    \"\"\"A client for the `{}` component.\"\"\"",
+            comp.label
+        )
+        .unwrap();
+        writeln!(s).unwrap();
+        writeln!(s, "    LABEL = {:?}", comp.label).unwrap();
+
+        for op in comp.ops {
+            let names = arg_names(op, PY_RESERVED);
+            let mut params = vec!["self".to_owned()];
+            params.extend(
+                names
+                    .iter()
+                    .zip(&op.args)
+                    .map(|(n, ty)| format!("{}: {}", n, Type::parse(ty).python())),
+            );
+            writeln!(s).unwrap();
+            writeln!(
+                s,
+                "    def {}({}) -> {}:",
+                ident(&op.name, PY_RESERVED),
+                params.join(", "),
+                Type::parse(&op.ret).python()
+            )
+            .unwrap();
+            writeln!(s, "        \"\"\"`{}`\"\"\"", signature(op)).unwrap();
+            writeln!(
+                s,
+                "        return self._call({:?}, {:?}, [{}])",
+                comp.label,
+                op.name,
+                names.join(", ")
+            )
+            .unwrap();
+        }
+    }
+    s
+}
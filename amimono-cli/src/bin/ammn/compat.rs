@@ -0,0 +1,91 @@
+//! API compatibility checks between app revisions.
+//!
+//! During a rolling deploy, old and new revisions of a job run side by side
+//! and call each other, so a change to an RPC component's API can break calls
+//! in either direction. This compares the RPC ops recorded in two dumped app
+//! configs and reports the changes that would break callers.
+//!
+//! Types that implement `JsonSchema` are compared by their JSON Schemas, so
+//! that fields and variants that were added, removed or retyped are seen.
+//! Other types are compared by name, as written in the `rpc_component!`
+//! invocation. Callers of optional ops handle them being unimplemented, so
+//! adding or removing one is safe, as is adding an optional field. Adding a
+//! variant is only a warning, since it breaks just the calls that use it.
+
+use amimono_schemas::{DumpConfig, DumpRpcOp};
+
+/// The differences between two revisions' APIs.
+#[derive(Default)]
+pub struct Changes {
+    /// Changes that break existing callers.
+    pub breaking: Vec<String>,
+
+    /// Changes that are safe once the deploy is complete, but may cause
+    /// errors while old and new revisions are both running.
+    pub warnings: Vec<String>,
+}
+
+fn signature(op: &DumpRpcOp) -> String {
+    format!("{}({}) -> {}", op.name, op.args.join(", "), op.ret)
+}
+
+/// Compare the APIs of the deployed revision and a new revision.
+pub fn compare(old: &DumpConfig, new: &DumpConfig) -> Changes {
+    let mut changes = Changes::default();
+
+    for (job_label, old_job) in old.jobs.iter() {
+        for (comp_label, old_comp) in old_job.components.iter() {
+            let Some(old_ops) = &old_comp.rpc_ops else {
+                continue;
+            };
+            let new_ops = new
+                .jobs
+                .values()
+                .find_map(|j| j.components.get(comp_label))
+                .and_then(|c| c.rpc_ops.as_ref());
+            let Some(new_ops) = new_ops else {
+                changes.breaking.push(format!(
+                    "component {} (job {}) was removed",
+                    comp_label, job_label
+                ));
+                continue;
+            };
+
+            for old_op in old_ops.iter() {
+                match new_ops.iter().find(|op| op.name == old_op.name) {
+                    None if old_op.optional => changes.warnings.push(format!(
+                        "{}: optional op {} was removed, calls to it will fail as unimplemented",
+                        comp_label,
+                        signature(old_op)
+                    )),
+                    None => changes.breaking.push(format!(
+                        "{}: op {} was removed",
+                        comp_label,
+                        signature(old_op)
+                    )),
+                    Some(new_op) => {
+                        for change in old_op.type_changes(new_op) {
+                            let msg = format!("{}: op {}: {}", comp_label, old_op.name, change);
+                            match change.breaking {
+                                true => changes.breaking.push(msg),
+                                false => changes.warnings.push(msg),
+                            }
+                        }
+                    }
+                }
+            }
+
+            for new_op in new_ops.iter() {
+                if !new_op.optional && !old_ops.iter().any(|op| op.name == new_op.name) {
+                    changes.warnings.push(format!(
+                        "{}: op {} was added, calls to the old revision will fail until it is replaced",
+                        comp_label,
+                        signature(new_op)
+                    ));
+                }
+            }
+        }
+    }
+
+    changes
+}
@@ -1,36 +1,723 @@
-use std::collections::HashMap;
+//! `amimono.toml`, the project's targets and pipelines.
+//!
+//! The file is checked against a schema before it's deserialized, so that
+//! typos and missing fields are reported at the line they're on rather than
+//! at the table they're in, and `ammn config check` reports every problem
+//! with the file at once.
 
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Range,
+};
+
+use amimono_schemas::DumpRollout;
 use serde::{Deserialize, Serialize};
+use toml::{
+    Spanned,
+    de::{DeTable, DeValue},
+};
+
+use crate::output::{self, ErrorKind};
+
+const FILE: &str = "amimono.toml";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub project: ProjectConfig,
     pub target: HashMap<String, TargetConfig>,
+    /// Groups of targets that a revision is deployed to in order, for
+    /// `ammn deploy --pipeline`.
+    #[serde(default)]
+    pub pipeline: HashMap<String, PipelineConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case", tag = "format")]
+#[serde(rename_all = "snake_case", tag = "format", deny_unknown_fields)]
 pub enum ProjectConfig {
-    Cargo,
+    /// A Cargo package, or a package in a Cargo workspace, e.g.
+    ///
+    /// ```toml
+    /// [project]
+    /// format = "cargo"
+    /// package = "app"
+    /// bin = "app"
+    /// release = true
+    /// features = ["proto"]
+    /// ```
+    Cargo {
+        /// The package the app is in. Defaults to the one in the current
+        /// directory, or the workspace's only package with a binary.
+        package: Option<String>,
+        /// The package's binary that runs the app, if it has more than one
+        /// and no `default-run`.
+        bin: Option<String>,
+        /// Build with `--release`.
+        release: Option<bool>,
+        /// Features to enable when building.
+        features: Option<Vec<String>>,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case", tag = "driver")]
+#[serde(rename_all = "snake_case", tag = "driver", deny_unknown_fields)]
 pub enum TargetConfig {
     Kubernetes {
         context: String,
         image: String,
         env: Option<HashMap<String, String>>,
+        /// The command `ammn watch` runs to build and push the image, with
+        /// `{image}` replaced by the image to build.
+        build: Option<String>,
+        /// Rollout settings for individual jobs, overriding the app's, e.g.
+        /// `[target.prod.rollout.calc]` with `maxUnavailable = 1` and
+        /// `minAvailable = "50%"`.
+        rollout: Option<HashMap<String, DumpRollout>>,
+        /// Environment variables filled from Kubernetes secrets, as
+        /// `secret-name/key`, e.g. for `AMIMONO_PAYLOAD_KEYS`.
+        secrets: Option<HashMap<String, String>>,
+        /// Leave routing and load balancing to a service mesh such as Istio
+        /// or Linkerd. Each job gets a service named after it, and
+        /// components are called at `{job}.{namespace}` rather than at pods
+        /// found by watching the cluster. Sidecar injection must be set up
+        /// separately.
+        mesh: Option<bool>,
+    },
+    /// Machines reached over SSH, running jobs as systemd units with the
+    /// static runtime.
+    Static {
+        /// The hosts each job runs on.
+        hosts: BTreeMap<String, Vec<String>>,
+        /// The SSH user, if not the default.
+        user: Option<String>,
+        /// The directory on each host for the binary, static config, and
+        /// storage. Defaults to `/opt/amimono`.
+        root: Option<String>,
+        /// The locally built binary to copy to the hosts.
+        binary: String,
+        env: Option<HashMap<String, String>>,
+        /// The command to build the binary, run before every deploy.
+        build: Option<String>,
     },
 }
 
-pub fn load() -> Config {
-    let cf_file = match std::fs::read_to_string("amimono.toml") {
+/// A group of targets a revision is promoted through, e.g. staging and then
+/// prod:
+///
+/// ```toml
+/// [pipeline.release]
+/// targets = ["staging", "prod"]
+/// smoke = "smoke-test"
+/// soak = 300
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineConfig {
+    /// The targets to deploy to, in order.
+    pub targets: Vec<String>,
+    /// A tool run against each target once it's deployed. The pipeline only
+    /// moves on to the next target if it succeeds.
+    pub smoke: Option<String>,
+    /// Arguments for the smoke tool.
+    pub smoke_args: Option<Vec<String>>,
+    /// How many seconds to wait after deploying to a target before smoke
+    /// testing it, so that problems that take a while to show up can.
+    pub soak: Option<u64>,
+}
+
+/// What a value in `amimono.toml` must be.
+#[derive(Clone, Copy)]
+enum Kind {
+    String,
+    Integer,
+    Bool,
+    /// An array of strings.
+    Strings,
+    /// A table of names, e.g. of targets or jobs, to values of one kind.
+    Map(&'static Kind),
+    /// A table with known keys.
+    Table(&'static [Field]),
+    /// A table whose keys depend on the value of one of them, e.g. a
+    /// target's driver.
+    Tagged(&'static str, &'static [(&'static str, &'static [Field])]),
+    /// Anything, left for deserialization to check.
+    Any,
+}
+
+struct Field {
+    key: &'static str,
+    kind: Kind,
+    required: bool,
+}
+
+const fn required(key: &'static str, kind: Kind) -> Field {
+    Field {
+        key,
+        kind,
+        required: true,
+    }
+}
+
+const fn optional(key: &'static str, kind: Kind) -> Field {
+    Field {
+        key,
+        kind,
+        required: false,
+    }
+}
+
+const ROOT: &[Field] = &[
+    required("project", Kind::Tagged("format", &[("cargo", CARGO)])),
+    required(
+        "target",
+        Kind::Map(&Kind::Tagged(
+            "driver",
+            &[("kubernetes", KUBERNETES), ("static", STATIC)],
+        )),
+    ),
+    optional("pipeline", Kind::Map(&Kind::Table(PIPELINE))),
+];
+
+const CARGO: &[Field] = &[
+    optional("package", Kind::String),
+    optional("bin", Kind::String),
+    optional("release", Kind::Bool),
+    optional("features", Kind::Strings),
+];
+
+const KUBERNETES: &[Field] = &[
+    required("context", Kind::String),
+    required("image", Kind::String),
+    optional("env", Kind::Map(&Kind::String)),
+    optional("build", Kind::String),
+    optional("rollout", Kind::Map(&Kind::Table(ROLLOUT))),
+    optional("secrets", Kind::Map(&Kind::String)),
+    optional("mesh", Kind::Bool),
+];
+
+const ROLLOUT: &[Field] = &[
+    optional("maxUnavailable", Kind::Any),
+    optional("maxSurge", Kind::Any),
+    optional("minAvailable", Kind::Any),
+    optional("statefulUpdate", Kind::Any),
+];
+
+const STATIC: &[Field] = &[
+    required("hosts", Kind::Map(&Kind::Strings)),
+    optional("user", Kind::String),
+    optional("root", Kind::String),
+    required("binary", Kind::String),
+    optional("env", Kind::Map(&Kind::String)),
+    optional("build", Kind::String),
+];
+
+const PIPELINE: &[Field] = &[
+    required("targets", Kind::Strings),
+    optional("smoke", Kind::String),
+    optional("smoke_args", Kind::Strings),
+    optional("soak", Kind::Integer),
+];
+
+/// A problem with `amimono.toml`, at a range of bytes in the file.
+struct Problem {
+    span: Range<usize>,
+    message: String,
+}
+
+impl Problem {
+    fn new(span: Range<usize>, message: impl Into<String>) -> Problem {
+        Problem {
+            span,
+            message: message.into(),
+        }
+    }
+
+    /// The problem as `amimono.toml:line:column: message`, followed by the
+    /// line it's on.
+    fn render(&self, src: &str) -> String {
+        let start = self.span.start.min(src.len());
+        let line_start = src[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = src[start..]
+            .find('\n')
+            .map(|i| start + i)
+            .unwrap_or(src.len());
+        let line = src[..start].matches('\n').count() + 1;
+        let column = src[line_start..start].chars().count() + 1;
+        let end = self.span.end.clamp(start, line_end);
+        let width = src[start..end].chars().count().max(1);
+        let gutter = " ".repeat(line.to_string().len());
+        format!(
+            "{}:{}:{}: {}\n{} |\n{} | {}\n{} | {}{}",
+            FILE,
+            line,
+            column,
+            self.message,
+            gutter,
+            line,
+            src[line_start..line_end].trim_end_matches('\r'),
+            gutter,
+            " ".repeat(column - 1),
+            "^".repeat(width)
+        )
+    }
+}
+
+/// How a path in the file is referred to in problems.
+fn describe(path: &str) -> String {
+    match path {
+        "" => FILE.to_owned(),
+        _ => format!("`{}`", path),
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    match path {
+        "" => key.to_owned(),
+        _ => format!("{}.{}", path, key),
+    }
+}
+
+fn check_value(path: &str, value: &Spanned<DeValue>, kind: Kind, problems: &mut Vec<Problem>) {
+    let expected = match (kind, value.get_ref()) {
+        (Kind::Any, _)
+        | (Kind::String, DeValue::String(_))
+        | (Kind::Integer, DeValue::Integer(_))
+        | (Kind::Bool, DeValue::Boolean(_)) => return,
+        (Kind::Strings, DeValue::Array(items)) => {
+            for (i, item) in items.iter().enumerate() {
+                check_value(&format!("{}[{}]", path, i), item, Kind::String, problems);
+            }
+            return;
+        }
+        (Kind::Map(kind), DeValue::Table(table)) => {
+            for (key, value) in table.iter() {
+                check_value(&join(path, key.get_ref()), value, *kind, problems);
+            }
+            return;
+        }
+        (Kind::Table(fields), DeValue::Table(table)) => {
+            check_fields(path, value.span(), table, fields, None, problems);
+            return;
+        }
+        (Kind::Tagged(tag, variants), DeValue::Table(table)) => {
+            check_tagged(path, value.span(), table, tag, variants, problems);
+            return;
+        }
+        (Kind::String, _) => "a string",
+        (Kind::Integer, _) => "an integer",
+        (Kind::Bool, _) => "true or false",
+        (Kind::Strings, _) => "an array of strings",
+        (Kind::Map(_) | Kind::Table(_) | Kind::Tagged(..), _) => "a table",
+    };
+    problems.push(Problem::new(
+        value.span(),
+        format!(
+            "`{}` must be {}, not {}",
+            path,
+            expected,
+            value.get_ref().type_str()
+        ),
+    ));
+}
+
+fn check_tagged(
+    path: &str,
+    span: Range<usize>,
+    table: &DeTable,
+    tag: &str,
+    variants: &[(&'static str, &'static [Field])],
+    problems: &mut Vec<Problem>,
+) {
+    let names = variants.iter().map(|(name, _)| *name);
+    let Some(value) = table.get(tag) else {
+        problems.push(Problem::new(
+            span,
+            format!(
+                "{} is missing `{}`, which must be {}",
+                describe(path),
+                tag,
+                one_of(names)
+            ),
+        ));
+        return;
+    };
+    let given = value.get_ref().as_str().unwrap_or_default();
+    match variants.iter().find(|(name, _)| *name == given) {
+        Some((_, fields)) => check_fields(path, span, table, fields, Some(tag), problems),
+        None => problems.push(Problem::new(
+            value.span(),
+            format!(
+                "`{}` must be {}{}",
+                join(path, tag),
+                one_of(names.clone()),
+                did_you_mean(given, names)
+            ),
+        )),
+    }
+}
+
+fn check_fields(
+    path: &str,
+    span: Range<usize>,
+    table: &DeTable,
+    fields: &[Field],
+    tag: Option<&str>,
+    problems: &mut Vec<Problem>,
+) {
+    let keys = fields.iter().map(|f| f.key);
+    for (key, value) in table.iter() {
+        let key_str: &str = key.get_ref();
+        if Some(key_str) == tag {
+            continue;
+        }
+        match fields.iter().find(|f| f.key == key_str) {
+            Some(field) => check_value(&join(path, key_str), value, field.kind, problems),
+            None => {
+                let hint = match did_you_mean(key_str, keys.clone()) {
+                    hint if !hint.is_empty() || fields.is_empty() => hint,
+                    _ => format!("; expected {}", one_of(keys.clone())),
+                };
+                problems.push(Problem::new(
+                    key.span(),
+                    format!("unknown key `{}` in {}{}", key_str, describe(path), hint),
+                ));
+            }
+        }
+    }
+    for field in fields.iter().filter(|f| f.required) {
+        if table.get(field.key).is_none() {
+            problems.push(Problem::new(
+                span.clone(),
+                format!("{} is missing `{}`", describe(path), field.key),
+            ));
+        }
+    }
+}
+
+/// Check what the schema can't, such as pipelines' targets existing.
+fn check_config(cf: &Config, doc: &Spanned<DeTable>, problems: &mut Vec<Problem>) {
+    match &cf.project {
+        ProjectConfig::Cargo {
+            package,
+            bin,
+            features,
+            ..
+        } => {
+            for (key, value) in [("package", package), ("bin", bin)] {
+                if value.as_ref().is_some_and(|v| v.is_empty()) {
+                    problems.push(Problem::new(
+                        span_of(doc, lookup(doc, &["project", key])),
+                        format!("`project.{}` must not be empty", key),
+                    ));
+                }
+            }
+            let list = lookup(doc, &["project", "features"]);
+            let items = list.and_then(|f| f.get_ref().as_array());
+            for (i, feature) in features.iter().flatten().enumerate() {
+                if !feature.is_empty() && !feature.contains([',', ' ']) {
+                    continue;
+                }
+                problems.push(Problem::new(
+                    items
+                        .and_then(|items| items.get(i))
+                        .map(|item| item.span())
+                        .unwrap_or_else(|| span_of(doc, list)),
+                    format!("`{}` isn't a feature name", feature),
+                ));
+            }
+        }
+    }
+
+    for (name, pipeline) in cf.pipeline.iter() {
+        let targets = lookup(doc, &["pipeline", name, "targets"]);
+        if pipeline.targets.is_empty() {
+            problems.push(Problem::new(
+                span_of(doc, targets),
+                format!("pipeline `{}` has no targets", name),
+            ));
+        }
+        let items = targets.and_then(|t| t.get_ref().as_array());
+        for (i, target) in pipeline.targets.iter().enumerate() {
+            if cf.target.contains_key(target) {
+                continue;
+            }
+            problems.push(Problem::new(
+                items
+                    .and_then(|items| items.get(i))
+                    .map(|item| item.span())
+                    .unwrap_or_else(|| span_of(doc, targets)),
+                format!(
+                    "pipeline `{}` deploys to `{}`, which isn't a target{}",
+                    name,
+                    target,
+                    did_you_mean(target, cf.target.keys().map(|s| s.as_str()))
+                ),
+            ));
+        }
+    }
+
+    for (name, target) in cf.target.iter() {
+        let at = |key: &str| span_of(doc, lookup(doc, &["target", name, key]));
+        match target {
+            TargetConfig::Kubernetes {
+                context,
+                image,
+                secrets,
+                ..
+            } => {
+                if context.is_empty() || context.chars().any(char::is_whitespace) {
+                    problems.push(Problem::new(
+                        at("context"),
+                        format!(
+                            "`target.{}.context` must be the name of a kubectl context",
+                            name
+                        ),
+                    ));
+                }
+                if image.is_empty() {
+                    problems.push(Problem::new(
+                        at("image"),
+                        format!("`target.{}.image` must not be empty", name),
+                    ));
+                }
+                for (key, secret) in secrets.iter().flatten() {
+                    if crate::target::secret_ref(secret).is_none() {
+                        let value = lookup(doc, &["target", name, "secrets", key]);
+                        problems.push(Problem::new(
+                            span_of(doc, value),
+                            format!(
+                                "`target.{}.secrets.{}` must be written as secret-name/key",
+                                name, key
+                            ),
+                        ));
+                    }
+                }
+            }
+            TargetConfig::Static {
+                hosts,
+                root,
+                binary,
+                ..
+            } => {
+                for (job, _) in hosts.iter().filter(|(_, h)| h.is_empty()) {
+                    let value = lookup(doc, &["target", name, "hosts", job]);
+                    problems.push(Problem::new(
+                        span_of(doc, value),
+                        format!("`target.{}.hosts.{}` lists no hosts", name, job),
+                    ));
+                }
+                if root.as_ref().is_some_and(|root| !root.starts_with('/')) {
+                    problems.push(Problem::new(
+                        at("root"),
+                        format!("`target.{}.root` must be an absolute path", name),
+                    ));
+                }
+                if binary.is_empty() {
+                    problems.push(Problem::new(
+                        at("binary"),
+                        format!("`target.{}.binary` must not be empty", name),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Check that Kubernetes targets' contexts are in the kubeconfig, which is
+/// left to `ammn config check` since it runs kubectl.
+fn check_contexts(cf: &Config, doc: &Spanned<DeTable>, problems: &mut Vec<Problem>) {
+    let contexts = cf
+        .target
+        .iter()
+        .filter_map(|(name, target)| match target {
+            TargetConfig::Kubernetes { context, .. } => Some((name, context)),
+            TargetConfig::Static { .. } => None,
+        })
+        .collect::<Vec<_>>();
+    if contexts.is_empty() {
+        return;
+    }
+    let known = match kubectl_contexts() {
+        Ok(known) => known,
+        Err(e) => {
+            log::warn!(
+                "could not list kubectl contexts, so they weren't checked: {}",
+                e
+            );
+            return;
+        }
+    };
+    for (name, context) in contexts {
+        if known.contains(context) {
+            continue;
+        }
+        let value = lookup(doc, &["target", name, "context"]);
+        problems.push(Problem::new(
+            span_of(doc, value),
+            format!(
+                "`target.{}.context` is `{}`, which isn't a context in the kubeconfig{}",
+                name,
+                context,
+                did_you_mean(context, known.iter().map(|s| s.as_str()))
+            ),
+        ));
+    }
+}
+
+fn kubectl_contexts() -> std::io::Result<HashSet<String>> {
+    let out = std::process::Command::new("kubectl")
+        .args(["config", "get-contexts", "-o", "name"])
+        .stderr(std::process::Stdio::null())
+        .output()?;
+    if !out.status.success() {
+        return Err(std::io::Error::other(format!(
+            "kubectl exited with {}",
+            out.status
+        )));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(|s| s.to_owned())
+        .collect())
+}
+
+fn lookup<'d, 'i>(
+    doc: &'d Spanned<DeTable<'i>>,
+    path: &[&str],
+) -> Option<&'d Spanned<DeValue<'i>>> {
+    let (first, rest) = path.split_first()?;
+    let mut value = doc.get_ref().get(*first)?;
+    for key in rest {
+        value = value.get_ref().as_table()?.get(*key)?;
+    }
+    Some(value)
+}
+
+/// Where a value is, or the start of the file if it isn't there.
+fn span_of(doc: &Spanned<DeTable>, value: Option<&Spanned<DeValue>>) -> Range<usize> {
+    value
+        .map(|v| v.span())
+        .unwrap_or(doc.span().start..doc.span().start)
+}
+
+pub fn one_of<'c>(candidates: impl Iterator<Item = &'c str>) -> String {
+    let candidates = candidates.map(|c| format!("`{}`", c)).collect::<Vec<_>>();
+    match candidates.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("one of {} or {}", rest.join(", "), last),
+        None => "nothing".to_owned(),
+    }
+}
+
+/// A suggestion for a misspelled word, if one of the candidates is close.
+fn did_you_mean<'c>(word: &str, candidates: impl Iterator<Item = &'c str>) -> String {
+    let closest = candidates
+        .map(|c| (distance(word, c), c))
+        .filter(|(d, c)| *d <= 2 && *d < c.len())
+        .min();
+    match closest {
+        Some((_, c)) => format!("; did you mean `{}`?", c),
+        None => String::new(),
+    }
+}
+
+/// The Levenshtein distance between two words.
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let up = row[j + 1];
+            row[j + 1] = (diag + (ca != *cb) as usize).min(row[j] + 1).min(up + 1);
+            diag = up;
+        }
+    }
+    row[b.len()]
+}
+
+fn read() -> String {
+    match std::fs::read_to_string(FILE) {
+        Ok(x) => x,
+        Err(e) => crate::fatal!(kind = ErrorKind::Config, "failed to load {}: {}", FILE, e),
+    }
+}
+
+/// Parse the file, checking it against the schema and then the config
+/// against itself.
+fn parse(src: &str) -> Result<(Config, Spanned<DeTable<'_>>), Vec<Problem>> {
+    let (doc, errors) = DeTable::parse_recoverable(src);
+    if !errors.is_empty() {
+        return Err(errors
+            .iter()
+            .map(|e| Problem::new(e.span().unwrap_or_default(), e.message()))
+            .collect());
+    }
+    let mut problems = Vec::new();
+    check_fields("", doc.span(), doc.get_ref(), ROOT, None, &mut problems);
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    // anything the schema leaves to deserialization, e.g. rollout budgets
+    let cf: Config = match toml::from_str(src) {
         Ok(x) => x,
-        Err(e) => crate::fatal!("failed to load amimono.toml: {}", e),
+        Err(e) => {
+            return Err(vec![Problem::new(
+                e.span().unwrap_or_default(),
+                e.message(),
+            )]);
+        }
     };
-    match toml::de::from_str(&cf_file) {
+    check_config(&cf, &doc, &mut problems);
+    match problems.is_empty() {
+        true => Ok((cf, doc)),
+        false => Err(problems),
+    }
+}
+
+fn invalid(src: &str, mut problems: Vec<Problem>) -> ! {
+    problems.sort_by_key(|p| p.span.start);
+    let rendered = problems
+        .iter()
+        .map(|p| p.render(src))
+        .collect::<Vec<_>>()
+        .join("\n");
+    crate::fatal!(kind = ErrorKind::Config, "invalid {}:\n{}", FILE, rendered)
+}
+
+pub fn load() -> Config {
+    let src = read();
+    match parse(&src) {
+        Ok((cf, _)) => cf,
+        Err(problems) => invalid(&src, problems),
+    }
+}
+
+#[derive(Serialize)]
+struct CheckResult {
+    targets: Vec<String>,
+    pipelines: Vec<String>,
+}
+
+/// Report every problem with `amimono.toml`, including Kubernetes targets
+/// whose contexts aren't in the kubeconfig.
+pub fn check() {
+    let src = read();
+    let (cf, doc) = match parse(&src) {
         Ok(x) => x,
-        Err(e) => crate::fatal!("failed to parse amimono.toml: {}", e),
+        Err(problems) => invalid(&src, problems),
+    };
+    let mut problems = Vec::new();
+    check_contexts(&cf, &doc, &mut problems);
+    if !problems.is_empty() {
+        invalid(&src, problems);
     }
+
+    let mut targets = cf.target.keys().cloned().collect::<Vec<_>>();
+    let mut pipelines = cf.pipeline.keys().cloned().collect::<Vec<_>>();
+    targets.sort();
+    pipelines.sort();
+    log::info!("{} is valid", FILE);
+    output::result(true, &CheckResult { targets, pipelines });
 }
@@ -0,0 +1,293 @@
+//! `ammn init`, which scaffolds a new project.
+//!
+//! The generated project is a Cargo workspace with a single app crate in
+//! `app/`, which has a small RPC component installed in one job, a `build.rs`
+//! computing the app revision, and a `configure()` function. The workspace
+//! root has an `amimono.toml` with a `local` target, which deploys to this
+//! machine with the static driver, and a `k8s` target to fill in, plus a
+//! Dockerfile and a CI workflow that checks formatting, lints, runs the tests,
+//! and makes sure the app config can be dumped.
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::output::{self, ErrorKind};
+
+/// Where the generated project gets its Amimono crates from.
+pub enum Source {
+    /// The crates.io release matching this version of ammn.
+    Release,
+    /// A local checkout of the Amimono repository.
+    Path(PathBuf),
+}
+
+impl Source {
+    fn dependency(&self, krate: &str) -> String {
+        match self {
+            Source::Release => format!("\"{}\"", env!("CARGO_PKG_VERSION")),
+            Source::Path(root) => format!("{{ path = {:?} }}", root.join(krate)),
+        }
+    }
+}
+
+const WORKSPACE_TOML: &str = r#"[workspace]
+resolver = "3"
+members = ["app"]
+"#;
+
+const APP_TOML: &str = r#"[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2024"
+
+[dependencies]
+amimono = {amimono}
+env_logger = "0.11.8"
+log = "0.4.28"
+serde = { version = "1.0.228", features = ["derive"] }
+tokio = { version = "1.48.0", features = ["full"] }
+
+[build-dependencies]
+amimono-build = {amimono_build}
+"#;
+
+const BUILD_RS: &str = r#"fn main() {
+    let mut digest = amimono_build::AppDigest::new();
+    let rev = digest
+        .add_glob("src/**/*.rs")
+        .add_path("Cargo.toml")
+        .compute();
+
+    amimono_build::BuildInfo::new(&rev).emit();
+    amimono_build::EmbeddedConfig::new(&rev, "../.amimono/config.json").emit();
+    digest.rerun_if_changed();
+}
+"#;
+
+const MAIN_RS: &str = r#"mod greeter;
+
+use amimono::{
+    component::Component,
+    config::{AppBuilder, AppConfig, JobBuilder},
+    runtime::BuildInfo,
+};
+
+const BUILD_INFO: BuildInfo = amimono::include_build_info!();
+
+pub fn configure() -> AppConfig {
+    AppBuilder::from_build_info(BUILD_INFO)
+        .with_embedded_config(amimono::include_config!())
+        .add_job(
+            JobBuilder::new()
+                .with_label("greeter")
+                .install(greeter::GreeterComponent::installer),
+        )
+        .build()
+}
+
+fn main() {
+    env_logger::init();
+    amimono::entry(configure());
+}
+"#;
+
+const GREETER_RS: &str = r#"use amimono::rpc::RpcResult;
+
+amimono::rpc_component! {
+    mod ops {
+        const LABEL: &'static str = "greeter";
+
+        /// Returns a greeting for the given name.
+        fn greet(name: String) -> String;
+    }
+}
+
+pub type GreeterComponent = ops::Component<Greeter>;
+
+pub struct Greeter;
+
+impl ops::Handler for Greeter {
+    async fn new() -> Self {
+        Greeter
+    }
+
+    async fn greet(&self, name: &String) -> RpcResult<String> {
+        log::info!("greeting {}", name);
+        Ok(format!("hello, {}!", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ops::Handler;
+    use super::*;
+
+    #[tokio::test]
+    async fn greets() {
+        let greeter = Greeter::new().await;
+        let res = greeter.greet(&"world".to_owned()).await;
+        assert_eq!(res.ok().as_deref(), Some("hello, world!"));
+    }
+}
+"#;
+
+const AMIMONO_TOML: &str = r#"[project]
+format = "cargo"
+# The workspace package that runs the app, which ammn builds to dump the app's
+# config and run its tools. Set `bin` too if it gets more than one binary.
+package = "{name}"
+# release = true
+# features = []
+
+# Runs the app on this machine with the static driver, which needs SSH access
+# to localhost.
+[target.local]
+driver = "static"
+binary = "target/release/{name}"
+build = "cargo build --release"
+root = "/tmp/{name}"
+
+[target.local.hosts]
+greeter = ["localhost"]
+
+[target.local.env]
+RUST_LOG = "info"
+
+# A Kubernetes cluster. Set the context, image, and build command for yours.
+[target.k8s]
+driver = "kubernetes"
+context = "minikube"
+image = "{name}"
+build = "docker build -t {image} . && minikube image load {image}"
+
+[target.k8s.env]
+RUST_LOG = "info"
+"#;
+
+const DOCKERFILE: &str = r#"FROM rust:1-slim-trixie AS build
+WORKDIR /app
+COPY . .
+RUN cargo build -p {name} --release
+
+FROM debian:trixie-slim
+WORKDIR /app
+COPY --from=build /app/target/release/{name} /app/{name}
+ENTRYPOINT ["/app/{name}"]
+"#;
+
+const DOCKERIGNORE: &str = r#"target/
+.amimono/
+"#;
+
+const GITIGNORE: &str = r#"target/
+.amimono/
+"#;
+
+const CI_YML: &str = r#"name: ci
+
+on:
+  push:
+  pull_request:
+
+jobs:
+  check:
+    runs-on: ubuntu-latest
+    steps:
+      - uses: actions/checkout@v4
+      - run: cargo fmt --all --check
+      - run: cargo clippy --workspace --all-targets -- -D warnings
+      - run: cargo test --workspace
+      - run: cargo run -- --dump-config > /dev/null
+"#;
+
+const README_MD: &str = r#"# {name}
+
+An [Amimono](https://github.com/aji/amimono) app.
+
+* `cargo run -- --local` runs every job in one process.
+* `ammn deploy local` deploys to this machine over SSH.
+* `ammn deploy k8s` deploys to Kubernetes, once the `k8s` target in
+  `amimono.toml` is set up for your cluster.
+* `ammn graph` prints the dependency graph between jobs and components.
+
+Components live in `app/src`, and are installed in jobs by `configure()` in
+`app/src/main.rs`.
+"#;
+
+#[derive(Serialize)]
+struct InitResult<'r> {
+    name: &'r str,
+    path: &'r Path,
+}
+
+/// Check that a name is usable as a Cargo package name.
+fn check_name(name: &str) -> Result<(), String> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match name.chars().next() {
+        None => Err("project name is empty".to_owned()),
+        Some(c) if c.is_ascii_digit() => Err(format!("project name {} starts with a digit", name)),
+        _ if !valid_chars => Err(format!(
+            "project name {} may only contain letters, digits, '-', and '_'",
+            name
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub fn init(name: &str, source: Source) {
+    if let Err(e) = check_name(name) {
+        crate::fatal!(kind = ErrorKind::Config, "{}", e);
+    }
+    let root = Path::new(name);
+    if root.exists() {
+        crate::fatal!(
+            kind = ErrorKind::Config,
+            "{} already exists",
+            root.display()
+        );
+    }
+
+    let fill = |template: &str| {
+        template
+            .replace("{name}", name)
+            .replace("{amimono}", &source.dependency("amimono"))
+            .replace("{amimono_build}", &source.dependency("amimono-build"))
+    };
+    let files: &[(&str, &str)] = &[
+        ("Cargo.toml", WORKSPACE_TOML),
+        ("amimono.toml", AMIMONO_TOML),
+        ("Dockerfile", DOCKERFILE),
+        (".dockerignore", DOCKERIGNORE),
+        (".gitignore", GITIGNORE),
+        (".github/workflows/ci.yml", CI_YML),
+        ("README.md", README_MD),
+        ("app/Cargo.toml", APP_TOML),
+        ("app/build.rs", BUILD_RS),
+        ("app/src/main.rs", MAIN_RS),
+        ("app/src/greeter.rs", GREETER_RS),
+    ];
+    for (path, template) in files {
+        let path = root.join(path);
+        log::debug!("writing {}", path.display());
+        let res = match path.parent() {
+            Some(dir) => std::fs::create_dir_all(dir),
+            None => Ok(()),
+        };
+        if let Err(e) = res.and_then(|()| std::fs::write(&path, fill(template))) {
+            crate::fatal!("failed to write {}: {}", path.display(), e);
+        }
+    }
+
+    log::info!("created project {} in {}", name, root.display());
+    if output::is_json() {
+        output::result(true, &InitResult { name, path: root });
+        return;
+    }
+    println!("created {}, to get started:", root.display());
+    println!();
+    println!("  cd {}", root.display());
+    println!("  cargo run -- --local");
+}
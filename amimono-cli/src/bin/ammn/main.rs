@@ -1,15 +1,33 @@
+pub mod call;
+pub mod clean;
+pub mod codegen;
+pub mod compat;
 pub mod config;
+pub mod init;
 pub mod logger;
+pub mod output;
+pub mod pipeline;
 pub mod project;
+pub mod replay;
+pub mod r#static;
 pub mod target;
+pub mod watch;
+
+use output::ErrorKind;
 
 macro_rules! fatal {
-    ($($arg:tt)*) => {
+    (kind = $kind:expr, $($arg:tt)*) => {
         {
-            ::log::error!($($arg)*);
-            ::std::process::exit(1);
+            let kind: crate::output::ErrorKind = $kind;
+            let msg = format!($($arg)*);
+            ::log::error!("{}", msg);
+            crate::output::failure(kind, &msg);
+            ::std::process::exit(kind.exit_code());
         }
     };
+    ($($arg:tt)*) => {
+        crate::fatal!(kind = crate::output::ErrorKind::Failed, $($arg)*)
+    };
 }
 
 pub(crate) use fatal;
@@ -32,14 +50,239 @@ pub fn cli() -> clap::Command {
                 .action(clap::ArgAction::SetTrue)
                 .help("Enable verbose logging."),
         )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .global(true)
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .help("Print results as text or as JSON for scripting."),
+        )
         .subcommand_required(true)
+        .subcommand(
+            Command::new("init")
+                .about("Create a new project in a new directory.")
+                .arg(
+                    Arg::new("name")
+                        .required(true)
+                        .help("The name of the project and its directory."),
+                )
+                .arg(
+                    Arg::new("amimono-path").long("amimono-path").help(
+                        "Use Amimono from a local checkout rather than the matching release.",
+                    ),
+                ),
+        )
         .subcommand(
             Command::new("deploy")
                 .about("Deploy a project target.")
                 .arg(
                     Arg::new("target")
-                        .required(true)
+                        .required_unless_present("pipeline")
                         .help("The target to deploy."),
+                )
+                .arg(
+                    Arg::new("pipeline")
+                        .long("pipeline")
+                        .conflicts_with("target")
+                        .help("Promote the app through a pipeline of targets from amimono.toml, resuming a previous run that stopped."),
+                )
+                .arg(
+                    Arg::new("restart")
+                        .long("restart")
+                        .requires("pipeline")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Start the pipeline over rather than resuming it."),
+                )
+                .arg(
+                    Arg::new("allow-breaking")
+                        .long("allow-breaking")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Deploy even if the new revision has breaking API changes."),
+                )
+                .arg(
+                    Arg::new("all")
+                        .long("all")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Roll every job, including ones that haven't changed."),
+                )
+                .arg(
+                    Arg::new("strategy")
+                        .long("strategy")
+                        .conflicts_with("pipeline")
+                        .value_parser(["rolling", "blue-green"])
+                        .default_value("rolling")
+                        .help("Roll jobs in place, or run the new revision alongside the old one and switch services over once it's ready."),
+                )
+                .arg(
+                    Arg::new("smoke")
+                        .long("smoke")
+                        .action(clap::ArgAction::Append)
+                        .help("A tool to run against the new revision before switching to it, for blue-green deploys, in addition to the app's smoke tests."),
+                )
+                .arg(
+                    Arg::new("grace-period")
+                        .long("grace-period")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("60")
+                        .help("How many seconds the old revision keeps running after a blue-green switch."),
+                ),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("Rebuild and redeploy a development target whenever the project changes.")
+                .arg(
+                    Arg::new("target")
+                        .required(true)
+                        .help("The target to deploy to. It must have a build command."),
+                )
+                .arg(
+                    Arg::new("allow-breaking")
+                        .long("allow-breaking")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Deploy even if a revision has breaking API changes."),
+                ),
+        )
+        .subcommand(
+            Command::new("tool")
+                .about("Run a tool as a one-off job against a deployed target.")
+                .arg(
+                    Arg::new("target")
+                        .required(true)
+                        .help("The target to run the tool in."),
+                )
+                .arg(Arg::new("tool").required(true).help("The tool to run."))
+                .arg(
+                    Arg::new("args")
+                        .num_args(0..)
+                        .trailing_var_arg(true)
+                        .help("Args to send to the tool."),
+                ),
+        )
+        .subcommand(
+            Command::new("replay")
+                .about("Replay a component's journaled requests against a local instance.")
+                .arg(
+                    Arg::new("target")
+                        .required(true)
+                        .help("The target to fetch the journal from."),
+                )
+                .arg(
+                    Arg::new("component")
+                        .required(true)
+                        .help("The component whose requests to replay."),
+                )
+                .arg(
+                    Arg::new("addr").long("addr").help(
+                        "The address of the instance to replay against. Discovered if not set.",
+                    ),
+                ),
+        )
+        .subcommand(
+            Command::new("call")
+                .about("Make an RPC call to a component deployed to a target.")
+                .arg(
+                    Arg::new("target")
+                        .required(true)
+                        .help("The target the component is deployed to."),
+                )
+                .arg(
+                    Arg::new("component")
+                        .required(true)
+                        .help("The component to call."),
+                )
+                .arg(Arg::new("verb").required(true).help("The op to call."))
+                .arg(Arg::new("json").long("json").help(
+                    "The op's arguments as JSON: an array, or the argument itself for ops with one.",
+                )),
+        )
+        .subcommand(
+            Command::new("graph")
+                .about("Print the dependency graph between jobs and components.")
+                .arg(
+                    Arg::new("target")
+                        .short('t')
+                        .long("target")
+                        .help("Include calls observed by a deployed target."),
+                )
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .value_parser(["dot", "json"])
+                        .default_value("dot")
+                        .help("The output format. DOT can be rendered with Graphviz."),
+                ),
+        )
+        .subcommand(
+            Command::new("codegen")
+                .about("Generate typed clients for the app's RPC components in another language.")
+                .arg(
+                    Arg::new("lang")
+                        .short('l')
+                        .long("lang")
+                        .required(true)
+                        .value_parser(["ts", "python"])
+                        .help("The language to generate clients in."),
+                )
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .help("The file to write the clients to, rather than stdout."),
+                )
+                .arg(
+                    Arg::new("component")
+                        .short('c')
+                        .long("component")
+                        .action(clap::ArgAction::Append)
+                        .help("Only generate clients for these components."),
+                ),
+        )
+        .subcommand(
+            Command::new("status")
+                .about("Show the health and storage usage of a deployed target.")
+                .arg(
+                    Arg::new("target")
+                        .required(true)
+                        .help("The target to inspect."),
+                ),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Work with the project's amimono.toml.")
+                .subcommand_required(true)
+                .subcommand(Command::new("check").about(
+                    "Report every problem with amimono.toml, including contexts missing from the kubeconfig.",
+                )),
+        )
+        .subcommand(
+            Command::new("clean")
+                .about("Remove the local runtime's storage, and optionally build artifacts.")
+                .arg(
+                    Arg::new("component")
+                        .short('c')
+                        .long("component")
+                        .action(clap::ArgAction::Append)
+                        .help("Only remove the storage of these components."),
+                )
+                .arg(
+                    Arg::new("build")
+                        .long("build")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Also remove the project's build artifacts."),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Print what would be removed without removing it."),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Clean even if the app appears to be running locally."),
                 ),
         )
 }
@@ -48,11 +291,50 @@ fn main() {
     let matches = cli().get_matches();
 
     logger::init(matches.get_flag("verbose"));
+    output::init(
+        match matches.get_one::<String>("output").map(|s| s.as_str()) {
+            Some("json") => output::Output::Json,
+            _ => output::Output::Text,
+        },
+    );
 
     if let Some(x) = matches.get_one::<String>("project") {
         if let Err(e) = std::env::set_current_dir(x) {
-            fatal!("could not find project {}: {}", x, e);
+            fatal!(
+                kind = ErrorKind::Config,
+                "could not find project {}: {}",
+                x,
+                e
+            );
+        }
+    }
+
+    // init runs before there's a project to load
+    if let Some(("init", sub_m)) = matches.subcommand() {
+        let name = sub_m.get_one::<String>("name").expect("name is required");
+        let source = match sub_m.get_one::<String>("amimono-path") {
+            Some(path) => match std::fs::canonicalize(path) {
+                Ok(path) => init::Source::Path(path),
+                Err(e) => fatal!(
+                    kind = ErrorKind::Config,
+                    "could not find Amimono checkout {}: {}",
+                    path,
+                    e
+                ),
+            },
+            None => init::Source::Release,
+        };
+        init::init(name, source);
+        return;
+    }
+
+    // config check reports the problems that would stop loading the config
+    if let Some(("config", sub_m)) = matches.subcommand() {
+        match sub_m.subcommand() {
+            Some(("check", _)) => config::check(),
+            _ => unreachable!("subcommand is required"),
         }
+        return;
     }
 
     let cf = config::load();
@@ -60,11 +342,150 @@ fn main() {
 
     match matches.subcommand() {
         Some(("deploy", sub_m)) => {
+            if let Some(name) = sub_m.get_one::<String>("pipeline") {
+                pipeline::deploy(
+                    &cf,
+                    &proj,
+                    name,
+                    sub_m.get_flag("restart"),
+                    sub_m.get_flag("allow-breaking"),
+                    sub_m.get_flag("all"),
+                );
+                return;
+            }
             let target_name = sub_m
                 .get_one::<String>("target")
                 .expect("target is required");
             let target = target::Target::from_config(&cf, target_name);
-            target.deploy(&proj);
+            let smoke = sub_m
+                .get_many::<String>("smoke")
+                .map(|x| x.cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            match sub_m.get_one::<String>("strategy").map(|s| s.as_str()) {
+                Some("blue-green") => {
+                    let grace = sub_m
+                        .get_one::<u64>("grace-period")
+                        .expect("grace-period has a default");
+                    target.deploy_blue_green(
+                        &proj,
+                        sub_m.get_flag("allow-breaking"),
+                        &smoke,
+                        std::time::Duration::from_secs(*grace),
+                    );
+                }
+                _ if !smoke.is_empty() => crate::fatal!(
+                    kind = ErrorKind::Config,
+                    "--smoke is only used by blue-green deploys"
+                ),
+                _ => target.deploy(
+                    &proj,
+                    sub_m.get_flag("allow-breaking"),
+                    sub_m.get_flag("all"),
+                    None,
+                ),
+            }
+        }
+        Some(("watch", sub_m)) => {
+            let target_name = sub_m
+                .get_one::<String>("target")
+                .expect("target is required");
+            let target = target::Target::from_config(&cf, target_name);
+            watch::watch(&target, &proj, sub_m.get_flag("allow-breaking"));
+        }
+        Some(("tool", sub_m)) => {
+            let target_name = sub_m
+                .get_one::<String>("target")
+                .expect("target is required");
+            let tool = sub_m.get_one::<String>("tool").expect("tool is required");
+            let args = sub_m
+                .get_many::<String>("args")
+                .map(|x| x.cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            let target = target::Target::from_config(&cf, target_name);
+            target.run_tool(tool, &args);
+        }
+        Some(("replay", sub_m)) => {
+            let target_name = sub_m
+                .get_one::<String>("target")
+                .expect("target is required");
+            let component = sub_m
+                .get_one::<String>("component")
+                .expect("component is required");
+            let target = target::Target::from_config(&cf, target_name);
+            replay::replay(
+                &target,
+                &proj,
+                component,
+                sub_m.get_one::<String>("addr").map(|s| s.as_str()),
+            );
+        }
+        Some(("call", sub_m)) => {
+            let target_name = sub_m
+                .get_one::<String>("target")
+                .expect("target is required");
+            let component = sub_m
+                .get_one::<String>("component")
+                .expect("component is required");
+            let verb = sub_m.get_one::<String>("verb").expect("verb is required");
+            let target = target::Target::from_config(&cf, target_name);
+            call::call(
+                &target,
+                component,
+                verb,
+                sub_m.get_one::<String>("json").map(|s| s.as_str()),
+            );
+        }
+        Some(("graph", sub_m)) => {
+            let mut graph = proj.get_app_graph();
+            if let Some(target_name) = sub_m.get_one::<String>("target") {
+                let target = target::Target::from_config(&cf, target_name);
+                graph.add_calls(target.observed_calls());
+            }
+            match sub_m.get_one::<String>("format").map(|s| s.as_str()) {
+                _ if output::is_json() => output::result(true, &graph),
+                Some("json") => match serde_json::to_string_pretty(&graph) {
+                    Ok(json) => println!("{}", json),
+                    Err(e) => fatal!("failed to serialize graph: {}", e),
+                },
+                _ => print!("{}", graph.to_dot()),
+            }
+        }
+        Some(("codegen", sub_m)) => {
+            let lang = sub_m
+                .get_one::<String>("lang")
+                .and_then(|l| codegen::Lang::parse(l))
+                .expect("lang is required");
+            let only = sub_m
+                .get_many::<String>("component")
+                .map(|x| x.cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            let cf = proj.get_app_config();
+            codegen::codegen(
+                &cf,
+                lang,
+                &only,
+                sub_m.get_one::<String>("out").map(|s| s.as_str()),
+            );
+        }
+        Some(("status", sub_m)) => {
+            let target_name = sub_m
+                .get_one::<String>("target")
+                .expect("target is required");
+            let target = target::Target::from_config(&cf, target_name);
+            target.status();
+        }
+        Some(("clean", sub_m)) => {
+            let components = sub_m
+                .get_many::<String>("component")
+                .map(|x| x.cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            clean::clean(
+                &proj,
+                &components,
+                sub_m.get_flag("build"),
+                sub_m.get_flag("dry-run"),
+                sub_m.get_flag("force"),
+            );
         }
         _ => unreachable!("subcommand is required"),
     }
@@ -0,0 +1,153 @@
+//! Machine-readable output.
+//!
+//! With `--output json`, each command prints a single JSON object to stdout
+//! when it finishes, and everything else, including the output of kubectl,
+//! goes to stderr. Every result has an `ok` field. Failed commands print
+//! `{"ok": false, "error": "...", "kind": "...", "exitCode": n, "transient": b}`
+//! and exit with the status for the kind of failure, so that scripts and CI
+//! can branch on it, e.g. to retry only transient failures:
+//!
+//! | kind          | exit code | transient | e.g.                                      |
+//! |---------------|-----------|-----------|-------------------------------------------|
+//! | `failed`      | 1         | no        | a tool or smoke test failed               |
+//! | `config`      | 2         | no        | invalid `amimono.toml`, unknown target    |
+//! | `build`       | 3         | no        | the app or image failed to build          |
+//! | `unreachable` | 4         | yes       | the cluster or a host couldn't be reached |
+//! | `conflict`    | 5         | no        | an apply was rejected, breaking changes   |
+//! | `timeout`     | 6         | yes       | a job didn't become ready in time         |
+//!
+//! Usage errors are reported by the argument parser, which also exits with 2.
+
+use std::{
+    process::Stdio,
+    sync::{
+        OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use serde::Serialize;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Text,
+    Json,
+}
+
+static OUTPUT: OnceLock<Output> = OnceLock::new();
+
+/// Set while running a step of a larger command, whose own result isn't
+/// printed.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn init(output: Output) {
+    OUTPUT.set(output).ok().expect("output already initialized");
+}
+
+pub fn is_json() -> bool {
+    OUTPUT.get() == Some(&Output::Json)
+}
+
+/// Where child processes should send their stdout, so that it doesn't mix
+/// with JSON results.
+pub fn child_stdout() -> Stdio {
+    match is_json() {
+        true => Stdio::from(std::io::stderr()),
+        false => Stdio::inherit(),
+    }
+}
+
+#[derive(Serialize)]
+struct Result<'r, T> {
+    ok: bool,
+    #[serde(flatten)]
+    result: &'r T,
+}
+
+/// Run a step of a larger command, such as one deploy of a pipeline, without
+/// printing the step's own result. Failures are still printed.
+pub fn quietly<R>(f: impl FnOnce() -> R) -> R {
+    let was = QUIET.swap(true, Ordering::Relaxed);
+    let res = f();
+    QUIET.store(was, Ordering::Relaxed);
+    res
+}
+
+/// Print a command's result, if in JSON mode.
+pub fn result<T: Serialize>(ok: bool, result: &T) {
+    if ok && QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    if is_json() {
+        match serde_json::to_string_pretty(&Result { ok, result }) {
+            Ok(json) => println!("{}", json),
+            Err(e) => log::error!("failed to serialize result: {}", e),
+        }
+    }
+}
+
+/// The kinds of failure, which `ammn` exits with distinct statuses for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorKind {
+    Failed,
+    Config,
+    Build,
+    Unreachable,
+    Conflict,
+    Timeout,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Failed => 1,
+            ErrorKind::Config => 2,
+            ErrorKind::Build => 3,
+            ErrorKind::Unreachable => 4,
+            ErrorKind::Conflict => 5,
+            ErrorKind::Timeout => 6,
+        }
+    }
+
+    /// Whether the same command might succeed if it's run again.
+    pub fn is_transient(self) -> bool {
+        matches!(self, ErrorKind::Unreachable | ErrorKind::Timeout)
+    }
+
+    /// The kind of failure an error from running kubectl, ssh, etc. is, as
+    /// classified by the function that ran it.
+    pub fn of(e: &std::io::Error) -> ErrorKind {
+        use std::io::ErrorKind as Io;
+        match e.kind() {
+            Io::ConnectionRefused
+            | Io::ConnectionReset
+            | Io::HostUnreachable
+            | Io::NetworkUnreachable
+            | Io::NotConnected => ErrorKind::Unreachable,
+            Io::TimedOut => ErrorKind::Timeout,
+            Io::AlreadyExists => ErrorKind::Conflict,
+            _ => ErrorKind::Failed,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Failure<'f> {
+    error: &'f str,
+    kind: ErrorKind,
+    exit_code: i32,
+    transient: bool,
+}
+
+/// Print a failure, if in JSON mode.
+pub fn failure(kind: ErrorKind, error: &str) {
+    let failure = Failure {
+        error,
+        kind,
+        exit_code: kind.exit_code(),
+        transient: kind.is_transient(),
+    };
+    result(false, &failure);
+}
@@ -0,0 +1,202 @@
+//! `ammn deploy --pipeline`, for promoting a revision through a group of
+//! targets, e.g. staging and then prod.
+//!
+//! The revision is deployed to each target in turn, and the pipeline's smoke
+//! tool is run against it. A target is only deployed once every target
+//! before it has passed. Progress is saved under `.amimono/pipelines` after
+//! every step, so when a step fails, running the pipeline again picks up
+//! where it stopped rather than redeploying targets that already passed.
+//!
+//! The revision being promoted is the one deployed to the first target.
+//! Every target should deploy the same build, e.g. the same image, and the
+//! pipeline stops if a later target ends up running a different revision.
+
+use std::{path::PathBuf, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{Config, PipelineConfig},
+    output::{self, ErrorKind},
+    project::Project,
+    target::Target,
+};
+
+/// The progress of a pipeline run.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    /// The revision being promoted, once it's deployed to the first target.
+    revision: Option<String>,
+    /// The targets the revision has been deployed to.
+    deployed: Vec<String>,
+    /// The targets whose smoke tests have passed.
+    verified: Vec<String>,
+}
+
+impl State {
+    fn path(name: &str) -> PathBuf {
+        PathBuf::from(".amimono")
+            .join("pipelines")
+            .join(format!("{}.json", name))
+    }
+
+    fn load(name: &str) -> Option<State> {
+        let path = State::path(name);
+        let s = std::fs::read_to_string(&path).ok()?;
+        match serde_json::from_str(&s) {
+            Ok(st) => Some(st),
+            Err(e) => {
+                log::warn!("ignoring unreadable {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn save(&self, name: &str) {
+        let path = State::path(name);
+        let json = serde_json::to_string_pretty(self).expect("state serializes");
+        let res = std::fs::create_dir_all(path.parent().expect("has a parent"))
+            .and_then(|_| std::fs::write(&path, json));
+        if let Err(e) = res {
+            crate::fatal!("failed to save pipeline state to {}: {}", path.display(), e);
+        }
+    }
+
+    fn finished(&self, cf: &PipelineConfig) -> bool {
+        let done = match cf.smoke {
+            Some(_) => &self.verified,
+            None => &self.deployed,
+        };
+        cf.targets.iter().all(|t| done.contains(t))
+    }
+}
+
+pub fn deploy(
+    cf: &Config,
+    proj: &Project,
+    name: &str,
+    restart: bool,
+    allow_breaking: bool,
+    all: bool,
+) {
+    let Some(pipeline) = cf.pipeline.get(name) else {
+        crate::fatal!(
+            kind = ErrorKind::Config,
+            "no pipeline {} in amimono.toml",
+            name
+        );
+    };
+    if pipeline.targets.is_empty() {
+        crate::fatal!(kind = ErrorKind::Config, "pipeline {} has no targets", name);
+    }
+    // check every target before deploying to any of them
+    let targets = pipeline
+        .targets
+        .iter()
+        .map(|t| (t, Target::from_config(cf, t)))
+        .collect::<Vec<_>>();
+
+    let mut state = match State::load(name) {
+        Some(st) if !restart && !st.finished(pipeline) => {
+            if let Some(rev) = &st.revision {
+                log::info!("resuming pipeline {} for revision {}", name, rev);
+            }
+            st
+        }
+        _ => State::default(),
+    };
+
+    for (target_name, target) in &targets {
+        let target_name = target_name.as_str();
+
+        if !state.deployed.iter().any(|t| t == target_name) {
+            // a resumed pipeline checks its revision before deploying: the
+            // target may already run it, e.g. if ammn stopped before saving,
+            // and a revision built since must not be deployed in its place
+            let running = state.revision.as_ref().filter(|rev| {
+                target
+                    .deployed_config()
+                    .is_some_and(|cf| cf.revision == **rev)
+            });
+            match running {
+                Some(rev) => log::info!(
+                    "pipeline {}: {} is already running {}",
+                    name,
+                    target_name,
+                    rev
+                ),
+                None => {
+                    log::info!("pipeline {}: deploying to {}...", name, target_name);
+                    let expect = state.revision.as_deref();
+                    output::quietly(|| target.deploy(proj, allow_breaking, all, expect));
+
+                    let deployed = target.deployed_config().map(|cf| cf.revision);
+                    match (&state.revision, deployed) {
+                        (_, None) => crate::fatal!(
+                            kind = ErrorKind::Unreachable,
+                            "could not get the revision deployed to {}; not continuing",
+                            target_name
+                        ),
+                        (None, Some(rev)) => state.revision = Some(rev),
+                        (Some(rev), Some(deployed)) if *rev != deployed => crate::fatal!(
+                            kind = ErrorKind::Conflict,
+                            "{} is running revision {}, but the pipeline is promoting {}. \
+                             use --restart to promote the new revision from the start",
+                            target_name,
+                            deployed,
+                            rev
+                        ),
+                        (Some(_), Some(_)) => {}
+                    }
+                }
+            }
+            state.deployed.push(target_name.to_owned());
+            state.save(name);
+        }
+
+        let Some(smoke) = &pipeline.smoke else {
+            continue;
+        };
+        if !state.verified.iter().any(|t| t == target_name) {
+            if let Some(soak) = pipeline.soak {
+                log::info!(
+                    "pipeline {}: waiting {}s before smoke testing {}...",
+                    name,
+                    soak,
+                    target_name
+                );
+                std::thread::sleep(Duration::from_secs(soak));
+            }
+            log::info!("pipeline {}: smoke testing {}...", name, target_name);
+            // a failing smoke tool stops ammn, leaving the target to be
+            // tested again when the pipeline is resumed
+            let args = pipeline.smoke_args.clone().unwrap_or_default();
+            output::quietly(|| target.run_tool(smoke, &args));
+            state.verified.push(target_name.to_owned());
+            state.save(name);
+        }
+    }
+
+    let revision = state.revision.as_deref().unwrap_or_default();
+    log::info!(
+        "pipeline {}: revision {} deployed to {}",
+        name,
+        revision,
+        pipeline.targets.join(", ")
+    );
+    output::result(
+        true,
+        &PipelineResult {
+            pipeline: name,
+            revision,
+            targets: &pipeline.targets,
+        },
+    );
+}
+
+#[derive(Serialize)]
+struct PipelineResult<'r> {
+    pipeline: &'r str,
+    revision: &'r str,
+    targets: &'r [String],
+}
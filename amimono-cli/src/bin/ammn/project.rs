@@ -1,38 +1,462 @@
-use std::process::Command;
+//! Building and running the app's binary.
+//!
+//! Cargo projects can be single packages or workspaces. The package and
+//! binary that run the app are picked from `[project]` in `amimono.toml`, or
+//! worked out from `cargo metadata` when there's only one candidate, and built
+//! with the profile and features it sets. A fingerprint of the workspace and
+//! the build settings is saved with the binary's path after each build, so
+//! that commands that run the app several times, or run it again before
+//! anything has changed, don't wait for cargo. Path dependencies outside the
+//! workspace aren't part of the fingerprint, so changes to them need
+//! `ammn clean --build` or a change in the workspace to be picked up.
 
-use amimono_schemas::DumpConfig;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::BufRead,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::OnceLock,
+};
+
+use amimono_schemas::{DumpConfig, DumpGraph, SCHEMA_VERSION};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{ProjectConfig, one_of},
+    output::ErrorKind,
+};
+
+/// Where the output of the last full `--dump-config` is saved, for
+/// `amimono_build::EmbeddedConfig` to embed in the next build.
+const SAVED_CONFIG: &str = ".amimono/config.json";
+
+/// Where the last build's fingerprint and binary are saved.
+const BUILD_CACHE: &str = ".amimono/build.json";
+
+/// Parse the output of `--dump-config`, refusing configs with a newer schema
+/// than this version of ammn understands, since deploying them would silently
+/// drop whatever the newer schema added.
+pub fn parse_app_config(bytes: &[u8]) -> Result<DumpConfig, String> {
+    let cf: DumpConfig =
+        serde_json::from_slice(bytes).map_err(|e| format!("failed to parse app config: {}", e))?;
+    if cf.schema_version > SCHEMA_VERSION {
+        return Err(format!(
+            "app config has schema version {}, but this ammn only understands up to {}; upgrade ammn",
+            cf.schema_version, SCHEMA_VERSION
+        ));
+    }
+    Ok(cf)
+}
+
+/// Read the config embedded in a binary at build time, if there is one.
+pub fn read_embedded_config(binary: &Path) -> Option<DumpConfig> {
+    let bytes = std::fs::read(binary).ok()?;
+    let cf = amimono_schemas::find_embedded_configs(&bytes)
+        .find_map(|dump| parse_app_config(dump).ok())?;
+    log::debug!(
+        "using config for revision {} embedded in {}",
+        cf.revision,
+        binary.display()
+    );
+    Some(cf)
+}
 
 pub enum Project {
-    Cargo,
+    Cargo(CargoProject),
+}
+
+/// A Cargo package and binary, and how to build them.
+pub struct CargoProject {
+    package: Option<String>,
+    bin: Option<String>,
+    release: bool,
+    features: Vec<String>,
+    /// The binary, once it's been built or found up to date.
+    built: OnceLock<PathBuf>,
+}
+
+/// The parts of `cargo metadata --no-deps` used to pick a binary.
+#[derive(Deserialize)]
+struct Metadata {
+    packages: Vec<Package>,
+    workspace_root: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    name: String,
+    manifest_path: PathBuf,
+    targets: Vec<CargoTarget>,
+    default_run: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CargoTarget {
+    name: String,
+    kind: Vec<String>,
+}
+
+impl Package {
+    fn bins(&self) -> impl Iterator<Item = &str> {
+        self.targets
+            .iter()
+            .filter(|t| t.kind.iter().any(|k| k == "bin"))
+            .map(|t| t.name.as_str())
+    }
+}
+
+/// A message from `cargo build --message-format json`, of which only
+/// compiled binaries are of interest.
+#[derive(Deserialize)]
+struct BuildMessage {
+    reason: String,
+    target: Option<CargoTarget>,
+    executable: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BuildCache {
+    fingerprint: String,
+    binary: PathBuf,
 }
 
 impl Project {
     pub fn from_config(cfg: &crate::config::Config) -> Self {
         match &cfg.project {
-            crate::config::ProjectConfig::Cargo => Project::Cargo,
+            ProjectConfig::Cargo {
+                package,
+                bin,
+                release,
+                features,
+            } => Project::Cargo(CargoProject {
+                package: package.clone(),
+                bin: bin.clone(),
+                release: release.unwrap_or(false),
+                features: features.clone().unwrap_or_default(),
+                built: OnceLock::new(),
+            }),
         }
     }
 
+    /// Get the app's config by running it, saving the output so that the
+    /// next build can embed it.
     pub fn get_app_config(&self) -> DumpConfig {
+        log::info!("dumping app config...");
+        let s = self.run_app(&["--dump-config"]);
+        let cf = parse_app_config(s.as_bytes())
+            .unwrap_or_else(|e| crate::fatal!(kind = ErrorKind::Build, "{}", e));
+        let saved = Path::new(SAVED_CONFIG);
+        let res = std::fs::create_dir_all(saved.parent().expect("has a parent"))
+            .and_then(|_| std::fs::write(saved, &s));
+        if let Err(e) = res {
+            log::warn!("could not save app config to {}: {}", SAVED_CONFIG, e);
+        }
+        cf
+    }
+
+    /// Get the app's config from the one embedded in its binary at build
+    /// time, if there is one, without running it.
+    pub fn get_embedded_config(&self) -> Option<DumpConfig> {
+        read_embedded_config(self.binary())
+    }
+
+    pub fn get_app_graph(&self) -> DumpGraph {
+        log::info!("dumping dependency graph...");
+        let s = self.run_app(&["--dump-graph", "json"]);
+        serde_json::from_str(&s).unwrap_or_else(|e| {
+            crate::fatal!(
+                kind = ErrorKind::Build,
+                "failed to parse dependency graph: {}",
+                e
+            )
+        })
+    }
+
+    /// Remove the project's build artifacts, or with `dry_run`, only report
+    /// what would be removed.
+    pub fn clean(&self, dry_run: bool) {
         match self {
-            Project::Cargo => {
-                log::info!("dumping app config via cargo...");
-                let out = Command::new("cargo")
-                    .args(["run", "--", "--dump-config"])
-                    .stderr(std::process::Stdio::inherit())
-                    .output()
-                    .unwrap_or_else(|e| crate::fatal!("failed to run cargo: {}", e));
-                if !out.status.success() {
+            Project::Cargo(_) => {
+                let mut cmd = Command::new("cargo");
+                cmd.arg("clean");
+                if dry_run {
+                    cmd.arg("--dry-run");
+                }
+                let status = cmd
+                    .stdout(crate::output::child_stdout())
+                    .stderr(Stdio::inherit())
+                    .status()
+                    .unwrap_or_else(|e| {
+                        crate::fatal!(kind = ErrorKind::Build, "failed to run cargo: {}", e)
+                    });
+                if !status.success() {
                     crate::fatal!(
-                        "cargo process exited with status {}",
-                        out.status.code().unwrap_or(-1)
+                        kind = ErrorKind::Build,
+                        "cargo clean exited with status {}",
+                        status.code().unwrap_or(-1)
                     );
                 }
-                let s = String::from_utf8(out.stdout)
-                    .unwrap_or_else(|e| crate::fatal!("failed to parse cargo output: {}", e));
-                serde_json::from_str(&s)
-                    .unwrap_or_else(|e| crate::fatal!("failed to parse app config: {}", e))
+                if !dry_run
+                    && let Err(e) = std::fs::remove_file(BUILD_CACHE)
+                    && e.kind() != std::io::ErrorKind::NotFound
+                {
+                    log::warn!("could not remove {}: {}", BUILD_CACHE, e);
+                }
             }
         }
     }
+
+    /// Run one of the app's tools locally, with its output going to the
+    /// terminal.
+    pub fn run_tool(&self, tool: &str, args: &[&str]) {
+        let binary = self.binary();
+        let status = Command::new(binary)
+            .args(["--tool", tool])
+            .args(args)
+            .stdout(crate::output::child_stdout())
+            .stderr(Stdio::inherit())
+            .status()
+            .unwrap_or_else(|e| {
+                crate::fatal!(
+                    kind = ErrorKind::Build,
+                    "failed to run {}: {}",
+                    binary.display(),
+                    e
+                )
+            });
+        if !status.success() {
+            crate::fatal!(
+                "tool {} exited with status {}",
+                tool,
+                status.code().unwrap_or(-1)
+            );
+        }
+    }
+
+    fn run_app(&self, args: &[&str]) -> String {
+        let binary = self.binary();
+        let out = Command::new(binary)
+            .args(args)
+            .stderr(Stdio::inherit())
+            .output()
+            .unwrap_or_else(|e| {
+                crate::fatal!(
+                    kind = ErrorKind::Build,
+                    "failed to run {}: {}",
+                    binary.display(),
+                    e
+                )
+            });
+        if !out.status.success() {
+            crate::fatal!(
+                kind = ErrorKind::Build,
+                "{} exited with status {}",
+                binary.display(),
+                out.status.code().unwrap_or(-1)
+            );
+        }
+        String::from_utf8(out.stdout).unwrap_or_else(|e| {
+            crate::fatal!(kind = ErrorKind::Build, "failed to parse app output: {}", e)
+        })
+    }
+
+    /// The app's binary, built first unless the last build is up to date.
+    fn binary(&self) -> &Path {
+        match self {
+            Project::Cargo(cargo) => cargo.built.get_or_init(|| cargo.build()),
+        }
+    }
+}
+
+impl CargoProject {
+    fn build(&self) -> PathBuf {
+        let meta = metadata();
+        let (package, bin) = self.select(&meta);
+        let args = self.build_args(package, bin);
+
+        let mut hasher = DefaultHasher::new();
+        crate::watch::fingerprint(&meta.workspace_root).hash(&mut hasher);
+        args.hash(&mut hasher);
+        let fingerprint = format!("{:016x}", hasher.finish());
+        if let Some(cache) = read_build_cache()
+            && cache.fingerprint == fingerprint
+            && cache.binary.exists()
+        {
+            log::debug!("{} is up to date", cache.binary.display());
+            return cache.binary;
+        }
+
+        log::info!("building {} in {}...", bin, package);
+        let mut child = Command::new("cargo")
+            .arg("build")
+            .args(&args)
+            .arg("--message-format=json-render-diagnostics")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .unwrap_or_else(|e| {
+                crate::fatal!(kind = ErrorKind::Build, "failed to run cargo: {}", e)
+            });
+        let stdout = std::io::BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let mut binary = None;
+        for line in stdout.lines().map_while(Result::ok) {
+            let Ok(msg) = serde_json::from_str::<BuildMessage>(&line) else {
+                continue;
+            };
+            if msg.reason == "compiler-artifact"
+                && msg.target.is_some_and(|t| t.name == bin)
+                && msg.executable.is_some()
+            {
+                binary = msg.executable;
+            }
+        }
+        let status = child.wait().unwrap_or_else(|e| {
+            crate::fatal!(kind = ErrorKind::Build, "failed to run cargo: {}", e)
+        });
+        if !status.success() {
+            crate::fatal!(
+                kind = ErrorKind::Build,
+                "cargo build exited with status {}",
+                status.code().unwrap_or(-1)
+            );
+        }
+        let Some(binary) = binary else {
+            crate::fatal!(
+                kind = ErrorKind::Build,
+                "cargo build didn't report building {}",
+                bin
+            );
+        };
+
+        let cache = BuildCache {
+            fingerprint,
+            binary: binary.clone(),
+        };
+        let saved = Path::new(BUILD_CACHE);
+        let res = serde_json::to_vec(&cache)
+            .map_err(std::io::Error::from)
+            .and_then(|bytes| {
+                std::fs::create_dir_all(saved.parent().expect("has a parent"))?;
+                std::fs::write(saved, bytes)
+            });
+        if let Err(e) = res {
+            log::warn!("could not save build fingerprint to {}: {}", BUILD_CACHE, e);
+        }
+        binary
+    }
+
+    /// Pick the package and binary that run the app.
+    fn select<'m>(&self, meta: &'m Metadata) -> (&'m str, &'m str) {
+        let with_bins = meta
+            .packages
+            .iter()
+            .filter(|p| p.bins().next().is_some())
+            .collect::<Vec<_>>();
+        let package = match &self.package {
+            Some(name) => match meta.packages.iter().find(|p| p.name == *name) {
+                Some(package) => package,
+                None => crate::fatal!(
+                    kind = ErrorKind::Config,
+                    "`project.package` is `{}`, which isn't in the workspace; expected {}",
+                    name,
+                    one_of(with_bins.iter().map(|p| p.name.as_str()))
+                ),
+            },
+            None => {
+                // the package in the current directory, as with `cargo run`
+                let here = std::env::current_dir().and_then(|d| d.canonicalize()).ok();
+                match meta
+                    .packages
+                    .iter()
+                    .find(|p| p.manifest_path.parent() == here.as_deref())
+                {
+                    Some(package) => package,
+                    None if with_bins.len() == 1 => with_bins[0],
+                    None if with_bins.is_empty() => crate::fatal!(
+                        kind = ErrorKind::Config,
+                        "no package in the workspace has a binary"
+                    ),
+                    None => crate::fatal!(
+                        kind = ErrorKind::Config,
+                        "set `project.package` to the package the app is in, {}",
+                        one_of(with_bins.iter().map(|p| p.name.as_str()))
+                    ),
+                }
+            }
+        };
+
+        let bins = package.bins().collect::<Vec<_>>();
+        if bins.is_empty() {
+            crate::fatal!(
+                kind = ErrorKind::Config,
+                "package {} has no binaries",
+                package.name
+            );
+        }
+        let bin = match self.bin.as_deref().or(package.default_run.as_deref()) {
+            Some(name) => match bins.iter().find(|b| **b == name) {
+                Some(bin) => bin,
+                None => crate::fatal!(
+                    kind = ErrorKind::Config,
+                    "package {} has no binary named `{}`; expected {}",
+                    package.name,
+                    name,
+                    one_of(bins.iter().copied())
+                ),
+            },
+            None if bins.len() == 1 => bins[0],
+            None => crate::fatal!(
+                kind = ErrorKind::Config,
+                "package {} has several binaries; set `project.bin` to {}",
+                package.name,
+                one_of(bins.iter().copied())
+            ),
+        };
+        (package.name.as_str(), bin)
+    }
+
+    fn build_args(&self, package: &str, bin: &str) -> Vec<String> {
+        let mut args = vec![
+            "--package".to_owned(),
+            package.to_owned(),
+            "--bin".to_owned(),
+            bin.to_owned(),
+        ];
+        if self.release {
+            args.push("--release".to_owned());
+        }
+        if !self.features.is_empty() {
+            args.push("--features".to_owned());
+            args.push(self.features.join(","));
+        }
+        args
+    }
+}
+
+fn metadata() -> Metadata {
+    let out = Command::new("cargo")
+        .args(["metadata", "--no-deps", "--format-version", "1"])
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap_or_else(|e| crate::fatal!(kind = ErrorKind::Build, "failed to run cargo: {}", e));
+    if !out.status.success() {
+        crate::fatal!(
+            kind = ErrorKind::Build,
+            "cargo metadata exited with status {}",
+            out.status.code().unwrap_or(-1)
+        );
+    }
+    serde_json::from_slice(&out.stdout).unwrap_or_else(|e| {
+        crate::fatal!(
+            kind = ErrorKind::Build,
+            "failed to parse cargo metadata: {}",
+            e
+        )
+    })
+}
+
+fn read_build_cache() -> Option<BuildCache> {
+    let bytes = std::fs::read(BUILD_CACHE).ok()?;
+    serde_json::from_slice(&bytes).ok()
 }
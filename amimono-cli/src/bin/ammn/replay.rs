@@ -0,0 +1,64 @@
+//! `ammn replay`, for re-sending a component's recorded traffic to a local
+//! instance.
+//!
+//! The component's journal is fetched from every replica in the target and
+//! saved under `.amimono/journal`, and the app's replay tool is run locally
+//! to send the recorded requests and compare the responses. The local
+//! instance should already be running, e.g. with `cargo run -- --local`.
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::{
+    output::{self, ErrorKind},
+    project::Project,
+    target::Target,
+};
+
+/// The label of the tool added to apps with a journal.
+const REPLAY_TOOL: &str = "amimono-replay";
+
+pub fn replay(target: &Target, proj: &Project, component: &str, addr: Option<&str>) {
+    log::info!("fetching journal for {}...", component);
+    let journal = target.fetch_journal(component);
+    let entries = journal
+        .split(|b| *b == b'\n')
+        .filter(|l| !l.is_empty())
+        .count();
+    if entries == 0 {
+        crate::fatal!(
+            kind = ErrorKind::Config,
+            "no journal entries found for {}. is a journal configured with AppBuilder::with_journal?",
+            component
+        );
+    }
+
+    let dir = Path::new(".amimono").join("journal");
+    let path = dir.join(format!("{}.jsonl", component));
+    if let Err(e) = std::fs::create_dir_all(&dir).and_then(|_| std::fs::write(&path, &journal)) {
+        crate::fatal!("failed to save journal to {}: {}", path.display(), e);
+    }
+    log::info!("saved {} entries to {}", entries, path.display());
+
+    let path = path.to_string_lossy();
+    let mut args = vec![component, path.as_ref()];
+    args.extend(addr);
+    proj.run_tool(REPLAY_TOOL, &args);
+
+    output::result(
+        true,
+        &ReplayResult {
+            component,
+            journal: &path,
+            entries,
+        },
+    );
+}
+
+#[derive(Serialize)]
+struct ReplayResult<'r> {
+    component: &'r str,
+    journal: &'r str,
+    entries: usize,
+}
@@ -0,0 +1,690 @@
+//! The `static` driver, for deploying to a handful of machines over SSH.
+//!
+//! Each job is placed on a list of hosts. Deploying generates the static
+//! runtime's `amimono.toml` from those lists, copies the binary and config to
+//! every host with rsync, writes a systemd unit per job that runs the binary
+//! with `--job`, `--bind`, and `--static`, and restarts the units. Jobs are
+//! restarted in dependency order, waiting for every replica of a wave to
+//! report ready before moving on.
+//!
+//! ```toml
+//! [target.vms]
+//! driver = "static"
+//! binary = "target/release/example-adder"
+//! build = "cargo build --release"
+//! user = "root"
+//!
+//! [target.vms.hosts]
+//! adder = ["10.0.0.1", "10.0.0.2"]
+//! driver = ["10.0.0.3"]
+//! ```
+//!
+//! Hosts are used both to connect with SSH and as the addresses jobs reach
+//! each other at, so they must be reachable from the other hosts. Since every
+//! job listens on the same port, a host can only run one job. The SSH user
+//! must be able to write to the remote root and to `/etc/systemd/system`, and
+//! to run `systemctl`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::Path,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use amimono_schemas::{DumpConfig, DumpEdge, DumpServerInfo};
+
+use crate::{
+    call,
+    output::{self, ErrorKind},
+    project::Project,
+    target::{self, ADMIN_PORT, DeployResult, PodStatus, StatusResult, ToolResult},
+};
+
+/// How long to wait for a restarted job to become ready.
+const READY_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Clone)]
+pub(crate) struct StaticTarget {
+    pub(crate) hosts: BTreeMap<String, Vec<String>>,
+    pub(crate) user: Option<String>,
+    pub(crate) root: String,
+    pub(crate) binary: String,
+    pub(crate) env: HashMap<String, String>,
+    pub(crate) build: Option<String>,
+}
+
+impl StaticTarget {
+    /// The remote root used when none is configured.
+    pub(crate) const DEFAULT_ROOT: &'static str = "/opt/amimono";
+
+    fn binary_name(&self) -> &str {
+        Path::new(&self.binary)
+            .file_name()
+            .and_then(|s| s.to_str())
+            .unwrap_or("app")
+    }
+
+    fn remote_binary(&self) -> String {
+        format!("{}/bin/{}", self.root, self.binary_name())
+    }
+
+    fn deployed_config_path(&self) -> String {
+        format!("{}/deployed.json", self.root)
+    }
+
+    fn all_hosts(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.hosts
+            .iter()
+            .flat_map(|(job, hosts)| hosts.iter().map(move |h| (job.as_str(), h.as_str())))
+    }
+
+    fn destination(&self, host: &str) -> String {
+        match &self.user {
+            Some(user) => format!("{}@{}", user, host),
+            None => host.to_owned(),
+        }
+    }
+
+    /// Run the target's build command, if it has one.
+    pub(crate) fn run_build(&self) -> Result<(), String> {
+        let Some(build) = &self.build else {
+            return Ok(());
+        };
+        log::info!("building {}...", self.binary);
+        log::debug!("build command: {}", build);
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(build)
+            .stdout(output::child_stdout())
+            .stderr(Stdio::inherit())
+            .status()
+            .map_err(|e| format!("failed to run build command: {}", e))?;
+        if !status.success() {
+            return Err(format!("build command exited with status {}", status));
+        }
+        Ok(())
+    }
+
+    fn do_ssh(&self, host: &str, script: &str) -> io::Result<()> {
+        log::debug!("ssh {}: {}", host, script);
+        let status = Command::new("ssh")
+            .arg(self.destination(host))
+            .arg(script)
+            .stdout(output::child_stdout())
+            .stderr(Stdio::inherit())
+            .status()?;
+        if !status.success() {
+            return Err(ssh_error("ssh", status));
+        }
+        Ok(())
+    }
+
+    fn do_ssh_output(&self, host: &str, script: &str) -> io::Result<Vec<u8>> {
+        log::debug!("ssh {}: {}", host, script);
+        let output = Command::new("ssh")
+            .arg(self.destination(host))
+            .arg(script)
+            .stderr(Stdio::inherit())
+            .output()?;
+        if !output.status.success() {
+            return Err(ssh_error("ssh", output.status));
+        }
+        Ok(output.stdout)
+    }
+
+    /// Write a file on a host, creating its directory if needed.
+    fn do_write(&self, host: &str, path: &str, contents: &str) -> io::Result<()> {
+        let dir = Path::new(path)
+            .parent()
+            .and_then(|p| p.to_str())
+            .unwrap_or("/");
+        let script = format!(
+            "mkdir -p {} && printf '%s' {} > {}",
+            quote(dir),
+            quote(contents),
+            quote(path)
+        );
+        self.do_ssh(host, &script)
+    }
+
+    fn do_rsync(&self, host: &str, local: &str, remote: &str) -> io::Result<()> {
+        let status = Command::new("rsync")
+            .arg("--compress")
+            .arg("--checksum")
+            .arg("--chmod=F755")
+            .arg("--rsync-path")
+            .arg(format!(
+                "mkdir -p {} && rsync",
+                quote(&format!("{}/bin", self.root))
+            ))
+            .arg(local)
+            .arg(format!("{}:{}", self.destination(host), remote))
+            .stdout(output::child_stdout())
+            .stderr(Stdio::inherit())
+            .status()?;
+        if !status.success() {
+            return Err(ssh_error("rsync", status));
+        }
+        Ok(())
+    }
+
+    /// Fetch a path from a host's admin endpoint.
+    fn do_get_admin(&self, host: &str, path: &str) -> io::Result<serde_json::Value> {
+        let url = format!("http://{}:{}{}", host, ADMIN_PORT, path);
+        let out = self.do_ssh_output(host, &format!("curl -sf {}", quote(&url)))?;
+        serde_json::from_slice(&out[..]).map_err(|e| io::Error::other(format!("bad JSON: {}", e)))
+    }
+
+    /// Wait for the host to be ready and serving the given revision, so that
+    /// an old process still holding the port isn't mistaken for the new one.
+    /// Apps too old to report their revision are taken at their word.
+    fn do_wait_for_ready(&self, host: &str, revision: &str) -> io::Result<()> {
+        let url = format!("http://{}:{}/ready", host, ADMIN_PORT);
+        let script = format!("curl -sf -o /dev/null {}", quote(&url));
+        let start = Instant::now();
+        let mut running = None;
+        loop {
+            let status = Command::new("ssh")
+                .arg(self.destination(host))
+                .arg(&script)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()?;
+            if status.success() {
+                let info = self
+                    .do_get_admin(host, "/rpc/_info")
+                    .ok()
+                    .and_then(|v| serde_json::from_value::<DumpServerInfo>(v).ok());
+                match info {
+                    Some(info) if info.revision != revision => running = Some(info.revision),
+                    _ => return Ok(()),
+                }
+            }
+            if start.elapsed() > READY_TIMEOUT {
+                let msg = match running {
+                    Some(old) => format!("timed out waiting for readiness, still running {}", old),
+                    None => "timed out waiting for readiness".to_owned(),
+                };
+                return Err(io::Error::new(io::ErrorKind::TimedOut, msg));
+            }
+            std::thread::sleep(Duration::from_secs(2));
+        }
+    }
+
+    /// Get the config of the most recently deployed revision, if any, from the
+    /// first host that has one.
+    pub(crate) fn get_deployed_config(&self) -> Option<DumpConfig> {
+        let script = format!(
+            "cat {} 2>/dev/null || true",
+            quote(&self.deployed_config_path())
+        );
+        for (_, host) in self.all_hosts() {
+            let out = match self.do_ssh_output(host, &script) {
+                Ok(out) => out,
+                Err(e) => {
+                    log::warn!("could not read deployed config from {}: {}", host, e);
+                    continue;
+                }
+            };
+            if out.iter().all(|b| b.is_ascii_whitespace()) {
+                continue;
+            }
+            match serde_json::from_slice(&out[..]) {
+                Ok(cf) => return Some(cf),
+                Err(e) => log::warn!("failed to parse deployed config on {}: {}", host, e),
+            }
+        }
+        None
+    }
+
+    /// Check the host lists against the app's jobs.
+    fn validate(&self, cf: &DumpConfig) -> Result<(), String> {
+        for job in self.hosts.keys() {
+            if !cf.jobs.contains_key(job) {
+                return Err(format!("hosts listed for unknown job {}", job));
+            }
+        }
+        for job in cf.jobs.keys() {
+            if self.hosts.get(job).is_none_or(|h| h.is_empty()) {
+                return Err(format!("job {} has no hosts", job));
+            }
+        }
+        for (label, job) in cf.jobs.iter() {
+            let hosts = self.hosts[label].len();
+            if job.replicas as usize != hosts {
+                log::warn!(
+                    "job {} requests {} replicas, but static targets run one per host ({})",
+                    label,
+                    job.replicas,
+                    hosts
+                );
+            }
+        }
+        let mut seen = HashMap::new();
+        for (job, host) in self.all_hosts() {
+            if let Some(other) = seen.insert(host, job) {
+                return Err(format!(
+                    "host {} is listed for both {} and {}, but can only run one job",
+                    host, other, job
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The static runtime's `amimono.toml`. Each host is a named replica, so
+    /// that the job's named ports can be listed with it.
+    fn static_config(&self, cf: &DumpConfig) -> String {
+        let mut out = String::new();
+        for (job, hosts) in self.hosts.iter() {
+            let mut ports = BTreeMap::new();
+            for port in cf.jobs[job]
+                .components
+                .values()
+                .flat_map(|c| c.ports.iter())
+            {
+                ports.entry(port.name.as_str()).or_insert(port.port);
+            }
+            let ports = ports
+                .iter()
+                .map(|(name, port)| format!("{:?} = {}", name, port))
+                .collect::<Vec<_>>()
+                .join(", ");
+            for host in hosts.iter() {
+                out.push_str(&format!("[job.{:?}.replica.{:?}]\n", job, host));
+                out.push_str(&format!("addr = {:?}\n", host));
+                out.push_str(&format!("ports = {{ {} }}\n\n", ports));
+            }
+        }
+        out
+    }
+
+    fn unit_name(job: &str) -> String {
+        format!("amimono-{}.service", job)
+    }
+
+    /// Run-once jobs are oneshot units, so restarting one waits for it to
+    /// complete, and fails if it does.
+    fn unit(&self, job: &str, host: &str, runs_once: bool) -> String {
+        let mut out = String::new();
+        out.push_str("[Unit]\n");
+        out.push_str(&format!("Description=amimono job {}\n", job));
+        out.push_str("After=network-online.target\n");
+        out.push_str("Wants=network-online.target\n");
+        out.push_str("\n[Service]\n");
+        out.push_str(&format!(
+            "ExecStart={} --job {} --bind {} --static {}\n",
+            self.remote_binary(),
+            job,
+            host,
+            self.root
+        ));
+        let mut env = self.env.iter().collect::<Vec<_>>();
+        env.sort();
+        for (key, value) in env {
+            out.push_str(&format!("Environment=\"{}={}\"\n", key, value));
+        }
+        if runs_once {
+            out.push_str("Type=oneshot\n");
+            out.push_str("RemainAfterExit=yes\n");
+            out.push_str("TimeoutStartSec=infinity\n");
+        } else {
+            out.push_str("Restart=always\n");
+            out.push_str("RestartSec=1\n");
+        }
+        out.push_str("\n[Install]\n");
+        out.push_str("WantedBy=multi-user.target\n");
+        out
+    }
+
+    pub(crate) fn deploy(
+        &self,
+        proj: &Project,
+        allow_breaking: bool,
+        all: bool,
+        expect: Option<&str>,
+    ) {
+        if let Err(e) = self.run_build() {
+            crate::fatal!(kind = ErrorKind::Build, "build failed: {}", e);
+        }
+        if !Path::new(&self.binary).is_file() {
+            crate::fatal!(
+                kind = ErrorKind::Build,
+                "binary {} not found, build it first",
+                self.binary
+            );
+        }
+
+        let cf = crate::project::read_embedded_config(Path::new(&self.binary))
+            .unwrap_or_else(|| proj.get_app_config());
+        target::check_revision(&cf, expect);
+        if let Err(e) = self.validate(&cf) {
+            crate::fatal!(kind = ErrorKind::Config, "invalid hosts: {}", e);
+        }
+
+        let deployed = self.get_deployed_config();
+        let changes = target::check_compat(deployed.as_ref(), &cf, allow_breaking);
+
+        // jobs whose digest matches the deployed one are left running as-is.
+        // run-once jobs run for every revision
+        let unchanged = |job: &str| {
+            let digest = cf.jobs[job].digest.as_ref();
+            let old = deployed.as_ref().and_then(|d| d.jobs.get(job));
+            !all && !cf.jobs[job].runs_once
+                && digest.is_some()
+                && old.and_then(|j| j.digest.as_ref()) == digest
+        };
+        let skipped = cf
+            .jobs
+            .keys()
+            .filter(|j| unchanged(j))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !skipped.is_empty() {
+            log::info!("skipping unchanged jobs: {}", skipped.join(", "));
+        }
+
+        let waves = match target::job_waves(&cf) {
+            Ok(w) => w,
+            Err(e) => crate::fatal!(kind = ErrorKind::Config, "invalid job dependencies: {}", e),
+        };
+
+        // the binary and config are shared by every job, so they're copied to
+        // every host, but only changed jobs are restarted
+        let config = self.static_config(&cf);
+        for (_, host) in self.all_hosts() {
+            log::info!("copying {} to {}...", self.binary_name(), host);
+            if let Err(e) = self.do_rsync(host, &self.binary, &self.remote_binary()) {
+                crate::fatal!(
+                    kind = ErrorKind::of(&e),
+                    "failed to copy binary to {}: {}",
+                    host,
+                    e
+                );
+            }
+            let path = format!("{}/amimono.toml", self.root);
+            if let Err(e) = self.do_write(host, &path, &config) {
+                crate::fatal!(
+                    kind = ErrorKind::of(&e),
+                    "failed to write static config on {}: {}",
+                    host,
+                    e
+                );
+            }
+        }
+
+        let waves = waves
+            .into_iter()
+            .map(|wave| {
+                wave.into_iter()
+                    .filter(|j| !unchanged(j))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|wave| !wave.is_empty())
+            .collect::<Vec<_>>();
+
+        for (i, wave) in waves.iter().enumerate() {
+            log::info!("restarting jobs: {}", wave.join(", "));
+            for job in wave.iter() {
+                let unit = Self::unit_name(job);
+                let runs_once = cf.jobs[job].runs_once;
+                for host in self.hosts[job].iter() {
+                    let path = format!("/etc/systemd/system/{}", unit);
+                    if let Err(e) = self.do_write(host, &path, &self.unit(job, host, runs_once)) {
+                        crate::fatal!(
+                            kind = ErrorKind::of(&e),
+                            "failed to write unit for {} on {}: {}",
+                            job,
+                            host,
+                            e
+                        );
+                    }
+                    let script = format!(
+                        "systemctl daemon-reload && systemctl enable {0} && systemctl restart {0}",
+                        quote(&unit)
+                    );
+                    if runs_once {
+                        log::info!("running {} on {}...", job, host);
+                    }
+                    if let Err(e) = self.do_ssh(host, &script) {
+                        crate::fatal!(
+                            kind = ErrorKind::of(&e),
+                            "failed to restart {} on {}: {}",
+                            job,
+                            host,
+                            e
+                        );
+                    }
+                }
+            }
+
+            // the last wave has no dependents, so there is nothing to wait
+            // for, unless smoke tests are about to run
+            if i + 1 == waves.len() && cf.smoke_tests.is_empty() {
+                break;
+            }
+
+            // run-once jobs have already completed by the time they're
+            // restarted
+            for job in wave.iter().filter(|j| !cf.jobs[*j].runs_once) {
+                log::info!("waiting for {} to become ready...", job);
+                for host in self.hosts[job].iter() {
+                    if let Err(e) = self.do_wait_for_ready(host, &cf.revision) {
+                        crate::fatal!(
+                            kind = ErrorKind::of(&e),
+                            "job {} on {} did not become ready: {}",
+                            job,
+                            host,
+                            e
+                        );
+                    }
+                }
+            }
+        }
+
+        self.smoke_test(&cf);
+
+        log::info!("recording deployed config...");
+        match serde_json::to_string(&cf) {
+            Ok(json) => {
+                for (_, host) in self.all_hosts() {
+                    if let Err(e) = self.do_write(host, &self.deployed_config_path(), &json) {
+                        log::warn!("failed to record deployed config on {}: {}", host, e);
+                    }
+                }
+            }
+            Err(e) => log::warn!("failed to serialize deployed config: {}", e),
+        }
+
+        log::info!("all done!");
+        output::result(
+            true,
+            &DeployResult {
+                revision: &cf.revision,
+                deployed: waves.iter().flatten().cloned().collect(),
+                skipped,
+                breaking_changes: changes.breaking,
+                warnings: changes.warnings,
+            },
+        );
+    }
+
+    /// Run a tool on the first host of the first job.
+    pub(crate) fn run_tool(&self, tool: &str, args: &[String]) {
+        let host = self.tool_host();
+        log::info!("running {} on {}...", tool, host);
+        if let Err(e) = self.do_run_tool(host, tool, args) {
+            crate::fatal!(
+                kind = ErrorKind::of(&e),
+                "tool {} failed on {}: {}",
+                tool,
+                host,
+                e
+            );
+        }
+
+        log::info!("tool {} finished successfully", tool);
+        output::result(true, &ToolResult { tool, job: host });
+    }
+
+    /// Run the app's smoke tests against a newly deployed revision. Static
+    /// deploys replace the binary in place, so there's nothing to roll back
+    /// to if they fail.
+    fn smoke_test(&self, cf: &DumpConfig) {
+        let host = self.tool_host();
+        for tool in cf.smoke_tests.iter() {
+            log::info!("smoke testing {} with {} on {}...", cf.revision, tool, host);
+            if let Err(e) = self.do_run_tool(host, tool, &[]) {
+                crate::fatal!(
+                    kind = ErrorKind::of(&e),
+                    "smoke test {} failed on {}: {}. static deploys can't be rolled back, \
+                     so {} is still running, but it hasn't been recorded as deployed",
+                    tool,
+                    host,
+                    e,
+                    cf.revision
+                );
+            }
+        }
+    }
+
+    /// The host tools are run on.
+    fn tool_host(&self) -> &str {
+        match self.all_hosts().next() {
+            Some((_, host)) => host,
+            None => crate::fatal!(
+                kind = ErrorKind::Config,
+                "target has no hosts to run the tool on"
+            ),
+        }
+    }
+
+    fn do_run_tool(&self, host: &str, tool: &str, args: &[String]) -> io::Result<()> {
+        let script = [
+            self.remote_binary().as_str(),
+            "--static",
+            &self.root,
+            "--bind",
+            host,
+            "--tool",
+            tool,
+        ]
+        .into_iter()
+        .chain(args.iter().map(|s| s.as_str()))
+        .map(quote)
+        .collect::<Vec<_>>()
+        .join(" ");
+        self.do_ssh(host, &script)
+    }
+
+    pub(crate) fn status(&self) {
+        // every host has the same revision unless a deploy failed partway
+        let revision = self
+            .get_deployed_config()
+            .map(|cf| cf.revision)
+            .unwrap_or_else(|| "<unknown>".to_owned());
+        let mut result = StatusResult { pods: Vec::new() };
+        for (job, host) in self.all_hosts() {
+            let script = format!(
+                "systemctl is-active {} || true",
+                quote(&Self::unit_name(job))
+            );
+            let phase = match self.do_ssh_output(host, &script) {
+                Ok(out) => String::from_utf8_lossy(&out).trim().to_owned(),
+                Err(e) => {
+                    log::warn!("could not reach {}: {}", host, e);
+                    "unreachable".to_owned()
+                }
+            };
+            let mut status = PodStatus {
+                name: host.to_owned(),
+                job: job.to_owned(),
+                revision: revision.clone(),
+                phase,
+                health: None,
+                storage: None,
+            };
+            if status.phase == "active" {
+                match self.do_get_admin(host, "/admin/health") {
+                    Ok(health) => status.health = Some(health),
+                    Err(e) => log::warn!("could not get health of {}: {}", host, e),
+                }
+                match self.do_get_admin(host, "/admin/storage") {
+                    Ok(storage) => status.storage = Some(storage),
+                    Err(e) => log::warn!("could not get storage usage of {}: {}", host, e),
+                }
+            }
+            target::text_status(&status);
+            result.pods.push(status);
+        }
+        output::result(true, &result);
+    }
+
+    /// Collect a component's journal from every host.
+    pub(crate) fn fetch_journal(&self, component: &str) -> Vec<u8> {
+        let mut journal = Vec::new();
+        for (_, host) in self.all_hosts() {
+            let url = format!("http://{}:{}/admin/journal/{}", host, ADMIN_PORT, component);
+            let script = format!("curl -sf {}", quote(&url));
+            match self.do_ssh_output(host, &script) {
+                Ok(entries) => journal.extend(entries),
+                Err(e) => log::debug!("no journal for {} on {}: {}", component, host, e),
+            }
+        }
+        journal
+    }
+
+    /// Collect the calls observed by every host.
+    pub(crate) fn observed_calls(&self) -> Vec<DumpEdge> {
+        let mut edges = Vec::new();
+        for (_, host) in self.all_hosts() {
+            let graph = self.do_get_admin(host, "/admin/graph").and_then(|g| {
+                serde_json::from_value::<amimono_schemas::DumpGraph>(g).map_err(io::Error::other)
+            });
+            match graph {
+                Ok(graph) => edges.extend(graph.edges),
+                Err(e) => log::warn!("could not get calls observed by {}: {}", host, e),
+            }
+        }
+        edges
+    }
+
+    /// Post an RPC request to a component from the first host of its job.
+    pub(crate) fn call(
+        &self,
+        job: &str,
+        component: &str,
+        request: &str,
+    ) -> io::Result<(u16, Vec<u8>)> {
+        let Some(host) = self.hosts.get(job).and_then(|hosts| hosts.first()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no hosts for job {}", job),
+            ));
+        };
+        let url = format!("http://{}:{}/rpc/{}", host, ADMIN_PORT, component);
+        let args = call::curl_args(&url, request);
+        let script = std::iter::once("curl".to_owned())
+            .chain(args.iter().map(|a| quote(a)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        call::parse_curl(&self.do_ssh_output(host, &script)?)
+    }
+}
+
+/// Quote a string for a POSIX shell.
+/// An error for a failed ssh or rsync command. Both exit with 255 when ssh
+/// can't connect, rather than when the remote command fails.
+fn ssh_error(what: &str, status: std::process::ExitStatus) -> io::Error {
+    let kind = match status.code() {
+        Some(255) => io::ErrorKind::ConnectionRefused,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("{} exited with status {}", what, status))
+}
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
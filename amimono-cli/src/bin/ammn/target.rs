@@ -1,15 +1,27 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     io::{self, Write},
+    time::Duration,
 };
 
-use amimono_schemas::DumpConfig;
+use amimono_schemas::{
+    DumpBudget, DumpConfig, DumpEdge, DumpJob, DumpPlacement, DumpPort, DumpProtocol, DumpRollout,
+    DumpStatefulUpdate,
+};
+use serde::Serialize;
 
-use crate::{config::TargetConfig, project::Project};
+use crate::{
+    call, compat,
+    config::TargetConfig,
+    output::{self, ErrorKind},
+    project::Project,
+    r#static::StaticTarget,
+};
 
 #[allow(private_interfaces)]
 pub enum Target {
     Kubernetes(KubernetesTarget),
+    Static(StaticTarget),
 }
 
 impl Target {
@@ -19,16 +31,51 @@ impl Target {
                 context,
                 image,
                 env,
+                build,
+                rollout,
+                secrets,
+                mesh,
             }) => {
+                let secrets = secrets.to_owned().unwrap_or_default();
+                let mesh = mesh.unwrap_or(false);
+                let mut env = env.to_owned().unwrap_or_default();
+                if mesh {
+                    env.insert("AMIMONO_MESH".to_owned(), "true".to_owned());
+                }
                 let tgt = KubernetesTarget {
                     context: context.clone(),
-                    env: env.to_owned().unwrap_or_default(),
+                    env,
                     image: image.to_owned(),
+                    build: build.clone(),
+                    rollout: rollout.to_owned().unwrap_or_default(),
+                    secrets,
+                    mesh,
                 };
                 Target::Kubernetes(tgt)
             }
+            Some(TargetConfig::Static {
+                hosts,
+                user,
+                root,
+                binary,
+                env,
+                build,
+            }) => {
+                let tgt = StaticTarget {
+                    hosts: hosts.clone(),
+                    user: user.clone(),
+                    root: root
+                        .clone()
+                        .unwrap_or_else(|| StaticTarget::DEFAULT_ROOT.to_owned()),
+                    binary: binary.clone(),
+                    env: env.to_owned().unwrap_or_default(),
+                    build: build.clone(),
+                };
+                Target::Static(tgt)
+            }
             None => {
                 crate::fatal!(
+                    kind = ErrorKind::Config,
                     "unknown target. available targets: {}",
                     cf.target
                         .keys()
@@ -40,17 +87,159 @@ impl Target {
         }
     }
 
-    pub fn deploy(&self, _proj: &Project) {
+    /// Deploy the project's current revision. With `expect`, nothing is
+    /// deployed unless the revision is that one.
+    pub fn deploy(&self, proj: &Project, allow_breaking: bool, all: bool, expect: Option<&str>) {
+        match self {
+            Target::Kubernetes(target) => target.deploy(proj, allow_breaking, all, expect),
+            Target::Static(target) => target.deploy(proj, allow_breaking, all, expect),
+        }
+    }
+
+    /// Deploy the new revision alongside the running one, and switch traffic
+    /// over once it's ready and the smoke tools pass. Only Kubernetes targets
+    /// can run two revisions at once.
+    pub fn deploy_blue_green(
+        &self,
+        proj: &Project,
+        allow_breaking: bool,
+        smoke: &[String],
+        grace: Duration,
+    ) {
+        match self {
+            Target::Kubernetes(target) => {
+                target.deploy_blue_green(proj, allow_breaking, smoke, grace)
+            }
+            Target::Static(_) => crate::fatal!(
+                kind = ErrorKind::Config,
+                "blue-green deploys need a Kubernetes target, static targets only run one revision"
+            ),
+        }
+    }
+
+    pub fn status(&self) {
+        match self {
+            Target::Kubernetes(target) => target.status(),
+            Target::Static(target) => target.status(),
+        }
+    }
+
+    /// The config of the app currently deployed to the target, if any.
+    pub fn deployed_config(&self) -> Option<DumpConfig> {
+        match self {
+            Target::Kubernetes(target) => target.get_deployed_config().unwrap_or_else(|e| {
+                log::warn!("could not get deployed config: {}", e);
+                None
+            }),
+            Target::Static(target) => target.get_deployed_config(),
+        }
+    }
+
+    pub fn run_tool(&self, tool: &str, args: &[String]) {
+        // older configs don't list their tools
+        if let Some(cf) = self.deployed_config()
+            && cf.schema_version >= 2
+            && !cf.tools.iter().any(|t| t == tool)
+        {
+            crate::fatal!(
+                kind = ErrorKind::Config,
+                "the deployed app has no tool {}; its tools are: {}",
+                tool,
+                cf.tools.join(", ")
+            );
+        }
+        match self {
+            Target::Kubernetes(target) => target.run_tool(tool, args),
+            Target::Static(target) => target.run_tool(tool, args),
+        }
+    }
+
+    pub fn observed_calls(&self) -> Vec<DumpEdge> {
+        match self {
+            Target::Kubernetes(target) => target.observed_calls(),
+            Target::Static(target) => target.observed_calls(),
+        }
+    }
+
+    /// Fetch a component's request journal from every replica that has one.
+    pub fn fetch_journal(&self, component: &str) -> Vec<u8> {
+        match self {
+            Target::Kubernetes(target) => target.fetch_journal(component),
+            Target::Static(target) => target.fetch_journal(component),
+        }
+    }
+
+    /// Post an RPC request to a component in the given job, returning the
+    /// response's status and body.
+    pub fn call(&self, job: &str, component: &str, request: &str) -> io::Result<(u16, Vec<u8>)> {
+        match self {
+            Target::Kubernetes(target) => target.call(job, component, request),
+            Target::Static(target) => target.call(job, component, request),
+        }
+    }
+
+    /// Build and push the target's image with the given tag, using the
+    /// target's build command, and return the target with its image replaced
+    /// by the new one. Static targets ignore the tag, and return a target
+    /// that doesn't build again when deployed.
+    pub fn build(&self, tag: &str) -> Result<Target, String> {
         match self {
-            Target::Kubernetes(target) => target.deploy(),
+            Target::Kubernetes(target) => {
+                let image = with_tag(&target.image, tag);
+                let Some(build) = &target.build else {
+                    return Err("target has no build command".to_owned());
+                };
+                let cmd = build.replace("{image}", &image);
+                log::info!("building {}...", image);
+                log::debug!("build command: {}", cmd);
+                let status = std::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(&cmd)
+                    .stdout(output::child_stdout())
+                    .stderr(std::process::Stdio::inherit())
+                    .status()
+                    .map_err(|e| format!("failed to run build command: {}", e))?;
+                if !status.success() {
+                    return Err(format!("build command exited with status {}", status));
+                }
+                Ok(Target::Kubernetes(KubernetesTarget {
+                    image,
+                    ..target.clone()
+                }))
+            }
+            Target::Static(target) => {
+                if target.build.is_none() {
+                    return Err("target has no build command".to_owned());
+                }
+                target.run_build()?;
+                Ok(Target::Static(StaticTarget {
+                    build: None,
+                    ..target.clone()
+                }))
+            }
         }
     }
 }
 
+/// Replace an image reference's tag, or add one if it has none.
+fn with_tag(image: &str, tag: &str) -> String {
+    let name_start = image.rfind('/').map(|i| i + 1).unwrap_or(0);
+    let repo = match image[name_start..].find([':', '@']) {
+        Some(i) => &image[..name_start + i],
+        None => image,
+    };
+    format!("{}:{}", repo, tag)
+}
+
+#[derive(Clone)]
 struct KubernetesTarget {
     context: String,
     env: HashMap<String, String>,
     image: String,
+    build: Option<String>,
+    rollout: HashMap<String, DumpRollout>,
+    secrets: HashMap<String, String>,
+    mesh: bool,
 }
 
 impl KubernetesTarget {
@@ -75,19 +264,16 @@ impl KubernetesTarget {
         log::debug!("kubectl delete: {}", yaml.trim_end());
         let mut child = cmd
             .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::inherit())
-            .stderr(std::process::Stdio::inherit())
+            .stdout(output::child_stdout())
+            .stderr(std::process::Stdio::piped())
             .spawn()?;
         {
             let stdin = child.stdin.as_mut().unwrap();
             stdin.write_all(yaml.as_bytes())?;
         }
-        let status = child.wait()?;
-        if !status.success() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("kubectl exited with status {}", status),
-            ));
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(kubectl_error(output.status, &output.stderr));
         }
         Ok(())
     }
@@ -99,36 +285,30 @@ impl KubernetesTarget {
         log::debug!("kubectl apply: {}", yaml.trim_end());
         let mut child = cmd
             .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::inherit())
-            .stderr(std::process::Stdio::inherit())
+            .stdout(output::child_stdout())
+            .stderr(std::process::Stdio::piped())
             .spawn()?;
         {
             let stdin = child.stdin.as_mut().unwrap();
             stdin.write_all(yaml.as_bytes())?;
         }
-        let status = child.wait()?;
-        if !status.success() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("kubectl exited with status {}", status),
-            ));
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(kubectl_error(output.status, &output.stderr));
         }
         Ok(())
     }
 
-    fn do_wait_for_job(&self, job: &str) -> io::Result<()> {
+    fn do_wait_for_job(&self, job: &str, timeout: &str) -> io::Result<()> {
         let mut cmd = std::process::Command::new("kubectl");
         cmd.arg("--context").arg(&self.context);
         cmd.arg("wait")
             .arg("--for=condition=complete")
-            .arg("--timeout=60s")
+            .arg(format!("--timeout={}", timeout))
             .arg("job/".to_string() + job);
         let output = cmd.output()?;
         if !output.status.success() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("kubectl exited with status {}", output.status),
-            ));
+            return Err(kubectl_error(output.status, &output.stderr));
         }
         Ok(())
     }
@@ -139,14 +319,56 @@ impl KubernetesTarget {
         cmd.arg("logs").arg("job/".to_string() + job);
         let output = cmd.output()?;
         if !output.status.success() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("kubectl exited with status {}", output.status),
-            ));
+            return Err(kubectl_error(output.status, &output.stderr));
         }
         Ok(output.stdout)
     }
 
+    fn do_follow_job_logs(&self, job: &str) -> io::Result<()> {
+        let mut cmd = std::process::Command::new("kubectl");
+        cmd.arg("--context").arg(&self.context);
+        cmd.arg("logs")
+            .arg("--follow")
+            .arg("--pod-running-timeout=120s")
+            .arg("job/".to_string() + job);
+        let output = cmd
+            .stdout(output::child_stdout())
+            .stderr(std::process::Stdio::piped())
+            .output()?;
+        if !output.status.success() {
+            return Err(kubectl_error(output.status, &output.stderr));
+        }
+        Ok(())
+    }
+
+    /// Apply the target's rollout settings on top of the app's.
+    fn override_rollouts(&self, cf: &mut DumpConfig) {
+        for (job_label, rollout) in self.rollout.iter() {
+            match cf.jobs.get_mut(job_label) {
+                Some(job) => job.rollout = job.rollout.overridden_by(rollout),
+                None => log::warn!("rollout configured for unknown job {}", job_label),
+            }
+        }
+    }
+
+    /// The config of the revision being deployed, from the one embedded in
+    /// the project's binary if there is one, or else by dumping it from the
+    /// image in the cluster.
+    fn app_config(&self, proj: &Project) -> DumpConfig {
+        if let Some(cf) = proj.get_embedded_config() {
+            return cf;
+        }
+        match self.get_app_config() {
+            Ok(c) => c,
+            Err(e) => crate::fatal!(
+                kind = ErrorKind::of(&e),
+                "failed to get app config from cluster {}: {}",
+                self.context,
+                e
+            ),
+        }
+    }
+
     fn get_app_config(&self) -> io::Result<DumpConfig> {
         let yaml = self.get_yaml(|w| w.add_dump_config_job())?;
 
@@ -157,7 +379,7 @@ impl KubernetesTarget {
         self.do_apply(&yaml)?;
 
         log::info!("waiting for dump-config job to complete...");
-        self.do_wait_for_job("dump-config")?;
+        self.do_wait_for_job("dump-config", "60s")?;
 
         log::info!("getting dump-config output");
         let output = self.do_get_job_output("dump-config")?;
@@ -165,44 +387,243 @@ impl KubernetesTarget {
         log::info!("cleaning up dump-config job...");
         self.do_delete(&yaml)?;
 
-        serde_json::from_slice(&output[..]).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("failed to parse dump config JSON: {}", e),
-            )
-        })
+        crate::project::parse_app_config(&output[..]).map_err(|e| io::Error::other(e))
     }
 
-    fn deploy(&self) {
-        let cf = match self.get_app_config() {
-            Ok(c) => c,
+    fn do_wait_for_rollout(&self, kind: &str, name: &str) -> io::Result<()> {
+        let mut cmd = std::process::Command::new("kubectl");
+        cmd.arg("--context").arg(&self.context);
+        cmd.arg("rollout")
+            .arg("status")
+            .arg("--timeout=300s")
+            .arg(format!("{}/{}", kind, name));
+        let output = cmd
+            .stdout(output::child_stdout())
+            .stderr(std::process::Stdio::piped())
+            .output()?;
+        if !output.status.success() {
+            return Err(kubectl_error(output.status, &output.stderr));
+        }
+        Ok(())
+    }
+
+    fn do_rollout_undo(&self, kind: &str, name: &str) -> io::Result<()> {
+        let mut cmd = std::process::Command::new("kubectl");
+        cmd.arg("--context").arg(&self.context);
+        cmd.arg("rollout")
+            .arg("undo")
+            .arg(format!("{}/{}", kind, name));
+        let output = cmd
+            .stdout(output::child_stdout())
+            .stderr(std::process::Stdio::piped())
+            .output()?;
+        if !output.status.success() {
+            return Err(kubectl_error(output.status, &output.stderr));
+        }
+        Ok(())
+    }
+
+    /// The Deployments and StatefulSets matching a label selector, as
+    /// `kind/name`.
+    fn do_list_workloads(&self, selector: &str) -> io::Result<Vec<String>> {
+        let mut cmd = std::process::Command::new("kubectl");
+        cmd.arg("--context").arg(&self.context);
+        cmd.args([
+            "get",
+            "deployment,statefulset",
+            "-l",
+            selector,
+            "-o",
+            "name",
+        ]);
+        let output = cmd.output()?;
+        if !output.status.success() {
+            return Err(kubectl_error(output.status, &output.stderr));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|l| l.trim().to_owned())
+            .filter(|l| !l.is_empty())
+            .collect())
+    }
+
+    /// Delete Deployments and StatefulSets by `kind/name`, without waiting
+    /// for their pods to exit.
+    fn do_delete_workloads(&self, workloads: &[String]) -> io::Result<()> {
+        let mut cmd = std::process::Command::new("kubectl");
+        cmd.arg("--context").arg(&self.context);
+        cmd.args(["delete", "--wait=false", "--ignore-not-found=true"]);
+        cmd.args(workloads);
+        let output = cmd
+            .stdout(output::child_stdout())
+            .stderr(std::process::Stdio::piped())
+            .output()?;
+        if !output.status.success() {
+            return Err(kubectl_error(output.status, &output.stderr));
+        }
+        Ok(())
+    }
+
+    /// Get the config of the most recently deployed revision, if any.
+    fn get_deployed_config(&self) -> io::Result<Option<DumpConfig>> {
+        let mut cmd = std::process::Command::new("kubectl");
+        cmd.arg("--context").arg(&self.context);
+        cmd.args([
+            "get",
+            "configmap",
+            DEPLOYED_CONFIG,
+            "--ignore-not-found",
+            "-o",
+            "json",
+        ]);
+        let output = cmd.output()?;
+        if !output.status.success() {
+            return Err(kubectl_error(output.status, &output.stderr));
+        }
+        if output.stdout.iter().all(|b| b.is_ascii_whitespace()) {
+            return Ok(None);
+        }
+        let cm: serde_json::Value = serde_json::from_slice(&output.stdout[..])
+            .map_err(|e| io::Error::other(format!("bad JSON: {}", e)))?;
+        let data = cm["data"]["config.json"].as_str().unwrap_or("{}");
+        serde_json::from_str(data)
+            .map(Some)
+            .map_err(|e| io::Error::other(format!("failed to parse deployed config: {}", e)))
+    }
+}
+
+/// Refuse to deploy a revision other than the expected one, e.g. when a
+/// pipeline that's promoting an older revision is resumed after a rebuild.
+pub(crate) fn check_revision(cf: &DumpConfig, expect: Option<&str>) {
+    if let Some(expected) = expect.filter(|r| *r != cf.revision) {
+        crate::fatal!(
+            kind = ErrorKind::Conflict,
+            "the revision to deploy is {}, but {} was expected; not deploying",
+            cf.revision,
+            expected
+        );
+    }
+}
+
+/// Refuse to deploy a revision with breaking API changes relative to the
+/// deployed one, unless they're explicitly allowed.
+pub(crate) fn check_compat(
+    deployed: Option<&DumpConfig>,
+    cf: &DumpConfig,
+    allow_breaking: bool,
+) -> compat::Changes {
+    let Some(deployed) = deployed else {
+        log::info!("no deployed revision found, skipping API compatibility check");
+        return compat::Changes::default();
+    };
+
+    log::info!(
+        "checking API compatibility of {} against deployed {}...",
+        cf.revision,
+        deployed.revision
+    );
+    let changes = compat::compare(deployed, cf);
+    for warning in changes.warnings.iter() {
+        log::warn!("{}", warning);
+    }
+    if changes.breaking.is_empty() {
+        return changes;
+    }
+    for change in changes.breaking.iter() {
+        log::error!("breaking change: {}", change);
+    }
+    if !allow_breaking {
+        crate::fatal!(
+            kind = ErrorKind::Conflict,
+            "refusing to deploy {} breaking API change(s), use --allow-breaking to deploy anyway",
+            changes.breaking.len()
+        );
+    }
+    log::warn!("deploying with breaking API changes");
+    changes
+}
+
+impl KubernetesTarget {
+    fn deploy(&self, proj: &Project, allow_breaking: bool, all: bool, expect: Option<&str>) {
+        let mut cf = self.app_config(proj);
+        check_revision(&cf, expect);
+
+        let deployed = match self.get_deployed_config() {
+            Ok(d) => d,
             Err(e) => crate::fatal!(
-                "failed to get app config from cluster {}: {}",
-                self.context,
+                kind = ErrorKind::of(&e),
+                "failed to get deployed config: {}",
+                e
+            ),
+        };
+        let changes = check_compat(deployed.as_ref(), &cf, allow_breaking);
+        self.override_rollouts(&mut cf);
+
+        // after a blue-green deploy, jobs run in workloads named for their
+        // revision, so every job is rolled into its usual one before those
+        // are removed
+        let blue_green = match self.do_list_workloads(BLUE_GREEN_LABEL) {
+            Ok(w) => w,
+            Err(e) => crate::fatal!(
+                kind = ErrorKind::of(&e),
+                "failed to list blue-green workloads: {}",
                 e
             ),
         };
+        let all = all || !blue_green.is_empty();
+
+        // jobs whose digest and rollout match the deployed ones are left
+        // running as-is. the digest covers the app's rollout settings, but not
+        // the target's. run-once jobs run for every revision
+        let unchanged = |job: &str| {
+            let digest = cf.jobs[job].digest.as_ref();
+            let old = deployed.as_ref().and_then(|d| d.jobs.get(job));
+            !all && !cf.jobs[job].runs_once
+                && digest.is_some()
+                && old.and_then(|j| j.digest.as_ref()) == digest
+                && old.map(|j| &j.rollout) == Some(&cf.jobs[job].rollout)
+        };
+        let skipped = cf
+            .jobs
+            .keys()
+            .filter(|j| unchanged(j))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !skipped.is_empty() {
+            log::info!("skipping unchanged jobs: {}", skipped.join(", "));
+        }
+
+        let waves = match job_waves(&cf) {
+            Ok(w) => w,
+            Err(e) => crate::fatal!(kind = ErrorKind::Config, "invalid job dependencies: {}", e),
+        };
 
         log::info!("generating Kubernetes objects from app config...");
         let yaml = self.get_yaml(|w| {
             for (job_label, job) in cf.jobs.iter() {
+                // in mesh mode, the job's service takes the place of a
+                // component's service with the same name
+                if self.mesh {
+                    w.add_mesh_service(job_label, None, job)?;
+                }
                 for (comp_label, comp) in job.components.iter() {
-                    if let Some(port) = comp.ports.iter().next().copied() {
-                        w.add_service(&job_label, &cf.revision, &comp_label, port)?;
+                    if self.mesh && comp_label == job_label {
+                        continue;
+                    }
+                    let ports = comp
+                        .ports
+                        .iter()
+                        .filter(|p| p.port != 0)
+                        .collect::<Vec<_>>();
+                    if !ports.is_empty() {
+                        w.add_service(job_label, None, comp_label, &ports)?;
                     }
                 }
-            }
-            for (job_label, job) in cf.jobs.iter() {
-                let ports = job
-                    .components
-                    .values()
-                    .flat_map(|x| x.ports.iter().cloned())
-                    .filter(|&p| p != 0)
-                    .collect::<Vec<u16>>();
                 if job.is_stateful {
-                    w.add_statefulset(&job_label, &cf.revision, &ports[..])?;
-                } else {
-                    w.add_deployment(&job_label, &cf.revision, &ports[..])?;
+                    w.add_headless_service(job_label)?;
+                }
+                if let Some(min_available) = job.rollout.min_available {
+                    w.add_disruption_budget(job_label, min_available)?;
                 }
             }
             Ok(())
@@ -216,121 +637,1351 @@ impl KubernetesTarget {
             ),
         };
 
-        log::info!("running kubectl apply...");
+        log::info!("running kubectl apply for services...");
         if let Err(e) = self.do_apply(&yaml) {
-            crate::fatal!("apply failed: {}", e);
+            crate::fatal!(kind = ErrorKind::of(&e), "apply failed: {}", e);
         }
 
-        log::info!("all done!");
-    }
-}
+        // jobs that no longer set minAvailable shouldn't keep their old budget
+        let removed = cf
+            .jobs
+            .iter()
+            .filter(|(label, job)| {
+                let old = deployed.as_ref().and_then(|d| d.jobs.get(*label));
+                job.rollout.min_available.is_none()
+                    && old.is_some_and(|j| j.rollout.min_available.is_some())
+            })
+            .map(|(label, _)| label)
+            .collect::<Vec<_>>();
+        if !removed.is_empty() {
+            let yaml = self.get_yaml(|w| {
+                for job_label in removed.iter() {
+                    w.add_disruption_budget_ref(job_label)?;
+                }
+                Ok(())
+            });
+            log::info!("removing disruption budgets...");
+            if let Err(e) = yaml.and_then(|y| self.do_delete(&y)) {
+                log::warn!("failed to remove disruption budgets: {}", e);
+            }
+        }
 
-struct KubernetesWriter<'w, W> {
-    tgt: &'w KubernetesTarget,
-    out: &'w mut W,
-}
+        let waves = waves
+            .into_iter()
+            .map(|wave| {
+                wave.into_iter()
+                    .filter(|j| !unchanged(j))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|wave| !wave.is_empty())
+            .collect::<Vec<_>>();
 
-impl<'w, W: io::Write> KubernetesWriter<'w, W> {
-    fn new(tgt: &'w KubernetesTarget, out: &'w mut W) -> Self {
-        KubernetesWriter { tgt, out }
-    }
+        for (i, wave) in waves.iter().enumerate() {
+            let yaml = self.get_yaml(|w| {
+                for job_label in wave.iter() {
+                    let job = &cf.jobs[job_label];
+                    if job.runs_once {
+                        w.add_run_once_job(job_label, &cf.revision, job)?;
+                    } else if job.is_stateful {
+                        w.add_statefulset(job_label, &cf.revision, job, false)?;
+                    } else {
+                        w.add_deployment(job_label, &cf.revision, job, false)?;
+                    }
+                }
+                Ok(())
+            });
+            let yaml = match yaml {
+                Ok(y) => y,
+                Err(e) => crate::fatal!(
+                    "failed to generate Kubernetes objects for context {}: {}",
+                    self.context,
+                    e
+                ),
+            };
 
-    fn add_dump_config_job(&mut self) -> io::Result<()> {
-        writeln!(self.out, "---")?;
-        writeln!(self.out, "apiVersion: batch/v1")?;
-        writeln!(self.out, "kind: Job")?;
-        writeln!(self.out, "metadata:")?;
-        writeln!(self.out, "  name: dump-config")?;
-        writeln!(self.out, "spec:")?;
-        writeln!(self.out, "  template:")?;
-        writeln!(self.out, "    spec:")?;
-        writeln!(self.out, "      containers:")?;
-        writeln!(self.out, "        - name: dump-config")?;
-        writeln!(self.out, "          image: {}", self.tgt.image)?;
-        writeln!(self.out, "          imagePullPolicy: IfNotPresent")?;
-        writeln!(self.out, "          args: [\"--dump-config\"]")?;
-        writeln!(self.out, "          env:")?;
-        writeln!(self.out, "            - name: RUST_LOG")?;
-        writeln!(self.out, "              value: warn")?;
-        writeln!(self.out, "            - name: RUST_BACKTRACE")?;
-        writeln!(self.out, "              value: \"1\"")?;
-        writeln!(self.out, "      restartPolicy: Never")?;
-        Ok(())
-    }
+            log::info!("running kubectl apply for jobs: {}", wave.join(", "));
+            if let Err(e) = self.do_apply(&yaml) {
+                crate::fatal!(kind = ErrorKind::of(&e), "apply failed: {}", e);
+            }
 
-    fn add_podtemplatespec(&mut self, job: &str, ports: &[u16]) -> io::Result<()> {
-        writeln!(self.out, "      containers:")?;
-        writeln!(self.out, "        - name: {}", job)?;
-        writeln!(self.out, "          image: {}", self.tgt.image)?;
-        writeln!(self.out, "          imagePullPolicy: IfNotPresent")?;
-        if !ports.is_empty() {
-            writeln!(self.out, "          ports:")?;
-            for port in ports {
-                writeln!(self.out, "            - containerPort: {}", port)?;
+            // run-once jobs are waited on even in the last wave, so a failure
+            // fails the deploy
+            for job_label in wave.iter().filter(|j| cf.jobs[*j].runs_once) {
+                log::info!("waiting for {} to complete...", job_label);
+                let name = revision_name(job_label, &cf.revision);
+                if let Err(e) = self.do_wait_for_job(&name, RUN_ONCE_TIMEOUT) {
+                    crate::fatal!(
+                        kind = ErrorKind::of(&e),
+                        "job {} did not complete: {}. once the problem is fixed, \
+                         delete job/{} to run it again",
+                        job_label,
+                        e,
+                        name
+                    );
+                }
+            }
+
+            // the last wave has no dependents, so there is nothing to wait
+            // for, unless blue-green workloads are still serving or smoke
+            // tests are about to run
+            if i + 1 == waves.len() && blue_green.is_empty() && cf.smoke_tests.is_empty() {
+                break;
+            }
+
+            for job_label in wave.iter().filter(|j| !cf.jobs[*j].runs_once) {
+                let kind = match cf.jobs[job_label].is_stateful {
+                    true => "statefulset",
+                    false => "deployment",
+                };
+                log::info!("waiting for {} to become ready...", job_label);
+                if let Err(e) = self.do_wait_for_rollout(kind, job_label) {
+                    crate::fatal!(
+                        kind = ErrorKind::of(&e),
+                        "job {} did not become ready: {}",
+                        job_label,
+                        e
+                    );
+                }
             }
         }
-        writeln!(self.out, "          args: [\"--job\", \"{}\"]", job)?;
-        if !self.tgt.env.is_empty() {
-            writeln!(self.out, "          env:")?;
-            for (key, value) in self.tgt.env.iter() {
-                assert!(!value.contains('"'));
-                writeln!(self.out, "            - name: {}", key)?;
-                writeln!(self.out, "              value: \"{}\"", value)?;
+
+        // the deployed config is only recorded once the smoke tests pass, so
+        // that after a rollback it's still the previous revision's
+        self.smoke_test_rolled(&cf, &waves.iter().flatten().collect::<Vec<_>>());
+
+        if !blue_green.is_empty() {
+            log::info!("removing blue-green workloads: {}", blue_green.join(", "));
+            if let Err(e) = self.do_delete_workloads(&blue_green) {
+                log::warn!("failed to remove blue-green workloads: {}", e);
             }
         }
-        Ok(())
-    }
 
-    fn add_deployment(&mut self, job: &str, rev: &str, ports: &[u16]) -> io::Result<()> {
-        writeln!(self.out, "---")?;
-        writeln!(self.out, "apiVersion: apps/v1")?;
-        writeln!(self.out, "kind: Deployment")?;
-        writeln!(self.out, "metadata:")?;
-        writeln!(self.out, "  name: {}", job)?;
-        writeln!(self.out, "  labels:")?;
-        writeln!(self.out, "    amimono-job: {}", job)?;
-        writeln!(self.out, "    amimono-rev: \"{}\"", rev)?;
-        writeln!(self.out, "spec:")?;
-        writeln!(self.out, "  replicas: 1")?;
-        writeln!(self.out, "  selector:")?;
-        writeln!(self.out, "    matchLabels:")?;
-        writeln!(self.out, "      amimono-job: {}", job)?;
-        writeln!(self.out, "  template:")?;
-        writeln!(self.out, "    metadata:")?;
-        writeln!(self.out, "      labels:")?;
-        writeln!(self.out, "        amimono-job: {}", job)?;
-        writeln!(self.out, "        amimono-rev: \"{}\"", rev)?;
-        writeln!(self.out, "    spec:")?;
-        self.add_podtemplatespec(job, ports)?;
-        Ok(())
+        log::info!("recording deployed config...");
+        let yaml = self.get_yaml(|w| w.add_deployed_config(&cf));
+        if let Err(e) = yaml.and_then(|y| self.do_apply(&y)) {
+            log::warn!("failed to record deployed config: {}", e);
+        }
+
+        log::info!("all done!");
+        output::result(
+            true,
+            &DeployResult {
+                revision: &cf.revision,
+                deployed: waves.iter().flatten().cloned().collect(),
+                skipped,
+                breaking_changes: changes.breaking,
+                warnings: changes.warnings,
+            },
+        );
     }
+}
 
-    fn add_statefulset(&mut self, job: &str, rev: &str, ports: &[u16]) -> io::Result<()> {
-        writeln!(self.out, "---")?;
-        writeln!(self.out, "apiVersion: apps/v1")?;
-        writeln!(self.out, "kind: StatefulSet")?;
-        writeln!(self.out, "metadata:")?;
-        writeln!(self.out, "  name: {}", job)?;
-        writeln!(self.out, "  labels:")?;
-        writeln!(self.out, "    amimono-job: {}", job)?;
-        writeln!(self.out, "    amimono-rev: \"{}\"", rev)?;
-        writeln!(self.out, "spec:")?;
-        writeln!(self.out, "  serviceName: {}", job)?;
-        writeln!(self.out, "  replicas: 1")?;
-        writeln!(self.out, "  selector:")?;
-        writeln!(self.out, "    matchLabels:")?;
-        writeln!(self.out, "      amimono-job: {}", job)?;
-        writeln!(self.out, "  template:")?;
-        writeln!(self.out, "    metadata:")?;
-        writeln!(self.out, "      labels:")?;
+impl KubernetesTarget {
+    /// Deploy a revision alongside the running one, in workloads named for
+    /// the revision, and switch the services over to it once it's ready and
+    /// its smoke tools have passed. The previous revision keeps serving
+    /// until then, and is removed after the grace period.
+    fn deploy_blue_green(
+        &self,
+        proj: &Project,
+        allow_breaking: bool,
+        smoke: &[String],
+        grace: Duration,
+    ) {
+        let mut cf = self.app_config(proj);
+
+        let deployed = match self.get_deployed_config() {
+            Ok(d) => d,
+            Err(e) => crate::fatal!(
+                kind = ErrorKind::of(&e),
+                "failed to get deployed config: {}",
+                e
+            ),
+        };
+        let changes = check_compat(deployed.as_ref(), &cf, allow_breaking);
+        self.override_rollouts(&mut cf);
+        let rev = cf.revision.clone();
+
+        let waves = match job_waves(&cf) {
+            Ok(w) => w,
+            Err(e) => crate::fatal!(kind = ErrorKind::Config, "invalid job dependencies: {}", e),
+        };
+
+        // services that don't select a revision are left alone until the
+        // switch, so the running revision keeps serving
+        log::info!("generating Kubernetes objects from app config...");
+        let yaml = self.get_yaml(|w| {
+            for (job_label, job) in cf.jobs.iter() {
+                if job.is_stateful {
+                    w.add_headless_service(job_label)?;
+                }
+                if let Some(min_available) = job.rollout.min_available {
+                    w.add_disruption_budget(job_label, min_available)?;
+                }
+            }
+            Ok(())
+        });
+        let yaml = match yaml {
+            Ok(y) => y,
+            Err(e) => crate::fatal!(
+                "failed to generate Kubernetes objects for context {}: {}",
+                self.context,
+                e
+            ),
+        };
+        if !yaml.is_empty() {
+            log::info!("running kubectl apply for headless services and budgets...");
+            if let Err(e) = self.do_apply(&yaml) {
+                crate::fatal!(kind = ErrorKind::of(&e), "apply failed: {}", e);
+            }
+        }
+
+        let not_ready = |e: &dyn std::fmt::Display| {
+            format!(
+                "{}. the previous revision is still serving; remove {} with \
+                 `kubectl delete deployment,statefulset -l amimono-rev={}`",
+                e, rev, rev
+            )
+        };
+
+        for wave in waves.iter() {
+            let yaml = self.get_yaml(|w| {
+                for job_label in wave.iter() {
+                    let job = &cf.jobs[job_label];
+                    if job.runs_once {
+                        w.add_run_once_job(job_label, &rev, job)?;
+                    } else if job.is_stateful {
+                        w.add_statefulset(job_label, &rev, job, true)?;
+                    } else {
+                        w.add_deployment(job_label, &rev, job, true)?;
+                    }
+                }
+                Ok(())
+            });
+            let yaml = match yaml {
+                Ok(y) => y,
+                Err(e) => crate::fatal!(
+                    "failed to generate Kubernetes objects for context {}: {}",
+                    self.context,
+                    e
+                ),
+            };
+
+            log::info!("running kubectl apply for jobs: {}", wave.join(", "));
+            if let Err(e) = self.do_apply(&yaml) {
+                crate::fatal!(kind = ErrorKind::of(&e), "apply failed: {}", not_ready(&e));
+            }
+
+            // every wave is waited on, since the services are only switched
+            // once the whole revision is ready
+            for job_label in wave.iter() {
+                let job = &cf.jobs[job_label];
+                let name = revision_name(job_label, &rev);
+                let res = match (job.runs_once, job.is_stateful) {
+                    (true, _) => {
+                        log::info!("waiting for {} to complete...", job_label);
+                        self.do_wait_for_job(&name, RUN_ONCE_TIMEOUT)
+                    }
+                    (false, stateful) => {
+                        log::info!("waiting for {} to become ready...", job_label);
+                        let kind = if stateful {
+                            "statefulset"
+                        } else {
+                            "deployment"
+                        };
+                        self.do_wait_for_rollout(kind, &name)
+                    }
+                };
+                if let Err(e) = res {
+                    crate::fatal!(
+                        kind = ErrorKind::of(&e),
+                        "job {} did not become ready: {}",
+                        job_label,
+                        not_ready(&e)
+                    );
+                }
+            }
+        }
+
+        // tools run with the new revision, so they only discover its
+        // replicas. a failing smoke tool stops ammn before the switch
+        let smoke = cf
+            .smoke_tests
+            .iter()
+            .chain(smoke.iter().filter(|t| !cf.smoke_tests.contains(t)))
+            .collect::<Vec<_>>();
+        for tool in smoke {
+            log::info!("smoke testing {} with {}...", rev, tool);
+            output::quietly(|| self.run_tool(tool, &[]));
+        }
+
+        // every service is switched in a single apply
+        log::info!("switching services to {}...", rev);
+        let yaml = self.get_yaml(|w| {
+            for (job_label, job) in cf.jobs.iter() {
+                if self.mesh {
+                    w.add_mesh_service(job_label, Some(&rev), job)?;
+                }
+                for (comp_label, comp) in job.components.iter() {
+                    if self.mesh && comp_label == job_label {
+                        continue;
+                    }
+                    let ports = comp
+                        .ports
+                        .iter()
+                        .filter(|p| p.port != 0)
+                        .collect::<Vec<_>>();
+                    if !ports.is_empty() {
+                        w.add_service(job_label, Some(&rev), comp_label, &ports)?;
+                    }
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = yaml.and_then(|y| match y.is_empty() {
+            true => Ok(()),
+            false => self.do_apply(&y),
+        }) {
+            crate::fatal!(
+                kind = ErrorKind::of(&e),
+                "failed to switch services: {}",
+                not_ready(&e)
+            );
+        }
+
+        log::info!("recording deployed config...");
+        let yaml = self.get_yaml(|w| w.add_deployed_config(&cf));
+        if let Err(e) = yaml.and_then(|y| self.do_apply(&y)) {
+            log::warn!("failed to record deployed config: {}", e);
+        }
+
+        // both blue-green workloads of other revisions and the usual ones of
+        // rolling deploys
+        let mut old = Vec::new();
+        for selector in [
+            format!("amimono-job,amimono-rev!={}", rev),
+            format!("amimono-job,!{}", BLUE_GREEN_LABEL),
+        ] {
+            match self.do_list_workloads(&selector) {
+                Ok(w) => old.extend(w),
+                Err(e) => log::warn!("failed to list previous workloads: {}", e),
+            }
+        }
+        old.sort();
+        old.dedup();
+        if !old.is_empty() {
+            log::info!(
+                "waiting {}s before removing previous workloads: {}",
+                grace.as_secs(),
+                old.join(", ")
+            );
+            std::thread::sleep(grace);
+            if let Err(e) = self.do_delete_workloads(&old) {
+                log::warn!("failed to remove previous workloads: {}", e);
+            }
+        }
+
+        log::info!("all done!");
+        output::result(
+            true,
+            &DeployResult {
+                revision: &rev,
+                deployed: cf.jobs.keys().cloned().collect(),
+                skipped: Vec::new(),
+                breaking_changes: changes.breaking,
+                warnings: changes.warnings,
+            },
+        );
+    }
+}
+
+impl KubernetesTarget {
+    fn run_tool(&self, tool: &str, args: &[String]) {
+        let job = tool_job_name(tool);
+        if !self.run_tool_job(tool, args) {
+            crate::fatal!(
+                "tool {} failed. the job has been left in place for inspection: job/{}",
+                tool,
+                job
+            );
+        }
+        output::result(true, &ToolResult { tool, job: &job });
+    }
+
+    /// Run a tool as a Kubernetes Job, returning whether it succeeded. Failed
+    /// jobs are left in place for inspection.
+    fn run_tool_job(&self, tool: &str, args: &[String]) -> bool {
+        let job = tool_job_name(tool);
+        let yaml = match self.get_yaml(|w| w.add_tool_job(&job, tool, args)) {
+            Ok(y) => y,
+            Err(e) => crate::fatal!("failed to generate tool job: {}", e),
+        };
+
+        log::info!("cleaning up any existing {} jobs...", job);
+        if let Err(e) = self.do_delete(&yaml) {
+            crate::fatal!(
+                kind = ErrorKind::of(&e),
+                "failed to clean up tool job: {}",
+                e
+            );
+        }
+
+        log::info!("creating {} job...", job);
+        if let Err(e) = self.do_apply(&yaml) {
+            crate::fatal!(kind = ErrorKind::of(&e), "failed to create tool job: {}", e);
+        }
+
+        log::info!("streaming {} logs...", job);
+        if let Err(e) = self.do_follow_job_logs(&job) {
+            log::warn!("failed to stream logs: {}", e);
+        }
+
+        log::info!("waiting for {} job to finish...", job);
+        let succeeded = loop {
+            let status = match self.do_get_json(&["get", "job", &job, "-o", "json"]) {
+                Ok(j) => j["status"].clone(),
+                Err(e) => crate::fatal!(
+                    kind = ErrorKind::of(&e),
+                    "failed to get tool job status: {}",
+                    e
+                ),
+            };
+            if status["succeeded"].as_u64().unwrap_or(0) > 0 {
+                break true;
+            }
+            if status["failed"].as_u64().unwrap_or(0) > 0 {
+                break false;
+            }
+            std::thread::sleep(std::time::Duration::from_secs(2));
+        };
+
+        if !succeeded {
+            return false;
+        }
+
+        log::info!("cleaning up {} job...", job);
+        if let Err(e) = self.do_delete(&yaml) {
+            log::warn!("failed to clean up tool job: {}", e);
+        }
+
+        log::info!("tool {} finished successfully", tool);
+        true
+    }
+
+    /// Run the app's smoke tests against a newly deployed revision, undoing
+    /// the rollouts of the jobs that were rolled if any of them fail.
+    fn smoke_test_rolled(&self, cf: &DumpConfig, rolled: &[&String]) {
+        for tool in cf.smoke_tests.iter() {
+            log::info!("smoke testing {} with {}...", cf.revision, tool);
+            if self.run_tool_job(tool, &[]) {
+                continue;
+            }
+            let rolled = rolled
+                .iter()
+                .filter(|j| !cf.jobs[j.as_str()].runs_once)
+                .collect::<Vec<_>>();
+            log::error!(
+                "smoke test {} failed, rolling back: {}",
+                tool,
+                rolled
+                    .iter()
+                    .map(|j| j.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            let mut failed = Vec::new();
+            for job_label in rolled.iter() {
+                let kind = match cf.jobs[job_label.as_str()].is_stateful {
+                    true => "statefulset",
+                    false => "deployment",
+                };
+                let res = self
+                    .do_rollout_undo(kind, job_label)
+                    .and_then(|_| self.do_wait_for_rollout(kind, job_label));
+                if let Err(e) = res {
+                    log::error!("failed to roll back {}: {}", job_label, e);
+                    failed.push(job_label.as_str());
+                }
+            }
+            let job = tool_job_name(tool);
+            match failed.is_empty() {
+                true => crate::fatal!(
+                    "smoke test {} failed, and {} was rolled back. the job has been \
+                     left in place for inspection: job/{}",
+                    tool,
+                    cf.revision,
+                    job
+                ),
+                false => crate::fatal!(
+                    "smoke test {} failed, and {} could not be rolled back. the job \
+                     has been left in place for inspection: job/{}",
+                    tool,
+                    failed.join(", "),
+                    job
+                ),
+            }
+        }
+    }
+
+    fn do_get_bytes(&self, args: &[&str]) -> io::Result<Vec<u8>> {
+        let mut cmd = std::process::Command::new("kubectl");
+        cmd.arg("--context").arg(&self.context);
+        cmd.args(args);
+        let output = cmd.output()?;
+        if !output.status.success() {
+            return Err(kubectl_error(output.status, &output.stderr));
+        }
+        Ok(output.stdout)
+    }
+
+    fn do_get_json(&self, args: &[&str]) -> io::Result<serde_json::Value> {
+        let output = self.do_get_bytes(args)?;
+        serde_json::from_slice(&output[..])
+            .map_err(|e| io::Error::other(format!("bad JSON: {}", e)))
+    }
+
+    /// Fetch a path from a pod's admin endpoint, via the API server proxy.
+    fn do_get_admin(
+        &self,
+        namespace: &str,
+        pod: &str,
+        path: &str,
+    ) -> io::Result<serde_json::Value> {
+        let url = format!(
+            "/api/v1/namespaces/{}/pods/{}:{}/proxy{}",
+            namespace, pod, ADMIN_PORT, path
+        );
+        self.do_get_json(&["get", "--raw", &url])
+    }
+
+    fn status(&self) {
+        let pods = match self.do_get_json(&["get", "pods", "-l", "amimono-job", "-o", "json"]) {
+            Ok(p) => p,
+            Err(e) => crate::fatal!(
+                kind = ErrorKind::of(&e),
+                "failed to list pods in {}: {}",
+                self.context,
+                e
+            ),
+        };
+
+        let items = pods["items"].as_array().cloned().unwrap_or_default();
+        if items.is_empty() {
+            log::info!("no amimono pods found in {}", self.context);
+        }
+
+        let mut result = StatusResult { pods: Vec::new() };
+        for pod in items.iter() {
+            let name = pod["metadata"]["name"].as_str().unwrap_or("<unknown>");
+            let namespace = pod["metadata"]["namespace"].as_str().unwrap_or("default");
+            let job = pod["metadata"]["labels"]["amimono-job"]
+                .as_str()
+                .unwrap_or("<unknown>");
+            let rev = pod["metadata"]["labels"]["amimono-rev"]
+                .as_str()
+                .unwrap_or("<unknown>");
+            let phase = pod["status"]["phase"].as_str().unwrap_or("Unknown");
+
+            let mut status = PodStatus {
+                name: name.to_owned(),
+                job: job.to_owned(),
+                revision: rev.to_owned(),
+                phase: phase.to_owned(),
+                health: None,
+                storage: None,
+            };
+            if phase != "Running" {
+                text_status(&status);
+                result.pods.push(status);
+                continue;
+            }
+
+            match self.do_get_admin(namespace, name, "/admin/health") {
+                Ok(health) => status.health = Some(health),
+                Err(e) => log::warn!("could not get health of {}: {}", name, e),
+            }
+            match self.do_get_admin(namespace, name, "/admin/storage") {
+                Ok(storage) => status.storage = Some(storage),
+                Err(e) => log::warn!("could not get storage usage of {}: {}", name, e),
+            }
+            text_status(&status);
+            result.pods.push(status);
+        }
+
+        output::result(true, &result);
+    }
+
+    /// Collect a component's journal from every running pod.
+    fn fetch_journal(&self, component: &str) -> Vec<u8> {
+        let pods = match self.do_get_json(&["get", "pods", "-l", "amimono-job", "-o", "json"]) {
+            Ok(p) => p,
+            Err(e) => crate::fatal!(
+                kind = ErrorKind::of(&e),
+                "failed to list pods in {}: {}",
+                self.context,
+                e
+            ),
+        };
+
+        let mut journal = Vec::new();
+        for pod in pods["items"].as_array().into_iter().flatten() {
+            let name = pod["metadata"]["name"].as_str().unwrap_or("<unknown>");
+            let namespace = pod["metadata"]["namespace"].as_str().unwrap_or("default");
+            if pod["status"]["phase"].as_str() != Some("Running") {
+                continue;
+            }
+            let url = format!(
+                "/api/v1/namespaces/{}/pods/{}:{}/proxy/admin/journal/{}",
+                namespace, name, ADMIN_PORT, component
+            );
+            match self.do_get_bytes(&["get", "--raw", &url]) {
+                Ok(entries) => journal.extend(entries),
+                Err(e) => log::debug!("no journal for {} on {}: {}", component, name, e),
+            }
+        }
+        journal
+    }
+
+    /// Collect the calls observed by every running pod.
+    fn observed_calls(&self) -> Vec<DumpEdge> {
+        let pods = match self.do_get_json(&["get", "pods", "-l", "amimono-job", "-o", "json"]) {
+            Ok(p) => p,
+            Err(e) => crate::fatal!(
+                kind = ErrorKind::of(&e),
+                "failed to list pods in {}: {}",
+                self.context,
+                e
+            ),
+        };
+
+        let mut edges = Vec::new();
+        for pod in pods["items"].as_array().into_iter().flatten() {
+            let name = pod["metadata"]["name"].as_str().unwrap_or("<unknown>");
+            let namespace = pod["metadata"]["namespace"].as_str().unwrap_or("default");
+            if pod["status"]["phase"].as_str() != Some("Running") {
+                continue;
+            }
+            let graph = self
+                .do_get_admin(namespace, name, "/admin/graph")
+                .and_then(|g| {
+                    serde_json::from_value::<amimono_schemas::DumpGraph>(g)
+                        .map_err(|e| io::Error::other(e))
+                });
+            match graph {
+                Ok(graph) => edges.extend(graph.edges),
+                Err(e) => log::warn!("could not get calls observed by {}: {}", name, e),
+            }
+        }
+        edges
+    }
+
+    /// Post an RPC request to a component by port-forwarding to a running pod
+    /// of its job.
+    fn call(&self, job: &str, component: &str, request: &str) -> io::Result<(u16, Vec<u8>)> {
+        let selector = format!("amimono-job={}", job);
+        let pods = self.do_get_json(&[
+            "get",
+            "pods",
+            "-l",
+            &selector,
+            "--field-selector=status.phase=Running",
+            "-o",
+            "json",
+        ])?;
+        let pod = pods["items"].as_array().and_then(|items| items.first());
+        let (Some(name), Some(namespace)) = (
+            pod.and_then(|p| p["metadata"]["name"].as_str()),
+            pod.and_then(|p| p["metadata"]["namespace"].as_str()),
+        ) else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("no running pods for job {}", job),
+            ));
+        };
+
+        let mut forward = std::process::Command::new("kubectl")
+            .arg("--context")
+            .arg(&self.context)
+            .args(["-n", namespace, "port-forward"])
+            .arg(format!("pod/{}", name))
+            .arg(format!(":{}", ADMIN_PORT))
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::inherit())
+            .spawn()?;
+        let res = (|| {
+            // kubectl prints the local port it picked once it's listening,
+            // then a line per connection, so the rest of its output is
+            // drained in the background rather than closing the pipe on it
+            let stdout = forward.stdout.take().expect("stdout is piped");
+            let mut stdout = io::BufReader::new(stdout);
+            let mut line = String::new();
+            io::BufRead::read_line(&mut stdout, &mut line)?;
+            std::thread::spawn(move || io::copy(&mut stdout, &mut io::sink()));
+            let port = line
+                .split(" -> ")
+                .next()
+                .and_then(|from| from.rsplit(':').next())
+                .and_then(|port| port.trim().parse::<u16>().ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        format!("could not port-forward to {}", name),
+                    )
+                })?;
+            let url = format!("http://127.0.0.1:{}/rpc/{}", port, component);
+            let output = std::process::Command::new("curl")
+                .args(call::curl_args(&url, request))
+                .output()?;
+            if !output.status.success() {
+                return Err(call::curl_error(output.status, &output.stderr));
+            }
+            call::parse_curl(&output.stdout)
+        })();
+        let _ = forward.kill();
+        let _ = forward.wait();
+        res
+    }
+}
+
+/// An error for a failed kubectl command, classified by what it printed so
+/// that ammn exits with the right status. Its stderr is passed on, since it's
+/// captured to classify it.
+fn kubectl_error(status: std::process::ExitStatus, stderr: &[u8]) -> io::Error {
+    let _ = io::stderr().write_all(stderr);
+    let stderr = String::from_utf8_lossy(stderr);
+    let has = |patterns: &[&str]| patterns.iter().any(|p| stderr.contains(p));
+    let kind = if has(&[
+        "Unable to connect to the server",
+        "connection refused",
+        "no such host",
+        "i/o timeout",
+        "TLS handshake timeout",
+    ]) {
+        io::ErrorKind::ConnectionRefused
+    } else if has(&["timed out waiting", "exceeded its progress deadline"]) {
+        io::ErrorKind::TimedOut
+    } else if has(&[
+        "the object has been modified",
+        "Conflict",
+        "AlreadyExists",
+        "field is immutable",
+    ]) {
+        io::ErrorKind::AlreadyExists
+    } else {
+        io::ErrorKind::Other
+    };
+    io::Error::new(kind, format!("kubectl exited with status {}", status))
+}
+
+/// How long a deploy waits for a run-once job to complete.
+const RUN_ONCE_TIMEOUT: &str = "3600s";
+
+/// Kubernetes object names must be lowercase alphanumerics and dashes.
+fn k8s_name(s: &str) -> String {
+    let name = s
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '-',
+        })
+        .collect::<String>();
+    name.trim_matches('-').to_owned()
+}
+
+fn tool_job_name(tool: &str) -> String {
+    format!("tool-{}", k8s_name(tool))
+}
+
+/// Run-once jobs get a Kubernetes Job per revision, since a Job's pods can't
+/// be changed once it's created, and a Job that already completed for the
+/// revision isn't run again. Blue-green deploys name their workloads the same
+/// way, so both revisions can run side by side.
+fn revision_name(job: &str, rev: &str) -> String {
+    let rev = k8s_name(rev);
+    format!("{}-{}", job, &rev[..rev.len().min(12)])
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DeployResult<'r> {
+    pub(crate) revision: &'r str,
+    pub(crate) deployed: Vec<String>,
+    pub(crate) skipped: Vec<String>,
+    pub(crate) breaking_changes: Vec<String>,
+    pub(crate) warnings: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ToolResult<'r> {
+    pub(crate) tool: &'r str,
+    pub(crate) job: &'r str,
+}
+
+#[derive(Serialize)]
+pub(crate) struct StatusResult {
+    pub(crate) pods: Vec<PodStatus>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct PodStatus {
+    pub(crate) name: String,
+    pub(crate) job: String,
+    pub(crate) revision: String,
+    pub(crate) phase: String,
+    /// The pod's `/admin/health` report, if it could be fetched.
+    pub(crate) health: Option<serde_json::Value>,
+    /// The pod's `/admin/storage` report, if it could be fetched.
+    pub(crate) storage: Option<serde_json::Value>,
+}
+
+/// Print a pod's status as text, unless in JSON mode.
+pub(crate) fn text_status(status: &PodStatus) {
+    if output::is_json() {
+        return;
+    }
+
+    println!(
+        "{} (job {}, rev {}): {}",
+        status.name, status.job, status.revision, status.phase
+    );
+
+    if let Some(health) = &status.health {
+        println!("  health: {}", format_status(&health["job"]));
+        if let Some(comps) = health["components"].as_object() {
+            for (label, status) in comps.iter() {
+                println!("    {}: {}", label, format_status(status));
+            }
+        }
+    }
+
+    for (label, usage) in status.storage.iter().flat_map(|s| s.as_object()).flatten() {
+        let used = usage["used"].as_u64().unwrap_or(0);
+        match usage["quota"].as_u64() {
+            Some(quota) => println!(
+                "  storage {}: {} of {} bytes ({:.0}%)",
+                label,
+                used,
+                quota,
+                100.0 * used as f64 / quota as f64
+            ),
+            None => println!("  storage {}: {} bytes", label, used),
+        }
+    }
+}
+
+fn format_status(status: &serde_json::Value) -> String {
+    let kind = status["status"].as_str().unwrap_or("unknown");
+    match status["reason"].as_str() {
+        Some(reason) => format!("{} ({})", kind, reason),
+        None => kind.to_owned(),
+    }
+}
+
+/// Groups the app's jobs into waves, such that every job's dependencies are in
+/// an earlier wave than the job itself.
+pub(crate) fn job_waves(cf: &DumpConfig) -> Result<Vec<Vec<String>>, String> {
+    let mut remaining: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (label, job) in cf.jobs.iter() {
+        let mut deps = Vec::new();
+        for dep in job.dependencies.iter() {
+            if !cf.jobs.contains_key(dep) {
+                return Err(format!("job {} depends on unknown job {}", label, dep));
+            }
+            deps.push(dep.as_str());
+        }
+        remaining.insert(label.as_str(), deps);
+    }
+
+    let mut waves: Vec<Vec<String>> = Vec::new();
+    while !remaining.is_empty() {
+        let wave = remaining
+            .iter()
+            .filter(|(_, deps)| deps.iter().all(|d| !remaining.contains_key(d)))
+            .map(|(label, _)| *label)
+            .collect::<Vec<_>>();
+        if wave.is_empty() {
+            let stuck = remaining.keys().cloned().collect::<Vec<_>>().join(", ");
+            return Err(format!("dependency cycle among jobs: {}", stuck));
+        }
+        for label in wave.iter() {
+            remaining.remove(label);
+        }
+        waves.push(wave.into_iter().map(|s| s.to_owned()).collect());
+    }
+
+    Ok(waves)
+}
+
+/// The name of the headless service for a stateful job. The amimono runtime
+/// relies on this name to construct stable pod addresses.
+fn headless_service(job: &str) -> String {
+    format!("{}-headless", job)
+}
+
+/// The label marking the Deployments and StatefulSets of blue-green deploys,
+/// which are named for their revision.
+const BLUE_GREEN_LABEL: &str = "amimono-blue-green";
+
+/// The ConfigMap holding the dumped config of the most recently deployed
+/// revision, for comparing against the next one.
+const DEPLOYED_CONFIG: &str = "amimono-deployed-config";
+
+/// The port on which jobs serve the admin endpoints. This is the same as the
+/// RPC port, `amimono::rpc::PORT`.
+pub(crate) const ADMIN_PORT: u16 = 9099;
+
+struct KubernetesWriter<'w, W> {
+    tgt: &'w KubernetesTarget,
+    out: &'w mut W,
+}
+
+impl<'w, W: io::Write> KubernetesWriter<'w, W> {
+    fn new(tgt: &'w KubernetesTarget, out: &'w mut W) -> Self {
+        KubernetesWriter { tgt, out }
+    }
+
+    fn add_dump_config_job(&mut self) -> io::Result<()> {
+        writeln!(self.out, "---")?;
+        writeln!(self.out, "apiVersion: batch/v1")?;
+        writeln!(self.out, "kind: Job")?;
+        writeln!(self.out, "metadata:")?;
+        writeln!(self.out, "  name: dump-config")?;
+        writeln!(self.out, "spec:")?;
+        writeln!(self.out, "  template:")?;
+        writeln!(self.out, "    spec:")?;
+        writeln!(self.out, "      containers:")?;
+        writeln!(self.out, "        - name: dump-config")?;
+        writeln!(self.out, "          image: {}", self.tgt.image)?;
+        writeln!(self.out, "          imagePullPolicy: IfNotPresent")?;
+        writeln!(self.out, "          args: [\"--dump-config\"]")?;
+        writeln!(self.out, "          env:")?;
+        writeln!(self.out, "            - name: RUST_LOG")?;
+        writeln!(self.out, "              value: warn")?;
+        writeln!(self.out, "            - name: RUST_BACKTRACE")?;
+        writeln!(self.out, "              value: \"1\"")?;
+        writeln!(self.out, "      restartPolicy: Never")?;
+        Ok(())
+    }
+
+    fn add_deployed_config(&mut self, cf: &DumpConfig) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(cf).map_err(|e| io::Error::other(e))?;
+        writeln!(self.out, "---")?;
+        writeln!(self.out, "apiVersion: v1")?;
+        writeln!(self.out, "kind: ConfigMap")?;
+        writeln!(self.out, "metadata:")?;
+        writeln!(self.out, "  name: {}", DEPLOYED_CONFIG)?;
+        writeln!(self.out, "data:")?;
+        writeln!(self.out, "  config.json: |")?;
+        for line in json.lines() {
+            writeln!(self.out, "    {}", line)?;
+        }
+        Ok(())
+    }
+
+    fn add_tool_job(&mut self, name: &str, tool: &str, args: &[String]) -> io::Result<()> {
+        let args = ["--tool", tool]
+            .into_iter()
+            .chain(args.iter().map(|s| s.as_str()))
+            .map(|s| serde_json::to_string(s).unwrap())
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(self.out, "---")?;
+        writeln!(self.out, "apiVersion: batch/v1")?;
+        writeln!(self.out, "kind: Job")?;
+        writeln!(self.out, "metadata:")?;
+        writeln!(self.out, "  name: {}", name)?;
+        writeln!(self.out, "  labels:")?;
+        writeln!(self.out, "    amimono-tool: {}", name)?;
+        writeln!(self.out, "spec:")?;
+        writeln!(self.out, "  backoffLimit: 0")?;
+        writeln!(self.out, "  template:")?;
+        writeln!(self.out, "    spec:")?;
+        writeln!(self.out, "      containers:")?;
+        writeln!(self.out, "        - name: {}", name)?;
+        writeln!(self.out, "          image: {}", self.tgt.image)?;
+        writeln!(self.out, "          imagePullPolicy: IfNotPresent")?;
+        writeln!(self.out, "          args: [{}]", args)?;
+        if !self.tgt.env.is_empty() || !self.tgt.secrets.is_empty() {
+            writeln!(self.out, "          env:")?;
+            for (key, value) in self.tgt.env.iter() {
+                assert!(!value.contains('"'));
+                writeln!(self.out, "            - name: {}", key)?;
+                writeln!(self.out, "              value: \"{}\"", value)?;
+            }
+            self.add_secret_env()?;
+        }
+        writeln!(self.out, "      restartPolicy: Never")?;
+        Ok(())
+    }
+
+    fn add_podtemplatespec(&mut self, job: &str, dump: &DumpJob) -> io::Result<()> {
+        // components in the same job can share ports, so they're listed once
+        let mut ports: Vec<&DumpPort> = Vec::new();
+        for port in dump.components.values().flat_map(|x| x.ports.iter()) {
+            if port.port != 0 && !ports.iter().any(|p| p.port == port.port) {
+                ports.push(port);
+            }
+        }
+        let storage = dump
+            .components
+            .values()
+            .filter_map(|c| c.storage)
+            .sum::<u64>();
+        writeln!(self.out, "      containers:")?;
+        writeln!(self.out, "        - name: {}", job)?;
+        writeln!(self.out, "          image: {}", self.tgt.image)?;
+        writeln!(self.out, "          imagePullPolicy: IfNotPresent")?;
+        if !ports.is_empty() {
+            writeln!(self.out, "          ports:")?;
+            for port in ports {
+                writeln!(self.out, "            - name: {}", port_name(&port.name))?;
+                writeln!(self.out, "              containerPort: {}", port.port)?;
+                writeln!(self.out, "              protocol: {}", k8s_protocol(port))?;
+            }
+        }
+        if storage > 0 {
+            writeln!(self.out, "          resources:")?;
+            writeln!(self.out, "            requests:")?;
+            writeln!(self.out, "              ephemeral-storage: \"{}\"", storage)?;
+        }
+        writeln!(self.out, "          args: [\"--job\", \"{}\"]", job)?;
+        writeln!(self.out, "          readinessProbe:")?;
+        writeln!(self.out, "            httpGet:")?;
+        writeln!(self.out, "              path: /ready")?;
+        writeln!(self.out, "              port: {}", ADMIN_PORT)?;
+        writeln!(self.out, "            periodSeconds: 5")?;
+        writeln!(self.out, "          env:")?;
+        for (key, field) in [
+            ("AMIMONO_POD_NAME", "metadata.name"),
+            ("AMIMONO_POD_NAMESPACE", "metadata.namespace"),
+            ("AMIMONO_POD_IP", "status.podIP"),
+        ] {
+            writeln!(self.out, "            - name: {}", key)?;
+            writeln!(self.out, "              valueFrom:")?;
+            writeln!(self.out, "                fieldRef:")?;
+            writeln!(self.out, "                  fieldPath: {}", field)?;
+        }
+        for (key, value) in self.tgt.env.iter() {
+            assert!(!value.contains('"'));
+            writeln!(self.out, "            - name: {}", key)?;
+            writeln!(self.out, "              value: \"{}\"", value)?;
+        }
+        self.add_secret_env()?;
+        self.add_placement(job, &dump.placement)?;
+        Ok(())
+    }
+
+    /// Environment variables filled from secrets, at the level of a
+    /// container's env list.
+    fn add_secret_env(&mut self) -> io::Result<()> {
+        for (key, secret) in self.tgt.secrets.iter() {
+            let (name, secret_key) = secret_ref(secret).expect("checked when loading the config");
+            writeln!(self.out, "            - name: {}", key)?;
+            writeln!(self.out, "              valueFrom:")?;
+            writeln!(self.out, "                secretKeyRef:")?;
+            writeln!(self.out, "                  name: {}", name)?;
+            writeln!(self.out, "                  key: {}", secret_key)?;
+        }
+        Ok(())
+    }
+
+    /// Render placement constraints as pod affinity and topology spread
+    /// constraints, at the level of a pod template's spec.
+    fn add_placement(&mut self, job: &str, placement: &[DumpPlacement]) -> io::Result<()> {
+        let colocate = placement
+            .iter()
+            .filter_map(|p| match p {
+                DumpPlacement::Colocate { job, topology } => Some((job, topology)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let anti_colocate = placement
+            .iter()
+            .filter_map(|p| match p {
+                DumpPlacement::AntiColocate { job, topology } => Some((job, topology)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let spread = placement
+            .iter()
+            .filter_map(|p| match p {
+                DumpPlacement::Spread { topology, max_skew } => Some((topology, max_skew)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if !colocate.is_empty() || !anti_colocate.is_empty() {
+            writeln!(self.out, "      affinity:")?;
+        }
+        for (kind, terms) in [
+            ("podAffinity", colocate),
+            ("podAntiAffinity", anti_colocate),
+        ] {
+            if terms.is_empty() {
+                continue;
+            }
+            writeln!(self.out, "        {}:", kind)?;
+            writeln!(
+                self.out,
+                "          requiredDuringSchedulingIgnoredDuringExecution:"
+            )?;
+            for (other, topology) in terms {
+                writeln!(self.out, "            - labelSelector:")?;
+                writeln!(self.out, "                matchLabels:")?;
+                writeln!(self.out, "                  amimono-job: {}", other)?;
+                writeln!(self.out, "              topologyKey: {}", topology)?;
+            }
+        }
+        if !spread.is_empty() {
+            writeln!(self.out, "      topologySpreadConstraints:")?;
+            for (topology, max_skew) in spread {
+                writeln!(self.out, "        - maxSkew: {}", max_skew)?;
+                writeln!(self.out, "          topologyKey: {}", topology)?;
+                writeln!(self.out, "          whenUnsatisfiable: ScheduleAnyway")?;
+                writeln!(self.out, "          labelSelector:")?;
+                writeln!(self.out, "            matchLabels:")?;
+                writeln!(self.out, "              amimono-job: {}", job)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A job's Deployment. Blue-green deploys give each revision its own,
+    /// selecting only that revision's pods.
+    fn add_deployment(
+        &mut self,
+        job: &str,
+        rev: &str,
+        dump: &DumpJob,
+        blue_green: bool,
+    ) -> io::Result<()> {
+        writeln!(self.out, "---")?;
+        writeln!(self.out, "apiVersion: apps/v1")?;
+        writeln!(self.out, "kind: Deployment")?;
+        self.add_workload_metadata(job, rev, blue_green)?;
+        writeln!(self.out, "spec:")?;
+        writeln!(self.out, "  replicas: {}", dump.replicas)?;
+        if dump.rollout.max_unavailable.is_some() || dump.rollout.max_surge.is_some() {
+            writeln!(self.out, "  strategy:")?;
+            writeln!(self.out, "    type: RollingUpdate")?;
+            writeln!(self.out, "    rollingUpdate:")?;
+            if let Some(max_unavailable) = dump.rollout.max_unavailable {
+                writeln!(self.out, "      maxUnavailable: {}", max_unavailable)?;
+            }
+            if let Some(max_surge) = dump.rollout.max_surge {
+                writeln!(self.out, "      maxSurge: {}", max_surge)?;
+            }
+        }
+        self.add_workload_selector(job, rev, blue_green)?;
+        writeln!(self.out, "  template:")?;
+        writeln!(self.out, "    metadata:")?;
+        writeln!(self.out, "      labels:")?;
         writeln!(self.out, "        amimono-job: {}", job)?;
         writeln!(self.out, "        amimono-rev: \"{}\"", rev)?;
+        if let Some(digest) = &dump.digest {
+            writeln!(self.out, "        amimono-digest: \"{}\"", digest)?;
+        }
         writeln!(self.out, "    spec:")?;
-        self.add_podtemplatespec(job, ports)?;
+        self.add_podtemplatespec(job, dump)?;
         Ok(())
     }
 
-    fn add_service(&mut self, job: &str, _rev: &str, component: &str, port: u16) -> io::Result<()> {
+    /// The metadata of a job's Deployment or StatefulSet.
+    fn add_workload_metadata(&mut self, job: &str, rev: &str, blue_green: bool) -> io::Result<()> {
+        writeln!(self.out, "metadata:")?;
+        match blue_green {
+            true => writeln!(self.out, "  name: {}", revision_name(job, rev))?,
+            false => writeln!(self.out, "  name: {}", job)?,
+        }
+        writeln!(self.out, "  labels:")?;
+        writeln!(self.out, "    amimono-job: {}", job)?;
+        writeln!(self.out, "    amimono-rev: \"{}\"", rev)?;
+        if blue_green {
+            writeln!(self.out, "    {}: \"true\"", BLUE_GREEN_LABEL)?;
+        }
+        Ok(())
+    }
+
+    /// The pod selector of a job's Deployment or StatefulSet, at the level of
+    /// its spec.
+    fn add_workload_selector(&mut self, job: &str, rev: &str, blue_green: bool) -> io::Result<()> {
+        writeln!(self.out, "  selector:")?;
+        writeln!(self.out, "    matchLabels:")?;
+        writeln!(self.out, "      amimono-job: {}", job)?;
+        if blue_green {
+            writeln!(self.out, "      amimono-rev: \"{}\"", rev)?;
+        }
+        Ok(())
+    }
+
+    fn add_run_once_job(&mut self, job: &str, rev: &str, dump: &DumpJob) -> io::Result<()> {
+        writeln!(self.out, "---")?;
+        writeln!(self.out, "apiVersion: batch/v1")?;
+        writeln!(self.out, "kind: Job")?;
+        writeln!(self.out, "metadata:")?;
+        writeln!(self.out, "  name: {}", revision_name(job, rev))?;
+        writeln!(self.out, "  labels:")?;
+        writeln!(self.out, "    amimono-job: {}", job)?;
+        writeln!(self.out, "    amimono-rev: \"{}\"", rev)?;
+        writeln!(self.out, "spec:")?;
+        // panics are retried in the process, according to the job's restart
+        // policy
+        writeln!(self.out, "  backoffLimit: 0")?;
+        writeln!(self.out, "  template:")?;
+        writeln!(self.out, "    metadata:")?;
+        writeln!(self.out, "      labels:")?;
+        writeln!(self.out, "        amimono-job: {}", job)?;
+        writeln!(self.out, "        amimono-rev: \"{}\"", rev)?;
+        writeln!(self.out, "    spec:")?;
+        self.add_podtemplatespec(job, dump)?;
+        writeln!(self.out, "      restartPolicy: Never")?;
+        Ok(())
+    }
+
+    fn add_statefulset(
+        &mut self,
+        job: &str,
+        rev: &str,
+        dump: &DumpJob,
+        blue_green: bool,
+    ) -> io::Result<()> {
+        writeln!(self.out, "---")?;
+        writeln!(self.out, "apiVersion: apps/v1")?;
+        writeln!(self.out, "kind: StatefulSet")?;
+        self.add_workload_metadata(job, rev, blue_green)?;
+        writeln!(self.out, "spec:")?;
+        writeln!(self.out, "  serviceName: {}", headless_service(job))?;
+        writeln!(self.out, "  replicas: {}", dump.replicas)?;
+        if dump.rollout.max_surge.is_some() {
+            log::warn!("ignoring maxSurge for stateful job {}", job);
+        }
+        match (dump.rollout.stateful_update, dump.rollout.max_unavailable) {
+            (Some(DumpStatefulUpdate::OnDelete), _) => {
+                writeln!(self.out, "  updateStrategy:")?;
+                writeln!(self.out, "    type: OnDelete")?;
+            }
+            (None, None) => (),
+            (update, max_unavailable) => {
+                writeln!(self.out, "  updateStrategy:")?;
+                writeln!(self.out, "    type: RollingUpdate")?;
+                writeln!(self.out, "    rollingUpdate:")?;
+                if let Some(DumpStatefulUpdate::RollingUpdate { partition }) = update {
+                    writeln!(self.out, "      partition: {}", partition)?;
+                }
+                if let Some(max_unavailable) = max_unavailable {
+                    writeln!(self.out, "      maxUnavailable: {}", max_unavailable)?;
+                }
+            }
+        }
+        self.add_workload_selector(job, rev, blue_green)?;
+        writeln!(self.out, "  template:")?;
+        writeln!(self.out, "    metadata:")?;
+        writeln!(self.out, "      labels:")?;
+        writeln!(self.out, "        amimono-job: {}", job)?;
+        writeln!(self.out, "        amimono-rev: \"{}\"", rev)?;
+        if let Some(digest) = &dump.digest {
+            writeln!(self.out, "        amimono-digest: \"{}\"", digest)?;
+        }
+        writeln!(self.out, "    spec:")?;
+        self.add_podtemplatespec(job, dump)?;
+        Ok(())
+    }
+
+    /// A PodDisruptionBudget keeping enough of a job's pods available during
+    /// voluntary disruptions.
+    fn add_disruption_budget(&mut self, job: &str, min_available: DumpBudget) -> io::Result<()> {
+        self.add_disruption_budget_ref(job)?;
+        writeln!(self.out, "spec:")?;
+        writeln!(self.out, "  minAvailable: {}", min_available)?;
+        writeln!(self.out, "  selector:")?;
+        writeln!(self.out, "    matchLabels:")?;
+        writeln!(self.out, "      amimono-job: {}", job)?;
+        Ok(())
+    }
+
+    /// Just enough of a job's PodDisruptionBudget to delete it.
+    fn add_disruption_budget_ref(&mut self, job: &str) -> io::Result<()> {
+        writeln!(self.out, "---")?;
+        writeln!(self.out, "apiVersion: policy/v1")?;
+        writeln!(self.out, "kind: PodDisruptionBudget")?;
+        writeln!(self.out, "metadata:")?;
+        writeln!(self.out, "  name: {}", job)?;
+        writeln!(self.out, "  labels:")?;
+        writeln!(self.out, "    amimono-job: {}", job)?;
+        Ok(())
+    }
+
+    /// A headless service giving each pod of a stateful job a stable DNS name.
+    fn add_headless_service(&mut self, job: &str) -> io::Result<()> {
+        writeln!(self.out, "---")?;
+        writeln!(self.out, "apiVersion: v1")?;
+        writeln!(self.out, "kind: Service")?;
+        writeln!(self.out, "metadata:")?;
+        writeln!(self.out, "  name: {}", headless_service(job))?;
+        writeln!(self.out, "  labels:")?;
+        writeln!(self.out, "    amimono-job: {}", job)?;
+        writeln!(self.out, "spec:")?;
+        writeln!(self.out, "  clusterIP: None")?;
+        writeln!(self.out, "  selector:")?;
+        writeln!(self.out, "    amimono-job: {}", job)?;
+        Ok(())
+    }
+
+    /// The service a mesh routes calls to a job's components through, with
+    /// the RPC port and the ports of the job's components. Blue-green deploys
+    /// select the pods of one revision.
+    fn add_mesh_service(&mut self, job: &str, rev: Option<&str>, dump: &DumpJob) -> io::Result<()> {
+        writeln!(self.out, "---")?;
+        writeln!(self.out, "apiVersion: v1")?;
+        writeln!(self.out, "kind: Service")?;
+        writeln!(self.out, "metadata:")?;
+        writeln!(self.out, "  name: {}", job)?;
+        writeln!(self.out, "  labels:")?;
+        writeln!(self.out, "    amimono-job: {}", job)?;
+        writeln!(self.out, "spec:")?;
+        writeln!(self.out, "  selector:")?;
+        writeln!(self.out, "    amimono-job: {}", job)?;
+        if let Some(rev) = rev {
+            writeln!(self.out, "    amimono-rev: \"{}\"", rev)?;
+        }
+        writeln!(self.out, "  ports:")?;
+        writeln!(self.out, "    - name: rpc")?;
+        writeln!(self.out, "      protocol: TCP")?;
+        writeln!(self.out, "      appProtocol: http")?;
+        writeln!(self.out, "      port: {}", ADMIN_PORT)?;
+        writeln!(self.out, "      targetPort: {}", ADMIN_PORT)?;
+        let mut seen = vec![ADMIN_PORT];
+        for port in dump.components.values().flat_map(|c| c.ports.iter()) {
+            if port.port == 0 || seen.contains(&port.port) {
+                continue;
+            }
+            seen.push(port.port);
+            writeln!(self.out, "    - name: {}", port_name(&port.name))?;
+            writeln!(self.out, "      protocol: {}", k8s_protocol(port))?;
+            if port.protocol == DumpProtocol::Http {
+                writeln!(self.out, "      appProtocol: http")?;
+            }
+            writeln!(self.out, "      port: {}", port.port)?;
+            writeln!(self.out, "      targetPort: {}", port.port)?;
+        }
+        Ok(())
+    }
+
+    /// A component's service. Blue-green deploys select the pods of one
+    /// revision.
+    fn add_service(
+        &mut self,
+        job: &str,
+        rev: Option<&str>,
+        component: &str,
+        ports: &[&DumpPort],
+    ) -> io::Result<()> {
+        // external bindings, such as gateways, get a load balancer
+        let kind = match ports.iter().any(|p| p.external) {
+            true => "LoadBalancer",
+            false => "NodePort",
+        };
         writeln!(self.out, "---")?;
         writeln!(self.out, "apiVersion: v1")?;
         writeln!(self.out, "kind: Service")?;
@@ -341,11 +1992,43 @@ impl<'w, W: io::Write> KubernetesWriter<'w, W> {
         writeln!(self.out, "spec:")?;
         writeln!(self.out, "  selector:")?;
         writeln!(self.out, "    amimono-job: {}", job)?;
-        writeln!(self.out, "  type: NodePort")?;
+        if let Some(rev) = rev {
+            writeln!(self.out, "    amimono-rev: \"{}\"", rev)?;
+        }
+        writeln!(self.out, "  type: {}", kind)?;
         writeln!(self.out, "  ports:")?;
-        writeln!(self.out, "    - protocol: TCP")?;
-        writeln!(self.out, "      port: {}", port)?;
-        writeln!(self.out, "      targetPort: {}", port)?;
+        for port in ports {
+            writeln!(self.out, "    - name: {}", port_name(&port.name))?;
+            writeln!(self.out, "      protocol: {}", k8s_protocol(port))?;
+            if port.protocol == DumpProtocol::Http {
+                writeln!(self.out, "      appProtocol: http")?;
+            }
+            writeln!(self.out, "      port: {}", port.port)?;
+            writeln!(self.out, "      targetPort: {}", port.port)?;
+        }
         Ok(())
     }
 }
+
+/// Split a `secret-name/key` reference.
+pub(crate) fn secret_ref(secret: &str) -> Option<(&str, &str)> {
+    secret
+        .split_once('/')
+        .filter(|(name, key)| !name.is_empty() && !key.is_empty())
+}
+
+fn k8s_protocol(port: &DumpPort) -> &'static str {
+    match port.protocol {
+        DumpProtocol::Tcp | DumpProtocol::Http => "TCP",
+        DumpProtocol::Udp => "UDP",
+    }
+}
+
+/// Kubernetes port names are limited to 15 lowercase alphanumeric characters
+/// and dashes.
+fn port_name(name: &str) -> String {
+    let name = name
+        .to_ascii_lowercase()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "-");
+    name.trim_matches('-').chars().take(15).collect::<String>()
+}
@@ -0,0 +1,106 @@
+//! `ammn watch`, a rebuild and redeploy loop for development clusters.
+//!
+//! The project tree is polled for changes. When something changes, the
+//! target's `build` command is run to build and push a new image, tagged
+//! uniquely so that the cluster pulls it, and the target is deployed with the
+//! new image. Since deploys skip jobs whose digest hasn't changed, only the
+//! affected jobs are rolled. Build failures are reported and the loop waits
+//! for the next change.
+//!
+//! The build command is responsible for building incrementally. A Dockerfile
+//! that caches dependencies in an early layer, or that copies a binary built
+//! on the host with `cargo build`, keeps rebuilds fast.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{project::Project, target::Target};
+
+/// How often the tree is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long the tree must be unchanged before a rebuild starts, so that a
+/// burst of saves results in a single rebuild.
+const SETTLE_TIME: Duration = Duration::from_millis(300);
+
+/// Directories that never affect the build.
+const IGNORED: &[&str] = &["target", ".git", ".amimono"];
+
+pub fn watch(target: &Target, proj: &Project, allow_breaking: bool) {
+    let root = Path::new(".");
+    let mut last = fingerprint(root);
+    rebuild(target, proj, allow_breaking);
+
+    log::info!("watching for changes...");
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+        let current = fingerprint(root);
+        if current == last {
+            continue;
+        }
+
+        // wait for the tree to settle
+        let mut settled = current;
+        loop {
+            std::thread::sleep(SETTLE_TIME);
+            let next = fingerprint(root);
+            if next == settled {
+                break;
+            }
+            settled = next;
+        }
+        last = settled;
+
+        log::info!("change detected, rebuilding...");
+        rebuild(target, proj, allow_breaking);
+        log::info!("watching for changes...");
+    }
+}
+
+fn rebuild(target: &Target, proj: &Project, allow_breaking: bool) {
+    let tag = format!(
+        "watch-{}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    );
+    match target.build(&tag) {
+        Ok(built) => built.deploy(proj, allow_breaking, false, None),
+        Err(e) => log::error!("build failed: {}", e),
+    }
+}
+
+/// A hash of the paths, sizes, and modification times of every file in the
+/// tree, which changes when any file does.
+pub fn fingerprint(root: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let mut stack = vec![root.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        let mut entries = entries.filter_map(|e| e.ok()).collect::<Vec<_>>();
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            if meta.is_dir() {
+                if !IGNORED.iter().any(|x| entry.file_name() == *x) {
+                    stack.push(path);
+                }
+                continue;
+            }
+            path.hash(&mut hasher);
+            meta.len().hash(&mut hasher);
+            meta.modified().ok().hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
@@ -0,0 +1,309 @@
+//! Structural comparison of the JSON Schemas of RPC ops' types, as generated
+//! by `schemars`, so that changes inside a type, like a new field or a
+//! removed variant, are seen rather than just changes to its name.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use serde_json::{Map, Value};
+
+use crate::DumpRpcOp;
+
+/// A difference between two revisions of an op's types.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeChange {
+    pub description: String,
+    /// Whether the change breaks calls between the two revisions, rather than
+    /// only calls that use something one of them doesn't have.
+    pub breaking: bool,
+}
+
+impl std::fmt::Display for TypeChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.description)
+    }
+}
+
+impl DumpRpcOp {
+    /// The changes to the op's arg and return types from this revision to
+    /// `new`. Types are compared structurally when both revisions have
+    /// schemas for them, and by name otherwise.
+    pub fn type_changes(&self, new: &DumpRpcOp) -> Vec<TypeChange> {
+        let mut differ = Differ::default();
+        if self.args.len() != new.args.len() {
+            differ.push(
+                true,
+                format!(
+                    "takes {} args instead of {}",
+                    new.args.len(),
+                    self.args.len()
+                ),
+            );
+            return differ.changes;
+        }
+        for (i, (old_ty, new_ty)) in self.args.iter().zip(new.args.iter()).enumerate() {
+            let path = match self.arg_names.get(i) {
+                Some(name) => name.clone(),
+                None => format!("arg{i}"),
+            };
+            let old_schema = self.arg_schemas.get(i).and_then(Option::as_ref);
+            let new_schema = new.arg_schemas.get(i).and_then(Option::as_ref);
+            differ.compare(&path, (old_ty, old_schema), (new_ty, new_schema));
+        }
+        differ.compare(
+            "ret",
+            (&self.ret, self.ret_schema.as_ref()),
+            (&new.ret, new.ret_schema.as_ref()),
+        );
+        differ.changes
+    }
+}
+
+/// A schema, and the root schema its `$ref`s are resolved against.
+#[derive(Clone, Copy)]
+struct Node<'a> {
+    schema: &'a Value,
+    root: &'a Value,
+}
+
+impl<'a> Node<'a> {
+    fn root(schema: &'a Value) -> Node<'a> {
+        Node {
+            schema,
+            root: schema,
+        }
+    }
+
+    fn at(self, schema: &'a Value) -> Node<'a> {
+        Node {
+            schema,
+            root: self.root,
+        }
+    }
+
+    /// Follow `$ref`s to the definition they point to, returning the last
+    /// reference followed, if any.
+    fn resolve(self) -> (Option<&'a str>, Node<'a>) {
+        let mut node = self;
+        let mut reference = None;
+        // a bound, in case of definitions that only refer to each other
+        for _ in 0..16 {
+            let Some(r) = node.schema.get("$ref").and_then(Value::as_str) else {
+                break;
+            };
+            let target = match r {
+                "#" => Some(node.root),
+                _ => r
+                    .strip_prefix("#/$defs/")
+                    .and_then(|name| node.root.get("$defs")?.get(name))
+                    .or_else(|| {
+                        let name = r.strip_prefix("#/definitions/")?;
+                        node.root.get("definitions")?.get(name)
+                    }),
+            };
+            let Some(target) = target else {
+                break;
+            };
+            reference = Some(r);
+            node = node.at(target);
+        }
+        (reference, node)
+    }
+
+    /// The JSON types the schema allows, with their formats.
+    fn types(self) -> BTreeSet<String> {
+        let format = self.schema.get("format").and_then(Value::as_str);
+        let with_format = |ty: &str| match format {
+            Some(format) if ty != "null" => format!("{ty} ({format})"),
+            _ => ty.to_owned(),
+        };
+        match self.schema.get("type") {
+            Some(Value::String(ty)) => BTreeSet::from([with_format(ty)]),
+            Some(Value::Array(tys)) => tys
+                .iter()
+                .filter_map(Value::as_str)
+                .map(with_format)
+                .collect(),
+            _ => BTreeSet::new(),
+        }
+    }
+
+    fn properties(self) -> Option<&'a Map<String, Value>> {
+        self.schema.get("properties")?.as_object()
+    }
+
+    fn required(self) -> BTreeSet<&'a str> {
+        let required = self.schema.get("required").and_then(Value::as_array);
+        required
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .collect()
+    }
+
+    /// The variants of an enum, with the schemas of their contents for ones
+    /// that have any, or `None` if the schema isn't an enum that `serde`
+    /// tags externally, which is its default.
+    fn variants(self) -> Option<BTreeMap<&'a str, Option<Node<'a>>>> {
+        let mut variants = BTreeMap::new();
+        if let Some(names) = self.schema.get("enum").and_then(Value::as_array) {
+            for name in names {
+                variants.insert(name.as_str()?, None);
+            }
+            return Some(variants);
+        }
+        for variant in self.schema.get("oneOf")?.as_array()? {
+            let (_, variant) = self.at(variant).resolve();
+            if let Some(variant) = variant.variants() {
+                variants.extend(variant);
+                continue;
+            }
+            if let Some(name) = variant.schema.get("const").and_then(Value::as_str) {
+                variants.insert(name, None);
+                continue;
+            }
+            let properties = variant.properties()?;
+            let (name, contents) = properties.iter().next()?;
+            if properties.len() != 1 || !variant.required().contains(name.as_str()) {
+                return None;
+            }
+            variants.insert(name.as_str(), Some(variant.at(contents)));
+        }
+        Some(variants)
+    }
+}
+
+#[derive(Default)]
+struct Differ<'a> {
+    changes: Vec<TypeChange>,
+    /// The pairs of definitions already compared, so recursive types end.
+    seen: HashSet<(&'a str, &'a str)>,
+}
+
+impl<'a> Differ<'a> {
+    fn push(&mut self, breaking: bool, description: String) {
+        self.changes.push(TypeChange {
+            description,
+            breaking,
+        });
+    }
+
+    fn compare(
+        &mut self,
+        path: &str,
+        (old_ty, old_schema): (&str, Option<&'a Value>),
+        (new_ty, new_schema): (&str, Option<&'a Value>),
+    ) {
+        match (old_schema, new_schema) {
+            (Some(old), Some(new)) => self.diff(path, Node::root(old), Node::root(new)),
+            _ if old_ty != new_ty => {
+                self.push(true, format!("`{path}` changed from {old_ty} to {new_ty}"))
+            }
+            _ => {}
+        }
+    }
+
+    fn diff(&mut self, path: &str, old: Node<'a>, new: Node<'a>) {
+        let (old_ref, old) = old.resolve();
+        let (new_ref, new) = new.resolve();
+        if let (Some(old_ref), Some(new_ref)) = (old_ref, new_ref)
+            && !self.seen.insert((old_ref, new_ref))
+        {
+            return;
+        }
+
+        let (old_types, new_types) = (old.types(), new.types());
+        if !old_types.is_empty() && !new_types.is_empty() && old_types != new_types {
+            let join = |tys: BTreeSet<String>| tys.into_iter().collect::<Vec<_>>().join(" or ");
+            self.push(
+                true,
+                format!(
+                    "`{path}` changed from {} to {}",
+                    join(old_types),
+                    join(new_types)
+                ),
+            );
+            return;
+        }
+
+        match (old.variants(), new.variants()) {
+            (Some(old_variants), Some(new_variants)) => {
+                for (name, old_contents) in old_variants.iter() {
+                    match (old_contents, new_variants.get(name)) {
+                        (_, None) => {
+                            self.push(true, format!("variant `{path}::{name}` was removed"))
+                        }
+                        (Some(old_contents), Some(Some(new_contents))) => {
+                            self.diff(&format!("{path}::{name}"), *old_contents, *new_contents)
+                        }
+                        (None, Some(None)) => {}
+                        (_, Some(_)) => self.push(
+                            true,
+                            format!("the contents of variant `{path}::{name}` changed"),
+                        ),
+                    }
+                }
+                for name in new_variants.keys() {
+                    if !old_variants.contains_key(name) {
+                        self.push(false, format!("variant `{path}::{name}` was added"));
+                    }
+                }
+                return;
+            }
+            (None, None) => {}
+            _ => {
+                self.push(true, format!("`{path}` changed to or from an enum"));
+                return;
+            }
+        }
+
+        if let (Some(old_props), Some(new_props)) = (old.properties(), new.properties()) {
+            let (old_required, new_required) = (old.required(), new.required());
+            for (name, old_prop) in old_props.iter() {
+                let field = format!("{path}.{name}");
+                let was_required = old_required.contains(name.as_str());
+                let Some(new_prop) = new_props.get(name) else {
+                    let kind = if was_required { "required" } else { "optional" };
+                    self.push(was_required, format!("{kind} field `{field}` was removed"));
+                    continue;
+                };
+                match (was_required, new_required.contains(name.as_str())) {
+                    (false, true) => self.push(true, format!("field `{field}` became required")),
+                    (true, false) => self.push(true, format!("field `{field}` became optional")),
+                    _ => {}
+                }
+                self.diff(&field, old.at(old_prop), new.at(new_prop));
+            }
+            for name in new_props.keys() {
+                if !old_props.contains_key(name) {
+                    let required = new_required.contains(name.as_str());
+                    let kind = if required { "required" } else { "optional" };
+                    self.push(required, format!("{kind} field `{path}.{name}` was added"));
+                }
+            }
+        }
+
+        for (key, suffix) in [("items", "[]"), ("additionalProperties", "{}")] {
+            if let (Some(old_sub @ Value::Object(_)), Some(new_sub @ Value::Object(_))) =
+                (old.schema.get(key), new.schema.get(key))
+            {
+                self.diff(&format!("{path}{suffix}"), old.at(old_sub), new.at(new_sub));
+            }
+        }
+
+        for key in ["anyOf", "oneOf", "allOf"] {
+            let (Some(old_subs), Some(new_subs)) = (
+                old.schema.get(key).and_then(Value::as_array),
+                new.schema.get(key).and_then(Value::as_array),
+            ) else {
+                continue;
+            };
+            if old_subs.len() != new_subs.len() {
+                self.push(true, format!("`{path}` changed"));
+                continue;
+            }
+            for (old_sub, new_sub) in old_subs.iter().zip(new_subs.iter()) {
+                self.diff(path, old.at(old_sub), new.at(new_sub));
+            }
+        }
+    }
+}
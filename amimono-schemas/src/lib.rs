@@ -1,32 +1,430 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
 
 use serde::{Deserialize, Serialize};
 
+mod json_schema;
+
+pub use json_schema::TypeChange;
+
+/// The current version of the `DumpConfig` schema. Dumps from before the
+/// version was recorded deserialize as version 1.
+pub const SCHEMA_VERSION: u32 = 4;
+
+/// Marks the start of a `DumpConfig` embedded in a binary with
+/// `amimono_build::EmbeddedConfig`. The config ends at the next NUL byte.
+pub const EMBEDDED_CONFIG_MARKER: &str = "\0amimono-embedded-config\0";
+
+/// Find the candidates for a config embedded in `bytes`, e.g. a binary. The
+/// marker also appears on its own wherever code refers to it, so callers
+/// should use the first candidate that parses.
+pub fn find_embedded_configs(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let marker = EMBEDDED_CONFIG_MARKER.as_bytes();
+    (0..bytes.len())
+        .filter(move |&i| bytes[i..].starts_with(marker))
+        .map(move |i| {
+            let rest = &bytes[i + marker.len()..];
+            let end = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+            &rest[..end]
+        })
+}
+
+fn legacy_schema_version() -> u32 {
+    1
+}
+
+fn one() -> u32 {
+    1
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DumpConfig {
+    #[serde(default = "legacy_schema_version")]
+    pub schema_version: u32,
     pub revision: String,
     pub jobs: HashMap<String, DumpJob>,
+    /// The labels of the app's tools.
+    #[serde(default)]
+    pub tools: Vec<String>,
+    /// The labels of the tools that `ammn deploy` runs against each new
+    /// revision once it's deployed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub smoke_tests: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<DumpBuildInfo>,
+}
+
+/// How the app's binary was built, beyond its revision.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DumpBuildInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_sha: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub built_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rustc_version: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DumpJob {
     pub is_stateful: bool,
+    /// The job runs to completion once per revision, rather than serving.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub runs_once: bool,
+    #[serde(default = "one")]
+    pub replicas: u32,
     pub components: HashMap<String, DumpComponent>,
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub placement: Vec<DumpPlacement>,
+    #[serde(default, skip_serializing_if = "DumpRollout::is_empty")]
+    pub rollout: DumpRollout,
+    /// Changes whenever anything that affects how the job runs changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DumpComponent {
     pub is_stateful: bool,
-    pub ports: Vec<u16>,
+    pub ports: Vec<DumpPort>,
+    /// The storage requested by the component, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub storage_hard_limit: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rpc_ops: Option<Vec<DumpRpcOp>>,
+    /// The ordinals of the job's replicas that run the component, if it
+    /// doesn't run on all of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replicas: Option<Vec<u32>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DumpRpcOp {
+    pub name: String,
+    pub args: Vec<String>,
+    /// The names of the args. Dumps from older revisions don't have them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub arg_names: Vec<String>,
+    pub ret: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub optional: bool,
+    /// The JSON Schemas of the args, where their types have one. Dumps from
+    /// older revisions don't have them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub arg_schemas: Vec<Option<serde_json::Value>>,
+    /// The JSON Schema of the return type, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ret_schema: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum DumpPlacement {
+    Colocate { job: String, topology: String },
+    AntiColocate { job: String, topology: String },
+    Spread { topology: String, max_skew: u32 },
+}
+
+/// How a job's replicas are replaced when it's deployed. Targets can also
+/// set these per job, overriding the app's settings.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DumpRollout {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_unavailable: Option<DumpBudget>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_surge: Option<DumpBudget>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_available: Option<DumpBudget>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stateful_update: Option<DumpStatefulUpdate>,
+}
+
+impl DumpRollout {
+    pub fn is_empty(&self) -> bool {
+        *self == DumpRollout::default()
+    }
+
+    /// The settings of `self`, with any set in `other` taking precedence.
+    pub fn overridden_by(&self, other: &DumpRollout) -> DumpRollout {
+        DumpRollout {
+            max_unavailable: other.max_unavailable.or(self.max_unavailable),
+            max_surge: other.max_surge.or(self.max_surge),
+            min_available: other.min_available.or(self.min_available),
+            stateful_update: other.stateful_update.or(self.stateful_update),
+        }
+    }
+}
+
+/// A number of replicas, written like a Kubernetes int-or-string: a bare
+/// number for a count, or a string like `"25%"` for a percentage. Budgets
+/// display as YAML scalars.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(into = "BudgetRepr", try_from = "BudgetRepr")]
+pub enum DumpBudget {
+    Count(u32),
+    Percent(u32),
+}
+
+impl std::fmt::Display for DumpBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DumpBudget::Count(n) => write!(f, "{}", n),
+            DumpBudget::Percent(n) => write!(f, "\"{}%\"", n),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "camelCase")]
-pub enum DumpBinding {
-    None,
+#[serde(untagged)]
+enum BudgetRepr {
+    Count(u32),
+    Percent(String),
+}
+
+impl From<DumpBudget> for BudgetRepr {
+    fn from(budget: DumpBudget) -> BudgetRepr {
+        match budget {
+            DumpBudget::Count(n) => BudgetRepr::Count(n),
+            DumpBudget::Percent(n) => BudgetRepr::Percent(format!("{}%", n)),
+        }
+    }
+}
+
+impl TryFrom<BudgetRepr> for DumpBudget {
+    type Error = String;
+
+    fn try_from(repr: BudgetRepr) -> Result<DumpBudget, String> {
+        match repr {
+            BudgetRepr::Count(n) => Ok(DumpBudget::Count(n)),
+            BudgetRepr::Percent(s) => match s.strip_suffix('%').map(|n| n.parse::<u32>()) {
+                Some(Ok(n)) if n <= 100 => Ok(DumpBudget::Percent(n)),
+                _ => Err(format!("invalid percentage {:?}", s)),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum DumpStatefulUpdate {
+    RollingUpdate { partition: u32 },
+    OnDelete,
+}
+
+/// A port bound by a component. Version 1 dumps list ports as bare numbers,
+/// which deserialize as TCP ports named after their number, and dumps from
+/// before bindings had kinds list every port as plain and internal.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", from = "PortRepr")]
+pub struct DumpPort {
+    pub name: String,
+    pub port: u16,
+    pub protocol: DumpProtocol,
+    pub kind: DumpBindingKind,
+    /// Whether the port should be reachable from outside the app.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub external: bool,
+}
+
+/// What a component binds a port for.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum DumpBindingKind {
     Rpc,
-    Tcp { port: u16 },
+    #[default]
+    Plain,
+    Gateway,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum DumpProtocol {
+    Tcp,
+    Udp,
+    /// HTTP over TCP, which targets can use for L7 load balancing.
+    Http,
+}
+
+impl DumpPort {
+    /// A TCP port with a name derived from its number.
+    pub fn unnamed(port: u16) -> DumpPort {
+        DumpPort {
+            name: format!("port-{port}"),
+            port,
+            protocol: DumpProtocol::Tcp,
+            kind: DumpBindingKind::Plain,
+            external: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortRepr {
+    Number(u16),
+    #[serde(rename_all = "camelCase")]
+    Named {
+        name: String,
+        port: u16,
+        protocol: DumpProtocol,
+        #[serde(default)]
+        kind: DumpBindingKind,
+        #[serde(default)]
+        external: bool,
+    },
+}
+
+impl From<PortRepr> for DumpPort {
+    fn from(repr: PortRepr) -> DumpPort {
+        match repr {
+            PortRepr::Number(port) => DumpPort::unnamed(port),
+            PortRepr::Named {
+                name,
+                port,
+                protocol,
+                kind,
+                external,
+            } => DumpPort {
+                name,
+                port,
+                protocol,
+                kind,
+                external,
+            },
+        }
+    }
+}
+
+/// What a running process serves, from its `GET /rpc/_info` endpoint.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DumpServerInfo {
+    pub revision: String,
+    /// The version of the protocol between RPC clients and servers.
+    pub protocol_version: u32,
+    /// The ops of each RPC component the process is serving, by label.
+    pub components: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DumpGraph {
+    /// The components in each job.
+    pub jobs: BTreeMap<String, Vec<String>>,
+    pub tools: Vec<String>,
+    pub edges: Vec<DumpEdge>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DumpEdge {
+    pub from: String,
+    pub to: String,
+    pub kind: DumpEdgeKind,
+    /// For call edges, the number of calls observed.
+    #[serde(default)]
+    pub calls: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum DumpEdgeKind {
+    /// A declared dependency from one job on another.
+    JobDependency,
+    /// Calls observed from a component or tool to a component.
+    Call,
+}
+
+impl DumpGraph {
+    /// Add observed call edges, summing the counts of edges that are already
+    /// present.
+    pub fn add_calls<I: IntoIterator<Item = DumpEdge>>(&mut self, edges: I) {
+        for edge in edges.into_iter().filter(|e| e.kind == DumpEdgeKind::Call) {
+            let existing = self
+                .edges
+                .iter_mut()
+                .find(|e| e.kind == edge.kind && e.from == edge.from && e.to == edge.to);
+            match existing {
+                Some(e) => e.calls += edge.calls,
+                None => self.edges.push(edge),
+            }
+        }
+    }
+
+    /// Render the graph in the Graphviz DOT language. Jobs are drawn as
+    /// clusters of components, job dependencies as dashed edges between
+    /// clusters, and calls as solid edges labeled with their counts.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph amimono {{").unwrap();
+        writeln!(out, "  compound=true;").unwrap();
+        writeln!(out, "  rankdir=LR;").unwrap();
+        writeln!(out, "  node [shape=ellipse];").unwrap();
+        for (job, components) in self.jobs.iter() {
+            writeln!(out, "  subgraph {} {{", dot_id(&format!("cluster_{job}"))).unwrap();
+            writeln!(out, "    label={};", dot_id(job)).unwrap();
+            for comp in components.iter() {
+                writeln!(out, "    {};", dot_id(comp)).unwrap();
+            }
+            writeln!(out, "  }}").unwrap();
+        }
+        for tool in self.tools.iter() {
+            writeln!(out, "  {} [shape=box];", dot_id(tool)).unwrap();
+        }
+        for edge in self.edges.iter() {
+            match edge.kind {
+                DumpEdgeKind::JobDependency => {
+                    let from = self.jobs.get(&edge.from).and_then(|c| c.first());
+                    let to = self.jobs.get(&edge.to).and_then(|c| c.first());
+                    if let (Some(from), Some(to)) = (from, to) {
+                        writeln!(
+                            out,
+                            "  {} -> {} [style=dashed, ltail={}, lhead={}];",
+                            dot_id(from),
+                            dot_id(to),
+                            dot_id(&format!("cluster_{}", edge.from)),
+                            dot_id(&format!("cluster_{}", edge.to)),
+                        )
+                        .unwrap();
+                    }
+                }
+                DumpEdgeKind::Call => {
+                    writeln!(
+                        out,
+                        "  {} -> {} [label=\"{}\"];",
+                        dot_id(&edge.from),
+                        dot_id(&edge.to),
+                        edge.calls
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+fn dot_id(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
 }
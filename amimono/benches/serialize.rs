@@ -0,0 +1,65 @@
+//! Serialization on the RPC request path, the way it was done before
+//! buffers were pooled and request bodies shared, and the way it's done now.
+//!
+//! Pooling saves growing a buffer for mid-sized responses. Small responses
+//! are copied out of their buffer and responses over the pooled size aren't
+//! pooled, so both should take about as long as before.
+//!
+//! Run with `cargo bench -p amimono --bench serialize`.
+
+use std::hint::black_box;
+
+use amimono::rpc::{Bytes, to_json_bytes};
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct Item {
+    key: String,
+    value: String,
+    version: u64,
+}
+
+fn items(n: usize) -> Vec<Item> {
+    (0..n)
+        .map(|i| Item {
+            key: format!("key-{i}"),
+            value: "x".repeat(64),
+            version: i as u64,
+        })
+        .collect()
+}
+
+/// Serializing a response: into a fresh `Vec` each time, which was then
+/// handed to axum, or into the thread's pooled buffer.
+fn response(c: &mut Criterion) {
+    let mut group = c.benchmark_group("response");
+    for n in [1, 100, 10_000] {
+        let items = items(n);
+        group.bench_with_input(BenchmarkId::new("to_vec", n), &items, |b, items| {
+            b.iter(|| Bytes::from(serde_json::to_vec(black_box(items)).unwrap()))
+        });
+        group.bench_with_input(BenchmarkId::new("pooled", n), &items, |b, items| {
+            b.iter(|| to_json_bytes(black_box(items)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// Taking a request body from axum: copying it into a `Vec`, or sharing it.
+fn request_body(c: &mut Criterion) {
+    let mut group = c.benchmark_group("request_body");
+    for n in [1, 100, 10_000] {
+        let body = to_json_bytes(&items(n)).unwrap();
+        group.bench_with_input(BenchmarkId::new("to_vec", n), &body, |b, body| {
+            b.iter(|| black_box(body).to_vec())
+        });
+        group.bench_with_input(BenchmarkId::new("shared", n), &body, |b, body| {
+            b.iter(|| black_box(body).clone())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, response, request_body);
+criterion_main!(benches);
@@ -0,0 +1,758 @@
+//! Addressable actors.
+//!
+//! An actor is a piece of state identified by a kind and a key, such as a
+//! shopping cart keyed by user ID. Actors are hosted by the replicas of a
+//! component, with each key owned by one replica chosen by hashing it, and can
+//! be reached by key from anywhere in the app with an [`ActorRef`]:
+//!
+//! ```ignore
+//! struct Cart { items: Vec<String> }
+//!
+//! impl Actor for Cart {
+//!     type Host = cart_ops::ComponentKind;
+//!     type Message = CartMessage;
+//!     type Reply = Vec<String>;
+//!
+//!     const KIND: &'static str = "cart";
+//!
+//!     async fn start(_key: &str) -> Self {
+//!         Cart { items: Vec::new() }
+//!     }
+//!
+//!     async fn handle(&mut self, msg: CartMessage) -> AppResult<Vec<String>> {
+//!         // ...
+//!     }
+//! }
+//!
+//! // in the host component's startup:
+//! amimono::actor::serve::<Cart>();
+//!
+//! // anywhere else:
+//! let items = amimono::actor::get::<Cart>(user_id).ask(CartMessage::List).await?;
+//! ```
+//!
+//! Each actor has a mailbox and handles one message at a time, in the order
+//! they were delivered. Actors are started when their first message arrives,
+//! and passivated (dropped) after being idle for [`Actor::IDLE_TIMEOUT`]. If
+//! an actor panics while handling a message, the message fails and the actor
+//! is restarted from `start` when its next message arrives.
+//!
+//! Ownership follows the host component's replicas, so while replicas are
+//! being added or removed an actor may briefly be started on two of them.
+//! Actors that must not lose state should either persist it and restore it
+//! in `start`, or set [`Actor::HANDOFF`] to have it handed over when their
+//! key moves:
+//!
+//! * When a replica starts an actor, it first asks the replica that would
+//!   own the key if it didn't, which is where the actor lived before this
+//!   replica was added, to hand it over. That replica stops hosting the
+//!   actor, [exports][Actor::export] its state, and forwards any messages it
+//!   hadn't handled yet, and the new owner [imports][Actor::import] the
+//!   state instead of calling `start`.
+//! * When a replica shuts down, it hands each of its actors over to the
+//!   replica that owns the key once it's gone.
+//!
+//! Handoff is deliberately lazy, rather than triggered when the routing
+//! changes: replicas see a new replica at different times, and an actor
+//! that's never sent another message doesn't need to move at all. Until
+//! every caller sees the same replicas, an actor can be handed back and forth
+//! between its old and new owner. If two replicas ask each other for the
+//! same actor at once, both give up after a timeout and start it from
+//! `start`.
+//!
+//! Only the host component can ask for an actor to be handed over or
+//! imported, and only to one of its own replicas.
+
+use std::{
+    any::Any,
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use fnv::FnvHasher;
+use futures::{FutureExt, future::BoxFuture};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    AppError, AppResult, cli,
+    component::{self, ComponentKind, Location},
+    context::{self, RequestContext},
+    rpc::http,
+    runtime,
+    util::StaticHashMap,
+    watchdog,
+};
+
+/// A type of actor.
+pub trait Actor: Send + Sized + 'static {
+    /// The component whose replicas host actors of this kind.
+    type Host: ComponentKind;
+
+    /// The messages the actor handles.
+    type Message: Serialize + DeserializeOwned + Send + 'static;
+
+    /// The reply to a message sent with [`ActorRef::ask`].
+    type Reply: Serialize + DeserializeOwned + Send + 'static;
+
+    /// A string identifying this kind of actor, unique within the app.
+    const KIND: &'static str;
+
+    /// How long an actor can go without receiving a message before it's
+    /// passivated.
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+    /// Whether actors of this kind are handed over between replicas when
+    /// their key moves, with [`export`][Actor::export] and
+    /// [`import`][Actor::import]. This costs a call to another replica
+    /// whenever an actor is started.
+    const HANDOFF: bool = false;
+
+    /// Create the actor with the given key.
+    fn start(key: &str) -> impl Future<Output = Self> + Send;
+
+    /// Handle a message.
+    fn handle(&mut self, msg: Self::Message)
+    -> impl Future<Output = AppResult<Self::Reply>> + Send;
+
+    /// Export the actor's state for the replica taking it over, serialized
+    /// however the actor likes. Returning `None`, the default, has the new
+    /// owner call `start` instead.
+    fn export(&mut self) -> impl Future<Output = Option<Vec<u8>>> + Send {
+        async { None }
+    }
+
+    /// Create the actor with the given key from the state exported by the
+    /// replica that hosted it before. Defaults to ignoring the state and
+    /// calling `start`.
+    fn import(key: &str, state: Vec<u8>) -> impl Future<Output = Self> + Send {
+        let _ = state;
+        Self::start(key)
+    }
+}
+
+struct Envelope<A: Actor> {
+    msg: A::Message,
+    ctx: RequestContext,
+    reply: Option<oneshot::Sender<AppResult<A::Reply>>>,
+}
+
+/// What an actor's mailbox carries.
+enum Letter<A: Actor> {
+    Message(Envelope<A>),
+    /// Stop hosting the actor, and hand it over.
+    HandOff(oneshot::Sender<Handover<A>>),
+}
+
+/// An actor being handed over to another replica.
+struct Handover<A: Actor> {
+    state: Option<Vec<u8>>,
+    /// Messages that were delivered to the actor but not handled, in order.
+    pending: Vec<Envelope<A>>,
+}
+
+type Mailbox<A> = mpsc::UnboundedSender<Letter<A>>;
+
+/// The actors of one kind hosted by this process.
+struct Mailboxes<A: Actor> {
+    boxes: Arc<Mutex<HashMap<String, Mailbox<A>>>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Wire<K, M> {
+    key: K,
+    msg: M,
+}
+
+/// A request for an actor to be handed over to the replica at `to`.
+#[derive(Serialize, Deserialize)]
+struct HandOffRequest<K> {
+    key: K,
+    to: K,
+}
+
+/// An actor's state, handed over by the replica that hosted it.
+#[derive(Serialize, Deserialize)]
+struct Import<K> {
+    key: K,
+    state: Option<Vec<u8>>,
+}
+
+trait Host: Send + Sync {
+    fn deliver_json<'h>(&'h self, body: &[u8], ask: bool) -> BoxFuture<'h, AppResult<Vec<u8>>>;
+
+    fn hand_off_json<'h>(
+        &'h self,
+        caller: Option<&str>,
+        body: &[u8],
+    ) -> BoxFuture<'h, AppResult<Vec<u8>>>;
+
+    fn import_json<'h>(
+        &'h self,
+        caller: Option<&str>,
+        body: &[u8],
+    ) -> BoxFuture<'h, AppResult<Vec<u8>>>;
+
+    fn hand_off_all(&self) -> BoxFuture<'_, ()>;
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+static HOSTS: StaticHashMap<&'static str, dyn Host> = StaticHashMap::new();
+
+/// Host actors of the given kind in this process. This should be called by
+/// the `Host` component while it's starting.
+pub fn serve<A: Actor>() {
+    if HOSTS.get(A::KIND).is_some() {
+        return;
+    }
+    let mailboxes = Mailboxes::<A> {
+        boxes: Arc::new(Mutex::new(HashMap::new())),
+    };
+    HOSTS.insert(A::KIND, Arc::new(mailboxes));
+}
+
+/// Get a reference to the actor with the given key.
+pub fn get<A: Actor, K: Into<String>>(key: K) -> ActorRef<A> {
+    ActorRef {
+        key: key.into(),
+        _actor: PhantomData,
+    }
+}
+
+/// A reference to an actor, which can be used to send it messages.
+pub struct ActorRef<A: Actor> {
+    key: String,
+    _actor: PhantomData<fn() -> A>,
+}
+
+impl<A: Actor> Clone for ActorRef<A> {
+    fn clone(&self) -> Self {
+        get(self.key.clone())
+    }
+}
+
+impl<A: Actor> ActorRef<A> {
+    /// The actor's key.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Send a message without waiting for it to be handled. This returns once
+    /// the message is in the actor's mailbox.
+    pub async fn tell(&self, msg: A::Message) -> AppResult<()> {
+        let res = match self.owner().await? {
+            None => deliver_local::<A>(&self.key, msg, None),
+            Some(loc) => {
+                let path = format!("/actor/{}/tell", A::KIND);
+                let wire = Wire {
+                    key: &self.key,
+                    msg,
+                };
+                http::post_json::<_, ()>(A::Host::LABEL, loc.addr(), &path, &wire, None).await
+            }
+        };
+        res.map_err(|e| AppError::Downstream(A::Host::LABEL.to_owned(), Box::new(e)))
+    }
+
+    /// Send a message and wait for the actor's reply.
+    pub async fn ask(&self, msg: A::Message) -> AppResult<A::Reply> {
+        let res = match self.owner().await? {
+            None => {
+                let (tx, rx) = oneshot::channel();
+                deliver_local::<A>(&self.key, msg, Some(tx))?;
+                match rx.await {
+                    Ok(res) => res,
+                    Err(_) => Err(AppError::spurious("actor stopped before replying")),
+                }
+            }
+            Some(loc) => {
+                let path = format!("/actor/{}/ask", A::KIND);
+                let wire = Wire {
+                    key: &self.key,
+                    msg,
+                };
+                http::post_json(A::Host::LABEL, loc.addr(), &path, &wire, None).await
+            }
+        };
+        res.map_err(|e| AppError::Downstream(A::Host::LABEL.to_owned(), Box::new(e)))
+    }
+
+    /// The replica that owns the actor, or `None` if it's this process.
+    async fn owner(&self) -> AppResult<Option<Location>> {
+        let affinity = Some(affinity::<A>(&self.key));
+
+        if !A::Host::is_local() {
+            return http::discover::<A::Host>(affinity, false).await.map(Some);
+        }
+        if runtime::args().action == cli::Action::Local {
+            return Ok(None);
+        }
+        let owner = http::discover::<A::Host>(affinity, false).await?;
+        let myself = A::Host::myself().await?;
+        match owner.addr::<str>() == myself.addr::<str>() {
+            true => Ok(None),
+            false => Ok(Some(owner)),
+        }
+    }
+}
+
+/// The key an actor is placed by, hashed with FNV-1a so that every build
+/// places it the same way.
+fn affinity<A: Actor>(key: &str) -> u64 {
+    let mut hasher = FnvHasher::default();
+    A::KIND.hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The replica that would own an actor if this process didn't, or `None`
+/// if there's no other replica.
+async fn runner_up<A: Actor>(key: &str) -> AppResult<Option<Location>> {
+    if runtime::args().action == cli::Action::Local {
+        return Ok(None);
+    }
+    let myself = A::Host::myself().await?;
+    let replicas = http::balanced_replicas::<A::Host>(false)
+        .await?
+        .into_iter()
+        .filter(|r| r.location.addr::<str>() != myself.addr::<str>())
+        .collect::<Vec<_>>();
+    let owner = http::choose_replica(&replicas, Some(affinity::<A>(key)));
+    Ok(owner.map(|r| r.location.clone()))
+}
+
+/// Ask the replica that hosted an actor before this one to hand it over,
+/// returning its state, if it had any. Failing to reach the replica is
+/// treated as it not having the actor.
+async fn pull<A: Actor>(key: &str) -> Option<Vec<u8>> {
+    let res = async {
+        let Some(from) = runner_up::<A>(key).await? else {
+            return Ok(None);
+        };
+        let myself = A::Host::myself().await?;
+        let req = HandOffRequest {
+            key,
+            to: myself.addr::<str>(),
+        };
+        let path = format!("/actor/{}/handoff", A::KIND);
+        http::post_json(A::Host::LABEL, from.addr(), &path, &req, None).await
+    };
+    match res.await {
+        Ok(state) => state,
+        Err(e) => {
+            log::warn!("could not pull actor {}/{}: {}", A::KIND, key, e);
+            None
+        }
+    }
+}
+
+/// Send messages that were delivered to an actor before it was handed over
+/// on to its new owner, in order.
+async fn forward<A: Actor>(key: String, to: String, pending: Vec<Envelope<A>>) {
+    for Envelope { msg, ctx, reply } in pending {
+        // serialized up front, since messages needn't be `Sync`
+        let mode = if reply.is_some() { "ask" } else { "tell" };
+        let path = format!("/actor/{}/{}", A::KIND, mode);
+        let res = match serde_json::to_vec(&Wire { key: &key, msg }) {
+            Ok(body) => {
+                let post = http::post_bytes(A::Host::LABEL, &to, &path, body, None);
+                context::scope(ctx, post).await
+            }
+            Err(e) => Err(e.into()),
+        };
+        match reply {
+            Some(reply) => {
+                let res = res.and_then(|body| Ok(serde_json::from_slice(&body)?));
+                let _ = reply.send(res);
+            }
+            None => {
+                if let Err(e) = res {
+                    log::warn!(
+                        "could not forward message to actor {}/{}: {}",
+                        A::KIND,
+                        key,
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Hand every actor hosted by this process over to the replica that owns it
+/// once this process is gone, before the job exits.
+pub(crate) async fn hand_off_all() {
+    futures::future::join_all(HOSTS.values().iter().map(|host| host.hand_off_all())).await;
+}
+
+/// Deliver a message to an actor hosted by this process.
+fn deliver_local<A: Actor>(
+    key: &str,
+    msg: A::Message,
+    reply: Option<oneshot::Sender<AppResult<A::Reply>>>,
+) -> AppResult<()> {
+    let host = HOSTS
+        .get(A::KIND)
+        .ok_or_else(|| AppError::misc(format!("actor kind {} is not served here", A::KIND)))?;
+    let mailboxes = host
+        .as_any()
+        .downcast_ref::<Mailboxes<A>>()
+        .ok_or_else(|| AppError::misc(format!("actor kind {} is ambiguous", A::KIND)))?;
+    let env = Envelope {
+        msg,
+        ctx: context::current(),
+        reply,
+    };
+    mailboxes.deliver(key, env);
+    Ok(())
+}
+
+impl<A: Actor> Mailboxes<A> {
+    fn deliver(&self, key: &str, env: Envelope<A>) {
+        let mut boxes = self.boxes.lock().expect("lock poisoned");
+        let letter = match boxes.get(key) {
+            Some(tx) => match tx.send(Letter::Message(env)) {
+                Ok(()) => return,
+                // the actor's task is gone, start a new one below
+                Err(mpsc::error::SendError(letter)) => letter,
+            },
+            None => Letter::Message(env),
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        let _ = tx.send(letter);
+        boxes.insert(key.to_owned(), tx);
+        watchdog::spawn_for(
+            A::Host::LABEL,
+            run::<A>(self.boxes.clone(), key.to_owned(), rx, None),
+        );
+    }
+
+    /// Take an actor hosted by this process out of its mailbox, to hand it
+    /// over to another replica. `None` if it isn't hosted here.
+    async fn take(&self, key: &str) -> Option<Handover<A>> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let boxes = self.boxes.lock().expect("lock poisoned");
+            boxes.get(key)?.send(Letter::HandOff(tx)).ok()?;
+        }
+        rx.await.ok()
+    }
+
+    /// Hand an actor over to the replica at `to`, which imports it.
+    async fn push(&self, key: &str, to: &str) -> AppResult<()> {
+        let Some(handover) = self.take(key).await else {
+            return Ok(());
+        };
+        let import = Import {
+            key,
+            state: handover.state,
+        };
+        let path = format!("/actor/{}/import", A::KIND);
+        http::post_json::<_, ()>(A::Host::LABEL, to, &path, &import, None).await?;
+        forward(key.to_owned(), to.to_owned(), handover.pending).await;
+        Ok(())
+    }
+}
+
+impl<A: Actor> Host for Mailboxes<A> {
+    fn deliver_json<'h>(&'h self, body: &[u8], ask: bool) -> BoxFuture<'h, AppResult<Vec<u8>>> {
+        let wire = serde_json::from_slice::<Wire<String, A::Message>>(body);
+        Box::pin(async move {
+            let wire =
+                wire.map_err(|e| AppError::misc(format!("actor message parse error: {e}")))?;
+            let env = |reply| Envelope {
+                msg: wire.msg,
+                ctx: context::current(),
+                reply,
+            };
+            if !ask {
+                self.deliver(&wire.key, env(None));
+                return Ok(serde_json::to_vec(&())?);
+            }
+            let (tx, rx) = oneshot::channel();
+            self.deliver(&wire.key, env(Some(tx)));
+            match rx.await {
+                Ok(res) => Ok(serde_json::to_vec(&res?)?),
+                Err(_) => Err(AppError::spurious("actor stopped before replying")),
+            }
+        })
+    }
+
+    fn hand_off_json<'h>(
+        &'h self,
+        caller: Option<&str>,
+        body: &[u8],
+    ) -> BoxFuture<'h, AppResult<Vec<u8>>> {
+        let from_host = check_host::<A>(caller);
+        let req = serde_json::from_slice::<HandOffRequest<String>>(body);
+        Box::pin(async move {
+            from_host?;
+            let req = req.map_err(|e| AppError::misc(format!("handoff parse error: {e}")))?;
+            let replicas = http::balanced_replicas::<A::Host>(true).await?;
+            if !replicas.iter().any(|r| r.location.addr::<str>() == req.to) {
+                return Err(AppError::misc(format!(
+                    "cannot hand actor {}/{} over to {}, which is not a replica of {}",
+                    A::KIND,
+                    req.key,
+                    req.to,
+                    A::Host::LABEL
+                )));
+            }
+            let Some(handover) = self.take(&req.key).await else {
+                return Ok(serde_json::to_vec(&None::<Vec<u8>>)?);
+            };
+            log::info!("handing actor {}/{} over to {}", A::KIND, req.key, req.to);
+            // the new owner handles these once it has the state, which it
+            // gets in the response
+            tokio::spawn(forward(req.key, req.to, handover.pending));
+            Ok(serde_json::to_vec(&handover.state)?)
+        })
+    }
+
+    fn import_json<'h>(
+        &'h self,
+        caller: Option<&str>,
+        body: &[u8],
+    ) -> BoxFuture<'h, AppResult<Vec<u8>>> {
+        let from_host = check_host::<A>(caller);
+        let import = serde_json::from_slice::<Import<String>>(body);
+        Box::pin(async move {
+            from_host?;
+            let import = import.map_err(|e| AppError::misc(format!("import parse error: {e}")))?;
+            let mut boxes = self.boxes.lock().expect("lock poisoned");
+            if boxes.get(&import.key).is_some_and(|tx| !tx.is_closed()) {
+                log::warn!(
+                    "actor {}/{} was started before it was handed over, dropping its old state",
+                    A::KIND,
+                    import.key
+                );
+                return Ok(serde_json::to_vec(&())?);
+            }
+            let (tx, rx) = mpsc::unbounded_channel();
+            boxes.insert(import.key.clone(), tx);
+            watchdog::spawn_for(
+                A::Host::LABEL,
+                run::<A>(self.boxes.clone(), import.key, rx, Some(import.state)),
+            );
+            Ok(serde_json::to_vec(&())?)
+        })
+    }
+
+    fn hand_off_all(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            if !A::HANDOFF {
+                return;
+            }
+            let keys = self
+                .boxes
+                .lock()
+                .expect("lock poisoned")
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>();
+            futures::future::join_all(keys.iter().map(async |key| {
+                // sent as the host, since only it may import actors
+                let res = component::scope(A::Host::LABEL, async {
+                    match runner_up::<A>(key).await? {
+                        Some(to) => self.push(key, to.addr()).await,
+                        None => Ok(()),
+                    }
+                });
+                if let Err(e) = res.await {
+                    log::warn!("could not hand over actor {}/{}: {}", A::KIND, key, e);
+                }
+            }))
+            .await;
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Refuse a handoff or import that wasn't asked for by the actor's host
+/// component.
+fn check_host<A: Actor>(caller: Option<&str>) -> AppResult<()> {
+    match caller == Some(A::Host::LABEL) {
+        true => Ok(()),
+        false => Err(AppError::Forbidden {
+            caller: caller.map(|c| c.to_owned()),
+            component: format!("actor {}", A::KIND),
+        }),
+    }
+}
+
+/// Handle a request sent to this process over HTTP by `caller`: a message to
+/// `tell` or `ask` an actor, or a `handoff` or `import` of one between
+/// replicas.
+pub(crate) async fn handle_json(
+    kind: &str,
+    mode: &str,
+    caller: Option<&str>,
+    body: &[u8],
+) -> AppResult<Vec<u8>> {
+    let Some(host) = HOSTS.get(kind) else {
+        return Err(AppError::misc(format!(
+            "actor kind {kind} is not served here"
+        )));
+    };
+    match mode {
+        "tell" => host.deliver_json(body, false).await,
+        "ask" => host.deliver_json(body, true).await,
+        "handoff" => host.hand_off_json(caller, body).await,
+        "import" => host.import_json(caller, body).await,
+        _ => Err(AppError::misc(format!("unknown actor request {mode}"))),
+    }
+}
+
+/// An actor's task, which handles messages from its mailbox until the actor
+/// is passivated or handed over. An actor that was handed over from another
+/// replica starts with the state it was exported with.
+async fn run<A: Actor>(
+    boxes: Arc<Mutex<HashMap<String, Mailbox<A>>>>,
+    key: String,
+    mut rx: mpsc::UnboundedReceiver<Letter<A>>,
+    imported: Option<Option<Vec<u8>>>,
+) {
+    let mut actor: Option<A> = None;
+    // whether to ask another replica for the actor before starting it
+    let mut pulled = !A::HANDOFF || imported.is_some();
+    if let Some(Some(state)) = imported {
+        log::info!("actor {}/{} was handed over", A::KIND, key);
+        match AssertUnwindSafe(A::import(&key, state))
+            .catch_unwind()
+            .await
+        {
+            Ok(a) => actor = Some(a),
+            Err(_) => log::error!("actor {}/{} panicked while importing", A::KIND, key),
+        }
+    }
+    loop {
+        let letter = match tokio::time::timeout(A::IDLE_TIMEOUT, rx.recv()).await {
+            Ok(Some(letter)) => letter,
+            Ok(None) => return,
+            Err(_) => {
+                // messages are only sent while holding the lock, so nothing
+                // can arrive between this check and the removal
+                let mut boxes = boxes.lock().expect("lock poisoned");
+                if rx.is_empty() {
+                    log::debug!("passivating idle actor {}/{}", A::KIND, key);
+                    boxes.remove(&key);
+                    return;
+                }
+                continue;
+            }
+        };
+        let Envelope { msg, ctx, reply } = match letter {
+            Letter::Message(env) => env,
+            Letter::HandOff(tx) => {
+                // the replica asking for the actor may have given up already
+                if tx.is_closed() {
+                    continue;
+                }
+                let state = match actor.as_mut() {
+                    Some(a) => match AssertUnwindSafe(a.export()).catch_unwind().await {
+                        Ok(state) => state,
+                        Err(_) => {
+                            log::error!("actor {}/{} panicked while exporting", A::KIND, key);
+                            None
+                        }
+                    },
+                    None => None,
+                };
+                let returned = {
+                    // like passivation, nothing can arrive once the mailbox
+                    // is removed
+                    let mut boxes = boxes.lock().expect("lock poisoned");
+                    boxes.remove(&key);
+                    let mut pending = Vec::new();
+                    while let Ok(letter) = rx.try_recv() {
+                        if let Letter::Message(env) = letter {
+                            pending.push(env);
+                        }
+                    }
+                    match tx.send(Handover { state, pending }) {
+                        Ok(()) => return,
+                        // it gave up while the actor was exported, so the
+                        // actor stays here, with the messages it hadn't
+                        // handled back in a new mailbox
+                        Err(handover) => {
+                            let (tx, new_rx) = mpsc::unbounded_channel();
+                            for env in handover.pending {
+                                let _ = tx.send(Letter::Message(env));
+                            }
+                            boxes.insert(key.clone(), tx);
+                            rx = new_rx;
+                            handover.state
+                        }
+                    }
+                };
+                log::warn!("actor {}/{} was not handed over, keeping it", A::KIND, key);
+                // the export was for another replica, so the actor is
+                // restored from it in case exporting changed it
+                if let Some(state) = returned {
+                    match AssertUnwindSafe(A::import(&key, state))
+                        .catch_unwind()
+                        .await
+                    {
+                        Ok(a) => actor = Some(a),
+                        Err(_) => {
+                            log::error!("actor {}/{} panicked while importing", A::KIND, key);
+                            actor = None;
+                        }
+                    }
+                }
+                continue;
+            }
+        };
+
+        let res = context::scope(ctx, async {
+            if actor.is_none() && !pulled {
+                pulled = true;
+                if let Some(state) = pull::<A>(&key).await {
+                    log::info!("actor {}/{} was handed over", A::KIND, key);
+                    match AssertUnwindSafe(A::import(&key, state))
+                        .catch_unwind()
+                        .await
+                    {
+                        Ok(a) => actor = Some(a),
+                        Err(_) => {
+                            log::error!("actor {}/{} panicked while importing", A::KIND, key)
+                        }
+                    }
+                }
+            }
+            if actor.is_none() {
+                match AssertUnwindSafe(A::start(&key)).catch_unwind().await {
+                    Ok(a) => actor = Some(a),
+                    Err(_) => {
+                        log::error!("actor {}/{} panicked while starting", A::KIND, key);
+                        return Err(AppError::spurious("actor failed to start"));
+                    }
+                }
+            }
+            let a = actor.as_mut().expect("actor not started");
+            match AssertUnwindSafe(a.handle(msg)).catch_unwind().await {
+                Ok(res) => res,
+                Err(_) => {
+                    log::error!("actor {}/{} panicked, restarting", A::KIND, key);
+                    actor = None;
+                    Err(AppError::spurious("actor panicked"))
+                }
+            }
+        })
+        .await;
+
+        if let Some(reply) = reply {
+            let _ = reply.send(res);
+        } else if let Err(e) = res {
+            log::warn!("actor {}/{} failed to handle message: {}", A::KIND, key, e);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Administrative HTTP endpoints, served alongside RPC handlers.
+//!
+//! In the local and static runtimes, apps that turn it on with
+//! [`AppBuilder::with_resolver`][crate::config::AppBuilder::with_resolver]
+//! also serve `/admin/resolve/{label}`, which answers where a component is
+//! running right now, as JSON with its job and replicas, or as plain
+//! `host:port` lines for one of its ports with `?port=<name>`, e.g. for curl
+//! scripts. The JSON includes replicas that aren't ready, while the lines
+//! only list the ones calls would be sent to:
+//!
+//! ```text
+//! curl -s "http://$(curl -s 'localhost:9099/admin/resolve/calc?port=rpc')/rpc/calc" -d ...
+//! ```
+//!
+//! `/admin/discovery` dumps what every component's discovery returns, in
+//! any runtime, in the form [`--freeze-discovery`][crate::freeze] loads.
+
+use std::collections::{BTreeMap, HashMap};
+
+use axum::{
+    Json, Router,
+    extract::{Path, Query},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::Serialize;
+
+use crate::{
+    freeze, graph, health, metrics, rpc::journal, runtime, schedule, shard, storage, watchdog,
+};
+
+#[derive(Serialize)]
+struct HealthReport {
+    job: health::Status,
+    components: std::collections::BTreeMap<String, health::Status>,
+}
+
+pub(crate) fn router() -> Router {
+    let router = Router::new()
+        .route("/ready", get(ready))
+        .route("/admin/health", get(admin_health))
+        .route("/admin/storage", get(admin_storage))
+        .route("/admin/resources", get(admin_resources))
+        .route("/admin/graph", get(admin_graph))
+        .route("/admin/journal/{label}", get(admin_journal))
+        .route("/admin/schedules", get(admin_schedules))
+        .route("/admin/build", get(admin_build))
+        .route("/admin/discovery", get(admin_discovery))
+        .route("/admin/shards/{label}", get(admin_shards))
+        .route("/metrics", get(metrics_text));
+    match runtime::config().resolver() {
+        true => router.route("/admin/resolve/{label}", get(admin_resolve)),
+        false => router,
+    }
+}
+
+async fn ready() -> (StatusCode, String) {
+    let status = health::job();
+    match status.is_ready() {
+        true => (StatusCode::OK, status.to_string()),
+        false => (StatusCode::SERVICE_UNAVAILABLE, status.to_string()),
+    }
+}
+
+async fn admin_health() -> Json<HealthReport> {
+    Json(HealthReport {
+        job: health::job(),
+        components: health::components(),
+    })
+}
+
+async fn admin_storage() -> Json<std::collections::BTreeMap<String, storage::StorageUsage>> {
+    Json(storage::usage())
+}
+
+async fn admin_resources() -> Json<watchdog::ResourceUsage> {
+    Json(watchdog::usage())
+}
+
+async fn admin_graph() -> Json<amimono_schemas::DumpGraph> {
+    Json(graph::graph())
+}
+
+async fn admin_journal(Path(label): Path<String>) -> Response {
+    match journal::read(&label).await {
+        Some(entries) => {
+            ([(header::CONTENT_TYPE, "application/x-ndjson")], entries).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, format!("no journal for {label}")).into_response(),
+    }
+}
+
+async fn admin_schedules() -> Json<std::collections::BTreeMap<String, schedule::ScheduleReport>> {
+    Json(schedule::report())
+}
+
+async fn admin_build() -> Json<runtime::BuildInfo> {
+    Json(runtime::build_info())
+}
+
+#[derive(Serialize)]
+struct Resolved {
+    component: String,
+    job: String,
+    replicas: Vec<ResolvedReplica>,
+}
+
+#[derive(Serialize)]
+struct ResolvedReplica {
+    addr: String,
+    name: Option<String>,
+    draining: bool,
+    ready: bool,
+    ports: BTreeMap<String, u16>,
+}
+
+async fn admin_resolve(
+    Path(label): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    if !runtime::provider().serves_resolver() {
+        let msg = "resolving components is not available in this runtime";
+        return (StatusCode::NOT_FOUND, msg).into_response();
+    }
+    let cf = runtime::config();
+    let (Some(job), Some(comp)) = (cf.component_job(&label), cf.component(&label)) else {
+        return (StatusCode::NOT_FOUND, format!("no such component: {label}")).into_response();
+    };
+    let replicas = match runtime::provider().discover_all_replicas(&label).await {
+        Ok(replicas) => replicas,
+        Err(e) => {
+            let msg = format!("could not discover {label}: {e}");
+            return (StatusCode::SERVICE_UNAVAILABLE, msg).into_response();
+        }
+    };
+    let replicas = replicas
+        .into_iter()
+        .map(|r| {
+            // the ports the component declares, unless the runtime knows
+            // better
+            let mut ports = comp
+                .bindings
+                .iter()
+                .map(|b| (b.port.name.to_owned(), b.port.number))
+                .collect::<BTreeMap<_, _>>();
+            ports.extend(r.ports);
+            ResolvedReplica {
+                addr: r.location.addr::<str>().to_owned(),
+                name: r.name,
+                draining: r.draining,
+                ready: r.ready,
+                ports,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let Some(port) = query.get("port") else {
+        return Json(Resolved {
+            component: label,
+            job: job.to_owned(),
+            replicas,
+        })
+        .into_response();
+    };
+    let mut lines = String::new();
+    for r in replicas.iter().filter(|r| r.ready && !r.draining) {
+        match r.ports.get(port) {
+            Some(number) => lines.push_str(&format!("{}:{}\n", r.addr, number)),
+            None => {
+                let msg = format!("{label} has no port named {port}");
+                return (StatusCode::NOT_FOUND, msg).into_response();
+            }
+        }
+    }
+    lines.into_response()
+}
+
+async fn admin_discovery() -> Json<freeze::Snapshot> {
+    Json(freeze::snapshot().await)
+}
+
+async fn admin_shards(Path(label): Path<String>) -> Response {
+    match shard::report(&label) {
+        Some(report) => Json(report).into_response(),
+        None => (StatusCode::NOT_FOUND, format!("no shards of {label} here")).into_response(),
+    }
+}
+
+async fn metrics_text() -> ([(axum::http::HeaderName, &'static str); 1], String) {
+    let content_type = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+    (
+        [(axum::http::header::CONTENT_TYPE, content_type)],
+        metrics::render(),
+    )
+}
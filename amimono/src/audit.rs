@@ -0,0 +1,176 @@
+//! A structured audit log of administrative and security-relevant actions.
+//!
+//! Audit events are always logged with the `amimono::audit` target. Apps
+//! that need a durable record, e.g. for compliance, can also have them
+//! written as JSON lines to stdout, to a component's storage, or both, with
+//! [`AppBuilder::with_audit`][crate::config::AppBuilder::with_audit]. Events
+//! are written to `audit.jsonl` in the storage directory of the configured
+//! component, by the processes that run it; other processes only write to
+//! stdout and the log.
+//!
+//! The runtime records these events:
+//!
+//! * `job.start` when a job starts, with the app's build info. The first
+//!   start of a new revision marks a deploy.
+//! * `tool.start` and `tool.finish` around each tool run, with its arguments
+//!   and whether it succeeded.
+//! * `rpc.denied` when a call is refused because the caller isn't allowed,
+//!   `rpc.untrusted_caller` when a caller's identity can't be verified,
+//!   `rpc.unauthenticated` when a request is refused for lack of a valid
+//!   caller token, and `rpc.plaintext_refused` when an unsealed request is
+//!   refused.
+//!
+//! Components add their own with [`event`]:
+//!
+//! ```ignore
+//! amimono::audit::event("refund.issued")
+//!     .with("order", order_id)
+//!     .with("amount", amount)
+//!     .record();
+//! ```
+
+use std::{
+    path::PathBuf,
+    sync::{LazyLock, OnceLock},
+};
+
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+
+use crate::{cli, component, runtime};
+
+const FILE: &str = "audit.jsonl";
+
+/// An audit event, recorded with [`record`][AuditEvent::record].
+#[derive(Serialize)]
+pub struct AuditEvent {
+    time: String,
+    action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    process: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    component: Option<&'static str>,
+    revision: String,
+    warning: bool,
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Start an audit event for the given action. The current component, if
+/// any, and the process's job or tool are filled in automatically.
+pub fn event(action: &str) -> AuditEvent {
+    let process = match &runtime::args().action {
+        cli::Action::Job(job) => Some(job.clone()),
+        cli::Action::Tool(tool) => Some(tool.to_string()),
+        cli::Action::Local => Some("local".to_owned()),
+        cli::Action::DumpConfig | cli::Action::DumpConfigEmbedded | cli::Action::DumpGraph(_) => {
+            None
+        }
+    };
+    AuditEvent {
+        time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        action: action.to_owned(),
+        process,
+        component: component::current(),
+        revision: runtime::config().revision().to_owned(),
+        warning: false,
+        fields: serde_json::Map::new(),
+    }
+}
+
+impl AuditEvent {
+    /// Add a field to the event. Values that can't be serialized are
+    /// recorded as `null`.
+    pub fn with<V: Serialize>(mut self, key: &str, value: V) -> AuditEvent {
+        let value = serde_json::to_value(value).unwrap_or_default();
+        self.fields.insert(key.to_owned(), value);
+        self
+    }
+
+    /// Mark the event as a warning, e.g. for refused actions. It's logged at
+    /// the warning level rather than info.
+    pub fn warning(mut self) -> AuditEvent {
+        self.warning = true;
+        self
+    }
+
+    /// Record the event.
+    pub fn record(self) {
+        let fields = serde_json::Value::Object(self.fields.clone());
+        let level = match self.warning {
+            true => log::Level::Warn,
+            false => log::Level::Info,
+        };
+        log::log!(target: "amimono::audit", level, "{} {}", self.action, fields);
+
+        let Some(cf) = runtime::config().audit() else {
+            return;
+        };
+        let line = match serde_json::to_string(&self) {
+            Ok(line) => line,
+            Err(e) => {
+                log::warn!("could not serialize audit event {}: {e}", self.action);
+                return;
+            }
+        };
+        if let Some(label) = &cf.storage
+            && component::is_local(label)
+        {
+            let _ = WRITER.send(line.clone());
+        }
+        if cf.stdout {
+            println!("{line}");
+        }
+    }
+}
+
+static WRITER: LazyLock<mpsc::UnboundedSender<String>> = LazyLock::new(|| {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(write_events(rx));
+    tx
+});
+
+/// Where events are written, once the file has been opened.
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+async fn open() -> std::io::Result<tokio::fs::File> {
+    let path = match PATH.get() {
+        Some(path) => path.clone(),
+        None => {
+            let label = runtime::config()
+                .audit()
+                .and_then(|cf| cf.storage.as_deref())
+                .expect("audit storage not configured");
+            let dir = runtime::provider()
+                .storage(label)
+                .await
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            let path = PATH.get_or_init(|| dir.join(FILE)).clone();
+            log::info!("writing audit events to {}", path.display());
+            path
+        }
+    };
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+async fn write_events(mut rx: mpsc::UnboundedReceiver<String>) {
+    let mut file = None;
+    while let Some(line) = rx.recv().await {
+        let res = async {
+            let f = match &mut file {
+                Some(f) => f,
+                None => file.insert(open().await?),
+            };
+            f.write_all(line.as_bytes()).await?;
+            f.write_all(b"\n").await?;
+            f.flush().await
+        };
+        if let Err(e) = res.await {
+            log::error!("failed to write audit event: {e}");
+            file = None;
+        }
+    }
+}
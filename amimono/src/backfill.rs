@@ -0,0 +1,213 @@
+//! One-shot work that runs once per revision, such as backfills.
+//!
+//! A [`MigrationComponent`] is installed with [`Migration::installer`], in a
+//! job of its own. When the job starts, the component runs its work to
+//! completion, and then the job exits. The work can checkpoint its progress
+//! into the component's storage with [`Progress::checkpoint`], so that a run
+//! that's interrupted resumes where it left off, and completed runs are
+//! recorded, so that the work is skipped if the job runs again for the same
+//! revision.
+//!
+//! Jobs that depend on a migration's job, as declared with
+//! [`AppBuilder::add_job_dependency`][crate::config::AppBuilder::add_job_dependency],
+//! aren't rolled out until it completes: `ammn deploy` runs migration jobs
+//! as Kubernetes Jobs, or as oneshot units on static targets, and waits for
+//! them to finish before moving on. If the work fails, the job fails, and so
+//! does the deploy.
+//!
+//! ```ignore
+//! struct FillEmails;
+//!
+//! impl MigrationComponent for FillEmails {
+//!     const LABEL: &'static str = "fill-emails";
+//!
+//!     async fn run(progress: &Progress) -> Result<()> {
+//!         let mut next = progress.resume::<u64>().unwrap_or(0);
+//!         while let Some(last) = fill_batch(next).await? {
+//!             next = last + 1;
+//!             progress.checkpoint(&next)?;
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//! ```
+
+use std::{marker::PhantomData, path::PathBuf, time::Instant};
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{
+    audit,
+    component::{Component, ComponentKind},
+    error::{Error, Result},
+    metrics, runtime,
+};
+
+/// The file in the component's storage holding the latest checkpoint.
+const CHECKPOINT_FILE: &str = ".amimono-checkpoint.json";
+
+/// The file in the component's storage holding the last revision whose run
+/// completed.
+const COMPLETED_FILE: &str = ".amimono-completed";
+
+/// Work that runs to completion once per revision. See the
+/// [module-level documentation][self].
+pub trait MigrationComponent: Send + Sync + 'static {
+    /// A globally unique label for the component.
+    const LABEL: &'static str;
+
+    /// Do the work. Returning an error fails the job.
+    fn run(progress: &Progress) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// The component kind of a [`MigrationComponent`], which is installed with
+/// `Migration::<M>::installer`.
+pub struct Migration<M>(PhantomData<fn() -> M>);
+
+impl<M: MigrationComponent> ComponentKind for Migration<M> {
+    type Instance = ();
+
+    const LABEL: &'static str = M::LABEL;
+    const STORAGE: Option<usize> = Some(0);
+    const RUNS_ONCE: bool = true;
+}
+
+impl<M: MigrationComponent> Component for Migration<M> {
+    type Kind = Self;
+
+    fn main<F>(set_instance: F) -> impl Future<Output = ()> + Send
+    where
+        F: FnOnce(()) -> BoxFuture<'static, ()> + Send,
+    {
+        Box::pin(async {
+            set_instance(()).await;
+            run::<M>().await;
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Checkpoint<T> {
+    revision: String,
+    state: T,
+}
+
+/// The progress of a migration's run, passed to
+/// [`MigrationComponent::run`].
+pub struct Progress {
+    label: &'static str,
+    revision: String,
+    dir: Option<PathBuf>,
+}
+
+impl Progress {
+    /// The state saved by the latest checkpoint of this revision's run, if
+    /// there is one. Checkpoints from other revisions are ignored, since
+    /// their work may not mean the same thing.
+    pub fn resume<T: DeserializeOwned>(&self) -> Option<T> {
+        let path = self.dir.as_ref()?.join(CHECKPOINT_FILE);
+        let bytes = std::fs::read(&path).ok()?;
+        match serde_json::from_slice::<Checkpoint<T>>(&bytes) {
+            Ok(cp) if cp.revision == self.revision => Some(cp.state),
+            Ok(_) => None,
+            Err(e) => {
+                log::warn!("{}: ignoring unreadable checkpoint: {}", self.label, e);
+                None
+            }
+        }
+    }
+
+    /// Save the run's state, so that if it's interrupted, the next run can
+    /// resume from here. Without storage, e.g. on Kubernetes, checkpoints
+    /// aren't saved and interrupted runs start over.
+    pub fn checkpoint<T: Serialize>(&self, state: &T) -> Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let cp = Checkpoint {
+            revision: self.revision.clone(),
+            state,
+        };
+        let json = serde_json::to_vec(&cp)
+            .map_err(|e| Error::User(format!("failed to serialize checkpoint: {e}")))?;
+        // written with a rename, so a crash never leaves it half written
+        let tmp = dir.join(format!("{CHECKPOINT_FILE}.tmp"));
+        std::fs::write(&tmp, json)
+            .and_then(|_| std::fs::rename(&tmp, dir.join(CHECKPOINT_FILE)))
+            .map_err(|e| Error::User(format!("failed to save checkpoint: {e}")))
+    }
+
+    /// Report how much of the work is done, as the
+    /// `amimono_migration_progress` metric.
+    pub fn report(&self, done: u64, total: u64) {
+        let fraction = match total {
+            0 => 1.0,
+            _ => done as f64 / total as f64,
+        };
+        metrics::gauge("amimono_migration_progress", &[("component", self.label)]).set(fraction);
+    }
+
+    fn completed(&self) -> bool {
+        let Some(dir) = &self.dir else {
+            return false;
+        };
+        std::fs::read_to_string(dir.join(COMPLETED_FILE))
+            .is_ok_and(|rev| rev.trim() == self.revision)
+    }
+
+    fn complete(&self) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        if let Err(e) = std::fs::write(dir.join(COMPLETED_FILE), &self.revision) {
+            log::warn!("{}: failed to record completion: {}", self.label, e);
+        }
+        let _ = std::fs::remove_file(dir.join(CHECKPOINT_FILE));
+    }
+}
+
+async fn run<M: MigrationComponent>() {
+    let label = M::LABEL;
+    let revision = runtime::config().revision().to_owned();
+    let dir = match runtime::provider().storage(label).await {
+        Ok(dir) => Some(dir),
+        Err(e) => {
+            log::warn!(
+                "{label}: no storage for checkpoints, so an interrupted run starts over: {e}"
+            );
+            None
+        }
+    };
+    let progress = Progress {
+        label,
+        revision,
+        dir,
+    };
+    if progress.completed() {
+        log::info!(
+            "{label}: already completed for revision {}, skipping",
+            progress.revision
+        );
+        return;
+    }
+
+    log::info!("{label}: running for revision {}", progress.revision);
+    let started = Instant::now();
+    if let Err(e) = M::run(&progress).await {
+        audit::event("migration.failed")
+            .with("component", label)
+            .with("error", e.to_string())
+            .warning()
+            .record();
+        // panicking fails the job, and a deploy waiting on it
+        panic!("{label}: migration failed: {e}");
+    }
+    progress.complete();
+    progress.report(1, 1);
+    log::info!("{label}: completed in {:?}", started.elapsed());
+    audit::event("migration.completed")
+        .with("component", label)
+        .with("revision", progress.revision.as_str())
+        .record();
+}
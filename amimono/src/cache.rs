@@ -0,0 +1,309 @@
+//! A built-in cache component, shared by the rest of the app.
+//!
+//! Rather than each component keeping its own ad-hoc cache, the app installs
+//! [`CacheComponent`] in a job, and components use it through a typed
+//! [`Cache`] for a namespace of their choosing:
+//!
+//! ```ignore
+//! // in the app's config
+//! JobBuilder::new()
+//!     .with_label("cache")
+//!     .install(amimono::cache::CacheComponent::installer)
+//!
+//! // anywhere else
+//! let profiles = Cache::<Profile>::new("profiles");
+//! if let Some(profile) = profiles.get(&user_id).await? {
+//!     return Ok(profile);
+//! }
+//! let profile = load_profile(&user_id).await?;
+//! profiles.put(&user_id, &profile, Some(Duration::from_secs(300))).await?;
+//! ```
+//!
+//! Each replica of the component holds entries in memory, up to a size set
+//! with [`AppBuilder::with_cache`][crate::config::AppBuilder::with_cache],
+//! evicting the least recently used ones when it's full. Entries are lost
+//! when a replica restarts, so the cache should only hold things that can be
+//! loaded again. By default, callers use the nearest replica, which is their
+//! own process's if the component is installed in their job, so each replica
+//! caches on its own. A sharded cache instead sends each key to the replica
+//! that owns it, chosen by hashing, so that every caller sees the same
+//! entries; keys move when replicas are added or removed.
+//!
+//! Values are stored as JSON, so a namespace should always be used with the
+//! same type.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use fnv::FnvHasher;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    AppError, AppResult,
+    component::Location,
+    metrics,
+    rpc::{RpcResult, http},
+    runtime,
+};
+
+crate::rpc_component! {
+    // the generated clients that `Cache` doesn't use would otherwise be warned about
+    #[allow(dead_code)]
+    mod ops {
+        const LABEL: &'static str = "amimono-cache";
+
+        /// Get an entry's value, as JSON.
+        fn get(namespace: String, key: String) -> Option<String>;
+
+        /// Set an entry's value, as JSON, expiring after a TTL in
+        /// milliseconds, or the default.
+        fn put(namespace: String, key: String, value: String, ttl_ms: Option<u64>) -> ();
+
+        /// The milliseconds left before an entry expires.
+        fn ttl(namespace: String, key: String) -> Option<u64>;
+
+        /// Remove an entry, returning whether there was one.
+        fn remove(namespace: String, key: String) -> bool;
+    }
+}
+
+/// The label of the cache component.
+pub const LABEL: &str = "amimono-cache";
+
+/// The built-in cache component. See the [module-level documentation][self].
+pub type CacheComponent = ops::Component<CacheHandler>;
+
+type Key = (String, String);
+
+struct Entry {
+    value: String,
+    expires: Option<Instant>,
+    /// When the entry was last used, as a position in `Lru::order`.
+    used: u64,
+}
+
+impl Entry {
+    fn size(key: &Key, value: &str) -> usize {
+        key.0.len() + key.1.len() + value.len()
+    }
+}
+
+/// Entries in memory, evicted least recently used first.
+struct Lru {
+    entries: HashMap<Key, Entry>,
+    order: BTreeMap<u64, Key>,
+    next: u64,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl Lru {
+    fn get(&mut self, key: &Key) -> Option<&Entry> {
+        let now = Instant::now();
+        if self.entries.get(key)?.expires.is_some_and(|t| t <= now) {
+            self.remove(key);
+            return None;
+        }
+        let used = self.next;
+        self.next += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.used);
+        self.order.insert(used, key.clone());
+        entry.used = used;
+        Some(entry)
+    }
+
+    fn put(&mut self, key: Key, value: String, expires: Option<Instant>) {
+        self.remove(&key);
+        let size = Entry::size(&key, &value);
+        if size > self.max_bytes {
+            return;
+        }
+        let used = self.next;
+        self.next += 1;
+        self.order.insert(used, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                expires,
+                used,
+            },
+        );
+        self.bytes += size;
+        while self.bytes > self.max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.remove(&oldest);
+            metrics::counter("amimono_cache_evictions", &[]).inc();
+        }
+        metrics::gauge("amimono_cache_bytes", &[]).set(self.bytes as f64);
+    }
+
+    fn remove(&mut self, key: &Key) -> bool {
+        let Some(entry) = self.entries.remove(key) else {
+            return false;
+        };
+        self.order.remove(&entry.used);
+        self.bytes -= Entry::size(key, &entry.value);
+        metrics::gauge("amimono_cache_bytes", &[]).set(self.bytes as f64);
+        true
+    }
+}
+
+/// The handler of [`CacheComponent`].
+pub struct CacheHandler {
+    lru: Mutex<Lru>,
+    default_ttl: Option<Duration>,
+}
+
+impl CacheHandler {
+    fn key(namespace: &str, key: &str) -> Key {
+        (namespace.to_owned(), key.to_owned())
+    }
+}
+
+impl ops::Handler for CacheHandler {
+    async fn new() -> Self {
+        let cf = runtime::config().cache().cloned().unwrap_or_default();
+        CacheHandler {
+            lru: Mutex::new(Lru {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                next: 0,
+                bytes: 0,
+                max_bytes: cf.max_bytes,
+            }),
+            default_ttl: cf.default_ttl,
+        }
+    }
+
+    async fn get(&self, namespace: &String, key: &String) -> RpcResult<Option<String>> {
+        let mut lru = self.lru.lock().expect("lock poisoned");
+        let value = lru.get(&Self::key(namespace, key)).map(|e| e.value.clone());
+        let name = match value {
+            Some(_) => "amimono_cache_hits",
+            None => "amimono_cache_misses",
+        };
+        metrics::counter(name, &[("namespace", namespace)]).inc();
+        Ok(value)
+    }
+
+    async fn put(
+        &self,
+        namespace: &String,
+        key: &String,
+        value: &String,
+        ttl_ms: &Option<u64>,
+    ) -> RpcResult<()> {
+        let ttl = ttl_ms.map(Duration::from_millis).or(self.default_ttl);
+        let expires = ttl.map(|ttl| Instant::now() + ttl);
+        let mut lru = self.lru.lock().expect("lock poisoned");
+        lru.put(Self::key(namespace, key), value.clone(), expires);
+        Ok(())
+    }
+
+    async fn ttl(&self, namespace: &String, key: &String) -> RpcResult<Option<u64>> {
+        let mut lru = self.lru.lock().expect("lock poisoned");
+        let expires = lru.get(&Self::key(namespace, key)).and_then(|e| e.expires);
+        Ok(expires.map(|t| t.saturating_duration_since(Instant::now()).as_millis() as u64))
+    }
+
+    async fn remove(&self, namespace: &String, key: &String) -> RpcResult<bool> {
+        let mut lru = self.lru.lock().expect("lock poisoned");
+        Ok(lru.remove(&Self::key(namespace, key)))
+    }
+}
+
+/// A namespace of the cache holding values of type `V`.
+pub struct Cache<V> {
+    namespace: String,
+    client: ops::Client,
+    _value: PhantomData<fn() -> V>,
+}
+
+impl<V> Clone for Cache<V> {
+    fn clone(&self) -> Self {
+        Cache {
+            namespace: self.namespace.clone(),
+            client: self.client.clone(),
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> Cache<V> {
+    /// Use a namespace of the cache.
+    pub fn new(namespace: &str) -> Cache<V> {
+        Cache {
+            namespace: namespace.to_owned(),
+            client: ops::Client::new(),
+            _value: PhantomData,
+        }
+    }
+
+    /// The replica that owns a key, if the cache is sharded.
+    async fn owner(&self, key: &str) -> AppResult<Option<Location>> {
+        if !runtime::config().cache().is_some_and(|cf| cf.sharded) {
+            return Ok(None);
+        }
+        let replicas = http::balanced_replicas::<ops::ComponentKind>(false).await?;
+        // FNV-1a, so that every build agrees on the owner
+        let mut hasher = FnvHasher::default();
+        (&self.namespace, key).hash(&mut hasher);
+        match http::choose_replica(&replicas, Some(hasher.finish())) {
+            Some(replica) => Ok(Some(replica.location.clone())),
+            None => Err(AppError::misc("no cache replicas")),
+        }
+    }
+
+    /// Get the value of a key, if it's cached and hasn't expired.
+    pub async fn get(&self, key: &str) -> AppResult<Option<V>> {
+        let (ns, k) = (self.namespace.clone(), key.to_owned());
+        let json = match self.owner(key).await? {
+            Some(loc) => self.client.at(loc).get(ns, k).await?,
+            None => self.client.get(ns, k).await?,
+        };
+        match json {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Cache a value for a key, replacing any value it already has. It
+    /// expires after `ttl`, or the cache's default TTL if it's `None`.
+    pub async fn put(&self, key: &str, value: &V, ttl: Option<Duration>) -> AppResult<()> {
+        let (ns, k) = (self.namespace.clone(), key.to_owned());
+        let json = serde_json::to_string(value)?;
+        let ttl_ms = ttl.map(|ttl| ttl.as_millis() as u64);
+        match self.owner(key).await? {
+            Some(loc) => self.client.at(loc).put(ns, k, json, ttl_ms).await,
+            None => self.client.put(ns, k, json, ttl_ms).await,
+        }
+    }
+
+    /// How long until a key's value expires, if it's cached and has a TTL.
+    pub async fn ttl(&self, key: &str) -> AppResult<Option<Duration>> {
+        let (ns, k) = (self.namespace.clone(), key.to_owned());
+        let ms = match self.owner(key).await? {
+            Some(loc) => self.client.at(loc).ttl(ns, k).await?,
+            None => self.client.ttl(ns, k).await?,
+        };
+        Ok(ms.map(Duration::from_millis))
+    }
+
+    /// Remove a key's value, returning whether it was cached.
+    pub async fn remove(&self, key: &str) -> AppResult<bool> {
+        let (ns, k) = (self.namespace.clone(), key.to_owned());
+        match self.owner(key).await? {
+            Some(loc) => self.client.at(loc).remove(ns, k).await,
+            None => self.client.remove(ns, k).await,
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! Typed channels between components in the same job.
+//!
+//! For pipelines where one component hands a steady stream of items to
+//! another in the same job, RPC calls add overhead for little benefit. A
+//! channel is instead declared on the app with
+//! [`AppBuilder::with_channel`][crate::config::AppBuilder::with_channel],
+//! which fails to build if the two components end up in different jobs. The
+//! runtime creates the channel on first use, and each component gets its end
+//! by the channel's label:
+//!
+//! ```ignore
+//! let frames = amimono::channel::producer::<Frame>("frames");
+//! frames.send(frame).await;
+//!
+//! let mut frames = amimono::channel::consumer::<Frame>("frames");
+//! let frame = frames.recv().await;
+//! ```
+//!
+//! Channels are bounded, so senders wait when the consumer falls behind.
+//! Items that haven't been received when the consumer is dropped, e.g.
+//! because its component panicked, are kept for the next consumer.
+
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    sync::{LazyLock, Mutex},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{component, metrics, runtime};
+
+/// The ends of a queue in this process. The runtime keeps the receiver
+/// while no one is receiving, so the queue is never closed and items that
+/// haven't been received are kept.
+pub(crate) struct Ends<T> {
+    pub(crate) tx: mpsc::Sender<T>,
+    pub(crate) rx: Option<mpsc::Receiver<T>>,
+}
+
+struct Slot {
+    ends: Box<dyn Any + Send>,
+    item_type: &'static str,
+}
+
+/// Queues in this process by key, each carrying the type it was first used
+/// with. Pipelines keep the queues between stages in the same job here too.
+pub(crate) struct Slots<K> {
+    slots: Mutex<HashMap<K, Slot>>,
+}
+
+impl<K: Hash + Eq> Slots<K> {
+    pub(crate) fn new() -> Slots<K> {
+        Slots {
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run `f` with the ends of a queue, creating it with room for
+    /// `capacity` items if necessary and passing its sender to `created`.
+    /// Panics if the queue carries a different type, naming it as `what`.
+    pub(crate) fn with_ends<T: Send + 'static, R>(
+        &self,
+        key: K,
+        capacity: usize,
+        what: fmt::Arguments,
+        created: impl FnOnce(&mpsc::Sender<T>),
+        f: impl FnOnce(&mut Ends<T>) -> R,
+    ) -> R {
+        let mut slots = self.slots.lock().expect("lock poisoned");
+        let slot = slots.entry(key).or_insert_with(|| {
+            let (tx, rx) = mpsc::channel::<T>(capacity);
+            created(&tx);
+            Slot {
+                ends: Box::new(Ends { tx, rx: Some(rx) }),
+                item_type: std::any::type_name::<T>(),
+            }
+        });
+        match slot.ends.downcast_mut::<Ends<T>>() {
+            Some(ends) => f(ends),
+            None => panic!(
+                "{} carries {}, not {}",
+                what,
+                slot.item_type,
+                std::any::type_name::<T>()
+            ),
+        }
+    }
+}
+
+static CHANNELS: LazyLock<Slots<String>> = LazyLock::new(Slots::new);
+
+/// Run `f` with the ends of a channel, creating it if necessary. Panics if
+/// the channel isn't configured, isn't running in this process, or carries
+/// a different type.
+fn with_ends<T: Send + 'static, R>(label: &str, f: impl FnOnce(&mut Ends<T>) -> R) -> R {
+    let cf = match runtime::config().channel(label) {
+        Some(cf) => cf,
+        None => panic!("no such channel: {}", label),
+    };
+    if !component::is_local(&cf.producer) {
+        panic!(
+            "channel {} used outside of its job, which runs {} and {}",
+            label, cf.producer, cf.consumer
+        );
+    }
+
+    CHANNELS.with_ends(
+        label.to_owned(),
+        cf.capacity,
+        format_args!("channel {}", label),
+        |_| (),
+        f,
+    )
+}
+
+/// Get the sending end of a channel. Producers can be cloned, and any number
+/// of them can be used at once.
+pub fn producer<T: Send + 'static>(label: &str) -> Producer<T> {
+    Producer {
+        tx: with_ends(label, |ends: &mut Ends<T>| ends.tx.clone()),
+        label: label.to_owned(),
+    }
+}
+
+/// Get the receiving end of a channel. A channel has one consumer at a
+/// time, and this panics if another one hasn't been dropped.
+pub fn consumer<T: Send + 'static>(label: &str) -> Consumer<T> {
+    match with_ends(label, |ends: &mut Ends<T>| ends.rx.take()) {
+        Some(rx) => Consumer {
+            rx: Some(rx),
+            label: label.to_owned(),
+        },
+        None => panic!("channel {} already has a consumer", label),
+    }
+}
+
+/// The sending end of a channel.
+pub struct Producer<T> {
+    tx: mpsc::Sender<T>,
+    label: String,
+}
+
+impl<T> Clone for Producer<T> {
+    fn clone(&self) -> Self {
+        Producer {
+            tx: self.tx.clone(),
+            label: self.label.clone(),
+        }
+    }
+}
+
+impl<T: Send + 'static> Producer<T> {
+    /// Send an item, waiting for room if the channel is full.
+    pub async fn send(&self, item: T) {
+        // the runtime keeps the receiver while there's no consumer, so the
+        // channel is never closed
+        let _ = self.tx.send(item).await;
+        self.report();
+    }
+
+    /// Send an item if there's room for it, or give it back if the channel
+    /// is full.
+    pub fn try_send(&self, item: T) -> Result<(), T> {
+        match self.tx.try_send(item) {
+            Ok(()) => {
+                self.report();
+                Ok(())
+            }
+            Err(e) => {
+                let labels = [("channel", self.label.as_str())];
+                metrics::counter("amimono_channel_full", &labels).inc();
+                Err(e.into_inner())
+            }
+        }
+    }
+
+    fn report(&self) {
+        let queued = self.tx.max_capacity() - self.tx.capacity();
+        let labels = [("channel", self.label.as_str())];
+        metrics::gauge("amimono_channel_queued", &labels).set(queued as f64);
+    }
+}
+
+/// The receiving end of a channel.
+pub struct Consumer<T: Send + 'static> {
+    // only taken when dropped
+    rx: Option<mpsc::Receiver<T>>,
+    label: String,
+}
+
+impl<T: Send + 'static> Consumer<T> {
+    /// Receive the next item, waiting for one if the channel is empty.
+    pub async fn recv(&mut self) -> T {
+        let rx = self.rx.as_mut().expect("consumer already dropped");
+        rx.recv().await.expect("channel closed")
+    }
+
+    /// Receive the next item if there is one.
+    pub fn try_recv(&mut self) -> Option<T> {
+        let rx = self.rx.as_mut().expect("consumer already dropped");
+        rx.try_recv().ok()
+    }
+}
+
+impl<T: Send + 'static> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        let rx = self.rx.take();
+        with_ends(&self.label, |ends: &mut Ends<T>| ends.rx = rx);
+    }
+}
@@ -2,17 +2,27 @@ pub struct Args {
     pub action: Action,
     pub bind: Option<String>,
     pub r#static: Option<String>,
+    pub simulate_network: Option<String>,
+    pub freeze_discovery: Option<String>,
     pub extra: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Action {
     DumpConfig,
+    DumpConfigEmbedded,
+    DumpGraph(GraphFormat),
     Local,
     Job(String),
     Tool(String),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    Dot,
+    Json,
+}
+
 pub fn parse_args() -> Result<Args, String> {
     use clap::{Arg, ArgAction, Command};
 
@@ -23,6 +33,20 @@ pub fn parse_args() -> Result<Args, String> {
                 .action(ArgAction::SetTrue)
                 .help("Dump the application configuration and exit"),
         )
+        .arg(
+            Arg::new("dump-config-embedded")
+                .long("dump-config-embedded")
+                .action(ArgAction::SetTrue)
+                .help("Print the configuration embedded at build time, without starting the app"),
+        )
+        .arg(
+            Arg::new("dump-graph")
+                .long("dump-graph")
+                .num_args(0..=1)
+                .value_parser(["dot", "json"])
+                .default_missing_value("dot")
+                .help("Dump the dependency graph as DOT or JSON and exit"),
+        )
         .arg(
             Arg::new("local")
                 .long("local")
@@ -53,6 +77,18 @@ pub fn parse_args() -> Result<Args, String> {
                 .action(ArgAction::Set)
                 .help("The IP address to bind to."),
         )
+        .arg(
+            Arg::new("simulate-network")
+                .long("simulate-network")
+                .action(ArgAction::Set)
+                .help("A file describing simulated network conditions for RPC calls."),
+        )
+        .arg(
+            Arg::new("freeze-discovery")
+                .long("freeze-discovery")
+                .action(ArgAction::Set)
+                .help("A dump of /admin/discovery to answer all discovery from, for debugging."),
+        )
         .arg(
             Arg::new("extra")
                 .num_args(0..)
@@ -63,6 +99,12 @@ pub fn parse_args() -> Result<Args, String> {
 
     let action = [
         m.get_flag("dump-config").then_some(Action::DumpConfig),
+        m.get_flag("dump-config-embedded")
+            .then_some(Action::DumpConfigEmbedded),
+        m.get_one::<String>("dump-graph").map(|f| match f.as_str() {
+            "json" => Action::DumpGraph(GraphFormat::Json),
+            _ => Action::DumpGraph(GraphFormat::Dot),
+        }),
         m.get_flag("local").then_some(Action::Local),
         m.get_one::<String>("job").map(|j| Action::Job(j.clone())),
         m.get_one::<String>("tool").map(|j| Action::Tool(j.clone())),
@@ -71,10 +113,12 @@ pub fn parse_args() -> Result<Args, String> {
     .filter(|x| x.is_some())
     .reduce(|_, _| None)
     .flatten()
-    .ok_or("must specify exactly one of --local, --job <job>, --tool <tool>, or --dump-config")?;
+    .ok_or("must specify exactly one of --local, --job <job>, --tool <tool>, --dump-config, --dump-config-embedded, or --dump-graph")?;
 
     let bind = m.get_one::<String>("bind").cloned();
     let r#static = m.get_one::<String>("static").cloned();
+    let simulate_network = m.get_one::<String>("simulate-network").cloned();
+    let freeze_discovery = m.get_one::<String>("freeze-discovery").cloned();
     let extra = m
         .get_many::<String>("extra")
         .map(|x| x.cloned().collect())
@@ -84,6 +128,8 @@ pub fn parse_args() -> Result<Args, String> {
         action,
         bind,
         r#static,
+        simulate_network,
+        freeze_discovery,
         extra,
     })
 }
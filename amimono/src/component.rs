@@ -1,18 +1,25 @@
 use std::{
     any::{Any, TypeId},
     borrow::Borrow,
+    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
     fmt,
+    panic::AssertUnwindSafe,
     path::PathBuf,
+    sync::{LazyLock, Mutex, Once, RwLock},
+    time::{Duration, Instant},
 };
 
-use futures::future::BoxFuture;
+use futures::{FutureExt, future::BoxFuture};
 use tokio::sync::SetOnce;
 
 use crate::{
     cli,
     config::{ComponentConfig, JobBuilder},
     error::Result,
-    runtime,
+    health, metrics,
+    migration::{self, StorageMigration},
+    rpc::RpcOp,
+    runtime, shard, storage, tasks,
     util::StaticHashMap,
 };
 
@@ -20,6 +27,9 @@ use crate::{
 #[derive(Copy, Clone, Hash, PartialEq, Eq)]
 pub struct Location<A = String> {
     ephemeral: bool,
+    /// Set for locations that name a replica by its ordinal, which are
+    /// resolved to an address by the runtime when they're called.
+    ordinal: Option<usize>,
     addr: A,
 }
 
@@ -27,6 +37,7 @@ impl<A> Location<A> {
     pub fn emphemeral(addr: A) -> Location<A> {
         Location {
             ephemeral: true,
+            ordinal: None,
             addr,
         }
     }
@@ -34,6 +45,7 @@ impl<A> Location<A> {
     pub fn stable(addr: A) -> Location<A> {
         Location {
             ephemeral: false,
+            ordinal: None,
             addr,
         }
     }
@@ -46,6 +58,12 @@ impl<A> Location<A> {
         !self.ephemeral
     }
 
+    /// The ordinal of the replica this location names, if it was created
+    /// with [`Location::ordinal`].
+    pub fn as_ordinal(&self) -> Option<usize> {
+        self.ordinal
+    }
+
     pub fn as_ephemeral(self) -> std::result::Result<A, A> {
         match self.ephemeral {
             true => Ok(self.addr),
@@ -74,9 +92,22 @@ impl<A> Location<A> {
 }
 
 impl Location<String> {
+    /// The stable location of the `n`th replica of a stateful job, counting
+    /// from 0, e.g. a StatefulSet's pod with ordinal `n` on Kubernetes. It
+    /// has no address of its own, and is resolved to one by the runtime when
+    /// a client is pointed at it with `at`.
+    pub fn ordinal(n: usize) -> Location {
+        Location {
+            ephemeral: false,
+            ordinal: Some(n),
+            addr: String::new(),
+        }
+    }
+
     pub fn borrow(&'_ self) -> Location<&'_ str> {
         Location {
             ephemeral: self.ephemeral,
+            ordinal: self.ordinal,
             addr: self.addr.as_str(),
         }
     }
@@ -86,6 +117,7 @@ impl<'l> Location<&'l str> {
     pub fn into_owned(self) -> Location<String> {
         Location {
             ephemeral: self.ephemeral,
+            ordinal: self.ordinal,
             addr: self.addr.to_owned(),
         }
     }
@@ -93,6 +125,9 @@ impl<'l> Location<&'l str> {
 
 impl<L: fmt::Debug> fmt::Debug for Location<L> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(n) = self.ordinal {
+            return write!(f, "Location::ordinal({n})");
+        }
         let kind = match self.ephemeral {
             true => "ephemeral",
             false => "stable",
@@ -101,6 +136,166 @@ impl<L: fmt::Debug> fmt::Debug for Location<L> {
     }
 }
 
+/// A replica of a component, as reported by discovery.
+#[derive(Clone, Debug)]
+pub struct Replica {
+    /// Where the replica can be reached.
+    pub location: Location,
+
+    /// The replica's name, if the runtime provides one.
+    pub name: Option<String>,
+
+    /// The zone the replica is placed in, if known.
+    pub zone: Option<String>,
+
+    /// The relative share of traffic this replica should receive.
+    pub weight: u32,
+
+    /// Whether the replica is being drained. Draining replicas are still
+    /// stably placed but should not receive new traffic.
+    pub draining: bool,
+
+    /// Whether the replica has passed its readiness checks. Replicas that are
+    /// running but not ready are only discovered by
+    /// [`ComponentKind::discover_all_replicas`].
+    pub ready: bool,
+
+    /// Named ports the replica exposes, if the runtime provides them.
+    pub ports: BTreeMap<String, u16>,
+
+    /// The shards the replica owns, as it last reported them, if the
+    /// component is [sharded][crate::shard] and the replica could be asked.
+    pub shards: Option<BTreeSet<u32>>,
+}
+
+impl Replica {
+    /// A replica with no metadata beyond its location.
+    pub fn at(location: Location) -> Replica {
+        Replica {
+            location,
+            name: None,
+            zone: None,
+            weight: 1,
+            draining: false,
+            ready: true,
+            ports: BTreeMap::new(),
+            shards: None,
+        }
+    }
+}
+
+/// A named port bound by a component.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Port {
+    /// The port's name, which runtimes use as the key for the port in a
+    /// replica's metadata. RPC components name their port `rpc`.
+    pub name: &'static str,
+    pub number: u16,
+    pub protocol: Protocol,
+}
+
+/// The protocol spoken on a port.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    /// HTTP over TCP.
+    Http,
+}
+
+impl Port {
+    pub const fn tcp(name: &'static str, number: u16) -> Port {
+        Port {
+            name,
+            number,
+            protocol: Protocol::Tcp,
+        }
+    }
+
+    pub const fn udp(name: &'static str, number: u16) -> Port {
+        Port {
+            name,
+            number,
+            protocol: Protocol::Udp,
+        }
+    }
+
+    pub const fn http(name: &'static str, number: u16) -> Port {
+        Port {
+            name,
+            number,
+            protocol: Protocol::Http,
+        }
+    }
+}
+
+impl Protocol {
+    /// The transport the protocol runs over, which is what two ports with
+    /// the same number can't share.
+    pub(crate) const fn transport(self) -> Protocol {
+        match self {
+            Protocol::Tcp | Protocol::Http => Protocol::Tcp,
+            Protocol::Udp => Protocol::Udp,
+        }
+    }
+}
+
+/// What a component binds a port for, which decides how targets expose it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BindingKind {
+    /// The component's RPC server.
+    Rpc,
+    /// A port the component serves itself, e.g. a cache's or an exporter's.
+    Plain,
+    /// An entry point for traffic from outside the app, e.g. a public API.
+    Gateway,
+}
+
+/// A port bound by a component, as declared by
+/// [`ComponentKind::bindings`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BindingDecl {
+    pub kind: BindingKind,
+    pub port: Port,
+    /// Whether the port should be reachable from outside the app, e.g.
+    /// through a load balancer. Only gateways are external by default.
+    pub external: bool,
+}
+
+impl BindingDecl {
+    /// The RPC server's port, which is named `rpc`.
+    pub const fn rpc(number: u16) -> BindingDecl {
+        BindingDecl {
+            kind: BindingKind::Rpc,
+            port: Port::http("rpc", number),
+            external: false,
+        }
+    }
+
+    pub const fn plain(port: Port) -> BindingDecl {
+        BindingDecl {
+            kind: BindingKind::Plain,
+            port,
+            external: false,
+        }
+    }
+
+    /// An HTTP entry point for traffic from outside the app.
+    pub const fn gateway(name: &'static str, number: u16) -> BindingDecl {
+        BindingDecl {
+            kind: BindingKind::Gateway,
+            port: Port::http(name, number),
+            external: true,
+        }
+    }
+
+    /// Set whether the port should be reachable from outside the app.
+    pub const fn with_external(mut self, external: bool) -> BindingDecl {
+        self.external = external;
+        self
+    }
+}
+
 /// An opaque identifier for a `ComponentKind`.
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct ComponentKindId(TypeId);
@@ -115,16 +310,58 @@ pub trait ComponentKind: 'static {
     /// A globally unique string identifier for this component.
     const LABEL: &'static str;
 
-    /// A list of ports this component will bind. This is metadata used for
-    /// things like generating container configs. Components within the same job
-    /// can have the same port numbers here, as long as they have a mechanism
-    /// for sharing the port.
+    /// A list of ports this component will bind, as plain TCP ports.
+    #[deprecated(note = "declare ports with `ComponentKind::bindings` instead")]
     const PORTS: &'static [u16] = &[];
 
+    /// Names and protocols for the ports in `PORTS`.
+    #[deprecated(note = "declare ports with `ComponentKind::bindings` instead")]
+    const NAMED_PORTS: &'static [Port] = &[];
+
+    /// The ports this component binds, and what for. This is metadata used
+    /// for things like generating container configs and deciding which ports
+    /// are exposed outside the app. Components within the same job can bind
+    /// the same port numbers, as long as they have a mechanism for sharing
+    /// the port. This defaults to plain bindings for the deprecated `PORTS`
+    /// and `NAMED_PORTS`.
+    #[allow(deprecated)]
+    fn bindings() -> Vec<BindingDecl> {
+        Self::PORTS
+            .iter()
+            .map(|&number| {
+                let port = Self::NAMED_PORTS
+                    .iter()
+                    .find(|p| p.number == number)
+                    .copied()
+                    .unwrap_or(Port::tcp("", number));
+                BindingDecl::plain(port)
+            })
+            .collect()
+    }
+
     /// Indicates how much disk storage is requested by this component, in
-    /// bytes. If `None`, the component is assumed to be stateless.
+    /// bytes. If `None`, the component is assumed to be stateless. Usage above
+    /// this amount is reported with warnings, unless the amount is 0.
     const STORAGE: Option<usize> = None;
 
+    /// If true, using more than `STORAGE` bytes makes the component unhealthy
+    /// and causes `Component::storage()` to fail until usage goes back down.
+    const STORAGE_HARD_LIMIT: bool = false;
+
+    /// The operations this component serves, if it's an RPC component. This
+    /// is used to check deploys for API compatibility.
+    const RPC_OPS: Option<&'static [RpcOp]> = None;
+
+    /// The labels of the components and tools allowed to call this
+    /// component, or `None` if anything can call it. This is currently only
+    /// enforced for RPC components.
+    const ALLOWED_CALLERS: Option<&'static [&'static str]> = None;
+
+    /// If true, the component runs to completion once per revision, rather
+    /// than serving until it's stopped. Such components must be in a job of
+    /// their own. See [`backfill`][crate::backfill].
+    const RUNS_ONCE: bool = false;
+
     /// Provided method to get this component kind's ID
     fn id() -> ComponentKindId {
         ComponentKindId(TypeId::of::<Self>())
@@ -132,13 +369,17 @@ pub trait ComponentKind: 'static {
 
     /// Provided method to get a future that resolves to this component's
     /// instance. It will resolve immediately with `None` if the component is
-    /// not running within the same process.
+    /// not running within the same process. Components that wait for each
+    /// other's instances before providing their own can never start, so the
+    /// job exits naming them instead of hanging.
     fn instance() -> Option<impl Future<Output = Self::Instance> + Send> {
         if Self::is_local() {
             let cell = INSTANCES.get_or_insert(Self::LABEL);
             Some(async move {
-                cell.wait()
+                wait_for(Self::LABEL, &cell)
                     .await
+                    .read()
+                    .expect("lock poisoned")
                     .downcast_ref::<Self::Instance>()
                     .expect("downcast failed")
                     .clone()
@@ -150,12 +391,7 @@ pub trait ComponentKind: 'static {
 
     /// Provided method to check if the component is running in the same process.
     fn is_local() -> bool {
-        match &runtime::args().action {
-            cli::Action::DumpConfig => panic!(),
-            cli::Action::Local => true,
-            cli::Action::Job(j) => runtime::config().component_job(Self::LABEL) == Some(j),
-            cli::Action::Tool(_) => false,
-        }
+        is_local(Self::LABEL)
     }
 
     /// Provided method to get the network location of the current process.
@@ -177,6 +413,75 @@ pub trait ComponentKind: 'static {
     fn discover_stable() -> impl Future<Output = Result<Vec<Location>>> + Send {
         runtime::provider().discover_stable(Self::LABEL)
     }
+
+    /// Provided method to get the replicas of this component that should
+    /// receive traffic, along with any metadata the runtime has about them
+    /// and, if the component is [sharded][crate::shard], the shards they own.
+    fn discover_replicas() -> impl Future<Output = Result<Vec<Replica>>> + Send {
+        async {
+            let replicas = runtime::provider().discover_replicas(Self::LABEL).await?;
+            Ok(shard::annotate(Self::LABEL, replicas).await)
+        }
+    }
+
+    /// Provided method to get every running replica of this component,
+    /// including ones that haven't passed their readiness checks, e.g. for
+    /// tools that need to reach a replica that's stuck starting up. Runtimes
+    /// that don't check readiness return the same replicas as
+    /// `discover_replicas`.
+    fn discover_all_replicas() -> impl Future<Output = Result<Vec<Replica>>> + Send {
+        async {
+            let replicas = runtime::provider()
+                .discover_all_replicas(Self::LABEL)
+                .await?;
+            Ok(shard::annotate(Self::LABEL, replicas).await)
+        }
+    }
+}
+
+/// A component whose instance is only meant to be used within its own job,
+/// e.g. because it's an in-memory index or holds other state that can't be
+/// sent over the network. The instance can be any `Clone + Send + Sync` type.
+///
+/// Components that use a local component should declare it in
+/// [`Component::local_dependencies`], so that building an `AppConfig` that
+/// places them in different jobs fails instead of the instance silently being
+/// unavailable at runtime.
+pub trait LocalComponentKind: ComponentKind {
+    /// Provided method to get this component's instance, waiting for the
+    /// component to start if necessary. Panics if the component isn't running
+    /// in the same process.
+    fn local() -> impl Future<Output = Self::Instance> + Send {
+        let instance = Self::instance();
+        async move {
+            match instance {
+                Some(instance) => instance.await,
+                None => panic!(
+                    "local component {} used outside of its job, declare it in \
+                     Component::local_dependencies to catch this when building the config",
+                    Self::LABEL
+                ),
+            }
+        }
+    }
+}
+
+/// A dependency of a component on a [`LocalComponentKind`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LocalDependency {
+    label: &'static str,
+}
+
+impl LocalDependency {
+    /// A dependency on the given local component.
+    pub fn on<K: LocalComponentKind>() -> LocalDependency {
+        LocalDependency { label: K::LABEL }
+    }
+
+    /// The label of the local component depended on.
+    pub fn label(&self) -> &'static str {
+        self.label
+    }
 }
 
 /// A trait for types that implement a `Component`.
@@ -199,11 +504,46 @@ pub trait Component: Sized + 'static {
     where
         F: FnOnce(<Self::Kind as ComponentKind>::Instance) -> BoxFuture<'static, ()> + Send;
 
+    /// Provided method to declare the local components this component uses.
+    /// Building an `AppConfig` fails if any of them aren't installed in the
+    /// same job as this component.
+    fn local_dependencies() -> Vec<LocalDependency> {
+        Vec::new()
+    }
+
+    /// Provided method to warm up the component, e.g. by filling caches or
+    /// opening connections. This is called once the instance is set, and the
+    /// component isn't reported ready until it completes. Warmup is best
+    /// effort: if it fails, the component becomes ready anyway.
+    fn warmup() -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Provided method to list the migrations of this component's storage, in
+    /// order of version. They're run before the component first starts, on
+    /// storage that isn't at the latest version yet. Only stateful
+    /// components can have migrations. See [`migration`][crate::migration].
+    fn migrations() -> Vec<Box<dyn StorageMigration>> {
+        Vec::new()
+    }
+
     /// Provided method to get this component's storage path. It's assumed this
     /// is only called from the implementation while it's running, and will
     /// panic if the component is not local or stateful.
     fn storage() -> impl Future<Output = Result<PathBuf>> + Send {
-        runtime::provider().storage(Self::Kind::LABEL)
+        async {
+            storage::check_quota(Self::Kind::LABEL)?;
+            runtime::provider().storage(Self::Kind::LABEL).await
+        }
+    }
+
+    /// Provided method to remove the files in this component's storage that
+    /// are past its local retention limits right away, rather than waiting
+    /// for the runtime's next check. Returns the number of bytes freed. This
+    /// does nothing unless the app is running locally with limits set with
+    /// [`AppBuilder::with_local_retention`][crate::config::AppBuilder::with_local_retention].
+    fn storage_gc() -> impl Future<Output = Result<u64>> + Send {
+        storage::gc(Self::Kind::LABEL)
     }
 
     /// Provided method to install this component implementation in a job config.
@@ -211,24 +551,315 @@ pub trait Component: Sized + 'static {
         job.add_component(ComponentConfig {
             id: Self::Kind::id(),
             label: Self::Kind::LABEL.to_owned(),
-            ports: Self::Kind::PORTS.to_owned(),
+            bindings: Self::Kind::bindings(),
             is_stateful: Self::Kind::STORAGE.is_some(),
+            storage: Self::Kind::STORAGE,
+            storage_hard_limit: Self::Kind::STORAGE_HARD_LIMIT,
+            rpc_ops: Self::Kind::RPC_OPS,
+            allowed_callers: Self::Kind::ALLOWED_CALLERS,
+            runs_once: Self::Kind::RUNS_ONCE,
+            local_dependencies: Self::local_dependencies()
+                .into_iter()
+                .map(|d| d.label())
+                .collect(),
+            migrations: Self::migrations,
             entry: component_impl_entry::<Self>,
         });
     }
 }
 
-type InstanceCell = SetOnce<Box<dyn Any + Send + Sync>>;
+/// A component's instance. The instance can be replaced if the component is
+/// restarted after panicking.
+type InstanceCell = SetOnce<RwLock<Box<dyn Any + Send + Sync>>>;
 
 static INSTANCES: StaticHashMap<&'static str, InstanceCell> = StaticHashMap::new();
 
+tokio::task_local! {
+    static CURRENT: &'static str;
+}
+
+/// The label of the component whose code is currently running, if any.
+///
+/// This is set for the duration of a component's `main` and while handling
+/// RPC requests on its behalf, but is not inherited by tasks spawned with
+/// `tokio::spawn`.
+pub fn current() -> Option<&'static str> {
+    CURRENT.try_with(|label| *label).ok()
+}
+
+/// How often components waiting for each other to start are looked for.
+const WAIT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which components are waiting for which others' instances, as edges from
+/// the waiting component to the one it's waiting for. Components can call
+/// each other before providing their own instance, e.g. in an RPC handler's
+/// `new()`, so a cycle of waits among components that haven't started yet
+/// would otherwise hang the job without a trace.
+static WAITING: Mutex<Vec<(&'static str, &'static str)>> = Mutex::new(Vec::new());
+
+/// Removes an edge from `WAITING` when it's dropped.
+struct Waiting(&'static str, &'static str);
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        let mut waiting = WAITING.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(i) = waiting.iter().position(|&e| e == (self.0, self.1)) {
+            waiting.swap_remove(i);
+        }
+    }
+}
+
+/// Wait for a component's instance. If the current component waits for it
+/// before it's started, the wait is recorded, and the job exits naming the
+/// cycle if the components involved turn out to be waiting for each other.
+async fn wait_for<'c>(
+    target: &'static str,
+    cell: &'c InstanceCell,
+) -> &'c RwLock<Box<dyn Any + Send + Sync>> {
+    let waiter = match (cell.get(), current()) {
+        (None, Some(waiter)) => waiter,
+        _ => return cell.wait().await,
+    };
+    WAITING
+        .lock()
+        .expect("lock poisoned")
+        .push((waiter, target));
+    let _waiting = Waiting(waiter, target);
+    log::debug!("{waiter} waiting for {target} to start");
+
+    let detect = async {
+        loop {
+            tokio::time::sleep(WAIT_CHECK_INTERVAL).await;
+            if let Some(cycle) = wait_cycle(waiter) {
+                let cycle = cycle.join(" -> ");
+                log::error!("components are waiting for each other to start, so none can: {cycle}");
+                std::process::exit(1);
+            }
+        }
+    };
+    tokio::select! {
+        instance = cell.wait() => instance,
+        () = detect => unreachable!(),
+    }
+}
+
+/// A cycle of waits that starts and ends with `waiter`, following only waits
+/// for components that still haven't started, which is what keeps them all
+/// from starting.
+fn wait_cycle(waiter: &'static str) -> Option<Vec<&'static str>> {
+    let waiting = WAITING.lock().expect("lock poisoned").clone();
+    let started = |label: &str| INSTANCES.get(label).is_some_and(|c| c.get().is_some());
+    let mut paths = vec![vec![waiter]];
+    let mut seen = HashSet::new();
+    while let Some(path) = paths.pop() {
+        let last = *path.last().expect("paths aren't empty");
+        if !seen.insert(last) {
+            continue;
+        }
+        for &(_, target) in waiting.iter().filter(|(w, _)| *w == last) {
+            if started(target) {
+                continue;
+            }
+            let mut next = path.clone();
+            next.push(target);
+            if target == waiter {
+                return Some(next);
+            }
+            paths.push(next);
+        }
+    }
+    None
+}
+
+/// Whether the component with the given label runs in this process.
+pub(crate) fn is_local(label: &str) -> bool {
+    match &runtime::args().action {
+        cli::Action::DumpConfig | cli::Action::DumpConfigEmbedded | cli::Action::DumpGraph(_) => {
+            panic!()
+        }
+        cli::Action::Local => true,
+        cli::Action::Job(j) => {
+            runtime::config().component_job(label) == Some(j) && runtime::runs_here(label)
+        }
+        cli::Action::Tool(_) => false,
+    }
+}
+
+/// Runs a future with `current()` returning the given label.
+pub(crate) fn scope<F: Future>(label: &'static str, fut: F) -> impl Future<Output = F::Output> {
+    CURRENT.scope(label, fut)
+}
+
 fn component_impl_entry<C: Component>() -> BoxFuture<'static, ()> {
-    Box::pin(C::main(|instance| {
-        Box::pin(async {
-            INSTANCES
-                .get_or_insert(C::Kind::LABEL)
-                .set(Box::new(instance))
-                .expect("SetOnce::set() failed!");
-        })
-    }))
+    let supervised = supervise(C::Kind::LABEL, || {
+        scope(
+            C::Kind::LABEL,
+            C::main(|instance| {
+                Box::pin(async {
+                    let cell = INSTANCES.get_or_insert(C::Kind::LABEL);
+                    match cell.get() {
+                        Some(slot) => *slot.write().expect("lock poisoned") = Box::new(instance),
+                        None => cell
+                            .set(RwLock::new(Box::new(instance)))
+                            .expect("SetOnce::set() failed!"),
+                    }
+                    if let Err(e) = C::warmup().await {
+                        log::warn!("{}: warmup failed: {}", C::Kind::LABEL, e);
+                    }
+                    health::started(C::Kind::LABEL);
+                })
+            }),
+        )
+    });
+    Box::pin(async {
+        if C::Kind::STORAGE.is_some() {
+            migration::run(C::Kind::LABEL, C::migrations()).await;
+        }
+        supervised.await
+    })
+}
+
+/// How much of a component's running time panics are counted over when
+/// deciding whether it's crash-looping. Time spent waiting to be restarted
+/// isn't counted, so long backoffs don't end a crash loop by themselves.
+const CRASH_LOOP_WINDOW: Duration = Duration::from_secs(60);
+
+/// How many panics within the window make a component crash-looping.
+const CRASH_LOOP_PANICS: usize = 3;
+
+/// The shortest wait before restarting a crash-looping component, whatever its
+/// restart policy. It doubles with each further panic, up to
+/// [`CRASH_LOOP_MAX_BACKOFF`].
+const CRASH_LOOP_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest wait before restarting a crash-looping component.
+const CRASH_LOOP_MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Components that are crash-looping, whose panics are summarized rather than
+/// each printed by the panic hook.
+static CRASH_LOOPING: LazyLock<Mutex<HashSet<&'static str>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Install a panic hook that stays quiet for components that are
+/// crash-looping, leaving other panics to the existing hook.
+fn quiet_crash_loops() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let prev = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let looping = current().is_some_and(|label| {
+                let looping = CRASH_LOOPING.lock().unwrap_or_else(|e| e.into_inner());
+                looping.contains(label)
+            });
+            if !looping {
+                prev(info);
+            }
+        }));
+    });
+}
+
+/// The message a panic was raised with, if it was raised with one.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&'static str>() {
+        Some(s) => s,
+        None => match panic.downcast_ref::<String>() {
+            Some(s) => s,
+            None => "(no message)",
+        },
+    }
+}
+
+/// Runs a component, restarting it if it panics according to its job's
+/// restart policy. Once the policy is exhausted, the panic is passed on,
+/// which fails the job.
+///
+/// A component that panics repeatedly within a short window is
+/// crash-looping. Its restarts are backed off even if its policy restarts it
+/// right away, and instead of every panic being printed, a summary with the
+/// latest error is logged once per window.
+async fn supervise<F, Fut>(label: &'static str, run: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let cf = runtime::config();
+    let policy = cf
+        .component_job(label)
+        .and_then(|j| cf.job(j))
+        .map(|j| j.restart_policy(label).clone())
+        .unwrap_or_default();
+    quiet_crash_loops();
+
+    let mut restarts = 0;
+    let mut recent = VecDeque::new();
+    let mut summarized: Option<Instant> = None;
+    loop {
+        let started = Instant::now();
+        let panic = match AssertUnwindSafe(run()).catch_unwind().await {
+            Ok(()) => return,
+            Err(panic) => panic,
+        };
+        metrics::counter("amimono_component_panics", &[("component", label)]).inc();
+        // the restarted instance starts its own tasks
+        tasks::TaskSet::of(label).abort_all();
+        let error = panic_message(&*panic);
+
+        // how long each recent run lasted before panicking
+        let now = Instant::now();
+        recent.push_back(now - started);
+        // runs that panic straight away would otherwise pile up for as long
+        // as the loop lasts, and the backoff stops doubling after 16 anyway
+        while recent.iter().sum::<Duration>() > CRASH_LOOP_WINDOW
+            || recent.len() > CRASH_LOOP_PANICS + 16
+        {
+            recent.pop_front();
+        }
+        let looping = recent.len() >= CRASH_LOOP_PANICS;
+
+        if policy.max_restarts.is_some_and(|n| restarts >= n) {
+            log::error!(
+                "{label}: panicked after {restarts} restarts, failing job; last error: {error}"
+            );
+            health::set_for(label, health::Status::unhealthy("panicked"));
+            CRASH_LOOPING.lock().expect("lock poisoned").remove(label);
+            std::panic::resume_unwind(panic);
+        }
+
+        let mut delay = policy.delay(restarts);
+        restarts += 1;
+        if looping {
+            let extra = (recent.len() - CRASH_LOOP_PANICS) as u32;
+            let backoff = (CRASH_LOOP_BACKOFF * (1 << extra)).min(CRASH_LOOP_MAX_BACKOFF);
+            delay = delay.max(backoff);
+
+            let summary = format!(
+                "restarted {} times in {}s of running; last error: {}",
+                recent.len(),
+                CRASH_LOOP_WINDOW.as_secs(),
+                error
+            );
+            if CRASH_LOOPING.lock().expect("lock poisoned").insert(label) {
+                metrics::counter("amimono_component_crash_loops", &[("component", label)]).inc();
+            }
+            if summarized.is_none_or(|t| now - t >= CRASH_LOOP_WINDOW) {
+                log::error!("{label}: crash-looping, {summary}; next restart in {delay:?}");
+                summarized = Some(now);
+            }
+            health::set_for(
+                label,
+                health::Status::unhealthy(format!("crash-looping: {summary}")),
+            );
+        } else {
+            if CRASH_LOOPING.lock().expect("lock poisoned").remove(label) {
+                log::info!("{label}: no longer crash-looping");
+                summarized = None;
+            }
+            log::error!("{label}: panicked, restarting in {delay:?} (restart {restarts}): {error}");
+            health::set_for(label, health::Status::unhealthy("panicked, restarting"));
+        }
+        tokio::time::sleep(delay).await;
+
+        metrics::counter("amimono_component_restarts", &[("component", label)]).inc();
+        health::register(label);
+    }
 }
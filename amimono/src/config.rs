@@ -1,8 +1,25 @@
-use std::collections::{BTreeMap, HashMap};
+use std::{
+    any::TypeId,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::Duration,
+};
 
+use fnv::FnvHasher;
 use futures::future::BoxFuture;
 
-use crate::{AppResult, component::ComponentKindId};
+use crate::{
+    AppResult, cache,
+    component::{BindingDecl, ComponentKindId},
+    emulation::Emulation,
+    gateway, metadata,
+    migration::{self, StorageMigration},
+    resource,
+    rpc::{HttpVersion, LogSampling, RpcOp, journal},
+    runtime::BuildInfo,
+    schema,
+};
 
 /// The configuration for a single component.
 pub struct ComponentConfig {
@@ -17,19 +34,438 @@ pub struct ComponentConfig {
     /// identified with a type that implements the `Component` trait.
     pub label: String,
 
-    /// The ports this component will bind. This is metadata used for things
-    /// like generating container configs. Components within the same job can
-    /// have the same port numbers here, as long as they have a mechanism for
-    /// sharing the port.
-    pub ports: Vec<u16>,
+    /// The ports this component will bind, and what for. This is metadata
+    /// used for things like generating container configs. Components within
+    /// the same job can bind the same port numbers, as long as they have a
+    /// mechanism for sharing the port.
+    pub bindings: Vec<BindingDecl>,
 
     /// Indicates whether the component is stateful. Stateful components can use
     /// local storage that will be persisted across application revisions.
     pub is_stateful: bool,
 
+    /// The amount of storage requested by the component, in bytes. A size of 0
+    /// means the amount is unspecified, and usage is not checked against it.
+    pub storage: Option<usize>,
+
+    /// Whether exceeding the requested storage should make the component
+    /// unhealthy and cause further calls to `Component::storage()` to fail.
+    pub storage_hard_limit: bool,
+
+    /// The operations served by the component, if it's an RPC component.
+    pub rpc_ops: Option<&'static [RpcOp]>,
+
+    /// The labels of the components and tools allowed to call the component,
+    /// or `None` if anything can call it.
+    pub allowed_callers: Option<&'static [&'static str]>,
+
+    /// Whether the component runs to completion once per revision. See
+    /// [`ComponentKind::RUNS_ONCE`][crate::component::ComponentKind::RUNS_ONCE].
+    pub runs_once: bool,
+
+    /// The labels of the local components this component uses, which must be
+    /// installed in the same job.
+    pub local_dependencies: Vec<&'static str>,
+
+    /// The migrations of the component's storage. See
+    /// [`Component::migrations`][crate::component::Component::migrations].
+    pub migrations: fn() -> Vec<Box<dyn StorageMigration>>,
+
     pub(crate) entry: fn() -> BoxFuture<'static, ()>,
 }
 
+/// Configuration for pushing metrics to a collector.
+///
+/// Refer to [`metrics::push`][crate::metrics::push] for more information.
+#[derive(Clone, Debug)]
+pub struct MetricsPushConfig {
+    /// The OTLP/HTTP metrics endpoint.
+    pub url: String,
+
+    /// How often metrics are pushed.
+    pub interval: Duration,
+
+    /// Extra headers to send with each push, e.g. for authentication.
+    pub headers: Vec<(String, String)>,
+}
+
+impl MetricsPushConfig {
+    /// Push to the given endpoint every 15 seconds.
+    pub fn new<S: Into<String>>(url: S) -> MetricsPushConfig {
+        MetricsPushConfig {
+            url: url.into(),
+            interval: Duration::from_secs(15),
+            headers: Vec::new(),
+        }
+    }
+
+    /// Set how often metrics are pushed.
+    pub fn with_interval(mut self, interval: Duration) -> MetricsPushConfig {
+        self.interval = interval;
+        self
+    }
+
+    /// Add a header to send with each push.
+    pub fn with_header<K: Into<String>, V: Into<String>>(
+        mut self,
+        k: K,
+        v: V,
+    ) -> MetricsPushConfig {
+        self.headers.push((k.into(), v.into()));
+        self
+    }
+}
+
+/// Settings for recording a component's RPC requests to a journal. Refer to
+/// [`rpc::journal`][crate::rpc::journal] for details.
+#[derive(Clone, Debug)]
+pub struct JournalConfig {
+    /// The fraction of requests that are recorded, from 0 to 1.
+    pub sample_rate: f64,
+
+    /// The size at which the journal is rotated. At most twice this much is
+    /// kept.
+    pub max_bytes: u64,
+}
+
+impl JournalConfig {
+    /// Record the given fraction of requests, keeping up to 64 MiB.
+    pub fn sampled(sample_rate: f64) -> JournalConfig {
+        JournalConfig {
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+            max_bytes: 64 << 20,
+        }
+    }
+
+    /// Set the size at which the journal is rotated.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> JournalConfig {
+        self.max_bytes = max_bytes;
+        self
+    }
+}
+
+/// A hook that redacts sensitive parts of a captured payload in place. It's
+/// given the op and the payload as JSON, which for requests is an object of
+/// the arguments by name, and for responses is the value returned.
+pub type Redactor = Arc<dyn Fn(&str, &mut serde_json::Value) + Send + Sync>;
+
+/// Settings for capturing the payloads of a component's RPC requests. Refer
+/// to [`rpc::capture`][crate::rpc::capture] for details.
+#[derive(Clone)]
+pub struct CaptureConfig {
+    /// The fraction of requests that are captured, from 0 to 1.
+    pub sample_rate: f64,
+
+    /// Names of fields whose values are replaced with `"[redacted]"`,
+    /// wherever they appear in requests and responses.
+    pub redacted_fields: BTreeSet<String>,
+
+    /// Payloads that serialize to more than this many bytes are truncated.
+    pub max_bytes: usize,
+
+    redactor: Option<Redactor>,
+}
+
+impl CaptureConfig {
+    /// Capture the given fraction of requests, truncating payloads over
+    /// 16 KiB.
+    pub fn sampled(sample_rate: f64) -> CaptureConfig {
+        CaptureConfig {
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+            redacted_fields: BTreeSet::new(),
+            max_bytes: 16 << 10,
+            redactor: None,
+        }
+    }
+
+    /// Redact the values of fields with the given name.
+    pub fn with_redacted_field<S: Into<String>>(mut self, name: S) -> CaptureConfig {
+        self.redacted_fields.insert(name.into());
+        self
+    }
+
+    /// Set the size past which payloads are truncated.
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> CaptureConfig {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Set a hook to redact payloads that can't be redacted by field name
+    /// alone. It runs after redacted fields have been replaced.
+    pub fn with_redactor<F>(mut self, f: F) -> CaptureConfig
+    where
+        F: Fn(&str, &mut serde_json::Value) + Send + Sync + 'static,
+    {
+        self.redactor = Some(Arc::new(f));
+        self
+    }
+
+    /// The hook set with [`with_redactor`][Self::with_redactor], if any.
+    pub fn redactor(&self) -> Option<&Redactor> {
+        self.redactor.as_ref()
+    }
+}
+
+impl std::fmt::Debug for CaptureConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CaptureConfig")
+            .field("sample_rate", &self.sample_rate)
+            .field("redacted_fields", &self.redacted_fields)
+            .field("max_bytes", &self.max_bytes)
+            .field("redactor", &self.redactor.is_some())
+            .finish()
+    }
+}
+
+/// Settings for dispatching in-process calls to a component through a queue
+/// served by a pool of workers. Refer to
+/// [`AppBuilder::with_local_dispatch`] for details.
+#[derive(Clone, Debug)]
+pub struct DispatchConfig {
+    /// The number of workers, i.e. how many in-process calls to the
+    /// component are handled concurrently.
+    pub workers: usize,
+
+    /// How many calls can wait for a worker before further calls are
+    /// rejected.
+    pub queue: usize,
+}
+
+impl DispatchConfig {
+    pub fn new(workers: usize, queue: usize) -> DispatchConfig {
+        DispatchConfig {
+            workers: workers.max(1),
+            queue: queue.max(1),
+        }
+    }
+}
+
+/// Limits on how many RPC requests a component's server handles at once.
+/// Refer to [`AppBuilder::with_concurrency_limit`] for details.
+#[derive(Clone, Debug)]
+pub struct ConcurrencyConfig {
+    /// How many requests are handled at once.
+    pub max_in_flight: usize,
+
+    /// How many requests can wait to be handled before further requests are
+    /// shed.
+    pub max_queued: usize,
+
+    /// How many of the requests handled at once can be for batch ops, so that
+    /// some capacity is always left for interactive ones.
+    pub max_batch: usize,
+}
+
+impl ConcurrencyConfig {
+    /// Limits with up to half of the in-flight requests for batch ops.
+    pub fn new(max_in_flight: usize, max_queued: usize) -> ConcurrencyConfig {
+        let max_in_flight = max_in_flight.max(1);
+        ConcurrencyConfig {
+            max_in_flight,
+            max_queued,
+            max_batch: max_in_flight.div_ceil(2),
+        }
+    }
+
+    /// Set how many in-flight requests can be for batch ops.
+    pub fn with_max_batch(mut self, max_batch: usize) -> ConcurrencyConfig {
+        self.max_batch = max_batch.clamp(1, self.max_in_flight);
+        self
+    }
+}
+
+/// Limits on how much of a component one caller can use. Refer to
+/// [`AppBuilder::with_caller_quota`] for details.
+#[derive(Clone, Debug, Default)]
+pub struct QuotaConfig {
+    /// How many requests a second the caller can make, in bursts of up to a
+    /// second's worth.
+    pub max_rps: Option<f64>,
+
+    /// How many of the caller's requests are handled at once.
+    pub max_in_flight: Option<usize>,
+}
+
+impl QuotaConfig {
+    /// A quota with no limits.
+    pub fn new() -> QuotaConfig {
+        QuotaConfig::default()
+    }
+
+    /// Limit how many requests a second the caller can make.
+    pub fn with_max_rps(mut self, max_rps: f64) -> QuotaConfig {
+        self.max_rps = Some(max_rps);
+        self
+    }
+
+    /// Limit how many of the caller's requests are handled at once.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> QuotaConfig {
+        self.max_in_flight = Some(max_in_flight);
+        self
+    }
+}
+
+/// Where audit events are written, in addition to the log. Refer to the
+/// [`audit`][crate::audit] module for details.
+#[derive(Clone, Debug, Default)]
+pub struct AuditConfig {
+    /// Whether events are written to stdout as JSON lines.
+    pub stdout: bool,
+
+    /// The component whose storage events are written to.
+    pub storage: Option<String>,
+}
+
+impl AuditConfig {
+    /// Write events to stdout as JSON lines.
+    pub fn with_stdout(mut self) -> AuditConfig {
+        self.stdout = true;
+        self
+    }
+
+    /// Write events to the given component's storage.
+    pub fn with_storage(mut self, label: &str) -> AuditConfig {
+        self.storage = Some(label.to_owned());
+        self
+    }
+}
+
+/// Limits on what a component keeps in its storage when running locally.
+/// Refer to [`AppBuilder::with_local_retention`] for details.
+#[derive(Clone, Debug, Default)]
+pub struct RetentionConfig {
+    /// The most bytes kept. Past this, the least recently modified files are
+    /// removed until the storage is back under it.
+    pub max_size: Option<u64>,
+
+    /// How long files are kept after they were last modified.
+    pub max_age: Option<Duration>,
+}
+
+impl RetentionConfig {
+    /// Keep at most `bytes` bytes.
+    pub fn with_max_size(mut self, bytes: u64) -> RetentionConfig {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Keep files for at most `age` after they were last modified.
+    pub fn with_max_age(mut self, age: Duration) -> RetentionConfig {
+        self.max_age = Some(age);
+        self
+    }
+}
+
+/// Settings for the built-in [cache component][crate::cache]. Refer to
+/// [`AppBuilder::with_cache`] for details.
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// The most bytes of keys and values each replica holds before evicting
+    /// the least recently used entries.
+    pub max_bytes: usize,
+
+    /// How long entries put without a TTL are kept, or `None` to keep them
+    /// until they're evicted.
+    pub default_ttl: Option<Duration>,
+
+    /// Whether keys are spread over the component's replicas by hashing, so
+    /// that every caller sees the same entries, rather than each replica
+    /// caching on its own.
+    pub sharded: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_bytes: 64 * 1024 * 1024,
+            default_ttl: None,
+            sharded: false,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Hold at most `max_bytes` of keys and values per replica.
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> CacheConfig {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Keep entries put without a TTL for at most `ttl`.
+    pub fn with_default_ttl(mut self, ttl: Duration) -> CacheConfig {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// Spread keys over the component's replicas.
+    pub fn sharded(mut self) -> CacheConfig {
+        self.sharded = true;
+        self
+    }
+}
+
+/// Settings for a channel between two components in the same job. Refer to
+/// [`AppBuilder::with_channel`] for details.
+#[derive(Clone, Debug)]
+pub struct ChannelConfig {
+    /// The label of the component that sends on the channel.
+    pub producer: String,
+
+    /// The label of the component that receives from the channel.
+    pub consumer: String,
+
+    /// How many items can wait to be received before senders wait.
+    pub capacity: usize,
+}
+
+impl ChannelConfig {
+    pub fn new(producer: &str, consumer: &str, capacity: usize) -> ChannelConfig {
+        ChannelConfig {
+            producer: producer.to_owned(),
+            consumer: consumer.to_owned(),
+            capacity: capacity.max(1),
+        }
+    }
+}
+
+/// Settings for a pipeline of components, each consuming the items produced
+/// by the one before it. Refer to [`AppBuilder::with_pipeline`] for details.
+#[derive(Clone, Debug)]
+pub struct PipelineConfig {
+    /// The labels of the components in the pipeline, from first to last.
+    pub stages: Vec<String>,
+
+    /// How many items can wait between two stages before the earlier one
+    /// waits.
+    pub capacity: usize,
+}
+
+impl PipelineConfig {
+    pub fn new(stages: &[&str], capacity: usize) -> PipelineConfig {
+        PipelineConfig {
+            stages: stages.iter().map(|s| (*s).to_owned()).collect(),
+            capacity: capacity.max(1),
+        }
+    }
+}
+
+/// Settings for a sharded component, whose keys are split into shards that
+/// are each owned by one of its replicas. Refer to
+/// [`AppBuilder::with_sharding`] for details.
+#[derive(Clone, Debug)]
+pub struct ShardingConfig {
+    /// How many shards the component's keys are split into.
+    pub shards: u32,
+}
+
+impl ShardingConfig {
+    pub fn new(shards: u32) -> ShardingConfig {
+        ShardingConfig {
+            shards: shards.max(1),
+        }
+    }
+}
+
 /// A fully configured application.
 ///
 /// Refer to the [module-level documentation][crate::config] for more information.
@@ -38,6 +474,31 @@ pub struct AppConfig {
     component_jobs: HashMap<String, String>,
     jobs: BTreeMap<String, JobConfig>,
     tools: BTreeMap<String, ToolConfig>,
+    metrics_push: Option<MetricsPushConfig>,
+    job_sources: BTreeMap<String, String>,
+    slow_start: Option<Duration>,
+    journals: BTreeMap<String, JournalConfig>,
+    captures: BTreeMap<String, CaptureConfig>,
+    dispatch: BTreeMap<String, DispatchConfig>,
+    concurrency: BTreeMap<String, ConcurrencyConfig>,
+    /// Quotas by component and caller, where a `None` caller is the quota of
+    /// callers without their own.
+    quotas: BTreeMap<String, BTreeMap<Option<String>, QuotaConfig>>,
+    log_sampling: BTreeMap<String, LogSampling>,
+    task_budgets: BTreeMap<String, usize>,
+    channels: BTreeMap<String, ChannelConfig>,
+    pipelines: BTreeMap<String, PipelineConfig>,
+    sharding: BTreeMap<String, ShardingConfig>,
+    single_flight: BTreeMap<String, BTreeSet<String>>,
+    local_retention: BTreeMap<String, RetentionConfig>,
+    cache: Option<CacheConfig>,
+    resources: HashMap<TypeId, resource::Provider>,
+    audit: Option<AuditConfig>,
+    http_version: HttpVersion,
+    emulation: Emulation,
+    resolver: bool,
+    build_info: BuildInfo,
+    embedded_config: Option<&'static str>,
 }
 
 impl AppConfig {
@@ -51,6 +512,12 @@ impl AppConfig {
         self.revision.as_str()
     }
 
+    /// Information about how the app was built. Apps that weren't given any
+    /// with `AppBuilder::from_build_info` only have a revision.
+    pub fn build_info(&self) -> BuildInfo {
+        self.build_info
+    }
+
     /// Retrieve a `JobConfig` by its label.
     pub fn job(&self, label: &str) -> Option<&JobConfig> {
         self.jobs.get(label)
@@ -71,12 +538,179 @@ impl AppConfig {
         self.tools.get(label)
     }
 
+    /// Where metrics are pushed, if anywhere. Environment overrides are not
+    /// reflected here; see [`metrics::push`][crate::metrics::push].
+    pub fn metrics_push(&self) -> Option<&MetricsPushConfig> {
+        self.metrics_push.as_ref()
+    }
+
+    /// How long newly discovered endpoints take to ramp up to their full
+    /// share of traffic, if slow start is enabled.
+    pub fn slow_start(&self) -> Option<Duration> {
+        self.slow_start
+    }
+
+    /// The HTTP version clients use for RPC requests between jobs, unless a
+    /// client overrides it.
+    pub fn http_version(&self) -> HttpVersion {
+        self.http_version
+    }
+
+    /// When emulated components run their emulator. See
+    /// [`emulation`][crate::emulation].
+    pub fn emulation(&self) -> Emulation {
+        self.emulation
+    }
+
+    /// Whether `/admin/resolve` is served, set with
+    /// [`AppBuilder::with_resolver`].
+    pub fn resolver(&self) -> bool {
+        self.resolver
+    }
+
+    /// The journal settings for a component, if its requests are journaled.
+    pub fn journal(&self, label: &str) -> Option<&JournalConfig> {
+        self.journals.get(label)
+    }
+
+    /// The capture settings for a component, if its payloads are captured.
+    pub fn capture(&self, label: &str) -> Option<&CaptureConfig> {
+        self.captures.get(label)
+    }
+
+    /// The dispatch settings for in-process calls to a component, if they go
+    /// through a dispatch queue.
+    pub fn local_dispatch(&self, label: &str) -> Option<&DispatchConfig> {
+        self.dispatch.get(label)
+    }
+
+    /// The limits on requests handled at once by a component's server, if it
+    /// has any.
+    pub fn concurrency_limit(&self, label: &str) -> Option<&ConcurrencyConfig> {
+        self.concurrency.get(label)
+    }
+
+    /// The quota of a caller of a component, if it has one. Callers that
+    /// can't be identified are `None`.
+    pub fn caller_quota(&self, label: &str, caller: Option<&str>) -> Option<&QuotaConfig> {
+        let quotas = self.quotas.get(label)?;
+        caller
+            .and_then(|c| quotas.get(&Some(c.to_owned())))
+            .or_else(|| quotas.get(&None))
+    }
+
+    /// How calls to a component are logged, if they're sampled.
+    pub fn log_sampling(&self, label: &str) -> Option<&LogSampling> {
+        self.log_sampling.get(label)
+    }
+
+    /// The most tasks a component should have running before the watchdog
+    /// warns about it. See [`AppBuilder::with_task_budget`].
+    pub fn task_budget(&self, label: &str) -> Option<usize> {
+        self.task_budgets.get(label).copied()
+    }
+
+    /// Whether identical concurrent calls to an op of a component are
+    /// collapsed into one. See [`AppBuilder::with_single_flight`].
+    pub fn single_flight(&self, label: &str, op: &str) -> bool {
+        self.single_flight
+            .get(label)
+            .is_some_and(|ops| ops.contains(op))
+    }
+
+    /// The limits on a component's storage when running locally, if it has
+    /// any. See [`AppBuilder::with_local_retention`].
+    pub fn local_retention(&self, label: &str) -> Option<&RetentionConfig> {
+        self.local_retention.get(label)
+    }
+
+    /// The settings for the built-in cache component, if any were given.
+    pub fn cache(&self) -> Option<&CacheConfig> {
+        self.cache.as_ref()
+    }
+
+    /// The factory for the resource with the given type, if one was
+    /// provided.
+    pub(crate) fn resource(&self, ty: TypeId) -> Option<&resource::Provider> {
+        self.resources.get(&ty)
+    }
+
+    /// Where audit events are written, if anywhere other than the log.
+    pub fn audit(&self) -> Option<&AuditConfig> {
+        self.audit.as_ref()
+    }
+
+    /// The config dump embedded at build time, if there is one for this
+    /// revision. See [`AppBuilder::with_embedded_config`].
+    pub fn embedded_config(&self) -> Option<&'static str> {
+        self.embedded_config
+    }
+
+    /// The settings for a channel between components, by its label.
+    pub fn channel(&self, label: &str) -> Option<&ChannelConfig> {
+        self.channels.get(label)
+    }
+
+    /// The settings for a pipeline of components, by its label.
+    pub fn pipeline(&self, label: &str) -> Option<&PipelineConfig> {
+        self.pipelines.get(label)
+    }
+
+    /// The sharding settings for a component, if it's sharded.
+    pub fn sharding(&self, label: &str) -> Option<&ShardingConfig> {
+        self.sharding.get(label)
+    }
+
     /// Retrieve a `ComponentConfig` by its label.
     pub fn component(&self, label: &str) -> Option<&ComponentConfig> {
         let job_label = self.component_job(label)?;
         let job = self.job(job_label)?;
         job.component(label)
     }
+
+    /// A digest of everything that affects how a job runs: its components
+    /// and their metadata, and its sources. If no source digest was provided
+    /// for the job with `AppBuilder::with_job_digests`, the app revision is
+    /// used instead, so the job's digest changes with every revision. The
+    /// digest is FNV-1a, so builds with different toolchains agree on it.
+    pub fn job_digest(&self, label: &str) -> Option<String> {
+        let job = self.job(label)?;
+        let mut hasher = FnvHasher::default();
+        for comp in job.components() {
+            comp.label.hash(&mut hasher);
+            comp.bindings.hash(&mut hasher);
+            comp.is_stateful.hash(&mut hasher);
+            comp.storage.hash(&mut hasher);
+            comp.storage_hard_limit.hash(&mut hasher);
+            for op in comp.rpc_ops.into_iter().flatten() {
+                op.hash(&mut hasher);
+            }
+            comp.allowed_callers.hash(&mut hasher);
+            comp.runs_once.hash(&mut hasher);
+            job.component_replicas(&comp.label).hash(&mut hasher);
+        }
+        job.replicas.hash(&mut hasher);
+        job.standby.hash(&mut hasher);
+        job.rollout.hash(&mut hasher);
+        job.dependencies.hash(&mut hasher);
+        job.placement.hash(&mut hasher);
+        match self.job_sources.get(label) {
+            Some(src) => src.hash(&mut hasher),
+            None => self.revision.hash(&mut hasher),
+        }
+        Some(format!("{:016x}", hasher.finish()))
+    }
+}
+
+/// Merge the settings of a mounted app into this one's, panicking if both
+/// have settings for the same thing.
+fn merge<V>(prefix: &str, what: &str, ours: &mut BTreeMap<String, V>, theirs: BTreeMap<String, V>) {
+    for (label, v) in theirs {
+        if ours.contains_key(&label) {
+            panic!("mounting {prefix}: the app already has {what} {label}");
+        }
+        ours.insert(label, v);
+    }
 }
 
 /// A fully configured job.
@@ -85,6 +719,18 @@ impl AppConfig {
 pub struct JobConfig {
     label: String,
     components: BTreeMap<String, ComponentConfig>,
+    replicas: u32,
+    standby: u32,
+    rollout: Rollout,
+    dependencies: BTreeSet<String>,
+    placement: Vec<Placement>,
+    runtime: TokioConfig,
+    component_runtimes: BTreeMap<String, TokioConfig>,
+    restart_policy: RestartPolicy,
+    component_restart_policies: BTreeMap<String, RestartPolicy>,
+    component_replicas: BTreeMap<String, BTreeSet<usize>>,
+    max_lifetime: Option<MaxLifetime>,
+    memory_budget: Option<u64>,
 }
 
 impl JobConfig {
@@ -108,12 +754,416 @@ impl JobConfig {
     pub fn is_stateful(&self) -> bool {
         self.components().any(|c| c.is_stateful)
     }
+
+    /// Whether the job's replicas have stable ordinals, which they do if the
+    /// job is stateful or some of its components only run on some replicas.
+    /// Targets deploy such jobs like stateful jobs.
+    pub fn has_ordinals(&self) -> bool {
+        self.is_stateful() || !self.component_replicas.is_empty()
+    }
+
+    /// The ordinals of the replicas that run a component, if it doesn't run
+    /// on all of the job's replicas.
+    pub fn component_replicas(&self, label: &str) -> Option<&BTreeSet<usize>> {
+        self.component_replicas.get(label)
+    }
+
+    /// Whether the replica with the given ordinal runs a component.
+    pub fn runs_on(&self, label: &str, ordinal: usize) -> bool {
+        self.component_replicas(label)
+            .is_none_or(|ordinals| ordinals.contains(&ordinal))
+    }
+
+    /// Indicates whether the job runs to completion once per revision, rather
+    /// than serving. Run-once components can't share a job with other
+    /// components, so this is true if any of its components run once.
+    pub fn runs_once(&self) -> bool {
+        self.components().any(|c| c.runs_once)
+    }
+
+    /// The number of replicas targets should run of the job, including
+    /// standbys.
+    pub fn replicas(&self) -> u32 {
+        self.replicas + self.standby
+    }
+
+    /// How many of the job's replicas are warm standbys, which don't serve
+    /// until they're promoted. See [`crate::standby`].
+    pub fn standby(&self) -> u32 {
+        self.standby
+    }
+
+    /// How the job's replicas are replaced during deploys.
+    pub fn rollout(&self) -> &Rollout {
+        &self.rollout
+    }
+
+    /// The labels of jobs that must be ready before this job is started.
+    pub fn dependencies(&self) -> impl Iterator<Item = &str> {
+        self.dependencies.iter().map(|s| s.as_str())
+    }
+
+    /// Constraints on where the job's replicas are placed.
+    pub fn placement(&self) -> &[Placement] {
+        &self.placement
+    }
+
+    /// The configuration of the tokio runtime the job runs on.
+    pub fn runtime(&self) -> &TokioConfig {
+        &self.runtime
+    }
+
+    /// The configuration of a component's separate tokio runtime, if it has
+    /// one.
+    pub fn component_runtime(&self, label: &str) -> Option<&TokioConfig> {
+        self.component_runtimes.get(label)
+    }
+
+    /// What to do when a component in the job panics.
+    pub fn restart_policy(&self, label: &str) -> &RestartPolicy {
+        self.component_restart_policies
+            .get(label)
+            .unwrap_or(&self.restart_policy)
+    }
+
+    /// When the job's replicas restart themselves, if they do.
+    pub fn max_lifetime(&self) -> Option<&MaxLifetime> {
+        self.max_lifetime.as_ref()
+    }
+
+    /// The most memory, in bytes, each of the job's replicas should use
+    /// before the watchdog warns about it, if it has a budget.
+    pub fn memory_budget(&self) -> Option<u64> {
+        self.memory_budget
+    }
+}
+
+/// A constraint on where a job's replicas are placed, relative to other jobs
+/// or to the cluster's topology. Placement is only enforced by runtimes that
+/// schedule jobs, i.e. when deployed to Kubernetes with `ammn deploy`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Placement {
+    /// Only place replicas in topology domains, such as nodes, that are
+    /// running a replica of another job. Replicas can't be scheduled until
+    /// the other job has been, so the job should usually depend on it too.
+    Colocate { job: String, topology: String },
+
+    /// Never place replicas in topology domains that are running a replica
+    /// of another job. A job can be kept apart from itself to put each of its
+    /// replicas in a separate domain.
+    AntiColocate { job: String, topology: String },
+
+    /// Spread replicas evenly across topology domains, allowing the number
+    /// of replicas in any two domains to differ by at most `max_skew`. This
+    /// is a preference, and doesn't stop replicas from being scheduled.
+    Spread { topology: String, max_skew: u32 },
+}
+
+impl Placement {
+    /// The topology key for nodes.
+    pub const NODE: &'static str = "kubernetes.io/hostname";
+
+    /// The topology key for zones.
+    pub const ZONE: &'static str = "topology.kubernetes.io/zone";
+
+    /// Share nodes with the given job.
+    pub fn colocate<S: Into<String>>(job: S) -> Placement {
+        Placement::Colocate {
+            job: job.into(),
+            topology: Placement::NODE.to_owned(),
+        }
+    }
+
+    /// Don't share nodes with the given job.
+    pub fn anti_colocate<S: Into<String>>(job: S) -> Placement {
+        Placement::AntiColocate {
+            job: job.into(),
+            topology: Placement::NODE.to_owned(),
+        }
+    }
+
+    /// Spread replicas evenly across zones.
+    pub fn spread_across_zones() -> Placement {
+        Placement::Spread {
+            topology: Placement::ZONE.to_owned(),
+            max_skew: 1,
+        }
+    }
+
+    /// Apply the constraint to a different topology key, e.g.
+    /// `Placement::ZONE` to colocate by zone instead of by node.
+    pub fn with_topology<S: Into<String>>(mut self, key: S) -> Placement {
+        match &mut self {
+            Placement::Colocate { topology, .. }
+            | Placement::AntiColocate { topology, .. }
+            | Placement::Spread { topology, .. } => *topology = key.into(),
+        }
+        self
+    }
+
+    /// The other job this constraint refers to, if any.
+    fn job(&self) -> Option<&str> {
+        match self {
+            Placement::Colocate { job, .. } | Placement::AntiColocate { job, .. } => Some(job),
+            Placement::Spread { .. } => None,
+        }
+    }
+}
+
+/// What to do when a component panics. By default, a panicking component
+/// fails its job, which is left to the platform to restart.
+#[derive(Clone, Debug)]
+pub struct RestartPolicy {
+    /// How many times the component can be restarted before the job fails,
+    /// or `None` for no limit.
+    pub max_restarts: Option<u32>,
+
+    /// How long to wait before the first restart. The wait doubles with each
+    /// restart after that, up to `max_backoff`.
+    pub backoff: Duration,
+
+    /// The longest wait between restarts.
+    pub max_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy::never()
+    }
+}
+
+impl RestartPolicy {
+    /// Fail the job as soon as the component panics.
+    pub fn never() -> RestartPolicy {
+        RestartPolicy {
+            max_restarts: Some(0),
+            backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    /// Restart the component right away every time it panics.
+    pub fn always() -> RestartPolicy {
+        RestartPolicy {
+            max_restarts: None,
+            backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    /// Restart the component every time it panics, waiting `initial` before
+    /// the first restart and doubling the wait each time, up to `max`.
+    pub fn backoff(initial: Duration, max: Duration) -> RestartPolicy {
+        RestartPolicy {
+            max_restarts: None,
+            backoff: initial,
+            max_backoff: max,
+        }
+    }
+
+    /// Limit how many times the component can be restarted before the job
+    /// fails.
+    pub fn with_max_restarts(mut self, n: u32) -> RestartPolicy {
+        self.max_restarts = Some(n);
+        self
+    }
+
+    /// How long to wait before restarting a component that has already been
+    /// restarted the given number of times.
+    pub(crate) fn delay(&self, restarts: u32) -> Duration {
+        let factor = 1u32 << restarts.min(16);
+        (self.backoff * factor).min(self.max_backoff.max(self.backoff))
+    }
+}
+
+/// When a job's replicas restart themselves, to shed fragmentation and
+/// leaked resources. Refer to [`JobBuilder::with_max_lifetime`] for details.
+#[derive(Clone, Debug)]
+pub struct MaxLifetime {
+    /// How long a replica runs before restarting.
+    pub lifetime: Duration,
+
+    /// Up to how much earlier a replica can restart, chosen at random when it
+    /// starts, so that replicas started together don't restart together.
+    pub jitter: Duration,
+
+    /// How long a replica stays up after it's marked unready, so that callers
+    /// stop sending it requests and in-flight ones finish.
+    pub drain: Duration,
+}
+
+impl MaxLifetime {
+    /// Restart after `lifetime`, with up to a tenth of it as jitter and a 15
+    /// second drain.
+    pub fn new(lifetime: Duration) -> MaxLifetime {
+        MaxLifetime {
+            lifetime,
+            jitter: lifetime / 10,
+            drain: Duration::from_secs(15),
+        }
+    }
+
+    /// Set the most a replica can restart early by.
+    pub fn with_jitter(mut self, jitter: Duration) -> MaxLifetime {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Set how long a replica drains before exiting.
+    pub fn with_drain(mut self, drain: Duration) -> MaxLifetime {
+        self.drain = drain;
+        self
+    }
+}
+
+/// A number of a job's replicas, either as a count or as a percentage of the
+/// job's replicas.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Budget {
+    Count(u32),
+    Percent(u32),
+}
+
+/// How a stateful job's replicas are updated.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum StatefulUpdate {
+    /// Replace replicas one at a time, from the highest ordinal down, leaving
+    /// replicas with ordinals below `partition` on the old revision.
+    RollingUpdate { partition: u32 },
+
+    /// Only replace replicas when they're deleted, e.g. by an operator.
+    OnDelete,
+}
+
+/// How a job's replicas are replaced when it's deployed, and how many of them
+/// can be down at once. Unset options use the target's defaults. Rollouts are
+/// only applied by targets that schedule jobs, i.e. when deployed to
+/// Kubernetes with `ammn deploy`, and the target's config can override them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Rollout {
+    /// How many replicas can be unavailable while the job is updated.
+    pub max_unavailable: Option<Budget>,
+
+    /// How many replicas can be created above the job's replica count while
+    /// it's updated. Stateful jobs never surge.
+    pub max_surge: Option<Budget>,
+
+    /// How many replicas must stay available during voluntary disruptions,
+    /// such as nodes being drained.
+    pub min_available: Option<Budget>,
+
+    /// How a stateful job's replicas are updated. Ignored for jobs that
+    /// aren't stateful.
+    pub stateful_update: Option<StatefulUpdate>,
+}
+
+impl Rollout {
+    pub fn new() -> Rollout {
+        Rollout::default()
+    }
+
+    pub fn with_max_unavailable(mut self, budget: Budget) -> Rollout {
+        self.max_unavailable = Some(budget);
+        self
+    }
+
+    pub fn with_max_surge(mut self, budget: Budget) -> Rollout {
+        self.max_surge = Some(budget);
+        self
+    }
+
+    pub fn with_min_available(mut self, budget: Budget) -> Rollout {
+        self.min_available = Some(budget);
+        self
+    }
+
+    pub fn with_stateful_update(mut self, update: StatefulUpdate) -> Rollout {
+        self.stateful_update = Some(update);
+        self
+    }
+
+    fn check(&self) {
+        let budgets = [self.max_unavailable, self.max_surge, self.min_available];
+        for budget in budgets.into_iter().flatten() {
+            if let Budget::Percent(n) = budget
+                && n > 100
+            {
+                panic!("rollout budgets can't be more than 100%");
+            }
+        }
+        let is_zero = |b: Option<Budget>| matches!(b, Some(Budget::Count(0) | Budget::Percent(0)));
+        if is_zero(self.max_unavailable) && is_zero(self.max_surge) {
+            panic!("rollouts with no unavailable or surge replicas can never make progress");
+        }
+    }
+}
+
+/// Configuration for a tokio runtime. Unset options use tokio's defaults.
+#[derive(Clone, Debug, Default)]
+pub struct TokioConfig {
+    /// The number of worker threads. Defaults to the number of CPU cores.
+    pub worker_threads: Option<usize>,
+
+    /// The maximum number of threads for blocking operations such as
+    /// `spawn_blocking`. Defaults to 512.
+    pub max_blocking_threads: Option<usize>,
+
+    /// The name given to the runtime's threads.
+    pub thread_name: Option<String>,
+}
+
+impl TokioConfig {
+    /// Create a config using tokio's defaults.
+    pub fn new() -> TokioConfig {
+        TokioConfig::default()
+    }
+
+    /// Set the number of worker threads.
+    pub fn with_worker_threads(mut self, n: usize) -> TokioConfig {
+        self.worker_threads = Some(n);
+        self
+    }
+
+    /// Set the maximum number of blocking threads.
+    pub fn with_max_blocking_threads(mut self, n: usize) -> TokioConfig {
+        self.max_blocking_threads = Some(n);
+        self
+    }
+
+    /// Set the name given to the runtime's threads.
+    pub fn with_thread_name<S: Into<String>>(mut self, name: S) -> TokioConfig {
+        self.thread_name = Some(name.into());
+        self
+    }
+
+    /// Build a multi-threaded runtime from the config. `name` is used for the
+    /// threads if no thread name is configured.
+    pub(crate) fn build(&self, name: &str) -> std::io::Result<tokio::runtime::Runtime> {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.enable_all();
+        builder.thread_name(self.thread_name.as_deref().unwrap_or(name));
+        if let Some(n) = self.worker_threads {
+            builder.worker_threads(n);
+        }
+        if let Some(n) = self.max_blocking_threads {
+            builder.max_blocking_threads(n);
+        }
+        builder.build()
+    }
 }
 
 /// A command line tool or batch job.
 pub struct ToolConfig {
     pub(crate) label: String,
     pub(crate) entry: Box<dyn ToolEntry>,
+    smoke_test: bool,
+}
+
+impl ToolConfig {
+    /// Whether the tool was added with [`AppBuilder::add_smoke_test`].
+    pub fn is_smoke_test(&self) -> bool {
+        self.smoke_test
+    }
 }
 
 pub(crate) trait ToolEntry: Send + Sync {
@@ -139,6 +1189,16 @@ where
 pub struct JobBuilder {
     label: Option<String>,
     components: BTreeMap<String, ComponentConfig>,
+    replicas: u32,
+    standby: u32,
+    rollout: Rollout,
+    runtime: TokioConfig,
+    component_runtimes: BTreeMap<String, TokioConfig>,
+    restart_policy: RestartPolicy,
+    component_restart_policies: BTreeMap<String, RestartPolicy>,
+    component_replicas: BTreeMap<String, BTreeSet<usize>>,
+    max_lifetime: Option<MaxLifetime>,
+    memory_budget: Option<u64>,
 }
 
 impl JobBuilder {
@@ -147,6 +1207,16 @@ impl JobBuilder {
         JobBuilder {
             label: None,
             components: BTreeMap::new(),
+            replicas: 1,
+            standby: 0,
+            rollout: Rollout::default(),
+            runtime: TokioConfig::default(),
+            component_runtimes: BTreeMap::new(),
+            restart_policy: RestartPolicy::default(),
+            component_restart_policies: BTreeMap::new(),
+            component_replicas: BTreeMap::new(),
+            max_lifetime: None,
+            memory_budget: None,
         }
     }
 
@@ -164,10 +1234,58 @@ impl JobBuilder {
                 }
                 comps.values().next().unwrap().label.clone()
             }
-        };
+        };
+        let component_runtimes = std::mem::take(&mut self.component_runtimes);
+        for comp in component_runtimes.keys() {
+            if !comps.contains_key(comp) {
+                panic!(
+                    "runtime configured for component {} not in job {}",
+                    comp, label
+                );
+            }
+        }
+        let component_restart_policies = std::mem::take(&mut self.component_restart_policies);
+        for comp in component_restart_policies.keys() {
+            if !comps.contains_key(comp) {
+                panic!(
+                    "restart policy configured for component {} not in job {}",
+                    comp, label
+                );
+            }
+        }
+        let component_replicas = std::mem::take(&mut self.component_replicas);
+        for (comp, ordinals) in component_replicas.iter() {
+            if !comps.contains_key(comp) {
+                panic!(
+                    "replicas configured for component {} not in job {}",
+                    comp, label
+                );
+            }
+            if let Some(n) = ordinals.iter().find(|n| **n >= self.replicas as usize) {
+                panic!(
+                    "component {} runs on replica {} of job {}, which has {} replicas",
+                    comp, n, label, self.replicas
+                );
+            }
+        }
+        if self.standby > 0 && !comps.values().any(|c| c.is_stateful) {
+            panic!("job {} has standby replicas, but isn't stateful", label);
+        }
         JobConfig {
             label,
             components: comps,
+            replicas: self.replicas,
+            standby: self.standby,
+            rollout: std::mem::take(&mut self.rollout),
+            dependencies: BTreeSet::new(),
+            placement: Vec::new(),
+            runtime: std::mem::take(&mut self.runtime),
+            component_runtimes,
+            restart_policy: std::mem::take(&mut self.restart_policy),
+            component_restart_policies,
+            component_replicas,
+            max_lifetime: self.max_lifetime.take(),
+            memory_budget: self.memory_budget,
         }
     }
 
@@ -176,16 +1294,145 @@ impl JobBuilder {
         self
     }
 
+    /// Set the number of replicas targets should run of the job, which
+    /// defaults to 1. Runtimes that place jobs explicitly, like the static
+    /// runtime, run as many replicas as they're given locations for instead.
+    pub fn with_replicas(&mut self, replicas: u32) -> &mut JobBuilder {
+        if replicas == 0 {
+            panic!("jobs must have at least one replica");
+        }
+        self.replicas = replicas;
+        self
+    }
+
+    /// Run warm standby replicas of the job alongside the replicas set with
+    /// [`with_replicas`][JobBuilder::with_replicas], which receive state from
+    /// the serving replicas and take over when one of them fails. Only
+    /// stateful jobs can have standbys. See [`crate::standby`].
+    pub fn with_standby(&mut self, standby: u32) -> &mut JobBuilder {
+        self.standby = standby;
+        self
+    }
+
+    /// Configure how the job's replicas are replaced during deploys. See
+    /// [`Rollout`].
+    pub fn with_rollout(&mut self, rollout: Rollout) -> &mut JobBuilder {
+        rollout.check();
+        self.rollout = rollout;
+        self
+    }
+
     /// Set the job's label.
     pub fn with_label<S: Into<String>>(&mut self, label: S) -> &mut JobBuilder {
         self.label = Some(label.into());
         self
     }
 
+    /// Configure the tokio runtime the job runs on. This is ignored in local
+    /// mode, where every job shares a runtime with default settings.
+    pub fn with_runtime(&mut self, runtime: TokioConfig) -> &mut JobBuilder {
+        self.runtime = runtime;
+        self
+    }
+
+    /// Run a component on a separate tokio runtime, isolating it from the
+    /// rest of the job. Tasks spawned by the component run on its runtime.
+    pub fn with_component_runtime(&mut self, label: &str, runtime: TokioConfig) -> &mut JobBuilder {
+        self.component_runtimes.insert(label.to_owned(), runtime);
+        self
+    }
+
+    /// Set what happens when a component in the job panics. Components
+    /// without their own policy use this one.
+    pub fn with_restart_policy(&mut self, policy: RestartPolicy) -> &mut JobBuilder {
+        self.restart_policy = policy;
+        self
+    }
+
+    /// Set what happens when the given component panics.
+    pub fn with_component_restart_policy(
+        &mut self,
+        label: &str,
+        policy: RestartPolicy,
+    ) -> &mut JobBuilder {
+        self.component_restart_policies
+            .insert(label.to_owned(), policy);
+        self
+    }
+
+    /// Run a component on only some of the job's replicas, given by their
+    /// ordinals counting from 0, e.g. `&[0]` for a singleton that lives in a
+    /// scaled job. The other replicas don't start it, and it's only
+    /// discovered at the replicas that run it. Since replicas need to know
+    /// their ordinals for this, the job is deployed like a stateful job, and
+    /// runtimes that don't give replicas ordinals refuse to start it. This is
+    /// ignored in local mode, where every component runs.
+    pub fn with_component_on_replicas(
+        &mut self,
+        label: &str,
+        ordinals: &[usize],
+    ) -> &mut JobBuilder {
+        if ordinals.is_empty() {
+            panic!("component {} must run on at least one replica", label);
+        }
+        self.component_replicas
+            .insert(label.to_owned(), ordinals.iter().copied().collect());
+        self
+    }
+
+    /// Restart the job's replicas after they've run for a while. When a
+    /// replica's lifetime is up, it marks itself unready, drains, and exits
+    /// cleanly, for the platform to start it again. This is ignored in local
+    /// mode and for jobs that run once.
+    pub fn with_max_lifetime(&mut self, max: MaxLifetime) -> &mut JobBuilder {
+        if max.lifetime.is_zero() {
+            panic!("max lifetime must be more than zero");
+        }
+        self.max_lifetime = Some(max);
+        self
+    }
+
+    /// Have the watchdog warn when a replica of the job uses more than
+    /// `bytes` of memory, measured as its resident set size. Budgets aren't
+    /// checked when running locally, where every job shares one process. See
+    /// [`crate::watchdog`].
+    pub fn with_memory_budget(&mut self, bytes: u64) -> &mut JobBuilder {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
     /// Add a component to the job.
     pub fn add_component<C: Into<ComponentConfig>>(&mut self, comp: C) -> &mut JobBuilder {
         let comp = comp.into();
         let key = comp.label.clone();
+        for (i, b) in comp.bindings.iter().enumerate() {
+            let dup = comp.bindings[..i].iter().find(|o| {
+                (o.port.number == b.port.number
+                    && o.port.protocol.transport() == b.port.protocol.transport())
+                    || (!b.port.name.is_empty() && o.port.name == b.port.name)
+            });
+            if let Some(o) = dup {
+                panic!(
+                    "component {} binds port {} ({}) twice, also as {} ({})",
+                    key, b.port.number, b.port.name, o.port.number, o.port.name
+                );
+            }
+        }
+        if let Some(o) = self
+            .components
+            .values()
+            .find(|o| o.runs_once != comp.runs_once)
+        {
+            let (once, other) = if comp.runs_once {
+                (&key, &o.label)
+            } else {
+                (&o.label, &key)
+            };
+            panic!(
+                "run-once component {} can't share a job with component {}",
+                once, other
+            );
+        }
         if self.components.insert(key.clone(), comp).is_some() {
             panic!("duplicate component label: {}", key);
         }
@@ -233,17 +1480,80 @@ impl AppBuilder {
                 component_jobs: HashMap::new(),
                 jobs: BTreeMap::new(),
                 tools: BTreeMap::new(),
+                metrics_push: None,
+                job_sources: BTreeMap::new(),
+                slow_start: None,
+                journals: BTreeMap::new(),
+                captures: BTreeMap::new(),
+                dispatch: BTreeMap::new(),
+                concurrency: BTreeMap::new(),
+                quotas: BTreeMap::new(),
+                log_sampling: BTreeMap::new(),
+                task_budgets: BTreeMap::new(),
+                channels: BTreeMap::new(),
+                pipelines: BTreeMap::new(),
+                sharding: BTreeMap::new(),
+                single_flight: BTreeMap::new(),
+                local_retention: BTreeMap::new(),
+                cache: None,
+                resources: HashMap::new(),
+                audit: None,
+                http_version: HttpVersion::default(),
+                emulation: Emulation::default(),
+                resolver: false,
+                embedded_config: None,
+                build_info: BuildInfo {
+                    revision: String::leak(revision.to_owned()),
+                    git_sha: None,
+                    built_at: None,
+                    rustc_version: None,
+                },
             },
         }
     }
 
+    /// Create an empty `AppBuilder` for the build described by `info`, using
+    /// its revision. See [`BuildInfo`].
+    pub fn from_build_info(info: BuildInfo) -> AppBuilder {
+        let mut builder = AppBuilder::new(info.revision);
+        builder.app.build_info = info;
+        builder
+    }
+
     /// Convert the builder into an `AppConfig`.
     pub fn build(&mut self) -> AppConfig {
+        self.check_dependencies();
+        if !self.app.journals.is_empty() && !self.app.tools.contains_key(journal::REPLAY_TOOL) {
+            self.add_tool(journal::REPLAY_TOOL, journal::replay);
+        }
         AppConfig {
             revision: self.app.revision.clone(),
             component_jobs: std::mem::take(&mut self.app.component_jobs),
             jobs: std::mem::take(&mut self.app.jobs),
             tools: std::mem::take(&mut self.app.tools),
+            metrics_push: self.app.metrics_push.take(),
+            job_sources: std::mem::take(&mut self.app.job_sources),
+            slow_start: self.app.slow_start,
+            journals: std::mem::take(&mut self.app.journals),
+            captures: std::mem::take(&mut self.app.captures),
+            dispatch: std::mem::take(&mut self.app.dispatch),
+            concurrency: std::mem::take(&mut self.app.concurrency),
+            quotas: std::mem::take(&mut self.app.quotas),
+            log_sampling: std::mem::take(&mut self.app.log_sampling),
+            task_budgets: std::mem::take(&mut self.app.task_budgets),
+            channels: std::mem::take(&mut self.app.channels),
+            pipelines: std::mem::take(&mut self.app.pipelines),
+            sharding: std::mem::take(&mut self.app.sharding),
+            single_flight: std::mem::take(&mut self.app.single_flight),
+            local_retention: std::mem::take(&mut self.app.local_retention),
+            cache: self.app.cache.take(),
+            resources: std::mem::take(&mut self.app.resources),
+            audit: self.app.audit.take(),
+            http_version: self.app.http_version,
+            emulation: self.app.emulation,
+            resolver: self.app.resolver,
+            build_info: self.app.build_info,
+            embedded_config: self.app.embedded_config,
         }
     }
 
@@ -252,6 +1562,158 @@ impl AppBuilder {
         self
     }
 
+    /// Mount another app, such as one maintained by another team, into this
+    /// one. `f` configures the mounted app as it would configure it on its
+    /// own. Its jobs are added with their labels prefixed by `prefix` and a
+    /// `-`, and so are their dependencies and placements relative to each
+    /// other, so the mounted app is wired together the same way. Its tools,
+    /// channels, pipelines, resources, and component settings are merged
+    /// into this app's.
+    ///
+    /// Component labels aren't prefixed, since they're part of each
+    /// component's kind, which is how its clients find it. A component,
+    /// tool, channel, pipeline, or resource that's in both apps panics, as
+    /// does an app-wide setting, such as the metrics push, that both apps
+    /// set.
+    pub fn mount<F: FnOnce(&mut AppBuilder)>(&mut self, prefix: &str, f: F) -> &mut AppBuilder {
+        let mut mounted = AppBuilder::new(&self.app.revision);
+        f(&mut mounted);
+        let app = mounted.app;
+
+        let mounted_jobs = app.jobs.keys().cloned().collect::<BTreeSet<_>>();
+        let rename = |job: &mut String| {
+            if mounted_jobs.contains(job) {
+                *job = format!("{prefix}-{job}");
+            }
+        };
+        for mut job in app.jobs.into_values() {
+            for comp in job.components() {
+                if let Some(other) = self.app.component_jobs.get(&comp.label) {
+                    panic!(
+                        "mounting {}: component {} is already in job {}",
+                        prefix, comp.label, other
+                    );
+                }
+            }
+            rename(&mut job.label);
+            job.dependencies = std::mem::take(&mut job.dependencies)
+                .into_iter()
+                .map(|mut dep| {
+                    rename(&mut dep);
+                    dep
+                })
+                .collect();
+            for placement in job.placement.iter_mut() {
+                match placement {
+                    Placement::Colocate { job, .. } | Placement::AntiColocate { job, .. } => {
+                        rename(job)
+                    }
+                    Placement::Spread { .. } => (),
+                }
+            }
+            self.add_job(job);
+        }
+        for (mut job, digest) in app.job_sources {
+            rename(&mut job);
+            self.app.job_sources.insert(job, digest);
+        }
+
+        merge(prefix, "tool", &mut self.app.tools, app.tools);
+        merge(prefix, "channel", &mut self.app.channels, app.channels);
+        merge(prefix, "pipeline", &mut self.app.pipelines, app.pipelines);
+        for (id, provider) in app.resources {
+            if self.app.resources.insert(id, provider).is_some() {
+                panic!("mounting {prefix}: a resource of the same type is already provided");
+            }
+        }
+
+        let settings = "settings for component";
+        merge(prefix, settings, &mut self.app.journals, app.journals);
+        merge(prefix, settings, &mut self.app.captures, app.captures);
+        merge(prefix, settings, &mut self.app.dispatch, app.dispatch);
+        merge(prefix, settings, &mut self.app.concurrency, app.concurrency);
+        merge(prefix, settings, &mut self.app.quotas, app.quotas);
+        merge(prefix, settings, &mut self.app.sharding, app.sharding);
+        merge(
+            prefix,
+            settings,
+            &mut self.app.log_sampling,
+            app.log_sampling,
+        );
+        merge(
+            prefix,
+            settings,
+            &mut self.app.task_budgets,
+            app.task_budgets,
+        );
+        merge(
+            prefix,
+            settings,
+            &mut self.app.single_flight,
+            app.single_flight,
+        );
+        merge(
+            prefix,
+            settings,
+            &mut self.app.local_retention,
+            app.local_retention,
+        );
+
+        let app_wide = |name: &str, ours: bool, theirs: bool| {
+            if ours && theirs {
+                panic!("mounting {prefix}: {name} is set by both apps");
+            }
+            theirs
+        };
+        if app_wide(
+            "metrics push",
+            self.app.metrics_push.is_some(),
+            app.metrics_push.is_some(),
+        ) {
+            self.app.metrics_push = app.metrics_push;
+        }
+        if app_wide(
+            "slow start",
+            self.app.slow_start.is_some(),
+            app.slow_start.is_some(),
+        ) {
+            self.app.slow_start = app.slow_start;
+        }
+        if app_wide("cache", self.app.cache.is_some(), app.cache.is_some()) {
+            self.app.cache = app.cache;
+        }
+        if app_wide("audit", self.app.audit.is_some(), app.audit.is_some()) {
+            self.app.audit = app.audit;
+        }
+        if app_wide(
+            "embedded config",
+            self.app.embedded_config.is_some(),
+            app.embedded_config.is_some(),
+        ) {
+            self.app.embedded_config = app.embedded_config;
+        }
+        let ours = self.app.http_version != HttpVersion::default();
+        let theirs = app.http_version != HttpVersion::default();
+        if app_wide(
+            "HTTP version",
+            ours && self.app.http_version != app.http_version,
+            theirs,
+        ) {
+            self.app.http_version = app.http_version;
+        }
+        let ours = self.app.emulation != Emulation::default();
+        let theirs = app.emulation != Emulation::default();
+        if app_wide(
+            "emulation",
+            ours && self.app.emulation != app.emulation,
+            theirs,
+        ) {
+            self.app.emulation = app.emulation;
+        }
+        self.app.resolver |= app.resolver;
+        self
+    }
+
     /// Add a job to the app.
     pub fn add_job<J: Into<JobConfig>>(&mut self, job: J) -> &mut AppBuilder {
         let job = job.into();
@@ -275,18 +1737,588 @@ impl AppBuilder {
         self
     }
 
+    /// Declare that a job depends on another job. When deploying, the
+    /// dependency will be rolled out and ready before the dependent job is
+    /// started. Both jobs must be added to the app before it is built.
+    pub fn add_job_dependency(&mut self, job: &str, dependency: &str) -> &mut AppBuilder {
+        match self.app.jobs.get_mut(job) {
+            Some(j) => j.dependencies.insert(dependency.to_owned()),
+            None => panic!("no such job: {}", job),
+        };
+        self
+    }
+
+    /// Constrain where a job's replicas are placed. Jobs referred to by the
+    /// constraint must be added to the app before it is built.
+    pub fn add_job_placement(&mut self, job: &str, placement: Placement) -> &mut AppBuilder {
+        match self.app.jobs.get_mut(job) {
+            Some(j) => j.placement.push(placement),
+            None => panic!("no such job: {}", job),
+        };
+        self
+    }
+
+    fn check_dependencies(&self) {
+        let mut routes = HashMap::new();
+        // paths by their shape, which must be the same path under any method
+        let mut shapes = HashMap::new();
+        for job in self.app.jobs.values() {
+            for comp in job.components() {
+                for dep in comp.local_dependencies.iter() {
+                    match self.app.component_jobs.get(*dep) {
+                        Some(j) if *j == job.label => (),
+                        Some(j) => panic!(
+                            "component {} uses local component {}, but they're in different jobs ({} and {})",
+                            comp.label, dep, job.label, j
+                        ),
+                        None => panic!(
+                            "component {} uses local component {}, which is not installed",
+                            comp.label, dep
+                        ),
+                    }
+                }
+                let migrations = (comp.migrations)();
+                if !comp.is_stateful && !migrations.is_empty() {
+                    panic!(
+                        "component {} has storage migrations but no storage",
+                        comp.label
+                    );
+                }
+                migration::check(&comp.label, &migrations);
+                for op in comp.rpc_ops.into_iter().flatten() {
+                    let Some(route) = op.http else { continue };
+                    let (method, path) = gateway::parse_route(route)
+                        .unwrap_or_else(|e| panic!("op {}::{}: {}", comp.label, op.name, e));
+                    for param in gateway::path_params(path) {
+                        if !op.arg_names.contains(&param) {
+                            panic!(
+                                "route {:?} of op {}::{} has parameter {}, which is not one of its arguments",
+                                route, comp.label, op.name, param
+                            );
+                        }
+                    }
+                    let this = format!("{}::{}", comp.label, op.name);
+                    if let Some(other) = routes.insert((method, path), this.clone()) {
+                        panic!("route {:?} is used by both {} and {}", route, other, this);
+                    }
+                    let shape = gateway::path_shape(path);
+                    match shapes.get(&shape) {
+                        Some((other_path, other)) if *other_path != path => panic!(
+                            "route {:?} of {} and path {:?} of {} name their parameters differently",
+                            route, this, other_path, other
+                        ),
+                        Some(_) => {}
+                        None => {
+                            shapes.insert(shape, (path, this));
+                        }
+                    }
+                }
+            }
+            for dep in job.dependencies() {
+                if !self.app.jobs.contains_key(dep) {
+                    panic!("job {} depends on unknown job {}", job.label, dep);
+                }
+            }
+            for other in job.placement.iter().filter_map(|p| p.job()) {
+                if !self.app.jobs.contains_key(other) {
+                    panic!("job {} placed relative to unknown job {}", job.label, other);
+                }
+            }
+        }
+
+        for label in self.app.journals.keys() {
+            if !self.app.component_jobs.contains_key(label) {
+                panic!("journal configured for unknown component {}", label);
+            }
+        }
+        for label in self.app.captures.keys() {
+            if !self.app.component_jobs.contains_key(label) {
+                panic!("capture configured for unknown component {}", label);
+            }
+        }
+        for label in self.app.dispatch.keys() {
+            if !self.app.component_jobs.contains_key(label) {
+                panic!("local dispatch configured for unknown component {}", label);
+            }
+        }
+        for label in self.app.concurrency.keys() {
+            if !self.app.component_jobs.contains_key(label) {
+                panic!(
+                    "concurrency limit configured for unknown component {}",
+                    label
+                );
+            }
+        }
+        for (label, quotas) in self.app.quotas.iter() {
+            if !self.app.component_jobs.contains_key(label) {
+                panic!("caller quota configured for unknown component {}", label);
+            }
+            for caller in quotas.keys().flatten() {
+                if !self.app.component_jobs.contains_key(caller)
+                    && !self.app.tools.contains_key(caller)
+                {
+                    panic!(
+                        "caller quota for {} configured for unknown caller {}",
+                        label, caller
+                    );
+                }
+            }
+        }
+        for label in self.app.log_sampling.keys() {
+            if !self.app.component_jobs.contains_key(label) {
+                panic!("log sampling configured for unknown component {}", label);
+            }
+        }
+        for label in self.app.task_budgets.keys() {
+            if !self.app.component_jobs.contains_key(label) {
+                panic!("task budget configured for unknown component {}", label);
+            }
+        }
+        for (label, ops) in self.app.single_flight.iter() {
+            let comp = self
+                .app
+                .component_jobs
+                .get(label)
+                .and_then(|j| self.app.jobs[j].component(label));
+            let Some(comp) = comp else {
+                panic!("single-flight configured for unknown component {}", label);
+            };
+            let known = comp.rpc_ops.unwrap_or_default();
+            for op in ops {
+                if !known.iter().any(|o| o.name == op) {
+                    panic!("single-flight configured for unknown op {}::{}", label, op);
+                }
+            }
+        }
+        for label in self.app.local_retention.keys() {
+            let comp = self
+                .app
+                .component_jobs
+                .get(label)
+                .and_then(|j| self.app.jobs[j].component(label));
+            match comp {
+                Some(comp) if comp.is_stateful => (),
+                Some(_) => panic!(
+                    "local retention configured for stateless component {}",
+                    label
+                ),
+                None => panic!("local retention configured for unknown component {}", label),
+            }
+        }
+        if let Some(label) = self.app.audit.as_ref().and_then(|a| a.storage.as_ref()) {
+            let comp = self
+                .app
+                .component_jobs
+                .get(label)
+                .and_then(|j| self.app.jobs[j].component(label));
+            match comp {
+                Some(comp) if comp.is_stateful => (),
+                Some(_) => panic!("audit storage component {} is not stateful", label),
+                None => panic!("audit storage configured for unknown component {}", label),
+            }
+        }
+        for (label, channel) in self.app.channels.iter() {
+            let job = |comp: &str| match self.app.component_jobs.get(comp) {
+                Some(job) => job,
+                None => panic!("channel {} uses unknown component {}", label, comp),
+            };
+            let (producer_job, consumer_job) = (job(&channel.producer), job(&channel.consumer));
+            if producer_job != consumer_job {
+                panic!(
+                    "channel {} connects {} and {}, but they're in different jobs ({} and {})",
+                    label, channel.producer, channel.consumer, producer_job, consumer_job
+                );
+            }
+        }
+        for label in self.app.sharding.keys() {
+            if !self.app.component_jobs.contains_key(label) {
+                panic!("sharding configured for unknown component {}", label);
+            }
+        }
+        if self.app.cache.is_some() && !self.app.component_jobs.contains_key(cache::LABEL) {
+            panic!("cache configured, but cache::CacheComponent isn't installed in any job");
+        }
+        // standbys run the component too, so they count
+        if let Some(job) = self.app.component_jobs.get(metadata::LABEL)
+            && (0..self.app.jobs[job].replicas() as usize)
+                .filter(|&i| self.app.jobs[job].runs_on(metadata::LABEL, i))
+                .count()
+                > 1
+        {
+            panic!(
+                "metadata::MetadataComponent is installed in job {}, which runs it on more than one replica",
+                job
+            );
+        }
+        if let Some(job) = self.app.component_jobs.get(schema::LABEL)
+            && self.app.jobs[job].replicas() > 1
+        {
+            panic!(
+                "schema::SchemaRegistryComponent is installed in job {}, which has more than one replica",
+                job
+            );
+        }
+        for (label, pipeline) in self.app.pipelines.iter() {
+            if pipeline.stages.len() < 2 {
+                panic!("pipeline {} needs at least two stages", label);
+            }
+            for (i, stage) in pipeline.stages.iter().enumerate() {
+                if !self.app.component_jobs.contains_key(stage) {
+                    panic!("pipeline {} uses unknown component {}", label, stage);
+                }
+                if pipeline.stages[..i].contains(stage) {
+                    panic!("pipeline {} uses {} more than once", label, stage);
+                }
+            }
+        }
+
+        // depth-first search for cycles, where `visiting` is the current path
+        fn visit<'a>(
+            jobs: &'a BTreeMap<String, JobConfig>,
+            label: &'a str,
+            visiting: &mut Vec<&'a str>,
+            done: &mut BTreeSet<&'a str>,
+        ) {
+            if done.contains(label) {
+                return;
+            }
+            if let Some(i) = visiting.iter().position(|x| *x == label) {
+                let cycle = visiting[i..].join(" -> ");
+                panic!("job dependency cycle: {} -> {}", cycle, label);
+            }
+            visiting.push(label);
+            for dep in jobs[label].dependencies() {
+                visit(jobs, dep, visiting, done);
+            }
+            visiting.pop();
+            done.insert(label);
+        }
+
+        let mut done = BTreeSet::new();
+        for label in self.app.jobs.keys() {
+            visit(&self.app.jobs, label, &mut Vec::new(), &mut done);
+        }
+    }
+
+    /// Push metrics to an OTLP/HTTP collector, e.g.
+    /// `http://otel-collector:4318/v1/metrics`, in addition to serving them
+    /// for scraping.
+    pub fn with_metrics_push(&mut self, push: MetricsPushConfig) -> &mut AppBuilder {
+        self.app.metrics_push = Some(push);
+        self
+    }
+
+    /// Ramp the share of traffic sent to newly discovered endpoints up to
+    /// their full weight over the given window, rather than sending them a
+    /// full share right away. Endpoints present when a component is first
+    /// discovered aren't ramped, and each process ramps endpoints from when
+    /// it first sees them.
+    pub fn with_slow_start(&mut self, window: Duration) -> &mut AppBuilder {
+        self.app.slow_start = Some(window);
+        self
+    }
+
+    /// Hand in-process calls to a component to a pool of workers through a
+    /// bounded queue, rather than running its handler on the caller's task.
+    /// Requests are still passed without serialization, but bursts of
+    /// internal calls can't starve other work, such as the RPC server, and
+    /// calls are rejected with a spurious error when the queue is full, like
+    /// an overloaded remote replica would. Calls made with a client's typed
+    /// methods are dispatched; requests passed by reference, like with
+    /// `RpcClient::call`, are still handled on the caller's task.
+    pub fn with_local_dispatch(
+        &mut self,
+        label: &str,
+        dispatch: DispatchConfig,
+    ) -> &mut AppBuilder {
+        self.app.dispatch.insert(label.to_owned(), dispatch);
+        self
+    }
+
+    /// Limit how many RPC requests to a component each of its servers handles
+    /// at once, scheduling the rest by the priority of their ops. Ops are
+    /// interactive unless they're marked `batch` in
+    /// [`rpc_component!`][crate::rpc_component]. Waiting interactive requests are handled
+    /// first, but a batch request is let through after a run of interactive
+    /// ones so that batch work still progresses, and batch requests can only
+    /// use part of the capacity, so long batch calls can't hold all of it.
+    /// When the queue is full, waiting batch requests are shed to make room
+    /// for interactive ones, and other requests are shed with
+    /// [`AppError::Overloaded`][crate::AppError::Overloaded], which tells
+    /// clients how long to wait before retrying. In-process calls aren't
+    /// limited.
+    pub fn with_concurrency_limit(
+        &mut self,
+        label: &str,
+        limit: ConcurrencyConfig,
+    ) -> &mut AppBuilder {
+        self.app.concurrency.insert(label.to_owned(), limit);
+        self
+    }
+
+    /// Limit how much of a component one caller, a component or tool with the
+    /// given label, can use, so that a misbehaving caller can't starve the
+    /// others. Each server of the component rejects requests from the caller
+    /// over its quota with
+    /// [`AppError::QuotaExceeded`][crate::AppError::QuotaExceeded], which
+    /// clients retry after backing off. Quotas are counted by each server
+    /// separately, so a caller can make up to its quota to every replica.
+    /// In-process calls aren't limited. See [`QuotaConfig`].
+    pub fn with_caller_quota(
+        &mut self,
+        label: &str,
+        caller: &str,
+        quota: QuotaConfig,
+    ) -> &mut AppBuilder {
+        let quotas = self.app.quotas.entry(label.to_owned()).or_default();
+        quotas.insert(Some(caller.to_owned()), quota);
+        self
+    }
+
+    /// Limit how much of a component each caller without its own
+    /// [quota][Self::with_caller_quota] can use. Every caller gets the quota
+    /// to itself, except callers that can't be identified, which share one.
+    pub fn with_default_caller_quota(
+        &mut self,
+        label: &str,
+        quota: QuotaConfig,
+    ) -> &mut AppBuilder {
+        let quotas = self.app.quotas.entry(label.to_owned()).or_default();
+        quotas.insert(None, quota);
+        self
+    }
+
+    /// Sample the logs of calls made to a component, for components called
+    /// too often to log every call. Clients log a summary of their calls to
+    /// the component every interval, with their count, latency percentiles,
+    /// errors, and retries, and only log the outgoing requests and retries
+    /// of a sample of calls. Calls that fail are still logged. Refer to
+    /// [`LogSampling`] for the settings.
+    pub fn with_log_sampling(&mut self, label: &str, sampling: LogSampling) -> &mut AppBuilder {
+        self.app.log_sampling.insert(label.to_owned(), sampling);
+        self
+    }
+
+    /// Have the watchdog warn when a component has more than `max` tasks
+    /// running, as an early sign of a task leak. Only tasks started through
+    /// the component's [`TaskSet`][crate::tasks::TaskSet], by its actors, or
+    /// with [`watchdog::spawn`][crate::watchdog::spawn] are counted. See
+    /// [`crate::watchdog`].
+    pub fn with_task_budget(&mut self, label: &str, max: usize) -> &mut AppBuilder {
+        self.app.task_budgets.insert(label.to_owned(), max);
+        self
+    }
+
+    /// Collapse identical concurrent calls to some of a component's ops, made
+    /// with [`RpcClient::call`][crate::rpc::RpcClient::call] from the same
+    /// process, into a single call whose result is shared. This protects
+    /// hot, read-only ops such as config lookups from bursts of identical
+    /// requests. Calls are identical when their serialized requests are, and
+    /// the shared call runs with the context and deadline of the caller that
+    /// made it. Ops that change state shouldn't be collapsed.
+    pub fn with_single_flight(&mut self, label: &str, ops: &[&str]) -> &mut AppBuilder {
+        let entry = self.app.single_flight.entry(label.to_owned()).or_default();
+        entry.extend(ops.iter().map(|&op| op.to_owned()));
+        self
+    }
+
+    /// Limit what a stateful component keeps in its storage when running
+    /// locally, so that development storage under `.amimono/storage` doesn't
+    /// grow forever. Files past the limits are removed whenever the runtime
+    /// measures the component's storage, or right away with
+    /// [`Component::storage_gc`][crate::component::Component::storage_gc].
+    /// Files whose names start with `.amimono`, where the runtime keeps its
+    /// own state, are never removed. Deployed storage isn't affected.
+    pub fn with_local_retention(
+        &mut self,
+        label: &str,
+        retention: RetentionConfig,
+    ) -> &mut AppBuilder {
+        self.app.local_retention.insert(label.to_owned(), retention);
+        self
+    }
+
+    /// Write audit events to stdout or a component's storage, in addition to
+    /// the log. See [`audit`][crate::audit].
+    pub fn with_audit(&mut self, audit: AuditConfig) -> &mut AppBuilder {
+        self.app.audit = Some(audit);
+        self
+    }
+
+    /// Embed the config dump generated by `amimono_build::EmbeddedConfig` in
+    /// a build script, from [`include_config!`][crate::include_config], so
+    /// that it can be read with `--dump-config-embedded` or by `ammn` without
+    /// running the app. An empty dump, for a build without a saved one, is
+    /// ignored.
+    pub fn with_embedded_config(&mut self, dump: &'static str) -> &mut AppBuilder {
+        self.app.embedded_config = dump
+            .strip_prefix(amimono_schemas::EMBEDDED_CONFIG_MARKER)
+            .map(|dump| dump.trim_end_matches('\0'));
+        self
+    }
+
+    /// Add a bounded channel between two components, which must be installed
+    /// in the same job. Items are passed in-process without serialization,
+    /// which suits high-throughput pipelines better than RPC calls, and
+    /// senders wait when the channel is full. See
+    /// [`channel`][crate::channel] for how components get each end.
+    pub fn with_channel(&mut self, label: &str, channel: ChannelConfig) -> &mut AppBuilder {
+        self.app.channels.insert(label.to_owned(), channel);
+        self
+    }
+
+    /// Shard a component: split its keys into `sharding.shards` shards, each
+    /// owned by one of its replicas, so that calls for a key can be sent to
+    /// the replica that holds its data. Callers map keys to shards with
+    /// [`shard::of`][crate::shard::of] and route calls with
+    /// [`RpcClient::with_shard`][crate::rpc::RpcClient::with_shard]. Which
+    /// replica owns each shard is published through discovery, and a replica
+    /// that gets a call for a shard it doesn't own, e.g. while shards move
+    /// after replicas are added or removed, forwards it to the owner. See
+    /// [`shard`][crate::shard].
+    pub fn with_sharding(&mut self, label: &str, sharding: ShardingConfig) -> &mut AppBuilder {
+        self.app.sharding.insert(label.to_owned(), sharding);
+        self
+    }
+
+    /// Configure the built-in cache component, which must be installed in a
+    /// job with [`CacheComponent::installer`][crate::cache::CacheComponent].
+    /// Without this, it uses [`CacheConfig::default`].
+    pub fn with_cache(&mut self, cache: CacheConfig) -> &mut AppBuilder {
+        self.app.cache = Some(cache);
+        self
+    }
+
+    /// Provide a resource shared by the components of each job, such as a
+    /// connection pool, which components get with
+    /// [`Deps::resource`][crate::resource::Deps::resource]. The factory is
+    /// run the first time a component in a process asks for the resource.
+    /// Panics if a resource of the same type was already provided.
+    pub fn provide<T, F, Fut>(&mut self, factory: F) -> &mut AppBuilder
+    where
+        T: Send + Sync + 'static,
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = T> + Send + 'static,
+    {
+        let provider = resource::Provider::new(factory);
+        if let Some(other) = self.app.resources.insert(TypeId::of::<T>(), provider) {
+            panic!("resource {} provided more than once", other.type_name);
+        }
+        self
+    }
+
+    /// Add a pipeline through the given components, in order. Each stage
+    /// consumes a stream of items from the stage before it, in-process when
+    /// the two are in the same job and over a long-lived HTTP request
+    /// otherwise, and waits when the next stage falls behind, so the whole
+    /// pipeline runs at the pace of its slowest stage. See
+    /// [`pipeline`][crate::pipeline] for how stages get their streams.
+    pub fn with_pipeline(&mut self, label: &str, pipeline: PipelineConfig) -> &mut AppBuilder {
+        self.app.pipelines.insert(label.to_owned(), pipeline);
+        self
+    }
+
+    /// Set the HTTP version used for RPC requests between jobs. See
+    /// [`HttpVersion`] for what each version requires.
+    pub fn with_http_version(&mut self, version: HttpVersion) -> &mut AppBuilder {
+        self.app.http_version = version;
+        self
+    }
+
+    /// Set when emulated components run their emulator instead of their
+    /// real implementation. Defaults to [`Emulation::Dev`]. See
+    /// [`emulation`][crate::emulation].
+    pub fn with_emulation(&mut self, emulation: Emulation) -> &mut AppBuilder {
+        self.app.emulation = emulation;
+        self
+    }
+
+    /// Serve `/admin/resolve/{label}` in the local and static runtimes. It
+    /// answers where a component is running right now, as JSON, or as
+    /// `host:port` lines for one of its ports with `?port=<name>`, for tools
+    /// like curl scripts during development. It's off by default, since it
+    /// tells anything that can reach a job where every component is.
+    pub fn with_resolver(&mut self) -> &mut AppBuilder {
+        self.app.resolver = true;
+        self
+    }
+
+    /// Record a sample of the RPC requests handled by a component, so that
+    /// they can be replayed later with `ammn replay`. This also adds the
+    /// replay tool to the app.
+    pub fn with_journal(&mut self, label: &str, journal: JournalConfig) -> &mut AppBuilder {
+        self.app.journals.insert(label.to_owned(), journal);
+        self
+    }
+
+    /// Capture a sample of the request and response payloads of a
+    /// component's RPC requests, redacted as configured, as logs. See
+    /// [`rpc::capture`][crate::rpc::capture].
+    pub fn with_capture(&mut self, label: &str, capture: CaptureConfig) -> &mut AppBuilder {
+        self.app.captures.insert(label.to_owned(), capture);
+        self
+    }
+
+    /// Set the digests of jobs' sources, as computed by
+    /// `amimono_build::AppDigest::compute_jobs`, i.e. `job=digest` pairs
+    /// separated by commas. These let deploys skip jobs that haven't changed.
+    pub fn with_job_digests(&mut self, digests: &str) -> &mut AppBuilder {
+        for pair in digests.split(',').filter(|s| !s.is_empty()) {
+            match pair.split_once('=') {
+                Some((job, digest)) => {
+                    self.app
+                        .job_sources
+                        .insert(job.trim().to_owned(), digest.trim().to_owned());
+                }
+                None => panic!("invalid job digest: {}", pair),
+            }
+        }
+        self
+    }
+
     /// Add a tool to the app.
     pub fn add_tool<Fut>(
         &mut self,
         label: &str,
         entry: fn(&'static [&'static str]) -> Fut,
     ) -> &mut AppBuilder
+    where
+        Fut: Future<Output = AppResult<()>> + Send + 'static,
+    {
+        self.insert_tool(label, entry, false)
+    }
+
+    /// Add a tool that checks a deployed revision of the app, e.g. by calling
+    /// its components. `ammn deploy` runs every smoke test against each new
+    /// revision once it's deployed, with no arguments, and treats one
+    /// returning an error as a failed deploy: rolling Kubernetes deploys undo
+    /// the rollouts of the jobs they changed, blue-green deploys don't switch
+    /// to the new revision, and static deploys, which can't be undone, stop
+    /// without recording the new revision as deployed.
+    pub fn add_smoke_test<Fut>(
+        &mut self,
+        label: &str,
+        entry: fn(&'static [&'static str]) -> Fut,
+    ) -> &mut AppBuilder
+    where
+        Fut: Future<Output = AppResult<()>> + Send + 'static,
+    {
+        self.insert_tool(label, entry, true)
+    }
+
+    fn insert_tool<Fut>(
+        &mut self,
+        label: &str,
+        entry: fn(&'static [&'static str]) -> Fut,
+        smoke_test: bool,
+    ) -> &mut AppBuilder
     where
         Fut: Future<Output = AppResult<()>> + Send + 'static,
     {
         let tool = ToolConfig {
             label: label.to_owned(),
             entry: Box::new(BoxToolEntry { entry }),
+            smoke_test,
         };
         let current = self.app.tools.insert(tool.label.clone(), tool);
         if current.is_some() {
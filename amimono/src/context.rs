@@ -0,0 +1,310 @@
+//! Request metadata that is propagated across RPC calls.
+//!
+//! A `RequestContext` is typically set once at the edge of the application,
+//! e.g. in a gateway component, by wrapping the handling of an external
+//! request with [`scope`]. Every `RpcClient` call made while the context is
+//! set carries it to the downstream component in a header, where it is set
+//! again for the duration of the handler. Handlers can read it with
+//! [`current`] instead of threading tenancy information through every op.
+//!
+//! The context is stored in a task-local, so it is not inherited by tasks
+//! spawned with `tokio::spawn`. Use `scope(current(), ...)` to carry it over
+//! explicitly.
+//!
+//! # Request IDs
+//!
+//! Every call gets a request ID at its first hop, i.e. when it's made while
+//! no request is being handled, unless the context already has one, e.g. from
+//! an external request's header. The ID is propagated with the rest of the
+//! context, so every downstream call made while handling the request shares
+//! it, and errors returned to the first hop are labeled with it. Installing
+//! the app's logger with [`init_logger`] prefixes it to every log record
+//! written while handling the request, so that a failure deep in the call
+//! graph can be found in the logs of every job it passed through.
+//!
+//! # Deadlines
+//!
+//! A context can carry a deadline, set with [`RequestContext::with_timeout`].
+//! Calls made while a deadline is set fail with
+//! [`AppError::DeadlineExceeded`] once it passes, and aren't retried past it.
+//! Deadlines are sent to other jobs as the remaining budget rather than as an
+//! absolute time, and the receiving job turns the budget back into a deadline
+//! on its own monotonic clock. This keeps deadlines correct when the clocks of
+//! different nodes disagree, at the cost of not counting the time the request
+//! spends in transit.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use rand::Rng;
+
+use crate::{AppError, AppResult};
+
+/// The header used to propagate the context between jobs.
+pub(crate) const HEADER: &str = "x-amimono-context";
+
+/// The header used to propagate the remaining budget of a deadline, in
+/// milliseconds.
+pub(crate) const BUDGET_HEADER: &str = "x-amimono-budget";
+
+/// Metadata about the request currently being handled.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestContext {
+    /// The tenant the request is being made on behalf of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+
+    /// The end user the request is being made on behalf of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    /// The locale to use for any user-facing output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+
+    /// The ID of the request, shared by every call made while handling it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+
+    /// Any other application-defined metadata.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, String>,
+
+    /// When the request must be finished by, according to this process's
+    /// monotonic clock. This is propagated separately from the rest of the
+    /// context.
+    #[serde(skip)]
+    pub deadline: Option<Instant>,
+}
+
+impl RequestContext {
+    /// Create an empty context.
+    pub fn new() -> RequestContext {
+        RequestContext::default()
+    }
+
+    /// Set the tenant.
+    pub fn with_tenant<S: Into<String>>(mut self, tenant: S) -> RequestContext {
+        self.tenant = Some(tenant.into());
+        self
+    }
+
+    /// Set the user.
+    pub fn with_user<S: Into<String>>(mut self, user: S) -> RequestContext {
+        self.user = Some(user.into());
+        self
+    }
+
+    /// Set the locale.
+    pub fn with_locale<S: Into<String>>(mut self, locale: S) -> RequestContext {
+        self.locale = Some(locale.into());
+        self
+    }
+
+    /// Set the request ID, e.g. to one from an external request, rather than
+    /// having one generated.
+    pub fn with_request_id<S: Into<String>>(mut self, id: S) -> RequestContext {
+        self.request_id = Some(id.into());
+        self
+    }
+
+    /// Set an application-defined value.
+    pub fn with_extra<K: Into<String>, V: Into<String>>(mut self, k: K, v: V) -> RequestContext {
+        self.extra.insert(k.into(), v.into());
+        self
+    }
+
+    /// Set a deadline the given duration from now, unless the context
+    /// already has an earlier one.
+    pub fn with_timeout(self, timeout: Duration) -> RequestContext {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    /// Set a deadline, unless the context already has an earlier one.
+    pub fn with_deadline(mut self, deadline: Instant) -> RequestContext {
+        self.deadline = Some(match self.deadline {
+            Some(d) => d.min(deadline),
+            None => deadline,
+        });
+        self
+    }
+
+    fn is_empty(&self) -> bool {
+        self == &RequestContext {
+            deadline: self.deadline,
+            ..RequestContext::default()
+        }
+    }
+}
+
+tokio::task_local! {
+    static CONTEXT: RequestContext;
+}
+
+/// Run a future with the given request context.
+pub fn scope<F: Future>(ctx: RequestContext, fut: F) -> impl Future<Output = F::Output> {
+    CONTEXT.scope(ctx, fut)
+}
+
+/// The context of the request currently being handled. This is empty if no
+/// context was set.
+pub fn current() -> RequestContext {
+    CONTEXT.try_with(|ctx| ctx.clone()).unwrap_or_default()
+}
+
+/// The current tenant, if any.
+pub fn tenant() -> Option<String> {
+    CONTEXT.try_with(|ctx| ctx.tenant.clone()).ok().flatten()
+}
+
+/// The current user, if any.
+pub fn user() -> Option<String> {
+    CONTEXT.try_with(|ctx| ctx.user.clone()).ok().flatten()
+}
+
+/// The current locale, if any.
+pub fn locale() -> Option<String> {
+    CONTEXT.try_with(|ctx| ctx.locale.clone()).ok().flatten()
+}
+
+/// The current request ID, if any.
+pub fn request_id() -> Option<String> {
+    CONTEXT
+        .try_with(|ctx| ctx.request_id.clone())
+        .ok()
+        .flatten()
+}
+
+/// The current deadline, if any.
+pub fn deadline() -> Option<Instant> {
+    CONTEXT.try_with(|ctx| ctx.deadline).ok().flatten()
+}
+
+/// How much time is left before the current deadline, if there is one. This
+/// is zero once the deadline has passed.
+pub fn remaining() -> Option<Duration> {
+    deadline().map(|d| d.saturating_duration_since(Instant::now()))
+}
+
+/// Fail with `AppError::DeadlineExceeded` if the future doesn't finish by the
+/// deadline.
+pub(crate) async fn enforce<T, F>(label: &str, deadline: Option<Instant>, fut: F) -> AppResult<T>
+where
+    F: Future<Output = AppResult<T>>,
+{
+    let Some(deadline) = deadline else {
+        return fut.await;
+    };
+    match tokio::time::timeout_at(deadline.into(), fut).await {
+        Ok(res) => res,
+        Err(_) => Err(AppError::DeadlineExceeded(label.to_owned())),
+    }
+}
+
+/// Run an outgoing call with a request ID, generating one if this is its
+/// first hop. Errors from a call that generated its ID are labeled with it.
+pub(crate) async fn originate<T, F>(fut: F) -> AppResult<T>
+where
+    F: Future<Output = AppResult<T>>,
+{
+    if request_id().is_some() {
+        return fut.await;
+    }
+    let id = format!("{:016x}", rand::rng().random::<u64>());
+    let ctx = current().with_request_id(id.as_str());
+    match CONTEXT.scope(ctx, fut).await {
+        Err(AppError::Downstream(at, e)) => {
+            Err(AppError::Downstream(format!("{at} (request {id})"), e))
+        }
+        res => res,
+    }
+}
+
+/// A logger that prefixes records written while handling a request with the
+/// request's ID. See [`init_logger`].
+pub struct RequestIdLogger<L>(pub L);
+
+impl<L: log::Log> log::Log for RequestIdLogger<L> {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        match request_id() {
+            Some(id) => self.0.log(
+                &log::Record::builder()
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .args(format_args!("[{id}] {}", record.args()))
+                    .build(),
+            ),
+            None => self.0.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// Install `logger` as the global logger, wrapped in a [`RequestIdLogger`],
+/// logging records up to `level`. Panics if a logger is already installed.
+///
+/// ```ignore
+/// let logger = env_logger::Builder::from_default_env().build();
+/// let level = logger.filter();
+/// amimono::context::init_logger(logger, level);
+/// ```
+pub fn init_logger<L: log::Log + 'static>(logger: L, level: log::LevelFilter) {
+    log::set_boxed_logger(Box::new(RequestIdLogger(logger))).expect("logger already installed");
+    log::set_max_level(level);
+}
+
+/// Encode the current deadline as a budget header value, if there is one.
+pub(crate) fn to_budget_header() -> Option<String> {
+    remaining().map(|r| r.as_millis().to_string())
+}
+
+/// Set a context's deadline from a budget header value. Malformed budgets are
+/// ignored.
+pub(crate) fn with_budget(ctx: RequestContext, value: &[u8]) -> RequestContext {
+    let budget = std::str::from_utf8(value)
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok());
+    match budget {
+        Some(ms) => ctx.with_timeout(Duration::from_millis(ms)),
+        None => {
+            log::warn!("ignoring malformed deadline budget");
+            ctx
+        }
+    }
+}
+
+/// Encode the current context as a header value, if there is one.
+pub(crate) fn to_header() -> Option<Vec<u8>> {
+    CONTEXT
+        .try_with(|ctx| match ctx.is_empty() {
+            true => None,
+            false => serde_json::to_vec(ctx).ok(),
+        })
+        .ok()
+        .flatten()
+}
+
+/// Decode a context from a header value. Malformed contexts are ignored.
+pub(crate) fn from_header(value: &[u8]) -> RequestContext {
+    match serde_json::from_slice(value) {
+        Ok(ctx) => ctx,
+        Err(e) => {
+            log::warn!("ignoring malformed request context: {e}");
+            RequestContext::default()
+        }
+    }
+}
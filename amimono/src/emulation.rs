@@ -0,0 +1,106 @@
+//! Emulated components, for running an app without its external
+//! dependencies.
+//!
+//! Components that wrap something outside the app, like an object store or
+//! an SMTP server, can be installed with two implementations: the real one,
+//! and an in-process emulator that implements the same component without
+//! leaving the process. Both are given to [`Emulated`]:
+//!
+//! ```ignore
+//! JobBuilder::new()
+//!     .with_label("mail")
+//!     .install(Emulated::<mail::Component<SmtpMailer>, mail::Component<FakeMailer>>::installer)
+//! ```
+//!
+//! Which of the two runs is decided when the component starts, by the app's
+//! [`Emulation`], set with
+//! [`AppBuilder::with_emulation`][crate::config::AppBuilder::with_emulation].
+//! By default, emulators run in the local development runtime and real
+//! implementations everywhere else, so local runs are hermetic without any
+//! changes to the app's config, and the config deployed is the same one run
+//! locally. Callers use the component's client as usual, and can't tell the
+//! difference.
+//!
+//! The component is installed as the real implementation declares it, so
+//! the emulator runs with the real implementation's storage migrations, if
+//! it has any, and local dependencies of either one must be installed in the
+//! same job.
+
+use std::marker::PhantomData;
+
+use futures::future::BoxFuture;
+
+use crate::{
+    component::{Component, ComponentKind, LocalDependency},
+    error::Result,
+    migration::StorageMigration,
+    runtime,
+};
+
+/// When [`Emulated`] components run their emulator.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Emulation {
+    /// Run emulators in the local development runtime, e.g. with `--local`,
+    /// and real implementations everywhere else.
+    #[default]
+    Dev,
+    /// Always run emulators, e.g. for tests or hermetic staging deploys.
+    Always,
+    /// Never run emulators.
+    Never,
+}
+
+/// Whether [`Emulated`] components are running their emulator in this
+/// process.
+pub fn is_emulating() -> bool {
+    match runtime::config().emulation() {
+        Emulation::Dev => runtime::provider().is_dev(),
+        Emulation::Always => true,
+        Emulation::Never => false,
+    }
+}
+
+/// A component with a real implementation `R` and an emulator `E`. See the
+/// [module-level documentation][self].
+pub struct Emulated<R, E>(PhantomData<fn() -> (R, E)>);
+
+impl<R, E> Component for Emulated<R, E>
+where
+    R: Component,
+    E: Component<Kind = R::Kind>,
+{
+    type Kind = R::Kind;
+
+    async fn main<F>(set_instance: F)
+    where
+        F: FnOnce(<Self::Kind as ComponentKind>::Instance) -> BoxFuture<'static, ()> + Send,
+    {
+        if is_emulating() {
+            log::info!("{}: running emulator", R::Kind::LABEL);
+            E::main(set_instance).await
+        } else {
+            R::main(set_instance).await
+        }
+    }
+
+    fn local_dependencies() -> Vec<LocalDependency> {
+        let mut deps = R::local_dependencies();
+        for dep in E::local_dependencies() {
+            if !deps.contains(&dep) {
+                deps.push(dep);
+            }
+        }
+        deps
+    }
+
+    async fn warmup() -> Result<()> {
+        match is_emulating() {
+            true => E::warmup().await,
+            false => R::warmup().await,
+        }
+    }
+
+    fn migrations() -> Vec<Box<dyn StorageMigration>> {
+        R::migrations()
+    }
+}
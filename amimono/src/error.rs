@@ -1,4 +1,4 @@
-use std::fmt;
+use std::{fmt, time::Duration};
 
 use axum::response::IntoResponse;
 use serde::{Deserialize, Serialize};
@@ -64,6 +64,59 @@ pub enum AppError {
     /// when an error crosses a component boundary within Amimono, e.g. when
     /// using `RpcClient`, and can be nested several layers deep.
     Downstream(String, Box<AppError>),
+
+    /// The caller isn't allowed to call the component. The caller is `None`
+    /// if it couldn't be identified.
+    Forbidden {
+        caller: Option<String>,
+        component: String,
+    },
+
+    /// The request couldn't be authenticated, e.g. because it had no caller
+    /// token or its token was invalid, and caller tokens are required.
+    Unauthenticated { component: String, reason: String },
+
+    /// The request's deadline passed before the component with the given
+    /// label could finish handling it.
+    DeadlineExceeded(String),
+
+    /// The component doesn't implement the op, e.g. because it's an optional
+    /// op, or because the component is running an older revision that
+    /// doesn't have it.
+    Unimplemented { component: String, op: String },
+
+    /// An attempt at calling an op of the component got no response within
+    /// the client's request timeout. These are retried like spurious errors.
+    RequestTimeout {
+        component: String,
+        op: String,
+        timeout_ms: u64,
+    },
+
+    /// A call to an op of the component didn't finish within the client's
+    /// operation timeout, across all of its attempts.
+    OperationTimeout {
+        component: String,
+        op: String,
+        timeout_ms: u64,
+    },
+
+    /// The caller is over its quota for the component, described by
+    /// `quota`. The caller is `None` if it couldn't be identified.
+    QuotaExceeded {
+        caller: Option<String>,
+        component: String,
+        quota: String,
+    },
+
+    /// The component shed the request because it's overloaded, and asks to
+    /// be called again no sooner than `retry_after_ms` from now. These are
+    /// retried like spurious errors, but after the delay the component asked
+    /// for rather than the client's own.
+    Overloaded {
+        component: String,
+        retry_after_ms: u64,
+    },
 }
 
 impl AppError {
@@ -92,6 +145,23 @@ impl RetryError for AppError {
             AppError::Spurious(_) => true,
             AppError::Misc(_) => false,
             AppError::Downstream(_, e) => e.should_retry(),
+            AppError::Forbidden { .. } => false,
+            AppError::Unauthenticated { .. } => false,
+            AppError::DeadlineExceeded(_) => false,
+            AppError::Unimplemented { .. } => false,
+            AppError::RequestTimeout { .. } => true,
+            AppError::OperationTimeout { .. } => false,
+            AppError::QuotaExceeded { .. } => true,
+            AppError::Overloaded { .. } => true,
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self.root_cause() {
+            AppError::Overloaded { retry_after_ms, .. } => {
+                Some(Duration::from_millis(*retry_after_ms))
+            }
+            _ => None,
         }
     }
 }
@@ -100,11 +170,29 @@ impl std::error::Error for AppError {}
 
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
-        let res = (
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            axum::Json(self),
-        );
-        res.into_response()
+        let status = match self {
+            AppError::Forbidden { .. } => axum::http::StatusCode::FORBIDDEN,
+            AppError::Unauthenticated { .. } => axum::http::StatusCode::UNAUTHORIZED,
+            AppError::DeadlineExceeded(_)
+            | AppError::RequestTimeout { .. }
+            | AppError::OperationTimeout { .. } => axum::http::StatusCode::GATEWAY_TIMEOUT,
+            AppError::Unimplemented { .. } => axum::http::StatusCode::NOT_IMPLEMENTED,
+            AppError::QuotaExceeded { .. } => axum::http::StatusCode::TOO_MANY_REQUESTS,
+            AppError::Overloaded { .. } => axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            _ => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        // the hint is also given in the standard header, in whole seconds,
+        // for callers that aren't amimono clients
+        let retry_after = match &self {
+            AppError::Overloaded { retry_after_ms, .. } => Some(retry_after_ms.div_ceil(1000)),
+            _ => None,
+        };
+        let mut res = (status, axum::Json(self)).into_response();
+        if let Some(secs) = retry_after {
+            res.headers_mut()
+                .insert(axum::http::header::RETRY_AFTER, secs.into());
+        }
+        res
     }
 }
 
@@ -114,6 +202,54 @@ impl fmt::Display for AppError {
             AppError::Spurious(s) => write!(f, "spurious: {s}"),
             AppError::Misc(s) => write!(f, "rpc error: {s}"),
             AppError::Downstream(at, e) => write!(f, "{at}: {e}"),
+            AppError::Forbidden { caller, component } => {
+                let caller = caller.as_deref().unwrap_or("unknown caller");
+                write!(f, "forbidden: {caller} may not call {component}")
+            }
+            AppError::Unauthenticated { component, reason } => {
+                write!(
+                    f,
+                    "unauthenticated: request to {component} refused: {reason}"
+                )
+            }
+            AppError::DeadlineExceeded(at) => write!(f, "deadline exceeded at {at}"),
+            AppError::Unimplemented { component, op } => {
+                write!(f, "unimplemented: {component} has no op {op}")
+            }
+            AppError::RequestTimeout {
+                component,
+                op,
+                timeout_ms,
+            } => write!(
+                f,
+                "request timeout: {component}.{op} did not respond within {timeout_ms}ms"
+            ),
+            AppError::OperationTimeout {
+                component,
+                op,
+                timeout_ms,
+            } => write!(
+                f,
+                "operation timeout: {component}.{op} did not finish within {timeout_ms}ms"
+            ),
+            AppError::QuotaExceeded {
+                caller,
+                component,
+                quota,
+            } => {
+                let caller = caller.as_deref().unwrap_or("unknown caller");
+                write!(
+                    f,
+                    "quota exceeded: {caller} is over its quota of {quota} for {component}"
+                )
+            }
+            AppError::Overloaded {
+                component,
+                retry_after_ms,
+            } => write!(
+                f,
+                "overloaded: {component} asks to be retried after {retry_after_ms}ms"
+            ),
         }
     }
 }
@@ -144,6 +280,10 @@ impl From<reqwest::Error> for AppError {
                 None => "(unknown)".to_owned(),
             };
             AppError::Spurious(format!("http timeout at {origin}"))
+        } else if value.is_connect() {
+            // the request never reached the server, so it's safe to retry,
+            // possibly at another replica
+            AppError::Spurious(format!("http connection error: {value}"))
         } else {
             AppError::Misc(format!("http error: {value}"))
         }
@@ -162,6 +302,13 @@ impl From<std::io::Error> for AppError {
     }
 }
 
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for AppError {
+    fn from(value: rusqlite::Error) -> Self {
+        AppError::Misc(format!("sqlite error: {value}"))
+    }
+}
+
 impl From<tokio::task::JoinError> for AppError {
     fn from(value: tokio::task::JoinError) -> Self {
         match value.try_into_panic() {
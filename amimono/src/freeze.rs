@@ -0,0 +1,303 @@
+//! Frozen discovery, for debugging problems that depend on what discovery
+//! returns.
+//!
+//! `/admin/discovery` dumps what the runtime discovers for every component of
+//! the app right now, as JSON. Running a job with `--freeze-discovery
+//! <file>` loads such a dump and answers every discovery call from it,
+//! never changing it, so that a flaky discovery result can be captured once
+//! and replayed until the problem is understood:
+//!
+//! ```text
+//! curl -s localhost:9099/admin/discovery > discovery.json
+//! app --job calc --freeze-discovery discovery.json
+//! ```
+//!
+//! The file can also be written or edited by hand. Each component lists what
+//! each kind of discovery returns, and only `addr` is required for each
+//! location or replica:
+//!
+//! ```json
+//! {
+//!   "components": {
+//!     "calc": {
+//!       "running": [{ "addr": "10.0.0.1" }],
+//!       "stable": [{ "addr": "10.0.0.1" }],
+//!       "replicas": [{ "addr": "10.0.0.1", "zone": "east", "ports": { "rpc": 9099 } }],
+//!       "all_replicas": [
+//!         { "addr": "10.0.0.1", "zone": "east", "ports": { "rpc": 9099 } },
+//!         { "addr": "10.0.0.2", "ready": false }
+//!       ]
+//!     }
+//!   }
+//! }
+//! ```
+//!
+//! Discovery the dump didn't have, because the runtime couldn't answer it or
+//! the component isn't in the file, fails. Everything else, such as where
+//! this process is, where each ordinal of a component is and storage, is
+//! left to the runtime the job would otherwise use, since runtimes resolve
+//! ordinals their own way rather than from stable discovery. Shard ownership is still asked of replicas, since it isn't
+//! part of discovery.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    component::{Location, Replica},
+    error::Result,
+    runtime::{self, RuntimeProvider},
+};
+
+/// What discovery returned for every component.
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Snapshot {
+    components: BTreeMap<String, ComponentSnapshot>,
+}
+
+/// What each kind of discovery returned for a component, or `None` if it
+/// failed.
+#[derive(Serialize, Deserialize, Default)]
+struct ComponentSnapshot {
+    running: Option<Vec<SnapshotLocation>>,
+    stable: Option<Vec<SnapshotLocation>>,
+    replicas: Option<Vec<SnapshotReplica>>,
+    all_replicas: Option<Vec<SnapshotReplica>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotLocation {
+    addr: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    ephemeral: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotReplica {
+    addr: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    ephemeral: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    zone: Option<String>,
+    #[serde(default = "default_weight")]
+    weight: u32,
+    #[serde(default, skip_serializing_if = "is_false")]
+    draining: bool,
+    #[serde(default = "default_ready")]
+    ready: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    ports: BTreeMap<String, u16>,
+}
+
+fn is_false(x: &bool) -> bool {
+    !*x
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_ready() -> bool {
+    true
+}
+
+impl From<&Location> for SnapshotLocation {
+    fn from(loc: &Location) -> Self {
+        SnapshotLocation {
+            addr: loc.addr::<str>().to_owned(),
+            ephemeral: loc.is_ephemeral(),
+        }
+    }
+}
+
+impl SnapshotLocation {
+    fn location(&self) -> Location {
+        match self.ephemeral {
+            true => Location::emphemeral(self.addr.clone()),
+            false => Location::stable(self.addr.clone()),
+        }
+    }
+}
+
+impl From<&Replica> for SnapshotReplica {
+    fn from(r: &Replica) -> Self {
+        SnapshotReplica {
+            addr: r.location.addr::<str>().to_owned(),
+            ephemeral: r.location.is_ephemeral(),
+            name: r.name.clone(),
+            zone: r.zone.clone(),
+            weight: r.weight,
+            draining: r.draining,
+            ready: r.ready,
+            ports: r.ports.clone(),
+        }
+    }
+}
+
+impl SnapshotReplica {
+    fn replica(&self) -> Replica {
+        let location = SnapshotLocation {
+            addr: self.addr.clone(),
+            ephemeral: self.ephemeral,
+        };
+        Replica {
+            name: self.name.clone(),
+            zone: self.zone.clone(),
+            weight: self.weight,
+            draining: self.draining,
+            ready: self.ready,
+            ports: self.ports.clone(),
+            ..Replica::at(location.location())
+        }
+    }
+}
+
+/// What the runtime discovers for every component of the app right now.
+pub(crate) async fn snapshot() -> Snapshot {
+    let provider = runtime::provider();
+    let mut snapshot = Snapshot::default();
+    for job in runtime::config().jobs() {
+        for comp in job.components() {
+            let label = comp.label.as_str();
+            let locations = |locs: Result<Vec<Location>>| {
+                locs.ok()
+                    .map(|locs| locs.iter().map(SnapshotLocation::from).collect())
+            };
+            let replicas = |replicas: Result<Vec<Replica>>| {
+                replicas
+                    .ok()
+                    .map(|replicas| replicas.iter().map(SnapshotReplica::from).collect())
+            };
+            let comp = ComponentSnapshot {
+                running: locations(provider.discover_running(label).await),
+                stable: locations(provider.discover_stable(label).await),
+                replicas: replicas(provider.discover_replicas(label).await),
+                all_replicas: replicas(provider.discover_all_replicas(label).await),
+            };
+            snapshot.components.insert(label.to_owned(), comp);
+        }
+    }
+    snapshot
+}
+
+/// A runtime whose discovery is answered from a snapshot, and which leaves
+/// everything else to the runtime it wraps.
+pub(crate) struct FrozenRuntime {
+    inner: Box<dyn RuntimeProvider>,
+    path: PathBuf,
+    components: HashMap<String, ComponentSnapshot>,
+}
+
+impl FrozenRuntime {
+    pub(crate) fn open(path: &Path, inner: Box<dyn RuntimeProvider>) -> Result<FrozenRuntime> {
+        let bytes =
+            std::fs::read(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let snapshot = serde_json::from_slice::<Snapshot>(&bytes)
+            .map_err(|e| format!("could not parse {}: {}", path.display(), e))?;
+        log::warn!(
+            "discovery is frozen to {}, with {} components",
+            path.display(),
+            snapshot.components.len()
+        );
+        Ok(FrozenRuntime {
+            inner,
+            path: path.to_owned(),
+            components: snapshot.components.into_iter().collect(),
+        })
+    }
+
+    fn lookup<'s, T>(
+        &'s self,
+        component: &str,
+        kind: &str,
+        get: impl FnOnce(&'s ComponentSnapshot) -> Option<&'s T>,
+    ) -> Result<&'s T> {
+        self.components.get(component).and_then(get).ok_or_else(|| {
+            format!(
+                "no {kind} discovery of {component} in frozen discovery {}",
+                self.path.display()
+            )
+            .into()
+        })
+    }
+}
+
+impl RuntimeProvider for FrozenRuntime {
+    fn discover_running<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+    ) -> BoxFuture<'f, Result<Vec<Location>>> {
+        Box::pin(async move {
+            let locs = self.lookup(component, "running", |c| c.running.as_ref())?;
+            Ok(locs.iter().map(SnapshotLocation::location).collect())
+        })
+    }
+
+    fn discover_stable<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+    ) -> BoxFuture<'f, Result<Vec<Location>>> {
+        Box::pin(async move {
+            let locs = self.lookup(component, "stable", |c| c.stable.as_ref())?;
+            Ok(locs.iter().map(SnapshotLocation::location).collect())
+        })
+    }
+
+    fn discover_replicas<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+    ) -> BoxFuture<'f, Result<Vec<Replica>>> {
+        Box::pin(async move {
+            let replicas = self.lookup(component, "replica", |c| c.replicas.as_ref())?;
+            Ok(replicas.iter().map(SnapshotReplica::replica).collect())
+        })
+    }
+
+    fn discover_all_replicas<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+    ) -> BoxFuture<'f, Result<Vec<Replica>>> {
+        Box::pin(async move {
+            let replicas = self.lookup(component, "all-replica", |c| c.all_replicas.as_ref())?;
+            Ok(replicas.iter().map(SnapshotReplica::replica).collect())
+        })
+    }
+
+    fn ordinal<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+        n: usize,
+    ) -> BoxFuture<'f, Result<Location>> {
+        self.inner.ordinal(component, n)
+    }
+
+    fn myself<'f, 'p: 'f, 'l: 'f>(&'p self, component: &'l str) -> BoxFuture<'f, Result<Location>> {
+        self.inner.myself(component)
+    }
+
+    fn my_ordinal<'f, 'p: 'f, 'l: 'f>(&'p self, job: &'l str) -> BoxFuture<'f, Result<usize>> {
+        self.inner.my_ordinal(job)
+    }
+
+    fn storage<'f, 'p: 'f, 'l: 'f>(&'p self, component: &'l str) -> BoxFuture<'f, Result<PathBuf>> {
+        self.inner.storage(component)
+    }
+
+    fn capabilities(&self) -> runtime::Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn is_dev(&self) -> bool {
+        self.inner.is_dev()
+    }
+
+    fn serves_resolver(&self) -> bool {
+        self.inner.serves_resolver()
+    }
+}
@@ -0,0 +1,291 @@
+//! A built-in HTTP gateway for the app's RPC ops.
+//!
+//! Ops can be exposed to the outside world without writing a wrapper around
+//! their client, by giving them a route with an `#[rpc(http = ...)]`
+//! attribute after their doc comment:
+//!
+//! ```ignore
+//! amimono::rpc_component! {
+//!     pub mod items {
+//!         const LABEL: &'static str = "items";
+//!
+//!         /// Get an item
+//!         #[rpc(http = "GET /v1/items/{id}")]
+//!         fn get(id: String) -> Option<Item>;
+//!
+//!         /// Add an item
+//!         #[rpc(http = "POST /v1/items")]
+//!         fn add(item: Item) -> String;
+//!     }
+//! }
+//! ```
+//!
+//! and installing [`GatewayComponent`] in a job, which serves every routed
+//! op in the app on [`PORT`] and calls the op's component for each request.
+//! An op's arguments are taken by name from the route's path parameters, the
+//! query string, and the fields of a JSON body, in that order. An argument
+//! that isn't found in any of them is the whole body if it's the only one,
+//! as with `add` above, and `null` if it's an `Option`. The response is the
+//! op's return value as JSON, and errors are returned as they are by the RPC
+//! server, with a status for their kind.
+//!
+//! Path parameters and query values are parsed as JSON, so that numbers and
+//! booleans work, except for `String` arguments, which are taken as is.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{MethodFilter, MethodRouter},
+};
+use futures::future::BoxFuture;
+use serde_json::Value;
+
+use crate::{
+    AppError, AppResult,
+    component::{BindingDecl, Component, ComponentKind},
+    context,
+    rpc::{RpcOp, http},
+    runtime,
+};
+
+/// The label of the gateway component.
+pub const LABEL: &str = "amimono-gateway";
+
+/// The port the gateway serves routed ops on.
+pub const PORT: u16 = 8080;
+
+/// The kind of [`GatewayComponent`].
+pub struct GatewayKind;
+
+impl ComponentKind for GatewayKind {
+    type Instance = ();
+
+    const LABEL: &'static str = LABEL;
+
+    fn bindings() -> Vec<BindingDecl> {
+        vec![BindingDecl::gateway("http", PORT)]
+    }
+}
+
+/// The gateway, which serves the app's routed ops over HTTP.
+pub struct GatewayComponent;
+
+impl Component for GatewayComponent {
+    type Kind = GatewayKind;
+
+    async fn main<F>(set_instance: F)
+    where
+        F: FnOnce(()) -> BoxFuture<'static, ()> + Send,
+    {
+        set_instance(()).await;
+
+        let addr = runtime::to_addr(PORT);
+        let listener = match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!("gateway could not listen on {addr:?}: {e}");
+                return;
+            }
+        };
+        log::info!("gateway listening on {addr:?}");
+        if let Err(e) = axum::serve(listener, router()).await {
+            log::error!("gateway failed: {e}");
+        }
+    }
+}
+
+/// Split an op's route into its method and path.
+pub(crate) fn parse_route(route: &str) -> Result<(&str, &str), String> {
+    let Some((method, path)) = route.trim().split_once(' ') else {
+        return Err(format!("route {route:?} should be a method and a path"));
+    };
+    if method_filter(method).is_none() {
+        return Err(format!("route {route:?} has unsupported method {method}"));
+    }
+    let path = path.trim();
+    if !path.starts_with('/') {
+        return Err(format!(
+            "route {route:?} should have a path starting with /"
+        ));
+    }
+    Ok((method, path))
+}
+
+/// The names of the parameters in a route's path, e.g. `id` in
+/// `/v1/items/{id}`.
+pub(crate) fn path_params(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter_map(|seg| seg.strip_prefix('{')?.strip_suffix('}'))
+}
+
+/// A route's path with its parameters' names left out, e.g. `/v1/items/{}`
+/// for `/v1/items/{id}`. The router can't tell paths with the same shape
+/// apart, so they must name their parameters the same.
+pub(crate) fn path_shape(path: &str) -> String {
+    path.split('/')
+        .map(
+            |seg| match seg.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(param) if param.starts_with('*') => "{*}",
+                Some(_) => "{}",
+                None => seg,
+            },
+        )
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn method_filter(method: &str) -> Option<MethodFilter> {
+    match method {
+        "GET" => Some(MethodFilter::GET),
+        "POST" => Some(MethodFilter::POST),
+        "PUT" => Some(MethodFilter::PUT),
+        "PATCH" => Some(MethodFilter::PATCH),
+        "DELETE" => Some(MethodFilter::DELETE),
+        _ => None,
+    }
+}
+
+/// An op served by the gateway.
+struct Route {
+    label: &'static str,
+    op: &'static RpcOp,
+}
+
+/// The routes of every op in the app that has one. Routes are checked when
+/// the app is built.
+fn router() -> axum::Router {
+    let mut paths = BTreeMap::<&str, MethodRouter>::new();
+    for comp in runtime::config().jobs().flat_map(|job| job.components()) {
+        let label: &'static str = String::leak(comp.label.clone());
+        for op in comp.rpc_ops.into_iter().flatten() {
+            let Some((method, path)) = op.http.and_then(|r| parse_route(r).ok()) else {
+                continue;
+            };
+            let filter = method_filter(method).expect("checked by parse_route");
+            let route = Arc::new(Route { label, op });
+            let handler = move |params: Option<Path<HashMap<String, String>>>,
+                                Query(query): Query<HashMap<String, String>>,
+                                body: axum::body::Bytes| {
+                let route = route.clone();
+                async move {
+                    let params = params.map(|Path(p)| p).unwrap_or_default();
+                    handle(&route, params, query, &body).await
+                }
+            };
+            let methods = paths.remove(path).unwrap_or_default();
+            paths.insert(path, methods.on(filter, handler));
+            log::debug!("gateway route {} -> {}.{}", path, label, op.name);
+        }
+    }
+    paths
+        .into_iter()
+        .fold(axum::Router::new(), |router, (path, methods)| {
+            router.route(path, methods)
+        })
+}
+
+async fn handle(
+    route: &Route,
+    params: HashMap<String, String>,
+    query: HashMap<String, String>,
+    body: &[u8],
+) -> axum::response::Response {
+    let q = match request(route.op, params, query, body) {
+        Ok(q) => q,
+        Err(e) => {
+            let body = axum::Json(serde_json::json!({ "error": e }));
+            return (StatusCode::BAD_REQUEST, body).into_response();
+        }
+    };
+    match context::originate(call(route, q)).await {
+        Ok(res) => axum::Json(res).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Build the serialized `Request` for an op from an HTTP request.
+fn request(
+    op: &RpcOp,
+    mut params: HashMap<String, String>,
+    mut query: HashMap<String, String>,
+    body: &[u8],
+) -> Result<Vec<u8>, String> {
+    let mut body = match body.iter().all(u8::is_ascii_whitespace) {
+        true => None,
+        false => Some(
+            serde_json::from_slice::<Value>(body).map_err(|e| format!("invalid JSON body: {e}"))?,
+        ),
+    };
+
+    let mut args = Vec::new();
+    let mut missing = Vec::new();
+    let mut used_fields = false;
+    for (i, (name, ty)) in op.arg_names.iter().zip(op.args).enumerate() {
+        let text = params.remove(*name).or_else(|| query.remove(*name));
+        let value = match text {
+            Some(text) if *ty == "String" => Some(Value::String(text)),
+            Some(text) => Some(serde_json::from_str(&text).unwrap_or(Value::String(text))),
+            None => body
+                .as_mut()
+                .and_then(|b| b.as_object_mut())
+                .and_then(|fields| fields.remove(*name))
+                .inspect(|_| used_fields = true),
+        };
+        match value {
+            Some(value) => args.push(value),
+            None => {
+                missing.push(i);
+                args.push(Value::Null);
+            }
+        }
+    }
+
+    // the only argument not given otherwise is the whole body
+    if let ([i], Some(body), false) = (missing.as_slice(), body, used_fields) {
+        args[*i] = body;
+        missing.clear();
+    }
+    missing.retain(|i| !op.args[*i].starts_with("Option<"));
+    if !missing.is_empty() {
+        let names = missing.iter().map(|i| op.arg_names[*i]).collect::<Vec<_>>();
+        return Err(format!("missing arguments: {}", names.join(", ")));
+    }
+
+    // ops with one argument take it as is, like the `Request` enum's
+    // newtype variants
+    let args = match args.len() {
+        1 => args.pop().expect("one argument"),
+        _ => Value::Array(args),
+    };
+    let q = serde_json::json!({ op.name: args });
+    Ok(serde_json::to_vec(&q).expect("JSON values serialize"))
+}
+
+/// Call an op's component, returning the op's return value.
+async fn call(route: &Route, q: Vec<u8>) -> AppResult<Value> {
+    let label = route.label;
+    let res = async {
+        let replicas = http::balanced_replicas_of(label).await?;
+        let Some(replica) = http::choose_replica(&replicas, None) else {
+            return Err(AppError::misc("discovery endpoints empty"));
+        };
+        let path = format!("/rpc/{label}");
+        http::post_bytes(label, replica.location.addr(), &path, q, None).await
+    };
+    let res = res
+        .await
+        .map_err(|e| AppError::Downstream(label.to_owned(), Box::new(e)))?;
+    // the response is the op's variant of the component's `Response` enum
+    match serde_json::from_slice::<Value>(&res)? {
+        Value::Object(mut res) if res.len() == 1 && res.contains_key(route.op.name) => {
+            Ok(res.remove(route.op.name).expect("checked above"))
+        }
+        res => Ok(res),
+    }
+}
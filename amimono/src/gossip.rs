@@ -0,0 +1,708 @@
+//! A runtime provider that discovers jobs by gossip.
+//!
+//! This is for deployments on bare machines with no shared registry at all,
+//! not even Redis or etcd, e.g. at the edge. It's selected by setting
+//! `AMIMONO_GOSSIP_SEEDS`.
+//!
+//! Every process is a member of a cluster, kept up to date with a SWIM-style
+//! protocol over UDP. A new member introduces itself to the seeds, and from
+//! then on learns about the rest of the cluster through updates piggybacked
+//! on the protocol's messages:
+//!
+//! - Each protocol period, a member pings another member, chosen round-robin.
+//!   If no ack arrives in time, it asks a few other members to ping it on its
+//!   behalf, so that one bad link doesn't get a member suspected.
+//! - A member that still can't be reached is suspected, and if it doesn't
+//!   refute the suspicion before `SUSPECT_TIMEOUT`, it's declared dead. A
+//!   member refutes a suspicion by gossiping itself as alive with a higher
+//!   incarnation number, which takes precedence over older news about it.
+//! - Members periodically exchange their whole view of the cluster with a
+//!   random member, so that views converge even if updates are missed, and
+//!   a member that knows of nobody else goes back to the seeds, so that a
+//!   cluster heals once a partition does.
+//!
+//! A member is discovered for its job while it's alive and ready, and only by
+//! members running the same revision. Suspected members are still returned
+//! by `discover_stable()`, since they're likely to come back.
+//!
+//! The following environment variables are used:
+//!
+//! - `AMIMONO_GOSSIP_SEEDS`: the gossip addresses of a few members to join
+//!   through, separated by commas, e.g. `10.0.0.1:7946,10.0.0.2:7946`. It's
+//!   fine for a member to list itself, so every member can be given the same
+//!   list.
+//! - `AMIMONO_GOSSIP_PORT`: the UDP port to gossip on. Defaults to 7946.
+//! - `AMIMONO_ADVERTISE_ADDR`: the address other members should use to reach
+//!   this one. Defaults to the `--bind` address.
+//!
+//! Gossip isn't authenticated, so the gossip port should only be reachable
+//! from the cluster's own machines.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+use rand::seq::{IndexedRandom, SliceRandom};
+use serde::{Deserialize, Serialize};
+use tokio::{net::UdpSocket, sync::oneshot};
+
+use crate::{component::Location, error::Result, health, metrics, runtime};
+
+/// How often a member probes another member.
+const PROTOCOL_PERIOD: Duration = Duration::from_secs(1);
+
+/// How long a direct probe waits for an ack before asking other members to
+/// probe indirectly.
+const ACK_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// How many members are asked to probe indirectly.
+const INDIRECT_PROBES: usize = 3;
+
+/// How long a suspected member has to refute the suspicion.
+const SUSPECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long dead members are remembered, so that stale gossip about them
+/// doesn't bring them back.
+const DEAD_RETENTION: Duration = Duration::from_secs(60);
+
+/// How often a member exchanges its whole view with a random member.
+const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long starting up waits for the seeds to answer, so that the first
+/// discoveries aren't empty.
+const JOIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The most updates piggybacked on one message.
+const MAX_PIGGYBACK: usize = 8;
+
+/// The largest message a member expects to receive.
+const MAX_MESSAGE: usize = 65507;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+impl MemberState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MemberState::Alive => "alive",
+            MemberState::Suspect => "suspect",
+            MemberState::Dead => "dead",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Member {
+    /// The member's gossip address, which identifies it.
+    id: String,
+    /// The address its components are reached at.
+    addr: String,
+    /// The job it runs, or `None` for tools.
+    job: Option<String>,
+    revision: String,
+    ready: bool,
+    incarnation: u64,
+    state: MemberState,
+}
+
+impl Member {
+    /// Whether news about a member supersedes what's known: newer
+    /// incarnations win, and within an incarnation, worse states win.
+    fn supersedes(&self, other: &Member) -> bool {
+        (self.incarnation, self.state) > (other.incarnation, other.state)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+enum Message {
+    /// Probe the receiver, which replies with an ack.
+    Ping {
+        seq: u64,
+        updates: Vec<Member>,
+    },
+    /// Ask the receiver to probe `target`, and pass its ack on.
+    PingReq {
+        seq: u64,
+        target: String,
+        updates: Vec<Member>,
+    },
+    Ack {
+        seq: u64,
+        updates: Vec<Member>,
+    },
+    /// The sender's whole view, which the receiver answers with its own if
+    /// `reply` is set.
+    Sync {
+        members: Vec<Member>,
+        reply: bool,
+    },
+}
+
+/// A probe waiting for an ack.
+enum Pending {
+    /// One of this member's own probes.
+    Probe(oneshot::Sender<()>),
+    /// A probe on behalf of another member, whose ack is passed on.
+    Forward(SocketAddr, u64),
+}
+
+struct Entry {
+    member: Member,
+    changed: Instant,
+}
+
+struct Cluster {
+    me: String,
+    members: HashMap<String, Entry>,
+    /// The members whose latest news is still being disseminated, with how
+    /// many more times to send it.
+    updates: HashMap<String, u32>,
+    /// The order in which members are probed, refilled and shuffled when
+    /// it runs out.
+    probe_order: Vec<String>,
+    pending: HashMap<u64, (Instant, Pending)>,
+}
+
+impl Cluster {
+    fn me(&self) -> &Member {
+        &self.members[&self.me].member
+    }
+
+    /// How many times news is sent, which grows with the log of the cluster
+    /// size, so that it reaches every member with high probability.
+    fn retransmits(&self) -> u32 {
+        3 * (self.members.len() as u32 + 1).ilog2() + 3
+    }
+
+    fn set(&mut self, member: Member) {
+        let id = member.id.clone();
+        self.members.insert(
+            id.clone(),
+            Entry {
+                member,
+                changed: Instant::now(),
+            },
+        );
+        self.updates.insert(id, self.retransmits());
+    }
+
+    /// Merge news about a member into the view.
+    fn merge(&mut self, news: Member) {
+        if news.id == self.me {
+            // news that this member is suspect or dead is refuted by
+            // gossiping that it's alive, with a newer incarnation
+            let me = self.me();
+            if news.state != MemberState::Alive && news.incarnation >= me.incarnation {
+                let mut me = me.clone();
+                me.incarnation = news.incarnation + 1;
+                log::info!("gossip: refuting suspicion of myself");
+                self.set(me);
+            }
+            return;
+        }
+        let known = self.members.get(&news.id).map(|e| &e.member);
+        let changed = match known {
+            None => news.state != MemberState::Dead,
+            Some(known) => news.supersedes(known),
+        };
+        if !changed {
+            return;
+        }
+        let was = known.map(|m| m.state);
+        if was != Some(news.state) {
+            let job = news.job.as_deref().unwrap_or("tool");
+            log::info!("gossip: {} ({}) is {}", news.id, job, news.state.as_str());
+        }
+        self.set(news);
+    }
+
+    /// The updates to piggyback on an outgoing message. This member's own
+    /// record always goes first, so every message also tells the receiver
+    /// that the sender is alive.
+    fn piggyback(&mut self) -> Vec<Member> {
+        let mut ids = self
+            .updates
+            .iter()
+            .filter(|(id, _)| **id != self.me)
+            .map(|(id, n)| (id.clone(), *n))
+            .collect::<Vec<_>>();
+        // the freshest news has the most sends left
+        ids.sort_by_key(|(_, n)| std::cmp::Reverse(*n));
+        ids.truncate(MAX_PIGGYBACK - 1);
+
+        let mut out = vec![self.me().clone()];
+        for (id, _) in ids {
+            if let Some(e) = self.members.get(&id) {
+                out.push(e.member.clone());
+            }
+            match self.updates.get_mut(&id) {
+                Some(n) if *n > 1 => *n -= 1,
+                _ => {
+                    self.updates.remove(&id);
+                }
+            }
+        }
+        out
+    }
+
+    /// The members that can be probed, i.e. everyone else not known dead.
+    fn peers(&self) -> Vec<String> {
+        self.members
+            .values()
+            .filter(|e| e.member.id != self.me && e.member.state != MemberState::Dead)
+            .map(|e| e.member.id.clone())
+            .collect()
+    }
+
+    fn next_probe_target(&mut self) -> Option<String> {
+        loop {
+            match self.probe_order.pop() {
+                Some(id) => {
+                    let probeable = self
+                        .members
+                        .get(&id)
+                        .is_some_and(|e| e.member.state != MemberState::Dead);
+                    if probeable && id != self.me {
+                        return Some(id);
+                    }
+                }
+                None => {
+                    self.probe_order = self.peers();
+                    if self.probe_order.is_empty() {
+                        return None;
+                    }
+                    self.probe_order.shuffle(&mut rand::rng());
+                }
+            }
+        }
+    }
+
+    fn suspect(&mut self, id: &str) {
+        let Some(e) = self.members.get(id) else {
+            return;
+        };
+        if e.member.state == MemberState::Alive {
+            let mut m = e.member.clone();
+            m.state = MemberState::Suspect;
+            self.merge(m);
+        }
+    }
+
+    /// Declare suspects that haven't refuted in time dead, and forget
+    /// members that have been dead for long enough.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let dead = self
+            .members
+            .values()
+            .filter(|e| e.member.state == MemberState::Suspect)
+            .filter(|e| now - e.changed > SUSPECT_TIMEOUT)
+            .map(|e| e.member.clone())
+            .collect::<Vec<_>>();
+        for mut m in dead {
+            m.state = MemberState::Dead;
+            self.merge(m);
+        }
+        self.members
+            .retain(|_, e| e.member.state != MemberState::Dead || now - e.changed < DEAD_RETENTION);
+        self.pending
+            .retain(|_, (at, _)| now - *at < PROTOCOL_PERIOD * 2);
+
+        for state in [MemberState::Alive, MemberState::Suspect, MemberState::Dead] {
+            let n = self
+                .members
+                .values()
+                .filter(|e| e.member.state == state)
+                .count();
+            metrics::gauge("amimono_gossip_members", &[("state", state.as_str())]).set(n as f64);
+        }
+    }
+}
+
+struct Gossip {
+    socket: UdpSocket,
+    seeds: Vec<String>,
+    cluster: Mutex<Cluster>,
+    seq: AtomicU64,
+}
+
+impl Gossip {
+    fn cluster(&self) -> std::sync::MutexGuard<'_, Cluster> {
+        self.cluster.lock().expect("lock poisoned")
+    }
+
+    async fn send(&self, to: &str, msg: &Message) {
+        let bytes = serde_json::to_vec(msg).expect("message serializes");
+        if let Err(e) = self.socket.send_to(&bytes, to).await {
+            log::debug!("gossip: failed to send to {to}: {e}");
+        }
+    }
+
+    async fn ping(&self, to: &str) -> oneshot::Receiver<()> {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        let updates = {
+            let mut cluster = self.cluster();
+            cluster
+                .pending
+                .insert(seq, (Instant::now(), Pending::Probe(tx)));
+            cluster.piggyback()
+        };
+        self.send(to, &Message::Ping { seq, updates }).await;
+        rx
+    }
+
+    async fn sync(&self, to: &str, reply: bool) {
+        let members = {
+            let cluster = self.cluster();
+            cluster.members.values().map(|e| e.member.clone()).collect()
+        };
+        self.send(to, &Message::Sync { members, reply }).await;
+    }
+
+    /// Probe one member each protocol period, suspecting it if neither it
+    /// nor anyone asked to probe it answers.
+    async fn probe_loop(self: Arc<Self>) {
+        let mut last_sync = Instant::now();
+        loop {
+            let started = Instant::now();
+            self.cluster().expire();
+
+            let target = self.cluster().next_probe_target();
+            let Some(target) = target else {
+                // alone, e.g. the first member up, or cut off from the rest
+                for seed in self.seeds.iter() {
+                    self.sync(seed, true).await;
+                }
+                tokio::time::sleep(PROTOCOL_PERIOD).await;
+                continue;
+            };
+
+            let mut rx = self.ping(&target).await;
+            let acked = match tokio::time::timeout(ACK_TIMEOUT, &mut rx).await {
+                Ok(res) => res.is_ok(),
+                Err(_) => {
+                    let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+                    let (tx, indirect) = oneshot::channel();
+                    let (helpers, updates) = {
+                        let mut cluster = self.cluster();
+                        let peers = cluster
+                            .peers()
+                            .into_iter()
+                            .filter(|id| *id != target)
+                            .collect::<Vec<_>>();
+                        let helpers = peers
+                            .choose_multiple(&mut rand::rng(), INDIRECT_PROBES)
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        cluster
+                            .pending
+                            .insert(seq, (Instant::now(), Pending::Probe(tx)));
+                        (helpers, cluster.piggyback())
+                    };
+                    for helper in helpers.iter() {
+                        let msg = Message::PingReq {
+                            seq,
+                            target: target.clone(),
+                            updates: updates.clone(),
+                        };
+                        self.send(helper, &msg).await;
+                    }
+                    let remaining = PROTOCOL_PERIOD.saturating_sub(started.elapsed());
+                    // a late direct ack counts too
+                    tokio::select! {
+                        res = &mut rx => res.is_ok(),
+                        res = indirect => res.is_ok(),
+                        _ = tokio::time::sleep(remaining) => false,
+                    }
+                }
+            };
+            if !acked {
+                self.cluster().suspect(&target);
+            }
+
+            if last_sync.elapsed() > SYNC_INTERVAL {
+                let peer = self.cluster().peers().choose(&mut rand::rng()).cloned();
+                if let Some(peer) = peer {
+                    self.sync(&peer, true).await;
+                }
+                last_sync = Instant::now();
+            }
+
+            tokio::time::sleep(PROTOCOL_PERIOD.saturating_sub(started.elapsed())).await;
+        }
+    }
+
+    async fn receive_loop(self: Arc<Self>) {
+        let mut buf = vec![0; MAX_MESSAGE];
+        loop {
+            let (n, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(x) => x,
+                Err(e) => {
+                    log::warn!("gossip: failed to receive: {e}");
+                    tokio::time::sleep(PROTOCOL_PERIOD).await;
+                    continue;
+                }
+            };
+            let msg = match serde_json::from_slice::<Message>(&buf[..n]) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    log::debug!("gossip: ignoring bad message from {from}: {e}");
+                    continue;
+                }
+            };
+            self.handle(from, msg).await;
+        }
+    }
+
+    async fn handle(&self, from: SocketAddr, msg: Message) {
+        let from_id = from.to_string();
+        match msg {
+            Message::Ping { seq, updates } => {
+                let updates = {
+                    let mut cluster = self.cluster();
+                    updates.into_iter().for_each(|m| cluster.merge(m));
+                    cluster.piggyback()
+                };
+                self.send(&from_id, &Message::Ack { seq, updates }).await;
+            }
+            Message::PingReq {
+                seq,
+                target,
+                updates,
+            } => {
+                let own_seq = self.seq.fetch_add(1, Ordering::Relaxed);
+                let updates = {
+                    let mut cluster = self.cluster();
+                    updates.into_iter().for_each(|m| cluster.merge(m));
+                    cluster
+                        .pending
+                        .insert(own_seq, (Instant::now(), Pending::Forward(from, seq)));
+                    cluster.piggyback()
+                };
+                let msg = Message::Ping {
+                    seq: own_seq,
+                    updates,
+                };
+                self.send(&target, &msg).await;
+            }
+            Message::Ack { seq, updates } => {
+                let (pending, reply) = {
+                    let mut cluster = self.cluster();
+                    updates.into_iter().for_each(|m| cluster.merge(m));
+                    let pending = cluster.pending.remove(&seq).map(|(_, p)| p);
+                    let reply = match pending {
+                        Some(Pending::Forward(..)) => cluster.piggyback(),
+                        _ => Vec::new(),
+                    };
+                    (pending, reply)
+                };
+                match pending {
+                    Some(Pending::Probe(tx)) => {
+                        let _ = tx.send(());
+                    }
+                    Some(Pending::Forward(to, seq)) => {
+                        let msg = Message::Ack {
+                            seq,
+                            updates: reply,
+                        };
+                        self.send(&to.to_string(), &msg).await;
+                    }
+                    None => (),
+                }
+            }
+            Message::Sync { members, reply } => {
+                {
+                    let mut cluster = self.cluster();
+                    members.into_iter().for_each(|m| cluster.merge(m));
+                }
+                if reply {
+                    self.sync(&from_id, false).await;
+                }
+            }
+        }
+    }
+
+    /// Gossip this member's readiness whenever it changes, so that it's only
+    /// discovered while it's ready.
+    async fn readiness_loop(self: Arc<Self>) {
+        loop {
+            let ready = health::job().is_ready();
+            {
+                let mut cluster = self.cluster();
+                if cluster.me().ready != ready {
+                    let mut me = cluster.me().clone();
+                    me.ready = ready;
+                    me.incarnation += 1;
+                    cluster.set(me);
+                }
+            }
+            tokio::time::sleep(PROTOCOL_PERIOD).await;
+        }
+    }
+}
+
+pub struct GossipRuntime {
+    gossip: Arc<Gossip>,
+    myself: String,
+}
+
+impl GossipRuntime {
+    /// The gossip port to use when none is provided by the environment.
+    pub const DEFAULT_PORT: u16 = 7946;
+
+    pub async fn new(
+        seeds: Vec<String>,
+        port: u16,
+        revision: &str,
+        myself: String,
+        job: Option<String>,
+    ) -> Self {
+        let socket = UdpSocket::bind(("0.0.0.0", port))
+            .await
+            .expect("failed to bind gossip port");
+        let id = format!("{}:{}", myself, port);
+        let me = Member {
+            id: id.clone(),
+            addr: myself.clone(),
+            job,
+            revision: revision.to_owned(),
+            ready: false,
+            incarnation: 0,
+            state: MemberState::Alive,
+        };
+        let mut cluster = Cluster {
+            me: id.clone(),
+            members: HashMap::new(),
+            updates: HashMap::new(),
+            probe_order: Vec::new(),
+            pending: HashMap::new(),
+        };
+        cluster.set(me);
+        let seeds = seeds.into_iter().filter(|s| *s != id).collect::<Vec<_>>();
+        if seeds.is_empty() {
+            log::warn!("no gossip seeds besides myself, waiting for others to join");
+        }
+
+        let gossip = Arc::new(Gossip {
+            socket,
+            seeds,
+            cluster: Mutex::new(cluster),
+            seq: AtomicU64::new(0),
+        });
+        tokio::spawn(gossip.clone().receive_loop());
+        tokio::spawn(gossip.clone().probe_loop());
+        tokio::spawn(gossip.clone().readiness_loop());
+
+        let joining = Instant::now();
+        while !gossip.seeds.is_empty() && gossip.cluster().peers().is_empty() {
+            if joining.elapsed() > JOIN_TIMEOUT {
+                log::warn!("no gossip seeds answered yet, starting anyway");
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        GossipRuntime { gossip, myself }
+    }
+
+    fn location(&self, job: &str, addr: String) -> Location {
+        let stateful = runtime::config()
+            .job(job)
+            .map(|j| j.is_stateful())
+            .unwrap_or(false);
+        match stateful {
+            true => Location::stable(addr),
+            false => Location::emphemeral(addr),
+        }
+    }
+
+    async fn myself_inner(&self, component: &str) -> Result<Location> {
+        let job = runtime::config()
+            .component_job(component)
+            .ok_or("component has no job")?;
+        Ok(self.location(job, self.myself.clone()))
+    }
+
+    async fn discover_inner(&self, component: &str, stable: bool) -> Result<Vec<Location>> {
+        let job = runtime::config()
+            .component_job(component)
+            .ok_or("component has no job")?;
+        let revision = runtime::config().revision();
+        let mut addrs = {
+            let cluster = self.gossip.cluster();
+            cluster
+                .members
+                .values()
+                .map(|e| &e.member)
+                .filter(|m| m.job.as_deref() == Some(job) && m.revision == revision && m.ready)
+                .filter(|m| match m.state {
+                    MemberState::Alive => true,
+                    MemberState::Suspect => stable,
+                    MemberState::Dead => false,
+                })
+                .map(|m| m.addr.clone())
+                .collect::<Vec<_>>()
+        };
+        addrs.sort();
+        addrs.dedup();
+        Ok(addrs
+            .into_iter()
+            .map(|addr| self.location(job, addr))
+            .collect())
+    }
+}
+
+impl runtime::RuntimeProvider for GossipRuntime {
+    fn discover_running<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+    ) -> BoxFuture<'f, Result<Vec<Location>>> {
+        Box::pin(self.discover_inner(component, false))
+    }
+
+    fn discover_stable<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+    ) -> BoxFuture<'f, Result<Vec<Location>>> {
+        Box::pin(self.discover_inner(component, true))
+    }
+
+    fn myself<'f, 'p: 'f, 'l: 'f>(&'p self, component: &'l str) -> BoxFuture<'f, Result<Location>> {
+        Box::pin(self.myself_inner(component))
+    }
+
+    fn storage<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        _component: &'l str,
+    ) -> BoxFuture<'f, Result<PathBuf>> {
+        Box::pin(async { Err("storage() not implemented for gossip runtime")? })
+    }
+
+    fn capabilities(&self) -> runtime::Capabilities {
+        // suspect members are only found by stable discovery
+        runtime::Capabilities {
+            storage: false,
+            myself: true,
+            stable_discovery: true,
+        }
+    }
+}
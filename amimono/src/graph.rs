@@ -0,0 +1,82 @@
+//! The application's dependency graph.
+//!
+//! The static part of the graph, jobs with their components and declared job
+//! dependencies, comes from the `AppConfig`. Calls between components can't be
+//! known ahead of time, so RPC clients record each call as it's made and the
+//! observed edges are served on the `/admin/graph` endpoint.
+
+use std::{
+    collections::BTreeMap,
+    sync::{LazyLock, Mutex},
+};
+
+use amimono_schemas::{DumpEdge, DumpEdgeKind, DumpGraph};
+
+use crate::{cli::Action, component, runtime};
+
+static CALLS: LazyLock<Mutex<BTreeMap<(String, &'static str), u64>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// Record a call to the given component from the current component or tool.
+/// Calls made from outside any component, e.g. from a spawned task, are not
+/// recorded.
+pub(crate) fn record_call(callee: &'static str) {
+    let Some(caller) = caller() else {
+        return;
+    };
+    let mut calls = CALLS.lock().expect("lock poisoned");
+    *calls.entry((caller, callee)).or_default() += 1;
+}
+
+/// The label of the component or tool whose code is currently running, if
+/// any.
+pub(crate) fn caller() -> Option<String> {
+    match (component::current(), &runtime::args().action) {
+        (Some(label), _) => Some(label.to_owned()),
+        (None, Action::Tool(tool)) => Some(tool.clone()),
+        (None, _) => None,
+    }
+}
+
+/// The call edges observed by this process.
+pub(crate) fn calls() -> Vec<DumpEdge> {
+    let calls = CALLS.lock().expect("lock poisoned");
+    calls
+        .iter()
+        .map(|((from, to), n)| DumpEdge {
+            from: from.clone(),
+            to: to.to_string(),
+            kind: DumpEdgeKind::Call,
+            calls: *n,
+        })
+        .collect()
+}
+
+/// The static dependency graph of the application, along with any calls this
+/// process has observed.
+pub(crate) fn graph() -> DumpGraph {
+    let cf = runtime::config();
+    let mut graph = DumpGraph {
+        jobs: cf
+            .jobs()
+            .map(|j| {
+                let comps = j.components().map(|c| c.label.clone()).collect();
+                (j.label().to_owned(), comps)
+            })
+            .collect(),
+        tools: cf.tools().map(|t| t.label.clone()).collect(),
+        edges: cf
+            .jobs()
+            .flat_map(|j| {
+                j.dependencies().map(|dep| DumpEdge {
+                    from: j.label().to_owned(),
+                    to: dep.to_owned(),
+                    kind: DumpEdgeKind::JobDependency,
+                    calls: 0,
+                })
+            })
+            .collect(),
+    };
+    graph.add_calls(calls());
+    graph
+}
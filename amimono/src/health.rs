@@ -0,0 +1,165 @@
+//! Health reporting for components.
+//!
+//! Components report their own health with [`set`], and the runtime
+//! aggregates the reports of every component in the job into a single job
+//! status. The job status is used for readiness checks and is exposed on the
+//! admin endpoint along with the per-component statuses.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{LazyLock, Mutex, OnceLock},
+};
+
+use serde::Serialize;
+
+use crate::component;
+
+/// The health of a component or job.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", content = "reason", rename_all = "camelCase")]
+pub enum Status {
+    /// The component has not yet provided its instance.
+    Starting,
+
+    /// The component is working normally.
+    Healthy,
+
+    /// The component is working, but with reduced functionality. Degraded
+    /// components are still considered ready.
+    Degraded(String),
+
+    /// The component cannot serve requests. A job with an unhealthy component
+    /// is not ready.
+    Unhealthy(String),
+}
+
+impl Status {
+    /// Create a degraded status
+    pub fn degraded<S: ToString>(reason: S) -> Status {
+        Status::Degraded(reason.to_string())
+    }
+
+    /// Create an unhealthy status
+    pub fn unhealthy<S: ToString>(reason: S) -> Status {
+        Status::Unhealthy(reason.to_string())
+    }
+
+    /// Whether a job or component with this status should receive traffic.
+    pub fn is_ready(&self) -> bool {
+        matches!(self, Status::Healthy | Status::Degraded(_))
+    }
+
+    fn severity(&self) -> usize {
+        match self {
+            Status::Healthy => 0,
+            Status::Degraded(_) => 1,
+            Status::Starting => 2,
+            Status::Unhealthy(_) => 3,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Starting => write!(f, "starting"),
+            Status::Healthy => write!(f, "healthy"),
+            Status::Degraded(s) => write!(f, "degraded: {s}"),
+            Status::Unhealthy(s) => write!(f, "unhealthy: {s}"),
+        }
+    }
+}
+
+static STATUSES: LazyLock<Mutex<BTreeMap<String, Status>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// Why the job is draining, once it is.
+static DRAINING: OnceLock<String> = OnceLock::new();
+
+/// Report the health of the current component.
+///
+/// This must be called from within the component, i.e. from its `main` or
+/// from an RPC handler. In tasks spawned separately, use [`set_for`] instead.
+pub fn set(status: Status) {
+    match component::current() {
+        Some(label) => set_for(label, status),
+        None => log::warn!("health::set() called outside of a component: {status}"),
+    }
+}
+
+/// Report the health of a component by label.
+pub fn set_for(label: &str, status: Status) {
+    let mut statuses = STATUSES.lock().expect("lock poisoned");
+    let prev = statuses.insert(label.to_owned(), status.clone());
+    if prev.as_ref() == Some(&status) {
+        return;
+    }
+    let prev = prev.map(|x| x.to_string());
+    let prev = prev.as_deref().unwrap_or("unknown");
+    match status {
+        Status::Healthy | Status::Starting => log::info!("{label}: {prev} -> {status}"),
+        _ => log::warn!("{label}: {prev} -> {status}"),
+    }
+}
+
+/// Get the most recently reported health of a component running in this
+/// process.
+pub fn get(label: &str) -> Option<Status> {
+    STATUSES.lock().expect("lock poisoned").get(label).cloned()
+}
+
+/// Get the health of every component running in this process.
+pub fn components() -> BTreeMap<String, Status> {
+    STATUSES.lock().expect("lock poisoned").clone()
+}
+
+/// Get the aggregated health of this job, which is the status of its least
+/// healthy component. If multiple components are unhealthy, the reasons are
+/// combined. A job that's draining before it exits is unhealthy, whatever its
+/// components report.
+pub fn job() -> Status {
+    if let Some(reason) = DRAINING.get() {
+        return Status::Unhealthy(format!("draining: {reason}"));
+    }
+    let statuses = STATUSES.lock().expect("lock poisoned");
+    let worst = match statuses.values().max_by_key(|s| s.severity()) {
+        Some(s) => s.severity(),
+        None => return Status::Starting,
+    };
+    let reasons = statuses
+        .iter()
+        .filter(|(_, s)| s.severity() == worst)
+        .filter_map(|(label, s)| match s {
+            Status::Degraded(r) | Status::Unhealthy(r) => Some(format!("{label}: {r}")),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+    match statuses.values().find(|s| s.severity() == worst) {
+        Some(Status::Degraded(_)) => Status::Degraded(reasons),
+        Some(Status::Unhealthy(_)) => Status::Unhealthy(reasons),
+        Some(s) => s.clone(),
+        None => unreachable!(),
+    }
+}
+
+/// Mark the job unready for good, so that callers stop sending it requests
+/// before it exits.
+pub(crate) fn drain(reason: &str) {
+    if DRAINING.set(reason.to_owned()).is_ok() {
+        log::warn!("job draining: {reason}");
+    }
+}
+
+pub(crate) fn register(label: &str) {
+    set_for(label, Status::Starting);
+}
+
+pub(crate) fn started(label: &str) {
+    let mut statuses = STATUSES.lock().expect("lock poisoned");
+    if let Some(status @ Status::Starting) = statuses.get_mut(label) {
+        *status = Status::Healthy;
+        log::info!("{label}: starting -> healthy");
+    }
+}
@@ -10,53 +10,231 @@ use std::{
 use futures::{StreamExt, future::BoxFuture};
 use kube::{
     Api, ResourceExt,
-    api::{ObjectList, WatchEvent},
+    api::{ListParams, ObjectList, WatchEvent, WatchParams},
 };
 use serde::de::DeserializeOwned;
 use tokio::sync::RwLock;
 
-use crate::{component::Location, error::Result, runtime};
+use crate::{
+    component::{Location, Replica},
+    error::Result,
+    runtime, settings,
+};
 
 pub struct K8sRuntime {
-    discovery_cache: Arc<K8sWatcher<DiscoveryCache>>,
+    namespace: String,
+    pod: Option<PodIdentity>,
+    /// `None` in mesh mode, where pods aren't watched.
+    discovery_cache: Option<Arc<K8sWatcher<DiscoveryCache>>>,
+    /// `None` unless a settings ConfigMap is named by the environment. Only
+    /// held so that the watcher keeps running.
+    _settings_cache: Option<Arc<K8sWatcher<SettingsCache>>>,
+}
+
+/// The identity of the current pod, provided through the downward API by
+/// environment variables that `ammn deploy` adds to the pod template.
+struct PodIdentity {
+    name: String,
+    ip: String,
+}
+
+impl PodIdentity {
+    fn from_env() -> Option<PodIdentity> {
+        let name = std::env::var("AMIMONO_POD_NAME").ok()?;
+        let ip = std::env::var("AMIMONO_POD_IP").ok()?;
+        Some(PodIdentity { name, ip })
+    }
+}
+
+/// The name of the headless service that gives the pods of a stateful job
+/// their stable DNS names. This must match what `ammn deploy` generates.
+fn headless_service(job: &str) -> String {
+    format!("{job}-headless")
 }
 
 impl K8sRuntime {
-    pub async fn new(namespace: String, config: kube::config::Config) -> Self {
+    /// The namespace to use when none is provided by the environment.
+    pub const DEFAULT_NAMESPACE: &'static str = "default";
+
+    /// Create the runtime. In mesh mode, for clusters where a service mesh
+    /// such as Istio or Linkerd routes and balances traffic, each component
+    /// is discovered as its job's service, `{job}.{namespace}`, and pods
+    /// aren't watched. Replicas of stateful jobs can still be called at
+    /// their stable names.
+    ///
+    /// If `AMIMONO_SETTINGS_CONFIGMAP` names a ConfigMap, it's watched for
+    /// the app's [settings][crate::settings].
+    pub async fn new(namespace: String, config: kube::config::Config, mesh: bool) -> Self {
         let client = kube::Client::try_from(config).expect("failed to create Kubernetes client");
+        let discovery_cache = match mesh {
+            true => None,
+            false => {
+                let cache = K8sWatcher::new(
+                    Api::namespaced(client.clone(), &namespace),
+                    None,
+                    DiscoveryCache::new(),
+                )
+                .await;
+                cache.start();
+                Some(cache)
+            }
+        };
 
-        let discovery_cache = K8sWatcher::new(
-            Api::namespaced(client.clone(), &namespace),
-            DiscoveryCache::new(),
-        )
-        .await;
-        discovery_cache.start();
+        let settings_cache = match std::env::var("AMIMONO_SETTINGS_CONFIGMAP") {
+            Ok(name) => {
+                log::debug!("watching ConfigMap {name} for settings");
+                let cache = K8sWatcher::new(
+                    Api::namespaced(client.clone(), &namespace),
+                    Some(format!("metadata.name={name}")),
+                    SettingsCache { name },
+                )
+                .await;
+                cache.start();
+                Some(cache)
+            }
+            Err(_) => None,
+        };
+
+        let pod = PodIdentity::from_env();
+        if pod.is_none() {
+            log::warn!("pod identity not provided by environment, myself() will not work");
+        }
 
-        K8sRuntime { discovery_cache }
+        K8sRuntime {
+            namespace,
+            pod,
+            discovery_cache,
+            _settings_cache: settings_cache,
+        }
     }
 
-    async fn discover_inner(&self, component: &str) -> Result<Vec<Location>> {
+    /// Pods of jobs with ordinals have a stable DNS name that follows them
+    /// across restarts, while other pods can only be reached by their IP.
+    fn location(&self, job: &str, pod_name: &str, pod_ip: &str) -> Location {
+        let stateful = runtime::config()
+            .job(job)
+            .map(|j| j.has_ordinals())
+            .unwrap_or(false);
+        match stateful {
+            true => Location::stable(format!(
+                "{}.{}.{}.svc",
+                pod_name,
+                headless_service(job),
+                self.namespace
+            )),
+            false => Location::emphemeral(pod_ip.to_owned()),
+        }
+    }
+
+    async fn myself_inner(&self, component: &str) -> Result<Location> {
         let job = runtime::config()
             .component_job(component)
             .ok_or("component has no job")?;
+        let pod = self
+            .pod
+            .as_ref()
+            .ok_or("pod identity not available, AMIMONO_POD_NAME and AMIMONO_POD_IP must be set")?;
+        Ok(self.location(job, &pod.name, &pod.ip))
+    }
 
-        let cache = self.discovery_cache.read().await;
+    /// Replicas of jobs with ordinals are the pods of a StatefulSet, which
+    /// are named after their ordinal.
+    async fn ordinal_inner(&self, component: &str, n: usize) -> Result<Location> {
+        let job = runtime::config()
+            .component_job(component)
+            .ok_or("component has no job")?;
+        let Some(job_cf) = runtime::config().job(job).filter(|j| j.has_ordinals()) else {
+            Err(format!(
+                "{component} is not in a stateful job, so has no ordinals"
+            ))?
+        };
+        if !job_cf.runs_on(component, n) {
+            Err(format!("{component} does not run on replica {n} of {job}"))?;
+        }
+        let cache = match &self.discovery_cache {
+            Some(cache) => cache.read().await,
+            None => return Ok(self.location(job, &format!("{job}-{n}"), "")),
+        };
+        // blue-green deploys name pods after the revision as well, so match
+        // on the ordinal alone
+        let ordinal = n.to_string();
+        let name = cache
+            .pods_by_job
+            .get(job)
+            .iter()
+            .flat_map(|names| names.iter())
+            .find(|name| name.rsplit_once('-').is_some_and(|(_, o)| o == ordinal))
+            .cloned()
+            .unwrap_or_else(|| format!("{job}-{n}"));
+        Ok(self.location(job, &name, ""))
+    }
 
-        // TODO: correctly choose between Ephemeral and Stable here. I'm just
-        // making changes to get the program to compile.
+    /// The running pods of a component's job, ready or not, leaving out the
+    /// ones whose ordinal doesn't run the component. In mesh mode this is the
+    /// job's service, which only routes to ready pods, or the pods that run
+    /// the component by name if only some do.
+    async fn replicas_inner(&self, component: &str) -> Result<Vec<Replica>> {
+        let job = runtime::config()
+            .component_job(component)
+            .ok_or("component has no job")?;
+        let ordinals = runtime::config()
+            .job(job)
+            .and_then(|j| j.component_replicas(component));
+
+        let cache = match &self.discovery_cache {
+            Some(cache) => cache.read().await,
+            None => {
+                if let Some(ordinals) = ordinals {
+                    let replicas = ordinals
+                        .iter()
+                        .map(|n| Replica::at(self.location(job, &format!("{job}-{n}"), "")))
+                        .collect();
+                    return Ok(replicas);
+                }
+                let service = format!("{}.{}", job, self.namespace);
+                return Ok(vec![Replica::at(Location::stable(service))]);
+            }
+        };
 
-        let locations = cache
+        let replicas = cache
             .pods_by_job
             .get(job)
             .iter()
             .flat_map(|names| names.iter())
-            .filter_map(|name| cache.pods.get(name.as_str()))
-            .map(|pod| pod.ip.as_str())
-            .map(|ip| Location::stable(ip.to_owned()))
+            .filter(|name| {
+                ordinals
+                    .is_none_or(|ordinals| pod_ordinal(name).is_some_and(|n| ordinals.contains(&n)))
+            })
+            .filter_map(|name| Some((name, cache.pods.get(name.as_str())?)))
+            .map(|(name, pod)| Replica {
+                ready: pod.ready,
+                ..Replica::at(self.location(job, name, &pod.ip))
+            })
             .collect::<Vec<_>>();
 
+        Ok(replicas)
+    }
+
+    async fn discover_inner(&self, component: &str) -> Result<Vec<Location>> {
+        let locations = self
+            .ready_inner(component)
+            .await?
+            .into_iter()
+            .map(|r| r.location)
+            .collect();
         Ok(locations)
     }
+
+    async fn ready_inner(&self, component: &str) -> Result<Vec<Replica>> {
+        let mut replicas = self.replicas_inner(component).await?;
+        replicas.retain(|r| r.ready);
+        Ok(replicas)
+    }
+}
+
+/// The ordinal a StatefulSet gives a pod, at the end of its name.
+fn pod_ordinal(name: &str) -> Option<usize> {
+    name.rsplit_once('-')?.1.parse().ok()
 }
 
 impl runtime::RuntimeProvider for K8sRuntime {
@@ -74,11 +252,45 @@ impl runtime::RuntimeProvider for K8sRuntime {
         Box::pin(self.discover_inner(component))
     }
 
-    fn myself<'f, 'p: 'f, 'l: 'f>(
+    fn discover_replicas<'f, 'p: 'f, 'l: 'f>(
         &'p self,
-        _component: &'l str,
+        component: &'l str,
+    ) -> BoxFuture<'f, Result<Vec<Replica>>> {
+        Box::pin(self.ready_inner(component))
+    }
+
+    fn discover_all_replicas<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+    ) -> BoxFuture<'f, Result<Vec<Replica>>> {
+        Box::pin(self.replicas_inner(component))
+    }
+
+    fn ordinal<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+        n: usize,
     ) -> BoxFuture<'f, Result<Location>> {
-        Box::pin(async { Err("myself() not implemented for k8s runtime")? })
+        Box::pin(self.ordinal_inner(component, n))
+    }
+
+    fn myself<'f, 'p: 'f, 'l: 'f>(&'p self, component: &'l str) -> BoxFuture<'f, Result<Location>> {
+        Box::pin(self.myself_inner(component))
+    }
+
+    fn my_ordinal<'f, 'p: 'f, 'l: 'f>(&'p self, job: &'l str) -> BoxFuture<'f, Result<usize>> {
+        Box::pin(async move {
+            if !runtime::config().job(job).is_some_and(|j| j.has_ordinals()) {
+                Err(format!("{job} is not deployed with ordinals"))?;
+            }
+            let pod = self
+                .pod
+                .as_ref()
+                .ok_or("pod identity not available, AMIMONO_POD_NAME must be set")?;
+            let n = pod_ordinal(&pod.name)
+                .ok_or_else(|| format!("pod name {} does not end in an ordinal", pod.name))?;
+            Ok(n)
+        })
     }
 
     fn storage<'f, 'p: 'f, 'l: 'f>(
@@ -87,6 +299,14 @@ impl runtime::RuntimeProvider for K8sRuntime {
     ) -> BoxFuture<'f, Result<PathBuf>> {
         Box::pin(async { Err("storage() not implemented for k8s runtime")? })
     }
+
+    fn capabilities(&self) -> runtime::Capabilities {
+        runtime::Capabilities {
+            storage: false,
+            myself: self.pod.is_some(),
+            stable_discovery: false,
+        }
+    }
 }
 
 trait K8sCache: Send + Sync + 'static {
@@ -104,6 +324,9 @@ struct DiscoveryCache {
 struct DiscoveryCachePod {
     ip: String,
     job: String,
+    /// Whether the pod's `Ready` condition is true. Pods that are running
+    /// but not ready are kept, so they can still be found on request.
+    ready: bool,
 }
 
 enum DiscoveryCacheError {
@@ -159,7 +382,13 @@ impl DiscoveryCache {
             .ok_or(Ignored("pod does not have amimono-rev label"))?
             .clone();
 
-        if job_rev != runtime::config().revision() {
+        // pods of jobs that were left running by a deploy because they
+        // didn't change have an older revision, but the same digest
+        let job_digest = pod_labels.get("amimono-digest");
+        if job_rev != runtime::config().revision()
+            && (job_digest.is_none()
+                || job_digest != runtime::config().job_digest(&job_label).as_ref())
+        {
             return Err(Ignored("pod revision does not match"));
         }
 
@@ -169,9 +398,16 @@ impl DiscoveryCache {
             .ok_or(Ignored("pod has no IP"))?
             .to_owned();
 
+        let ready = status
+            .conditions
+            .iter()
+            .flatten()
+            .any(|c| c.type_ == "Ready" && c.status == "True");
+
         let pod = DiscoveryCachePod {
             ip: pod_ip,
             job: job_label.clone(),
+            ready,
         };
 
         self.pods.insert(pod_name.clone(), pod);
@@ -290,8 +526,46 @@ impl K8sCache for DiscoveryCache {
     }
 }
 
+/// Feeds the data of a ConfigMap to the app's settings. A ConfigMap that
+/// doesn't exist, or is deleted, has no settings.
+struct SettingsCache {
+    name: String,
+}
+
+impl SettingsCache {
+    fn set(&self, cm: Option<k8s_openapi::api::core::v1::ConfigMap>) {
+        let data = cm.and_then(|cm| cm.data).unwrap_or_default();
+        let source = format!("ConfigMap {}", self.name);
+        settings::replace(&source, data);
+    }
+}
+
+impl K8sCache for SettingsCache {
+    type Resource = k8s_openapi::api::core::v1::ConfigMap;
+
+    fn reset(&mut self, list: ObjectList<Self::Resource>) {
+        let cm = list.items.into_iter().find(|cm| cm.name_any() == self.name);
+        self.set(cm);
+    }
+
+    fn update(&mut self, event: WatchEvent<Self::Resource>) {
+        match event {
+            WatchEvent::Added(cm) | WatchEvent::Modified(cm) => self.set(Some(cm)),
+            WatchEvent::Deleted(_) => self.set(None),
+            WatchEvent::Bookmark(o) => {
+                log::debug!("bookmark: {:?} (noop)", o.metadata.resource_version);
+            }
+            WatchEvent::Error(e) => {
+                log::error!("watch error: {:?}", e);
+            }
+        }
+    }
+}
+
 struct K8sWatcher<T: K8sCache> {
     api: Api<T::Resource>,
+    /// A field selector narrowing what's watched, e.g. to one object.
+    fields: Option<String>,
     data: RwLock<K8sWatcherData<T>>,
 }
 
@@ -316,13 +590,14 @@ impl<T: K8sCache> K8sWatcher<T>
 where
     T::Resource: kube::Resource,
 {
-    async fn new(api: Api<T::Resource>, data: T) -> Arc<Self> {
+    async fn new(api: Api<T::Resource>, fields: Option<String>, data: T) -> Arc<Self> {
         let inner = K8sWatcherData {
             resource_version: None,
             data,
         };
         Arc::new(K8sWatcher {
             api,
+            fields,
             data: RwLock::new(inner),
         })
     }
@@ -366,7 +641,11 @@ where
     async fn try_init(&self) -> std::result::Result<(), kube::Error> {
         log::info!("initializing k8s watcher");
 
-        let list = self.api.list(&Default::default()).await?;
+        let mut params = ListParams::default();
+        if let Some(fields) = &self.fields {
+            params = params.fields(fields);
+        }
+        let list = self.api.list(&params).await?;
 
         let resource_version = list
             .metadata
@@ -395,10 +674,11 @@ where
 
             log::debug!("starting k8s watch iteration from {:?}", resource_version);
 
-            let watch = self
-                .api
-                .watch(&Default::default(), &resource_version)
-                .await?;
+            let mut params = WatchParams::default();
+            if let Some(fields) = &self.fields {
+                params = params.fields(fields);
+            }
+            let watch = self.api.watch(&params, resource_version).await?;
             Box::pin(watch)
         };
 
@@ -7,30 +7,87 @@
 //! optional functionality such as the RPC subsystem makes it easy to define
 //! new components that can be used throughout the application.
 
-use amimono_schemas::{DumpComponent, DumpConfig, DumpJob};
-use std::{collections::HashMap, path::PathBuf, process};
+// lets the crate use its own exported macros, which refer to `::amimono`
+extern crate self as amimono;
+
+/// Log an error and panic with it, for when the app can't start.
+macro_rules! fatal {
+    ($($arg:tt)*) => {
+        {
+            let msg = format!($($arg)*);
+            ::log::error!("{}", msg);
+            panic!("{}", msg);
+        }
+    };
+}
+
+pub(crate) use fatal;
+
+use amimono_schemas::{
+    DumpBindingKind, DumpBudget, DumpBuildInfo, DumpComponent, DumpConfig, DumpJob, DumpPlacement,
+    DumpPort, DumpProtocol, DumpRollout, DumpRpcOp, DumpStatefulUpdate,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process,
+};
 
 use crate::{
     component::Location, local::LocalRuntime, runtime::NoopRuntime, r#static::StaticRuntime,
 };
 
+pub mod actor;
+pub mod audit;
+pub mod backfill;
+pub mod cache;
+pub mod channel;
 pub mod component;
 pub mod config;
+pub mod context;
+pub mod emulation;
+pub mod gateway;
+mod gossip;
+pub mod health;
+pub mod metadata;
+pub mod metrics;
+pub mod migration;
+pub mod pipeline;
+#[cfg(feature = "sqlite")]
+pub mod queue;
+pub mod resource;
 pub mod retry;
 pub mod rpc;
 pub mod runtime;
+pub mod schedule;
+pub mod schema;
+pub mod settings;
+pub mod shard;
+pub mod standby;
+pub mod tasks;
+pub mod watchdog;
 
+pub(crate) mod admin;
 pub(crate) mod cli;
 pub(crate) mod error;
+pub(crate) mod freeze;
+pub(crate) mod graph;
 pub(crate) mod k8s;
 pub(crate) mod local;
+#[cfg(feature = "redis")]
+pub(crate) mod redis;
 pub(crate) mod r#static;
+pub(crate) mod storage;
 pub(crate) mod util;
 
 pub use error::{AppError, AppResult, Error, Result};
 
 pub use futures::future::BoxFuture;
 
+/// Re-exported so that RPC types can derive `JsonSchema`, with
+/// `#[schemars(crate = "amimono::schemars")]`, without depending on it.
+pub use schemars;
+
 /// The main Amimono entry point.
 pub fn entry(cf: config::AppConfig) -> ! {
     if let Err(e) = entry_inner(cf) {
@@ -41,27 +98,52 @@ pub fn entry(cf: config::AppConfig) -> ! {
     }
 }
 
-#[tokio::main]
-async fn entry_inner(cf: config::AppConfig) -> Result<()> {
+fn entry_inner(cf: config::AppConfig) -> Result<()> {
     log::debug!("parse command line args");
     let args = cli::parse_args()?;
 
-    log::debug!("initializing runtime provider");
-    let provider = init_runtime_provider(&cf, &args).await;
+    // config dumps don't need the runtime, so that ammn can get them quickly
+    match args.action {
+        cli::Action::DumpConfig => return dump_config(&cf),
+        cli::Action::DumpConfigEmbedded => return dump_config_embedded(&cf),
+        _ => (),
+    }
 
-    log::debug!("initializing runtime");
-    runtime::init(cf, args, provider);
+    log::debug!("building tokio runtime");
+    let (name, tokio_cf) = match &args.action {
+        cli::Action::Job(job) => match cf.job(job) {
+            Some(j) => (job.as_str(), j.runtime().clone()),
+            None => Err(format!("no such job: {}", job))?,
+        },
+        _ => ("amimono", config::TokioConfig::default()),
+    };
+    let rt = tokio_cf
+        .build(name)
+        .map_err(|e| format!("failed to build tokio runtime: {}", e))?;
+
+    rt.block_on(async {
+        log::debug!("initializing runtime provider");
+        let mut provider = init_runtime_provider(&cf, &args).await;
+        if let Some(path) = &args.freeze_discovery {
+            provider = Box::new(freeze::FrozenRuntime::open(Path::new(path), provider)?);
+        }
 
-    log::debug!("starting application");
-    start().await
+        log::debug!("initializing runtime");
+        runtime::init(cf, args, provider);
+
+        log::debug!("starting application");
+        start().await
+    })
 }
 
 async fn init_runtime_provider(
-    _cf: &config::AppConfig,
+    cf: &config::AppConfig,
     args: &cli::Args,
 ) -> Box<dyn runtime::RuntimeProvider> {
     match args.action {
-        cli::Action::DumpConfig => Box::new(NoopRuntime),
+        cli::Action::DumpConfig | cli::Action::DumpConfigEmbedded | cli::Action::DumpGraph(_) => {
+            Box::new(NoopRuntime)
+        }
         cli::Action::Local => {
             let dir = match std::env::var("CARGO_MANIFEST_DIR") {
                 Ok(dir) => dir,
@@ -76,16 +158,23 @@ async fn init_runtime_provider(
             if let Some(s) = &args.r#static {
                 let myself = match &args.bind {
                     Some(x) => Location::stable(x.clone()),
-                    None => {
-                        log::error!("static runtime requires --bind");
-                        panic!();
-                    }
+                    None => crate::fatal!("static runtime requires --bind"),
                 };
                 log::debug!("starting static runtime as {myself:?} in {s}");
                 Box::new(StaticRuntime::open(PathBuf::from(s), myself))
+            } else if let Ok(url) = std::env::var("AMIMONO_REDIS_URL") {
+                init_redis_runtime(cf, args, &url).await
+            } else if let Ok(seeds) = std::env::var("AMIMONO_GOSSIP_SEEDS") {
+                init_gossip_runtime(cf, args, &seeds).await
             } else if let Ok(config) = kube::config::Config::incluster_env() {
                 log::debug!("detected Kubernetes environment");
-                Box::new(k8s::K8sRuntime::new("default".to_owned(), config).await)
+                let namespace = std::env::var("AMIMONO_POD_NAMESPACE")
+                    .unwrap_or_else(|_| k8s::K8sRuntime::DEFAULT_NAMESPACE.to_owned());
+                let mesh = std::env::var("AMIMONO_MESH").is_ok_and(|v| v == "true" || v == "1");
+                if mesh {
+                    log::debug!("mesh mode, discovering job services");
+                }
+                Box::new(k8s::K8sRuntime::new(namespace, config, mesh).await)
             } else if let Ok(dir) = std::env::var("CARGO_MANIFEST_DIR") {
                 log::debug!("detected local development environment");
                 Box::new(LocalRuntime::new(dir))
@@ -97,21 +186,155 @@ async fn init_runtime_provider(
     }
 }
 
+async fn init_gossip_runtime(
+    cf: &config::AppConfig,
+    args: &cli::Args,
+    seeds: &str,
+) -> Box<dyn runtime::RuntimeProvider> {
+    log::debug!("starting gossip runtime");
+    let seeds = seeds
+        .split(',')
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty())
+        .collect();
+    let port = match std::env::var("AMIMONO_GOSSIP_PORT") {
+        Ok(p) => p.parse().expect("invalid AMIMONO_GOSSIP_PORT"),
+        Err(_) => gossip::GossipRuntime::DEFAULT_PORT,
+    };
+    let Some(myself) = std::env::var("AMIMONO_ADVERTISE_ADDR")
+        .ok()
+        .or_else(|| args.bind.clone())
+    else {
+        crate::fatal!("gossip runtime requires AMIMONO_ADVERTISE_ADDR or --bind");
+    };
+    let job = match &args.action {
+        cli::Action::Job(job) => Some(job.clone()),
+        _ => None,
+    };
+    Box::new(gossip::GossipRuntime::new(seeds, port, cf.revision(), myself, job).await)
+}
+
+#[cfg(feature = "redis")]
+async fn init_redis_runtime(
+    cf: &config::AppConfig,
+    args: &cli::Args,
+    url: &str,
+) -> Box<dyn runtime::RuntimeProvider> {
+    log::debug!("starting Redis runtime");
+    let prefix = std::env::var("AMIMONO_REDIS_PREFIX")
+        .unwrap_or_else(|_| redis::RedisRuntime::DEFAULT_PREFIX.to_owned());
+    let myself = std::env::var("AMIMONO_ADVERTISE_ADDR")
+        .ok()
+        .or_else(|| args.bind.clone());
+    let rt = redis::RedisRuntime::new(url, &prefix, cf.revision(), myself).await;
+    if let cli::Action::Job(job) = &args.action {
+        rt.register(job);
+    }
+    Box::new(rt)
+}
+
+#[cfg(not(feature = "redis"))]
+async fn init_redis_runtime(
+    _cf: &config::AppConfig,
+    _args: &cli::Args,
+    _url: &str,
+) -> Box<dyn runtime::RuntimeProvider> {
+    crate::fatal!("AMIMONO_REDIS_URL is set, but amimono was built without the redis feature");
+}
+
 async fn start() -> Result<()> {
     use cli::Action;
 
     match &runtime::args().action {
-        Action::DumpConfig => dump_config(),
+        Action::DumpConfig | Action::DumpConfigEmbedded => {
+            unreachable!("config dumps are handled before the runtime starts")
+        }
+        Action::DumpGraph(format) => dump_graph(*format),
         Action::Local => runtime::launch_local().await,
         Action::Job(job) => runtime::launch_job(job.as_str()).await,
         Action::Tool(tool) => runtime::launch_tool(tool.as_str()).await,
     }
 }
 
-fn dump_config() -> Result<()> {
-    let cf = {
-        let cf = runtime::config();
+fn dump_placement(p: &config::Placement) -> DumpPlacement {
+    match p.clone() {
+        config::Placement::Colocate { job, topology } => DumpPlacement::Colocate { job, topology },
+        config::Placement::AntiColocate { job, topology } => {
+            DumpPlacement::AntiColocate { job, topology }
+        }
+        config::Placement::Spread { topology, max_skew } => {
+            DumpPlacement::Spread { topology, max_skew }
+        }
+    }
+}
+
+/// An op as it's dumped, with its types normalized so that they compare
+/// equal however they were formatted.
+pub(crate) fn dump_rpc_op(op: &rpc::RpcOp) -> DumpRpcOp {
+    let schemas = (op.schemas)();
+    DumpRpcOp {
+        name: op.name.to_owned(),
+        args: op.args.iter().map(|t| normalize_type(t)).collect(),
+        arg_names: op.arg_names.iter().map(|&n| n.to_owned()).collect(),
+        ret: normalize_type(op.ret),
+        optional: op.optional,
+        arg_schemas: schemas.args,
+        ret_schema: schemas.ret,
+    }
+}
+
+fn dump_binding(b: &component::BindingDecl) -> DumpPort {
+    DumpPort {
+        // ports declared with the deprecated `PORTS` have no name
+        name: match b.port.name {
+            "" => DumpPort::unnamed(b.port.number).name,
+            name => name.to_owned(),
+        },
+        port: b.port.number,
+        protocol: match b.port.protocol {
+            component::Protocol::Tcp => DumpProtocol::Tcp,
+            component::Protocol::Udp => DumpProtocol::Udp,
+            component::Protocol::Http => DumpProtocol::Http,
+        },
+        kind: match b.kind {
+            component::BindingKind::Rpc => DumpBindingKind::Rpc,
+            component::BindingKind::Plain => DumpBindingKind::Plain,
+            component::BindingKind::Gateway => DumpBindingKind::Gateway,
+        },
+        external: b.external,
+    }
+}
+
+fn dump_rollout(rollout: &config::Rollout) -> DumpRollout {
+    let budget = |b: Option<config::Budget>| {
+        b.map(|b| match b {
+            config::Budget::Count(n) => DumpBudget::Count(n),
+            config::Budget::Percent(n) => DumpBudget::Percent(n),
+        })
+    };
+    DumpRollout {
+        max_unavailable: budget(rollout.max_unavailable),
+        max_surge: budget(rollout.max_surge),
+        min_available: budget(rollout.min_available),
+        stateful_update: rollout.stateful_update.map(|u| match u {
+            config::StatefulUpdate::RollingUpdate { partition } => {
+                DumpStatefulUpdate::RollingUpdate { partition }
+            }
+            config::StatefulUpdate::OnDelete => DumpStatefulUpdate::OnDelete,
+        }),
+    }
+}
+
+fn dump_build_info(info: runtime::BuildInfo) -> DumpBuildInfo {
+    DumpBuildInfo {
+        git_sha: info.git_sha.map(|s| s.to_owned()),
+        built_at: info.built_at.map(|s| s.to_owned()),
+        rustc_version: info.rustc_version.map(|s| s.to_owned()),
+    }
+}
 
+fn dump_config(cf: &config::AppConfig) -> Result<()> {
+    let cf = {
         let mut jobs = HashMap::new();
 
         for job in cf.jobs() {
@@ -119,22 +342,46 @@ fn dump_config() -> Result<()> {
             for comp in job.components() {
                 let dump_comp = DumpComponent {
                     is_stateful: comp.is_stateful,
-                    ports: comp.ports.clone(),
+                    ports: comp.bindings.iter().map(dump_binding).collect(),
+                    storage: comp.storage.map(|n| n as u64),
+                    storage_hard_limit: comp.storage_hard_limit,
+                    rpc_ops: comp
+                        .rpc_ops
+                        .map(|ops| ops.iter().map(dump_rpc_op).collect()),
+                    replicas: job
+                        .component_replicas(&comp.label)
+                        .map(|ordinals| ordinals.iter().map(|n| *n as u32).collect()),
                 };
                 components.insert(comp.label.clone(), dump_comp);
             }
             jobs.insert(
                 job.label().to_owned(),
                 DumpJob {
-                    is_stateful: job.is_stateful(),
+                    // replicas that run different components need stable
+                    // ordinals, which targets only give stateful jobs
+                    is_stateful: job.has_ordinals(),
+                    runs_once: job.runs_once(),
+                    replicas: job.replicas(),
                     components,
+                    dependencies: job.dependencies().map(|s| s.to_owned()).collect(),
+                    placement: job.placement().iter().map(dump_placement).collect(),
+                    rollout: dump_rollout(job.rollout()),
+                    digest: cf.job_digest(job.label()),
                 },
             );
         }
 
         DumpConfig {
+            schema_version: amimono_schemas::SCHEMA_VERSION,
             revision: cf.revision().to_owned(),
             jobs,
+            tools: cf.tools().map(|t| t.label.clone()).collect(),
+            smoke_tests: cf
+                .tools()
+                .filter(|t| t.is_smoke_test())
+                .map(|t| t.label.clone())
+                .collect(),
+            build: Some(dump_build_info(cf.build_info())),
         }
     };
 
@@ -143,3 +390,56 @@ fn dump_config() -> Result<()> {
     println!("{}", json);
     Ok(())
 }
+
+/// Print the config dump embedded at build time, failing if there isn't one
+/// for this revision.
+fn dump_config_embedded(cf: &config::AppConfig) -> Result<()> {
+    let dump = cf
+        .embedded_config()
+        .ok_or("no config was embedded in this build")?;
+    let embedded: DumpConfig = serde_json::from_str(dump)
+        .map_err(|e| format!("failed to parse embedded config: {}", e))?;
+    if embedded.revision != cf.revision() {
+        Err(format!(
+            "embedded config is for revision {}, not {}",
+            embedded.revision,
+            cf.revision()
+        ))?;
+    }
+    println!("{}", dump);
+    Ok(())
+}
+
+/// `stringify!` puts spaces between tokens, e.g. `Option < String >`. This
+/// removes them where they aren't needed, so that the dumped types are both
+/// readable and stable across formatting changes.
+fn normalize_type(ty: &str) -> String {
+    let mut out = String::new();
+    let mut space = false;
+    for c in ty.chars() {
+        if c.is_whitespace() {
+            space = true;
+            continue;
+        }
+        let prev_ident = out.ends_with(|p: char| p.is_alphanumeric() || p == '_');
+        if space && prev_ident && (c.is_alphanumeric() || c == '_' || c == '\'') {
+            out.push(' ');
+        }
+        space = false;
+        out.push(c);
+    }
+    out
+}
+
+fn dump_graph(format: cli::GraphFormat) -> Result<()> {
+    let graph = graph::graph();
+    match format {
+        cli::GraphFormat::Dot => print!("{}", graph.to_dot()),
+        cli::GraphFormat::Json => {
+            let json = serde_json::to_string_pretty(&graph)
+                .map_err(|e| format!("failed to serialize graph to JSON: {}", e))?;
+            println!("{}", json);
+        }
+    }
+    Ok(())
+}
@@ -51,4 +51,20 @@ impl runtime::RuntimeProvider for LocalRuntime {
             Ok(dir)
         })
     }
+
+    fn is_dev(&self) -> bool {
+        true
+    }
+
+    fn serves_resolver(&self) -> bool {
+        true
+    }
+
+    fn capabilities(&self) -> runtime::Capabilities {
+        runtime::Capabilities {
+            storage: true,
+            myself: true,
+            stable_discovery: true,
+        }
+    }
 }
@@ -0,0 +1,266 @@
+//! A built-in metadata component, for small values shared by the whole app.
+//!
+//! Feature flags, leader notes, config overrides and the like don't warrant a
+//! database, but need to outlive any one replica. The app installs
+//! [`MetadataComponent`] in a job of its own, and components use it through a
+//! typed [`Metadata`] for each key:
+//!
+//! ```ignore
+//! // in the app's config
+//! JobBuilder::new()
+//!     .with_label("metadata")
+//!     .install(amimono::metadata::MetadataComponent::installer)
+//!
+//! // anywhere else
+//! let flags = Metadata::<Flags>::new("flags");
+//! flags.set(&Flags { dark_mode: true }).await?;
+//!
+//! let mut watch = flags.watch();
+//! loop {
+//!     let flags = watch.changed().await?;
+//!     log::info!("flags are now {flags:?}");
+//! }
+//! ```
+//!
+//! The component is stateful and runs as a single replica, so every caller
+//! sees the same values, and keeps them in one JSON file in its storage,
+//! which is rewritten on every change. It's meant for a handful of small,
+//! rarely changing values, not as a general purpose store. In runtimes
+//! without storage, values are only kept in memory, and are lost when the
+//! replica restarts.
+//!
+//! Values are stored as JSON, so a key should always be used with the same
+//! type.
+
+use std::{
+    collections::BTreeMap,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{
+    AppError, AppResult,
+    component::Component,
+    rpc::{RpcComponentKind, RpcResult},
+    runtime,
+};
+
+crate::rpc_component! {
+    // the generated clients that `Metadata` doesn't use would otherwise be warned about
+    #[allow(dead_code)]
+    mod ops {
+        const LABEL: &'static str = "amimono-metadata";
+        const STORAGE: Option<usize> = Some(0);
+
+        /// Get a key's value, as JSON, and the version it was set at.
+        fn get(key: String) -> Option<(u64, String)>;
+
+        /// Set a key's value, as JSON, or remove it if `None`, returning the
+        /// new version.
+        fn set(key: String, value: Option<String>) -> u64;
+    }
+}
+
+/// The label of the metadata component.
+pub const LABEL: &str = ops::ComponentKind::LABEL;
+
+/// The file the values are kept in, in the component's storage.
+const FILE: &str = "metadata.json";
+
+/// How often a [`MetadataWatch`] checks for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The built-in metadata component. See the [module-level documentation][self].
+pub type MetadataComponent = ops::Component<MetadataHandler>;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    version: u64,
+    value: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Values {
+    /// The version of the last change, to any key.
+    version: u64,
+    entries: BTreeMap<String, Entry>,
+}
+
+/// The handler of [`MetadataComponent`].
+pub struct MetadataHandler {
+    values: Mutex<Values>,
+    /// The file the values are saved to, if the runtime has storage.
+    path: Option<PathBuf>,
+}
+
+impl MetadataHandler {
+    fn load(path: &Path) -> Values {
+        match std::fs::read(path) {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(values) => values,
+                Err(e) => panic!("could not parse metadata in {path:?}: {e}"),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Values::default(),
+            Err(e) => panic!("could not read metadata from {path:?}: {e}"),
+        }
+    }
+
+    /// Save the values, replacing the file so that it's never left half
+    /// written.
+    fn save(&self, values: &Values) -> RpcResult<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let tmp = path.with_extension("json.tmp");
+        let data = serde_json::to_vec(values)?;
+        std::fs::write(&tmp, data)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|e| AppError::misc(format!("could not save metadata: {e}")))
+    }
+}
+
+impl ops::Handler for MetadataHandler {
+    async fn new() -> Self {
+        let path = match runtime::capabilities().storage {
+            true => match MetadataComponent::storage().await {
+                Ok(dir) => Some(dir.join(FILE)),
+                Err(e) => panic!("could not get metadata storage: {e}"),
+            },
+            false => {
+                log::warn!("no storage in this runtime, metadata will only be kept in memory");
+                None
+            }
+        };
+        let values = path.as_deref().map(Self::load).unwrap_or_default();
+        log::info!("loaded {} metadata keys", values.entries.len());
+        MetadataHandler {
+            values: Mutex::new(values),
+            path,
+        }
+    }
+
+    async fn get(&self, key: &String) -> RpcResult<Option<(u64, String)>> {
+        let values = self.values.lock().expect("lock poisoned");
+        Ok(values
+            .entries
+            .get(key)
+            .map(|e| (e.version, e.value.clone())))
+    }
+
+    async fn set(&self, key: &String, value: &Option<String>) -> RpcResult<u64> {
+        let mut values = self.values.lock().expect("lock poisoned");
+        let version = values.version + 1;
+        let prev = match value {
+            Some(value) => values.entries.insert(
+                key.clone(),
+                Entry {
+                    version,
+                    value: value.clone(),
+                },
+            ),
+            None => values.entries.remove(key),
+        };
+        values.version = version;
+        if let Err(e) = self.save(&values) {
+            // keep memory in line with the file
+            values.version = version - 1;
+            match prev {
+                Some(prev) => values.entries.insert(key.clone(), prev),
+                None => values.entries.remove(key),
+            };
+            return Err(e);
+        }
+        Ok(version)
+    }
+}
+
+/// A metadata key holding a value of type `V`.
+pub struct Metadata<V> {
+    key: String,
+    client: ops::Client,
+    _value: PhantomData<fn() -> V>,
+}
+
+impl<V> Clone for Metadata<V> {
+    fn clone(&self) -> Self {
+        Metadata {
+            key: self.key.clone(),
+            client: self.client.clone(),
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> Metadata<V> {
+    /// Use a metadata key.
+    pub fn new(key: &str) -> Metadata<V> {
+        Metadata {
+            key: key.to_owned(),
+            client: ops::Client::new(),
+            _value: PhantomData,
+        }
+    }
+
+    async fn get_versioned(&self) -> AppResult<Option<(u64, V)>> {
+        match self.client.get(self.key.clone()).await? {
+            Some((version, json)) => Ok(Some((version, serde_json::from_str(&json)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the key's value, if it has one.
+    pub async fn get(&self) -> AppResult<Option<V>> {
+        Ok(self.get_versioned().await?.map(|(_, value)| value))
+    }
+
+    /// Set the key's value, replacing any value it already has.
+    pub async fn set(&self, value: &V) -> AppResult<()> {
+        let json = serde_json::to_string(value)?;
+        self.client.set(self.key.clone(), Some(json)).await?;
+        Ok(())
+    }
+
+    /// Remove the key's value.
+    pub async fn remove(&self) -> AppResult<()> {
+        self.client.set(self.key.clone(), None).await?;
+        Ok(())
+    }
+
+    /// Watch the key for changes. The first call to
+    /// [`changed`][MetadataWatch::changed] returns the current value.
+    pub fn watch(&self) -> MetadataWatch<V> {
+        MetadataWatch {
+            key: self.clone(),
+            seen: None,
+        }
+    }
+}
+
+/// Changes to a metadata key's value, from [`Metadata::watch`].
+pub struct MetadataWatch<V> {
+    key: Metadata<V>,
+    /// The version of the value last returned, which is `Some(None)` if the
+    /// key had no value.
+    seen: Option<Option<u64>>,
+}
+
+impl<V: Serialize + DeserializeOwned> MetadataWatch<V> {
+    /// Wait for the key's value to change, returning the new value, or `None`
+    /// if it was removed. The key is checked about once a second, so changes
+    /// in quick succession may only be seen as the last one.
+    pub async fn changed(&mut self) -> AppResult<Option<V>> {
+        loop {
+            let current = self.key.get_versioned().await?;
+            let version = current.as_ref().map(|(v, _)| *v);
+            if self.seen != Some(version) {
+                self.seen = Some(version);
+                return Ok(current.map(|(_, value)| value));
+            }
+            tokio::time::sleep(WATCH_INTERVAL).await;
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! A minimal metrics registry.
+//!
+//! Metrics are identified by a name and a set of label pairs, and are created
+//! on first use. All registered metrics are served in the OpenMetrics text
+//! format on the `/metrics` admin endpoint, and can optionally be pushed to
+//! a collector for jobs that can't be scraped. See [`push`] for details.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+pub mod push;
+
+/// A monotonically increasing count.
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can go up and down.
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, v: f64) {
+        self.0.store(v.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+type Labels = Vec<(String, String)>;
+
+enum Family {
+    Counter(BTreeMap<Labels, Arc<Counter>>),
+    Gauge(BTreeMap<Labels, Arc<Gauge>>),
+}
+
+static REGISTRY: LazyLock<Mutex<BTreeMap<&'static str, Family>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+fn to_labels(labels: &[(&str, &str)]) -> Labels {
+    labels
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+fn format_labels(labels: &Labels) -> String {
+    let mut out = String::new();
+    for (i, (k, v)) in labels.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let v = v.replace('\\', "\\\\").replace('"', "\\\"");
+        write!(out, "{k}=\"{v}\"").unwrap();
+    }
+    out
+}
+
+/// Get or create a counter. Panics if the name is already used by a metric of
+/// a different type.
+pub fn counter(name: &'static str, labels: &[(&str, &str)]) -> Arc<Counter> {
+    let mut reg = REGISTRY.lock().expect("lock poisoned");
+    let family = reg
+        .entry(name)
+        .or_insert_with(|| Family::Counter(BTreeMap::new()));
+    match family {
+        Family::Counter(series) => series.entry(to_labels(labels)).or_default().clone(),
+        _ => panic!("metric {name} is not a counter"),
+    }
+}
+
+/// Get or create a gauge. Panics if the name is already used by a metric of a
+/// different type.
+pub fn gauge(name: &'static str, labels: &[(&str, &str)]) -> Arc<Gauge> {
+    let mut reg = REGISTRY.lock().expect("lock poisoned");
+    let family = reg
+        .entry(name)
+        .or_insert_with(|| Family::Gauge(BTreeMap::new()));
+    match family {
+        Family::Gauge(series) => series.entry(to_labels(labels)).or_default().clone(),
+        _ => panic!("metric {name} is not a gauge"),
+    }
+}
+
+fn write_series(out: &mut String, name: &str, labels: &Labels, value: impl std::fmt::Display) {
+    let labels = format_labels(labels);
+    match labels.is_empty() {
+        true => writeln!(out, "{name} {value}").unwrap(),
+        false => writeln!(out, "{name}{{{labels}}} {value}").unwrap(),
+    }
+}
+
+/// Render all metrics in the OpenMetrics text format.
+pub fn render() -> String {
+    let reg = REGISTRY.lock().expect("lock poisoned");
+    let mut out = String::new();
+    for (name, family) in reg.iter() {
+        match family {
+            Family::Counter(series) => {
+                writeln!(out, "# TYPE {name} counter").unwrap();
+                for (labels, c) in series.iter() {
+                    write_series(&mut out, &format!("{name}_total"), labels, c.get());
+                }
+            }
+            Family::Gauge(series) => {
+                writeln!(out, "# TYPE {name} gauge").unwrap();
+                for (labels, g) in series.iter() {
+                    write_series(&mut out, name, labels, g.get());
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// The value of a single series at the time of a [`snapshot`].
+pub(crate) enum Value {
+    Counter(u64),
+    Gauge(f64),
+}
+
+/// A point-in-time copy of every series, as `(name, labels, value)`.
+pub(crate) fn snapshot() -> Vec<(&'static str, Labels, Value)> {
+    let reg = REGISTRY.lock().expect("lock poisoned");
+    let mut out = Vec::new();
+    for (name, family) in reg.iter() {
+        match family {
+            Family::Counter(series) => {
+                for (labels, c) in series.iter() {
+                    out.push((*name, labels.clone(), Value::Counter(c.get())));
+                }
+            }
+            Family::Gauge(series) => {
+                for (labels, g) in series.iter() {
+                    out.push((*name, labels.clone(), Value::Gauge(g.get())));
+                }
+            }
+        }
+    }
+    out
+}
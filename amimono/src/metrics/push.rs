@@ -0,0 +1,204 @@
+//! Pushing metrics to a collector.
+//!
+//! Metrics are normally scraped from the `/metrics` admin endpoint, but jobs
+//! that can't be reached by a scraper, such as tools or jobs behind NAT, can
+//! push them instead. Pushes use OTLP over HTTP with the JSON encoding, which
+//! the OpenTelemetry collector and most metrics backends accept.
+//!
+//! Pushing is configured with
+//! [`AppBuilder::with_metrics_push`][crate::config::AppBuilder::with_metrics_push],
+//! and can be enabled or adjusted at deploy time with environment variables:
+//!
+//! * `AMIMONO_METRICS_PUSH_URL` sets the endpoint, enabling pushes if they
+//!   weren't configured in the app.
+//! * `AMIMONO_METRICS_PUSH_INTERVAL` sets the push interval in seconds.
+//! * `AMIMONO_METRICS_PUSH_HEADERS` adds headers, as comma-separated
+//!   `key=value` pairs.
+//!
+//! Jobs push periodically for as long as they run. Tools additionally push
+//! once more when they finish, so short-lived tools always report.
+
+use std::{
+    sync::{LazyLock, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{Value as Json, json};
+
+use crate::{
+    config::MetricsPushConfig,
+    metrics::{self, Value},
+    runtime,
+};
+
+struct Pusher {
+    cf: MetricsPushConfig,
+    service: String,
+    start: SystemTime,
+    client: reqwest::Client,
+}
+
+static PUSHER: OnceLock<Option<Pusher>> = OnceLock::new();
+
+static ENV_CONFIG: LazyLock<Option<MetricsPushConfig>> = LazyLock::new(|| {
+    let mut cf = match std::env::var("AMIMONO_METRICS_PUSH_URL") {
+        Ok(url) => MetricsPushConfig::new(url),
+        Err(_) => runtime::config().metrics_push()?.clone(),
+    };
+    if let Ok(secs) = std::env::var("AMIMONO_METRICS_PUSH_INTERVAL") {
+        match secs.parse::<u64>() {
+            Ok(secs) if secs > 0 => cf.interval = Duration::from_secs(secs),
+            _ => log::warn!("ignoring invalid AMIMONO_METRICS_PUSH_INTERVAL={secs:?}"),
+        }
+    }
+    if let Ok(headers) = std::env::var("AMIMONO_METRICS_PUSH_HEADERS") {
+        for pair in headers.split(',').filter(|x| !x.is_empty()) {
+            match pair.split_once('=') {
+                Some((k, v)) => cf.headers.push((k.trim().to_owned(), v.trim().to_owned())),
+                None => log::warn!("ignoring invalid metrics push header {pair:?}"),
+            }
+        }
+    }
+    Some(cf)
+});
+
+/// The push settings, including overrides from the environment, if metrics
+/// are pushed.
+pub(crate) fn config() -> Option<&'static MetricsPushConfig> {
+    ENV_CONFIG.as_ref()
+}
+
+/// Start pushing metrics in the background, if configured. `service` is
+/// reported as the `service.name` resource attribute.
+pub(crate) fn start(service: &str) {
+    let pusher = PUSHER.get_or_init(|| {
+        let cf = ENV_CONFIG.clone()?;
+        log::info!("pushing metrics to {} every {:?}", cf.url, cf.interval);
+        Some(Pusher {
+            cf,
+            service: service.to_owned(),
+            start: SystemTime::now(),
+            client: reqwest::Client::new(),
+        })
+    });
+
+    if let Some(p) = pusher {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(p.cf.interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                p.push().await;
+            }
+        });
+    }
+}
+
+/// Push metrics immediately, if configured.
+pub(crate) async fn flush() {
+    if let Some(Some(p)) = PUSHER.get() {
+        p.push().await;
+    }
+}
+
+pub(crate) fn unix_nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+pub(crate) fn attributes<'a>(pairs: impl IntoIterator<Item = (&'a str, &'a str)>) -> Json {
+    pairs
+        .into_iter()
+        .map(|(k, v)| json!({ "key": k, "value": { "stringValue": v } }))
+        .collect()
+}
+
+/// The OTLP resource for this process, reported as `service`.
+pub(crate) fn resource(service: &str) -> Json {
+    let mut resource = vec![
+        ("service.name", service),
+        ("service.version", runtime::config().revision()),
+    ];
+    let pod = std::env::var("AMIMONO_POD_NAME").ok();
+    if let Some(pod) = &pod {
+        resource.push(("service.instance.id", pod.as_str()));
+    }
+    json!({ "attributes": attributes(resource) })
+}
+
+impl Pusher {
+    fn payload(&self) -> Json {
+        let start = unix_nanos(self.start);
+        let now = unix_nanos(SystemTime::now());
+
+        let mut names: Vec<&'static str> = Vec::new();
+        let mut points: Vec<(bool, Vec<Json>)> = Vec::new();
+        for (name, labels, value) in metrics::snapshot() {
+            if names.last() != Some(&name) {
+                names.push(name);
+                points.push((matches!(value, Value::Counter(_)), Vec::new()));
+            }
+            let attrs = attributes(labels.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+            let point = match value {
+                Value::Counter(n) => json!({
+                    "attributes": attrs,
+                    "startTimeUnixNano": start,
+                    "timeUnixNano": now,
+                    "asInt": n.to_string(),
+                }),
+                Value::Gauge(x) => json!({
+                    "attributes": attrs,
+                    "timeUnixNano": now,
+                    "asDouble": x,
+                }),
+            };
+            points.last_mut().unwrap().1.push(point);
+        }
+
+        let metrics = names
+            .into_iter()
+            .zip(points)
+            .map(|(name, (is_counter, points))| match is_counter {
+                true => json!({
+                    "name": name,
+                    "sum": {
+                        "dataPoints": points,
+                        // cumulative
+                        "aggregationTemporality": 2,
+                        "isMonotonic": true,
+                    },
+                }),
+                false => json!({
+                    "name": name,
+                    "gauge": { "dataPoints": points },
+                }),
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "resourceMetrics": [{
+                "resource": resource(&self.service),
+                "scopeMetrics": [{
+                    "scope": { "name": "amimono" },
+                    "metrics": metrics,
+                }],
+            }],
+        })
+    }
+
+    async fn push(&self) {
+        let mut req = self.client.post(&self.cf.url).json(&self.payload());
+        for (k, v) in self.cf.headers.iter() {
+            req = req.header(k, v);
+        }
+        match req.timeout(Duration::from_secs(10)).send().await {
+            Ok(resp) if resp.status().is_success() => {
+                log::debug!("pushed metrics to {}", self.cf.url);
+            }
+            Ok(resp) => log::warn!("metrics push to {} failed: {}", self.cf.url, resp.status()),
+            Err(e) => log::warn!("metrics push to {} failed: {e}", self.cf.url),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Migrations of stateful components' storage between revisions.
+//!
+//! A component whose on-disk format changes lists its migrations in
+//! [`Component::migrations`][crate::component::Component::migrations], each
+//! one bringing the storage up to a schema version. Before the component's
+//! `main` first runs, the runtime reads the version recorded in its storage
+//! directory, runs the migrations for every later version in order, and
+//! records each version as it's reached. Storage that's never been migrated
+//! is at version 0.
+//!
+//! A component refuses to start if its storage is at a version newer than
+//! any of its migrations, e.g. after rolling back to an older revision, since
+//! it can't know what the newer format looks like.
+//!
+//! ```ignore
+//! struct SplitIndex;
+//!
+//! impl StorageMigration for SplitIndex {
+//!     fn version(&self) -> u32 {
+//!         1
+//!     }
+//!
+//!     fn migrate<'m>(&'m self, dir: &'m Path) -> BoxFuture<'m, Result<()>> {
+//!         Box::pin(async move { split_index(dir).await })
+//!     }
+//! }
+//! ```
+
+use std::path::Path;
+
+use futures::future::BoxFuture;
+
+use crate::{
+    error::{Error, Result},
+    health, runtime,
+};
+
+/// The file in a component's storage directory recording its schema version.
+const VERSION_FILE: &str = ".amimono-storage-version";
+
+/// A change to the on-disk format of a component's storage.
+pub trait StorageMigration: Send + Sync + 'static {
+    /// The schema version the storage is at once the migration has run.
+    /// Versions start at 1, and each of a component's migrations must have a
+    /// higher version than the one before it.
+    fn version(&self) -> u32;
+
+    /// Migrate the storage in `dir` from the previous version. A migration
+    /// that fails leaves the storage at the previous version, and is run
+    /// again the next time the component starts, so it should be safe to
+    /// retry.
+    fn migrate<'m>(&'m self, dir: &'m Path) -> BoxFuture<'m, Result<()>>;
+}
+
+/// Check that a component's migrations are in order, when building the config.
+pub(crate) fn check(label: &str, migrations: &[Box<dyn StorageMigration>]) {
+    let mut prev = 0;
+    for m in migrations {
+        if m.version() <= prev {
+            panic!(
+                "storage migrations for {} are out of order: version {} follows {}",
+                label,
+                m.version(),
+                prev
+            );
+        }
+        prev = m.version();
+    }
+}
+
+fn read_version(dir: &Path) -> Result<u32> {
+    match std::fs::read_to_string(dir.join(VERSION_FILE)) {
+        Ok(s) => s
+            .trim()
+            .parse()
+            .map_err(|_| Error::User(format!("unreadable storage version {:?}", s.trim()))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(Error::User(format!("failed to read storage version: {e}"))),
+    }
+}
+
+/// Record the version with a rename, so a crash never leaves it half
+/// written.
+fn write_version(dir: &Path, version: u32) -> Result<()> {
+    let tmp = dir.join(format!("{VERSION_FILE}.tmp"));
+    std::fs::write(&tmp, format!("{version}\n"))
+        .and_then(|_| std::fs::rename(&tmp, dir.join(VERSION_FILE)))
+        .map_err(|e| Error::User(format!("failed to record storage version: {e}")))
+}
+
+async fn migrate(label: &str, dir: &Path, migrations: &[Box<dyn StorageMigration>]) -> Result<()> {
+    let current = read_version(dir)?;
+    let latest = migrations.last().map(|m| m.version()).unwrap_or(0);
+    if current > latest {
+        return Err(Error::User(format!(
+            "storage is at version {current}, but this revision only knows versions up to {latest}"
+        )));
+    }
+
+    for m in migrations.iter().filter(|m| m.version() > current) {
+        log::info!("{label}: migrating storage to version {}", m.version());
+        health::set_for(
+            label,
+            health::Status::degraded(format!("migrating storage to version {}", m.version())),
+        );
+        m.migrate(dir).await.map_err(|e| {
+            Error::User(format!("migration to version {} failed: {e}", m.version()))
+        })?;
+        write_version(dir, m.version())?;
+    }
+    Ok(())
+}
+
+/// Bring a stateful component's storage up to date before it starts. If the
+/// storage can't be migrated, the component isn't started, and the job fails.
+pub(crate) async fn run(label: &'static str, migrations: Vec<Box<dyn StorageMigration>>) {
+    let dir = match runtime::provider().storage(label).await {
+        Ok(dir) => dir,
+        Err(e) => {
+            if !migrations.is_empty() {
+                log::warn!("{label}: not migrating storage, which is unavailable: {e}");
+            }
+            return;
+        }
+    };
+    match migrate(label, &dir, &migrations).await {
+        Ok(()) => health::register(label),
+        Err(e) => {
+            log::error!("{label}: refusing to start: {e}");
+            health::set_for(label, health::Status::unhealthy(format!("storage: {e}")));
+            panic!("{label}: refusing to start: {e}");
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! Pipelines of components, each consuming a stream of items from the one
+//! before it.
+//!
+//! For topologies like ingest → transform → index, a pipeline is declared on
+//! the app with
+//! [`AppBuilder::with_pipeline`][crate::config::AppBuilder::with_pipeline],
+//! naming its stages in order. Each stage gets the stream from the stage
+//! before it with [`input`], and the stream to the stage after it with
+//! [`output`], by the pipeline's label and its own:
+//!
+//! ```ignore
+//! // in the transform stage
+//! let mut docs = amimono::pipeline::input::<Doc>("indexing", Transform::LABEL);
+//! let tokens = amimono::pipeline::output::<Tokens>("indexing", Transform::LABEL);
+//! loop {
+//!     let doc = docs.recv().await;
+//!     tokens.send(tokenize(doc)).await;
+//! }
+//! ```
+//!
+//! Stages in the same job are connected by a bounded in-process queue,
+//! without serialization. Stages in different jobs are connected by a
+//! long-lived HTTP request to a replica of the later stage, whose body is a
+//! stream of JSON items, one per line. The receiving end only reads the body
+//! as fast as its stage takes items, so either way, a stage that falls
+//! behind makes the stages before it wait. If the connection fails, the
+//! sending end reconnects, possibly to another replica, and items that were
+//! in flight may be lost. Items sent between jobs are sealed like RPC
+//! payloads when the later stage has a payload key; see
+//! [`rpc`][crate::rpc].
+//!
+//! Each hop carries its own type. A stage's input is held by the runtime
+//! while no one is receiving from it, so items that haven't been received
+//! when the stage restarts are kept for it.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use axum::body::Bytes;
+use futures::{Stream, StreamExt, future::BoxFuture};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::sync::mpsc;
+
+use crate::{
+    channel::{Ends, Slots},
+    component, metrics,
+    rpc::{RpcError, RpcResult, http},
+    runtime,
+};
+
+/// How long to wait before reconnecting to the next stage.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The most bytes of items sent to another job in one chunk.
+const MAX_CHUNK: usize = 64 * 1024;
+
+/// Hands an item received from another job, as JSON, to a stage's input,
+/// waiting for room. Fails if the item isn't valid for the input's type.
+type Feed = Arc<dyn Fn(&[u8]) -> serde_json::Result<BoxFuture<'static, ()>> + Send + Sync>;
+
+/// A stage of a pipeline, as the labels of the pipeline and the stage.
+type Key = (String, String);
+
+/// The inputs of the stages running in this process.
+static INPUTS: LazyLock<Slots<Key>> = LazyLock::new(Slots::new);
+
+/// How items from other jobs reach the inputs in `INPUTS`.
+static FEEDS: LazyLock<Mutex<HashMap<Key, Feed>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The connections to stages in other jobs.
+static CONNECTIONS: LazyLock<Mutex<HashMap<Key, mpsc::Sender<Bytes>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The stage after `stage` in a pipeline, and the pipeline's capacity.
+/// Panics if there's no such pipeline or stage, or `stage` is the last one.
+fn next_stage(pipeline: &str, stage: &str) -> (String, usize) {
+    let cf = match runtime::config().pipeline(pipeline) {
+        Some(cf) => cf,
+        None => panic!("no such pipeline: {}", pipeline),
+    };
+    match cf.stages.iter().position(|s| s == stage) {
+        Some(i) if i + 1 < cf.stages.len() => (cf.stages[i + 1].clone(), cf.capacity),
+        Some(_) => panic!("{} is the last stage of pipeline {}", stage, pipeline),
+        None => panic!("pipeline {} has no stage {}", pipeline, stage),
+    }
+}
+
+/// Run `f` with the ends of a stage's input, creating it if necessary.
+/// Panics if the stage isn't running in this process, isn't a stage after
+/// the first, or its input carries a different type.
+fn with_ends<T, R>(pipeline: &str, stage: &str, f: impl FnOnce(&mut Ends<T>) -> R) -> R
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    let cf = match runtime::config().pipeline(pipeline) {
+        Some(cf) => cf,
+        None => panic!("no such pipeline: {}", pipeline),
+    };
+    match cf.stages.iter().position(|s| s == stage) {
+        Some(0) => panic!("{} is the first stage of pipeline {}", stage, pipeline),
+        Some(_) => (),
+        None => panic!("pipeline {} has no stage {}", pipeline, stage),
+    }
+    if !component::is_local(stage) {
+        panic!("input of {} used outside of its job", stage);
+    }
+
+    let key = (pipeline.to_owned(), stage.to_owned());
+    let created = |tx: &mpsc::Sender<T>| {
+        let tx = tx.clone();
+        let feed: Feed = Arc::new(move |line| {
+            let item = serde_json::from_slice::<T>(line)?;
+            let tx = tx.clone();
+            Ok(Box::pin(async move {
+                let _ = tx.send(item).await;
+            }))
+        });
+        let mut feeds = FEEDS.lock().expect("lock poisoned");
+        feeds.insert(key.clone(), feed);
+    };
+    INPUTS.with_ends(
+        key.clone(),
+        cf.capacity,
+        format_args!("input of {} in pipeline {}", stage, pipeline),
+        created,
+        f,
+    )
+}
+
+/// Get the stream of items from the stage before `stage`. A stage has one
+/// input at a time, and this panics if another one hasn't been dropped.
+pub fn input<T>(pipeline: &str, stage: &str) -> Input<T>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    match with_ends(pipeline, stage, |ends: &mut Ends<T>| ends.rx.take()) {
+        Some(rx) => Input {
+            rx: Some(rx),
+            pipeline: pipeline.to_owned(),
+            stage: stage.to_owned(),
+        },
+        None => panic!("{} already has an input for pipeline {}", stage, pipeline),
+    }
+}
+
+/// Get the stream of items to the stage after `stage`. Outputs can be
+/// cloned, and any number of them can be used at once.
+pub fn output<T>(pipeline: &str, stage: &str) -> Output<T>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    let (next, capacity) = next_stage(pipeline, stage);
+    let link = if component::is_local(&next) {
+        Link::Local(with_ends(pipeline, &next, |ends: &mut Ends<T>| {
+            ends.tx.clone()
+        }))
+    } else {
+        let mut conns = CONNECTIONS.lock().expect("lock poisoned");
+        let key = (pipeline.to_owned(), next.clone());
+        let tx = conns
+            .entry(key)
+            .or_insert_with(|| connect(pipeline.to_owned(), next.clone(), capacity));
+        Link::Remote(tx.clone())
+    };
+    Output {
+        link,
+        pipeline: pipeline.to_owned(),
+        next,
+    }
+}
+
+enum Link<T> {
+    Local(mpsc::Sender<T>),
+    Remote(mpsc::Sender<Bytes>),
+}
+
+/// The stream of items to the next stage of a pipeline.
+pub struct Output<T> {
+    link: Link<T>,
+    pipeline: String,
+    next: String,
+}
+
+impl<T> Clone for Output<T> {
+    fn clone(&self) -> Self {
+        let link = match &self.link {
+            Link::Local(tx) => Link::Local(tx.clone()),
+            Link::Remote(tx) => Link::Remote(tx.clone()),
+        };
+        Output {
+            link,
+            pipeline: self.pipeline.clone(),
+            next: self.next.clone(),
+        }
+    }
+}
+
+impl<T: Serialize + Send + 'static> Output<T> {
+    /// Send an item, waiting for room if the next stage is behind. Items
+    /// that can't be serialized for another job are dropped with a warning.
+    pub async fn send(&self, item: T) {
+        // the runtime keeps the receiving ends, so the queues are never
+        // closed
+        match &self.link {
+            Link::Local(tx) => {
+                let _ = tx.send(item).await;
+                self.report(tx.max_capacity() - tx.capacity());
+            }
+            Link::Remote(tx) => {
+                let mut line = match serde_json::to_vec(&item) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        log::warn!(
+                            "dropping item for {} in pipeline {}: {}",
+                            self.next,
+                            self.pipeline,
+                            e
+                        );
+                        return;
+                    }
+                };
+                line.push(b'\n');
+                let _ = tx.send(Bytes::from(line)).await;
+                self.report(tx.max_capacity() - tx.capacity());
+            }
+        }
+    }
+
+    fn report(&self, queued: usize) {
+        let labels = [
+            ("pipeline", self.pipeline.as_str()),
+            ("stage", self.next.as_str()),
+        ];
+        metrics::gauge("amimono_pipeline_queued", &labels).set(queued as f64);
+    }
+}
+
+/// The stream of items from the previous stage of a pipeline.
+pub struct Input<T: DeserializeOwned + Send + 'static> {
+    // only taken when dropped
+    rx: Option<mpsc::Receiver<T>>,
+    pipeline: String,
+    stage: String,
+}
+
+impl<T: DeserializeOwned + Send + 'static> Input<T> {
+    /// Receive the next item, waiting for one if there aren't any.
+    pub async fn recv(&mut self) -> T {
+        let rx = self.rx.as_mut().expect("input already dropped");
+        rx.recv().await.expect("input closed")
+    }
+
+    /// Receive the next item if there is one.
+    pub fn try_recv(&mut self) -> Option<T> {
+        let rx = self.rx.as_mut().expect("input already dropped");
+        rx.try_recv().ok()
+    }
+}
+
+impl<T: DeserializeOwned + Send + 'static> Drop for Input<T> {
+    fn drop(&mut self) {
+        let rx = self.rx.take();
+        with_ends(&self.pipeline, &self.stage, |ends: &mut Ends<T>| {
+            ends.rx = rx
+        });
+    }
+}
+
+/// Start sending items to a stage in another job, returning the queue they
+/// wait in until they're written to the connection.
+fn connect(pipeline: String, stage: String, capacity: usize) -> mpsc::Sender<Bytes> {
+    let (tx, rx) = mpsc::channel::<Bytes>(capacity);
+    let rx = Arc::new(tokio::sync::Mutex::new(rx));
+    tokio::spawn(async move {
+        loop {
+            let body = chunks(rx.clone());
+            match http::http_pipeline_stream(&pipeline, &stage, body).await {
+                // every output was dropped, which the runtime never does
+                Ok(()) => return,
+                Err(e) => {
+                    log::warn!(
+                        "connection to {} in pipeline {} failed, reconnecting: {}",
+                        stage,
+                        pipeline,
+                        e
+                    );
+                    let labels = [("pipeline", pipeline.as_str()), ("stage", stage.as_str())];
+                    metrics::counter("amimono_pipeline_reconnects", &labels).inc();
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    });
+    tx
+}
+
+/// The items waiting to be sent, as chunks of lines. Items that are already
+/// waiting when a chunk is taken are sent together.
+fn chunks(
+    rx: Arc<tokio::sync::Mutex<mpsc::Receiver<Bytes>>>,
+) -> impl Stream<Item = Result<Bytes, Infallible>> + Send + 'static {
+    futures::stream::unfold(rx, |rx| async move {
+        let chunk = {
+            let mut guard = rx.lock().await;
+            let mut chunk = guard.recv().await?.to_vec();
+            while chunk.len() < MAX_CHUNK {
+                match guard.try_recv() {
+                    Ok(line) => chunk.extend_from_slice(&line),
+                    Err(_) => break,
+                }
+            }
+            chunk
+        };
+        Some((Ok(Bytes::from(chunk)), rx))
+    })
+}
+
+/// Hand the items in a stream from another job to a stage's input, as
+/// they're received, returning how many there were. Returns an error right
+/// away if the stage hasn't taken its input yet, so that the sender tries
+/// again later. `open` opens each line if the stream is sealed.
+pub(crate) async fn receive<S, E>(
+    pipeline: &str,
+    stage: &str,
+    body: S,
+    open: impl Fn(&[u8]) -> RpcResult<Vec<u8>>,
+) -> RpcResult<u64>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: std::fmt::Display,
+{
+    let feed = FEEDS
+        .lock()
+        .expect("lock poisoned")
+        .get(&(pipeline.to_owned(), stage.to_owned()))
+        .cloned();
+    let Some(feed) = feed else {
+        return Err(RpcError::spurious(format!(
+            "{stage} is not yet receiving from pipeline {pipeline}"
+        )));
+    };
+
+    let labels = [("pipeline", pipeline), ("stage", stage)];
+    let mut body = std::pin::pin!(body);
+    let mut buf: Vec<u8> = Vec::new();
+    let mut received = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| RpcError::spurious(format!("stream failed: {e}")))?;
+        buf.extend_from_slice(&chunk);
+        while let Some(i) = buf.iter().position(|&b| b == b'\n') {
+            let line = buf.drain(..=i).collect::<Vec<u8>>();
+            let line = &line[..line.len() - 1];
+            if line.is_empty() {
+                continue;
+            }
+            // waiting here stops reading the body, which holds back the
+            // sender
+            match feed(&open(line)?) {
+                Ok(send) => send.await,
+                Err(e) => {
+                    log::warn!("dropping malformed item for {stage} in pipeline {pipeline}: {e}");
+                    metrics::counter("amimono_pipeline_malformed", &labels).inc();
+                    continue;
+                }
+            }
+            received += 1;
+        }
+    }
+    Ok(received)
+}
@@ -0,0 +1,558 @@
+//! A built-in durable queue component, for work that has to outlive the
+//! process that created it.
+//!
+//! Most apps that need a queue need a small, reliable one, not a Kafka
+//! cluster. The app installs [`QueueComponent`] in a job of its own, and
+//! components use it through a typed [`Queue`] for each queue, reading from
+//! it as a [`Consumer`] in a group:
+//!
+//! ```ignore
+//! // in the app's config
+//! JobBuilder::new()
+//!     .with_label("queue")
+//!     .install(amimono::queue::QueueComponent::installer)
+//!
+//! // producing
+//! let emails = Queue::<Email>::new("emails");
+//! emails.enqueue(&Email { to, subject, body }).await?;
+//!
+//! // consuming, in every replica of the sender's job
+//! let senders = emails.consumer("senders");
+//! loop {
+//!     let delivery = senders.next().await?;
+//!     match send(&delivery.value).await {
+//!         Ok(()) => senders.ack(&delivery).await?,
+//!         Err(_) => senders.retry(&delivery, Duration::from_secs(30)).await?,
+//!     };
+//! }
+//! ```
+//!
+//! Messages are kept in a SQLite database in the component's storage, and
+//! delivered at least once to each consumer group: consumers in the same
+//! group, e.g. the replicas of one job, share the messages between them,
+//! while each group gets every message. A delivered message is hidden from
+//! the rest of its group for a visibility timeout, and delivered again once
+//! that runs out unless it was acknowledged, so consumers that crash or hang
+//! don't lose it. Consumers should therefore expect to see a message more
+//! than once, e.g. because its timeout ran out just before it was
+//! acknowledged, or because an enqueue was retried. A group starts with the
+//! oldest message still in the queue when it's first used, and messages are
+//! removed once every group has acknowledged them.
+//!
+//! Each queue is kept by one replica of the component, chosen by hashing the
+//! queue's name, so the component's job is normally given a single replica,
+//! and its replica count shouldn't change while queues hold messages. In
+//! runtimes without storage the component refuses to start, since every
+//! message would be lost when it restarts, unless `AMIMONO_QUEUE_IN_MEMORY`
+//! is `true`, in which case messages are only kept in memory.
+//!
+//! The component reports `amimono_queue_messages` and
+//! `amimono_queue_in_flight` gauges, and counters of messages enqueued,
+//! delivered, redelivered and acknowledged, labeled by queue and group.
+//!
+//! Values are stored as JSON, so a queue should always be used with the same
+//! type. This requires the `sqlite` feature.
+
+use std::{
+    marker::PhantomData,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::sync::Notify;
+
+use crate::{AppResult, component::Component, metrics, rpc::RpcResult, runtime};
+
+crate::rpc_component! {
+    // the generated clients that `Queue` doesn't use would otherwise be warned about
+    #[allow(dead_code)]
+    mod ops {
+        const LABEL: &'static str = "amimono-queue";
+        const STORAGE: Option<usize> = Some(0);
+
+        /// Add a message, as JSON, to the end of a queue, returning its
+        /// sequence number.
+        fn enqueue(queue: String, value: String) -> u64;
+
+        /// Take up to `max` messages for a group, hiding them from the rest
+        /// of the group for `visibility_ms`, and waiting up to `wait_ms` for
+        /// one if there are none. Each is its sequence number, attempt,
+        /// lease and JSON.
+        fn dequeue(queue: String, group: String, max: u32, visibility_ms: u64, wait_ms: u64) -> Vec<(u64, u32, u64, String)>;
+
+        /// Acknowledge a message, returning whether the lease was still held.
+        fn ack(queue: String, group: String, seq: u64, lease: u64) -> bool;
+
+        /// Make a message visible to the group again after `delay_ms`,
+        /// returning whether the lease was still held.
+        fn release(queue: String, group: String, seq: u64, lease: u64, delay_ms: u64) -> bool;
+    }
+}
+
+/// The label of the queue component.
+pub const LABEL: &str = "amimono-queue";
+
+/// The database the queues are kept in, in the component's storage.
+const FILE: &str = "queue.sqlite3";
+
+/// Whether queues may be kept in memory in runtimes without storage.
+static IN_MEMORY: LazyLock<bool> = LazyLock::new(|| {
+    std::env::var("AMIMONO_QUEUE_IN_MEMORY").is_ok_and(|v| v == "true" || v == "1")
+});
+
+/// How long [`Consumer::receive`] waits for a message. This is kept below
+/// the default request timeout.
+const POLL_WAIT: Duration = Duration::from_secs(2);
+
+/// The visibility timeout of consumers that don't set their own.
+const DEFAULT_VISIBILITY: Duration = Duration::from_secs(30);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        queue TEXT NOT NULL,
+        value TEXT NOT NULL,
+        enqueued_ms INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS messages_by_queue ON messages (queue, seq);
+    CREATE TABLE IF NOT EXISTS groups (
+        queue TEXT NOT NULL,
+        name TEXT NOT NULL,
+        next_seq INTEGER NOT NULL,
+        PRIMARY KEY (queue, name)
+    );
+    CREATE TABLE IF NOT EXISTS leases (
+        queue TEXT NOT NULL,
+        grp TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        lease INTEGER NOT NULL,
+        attempt INTEGER NOT NULL,
+        visible_ms INTEGER NOT NULL,
+        PRIMARY KEY (queue, grp, seq)
+    );
+    CREATE INDEX IF NOT EXISTS leases_by_visible ON leases (queue, grp, visible_ms);
+";
+
+/// The built-in queue component. See the [module-level documentation][self].
+pub type QueueComponent = ops::Component<QueueHandler>;
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// The handler of [`QueueComponent`].
+pub struct QueueHandler {
+    db: Arc<Mutex<Connection>>,
+    /// Notified when messages are enqueued or released, to wake waiting
+    /// dequeues.
+    ready: Notify,
+}
+
+impl QueueHandler {
+    async fn open() -> Connection {
+        let conn = match runtime::capabilities().storage {
+            true => match QueueComponent::storage().await {
+                Ok(dir) => Connection::open(dir.join(FILE)),
+                Err(e) => panic!("could not get queue storage: {e}"),
+            },
+            false if *IN_MEMORY => {
+                log::warn!("no storage in this runtime, queues will only be kept in memory");
+                Connection::open_in_memory()
+            }
+            false => crate::fatal!(
+                "no storage in this runtime for queues; set AMIMONO_QUEUE_IN_MEMORY=true \
+                 to keep them in memory, losing them on restart"
+            ),
+        };
+        let conn = conn.unwrap_or_else(|e| panic!("could not open queue database: {e}"));
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .and_then(|_| conn.pragma_update(None, "synchronous", "FULL"))
+            .and_then(|_| conn.execute_batch(SCHEMA))
+            .unwrap_or_else(|e| panic!("could not set up queue database: {e}"));
+        conn
+    }
+
+    /// Run a function against the database on the blocking pool.
+    async fn with_db<T, F>(&self, f: F) -> RpcResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let db = self.db.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            let mut conn = db.lock().expect("lock poisoned");
+            f(&mut conn)
+        })
+        .await?;
+        Ok(res?)
+    }
+
+    /// Take messages for a group without waiting: first the ones whose
+    /// visibility timeout ran out, then new ones.
+    fn take(
+        conn: &mut Connection,
+        queue: &str,
+        group: &str,
+        max: u32,
+        visibility_ms: u64,
+    ) -> rusqlite::Result<Vec<(u64, u32, u64, String)>> {
+        let now = now_ms();
+        let visible = now.saturating_add(visibility_ms as i64);
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR IGNORE INTO groups (queue, name, next_seq)
+             VALUES (?1, ?2, COALESCE((SELECT MIN(seq) FROM messages WHERE queue = ?1), 0))",
+            params![queue, group],
+        )?;
+
+        let mut taken = Vec::new();
+        let expired = {
+            let mut stmt = tx.prepare(
+                "SELECT leases.seq, leases.attempt, messages.value FROM leases
+                 JOIN messages ON messages.seq = leases.seq
+                 WHERE leases.queue = ?1 AND leases.grp = ?2 AND leases.visible_ms <= ?3
+                 ORDER BY leases.seq LIMIT ?4",
+            )?;
+            stmt.query_map(params![queue, group, now, max], |row| {
+                Ok((row.get::<_, u64>(0)?, row.get::<_, u32>(1)?, row.get(2)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(u64, u32, String)>>>()?
+        };
+        for (seq, attempt, value) in expired {
+            let lease = rand::random::<u32>() as u64;
+            tx.execute(
+                "UPDATE leases SET lease = ?4, attempt = ?5, visible_ms = ?6
+                 WHERE queue = ?1 AND grp = ?2 AND seq = ?3",
+                params![queue, group, seq, lease, attempt + 1, visible],
+            )?;
+            taken.push((seq, attempt + 1, lease, value));
+        }
+        let redelivered = taken.len();
+
+        let next: u64 = tx.query_row(
+            "SELECT next_seq FROM groups WHERE queue = ?1 AND name = ?2",
+            params![queue, group],
+            |row| row.get(0),
+        )?;
+        let fresh = {
+            let mut stmt = tx.prepare(
+                "SELECT seq, value FROM messages WHERE queue = ?1 AND seq >= ?2
+                 ORDER BY seq LIMIT ?3",
+            )?;
+            stmt.query_map(params![queue, next, max as usize - taken.len()], |row| {
+                Ok((row.get::<_, u64>(0)?, row.get(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(u64, String)>>>()?
+        };
+        for (seq, value) in fresh {
+            let lease = rand::random::<u32>() as u64;
+            tx.execute(
+                "INSERT INTO leases (queue, grp, seq, lease, attempt, visible_ms)
+                 VALUES (?1, ?2, ?3, ?4, 1, ?5)",
+                params![queue, group, seq, lease, visible],
+            )?;
+            tx.execute(
+                "UPDATE groups SET next_seq = ?3 WHERE queue = ?1 AND name = ?2",
+                params![queue, group, seq + 1],
+            )?;
+            taken.push((seq, 1, lease, value));
+        }
+        tx.commit()?;
+
+        let labels = [("queue", queue), ("group", group)];
+        metrics::counter("amimono_queue_redelivered", &labels).add(redelivered as u64);
+        metrics::counter("amimono_queue_delivered", &labels).add(taken.len() as u64);
+        Self::report(conn, queue, Some(group))?;
+        Ok(taken)
+    }
+
+    /// Remove the messages of a queue that every group is done with.
+    fn collect(conn: &Connection, queue: &str) -> rusqlite::Result<()> {
+        conn.execute(
+            "DELETE FROM messages WHERE queue = ?1
+             AND seq < (SELECT MIN(next_seq) FROM groups WHERE queue = ?1)
+             AND seq NOT IN (SELECT seq FROM leases WHERE queue = ?1)",
+            params![queue],
+        )?;
+        Ok(())
+    }
+
+    /// Update the gauges of a queue, and of one of its groups.
+    fn report(conn: &Connection, queue: &str, group: Option<&str>) -> rusqlite::Result<()> {
+        let messages: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM messages WHERE queue = ?1",
+            params![queue],
+            |row| row.get(0),
+        )?;
+        metrics::gauge("amimono_queue_messages", &[("queue", queue)]).set(messages as f64);
+        if let Some(group) = group {
+            let in_flight: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM leases WHERE queue = ?1 AND grp = ?2",
+                params![queue, group],
+                |row| row.get(0),
+            )?;
+            let labels = [("queue", queue), ("group", group)];
+            metrics::gauge("amimono_queue_in_flight", &labels).set(in_flight as f64);
+        }
+        Ok(())
+    }
+}
+
+impl ops::Handler for QueueHandler {
+    async fn new() -> Self {
+        let conn = Self::open().await;
+        let messages: i64 = conn
+            .query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))
+            .unwrap_or_else(|e| panic!("could not read queue database: {e}"));
+        log::info!("opened queue database with {messages} messages");
+        QueueHandler {
+            db: Arc::new(Mutex::new(conn)),
+            ready: Notify::new(),
+        }
+    }
+
+    async fn enqueue(&self, queue: &String, value: &String) -> RpcResult<u64> {
+        let (queue, value) = (queue.clone(), value.clone());
+        let seq = self
+            .with_db(move |conn| {
+                conn.execute(
+                    "INSERT INTO messages (queue, value, enqueued_ms) VALUES (?1, ?2, ?3)",
+                    params![queue, value, now_ms()],
+                )?;
+                let seq = conn.last_insert_rowid() as u64;
+                metrics::counter("amimono_queue_enqueued", &[("queue", &queue)]).inc();
+                Self::report(conn, &queue, None)?;
+                Ok(seq)
+            })
+            .await?;
+        self.ready.notify_waiters();
+        Ok(seq)
+    }
+
+    async fn dequeue(
+        &self,
+        queue: &String,
+        group: &String,
+        max: &u32,
+        visibility_ms: &u64,
+        wait_ms: &u64,
+    ) -> RpcResult<Vec<(u64, u32, u64, String)>> {
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(*wait_ms);
+        loop {
+            // registered before looking, so that an enqueue in between isn't
+            // missed
+            let ready = self.ready.notified();
+            let (queue, group, max, visibility_ms) =
+                (queue.clone(), group.clone(), *max, *visibility_ms);
+            let taken = self
+                .with_db(move |conn| Self::take(conn, &queue, &group, max, visibility_ms))
+                .await?;
+            if !taken.is_empty() || tokio::time::Instant::now() >= deadline {
+                return Ok(taken);
+            }
+            // messages whose visibility timeout runs out don't notify, so
+            // look again every so often
+            let check = tokio::time::Instant::now() + Duration::from_millis(500);
+            let _ = tokio::time::timeout_at(deadline.min(check), ready).await;
+        }
+    }
+
+    async fn ack(&self, queue: &String, group: &String, seq: &u64, lease: &u64) -> RpcResult<bool> {
+        let (queue, group, seq, lease) = (queue.clone(), group.clone(), *seq, *lease);
+        self.with_db(move |conn| {
+            let held = conn.execute(
+                "DELETE FROM leases WHERE queue = ?1 AND grp = ?2 AND seq = ?3 AND lease = ?4",
+                params![queue, group, seq, lease],
+            )? > 0;
+            if held {
+                let labels = [("queue", queue.as_str()), ("group", group.as_str())];
+                metrics::counter("amimono_queue_acked", &labels).inc();
+                Self::collect(conn, &queue)?;
+                Self::report(conn, &queue, Some(&group))?;
+            }
+            Ok(held)
+        })
+        .await
+    }
+
+    async fn release(
+        &self,
+        queue: &String,
+        group: &String,
+        seq: &u64,
+        lease: &u64,
+        delay_ms: &u64,
+    ) -> RpcResult<bool> {
+        let (queue, group, seq, lease) = (queue.clone(), group.clone(), *seq, *lease);
+        let visible = now_ms().saturating_add(*delay_ms as i64);
+        let held = self
+            .with_db(move |conn| {
+                let held = conn
+                    .query_row(
+                        "UPDATE leases SET visible_ms = ?5
+                         WHERE queue = ?1 AND grp = ?2 AND seq = ?3 AND lease = ?4
+                         RETURNING seq",
+                        params![queue, group, seq, lease, visible],
+                        |_| Ok(()),
+                    )
+                    .optional()?;
+                Ok(held.is_some())
+            })
+            .await?;
+        if held && *delay_ms == 0 {
+            self.ready.notify_waiters();
+        }
+        Ok(held)
+    }
+}
+
+/// A durable queue of values of type `V`.
+pub struct Queue<V> {
+    name: String,
+    client: ops::Client,
+    _value: PhantomData<fn() -> V>,
+}
+
+impl<V> Clone for Queue<V> {
+    fn clone(&self) -> Self {
+        Queue {
+            name: self.name.clone(),
+            client: self.client.clone(),
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> Queue<V> {
+    /// Use a queue.
+    pub fn new(name: &str) -> Queue<V> {
+        Queue {
+            name: name.to_owned(),
+            client: ops::Client::new().with_affinity(name),
+            _value: PhantomData,
+        }
+    }
+
+    /// Add a value to the end of the queue, returning its sequence number.
+    /// A value can be added more than once if the call is retried.
+    pub async fn enqueue(&self, value: &V) -> AppResult<u64> {
+        let json = serde_json::to_string(value)?;
+        self.client.enqueue(self.name.clone(), json).await
+    }
+
+    /// Read from the queue as a member of a consumer group. Every group gets
+    /// every value, while the members of a group share them.
+    pub fn consumer(&self, group: &str) -> Consumer<V> {
+        Consumer {
+            queue: self.clone(),
+            group: group.to_owned(),
+            visibility: DEFAULT_VISIBILITY,
+        }
+    }
+}
+
+/// A member of a consumer group of a [`Queue`].
+pub struct Consumer<V> {
+    queue: Queue<V>,
+    group: String,
+    visibility: Duration,
+}
+
+impl<V> Clone for Consumer<V> {
+    fn clone(&self) -> Self {
+        Consumer {
+            queue: self.queue.clone(),
+            group: self.group.clone(),
+            visibility: self.visibility,
+        }
+    }
+}
+
+/// A value received from a queue, which should be acknowledged with
+/// [`Consumer::ack`] once it's been handled.
+#[derive(Clone, Debug)]
+pub struct Delivery<V> {
+    /// The value.
+    pub value: V,
+
+    /// The value's sequence number in its queue.
+    pub seq: u64,
+
+    /// How many times the value has been delivered to the group, starting
+    /// at 1.
+    pub attempt: u32,
+
+    lease: u64,
+}
+
+impl<V: Serialize + DeserializeOwned> Consumer<V> {
+    /// Hide received values from the rest of the group for `visibility`
+    /// rather than 30 seconds. Values that aren't acknowledged by then are
+    /// delivered again.
+    pub fn with_visibility(self, visibility: Duration) -> Consumer<V> {
+        Consumer { visibility, ..self }
+    }
+
+    /// Receive up to `max` values, waiting a couple of seconds for one if
+    /// there are none. This returns an empty list if none arrive in that
+    /// time.
+    pub async fn receive(&self, max: usize) -> AppResult<Vec<Delivery<V>>> {
+        let taken = self
+            .queue
+            .client
+            .dequeue(
+                self.queue.name.clone(),
+                self.group.clone(),
+                max.min(u32::MAX as usize) as u32,
+                self.visibility.as_millis() as u64,
+                POLL_WAIT.as_millis() as u64,
+            )
+            .await?;
+        let mut deliveries = Vec::with_capacity(taken.len());
+        for (seq, attempt, lease, json) in taken {
+            deliveries.push(Delivery {
+                value: serde_json::from_str(&json)?,
+                seq,
+                attempt,
+                lease,
+            });
+        }
+        Ok(deliveries)
+    }
+
+    /// Wait for the next value.
+    pub async fn next(&self) -> AppResult<Delivery<V>> {
+        loop {
+            if let Some(delivery) = self.receive(1).await?.pop() {
+                return Ok(delivery);
+            }
+        }
+    }
+
+    /// Acknowledge a value, so that it isn't delivered to the group again.
+    /// Returns false if its visibility timeout had already run out, in which
+    /// case it may have been delivered again.
+    pub async fn ack(&self, delivery: &Delivery<V>) -> AppResult<bool> {
+        let (queue, group) = (self.queue.name.clone(), self.group.clone());
+        self.queue
+            .client
+            .ack(queue, group, delivery.seq, delivery.lease)
+            .await
+    }
+
+    /// Give a value back to the group, to be delivered again after `delay`.
+    /// Returns false if its visibility timeout had already run out.
+    pub async fn retry(&self, delivery: &Delivery<V>, delay: Duration) -> AppResult<bool> {
+        let (queue, group) = (self.queue.name.clone(), self.group.clone());
+        let delay_ms = delay.as_millis() as u64;
+        self.queue
+            .client
+            .release(queue, group, delivery.seq, delivery.lease, delay_ms)
+            .await
+    }
+}
@@ -0,0 +1,305 @@
+//! A runtime provider that uses Redis for discovery.
+//!
+//! This is for deployments where jobs can reach a shared Redis but not the
+//! Kubernetes API. It's selected by setting `AMIMONO_REDIS_URL`, and requires
+//! the `redis` feature.
+//!
+//! Each running job registers its address under a key that expires after
+//! `TTL`, and refreshes the key for as long as the job is ready. The keys of
+//! a job's replicas are listed in a set, which discovery reads, skipping and
+//! cleaning up members whose keys have expired. Registrations are announced
+//! on a pub/sub channel so that other processes can drop their cached
+//! discovery results right away instead of waiting for them to expire.
+//!
+//! The following environment variables are used:
+//!
+//! - `AMIMONO_REDIS_URL`: the Redis server, e.g. `redis://redis:6379/0`.
+//! - `AMIMONO_REDIS_PREFIX`: a prefix for every key, so that several apps can
+//!   share a server. Defaults to `amimono`.
+//! - `AMIMONO_ADVERTISE_ADDR`: the address other jobs should use to reach
+//!   this one. Defaults to the `--bind` address.
+//!
+//! Keys include the app revision, so replicas running different revisions
+//! don't discover each other.
+//!
+//! Registrations expire by a relative TTL that only the Redis server's clock
+//! measures, and cached discovery results expire by this process's monotonic
+//! clock, so no timestamps are compared across machines and skew between the
+//! jobs' clocks doesn't affect which replicas are discovered.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ::redis::{AsyncCommands, aio::MultiplexedConnection};
+use futures::{StreamExt, future::BoxFuture};
+
+use crate::{component::Location, error::Result, health, runtime};
+
+/// How long a registration lasts without being refreshed.
+const TTL: Duration = Duration::from_secs(15);
+
+/// How often registrations are refreshed.
+const REFRESH: Duration = Duration::from_secs(5);
+
+/// How long discovery results are cached, if no change is announced.
+const CACHE_TTL: Duration = Duration::from_secs(10);
+
+type DiscoveryCache = HashMap<String, (Instant, Vec<String>)>;
+
+pub struct RedisRuntime {
+    conn: MultiplexedConnection,
+    prefix: String,
+    myself: Option<String>,
+    cache: Arc<Mutex<DiscoveryCache>>,
+}
+
+impl RedisRuntime {
+    /// The key prefix to use when none is provided by the environment.
+    pub const DEFAULT_PREFIX: &'static str = "amimono";
+
+    pub async fn new(url: &str, prefix: &str, revision: &str, myself: Option<String>) -> Self {
+        let client = ::redis::Client::open(url).expect("invalid Redis URL");
+        let conn = client
+            .get_multiplexed_async_connection()
+            .await
+            .expect("failed to connect to Redis");
+
+        if myself.is_none() {
+            log::warn!("no address to advertise, myself() will not work and jobs won't register");
+        }
+
+        let rt = RedisRuntime {
+            conn,
+            prefix: format!("{}:{}", prefix, revision),
+            myself,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        };
+        tokio::spawn(watch_changes(client, rt.channel(), rt.cache.clone()));
+        rt
+    }
+
+    /// Keep this process registered as a replica of the job for as long as
+    /// the process runs, while the job is ready.
+    pub fn register(&self, job: &str) {
+        let Some(addr) = self.myself.clone() else {
+            return;
+        };
+        let conn = self.conn.clone();
+        let set = self.job_key(job);
+        let key = self.replica_key(job, &addr);
+        let channel = self.channel();
+        let job = job.to_owned();
+        tokio::spawn(async move {
+            let mut registered = false;
+            loop {
+                let ready = health::job().is_ready();
+                let res = match ready {
+                    true => refresh(conn.clone(), &set, &key, &addr).await,
+                    false if registered => unregister(conn.clone(), &set, &key, &addr).await,
+                    false => Ok(()),
+                };
+                match res {
+                    Ok(()) => {
+                        if ready != registered {
+                            let what = if ready { "registered" } else { "unregistered" };
+                            log::info!("{what} {addr} for job {job}");
+                            let res = conn.clone().publish::<_, _, ()>(&channel, &job).await;
+                            if let Err(e) = res {
+                                log::warn!("failed to announce change to job {job}: {e}");
+                            }
+                        }
+                        registered = ready;
+                    }
+                    Err(e) => log::warn!("failed to update registration for job {job}: {e}"),
+                }
+                tokio::time::sleep(REFRESH).await;
+            }
+        });
+    }
+
+    fn job_key(&self, job: &str) -> String {
+        format!("{}:job:{}", self.prefix, job)
+    }
+
+    fn replica_key(&self, job: &str, addr: &str) -> String {
+        format!("{}:job:{}:replica:{}", self.prefix, job, addr)
+    }
+
+    fn channel(&self) -> String {
+        format!("{}:changes", self.prefix)
+    }
+
+    fn location(&self, job: &str, addr: String) -> Location {
+        let stateful = runtime::config()
+            .job(job)
+            .map(|j| j.is_stateful())
+            .unwrap_or(false);
+        match stateful {
+            true => Location::stable(addr),
+            false => Location::emphemeral(addr),
+        }
+    }
+
+    async fn myself_inner(&self, component: &str) -> Result<Location> {
+        let job = runtime::config()
+            .component_job(component)
+            .ok_or("component has no job")?;
+        let addr = self
+            .myself
+            .clone()
+            .ok_or("no address to advertise, AMIMONO_ADVERTISE_ADDR or --bind must be set")?;
+        Ok(self.location(job, addr))
+    }
+
+    async fn discover_inner(&self, component: &str) -> Result<Vec<Location>> {
+        let job = runtime::config()
+            .component_job(component)
+            .ok_or("component has no job")?;
+
+        let cached = self.cache.lock().expect("lock poisoned").get(job).cloned();
+        let addrs = match cached {
+            Some((at, addrs)) if at.elapsed() < CACHE_TTL => addrs,
+            _ => {
+                let addrs = self
+                    .read_job(job)
+                    .await
+                    .map_err(|e| format!("could not read replicas from Redis: {e}"))?;
+                self.cache
+                    .lock()
+                    .expect("lock poisoned")
+                    .insert(job.to_owned(), (Instant::now(), addrs.clone()));
+                addrs
+            }
+        };
+
+        Ok(addrs
+            .into_iter()
+            .map(|addr| self.location(job, addr))
+            .collect())
+    }
+
+    /// Read the addresses registered for a job, removing expired ones from
+    /// the set.
+    async fn read_job(&self, job: &str) -> ::redis::RedisResult<Vec<String>> {
+        let mut conn = self.conn.clone();
+        let set = self.job_key(job);
+        let mut members: Vec<String> = conn.smembers(&set).await?;
+        members.sort();
+        if members.is_empty() {
+            return Ok(members);
+        }
+
+        let keys = members
+            .iter()
+            .map(|addr| self.replica_key(job, addr))
+            .collect::<Vec<_>>();
+        let alive: Vec<Option<String>> = conn.mget(&keys).await?;
+
+        let (live, expired): (Vec<_>, Vec<_>) = members
+            .into_iter()
+            .zip(alive)
+            .partition(|(_, alive)| alive.is_some());
+        if !expired.is_empty() {
+            let expired = expired
+                .into_iter()
+                .map(|(addr, _)| addr)
+                .collect::<Vec<_>>();
+            log::debug!("removing expired replicas of job {job}: {expired:?}");
+            let _: () = conn.srem(&set, expired).await?;
+        }
+        Ok(live.into_iter().map(|(addr, _)| addr).collect())
+    }
+}
+
+async fn refresh(
+    mut conn: MultiplexedConnection,
+    set: &str,
+    key: &str,
+    addr: &str,
+) -> ::redis::RedisResult<()> {
+    let mut pipe = ::redis::pipe();
+    pipe.atomic()
+        .set_ex(key, addr, TTL.as_secs())
+        .ignore()
+        .sadd(set, addr)
+        .ignore();
+    pipe.query_async(&mut conn).await
+}
+
+async fn unregister(
+    mut conn: MultiplexedConnection,
+    set: &str,
+    key: &str,
+    addr: &str,
+) -> ::redis::RedisResult<()> {
+    let mut pipe = ::redis::pipe();
+    pipe.atomic().del(key).ignore().srem(set, addr).ignore();
+    pipe.query_async(&mut conn).await
+}
+
+/// Drop cached discovery results for jobs whose replicas change, as
+/// announced on the channel. If the subscription fails, cached results still
+/// expire after `CACHE_TTL`.
+async fn watch_changes(
+    client: ::redis::Client,
+    channel: String,
+    cache: Arc<Mutex<DiscoveryCache>>,
+) {
+    loop {
+        let res = async {
+            let mut pubsub = client.get_async_pubsub().await?;
+            pubsub.subscribe(&channel).await?;
+            let mut messages = pubsub.on_message();
+            while let Some(msg) = messages.next().await {
+                let job: String = msg.get_payload()?;
+                log::debug!("replicas of job {job} changed, dropping cached discovery");
+                cache.lock().expect("lock poisoned").remove(&job);
+            }
+            Ok::<_, ::redis::RedisError>(())
+        };
+        match res.await {
+            Ok(()) => log::warn!("Redis change notifications ended, resubscribing"),
+            Err(e) => log::warn!("Redis change notifications failed, resubscribing: {e}"),
+        }
+        tokio::time::sleep(REFRESH).await;
+    }
+}
+
+impl runtime::RuntimeProvider for RedisRuntime {
+    fn discover_running<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+    ) -> BoxFuture<'f, Result<Vec<Location>>> {
+        Box::pin(self.discover_inner(component))
+    }
+
+    fn discover_stable<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+    ) -> BoxFuture<'f, Result<Vec<Location>>> {
+        Box::pin(self.discover_inner(component))
+    }
+
+    fn myself<'f, 'p: 'f, 'l: 'f>(&'p self, component: &'l str) -> BoxFuture<'f, Result<Location>> {
+        Box::pin(self.myself_inner(component))
+    }
+
+    fn storage<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        _component: &'l str,
+    ) -> BoxFuture<'f, Result<PathBuf>> {
+        Box::pin(async { Err("storage() not implemented for Redis runtime")? })
+    }
+
+    fn capabilities(&self) -> runtime::Capabilities {
+        runtime::Capabilities {
+            storage: false,
+            myself: self.myself.is_some(),
+            stable_discovery: false,
+        }
+    }
+}
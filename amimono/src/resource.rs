@@ -0,0 +1,118 @@
+//! Resources shared by the components of a job.
+//!
+//! Components that talk to the same database or cache shouldn't each open
+//! their own connection pool. Instead, the app provides a factory for the
+//! resource with [`AppBuilder::provide`][crate::config::AppBuilder::provide],
+//! and components ask for it by type, typically in their handler's `new`:
+//!
+//! ```ignore
+//! app.provide(async || {
+//!     PgPool::connect(&std::env::var("DATABASE_URL").unwrap())
+//!         .await
+//!         .expect("could not connect to the database")
+//! });
+//!
+//! impl ledger::Handler for Ledger {
+//!     async fn new() -> Self {
+//!         Ledger {
+//!             db: Deps::resource::<PgPool>().await,
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Each resource is built the first time a component in the process asks for
+//! it, so only jobs that use it build it, and every component after that
+//! shares the same one. When running locally, every job shares one process,
+//! and so one of each resource.
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+    time::Instant,
+};
+
+use futures::future::BoxFuture;
+use tokio::sync::OnceCell;
+
+use crate::{metrics, runtime};
+
+type Shared = Arc<dyn Any + Send + Sync>;
+
+/// A factory for a resource, as given to `AppBuilder::provide`.
+pub(crate) struct Provider {
+    pub(crate) type_name: &'static str,
+    factory: Box<dyn Fn() -> BoxFuture<'static, Shared> + Send + Sync>,
+}
+
+impl Provider {
+    pub(crate) fn new<T, F, Fut>(factory: F) -> Provider
+    where
+        T: Send + Sync + 'static,
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = T> + Send + 'static,
+    {
+        let factory = move || -> BoxFuture<'static, Shared> {
+            let fut = factory();
+            Box::pin(async move { Arc::new(fut.await) as Shared })
+        };
+        Provider {
+            type_name: std::any::type_name::<T>(),
+            factory: Box::new(factory),
+        }
+    }
+}
+
+/// The resources built so far in this process. A resource's cell is shared
+/// by everything waiting for it to be built, so it's only built once.
+static BUILT: LazyLock<Mutex<HashMap<TypeId, Arc<OnceCell<Shared>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Access to the resources provided to the app.
+pub struct Deps;
+
+impl Deps {
+    /// Get the resource of type `T`, building it if no component in this
+    /// process has asked for it yet. Panics if the app doesn't provide one.
+    pub async fn resource<T: Send + Sync + 'static>() -> Arc<T> {
+        match Deps::try_resource::<T>().await {
+            Some(resource) => resource,
+            None => panic!(
+                "no resource of type {} was provided to the app",
+                std::any::type_name::<T>()
+            ),
+        }
+    }
+
+    /// Get the resource of type `T` like [`resource`][Deps::resource], or
+    /// `None` if the app doesn't provide one.
+    pub async fn try_resource<T: Send + Sync + 'static>() -> Option<Arc<T>> {
+        let provider = runtime::config().resource(TypeId::of::<T>())?;
+        let cell = BUILT
+            .lock()
+            .expect("lock poisoned")
+            .entry(TypeId::of::<T>())
+            .or_default()
+            .clone();
+        let resource = cell
+            .get_or_init(async || {
+                log::info!("building resource {}", provider.type_name);
+                let start = Instant::now();
+                let resource = (provider.factory)().await;
+                metrics::gauge(
+                    "amimono_resource_build_seconds",
+                    &[("resource", provider.type_name)],
+                )
+                .set(start.elapsed().as_secs_f64());
+                resource
+            })
+            .await;
+        Some(
+            resource
+                .clone()
+                .downcast::<T>()
+                .expect("resource has the type it was provided with"),
+        )
+    }
+}
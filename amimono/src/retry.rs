@@ -2,6 +2,13 @@ use std::{fmt, ops::RangeInclusive, time::Duration};
 
 pub trait RetryError {
     fn should_retry(&self) -> bool;
+
+    /// How long the failed side asked to be left alone before it's tried
+    /// again, e.g. because it's shedding load. Strategies that honor this
+    /// wait that long instead of their own delay.
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
 }
 
 pub trait RetryStrategy<E>: Sync {
@@ -89,6 +96,13 @@ impl<E: RetryError> RetryStrategy<E> for Retry {
             return None;
         }
 
+        // the server knows better how long it needs, so its hint replaces the
+        // backoff, spread a little so that the callers it shed don't all come
+        // back at once
+        if let Some(after) = last_error.retry_after() {
+            return Some(after.mul_f64(rand::random_range(1.0..=1.2)));
+        }
+
         let f = self
             .factor
             .powi(completed_attempts as i32 - 1)
@@ -110,8 +124,14 @@ where
                 return Ok(x);
             }
             Err(e) => match retry.retry(attempt, &e) {
+                Some(dur) if crate::context::remaining().is_some_and(|r| r <= dur) => {
+                    log::error!("no retries, deadline would pass: {e}");
+                    return Err(e);
+                }
                 Some(dur) => {
-                    log::warn!("retry after {dur:?}: {e}");
+                    if crate::rpc::sampling::retry() {
+                        log::warn!("retry after {dur:?}: {e}");
+                    }
                     tokio::time::sleep(dur).await;
                 }
                 None => {
@@ -0,0 +1,260 @@
+//! Adaptive limits on how many calls a client has in flight.
+//!
+//! A fixed limit on concurrent calls is either too low, wasting capacity, or
+//! too high to protect a component that's struggling. With an
+//! [`AdaptiveLimit`], a client instead estimates how many calls the component
+//! can handle at once from the latency and failures of its calls, raising the
+//! limit while calls are fast and lowering it as they slow down, fail with
+//! spurious errors, or are shed. Calls over the limit wait for one in flight
+//! to finish, or until the request's deadline.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Notify;
+
+use crate::{
+    AppError, context, metrics,
+    rpc::{RpcError, RpcResult},
+};
+
+#[derive(Copy, Clone, Debug)]
+enum Algorithm {
+    Aimd { timeout: Duration },
+    Gradient,
+}
+
+/// How a client adapts the number of calls it has in flight to a component.
+///
+/// Limits only apply to calls sent over HTTP, and not to calls made with
+/// progress updates. A client's limit is shared by its clones.
+#[derive(Copy, Clone, Debug)]
+pub struct AdaptiveLimit {
+    algorithm: Algorithm,
+    initial: u32,
+    min: u32,
+    max: u32,
+}
+
+impl AdaptiveLimit {
+    /// Additive increase, multiplicative decrease: the limit grows by one
+    /// for every limit's worth of calls that succeed within `timeout`, and
+    /// is cut by a tenth whenever one fails spuriously or takes longer.
+    pub const fn aimd(timeout: Duration) -> AdaptiveLimit {
+        AdaptiveLimit {
+            algorithm: Algorithm::Aimd { timeout },
+            initial: 20,
+            min: 1,
+            max: 1000,
+        }
+    }
+
+    /// Gradient: the limit follows the ratio between the component's latency
+    /// when it isn't loaded, i.e. the lowest seen recently, and the latency
+    /// of each call, so it shrinks once calls take more than twice as long as
+    /// they could, i.e. they're queueing in the component, and grows while
+    /// they don't. Spurious failures cut it like with [`aimd`][Self::aimd].
+    /// This needs no timeout to be chosen.
+    pub const fn gradient() -> AdaptiveLimit {
+        AdaptiveLimit {
+            algorithm: Algorithm::Gradient,
+            initial: 20,
+            min: 1,
+            max: 1000,
+        }
+    }
+
+    /// Set the limit to start from. Defaults to 20.
+    pub const fn with_initial(self, initial: u32) -> AdaptiveLimit {
+        AdaptiveLimit { initial, ..self }
+    }
+
+    /// Set the lowest the limit can go. Defaults to 1.
+    pub const fn with_min(self, min: u32) -> AdaptiveLimit {
+        AdaptiveLimit { min, ..self }
+    }
+
+    /// Set the highest the limit can go. Defaults to 1000.
+    pub const fn with_max(self, max: u32) -> AdaptiveLimit {
+        AdaptiveLimit { max, ..self }
+    }
+}
+
+/// How much the limit is cut by when a call fails spuriously or is too slow.
+const BACKOFF: f64 = 0.9;
+
+/// How much slower than the unloaded latency calls can be before the
+/// gradient shrinks the limit.
+const TOLERANCE: f64 = 2.0;
+
+/// How long the lowest latency seen is used as the unloaded latency, before
+/// it's measured again, so that the limit follows lasting changes in it.
+const BASE_RTT_WINDOW: Duration = Duration::from_secs(30);
+
+/// How much each sample moves the limit towards the gradient's estimate.
+const SMOOTHING: f64 = 0.2;
+
+struct State {
+    limit: f64,
+    in_flight: u32,
+    /// The unloaded latency, in seconds, for the gradient.
+    base_rtt: Option<f64>,
+    /// The lowest latency seen in the current window, and when the window
+    /// started.
+    window: (f64, Instant),
+}
+
+/// The adaptive limit of a client and its clones.
+pub(crate) struct Limiter {
+    label: &'static str,
+    policy: AdaptiveLimit,
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+impl Limiter {
+    pub(crate) fn new(label: &'static str, policy: AdaptiveLimit) -> Limiter {
+        let min = policy.min.max(1);
+        let limit = policy.initial.clamp(min, policy.max.max(min));
+        metrics::gauge("amimono_client_concurrency_limit", &[("component", label)])
+            .set(limit as f64);
+        Limiter {
+            label,
+            policy,
+            state: Mutex::new(State {
+                limit: limit as f64,
+                in_flight: 0,
+                base_rtt: None,
+                window: (f64::MAX, Instant::now()),
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Make a call once there's room under the limit, and adjust the limit
+    /// by how it went.
+    pub(crate) async fn run<X, F>(&self, call: F) -> RpcResult<X>
+    where
+        F: Future<Output = RpcResult<X>>,
+    {
+        let permit = self.acquire().await?;
+        let started = Instant::now();
+        let res = call.await;
+        let dropped = matches!(
+            res.as_ref().map_err(|e| e.root_cause()),
+            Err(AppError::Spurious(_) | AppError::Overloaded { .. })
+        );
+        permit.sample(started.elapsed(), dropped);
+        res
+    }
+
+    async fn acquire(&self) -> RpcResult<Permit<'_>> {
+        let wait = async {
+            let mut waited = false;
+            loop {
+                let notified = self.notify.notified();
+                if let Some(permit) = self.try_acquire() {
+                    return permit;
+                }
+                if !waited {
+                    waited = true;
+                    metrics::counter("amimono_client_limited", &[("component", self.label)]).inc();
+                }
+                notified.await;
+            }
+        };
+        match context::remaining() {
+            Some(remaining) => tokio::time::timeout(remaining, wait)
+                .await
+                .map_err(|_| RpcError::DeadlineExceeded(self.label.to_owned())),
+            None => Ok(wait.await),
+        }
+    }
+
+    fn try_acquire(&self) -> Option<Permit<'_>> {
+        let mut state = self.state.lock().expect("lock poisoned");
+        if state.in_flight as f64 >= state.limit.floor() {
+            return None;
+        }
+        state.in_flight += 1;
+        Some(Permit {
+            limiter: self,
+            in_flight: state.in_flight,
+        })
+    }
+
+    fn update(&self, state: &mut State, rtt: Duration, in_flight: u32, dropped: bool) {
+        let rtt = rtt.as_secs_f64();
+        let limit = state.limit;
+        let new = match self.policy.algorithm {
+            Algorithm::Aimd { timeout } => {
+                if dropped || rtt > timeout.as_secs_f64() {
+                    limit * BACKOFF
+                } else if (in_flight as f64) < limit / 2.0 {
+                    // the limit isn't what's holding calls back, so there's
+                    // nothing to learn about it
+                    return;
+                } else {
+                    limit + 1.0 / limit
+                }
+            }
+            Algorithm::Gradient => {
+                state.window.0 = state.window.0.min(rtt);
+                if state.window.1.elapsed() >= BASE_RTT_WINDOW {
+                    state.base_rtt = Some(state.window.0);
+                    state.window = (f64::MAX, Instant::now());
+                }
+                let base = match state.base_rtt {
+                    Some(base) if base <= rtt => base,
+                    _ => {
+                        state.base_rtt = Some(rtt);
+                        rtt
+                    }
+                };
+                if dropped {
+                    limit * BACKOFF
+                } else if (in_flight as f64) < limit / 2.0 {
+                    return;
+                } else {
+                    let gradient = (TOLERANCE * base / rtt.max(f64::MIN_POSITIVE)).clamp(0.5, 1.0);
+                    let estimate = limit * gradient + limit.sqrt();
+                    limit * (1.0 - SMOOTHING) + estimate * SMOOTHING
+                }
+            }
+        };
+        let min = self.policy.min.max(1) as f64;
+        state.limit = new.clamp(min, (self.policy.max as f64).max(min));
+        if state.limit.floor() != limit.floor() {
+            metrics::gauge(
+                "amimono_client_concurrency_limit",
+                &[("component", self.label)],
+            )
+            .set(state.limit.floor());
+        }
+    }
+}
+
+/// A call counted against the limit, released when it's dropped, including
+/// when the call is cancelled.
+struct Permit<'a> {
+    limiter: &'a Limiter,
+    /// How many calls were in flight when this one started.
+    in_flight: u32,
+}
+
+impl Permit<'_> {
+    fn sample(self, rtt: Duration, dropped: bool) {
+        let mut state = self.limiter.state.lock().expect("lock poisoned");
+        self.limiter
+            .update(&mut state, rtt, self.in_flight, dropped);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().expect("lock poisoned").in_flight -= 1;
+        self.limiter.notify.notify_waiters();
+    }
+}
@@ -0,0 +1,254 @@
+//! Caller authentication and allowlists for RPC components.
+//!
+//! Every outgoing request carries a header identifying the process making
+//! it and, if it's made from a component or tool, that caller's label. When
+//! `AMIMONO_CALLER_SECRET` is set, the header is a token signed with a key
+//! derived from the secret and the caller's revision, naming the caller's
+//! job, label, revision and the time it was made. Servers check the
+//! signature, and that the caller runs in the job it claims to. Every job in the app must share the same secret, which is
+//! best filled from the target's secrets. Without a secret the header is just
+//! the caller's label and is trusted as-is, which is only suitable for local
+//! development.
+//!
+//! By default, requests whose token doesn't check out are treated as coming
+//! from an unknown caller, which only matters to components with an
+//! allowlist. When `AMIMONO_CALLER_REQUIRED` is `true`, requests without a
+//! valid token are refused before they're dispatched, with
+//! [`AppError::Unauthenticated`]. This is a lighter-weight alternative to
+//! mTLS for clusters without the infrastructure for certificates. It's
+//! turned on by setting the secret on every job first, then setting
+//! `AMIMONO_CALLER_REQUIRED`.
+//!
+//! Components declare the labels of the components and tools that may call
+//! them with `ALLOWED_CALLERS`. Calls made from outside any component, e.g.
+//! from a task started with `tokio::spawn`, are authenticated as coming from
+//! their job, but carry no caller and are rejected by components with an
+//! allowlist.
+
+use std::{
+    sync::{LazyLock, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ring::{hkdf, hmac};
+
+use crate::{AppError, audit, cli, graph, metrics, rpc::RpcResult, runtime};
+
+/// The header carrying the caller's identity.
+pub(crate) const HEADER: &str = "x-amimono-caller";
+
+/// How far a token's timestamp can be from the current time.
+const MAX_SKEW: Duration = Duration::from_secs(300);
+
+static SECRET: LazyLock<Option<hkdf::Prk>> = LazyLock::new(|| {
+    let secret = std::env::var("AMIMONO_CALLER_SECRET").ok()?;
+    let salt = hkdf::Salt::new(hkdf::HKDF_SHA256, b"amimono caller token");
+    Some(salt.extract(secret.as_bytes()))
+});
+
+static REQUIRED: LazyLock<bool> = LazyLock::new(|| {
+    let required = std::env::var("AMIMONO_CALLER_REQUIRED").is_ok_and(|v| v == "true" || v == "1");
+    if required && SECRET.is_none() {
+        log::error!("caller tokens are required but AMIMONO_CALLER_SECRET is not set");
+    }
+    required
+});
+
+/// The signing key for the current revision, derived from the secret once.
+/// Keys for other revisions are derived for each token that claims one, since
+/// the revision isn't trusted until the signature checks out.
+static CURRENT_KEY: OnceLock<hmac::Key> = OnceLock::new();
+
+fn derive_key(secret: &hkdf::Prk, revision: &str) -> hmac::Key {
+    let info = [revision.as_bytes()];
+    let okm = secret
+        .expand(&info, hmac::HMAC_SHA256)
+        .expect("key length is valid");
+    hmac::Key::from(okm)
+}
+
+fn key(secret: &hkdf::Prk, revision: &str) -> hmac::Key {
+    if revision == runtime::config().revision() {
+        return CURRENT_KEY
+            .get_or_init(|| derive_key(secret, revision))
+            .clone();
+    }
+    derive_key(secret, revision)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The job or tool a token is issued for. When running locally, that's the
+/// job the caller would be in.
+fn process(caller: Option<&str>) -> Option<String> {
+    match &runtime::args().action {
+        cli::Action::Job(job) => Some(job.clone()),
+        cli::Action::Tool(tool) => Some(tool.to_string()),
+        cli::Action::Local => Some(
+            caller
+                .and_then(|c| runtime::config().component_job(c))
+                .unwrap_or("local")
+                .to_owned(),
+        ),
+        cli::Action::DumpConfig | cli::Action::DumpConfigEmbedded | cli::Action::DumpGraph(_) => {
+            None
+        }
+    }
+}
+
+/// The identity of the current caller, to attach to an outgoing request. With
+/// a secret this is a token, `job;caller;revision;timestamp;signature`, where
+/// the caller is empty outside of any component or tool. Without one it's
+/// just the caller's label.
+pub(crate) fn identity() -> Option<String> {
+    let caller = graph::caller();
+    let Some(secret) = SECRET.as_ref() else {
+        return caller;
+    };
+    let revision = runtime::config().revision();
+    let msg = format!(
+        "{};{};{};{}",
+        process(caller.as_deref())?,
+        caller.unwrap_or_default(),
+        revision,
+        now()
+    );
+    let tag = hmac::sign(&key(secret, revision), msg.as_bytes());
+    Some(format!("{};{}", msg, hex(tag.as_ref())))
+}
+
+/// The verified identity of the process that sent a request.
+pub(crate) struct Identity {
+    /// The label of the component or tool making the call, if any.
+    pub(crate) caller: Option<String>,
+}
+
+/// Get the identity from an incoming request's header, or `None` if there's
+/// no header. Returns an error describing the problem if the identity can't
+/// be trusted.
+pub(crate) fn verify(header: Option<&[u8]>) -> Result<Option<Identity>, String> {
+    let Some(header) = header else {
+        return Ok(None);
+    };
+    let header = std::str::from_utf8(header).map_err(|_| "identity is not UTF-8")?;
+    let Some(secret) = SECRET.as_ref() else {
+        if *REQUIRED {
+            return Err("no caller secret is configured".to_owned());
+        }
+        return Ok(Some(Identity {
+            caller: Some(header.to_owned()),
+        }));
+    };
+
+    let Some((msg, sig)) = header.rsplit_once(';') else {
+        return Err("identity is not signed".to_owned());
+    };
+    let [job, caller, revision, ts] = msg.split(';').collect::<Vec<_>>()[..] else {
+        return Err("identity is not a valid token".to_owned());
+    };
+    let ts = ts
+        .parse::<u64>()
+        .map_err(|_| "identity has an invalid timestamp")?;
+    if now().abs_diff(ts) > MAX_SKEW.as_secs() {
+        return Err("identity has expired".to_owned());
+    }
+    if sig.len() % 2 != 0 {
+        return Err("identity has an invalid signature".to_owned());
+    }
+    let sig = (0..sig.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&sig[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| "identity has an invalid signature")?;
+    hmac::verify(&key(secret, revision), msg.as_bytes(), &sig)
+        .map_err(|_| "identity signature mismatch")?;
+
+    let cf = runtime::config();
+    if let Some(expected) = cf.component_job(caller)
+        && expected != job
+    {
+        return Err(format!("{caller} does not run in job {job}"));
+    }
+    Ok(Some(Identity {
+        caller: (!caller.is_empty()).then(|| caller.to_owned()),
+    }))
+}
+/// Check whether a caller may call the given component.
+pub(crate) fn check(component: &str, caller: Option<&str>) -> RpcResult<()> {
+    let allowed = runtime::config()
+        .component(component)
+        .and_then(|c| c.allowed_callers);
+    let Some(allowed) = allowed else {
+        return Ok(());
+    };
+    if caller.is_some_and(|c| allowed.contains(&c)) {
+        return Ok(());
+    }
+    audit::event("rpc.denied")
+        .with("target", component)
+        .with("caller", caller)
+        .warning()
+        .record();
+    metrics::counter("amimono_rpc_forbidden", &[("component", component)]).inc();
+    Err(AppError::Forbidden {
+        caller: caller.map(|c| c.to_owned()),
+        component: component.to_owned(),
+    })
+}
+
+fn unauthenticated(target: &str, reason: String) -> AppError {
+    audit::event("rpc.unauthenticated")
+        .with("target", target)
+        .with("reason", &reason)
+        .warning()
+        .record();
+    metrics::counter("amimono_rpc_unauthenticated", &[("target", target)]).inc();
+    AppError::Unauthenticated {
+        component: target.to_owned(),
+        reason,
+    }
+}
+
+/// Authenticate an incoming request to the target, returning its caller.
+/// Requests that can't be authenticated are refused if tokens are required.
+pub(crate) fn authenticate(target: &str, header: Option<&[u8]>) -> RpcResult<Option<String>> {
+    match verify(header) {
+        Ok(Some(id)) => Ok(id.caller),
+        Ok(None) if *REQUIRED => Err(unauthenticated(
+            target,
+            "request has no caller token".to_owned(),
+        )),
+        Ok(None) => Ok(None),
+        Err(e) if *REQUIRED => Err(unauthenticated(target, e)),
+        Err(e) => {
+            audit::event("rpc.untrusted_caller")
+                .with("target", target)
+                .with("error", e)
+                .warning()
+                .record();
+            Ok(None)
+        }
+    }
+}
+
+/// Authenticate an incoming request and check its caller against the
+/// component's allowlist, returning its caller.
+pub(crate) fn check_header(component: &str, header: Option<&[u8]>) -> RpcResult<Option<String>> {
+    let caller = authenticate(component, header)?;
+    check(component, caller.as_deref())?;
+    Ok(caller)
+}
+
+/// Check an in-process call from the current component.
+pub(crate) fn check_local(component: &str) -> RpcResult<()> {
+    check(component, graph::caller().as_deref())
+}
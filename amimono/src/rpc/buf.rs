@@ -0,0 +1,74 @@
+//! Pooled buffers for serializing requests and responses.
+//!
+//! Requests and responses are serialized into buffers taken from a pool, so
+//! a busy server stops growing buffers a few bytes at a time. A payload that
+//! fills most of its buffer is handed out as a `Bytes` that owns the buffer,
+//! so it isn't copied on the way out, and once the `Bytes` and its clones
+//! have been dropped, the buffer goes back to the pool of the thread that
+//! dropped it. Smaller payloads are copied out and their buffer goes straight
+//! back, so that a small `Bytes` that's kept around doesn't hold on to a much
+//! larger buffer. Each thread pools at most [`MAX_BUFFERS`] buffers of up to
+//! [`MAX_POOLED`] bytes.
+
+use std::cell::RefCell;
+
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::rpc::RpcResult;
+
+/// How big a buffer is to begin with.
+const INITIAL: usize = 4 * 1024;
+
+/// How big a buffer can get and still be pooled. Larger ones are freed, so
+/// that an occasional huge response doesn't hold on to its memory.
+const MAX_POOLED: usize = 256 * 1024;
+
+/// How many buffers each thread keeps.
+const MAX_BUFFERS: usize = 8;
+
+thread_local! {
+    static POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A buffer that goes back to the pool when it's dropped.
+struct Pooled(Vec<u8>);
+
+impl AsRef<[u8]> for Pooled {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        let mut buf = std::mem::take(&mut self.0);
+        if buf.capacity() > MAX_POOLED {
+            return;
+        }
+        buf.clear();
+        // the pool is gone if the thread is exiting
+        let _ = POOL.try_with(|pool| {
+            let mut pool = pool.borrow_mut();
+            if pool.len() < MAX_BUFFERS {
+                pool.push(buf);
+            }
+        });
+    }
+}
+
+/// Serialize a value as JSON into a pooled buffer.
+///
+/// This is how the RPC client and server serialize requests and responses,
+/// and can be used by [raw handlers][crate::rpc::serve_raw] too.
+pub fn to_json_bytes<T: Serialize + ?Sized>(value: &T) -> RpcResult<Bytes> {
+    let buf = POOL
+        .with_borrow_mut(|pool| pool.pop())
+        .unwrap_or_else(|| Vec::with_capacity(INITIAL));
+    let mut buf = Pooled(buf);
+    serde_json::to_writer(&mut buf.0, value)?;
+    if buf.0.len() < buf.0.capacity() / 2 {
+        return Ok(Bytes::copy_from_slice(&buf.0));
+    }
+    Ok(Bytes::from_owner(buf))
+}
@@ -0,0 +1,323 @@
+//! Capturing request and response payloads, for debugging in production.
+//!
+//! When capture is enabled for a component with
+//! [`AppBuilder::with_capture`][crate::config::AppBuilder::with_capture], a
+//! sample of the requests its RPC server handles are captured in full, along
+//! with their responses, the op, the caller, the request ID, and how long
+//! they took. Requests are captured as their arguments by name, and
+//! responses as the value returned. Before a payload leaves the process, the
+//! values of the configured sensitive fields, which can be arguments or
+//! fields nested anywhere in them, are replaced with `"[redacted]"`, the
+//! component's redaction hook runs on it, and it's truncated if it's too
+//! large.
+//!
+//! Captures are pushed as OTLP log records, over HTTP with the JSON encoding,
+//! to the endpoint in `AMIMONO_CAPTURE_PUSH_URL`, or, if metrics are
+//! [pushed][crate::metrics::push] to an OTLP collector, to the same
+//! collector's `/v1/logs` endpoint. Pushes send the same headers as metrics
+//! pushes. Without an endpoint, captures are logged with the
+//! `amimono::capture` target. Either way, the request ID is attached, so a
+//! capture can be matched up with everything else logged for the request.
+//!
+//! Unlike the [journal][super::journal], which records requests to replay
+//! them, captures are meant to be read, and are cheap enough to leave on at
+//! a low sample rate.
+
+use std::{
+    sync::{
+        LazyLock, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::body::Bytes;
+use serde::Serialize;
+use serde_json::{Value as Json, json};
+use tokio::sync::mpsc;
+
+use crate::{
+    config::CaptureConfig,
+    metrics::{self, push},
+    rpc::{RpcOp, RpcResult, journal},
+    runtime,
+};
+
+/// How many captures are pushed at once, at most.
+const MAX_BATCH: usize = 256;
+
+/// How many captures can wait to be pushed before new ones are dropped.
+const MAX_QUEUED: usize = 4096;
+
+/// How long to wait between pushes, to batch up captures.
+const PUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+const REDACTED: &str = "[redacted]";
+
+/// A captured request.
+#[derive(Serialize)]
+struct Capture {
+    #[serde(skip)]
+    t: SystemTime,
+    label: &'static str,
+    op: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    caller: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    /// How long the request took to handle, in microseconds.
+    us: u64,
+    q: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ok: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    err: Option<String>,
+}
+
+/// A request being handled that was sampled for capture.
+pub(crate) struct Pending {
+    label: &'static str,
+    cf: &'static CaptureConfig,
+    ops: &'static [RpcOp],
+    caller: Option<String>,
+    request_id: Option<String>,
+    t: SystemTime,
+    started: Instant,
+}
+
+/// Decide whether to capture a request to the component, returning a
+/// `Pending` to finish once the request is handled if so.
+pub(crate) fn start(
+    label: &str,
+    caller: Option<&str>,
+    request_id: Option<&str>,
+) -> Option<Pending> {
+    let cf = runtime::config();
+    let capture = cf.capture(label)?;
+    if rand::random::<f64>() >= capture.sample_rate {
+        return None;
+    }
+    let comp = cf.component(label)?;
+    Some(Pending {
+        label: comp.label.as_str(),
+        cf: capture,
+        ops: comp.rpc_ops.unwrap_or_default(),
+        caller: caller.map(|c| c.to_owned()),
+        request_id: request_id.map(|id| id.to_owned()),
+        t: SystemTime::now(),
+        started: Instant::now(),
+    })
+}
+
+impl Pending {
+    /// Capture the request and its result.
+    pub(crate) fn finish(self, q: &[u8], res: &RpcResult<Bytes>) {
+        let us = self.started.elapsed().as_micros() as u64;
+        let q = journal::to_value(q);
+        let op = journal::op_name(&q).unwrap_or_default();
+        let (ok, err) = match res {
+            Ok(a) => (Some(self.redact(&op, unwrap(journal::to_value(a)))), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        let args = self.named_args(&op, unwrap(q));
+        let capture = Capture {
+            t: self.t,
+            label: self.label,
+            q: self.redact(&op, args),
+            op,
+            caller: self.caller,
+            request_id: self.request_id,
+            us,
+            ok,
+            err,
+        };
+        if QUEUED.fetch_add(1, Ordering::Relaxed) >= MAX_QUEUED as u64 {
+            QUEUED.fetch_sub(1, Ordering::Relaxed);
+            metrics::counter("amimono_rpc_captures_dropped", &[("component", self.label)]).inc();
+            return;
+        }
+        let _ = SENDER.send(capture);
+    }
+
+    /// Name the arguments of a request, which are serialized as a tuple, or
+    /// on their own for ops with one argument.
+    fn named_args(&self, op: &str, args: Json) -> Json {
+        let Some(op) = self.ops.iter().find(|o| o.name == op) else {
+            return args;
+        };
+        match args {
+            Json::Array(items) if items.len() == op.arg_names.len() && items.len() != 1 => {
+                let named = op.arg_names.iter().map(|n| n.to_string()).zip(items);
+                Json::Object(named.collect())
+            }
+            args if op.arg_names.len() == 1 => json!({ op.arg_names[0]: args }),
+            args => args,
+        }
+    }
+
+    /// Redact a payload as configured for the component, and serialize it.
+    fn redact(&self, op: &str, mut payload: Json) -> String {
+        if !self.cf.redacted_fields.is_empty() {
+            redact_fields(&mut payload, self.cf);
+        }
+        if let Some(f) = self.cf.redactor() {
+            f(op, &mut payload);
+        }
+        let mut s = payload.to_string();
+        if s.len() > self.cf.max_bytes {
+            let mut end = self.cf.max_bytes;
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            s.truncate(end);
+            s.push_str("...[truncated]");
+        }
+        s
+    }
+}
+
+/// The payload of a serialized request or response, which is an externally
+/// tagged enum, i.e. `{"op": payload}`, or just `"op"` for ops without
+/// arguments.
+fn unwrap(v: Json) -> Json {
+    match v {
+        Json::Object(map) if map.len() == 1 => map.into_iter().next().expect("one entry").1,
+        Json::String(_) => Json::Null,
+        v => v,
+    }
+}
+
+fn redact_fields(v: &mut Json, cf: &CaptureConfig) {
+    match v {
+        Json::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                if cf.redacted_fields.contains(k) {
+                    *v = Json::String(REDACTED.to_owned());
+                } else {
+                    redact_fields(v, cf);
+                }
+            }
+        }
+        Json::Array(items) => items.iter_mut().for_each(|v| redact_fields(v, cf)),
+        _ => (),
+    }
+}
+
+/// The captures waiting to be pushed or logged.
+static QUEUED: AtomicU64 = AtomicU64::new(0);
+
+static SENDER: LazyLock<mpsc::UnboundedSender<Capture>> = LazyLock::new(|| {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(export(rx));
+    tx
+});
+
+/// The service captures are reported from, set when the job starts.
+static SERVICE: OnceLock<String> = OnceLock::new();
+
+/// Set the service that captures are reported from, as the `service.name`
+/// resource attribute.
+pub(crate) fn set_service(service: &str) {
+    let _ = SERVICE.set(service.to_owned());
+}
+
+/// The OTLP/HTTP logs endpoint to push captures to, if any.
+fn endpoint() -> Option<String> {
+    if let Ok(url) = std::env::var("AMIMONO_CAPTURE_PUSH_URL") {
+        return Some(url);
+    }
+    let url = push::config()?.url.as_str();
+    let base = url.strip_suffix("/v1/metrics")?;
+    Some(format!("{base}/v1/logs"))
+}
+
+async fn export(mut rx: mpsc::UnboundedReceiver<Capture>) {
+    let url = endpoint();
+    match &url {
+        Some(url) => log::info!("pushing captured requests to {url}"),
+        None => log::info!("logging captured requests"),
+    }
+    let client = reqwest::Client::new();
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    loop {
+        if rx.recv_many(&mut batch, MAX_BATCH).await == 0 {
+            return;
+        }
+        QUEUED.fetch_sub(batch.len() as u64, Ordering::Relaxed);
+        match &url {
+            Some(url) => {
+                push_batch(&client, url, &batch).await;
+                batch.clear();
+                tokio::time::sleep(PUSH_INTERVAL).await;
+            }
+            None => {
+                for capture in batch.drain(..) {
+                    match serde_json::to_string(&capture) {
+                        Ok(line) => log::info!(target: "amimono::capture", "{line}"),
+                        Err(e) => log::warn!("could not serialize capture: {e}"),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn log_record(capture: &Capture) -> Json {
+    let us = capture.us.to_string();
+    let mut attrs = vec![
+        ("rpc.service", capture.label),
+        ("rpc.method", capture.op.as_str()),
+        ("amimono.duration_us", us.as_str()),
+        ("amimono.request", capture.q.as_str()),
+    ];
+    if let Some(caller) = &capture.caller {
+        attrs.push(("amimono.caller", caller.as_str()));
+    }
+    if let Some(id) = &capture.request_id {
+        attrs.push(("amimono.request_id", id.as_str()));
+    }
+    if let Some(ok) = &capture.ok {
+        attrs.push(("amimono.response", ok.as_str()));
+    }
+    if let Some(err) = &capture.err {
+        attrs.push(("amimono.error", err.as_str()));
+    }
+    let (severity, text) = match capture.err {
+        None => (9, "INFO"),
+        Some(_) => (17, "ERROR"),
+    };
+    json!({
+        "timeUnixNano": push::unix_nanos(capture.t),
+        "severityNumber": severity,
+        "severityText": text,
+        "body": { "stringValue": format!("{} {}", capture.label, capture.op) },
+        "attributes": push::attributes(attrs),
+    })
+}
+
+async fn push_batch(client: &reqwest::Client, url: &str, batch: &[Capture]) {
+    let service = SERVICE.get().map(|s| s.as_str()).unwrap_or("amimono");
+    let payload = json!({
+        "resourceLogs": [{
+            "resource": push::resource(service),
+            "scopeLogs": [{
+                "scope": { "name": "amimono.capture" },
+                "logRecords": batch.iter().map(log_record).collect::<Vec<_>>(),
+            }],
+        }],
+    });
+    let mut req = client.post(url).json(&payload);
+    for (k, v) in push::config()
+        .map(|cf| cf.headers.as_slice())
+        .unwrap_or(&[])
+    {
+        req = req.header(k, v);
+    }
+    match req.timeout(Duration::from_secs(10)).send().await {
+        Ok(resp) if resp.status().is_success() => {
+            log::debug!("pushed {} captures to {url}", batch.len());
+        }
+        Ok(resp) => log::warn!("capture push to {url} failed: {}", resp.status()),
+        Err(e) => log::warn!("capture push to {url} failed: {e}"),
+    }
+}
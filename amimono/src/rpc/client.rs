@@ -1,14 +1,36 @@
-use std::borrow::Borrow;
+use std::{
+    borrow::Borrow,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use axum::body::Bytes;
+use fnv::FnvHasher;
 use futures::{
     FutureExt,
     future::{BoxFuture, Shared},
 };
+use serde::{Serialize, de::DeserializeOwned};
 
 use crate::{
-    component::{ComponentKind, Location},
+    component::{self, ComponentKind, Location},
+    context, graph,
     retry::{Retry, RetryStrategy},
-    rpc::{RpcComponentKind, RpcError, RpcResult, http},
+    rpc::{
+        HttpVersion, RpcComponentKind, RpcError, RpcMessage, RpcResult,
+        adaptive::{AdaptiveLimit, Limiter},
+        auth, buf, dispatch,
+        failover::{Failover, FailoverPolicy},
+        golden, http,
+        observe::{CallObserver, Destination, Observation, op_name},
+        progress::{self, Progress},
+        sampling, shaping, single_flight,
+        subscribe::{Subscription, Topic},
+        timeout::Timeouts,
+    },
+    runtime, shard,
 };
 
 /// A client for making requests to an RPC component.
@@ -16,11 +38,20 @@ use crate::{
 /// Cloning values of this type will result in clients that share resources
 /// such as connection pools.
 ///
-/// The `Client` struct defined by the [`rpc_ops!`][crate::rpc_ops] macro is a
+/// The `Client` struct defined by the [`rpc_component!`][crate::rpc_component] macro is a
 /// thin wrapper around this type.
 pub struct RpcClient<T: RpcComponentKind, R = Retry> {
     retry: R,
     instance: Option<Shared<BoxFuture<'static, <T as ComponentKind>::Instance>>>,
+    affinity: Option<u64>,
+    shard: Option<u32>,
+    unready: bool,
+    recording: Option<Arc<Path>>,
+    http_version: Option<HttpVersion>,
+    failover: Option<FailoverPolicy>,
+    observer: Option<Arc<dyn CallObserver>>,
+    limiter: Option<Arc<Limiter>>,
+    timeouts: Timeouts,
 }
 
 /// The default retry strategy for RPC clients: 5 attempts with exponential
@@ -36,6 +67,15 @@ impl<T: RpcComponentKind, R: Clone> Clone for RpcClient<T, R> {
         RpcClient {
             retry: self.retry.clone(),
             instance: self.instance.clone(),
+            affinity: self.affinity,
+            shard: self.shard,
+            unready: self.unready,
+            recording: self.recording.clone(),
+            http_version: self.http_version,
+            failover: self.failover,
+            observer: self.observer.clone(),
+            limiter: self.limiter.clone(),
+            timeouts: self.timeouts.clone(),
         }
     }
 }
@@ -45,6 +85,183 @@ impl<T: RpcComponentKind, R: Sync> RpcClient<T, R> {
         RpcClient {
             retry,
             instance: self.instance,
+            affinity: self.affinity,
+            shard: self.shard,
+            unready: self.unready,
+            recording: self.recording,
+            http_version: self.http_version,
+            failover: self.failover,
+            observer: self.observer,
+            limiter: self.limiter,
+            timeouts: self.timeouts,
+        }
+    }
+
+    /// Route requests by an affinity key. Requests with the same key will
+    /// consistently be sent to the same replica as long as it's discoverable,
+    /// and only keys belonging to replicas that go away will move when the
+    /// set of replicas changes. Keys are hashed with FNV-1a, so clients built
+    /// with different toolchains agree on where keys go. This has no effect
+    /// if the component is running in the same process.
+    pub fn with_affinity<K: Hash>(self, key: K) -> RpcClient<T, R> {
+        let mut hasher = FnvHasher::default();
+        key.hash(&mut hasher);
+        RpcClient {
+            retry: self.retry,
+            instance: self.instance,
+            affinity: Some(hasher.finish()),
+            shard: self.shard,
+            unready: self.unready,
+            recording: self.recording,
+            http_version: self.http_version,
+            failover: self.failover,
+            observer: self.observer,
+            limiter: self.limiter,
+            timeouts: self.timeouts,
+        }
+    }
+
+    /// Send calls to the replica that owns a shard of a
+    /// [sharded][crate::shard] component, e.g. one from
+    /// [`shard::of`][crate::shard::of], which forwards them to the current
+    /// owner if the shard has moved. If the component is running in the same
+    /// process, calls are only handled in-process if this process owns the
+    /// shard. Calls for a shard don't fail over, and progress calls and
+    /// subscriptions aren't routed by shard.
+    pub fn with_shard(self, shard: u32) -> RpcClient<T, R> {
+        RpcClient {
+            shard: Some(shard),
+            ..self
+        }
+    }
+
+    /// Also send calls to replicas that are running but haven't passed their
+    /// readiness checks, which are normally left out, e.g. to inspect a
+    /// replica that's stuck starting up. This has no effect if the component
+    /// is running in the same process.
+    pub fn with_unready_replicas(self) -> RpcClient<T, R> {
+        RpcClient {
+            unready: true,
+            ..self
+        }
+    }
+
+    /// Move on to other replicas when retrying a call, rather than picking
+    /// one independently for each attempt. See [`FailoverPolicy`].
+    pub fn with_failover(self, policy: FailoverPolicy) -> RpcClient<T, R> {
+        RpcClient {
+            failover: Some(policy),
+            ..self
+        }
+    }
+
+    /// Adapt how many calls this client and its clones have in flight to
+    /// the component at once, by how the calls perform. See
+    /// [`AdaptiveLimit`].
+    pub fn with_adaptive_limit(self, policy: AdaptiveLimit) -> RpcClient<T, R> {
+        RpcClient {
+            limiter: Some(Arc::new(Limiter::new(T::LABEL, policy))),
+            ..self
+        }
+    }
+
+    /// Give up on each attempt at a call over HTTP after `timeout`, instead
+    /// of [`DEFAULT_REQUEST_TIMEOUT`][crate::rpc::DEFAULT_REQUEST_TIMEOUT].
+    /// Attempts that time out fail with
+    /// [`AppError::RequestTimeout`][crate::AppError::RequestTimeout], and are
+    /// retried.
+    pub fn with_request_timeout(self, timeout: Duration) -> RpcClient<T, R> {
+        RpcClient {
+            timeouts: Timeouts {
+                request: timeout,
+                ..self.timeouts
+            },
+            ..self
+        }
+    }
+
+    /// Give up on each attempt at calling one op after `timeout`, overriding
+    /// the client's request timeout for that op. Panics if the component has
+    /// no such op.
+    pub fn with_op_request_timeout(self, op: &str, timeout: Duration) -> RpcClient<T, R> {
+        if !T::OPS.is_empty() && !T::OPS.iter().any(|o| o.name == op) {
+            panic!("request timeout set for unknown op {}::{}", T::LABEL, op);
+        }
+        let mut timeouts = self.timeouts;
+        timeouts.ops.insert(op.to_owned(), timeout);
+        RpcClient { timeouts, ..self }
+    }
+
+    /// Give up on calls that take longer than `timeout` in total, including
+    /// retries, failing them with
+    /// [`AppError::OperationTimeout`][crate::AppError::OperationTimeout].
+    /// Calls have no operation timeout by default.
+    pub fn with_operation_timeout(self, timeout: Duration) -> RpcClient<T, R> {
+        RpcClient {
+            timeouts: Timeouts {
+                operation: Some(timeout),
+                ..self.timeouts
+            },
+            ..self
+        }
+    }
+
+    /// Use a specific HTTP version for requests to other jobs, instead of the
+    /// app's. See [`HttpVersion`].
+    pub fn with_http_version(self, version: HttpVersion) -> RpcClient<T, R> {
+        RpcClient {
+            http_version: Some(version),
+            ..self
+        }
+    }
+
+    /// Record the requests sent with [`call`][Self::call],
+    /// [`call_owned`][Self::call_owned], and [`call_at`][Self::call_at], and
+    /// the responses to them, to a golden fixture file. See
+    /// [`golden`][crate::rpc::golden]. This overrides `AMIMONO_GOLDEN_DIR`.
+    pub fn with_recording<P: Into<PathBuf>>(self, path: P) -> RpcClient<T, R> {
+        RpcClient {
+            recording: Some(Arc::from(path.into())),
+            ..self
+        }
+    }
+
+    /// Report the calls made with this client to an observer, in addition to
+    /// the ones registered with
+    /// [`add_call_observer`][crate::rpc::add_call_observer]. This replaces
+    /// any observer the client already had.
+    pub fn with_observer(self, observer: Arc<dyn CallObserver>) -> RpcClient<T, R> {
+        RpcClient {
+            observer: Some(observer),
+            ..self
+        }
+    }
+
+    /// Whether requests are sent over HTTP rather than handled in-process.
+    fn is_remote(&self) -> bool {
+        self.instance.is_none()
+            || shaping::get(T::LABEL).is_some()
+            || self.shard.is_some_and(|s| !shard::owns(T::LABEL, s))
+    }
+
+    /// Pick a replica to send a request to: the owner of the client's shard,
+    /// if it has one.
+    async fn discover(&self) -> RpcResult<Location> {
+        match self.shard {
+            Some(shard) => shard::discover::<T>(shard, self.unready).await,
+            None => http::discover::<T>(self.affinity, self.unready).await,
+        }
+    }
+
+    /// Make a call over HTTP within the client's adaptive limit, if it has
+    /// one.
+    async fn limited<X, F>(&self, call: F) -> RpcResult<X>
+    where
+        F: Future<Output = RpcResult<X>>,
+    {
+        match &self.limiter {
+            Some(limiter) => limiter.run(call).await,
+            None => call.await,
         }
     }
 
@@ -52,9 +269,154 @@ impl<T: RpcComponentKind, R: Sync> RpcClient<T, R> {
     /// that is running in the same process, this will result in the target
     /// handler being invoked directly.
     pub async fn call_once(&self, q: &T::Request) -> RpcResult<T::Response> {
+        let obs = Observation::client(self.observer.as_ref(), T::LABEL, q.verb());
+        let call = self.timeouts.run(T::LABEL, q.verb(), self.attempt(q, &obs));
+        let res = sampling::scope(T::LABEL, q.verb(), context::originate(call)).await;
+        obs.complete(&res);
+        res
+    }
+
+    /// Make one attempt at a call. See [`call_once`][Self::call_once].
+    async fn attempt(&self, q: &T::Request, obs: &Observation) -> RpcResult<T::Response> {
+        graph::record_call(T::LABEL);
+        let started = Instant::now();
         let res = match &self.instance {
-            Some(inner) => inner.clone().await.handle(q).await,
-            None => http::http_call::<T>(q).await,
+            Some(inner) if !self.is_remote() => {
+                let res = match auth::check_local(T::LABEL) {
+                    Ok(()) => {
+                        let inner = inner.clone().await;
+                        let handle = component::scope(T::LABEL, inner.handle(q));
+                        context::enforce(T::LABEL, context::deadline(), handle).await
+                    }
+                    Err(e) => Err(e),
+                };
+                obs.attempt(Destination::Local, started, &res);
+                res
+            }
+            _ => match self.discover().await {
+                Ok(loc) => {
+                    let addr: &str = loc.addr();
+                    let call = http::http_call_at::<T>(addr, q, self.http_version);
+                    let res = shard::outgoing(self.shard, self.limited(call)).await;
+                    obs.attempt(Destination::Remote(addr), started, &res);
+                    res
+                }
+                Err(e) => {
+                    let res = Err(e);
+                    obs.attempt(Destination::Unresolved, started, &res);
+                    res
+                }
+            },
+        };
+        res.map_err(|e| RpcError::Downstream(T::LABEL.to_owned(), Box::new(e)))
+    }
+
+    /// Send a request once through the component's local dispatch queue. See
+    /// [`call_owned`][Self::call_owned].
+    async fn dispatch_once(
+        &self,
+        inner: &Shared<BoxFuture<'static, <T as ComponentKind>::Instance>>,
+        q: &Arc<T::Request>,
+        obs: &Observation,
+    ) -> RpcResult<T::Response> {
+        graph::record_call(T::LABEL);
+        let started = Instant::now();
+        let res = match auth::check_local(T::LABEL) {
+            Ok(()) => {
+                let inner = inner.clone().await;
+                let q = q.clone();
+                let ctx = context::current();
+                let handle = dispatch::run(T::LABEL, async move {
+                    component::scope(T::LABEL, context::scope(ctx, inner.handle(&q))).await
+                });
+                context::enforce(T::LABEL, context::deadline(), handle).await
+            }
+            Err(e) => Err(e),
+        };
+        obs.attempt(Destination::Local, started, &res);
+        res.map_err(|e| RpcError::Downstream(T::LABEL.to_owned(), Box::new(e)))
+    }
+
+    /// Send a request once, receiving progress updates from the handler as it
+    /// runs. Requests made this way are never retried, since retrying a
+    /// long-running operation could duplicate its work.
+    pub fn call_with_progress(&self, q: T::Request) -> Progress<T::Response> {
+        graph::record_call(T::LABEL);
+        let instance = self.instance.clone();
+        let affinity = self.affinity;
+        let unready = self.unready;
+        let version = self.http_version;
+        let ctx = context::current();
+        let allowed = auth::check_local(T::LABEL);
+        Progress::spawn(move |tx| {
+            context::scope(
+                ctx,
+                context::originate(async move {
+                    let res = match instance {
+                        Some(inner) if shaping::get(T::LABEL).is_none() => match allowed {
+                            Ok(()) => {
+                                let inner = inner.await;
+                                let handle = component::scope(T::LABEL, inner.handle(&q));
+                                progress::scope(tx, handle).await
+                            }
+                            Err(e) => Err(e),
+                        },
+                        _ => http::http_call_stream::<T>(&q, affinity, unready, version, tx).await,
+                    };
+                    res.map_err(|e| RpcError::Downstream(T::LABEL.to_owned(), Box::new(e)))
+                }),
+            )
+        })
+    }
+
+    /// Send an already serialized request once, e.g. one replayed from logs,
+    /// and return the serialized response. The request must be in the JSON
+    /// form of the component's `Request` enum.
+    pub async fn call_raw_once(&self, q: &Bytes) -> RpcResult<Bytes> {
+        let obs = Observation::client_raw(self.observer.as_ref(), T::LABEL, q);
+        let verb = op_name(q).unwrap_or("unknown");
+        let call = self.timeouts.run(T::LABEL, verb, self.raw_attempt(q, &obs));
+        let res = sampling::scope(T::LABEL, verb, context::originate(call)).await;
+        obs.complete(&res);
+        res
+    }
+
+    /// Make one attempt at a call with a serialized request. See
+    /// [`call_raw_once`][Self::call_raw_once].
+    async fn raw_attempt(&self, q: &Bytes, obs: &Observation) -> RpcResult<Bytes> {
+        graph::record_call(T::LABEL);
+        let started = Instant::now();
+        let res = match &self.instance {
+            Some(inner) if !self.is_remote() => {
+                let res = match auth::check_local(T::LABEL) {
+                    Ok(()) => {
+                        // wait for the component to start, so its handler is
+                        // registered
+                        inner.clone().await;
+                        match http::HTTP_HANDLERS.get(T::LABEL) {
+                            Some(h) => h.handle_json(q).await,
+                            None => Err(RpcError::Misc(format!("no handler for {}", T::LABEL))),
+                        }
+                    }
+                    Err(e) => Err(e),
+                };
+                obs.attempt(Destination::Local, started, &res);
+                res
+            }
+            _ => match self.discover().await {
+                Ok(loc) => {
+                    let addr: &str = loc.addr();
+                    let call = http::http_call_raw_at::<T>(addr, q.clone(), self.http_version);
+                    let res = shard::outgoing(self.shard, self.limited(call)).await;
+                    obs.attempt(Destination::Remote(addr), started, &res);
+                    res
+                }
+                Err(e) => {
+                    let res = Err(e);
+                    obs.attempt(Destination::Unresolved, started, &res);
+                    res
+                }
+            },
         };
         res.map_err(|e| RpcError::Downstream(T::LABEL.to_owned(), Box::new(e)))
     }
@@ -67,19 +429,60 @@ impl<T: RpcComponentKind, R: Sync> RpcClient<T, R> {
         L: Borrow<Location<A>>,
         A: Borrow<str>,
     {
-        let addr = loc.borrow().addr();
+        let obs = Observation::client(self.observer.as_ref(), T::LABEL, q.verb());
+        let call = self
+            .timeouts
+            .run(T::LABEL, q.verb(), self.at_attempt(loc.borrow(), q, &obs));
+        let res = sampling::scope(T::LABEL, q.verb(), context::originate(call)).await;
+        obs.complete(&res);
+        res
+    }
+
+    /// Make one attempt at a call to a specific location. See
+    /// [`call_at_once`][Self::call_at_once].
+    async fn at_attempt<A: Borrow<str>>(
+        &self,
+        loc: &Location<A>,
+        q: &T::Request,
+        obs: &Observation,
+    ) -> RpcResult<T::Response> {
+        let resolved = match loc.as_ordinal() {
+            Some(n) => match runtime::provider().ordinal(T::LABEL, n).await {
+                Ok(loc) => Some(loc),
+                Err(e) => {
+                    let e = RpcError::Misc(format!("could not resolve replica {n}: {e}"));
+                    return Err(RpcError::Downstream(T::LABEL.to_owned(), Box::new(e)));
+                }
+            },
+            None => None,
+        };
+        let addr: &str = match &resolved {
+            Some(loc) => loc.addr(),
+            None => loc.addr(),
+        };
+        graph::record_call(T::LABEL);
+        let started = Instant::now();
 
         // TODO: not 100% sure why this box is needed but the futures types are
-        // too complicated for rustc rpc_ops! handlers for some reason and I'm
+        // too complicated for rustc rpc_component! handlers for some reason and I'm
         // choosing not to dig into it right now.
         let block: BoxFuture<'_, RpcResult<T::Response>> = Box::pin(async {
             if T::is_local()
+                && shaping::get(T::LABEL).is_none()
                 && T::myself().await.ok().as_ref().map(|x| x.addr()) == Some(addr)
                 && let Some(inner) = &self.instance
             {
-                inner.clone().await.handle(q).await
+                let res = match auth::check_local(T::LABEL) {
+                    Ok(()) => component::scope(T::LABEL, inner.clone().await.handle(q)).await,
+                    Err(e) => Err(e),
+                };
+                obs.attempt(Destination::Local, started, &res);
+                res
             } else {
-                http::http_call_at::<T>(addr, q).await
+                let call = http::http_call_at::<T>(addr, q, self.http_version);
+                let res = self.limited(call).await;
+                obs.attempt(Destination::Remote(addr), started, &res);
+                res
             }
         });
         let res = block.await;
@@ -95,14 +498,108 @@ impl<T: RpcComponentKind> RpcClient<T, Retry> {
         Self {
             retry: DEFAULT_RETRY.clone(),
             instance: T::instance().map(|x| x.boxed().shared()),
+            affinity: None,
+            shard: None,
+            unready: false,
+            recording: golden::default_path(T::LABEL),
+            http_version: None,
+            failover: None,
+            observer: None,
+            limiter: None,
+            timeouts: Timeouts::default(),
         }
     }
 }
 
 impl<T: RpcComponentKind, R: RetryStrategy<RpcError>> RpcClient<T, R> {
     /// Send a request, retrying the request according to the retry strategy.
+    /// For ops with
+    /// [single-flight][crate::config::AppBuilder::with_single_flight]
+    /// enabled, this shares the result of an identical call already in flight
+    /// instead.
     pub async fn call(&self, q: &T::Request) -> RpcResult<T::Response> {
-        crate::retry::attempt(&self.retry, || self.call_once(q)).await
+        single_flight::call::<T, _, _>(q, || self.call_unshared(q)).await
+    }
+
+    /// Send a request without regard to single-flight. See
+    /// [`call`][Self::call].
+    async fn call_unshared(&self, q: &T::Request) -> RpcResult<T::Response> {
+        let obs = Observation::client(self.observer.as_ref(), T::LABEL, q.verb());
+        let call = self.timeouts.run(T::LABEL, q.verb(), async {
+            match self.failover {
+                Some(policy) if self.is_remote() && self.shard.is_none() => {
+                    let failover = Failover::new(policy);
+                    let once = || async {
+                        graph::record_call(T::LABEL);
+                        let call = failover.attempt::<T>(
+                            q,
+                            self.affinity,
+                            self.unready,
+                            self.http_version,
+                            &obs,
+                        );
+                        self.limited(call)
+                            .await
+                            .map_err(|e| RpcError::Downstream(T::LABEL.to_owned(), Box::new(e)))
+                    };
+                    crate::retry::attempt(&self.retry, once).await
+                }
+                _ => crate::retry::attempt(&self.retry, || self.attempt(q, &obs)).await,
+            }
+        });
+        let res = sampling::scope(T::LABEL, q.verb(), context::originate(call)).await;
+        obs.complete(&res);
+        if let Some(path) = &self.recording {
+            golden::record::<T>(path, q, &res);
+        }
+        res
+    }
+
+    /// Send a request, retrying the request according to the retry strategy.
+    ///
+    /// This behaves like [`call`][Self::call], except that when the component
+    /// is running in the same process and is configured with
+    /// [`AppBuilder::with_local_dispatch`][crate::config::AppBuilder::with_local_dispatch],
+    /// the request is handled by one of the component's dispatch workers
+    /// rather than on the caller's task. Requests passed by reference can't
+    /// be handed to another task without serializing them, so `call` and the
+    /// other methods always run in-process handlers on the caller's task.
+    pub async fn call_owned(&self, q: T::Request) -> RpcResult<T::Response> {
+        let inner = match &self.instance {
+            Some(inner) if !self.is_remote() && dispatch::enabled(T::LABEL) => inner,
+            _ => return self.call(&q).await,
+        };
+        let obs = Observation::client(self.observer.as_ref(), T::LABEL, q.verb());
+        let q = Arc::new(q);
+        let call = crate::retry::attempt(&self.retry, || self.dispatch_once(inner, &q, &obs));
+        let call = self.timeouts.run(T::LABEL, q.verb(), call);
+        let res = sampling::scope(T::LABEL, q.verb(), context::originate(call)).await;
+        obs.complete(&res);
+        if let Some(path) = &self.recording {
+            golden::record::<T>(path, &q, &res);
+        }
+        res
+    }
+
+    /// Send an already serialized request, retrying the request according to
+    /// the retry strategy. See [`call_raw_once`][Self::call_raw_once].
+    pub async fn call_raw(&self, q: Bytes) -> RpcResult<Bytes> {
+        let obs = Observation::client_raw(self.observer.as_ref(), T::LABEL, &q);
+        let verb = op_name(&q).unwrap_or("unknown");
+        let call = crate::retry::attempt(&self.retry, || self.raw_attempt(&q, &obs));
+        let call = self.timeouts.run(T::LABEL, verb, call);
+        let res = sampling::scope(T::LABEL, verb, context::originate(call)).await;
+        obs.complete(&res);
+        res
+    }
+
+    /// Send a request to the replica that owns a shard, retrying the request
+    /// according to the retry strategy. See [`with_shard`][Self::with_shard].
+    pub async fn call_shard(&self, shard: u32, q: &T::Request) -> RpcResult<T::Response>
+    where
+        R: Clone,
+    {
+        self.clone().with_shard(shard).call(q).await
     }
 
     /// Send a request to a specific location, retrying the request according to
@@ -113,6 +610,84 @@ impl<T: RpcComponentKind, R: RetryStrategy<RpcError>> RpcClient<T, R> {
         A: Borrow<str>,
     {
         let loc = loc.borrow();
-        crate::retry::attempt(&self.retry, || self.call_at_once(loc, q)).await
+        let obs = Observation::client(self.observer.as_ref(), T::LABEL, q.verb());
+        let call = crate::retry::attempt(&self.retry, || self.at_attempt(loc, q, &obs));
+        let call = self.timeouts.run(T::LABEL, q.verb(), call);
+        let res = sampling::scope(T::LABEL, q.verb(), context::originate(call)).await;
+        obs.complete(&res);
+        if let Some(path) = &self.recording {
+            golden::record::<T>(path, q, &res);
+        }
+        res
+    }
+
+    /// Subscribe to a subscription op, given a request of the component's
+    /// `Subscribe` enum. When the component is running in the same process,
+    /// the subscription reads from the handler's topic directly. Otherwise
+    /// the initial connection is retried according to the retry strategy,
+    /// and the subscription reconnects on its own after that. See
+    /// [`Subscription`].
+    pub async fn subscribe<Q, X>(&self, q: &Q) -> RpcResult<Subscription<X>>
+    where
+        Q: Serialize,
+        X: Clone + DeserializeOwned + Send + 'static,
+    {
+        graph::record_call(T::LABEL);
+        let q = buf::to_json_bytes(q)?;
+        let res = match &self.instance {
+            Some(inner) if !self.is_remote() => match auth::check_local(T::LABEL) {
+                Ok(()) => {
+                    let inner = inner.clone().await;
+                    match component::scope(T::LABEL, inner.subscribe(&q)).await {
+                        Ok(topic) => match topic.as_any().downcast_ref::<Topic<X>>() {
+                            Some(topic) => Ok(topic.subscribe()),
+                            None => Err(RpcError::Misc(
+                                "subscription event type mismatch".to_owned(),
+                            )),
+                        },
+                        Err(e) => Err(e),
+                    }
+                }
+                Err(e) => Err(e),
+            },
+            _ => {
+                let connect = || {
+                    Subscription::remote::<T>(
+                        q.clone(),
+                        self.affinity,
+                        self.shard,
+                        self.unready,
+                        self.http_version,
+                    )
+                };
+                crate::retry::attempt(&self.retry, connect).await
+            }
+        };
+        res.map_err(|e| RpcError::Downstream(T::LABEL.to_owned(), Box::new(e)))
+    }
+
+    /// Make a call to every running replica of the component concurrently,
+    /// returning the location and result of each. `f` is given each
+    /// location in turn to make the call, e.g. with
+    /// [`call_at`][Self::call_at].
+    pub async fn for_each_replica<F, Fut, X>(
+        &self,
+        f: F,
+    ) -> RpcResult<Vec<(Location, RpcResult<X>)>>
+    where
+        F: Fn(Location) -> Fut,
+        Fut: Future<Output = RpcResult<X>>,
+    {
+        let replicas = match self.unready {
+            true => T::discover_all_replicas().await,
+            false => T::discover_replicas().await,
+        };
+        let replicas =
+            replicas.map_err(|e| RpcError::Misc(format!("could not discover endpoint: {e}")))?;
+        let calls = replicas.into_iter().map(|r| r.location).map(|loc| {
+            let call = f(loc.clone());
+            async move { (loc, call.await) }
+        });
+        Ok(futures::future::join_all(calls).await)
     }
 }
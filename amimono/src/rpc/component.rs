@@ -4,13 +4,14 @@ use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    component::{Component, ComponentKind},
-    rpc::{RpcResult, http},
+    component::{BindingDecl, Component, ComponentKind, LocalDependency},
+    rpc::{RpcError, RpcResult, http, observe, subscribe::DynTopic},
+    schema,
 };
 
 /// A type that can be used as an RPC request or response.
 ///
-/// Message types created by the [`rpc_ops!`][crate::rpc_ops] macro are
+/// Message types created by the [`rpc_component!`][crate::rpc_component] macro are
 /// automatically given an `RpcMessage` impl.
 pub trait RpcMessage: Serialize + for<'a> Deserialize<'a> + Send + Sync + 'static {
     fn verb(&self) -> &'static str;
@@ -25,13 +26,151 @@ pub trait RpcComponentKind: 'static {
     type Response: RpcMessage;
 
     const LABEL: &'static str;
+
+    /// The operations in the component's API.
+    const OPS: &'static [RpcOp] = &[];
+
+    /// The subscription ops in the component's API, whose `ret` is the
+    /// type of their events. These aren't included in `OPS`.
+    const SUBSCRIPTIONS: &'static [RpcOp] = &[];
+
+    /// The labels of the components and tools allowed to call this
+    /// component, or `None` if anything can call it.
+    const ALLOWED_CALLERS: Option<&'static [&'static str]> = None;
+
+    /// The storage the component needs, if it's stateful. Refer to
+    /// [`ComponentKind::STORAGE`] for details.
+    const STORAGE: Option<usize> = None;
+}
+
+/// The signature of an RPC operation, as written in the
+/// [`rpc_component!`][crate::rpc_component] invocation, with the JSON Schemas of
+/// its types where they implement [`JsonSchema`][schemars::JsonSchema].
+#[derive(Copy, Clone, Debug)]
+pub struct RpcOp {
+    pub name: &'static str,
+    pub args: &'static [&'static str],
+    /// The names of the args, as written, for generated clients.
+    pub arg_names: &'static [&'static str],
+    pub ret: &'static str,
+    /// Whether callers must be prepared for the op to be unimplemented.
+    pub optional: bool,
+    /// How the op's requests are scheduled when the server is busy.
+    pub priority: Priority,
+    /// The route the op is exposed on by the
+    /// [gateway][crate::gateway], as a method and path, e.g.
+    /// `POST /v1/add`.
+    pub http: Option<&'static str>,
+    /// Generates the JSON Schemas of the args and return type, which are
+    /// compared structurally by [schema checks][crate::schema] and
+    /// `ammn compat`. Types without one are compared by name.
+    pub schemas: fn() -> RpcOpSchemas,
+}
+
+// by hand, since the schemas are generated by a function, whose address
+// means nothing
+impl RpcOp {
+    fn key(&self) -> impl PartialEq + std::hash::Hash + '_ {
+        let RpcOp {
+            name,
+            args,
+            arg_names,
+            ret,
+            optional,
+            priority,
+            http,
+            schemas: _,
+        } = self;
+        (name, args, arg_names, ret, optional, priority, http)
+    }
+}
+
+impl PartialEq for RpcOp {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for RpcOp {}
+
+impl std::hash::Hash for RpcOp {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+/// The JSON Schemas of an op's types, or `None` for types that don't
+/// implement [`JsonSchema`][schemars::JsonSchema].
+pub struct RpcOpSchemas {
+    pub args: Vec<Option<serde_json::Value>>,
+    pub ret: Option<serde_json::Value>,
+}
+
+/// Gets the JSON Schema of `T` in the code generated by
+/// [`rpc_component!`][crate::rpc_component], if it has one, by calling
+/// `json_schema()` on a `&SchemaOf<T>` with [`HasJsonSchema`] and
+/// [`NoJsonSchema`] in scope. Method resolution picks the former when `T`
+/// implements `JsonSchema`, since it takes the reference as is.
+#[doc(hidden)]
+pub struct SchemaOf<T: ?Sized>(std::marker::PhantomData<T>);
+
+impl<T: ?Sized> SchemaOf<T> {
+    pub const fn new() -> Self {
+        SchemaOf(std::marker::PhantomData)
+    }
+}
+
+impl<T: ?Sized> Default for SchemaOf<T> {
+    fn default() -> Self {
+        SchemaOf::new()
+    }
+}
+
+#[doc(hidden)]
+pub trait HasJsonSchema {
+    fn json_schema(&self) -> Option<serde_json::Value>;
+}
+
+impl<T: schemars::JsonSchema + ?Sized> HasJsonSchema for SchemaOf<T> {
+    fn json_schema(&self) -> Option<serde_json::Value> {
+        Some(schemars::schema_for!(T).to_value())
+    }
+}
+
+#[doc(hidden)]
+pub trait NoJsonSchema {
+    fn json_schema(&self) -> Option<serde_json::Value>;
+}
+
+impl<T: ?Sized> NoJsonSchema for &SchemaOf<T> {
+    fn json_schema(&self) -> Option<serde_json::Value> {
+        None
+    }
+}
+
+/// The priority class of an op, which decides how its requests are
+/// scheduled by components with a
+/// [concurrency limit][crate::config::AppBuilder::with_concurrency_limit].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Priority {
+    /// Requests someone is waiting on, which are handled first.
+    Interactive,
+    /// Long-running or bulk requests, which only get part of the server's
+    /// capacity and are shed first.
+    Batch,
 }
 
 impl<T: RpcComponentKind> ComponentKind for T {
     type Instance = Arc<dyn RpcInstance<Self>>;
 
     const LABEL: &'static str = T::LABEL;
-    const PORTS: &'static [u16] = &[http::PORT];
+    const RPC_OPS: Option<&'static [RpcOp]> = Some(T::OPS);
+    const ALLOWED_CALLERS: Option<&'static [&'static str]> = T::ALLOWED_CALLERS;
+    const STORAGE: Option<usize> = T::STORAGE;
+
+    fn bindings() -> Vec<BindingDecl> {
+        vec![BindingDecl::rpc(http::PORT)]
+    }
 }
 
 /// An RPC component's instance, used as a trait object.
@@ -40,6 +179,11 @@ pub trait RpcInstance<T: RpcComponentKind>: Send + Sync {
     where
         'i: 'f,
         'q: 'f;
+
+    fn subscribe<'i, 'q, 'f>(&'i self, q: &'q [u8]) -> BoxFuture<'f, RpcResult<Arc<dyn DynTopic>>>
+    where
+        'i: 'f,
+        'q: 'f;
 }
 
 /// A type implementing an RPC component.
@@ -51,10 +195,36 @@ pub trait RpcComponent: Send + Sync + 'static {
 
     fn start() -> impl Future<Output = Self> + Send;
 
+    /// Declare the local components this component uses. Refer to
+    /// [`Component::local_dependencies`] for details.
+    fn local_dependencies() -> Vec<LocalDependency> {
+        Vec::new()
+    }
+
+    /// Warm up the component after it starts, before it's reported ready.
+    /// The instance isn't available to other components yet, so warmup
+    /// requests to the component itself should be made through `self`. If
+    /// warmup fails, the component becomes ready anyway.
+    fn warmup(&self) -> impl Future<Output = RpcResult<()>> + Send {
+        async { Ok(()) }
+    }
+
     fn handle(
         &self,
         q: &<Self::Kind as RpcComponentKind>::Request,
     ) -> impl Future<Output = RpcResult<<Self::Kind as RpcComponentKind>::Response>> + Send;
+
+    /// Open a subscription, given the JSON form of the component's
+    /// `Subscribe` enum, returning the op's [`Topic`][crate::rpc::Topic].
+    fn subscribe(&self, q: &[u8]) -> impl Future<Output = RpcResult<Arc<dyn DynTopic>>> + Send {
+        let op = observe::op_name(q).unwrap_or("unknown").to_owned();
+        async move {
+            Err(RpcError::Unimplemented {
+                component: <Self::Kind as RpcComponentKind>::LABEL.to_owned(),
+                op,
+            })
+        }
+    }
 }
 
 impl<T: RpcComponent> RpcInstance<T::Kind> for T {
@@ -68,20 +238,40 @@ impl<T: RpcComponent> RpcInstance<T::Kind> for T {
     {
         Box::pin(RpcComponent::handle(self, q))
     }
+
+    fn subscribe<'i, 'q, 'f>(&'i self, q: &'q [u8]) -> BoxFuture<'f, RpcResult<Arc<dyn DynTopic>>>
+    where
+        'i: 'f,
+        'q: 'f,
+    {
+        Box::pin(RpcComponent::subscribe(self, q))
+    }
 }
 
 impl<T: RpcComponent> Component for T {
     type Kind = T::Kind;
 
+    fn local_dependencies() -> Vec<LocalDependency> {
+        <T as RpcComponent>::local_dependencies()
+    }
+
     fn main<F>(set_instance: F) -> impl Future<Output = ()> + Send
     where
         F: FnOnce(<Self::Kind as ComponentKind>::Instance) -> BoxFuture<'static, ()> + Send,
     {
         Box::pin(async {
             let instance = Arc::new(T::start().await);
+            if let Err(e) = instance.warmup().await {
+                log::warn!(
+                    "{}: warmup failed: {}",
+                    <Self::Kind as ComponentKind>::LABEL,
+                    e
+                );
+            }
             set_instance(instance.clone()).await;
             let handler = Arc::new(http::DefaultHttpInstance::<T::Kind>(instance.clone()));
             http::HTTP_HANDLERS.insert(<Self::Kind as ComponentKind>::LABEL, handler);
+            schema::publish::<T::Kind>();
             http::HTTP_SERVER.clone().await;
         })
     }
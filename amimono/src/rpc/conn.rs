@@ -0,0 +1,121 @@
+//! Connection accounting for the RPC server.
+//!
+//! The number of connections accepted, and how many requests arrive over
+//! each HTTP version, show whether multiplexing is working: with HTTP/2
+//! between jobs, the requests per connection should go up sharply.
+
+use std::{
+    io,
+    pin::Pin,
+    sync::atomic::{AtomicI64, Ordering},
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::metrics;
+
+static OPEN: AtomicI64 = AtomicI64::new(0);
+
+fn set_open(delta: i64) {
+    let open = OPEN.fetch_add(delta, Ordering::Relaxed) + delta;
+    metrics::gauge("amimono_http_connections_open", &[]).set(open as f64);
+}
+
+/// A listener that counts the connections it accepts.
+pub(crate) struct CountingListener(pub(crate) TcpListener);
+
+/// A connection that's counted as open until it's dropped.
+pub(crate) struct Counted(TcpStream);
+
+impl axum::serve::Listener for CountingListener {
+    type Io = Counted;
+    type Addr = std::net::SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (io, addr) = axum::serve::Listener::accept(&mut self.0).await;
+        metrics::counter("amimono_http_connections_accepted", &[]).inc();
+        set_open(1);
+        (Counted(io), addr)
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.0.local_addr()
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        set_open(-1);
+    }
+}
+
+impl AsyncRead for Counted {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Counted {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.0.is_write_vectored()
+    }
+}
+
+fn version_label(version: axum::http::Version) -> &'static str {
+    match version {
+        axum::http::Version::HTTP_09 => "0.9",
+        axum::http::Version::HTTP_10 => "1.0",
+        axum::http::Version::HTTP_11 => "1.1",
+        axum::http::Version::HTTP_2 => "2",
+        axum::http::Version::HTTP_3 => "3",
+        _ => "unknown",
+    }
+}
+
+/// Count a request received by the server.
+pub(crate) async fn count_request(
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let version = version_label(req.version());
+    metrics::counter("amimono_http_requests_received", &[("version", version)]).inc();
+    next.run(req).await
+}
+
+/// Count a response received by a client.
+pub(crate) fn count_response(version: axum::http::Version) {
+    let version = version_label(version);
+    metrics::counter("amimono_http_responses_received", &[("version", version)]).inc();
+}
@@ -0,0 +1,112 @@
+//! Dispatch queues for in-process calls.
+//!
+//! In-process calls normally run the callee's handler on the caller's task,
+//! so a burst of internal calls competes with everything else on the runtime
+//! and is never turned away. Components configured with
+//! [`AppBuilder::with_local_dispatch`][crate::config::AppBuilder::with_local_dispatch]
+//! instead get a bounded queue served by a fixed pool of workers. Requests are
+//! still passed without serialization. When the queue is full, calls fail
+//! with a spurious error right away, so callers back off and retry like they
+//! would against an overloaded replica.
+
+use std::{
+    collections::HashMap,
+    panic::AssertUnwindSafe,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use futures::{FutureExt, future::BoxFuture};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    AppError, metrics,
+    rpc::{RpcError, RpcResult},
+    runtime,
+};
+
+type Job = BoxFuture<'static, ()>;
+
+struct Dispatcher {
+    tx: mpsc::Sender<Job>,
+    queue: usize,
+}
+
+static DISPATCHERS: LazyLock<Mutex<HashMap<&'static str, Arc<Dispatcher>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Whether in-process calls to the component go through a dispatch queue.
+pub(crate) fn enabled(label: &str) -> bool {
+    runtime::config().local_dispatch(label).is_some()
+}
+
+/// The component's dispatcher, starting its workers on first use.
+fn dispatcher(label: &'static str) -> Option<Arc<Dispatcher>> {
+    let mut dispatchers = DISPATCHERS.lock().expect("lock poisoned");
+    if let Some(d) = dispatchers.get(label) {
+        return Some(d.clone());
+    }
+
+    let cf = runtime::config().local_dispatch(label)?.clone();
+    let (tx, rx) = mpsc::channel::<Job>(cf.queue);
+    let rx = Arc::new(tokio::sync::Mutex::new(rx));
+    for _ in 0..cf.workers {
+        let rx = rx.clone();
+        tokio::spawn(async move {
+            loop {
+                let job = match rx.lock().await.recv().await {
+                    Some(job) => job,
+                    None => break,
+                };
+                if AssertUnwindSafe(job).catch_unwind().await.is_err() {
+                    log::error!("{} handler panicked in local dispatch worker", label);
+                }
+            }
+        });
+    }
+    log::debug!(
+        "started {} local dispatch workers for {}, queue size {}",
+        cf.workers,
+        label,
+        cf.queue
+    );
+
+    let d = Arc::new(Dispatcher {
+        tx,
+        queue: cf.queue,
+    });
+    dispatchers.insert(label, d.clone());
+    Some(d)
+}
+
+/// Run a call on one of the component's dispatch workers, or fail with a
+/// spurious error if its queue is full.
+pub(crate) async fn run<O, F>(label: &'static str, fut: F) -> RpcResult<O>
+where
+    O: Send + 'static,
+    F: Future<Output = RpcResult<O>> + Send + 'static,
+{
+    let d = match dispatcher(label) {
+        Some(d) => d,
+        None => return fut.await,
+    };
+
+    let (res_tx, res_rx) = oneshot::channel();
+    let job: Job = Box::pin(async move {
+        let _ = res_tx.send(fut.await);
+    });
+    let labels = [("component", label)];
+    if d.tx.try_send(job).is_err() {
+        metrics::counter("amimono_local_dispatch_rejected", &labels).inc();
+        return Err(RpcError::Spurious(format!(
+            "local dispatch queue for {} is full",
+            label
+        )));
+    }
+    let waiting = d.queue - d.tx.capacity();
+    metrics::gauge("amimono_local_dispatch_queued", &labels).set(waiting as f64);
+
+    match res_rx.await {
+        Ok(res) => res,
+        Err(_) => Err(AppError::spurious("handler panicked")),
+    }
+}
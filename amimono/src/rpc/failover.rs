@@ -0,0 +1,139 @@
+//! Failover between replicas within a single logical call.
+//!
+//! By default, every attempt at a call picks a replica independently, so a
+//! retry after a replica fails can easily land on the same replica again.
+//! With a [`FailoverPolicy`], the first attempt picks a replica as usual, and
+//! each retry after a retryable error moves on to the next replica in the
+//! list discovered for the first attempt, wrapping around if every replica
+//! has been tried.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    component::{Location, Replica},
+    rpc::{
+        RpcComponentKind, RpcError, RpcResult, http,
+        observe::{Destination, Observation},
+        outlier,
+    },
+};
+
+/// How a client moves between replicas when it retries a call.
+///
+/// Failover only applies to calls made with `call`, and has no effect when
+/// the component is running in the same process or when calls are made to a
+/// specific location.
+#[derive(Copy, Clone, Debug)]
+pub struct FailoverPolicy {
+    suspect: Option<Duration>,
+}
+
+impl FailoverPolicy {
+    /// Try the discovered replicas in turn on retries.
+    pub const fn round_robin() -> FailoverPolicy {
+        FailoverPolicy { suspect: None }
+    }
+
+    /// Also mark a replica that fails with a connection error, timeout, or
+    /// spurious error, or that sheds the call, as suspect for the given time,
+    /// leaving it out of the replicas this process balances calls across.
+    /// This is a stronger reaction than outlier ejection, which waits for
+    /// several failures.
+    pub const fn with_suspect(self, time: Duration) -> FailoverPolicy {
+        FailoverPolicy {
+            suspect: Some(time),
+        }
+    }
+}
+
+/// The replicas a logical call moves through, and the one it's on.
+struct Rotation {
+    replicas: Vec<Location>,
+    next: usize,
+}
+
+/// The failover state of a single logical call.
+pub(crate) struct Failover {
+    policy: FailoverPolicy,
+    rotation: Mutex<Option<Rotation>>,
+}
+
+impl Failover {
+    pub(crate) fn new(policy: FailoverPolicy) -> Failover {
+        Failover {
+            policy,
+            rotation: Mutex::new(None),
+        }
+    }
+
+    /// The location for the next attempt. The first attempt discovers the
+    /// component's replicas and picks one the usual way, later attempts take
+    /// the replicas after it in order.
+    async fn next<T: RpcComponentKind>(
+        &self,
+        affinity: Option<u64>,
+        unready: bool,
+    ) -> RpcResult<Location> {
+        if let Some(rot) = self.rotation.lock().expect("lock poisoned").as_mut() {
+            let loc = rot.replicas[rot.next % rot.replicas.len()].clone();
+            rot.next += 1;
+            return Ok(loc);
+        }
+
+        let mut replicas: Vec<Replica> = http::balanced_replicas::<T>(unready).await?;
+        replicas.retain(|r| r.weight > 0);
+        replicas.sort_by(|a, b| a.location.addr::<str>().cmp(b.location.addr()));
+        let first = match http::choose_replica(&replicas, affinity) {
+            Some(r) => r.location.clone(),
+            None => return Err(RpcError::Misc("discovery endpoints empty".to_owned())),
+        };
+        let start = replicas
+            .iter()
+            .position(|r| r.location == first)
+            .unwrap_or(0);
+        let replicas = replicas.into_iter().map(|r| r.location).collect();
+        *self.rotation.lock().expect("lock poisoned") = Some(Rotation {
+            replicas,
+            next: start + 1,
+        });
+        Ok(first)
+    }
+
+    /// Make one attempt at a call over HTTP.
+    pub(crate) async fn attempt<T: RpcComponentKind>(
+        &self,
+        q: &T::Request,
+        affinity: Option<u64>,
+        unready: bool,
+        version: Option<http::HttpVersion>,
+        obs: &Observation,
+    ) -> RpcResult<T::Response> {
+        let started = Instant::now();
+        let loc = match self.next::<T>(affinity, unready).await {
+            Ok(loc) => loc,
+            Err(e) => {
+                let res = Err(e);
+                obs.attempt(Destination::Unresolved, started, &res);
+                return res;
+            }
+        };
+        let addr: &str = loc.addr();
+        let res = http::http_call_at::<T>(addr, q, version).await;
+        obs.attempt(Destination::Remote(addr), started, &res);
+        if let (
+            Err(
+                RpcError::Spurious(_)
+                | RpcError::RequestTimeout { .. }
+                | RpcError::Overloaded { .. },
+            ),
+            Some(time),
+        ) = (&res, self.policy.suspect)
+        {
+            outlier::suspect(T::LABEL, addr, time);
+        }
+        res
+    }
+}
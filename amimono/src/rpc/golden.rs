@@ -0,0 +1,219 @@
+//! Golden traffic, for contract testing RPC components.
+//!
+//! Clients can record the requests they send and the responses they get to
+//! fixture files, either by setting `AMIMONO_GOLDEN_DIR` to a directory, which
+//! records every client's calls to `{dir}/{label}.jsonl`, or with a client's
+//! `with_recording` method. This is meant for integration runs, e.g. an
+//! end-to-end test suite run against `--local`, to capture how components
+//! actually behave.
+//!
+//! [`verify`] replays a fixture file against a handler in a unit test and
+//! reports any op whose response differs from the recorded one, which catches
+//! changes to a component's behavior that its callers may depend on:
+//!
+//! ```ignore
+//! #[tokio::test]
+//! async fn adder_contract() {
+//!     let adder = ops::Component::from_handler(Adder::new().await);
+//!     golden::verify::<ops::ComponentKind, _>(&adder, "tests/golden/adder.jsonl")
+//!         .await
+//!         .assert_ok();
+//! }
+//! ```
+//!
+//! Each line of a fixture file is a JSON object with the op, the request, and
+//! either the response or the error. Request journals written by
+//! [`journal`][crate::rpc::journal] have the same shape, so they can be used
+//! as fixtures too.
+
+use std::{
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::rpc::{
+    RpcComponentKind, RpcError, RpcMessage, RpcResult, component::RpcInstance, journal,
+};
+
+/// A recorded call.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    op: String,
+    q: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ok: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    err: Option<RpcError>,
+}
+
+static DIR: LazyLock<Option<PathBuf>> =
+    LazyLock::new(|| std::env::var_os("AMIMONO_GOLDEN_DIR").map(PathBuf::from));
+
+/// Serializes appends, so that lines from concurrent calls don't interleave.
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Where a new client for the component should record its calls, if
+/// anywhere.
+pub(crate) fn default_path(label: &str) -> Option<Arc<Path>> {
+    DIR.as_ref()
+        .map(|dir| Arc::from(dir.join(format!("{label}.jsonl"))))
+}
+
+/// Append a call to a fixture file. The write is synchronous, so that calls
+/// recorded right before the process exits aren't lost.
+pub(crate) fn record<K: RpcComponentKind>(
+    path: &Path,
+    q: &K::Request,
+    res: &RpcResult<K::Response>,
+) {
+    let res = (|| {
+        let (ok, err) = match res {
+            Ok(a) => (Some(serde_json::to_value(a)?), None),
+            // calls are recorded from the client side, where errors are
+            // wrapped with the component's label
+            Err(RpcError::Downstream(label, e)) if label == K::LABEL => (None, Some((**e).clone())),
+            Err(e) => (None, Some(e.clone())),
+        };
+        let record = Record {
+            op: q.verb().to_owned(),
+            q: serde_json::to_value(q)?,
+            ok,
+            err,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+
+        let _guard = WRITE_LOCK.lock().expect("lock poisoned");
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(&line)?;
+        Ok::<_, RpcError>(())
+    })();
+    if let Err(e) = res {
+        log::warn!(
+            "failed to record call to {} in {}: {e}",
+            K::LABEL,
+            path.display()
+        );
+    }
+}
+
+/// The result of replaying a fixture file against a handler.
+#[derive(Debug, Default)]
+pub struct GoldenReport {
+    /// The number of recorded calls that were replayed.
+    pub checked: usize,
+
+    /// The calls whose responses differed from the recorded ones.
+    pub mismatches: Vec<GoldenMismatch>,
+}
+
+/// A replayed call whose response differed from the recorded one.
+#[derive(Debug)]
+pub struct GoldenMismatch {
+    /// The line of the fixture file the call was recorded on, starting at 1.
+    pub line: usize,
+    pub op: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl GoldenReport {
+    /// Whether every call matched.
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    /// Panic with a description of every mismatch, if there are any.
+    pub fn assert_ok(&self) {
+        if !self.is_ok() {
+            panic!("{self}");
+        }
+    }
+}
+
+impl fmt::Display for GoldenReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} golden calls differed",
+            self.mismatches.len(),
+            self.checked
+        )?;
+        for m in self.mismatches.iter() {
+            write!(
+                f,
+                "\n  line {} ({}): expected {}, got {}",
+                m.line, m.op, m.expected, m.actual
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Replay the calls recorded in a fixture file against a handler, comparing
+/// its responses to the recorded ones. Requests that no longer parse as the
+/// component's `Request` type count as mismatches. Panics if the file can't
+/// be read.
+pub async fn verify<K, I>(handler: &I, path: impl AsRef<Path>) -> GoldenReport
+where
+    K: RpcComponentKind,
+    I: RpcInstance<K> + ?Sized,
+{
+    let path = path.as_ref();
+    let fixtures = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("could not read golden file {}: {e}", path.display()));
+
+    let mut report = GoldenReport::default();
+    for (i, line) in fixtures.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = match serde_json::from_str::<Record>(line) {
+            Ok(r) => r,
+            Err(e) => panic!("invalid golden record at {}:{}: {e}", path.display(), i + 1),
+        };
+        report.checked += 1;
+        let recorded = (record.ok, record.err);
+        let expected = journal::describe(&recorded);
+
+        let q = match serde_json::from_value::<K::Request>(record.q) {
+            Ok(q) => q,
+            Err(e) => {
+                report.mismatches.push(GoldenMismatch {
+                    line: i + 1,
+                    op: record.op,
+                    expected,
+                    actual: format!("request no longer parses: {e}"),
+                });
+                continue;
+            }
+        };
+        let got = match handler.handle(&q).await {
+            Ok(a) => (serde_json::to_value(&a).ok(), None),
+            Err(e) => (None, Some(e)),
+        };
+        let errors_match = match (&got.1, &recorded.1) {
+            (Some(a), Some(b)) => serde_json::to_value(a).ok() == serde_json::to_value(b).ok(),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        if got.0 != recorded.0 || !errors_match {
+            report.mismatches.push(GoldenMismatch {
+                line: i + 1,
+                op: record.op,
+                expected,
+                actual: journal::describe(&got),
+            });
+        }
+    }
+    report
+}
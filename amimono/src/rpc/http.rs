@@ -1,22 +1,87 @@
-use std::{net::SocketAddr, sync::LazyLock, time::Duration};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    hash::Hasher,
+    net::SocketAddr,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
 
+use amimono_schemas::DumpServerInfo;
+use axum::{body::Bytes, response::IntoResponse};
+use fnv::FnvHasher;
 use futures::{
-    FutureExt,
+    FutureExt, StreamExt,
     future::{BoxFuture, Shared},
 };
 use rand::seq::IndexedRandom;
+use tokio::sync::mpsc;
 
 use crate::{
-    component::ComponentKind,
-    rpc::{RpcComponentKind, RpcError, RpcResult},
+    component::{self, ComponentKind, Location, Replica},
+    context,
+    rpc::{
+        RpcComponentKind, RpcError, RpcResult, auth, buf, capture, conn, journal,
+        observe::{self, Observation},
+        outlier, priority,
+        progress::{self, ProgressSender, ProgressUpdate},
+        quota, ramp, raw, sampling,
+        seal::{self, Part, Seal},
+        shaping,
+        subscribe::DynTopic,
+        timeout,
+    },
+    schema, standby,
     util::StaticHashMap,
 };
 
 /// The port used for the RPC HTTP server
 pub const PORT: u16 = 9099;
 
+/// The version of the protocol between RPC clients and servers, which is
+/// increased when a change to how requests are sent would break older
+/// processes. Servers report it from `GET /rpc/_info`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// How long a replica's `/rpc/_info` is cached by clients.
+const SERVER_INFO_TTL: Duration = Duration::from_secs(60);
+
+/// How often servers send a keep-alive on a stream of server-sent events
+/// that's otherwise quiet.
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// How long a client waits for anything on a stream of server-sent events,
+/// a few keep-alives' worth, before giving up on the server.
+const SSE_IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// The HTTP version used for RPC requests between jobs.
+///
+/// The RPC server always accepts both HTTP/1.1 and, when amimono is built
+/// with the `http2` feature, HTTP/2 with prior knowledge. Switching clients to
+/// HTTP/2 is safe once every job has been deployed with the feature.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// HTTP/1.1, which needs a connection per concurrent request.
+    #[default]
+    Http1,
+
+    /// HTTP/2 with prior knowledge, i.e. without negotiating an upgrade,
+    /// which multiplexes concurrent requests over a single connection per
+    /// replica. This requires the `http2` feature, and falls back to
+    /// HTTP/1.1 with a warning without it.
+    Http2,
+}
+
 pub trait HttpInstance: Send + Sync + 'static {
-    fn handle_json<'h, 'q, 'f>(&'h self, q: &'q [u8]) -> BoxFuture<'f, RpcResult<Vec<u8>>>
+    fn handle_json<'h, 'q, 'f>(&'h self, q: &'q [u8]) -> BoxFuture<'f, RpcResult<Bytes>>
+    where
+        'h: 'f,
+        'q: 'f;
+
+    fn subscribe_json<'h, 'q, 'f>(
+        &'h self,
+        q: &'q [u8],
+    ) -> BoxFuture<'f, RpcResult<Arc<dyn DynTopic>>>
     where
         'h: 'f,
         'q: 'f;
@@ -25,23 +90,46 @@ pub trait HttpInstance: Send + Sync + 'static {
 pub struct DefaultHttpInstance<T: RpcComponentKind>(pub <T as ComponentKind>::Instance);
 
 impl<T: RpcComponentKind> HttpInstance for DefaultHttpInstance<T> {
-    fn handle_json<'h, 'q, 'f>(&'h self, q: &'q [u8]) -> BoxFuture<'f, RpcResult<Vec<u8>>>
+    fn handle_json<'h, 'q, 'f>(&'h self, q: &'q [u8]) -> BoxFuture<'f, RpcResult<Bytes>>
     where
         'h: 'f,
         'q: 'f,
     {
-        Box::pin(async {
+        Box::pin(component::scope(T::LABEL, async {
+            if let Some(res) = raw::dispatch(T::LABEL, q).await {
+                return res;
+            }
             let q = match serde_json::from_slice::<T::Request>(q) {
                 Ok(q) => q,
-                Err(e) => Err(RpcError::Misc(format!("request parse error: {e}")))?,
+                Err(e) => match observe::op_name(q) {
+                    // e.g. a newer revision calling an op this one doesn't have
+                    Some(op) if !T::OPS.iter().any(|x| x.name == op) => {
+                        Err(RpcError::Unimplemented {
+                            component: T::LABEL.to_owned(),
+                            op: op.to_owned(),
+                        })?
+                    }
+                    _ => Err(RpcError::Misc(format!("request parse error: {e}")))?,
+                },
             };
             let a = self.0.handle(&q).await?;
-            let res = match serde_json::to_vec(&a) {
+            let res = match buf::to_json_bytes(&a) {
                 Ok(res) => res,
                 Err(e) => Err(RpcError::Misc(format!("serialization failed: {e}")))?,
             };
             Ok(res)
-        })
+        }))
+    }
+
+    fn subscribe_json<'h, 'q, 'f>(
+        &'h self,
+        q: &'q [u8],
+    ) -> BoxFuture<'f, RpcResult<Arc<dyn DynTopic>>>
+    where
+        'h: 'f,
+        'q: 'f,
+    {
+        Box::pin(component::scope(T::LABEL, self.0.subscribe(q)))
     }
 }
 
@@ -58,56 +146,886 @@ static HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
     reqwest::Client::new()
 });
 
+#[cfg(feature = "http2")]
+static HTTP2_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    log::debug!("created global reqwest HTTP/2 client");
+    reqwest::Client::builder()
+        .http2_prior_knowledge()
+        .build()
+        .expect("failed to build HTTP/2 client")
+});
+
+/// The shared client for an HTTP version, or the app's default version if
+/// none is given.
+fn http_client(version: Option<HttpVersion>) -> &'static reqwest::Client {
+    match version.unwrap_or_else(|| crate::runtime::config().http_version()) {
+        HttpVersion::Http1 => &HTTP_CLIENT,
+        #[cfg(feature = "http2")]
+        HttpVersion::Http2 => &HTTP2_CLIENT,
+        #[cfg(not(feature = "http2"))]
+        HttpVersion::Http2 => {
+            static WARNED: std::sync::Once = std::sync::Once::new();
+            WARNED.call_once(|| {
+                log::warn!("HTTP/2 requested, but amimono was built without the http2 feature")
+            });
+            &HTTP_CLIENT
+        }
+    }
+}
+
+fn request_context(headers: &axum::http::HeaderMap) -> context::RequestContext {
+    let ctx = match headers.get(context::HEADER) {
+        Some(v) => context::from_header(v.as_bytes()),
+        None => Default::default(),
+    };
+    match headers.get(context::BUDGET_HEADER) {
+        Some(v) => context::with_budget(ctx, v.as_bytes()),
+        None => ctx,
+    }
+}
+
+fn with_request_context(mut req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    if let Some(ctx) = context::to_header() {
+        match reqwest::header::HeaderValue::from_bytes(&ctx) {
+            Ok(v) => req = req.header(context::HEADER, v),
+            Err(e) => log::warn!("could not propagate request context: {e}"),
+        }
+    }
+    if let Some(budget) = context::to_budget_header() {
+        req = req.header(context::BUDGET_HEADER, budget);
+    }
+    if let Some(caller) = auth::identity() {
+        req = req.header(auth::HEADER, caller);
+    }
+    crate::shard::with_headers(req)
+}
+
+/// Fail fast if the current deadline has already passed, rather than sending
+/// a request the callee would reject.
+fn check_deadline(label: &str) -> RpcResult<()> {
+    match context::remaining() {
+        Some(r) if r.is_zero() => Err(RpcError::DeadlineExceeded(label.to_owned())),
+        _ => Ok(()),
+    }
+}
+
+/// The timeout for a single attempt at a request, which is shortened to fit
+/// within the current deadline.
+fn attempt_timeout() -> Duration {
+    let timeout = timeout::request();
+    match context::remaining() {
+        Some(r) => timeout.min(r),
+        None => timeout,
+    }
+}
+
+/// Handle a request once the component has room for it, if its caller is
+/// within its quota. Time spent waiting for room counts against the
+/// request's deadline.
+async fn dispatch(
+    label: &str,
+    caller: Option<&str>,
+    h: &dyn HttpInstance,
+    q: &[u8],
+) -> RpcResult<Bytes> {
+    let _quota = quota::admit(label, caller)?;
+    let priority = priority::priority(label, observe::op_name(q));
+    let _permit = priority::admit(label, priority).await?;
+    h.handle_json(q).await
+}
+
+fn check_caller(label: &str, headers: &axum::http::HeaderMap) -> RpcResult<Option<String>> {
+    auth::check_header(label, headers.get(auth::HEADER).map(|v| v.as_bytes()))
+}
+
+/// What this process serves, for clients checking what they can call.
+fn local_server_info() -> DumpServerInfo {
+    let cf = crate::runtime::config();
+    let components = cf
+        .jobs()
+        .flat_map(|job| job.components())
+        .filter(|c| HTTP_HANDLERS.get(c.label.as_str()).is_some())
+        .map(|c| {
+            let ops = c.rpc_ops.unwrap_or_default().iter();
+            (c.label.clone(), ops.map(|op| op.name.to_owned()).collect())
+        })
+        .collect();
+    DumpServerInfo {
+        revision: cf.revision().to_owned(),
+        protocol_version: PROTOCOL_VERSION,
+        components,
+    }
+}
+
+static SERVER_INFO: LazyLock<Mutex<HashMap<String, (Instant, DumpServerInfo)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Ask the process at `addr` which revision and protocol version it runs,
+/// and which RPC components and ops it serves. Answers are cached for a
+/// minute, so this is cheap to call before using a replica.
+pub async fn server_info(addr: &str) -> RpcResult<DumpServerInfo> {
+    if let Some((at, info)) = SERVER_INFO.lock().expect("lock poisoned").get(addr)
+        && at.elapsed() < SERVER_INFO_TTL
+    {
+        return Ok(info.clone());
+    }
+    let url = format!("http://{}:{}/rpc/_info", addr, PORT);
+    let resp = HTTP_CLIENT
+        .get(&url)
+        .timeout(attempt_timeout())
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        return Err(RpcError::Misc(format!(
+            "{addr} did not report what it serves: {}",
+            resp.status()
+        )));
+    }
+    let info = serde_json::from_slice::<DumpServerInfo>(&resp.bytes().await?)?;
+    SERVER_INFO
+        .lock()
+        .expect("lock poisoned")
+        .insert(addr.to_owned(), (Instant::now(), info.clone()));
+    Ok(info)
+}
+
+/// Explain why the process at `addr` has no handler for a component, from
+/// what it says it serves, if it can be asked.
+async fn explain_missing(label: &str, addr: &str) -> Option<String> {
+    let info = server_info(addr).await.ok()?;
+    let revision = crate::runtime::config().revision();
+    let serves = match info.components.is_empty() {
+        true => "no RPC components".to_owned(),
+        false => info
+            .components
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+    Some(if info.protocol_version != PROTOCOL_VERSION {
+        format!(
+            "{addr} speaks RPC protocol version {}, but this process speaks version {}",
+            info.protocol_version, PROTOCOL_VERSION
+        )
+    } else if info.revision != revision {
+        format!(
+            "{addr} runs revision {}, which doesn't serve {label} (this process runs {revision}, and it serves {serves})",
+            info.revision
+        )
+    } else {
+        format!("{addr} doesn't serve {label}, only {serves}")
+    })
+}
+
 async fn rpc_http_server() {
-    let app = axum::Router::new().route(
-        "/rpc/{label}",
-        axum::routing::post(
-            async |axum::extract::Path(label): axum::extract::Path<String>,
-                   body: axum::body::Bytes| {
-                let bytes = body.to_vec();
-                match HTTP_HANDLERS.get(label.as_str()) {
-                    Some(h) => h.handle_json(&bytes).await,
-                    None => Err(RpcError::Misc(format!("no handler for {label}"))),
-                }
-            },
-        ),
-    );
+    let app = axum::Router::new()
+        .route(
+            "/rpc/_info",
+            axum::routing::get(async || axum::Json(local_server_info())),
+        )
+        .route(
+            "/rpc/{label}",
+            axum::routing::post(
+                async |axum::extract::Path(label): axum::extract::Path<String>,
+                       headers: axum::http::HeaderMap,
+                       body: axum::body::Bytes| {
+                    let (bytes, seal) = match seal::open_request(Some(&label), &headers, &body) {
+                        Ok(opened) => opened,
+                        Err(e) => return e.into_response(),
+                    };
+                    let ctx = request_context(&headers);
+                    let obs = Observation::server(&label, &bytes);
+                    let res = async {
+                        let caller = check_caller(&label, &headers)?;
+                        let forward = crate::shard::forward(&label, &headers, &bytes);
+                        if let Some(res) = context::scope(ctx.clone(), forward).await {
+                            return res;
+                        }
+                        let h = HTTP_HANDLERS
+                            .get(label.as_str())
+                            .ok_or_else(|| RpcError::Misc(format!("no handler for {label}")))?;
+                        let journal = journal::start(&label, &headers);
+                        let capture =
+                            capture::start(&label, caller.as_deref(), ctx.request_id.as_deref());
+                        let deadline = ctx.deadline;
+                        let dispatched = dispatch(&label, caller.as_deref(), &*h, &bytes);
+                        let handle = context::enforce(&label, deadline, dispatched);
+                        let res = context::scope(ctx, handle).await;
+                        if let Some(j) = journal {
+                            j.finish(&bytes, &res);
+                        }
+                        if let Some(c) = capture {
+                            c.finish(&bytes, &res);
+                        }
+                        res
+                    };
+                    let res = res.await;
+                    obs.complete(&res);
+                    seal::respond(seal.as_ref(), res)
+                },
+            ),
+        )
+        .route("/rpc/{label}/stream", axum::routing::post(handle_stream))
+        .route(
+            "/rpc/{label}/subscribe",
+            axum::routing::post(handle_subscribe),
+        )
+        .route(
+            "/pipeline/{label}/{stage}",
+            axum::routing::post(handle_pipeline),
+        )
+        .route(
+            "/actor/{kind}/{mode}",
+            axum::routing::post(
+                async |axum::extract::Path((kind, mode)): axum::extract::Path<(String, String)>,
+                       headers: axum::http::HeaderMap,
+                       body: axum::body::Bytes| {
+                    let (bytes, seal) = match seal::open_request(None, &headers, &body) {
+                        Ok(opened) => opened,
+                        Err(e) => return e.into_response(),
+                    };
+                    let target = format!("actor {kind}");
+                    let caller = match auth::authenticate(
+                        &target,
+                        headers.get(auth::HEADER).map(|v| v.as_bytes()),
+                    ) {
+                        Ok(caller) => caller,
+                        Err(e) => return seal::respond(seal.as_ref(), Err(e)),
+                    };
+                    let ctx = request_context(&headers);
+                    let handled =
+                        crate::actor::handle_json(&kind, &mode, caller.as_deref(), &bytes);
+                    let res = context::scope(ctx, handled);
+                    seal::respond(seal.as_ref(), res.await.map(Bytes::from))
+                },
+            ),
+        )
+        .route(
+            "/standby/{label}/{mode}",
+            axum::routing::post(
+                async |axum::extract::Path((label, mode)): axum::extract::Path<(
+                    String,
+                    String,
+                )>,
+                       headers: axum::http::HeaderMap,
+                       body: axum::body::Bytes| {
+                    let (bytes, seal) = match seal::open_request(None, &headers, &body) {
+                        Ok(opened) => opened,
+                        Err(e) => return e.into_response(),
+                    };
+                    let target = format!("standby {label}");
+                    if let Err(e) =
+                        auth::authenticate(&target, headers.get(auth::HEADER).map(|v| v.as_bytes()))
+                    {
+                        return seal::respond(seal.as_ref(), Err(e));
+                    }
+                    let res = crate::standby::handle(&label, &mode, &bytes).await;
+                    seal::respond(seal.as_ref(), res.map(Bytes::from))
+                },
+            ),
+        )
+        .layer(axum::middleware::from_fn(conn::count_request))
+        .merge(crate::admin::router());
 
     let addr: SocketAddr = crate::runtime::to_addr(PORT);
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     log::info!("rpc server listening on {:?}", addr);
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(conn::CountingListener(listener), app)
+        .await
+        .unwrap();
 }
 
-pub async fn http_call<R: RpcComponentKind>(q: &R::Request) -> RpcResult<R::Response> {
-    let loc = match R::discover_running().await {
-        Ok(locs) => match locs.choose(&mut rand::rng()) {
-            Some(x) => x.clone(),
-            None => return Err(RpcError::Misc(format!("discovery endpoints empty"))),
-        },
-        Err(e) => return Err(RpcError::Misc(format!("could not discover endpoint: {e}"))),
+/// Handles a request while streaming progress updates to the caller as
+/// server-sent events. `progress` events carry a `ProgressUpdate`, and the
+/// stream ends with either an `ok` event carrying the response or an `error`
+/// event carrying an `RpcError`.
+async fn handle_stream(
+    axum::extract::Path(label): axum::extract::Path<String>,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    use axum::response::sse::{Event, KeepAlive, Sse};
+
+    let h = match HTTP_HANDLERS.get(label.as_str()) {
+        Some(h) => h,
+        None => return RpcError::Misc(format!("no handler for {label}")).into_response(),
+    };
+    let caller = match check_caller(&label, &headers) {
+        Ok(caller) => caller,
+        Err(e) => return e.into_response(),
+    };
+    let (bytes, seal) = match seal::open_request(Some(&label), &headers, &body) {
+        Ok(opened) => opened,
+        Err(e) => return e.into_response(),
+    };
+    let ctx = request_context(&headers);
+    let journal = journal::start(&label, &headers);
+    let capture = capture::start(&label, caller.as_deref(), ctx.request_id.as_deref());
+    let deadline = ctx.deadline;
+    let obs = Observation::server(&label, &bytes);
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let join = tokio::spawn(progress::scope(
+        tx,
+        context::scope(ctx, async move {
+            let dispatched = dispatch(&label, caller.as_deref(), &*h, &bytes);
+            let res = context::enforce(&label, deadline, dispatched).await;
+            if let Some(j) = journal {
+                j.finish(&bytes, &res);
+            }
+            if let Some(c) = capture {
+                c.finish(&bytes, &res);
+            }
+            obs.complete(&res);
+            res
+        }),
+    ));
+
+    // events carry JSON, or the hex of the sealed JSON if the request was
+    // sealed
+    let seal = seal.map(Arc::new);
+    let event = move |name: &str, data: Option<String>| {
+        let data = match (&seal, data) {
+            (_, None) => return Event::default().event(name),
+            (Some(seal), Some(data)) => seal.seal_text(Part::Event, &data),
+            (None, Some(data)) => data,
+        };
+        Event::default().event(name).data(data)
+    };
+
+    let events = futures::stream::unfold(Some((rx, join)), move |state| {
+        let event = event.clone();
+        async move {
+            let (mut rx, join) = state?;
+            match rx.recv().await {
+                Some(update) => {
+                    let ev = event("progress", serde_json::to_string(&update).ok());
+                    Some((ev, Some((rx, join))))
+                }
+                None => {
+                    let ev = match join.await {
+                        Ok(Ok(res)) => {
+                            event("ok", Some(String::from_utf8_lossy(&res).into_owned()))
+                        }
+                        Ok(Err(e)) => event("error", serde_json::to_string(&e).ok()),
+                        Err(e) => {
+                            let e = RpcError::Misc(format!("handler failed: {e}"));
+                            event("error", serde_json::to_string(&e).ok())
+                        }
+                    };
+                    Some((ev, None))
+                }
+            }
+        }
+    });
+
+    Sse::new(events.map(Ok::<_, Infallible>))
+        .keep_alive(KeepAlive::new().interval(SSE_KEEP_ALIVE))
+        .into_response()
+}
+
+/// Serves a subscription, pushing the topic's events to the subscriber as
+/// server-sent events for as long as it stays connected. `event` events
+/// carry an event, with its resume token as the ID, `missed` events stand
+/// in for events the subscriber missed, and an `end` event is sent if the
+/// topic ends. A subscriber reconnecting with a `Last-Event-ID` header
+/// resumes after that event.
+async fn handle_subscribe(
+    axum::extract::Path(label): axum::extract::Path<String>,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    use axum::response::sse::{Event, KeepAlive, Sse};
+
+    let h = match HTTP_HANDLERS.get(label.as_str()) {
+        Some(h) => h,
+        None => return RpcError::Misc(format!("no handler for {label}")).into_response(),
+    };
+    if let Err(e) = check_caller(&label, &headers) {
+        return e.into_response();
+    }
+    let (bytes, seal) = match seal::open_request(Some(&label), &headers, &body) {
+        Ok(opened) => opened,
+        Err(e) => return e.into_response(),
+    };
+    let after = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned());
+    let ctx = request_context(&headers);
+    let topic = match context::scope(ctx, h.subscribe_json(&bytes)).await {
+        Ok(topic) => topic,
+        Err(e) => return e.into_response(),
+    };
+    log::debug!("subscription to {label} opened");
+
+    let events = topic.serve(after.as_deref()).map(move |item| match item {
+        Ok((id, data)) => {
+            let data = match &seal {
+                Some(seal) => seal.seal_text(Part::Event, &data),
+                None => data,
+            };
+            Event::default().event("event").id(id).data(data)
+        }
+        Err(_) => Event::default().event("missed"),
+    });
+    let end = futures::stream::once(async { Event::default().event("end") });
+
+    Sse::new(events.chain(end).map(Ok::<_, Infallible>))
+        .keep_alive(KeepAlive::new().interval(SSE_KEEP_ALIVE))
+        .into_response()
+}
+
+/// Picks a replica at random according to the replicas' weights. With an
+/// affinity key, the replica with the highest weighted score for the key is
+/// picked instead (rendezvous hashing), so the same key consistently maps to
+/// the same replica. Scores are hashed with FNV-1a, so that processes built
+/// with different toolchains pick the same replica.
+pub(crate) fn choose_replica(replicas: &[Replica], affinity: Option<u64>) -> Option<&Replica> {
+    match affinity {
+        Some(key) => replicas.iter().filter(|r| r.weight > 0).max_by(|a, b| {
+            let score = |r: &Replica| {
+                let mut hasher = FnvHasher::default();
+                hasher.write(&key.to_le_bytes());
+                hasher.write(r.location.addr::<str>().as_bytes());
+                // map the hash into (0, 1) and weight it
+                let h = (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64;
+                r.weight as f64 / -h.max(f64::MIN_POSITIVE).ln()
+            };
+            score(a).total_cmp(&score(b))
+        }),
+        None => replicas
+            .choose_weighted(&mut rand::rng(), |r| r.weight)
+            .ok(),
+    }
+}
+
+/// The replicas of a component that calls are balanced across, with
+/// standbys and ejected endpoints left out and slow start applied. Replicas
+/// that aren't ready are only included if `unready` is set.
+pub(crate) async fn balanced_replicas<R: ComponentKind>(unready: bool) -> RpcResult<Vec<Replica>> {
+    let discovered = match unready {
+        true => R::discover_all_replicas().await,
+        false => R::discover_replicas().await,
+    };
+    match discovered {
+        Ok(replicas) => {
+            let replicas = standby::serving(R::LABEL, replicas);
+            Ok(ramp::apply(R::LABEL, outlier::filter(replicas)))
+        }
+        Err(e) => Err(RpcError::Misc(format!("could not discover endpoint: {e}"))),
+    }
+}
+
+/// The balanced replicas of a component by its label, for callers that
+/// don't know its type.
+pub(crate) async fn balanced_replicas_of(label: &'static str) -> RpcResult<Vec<Replica>> {
+    match crate::runtime::provider().discover_replicas(label).await {
+        Ok(replicas) => {
+            let replicas = standby::serving(label, replicas);
+            Ok(ramp::apply(label, outlier::filter(replicas)))
+        }
+        Err(e) => Err(RpcError::Misc(format!("could not discover endpoint: {e}"))),
+    }
+}
+
+/// Pick a replica of a component to send a request to.
+pub(crate) async fn discover<R: ComponentKind>(
+    affinity: Option<u64>,
+    unready: bool,
+) -> RpcResult<Location> {
+    let replicas = balanced_replicas::<R>(unready).await?;
+    match choose_replica(&replicas, affinity) {
+        Some(x) => Ok(x.location.clone()),
+        None => Err(RpcError::Misc(format!("discovery endpoints empty"))),
+    }
+}
+
+/// Receives a stream of items for a pipeline stage in this process, one per
+/// line, as JSON or the hex of the sealed JSON if the request was sealed.
+/// The response, once the stream ends, is how many items were received.
+async fn handle_pipeline(
+    axum::extract::Path((label, stage)): axum::extract::Path<(String, String)>,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Body,
+) -> axum::response::Response {
+    let target = format!("pipeline {label}");
+    if let Err(e) = auth::authenticate(&target, headers.get(auth::HEADER).map(|v| v.as_bytes())) {
+        return e.into_response();
+    }
+    let seal = match seal::incoming(Some(&stage), &headers) {
+        Ok(seal) => seal,
+        Err(e) => return e.into_response(),
+    };
+    let open = |line: &[u8]| match &seal {
+        Some(seal) => Ok(seal
+            .open_text(Part::Request, &String::from_utf8_lossy(line))?
+            .into_bytes()),
+        None => Ok(line.to_vec()),
+    };
+    let res = crate::pipeline::receive(&label, &stage, body.into_data_stream(), open).await;
+    res.and_then(|n| Ok(serde_json::to_vec(&n)?))
+        .into_response()
+}
+
+/// Sends a stream of items to a replica of a pipeline stage, returning when
+/// the stream ends or the connection fails. Each chunk of the stream is one
+/// or more items, each ending with a newline.
+pub(crate) async fn http_pipeline_stream<S>(pipeline: &str, stage: &str, body: S) -> RpcResult<()>
+where
+    S: futures::Stream<Item = Result<Bytes, Infallible>> + Send + 'static,
+{
+    let locs = crate::runtime::provider()
+        .discover_running(stage)
+        .await
+        .map_err(|e| RpcError::Misc(format!("could not discover endpoint: {e}")))?;
+    let Some(loc) = locs.choose(&mut rand::rng()) else {
+        return Err(RpcError::misc("discovery endpoints empty"));
+    };
+    let url = format!(
+        "http://{}:{}/pipeline/{}/{}",
+        loc.addr::<str>(),
+        PORT,
+        pipeline,
+        stage
+    );
+    log::debug!("outgoing pipeline stream: {} -> {}", stage, url);
+
+    let mut req = HTTP_CLIENT
+        .post(&url)
+        .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson");
+    let body = match Seal::outgoing(stage) {
+        Some(s) => {
+            req = req.header(seal::HEADER, s.header());
+            // each line is sealed on its own, so the receiver can open
+            // items as they arrive
+            let sealed = body.map(move |chunk| {
+                let chunk = chunk?;
+                let mut out = Vec::new();
+                for line in chunk.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+                    let json = String::from_utf8_lossy(line);
+                    out.extend_from_slice(s.seal_text(Part::Request, &json).as_bytes());
+                    out.push(b'\n');
+                }
+                Ok::<_, Infallible>(Bytes::from(out))
+            });
+            reqwest::Body::wrap_stream(sealed)
+        }
+        None => reqwest::Body::wrap_stream(body),
+    };
+    let resp = with_request_context(req).body(body).send().await?;
+    conn::count_response(resp.version());
+    if !resp.status().is_success() {
+        return Err(serde_json::from_slice::<RpcError>(&resp.bytes().await?)?);
+    }
+    Ok(())
+}
+
+/// Sends a request to the streaming endpoint, forwarding progress updates to
+/// the sender until the final result arrives.
+pub async fn http_call_stream<R: RpcComponentKind>(
+    q: &R::Request,
+    affinity: Option<u64>,
+    unready: bool,
+    version: Option<HttpVersion>,
+    tx: ProgressSender,
+) -> RpcResult<R::Response> {
+    let label = R::LABEL;
+    check_deadline(label)?;
+    schema::check::<R>();
+    let loc = discover::<R>(affinity, unready).await?;
+    let url = format!("http://{}:{}/rpc/{}/stream", loc.addr::<str>(), PORT, label);
+    log::debug!("outgoing streaming RPC: {} -> {}", label, url);
+    let seal = Seal::outgoing(label);
+    let mut req = http_client(version)
+        .post(&url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(reqwest::header::ACCEPT, "text/event-stream");
+    req = match &seal {
+        Some(s) => req
+            .header(seal::HEADER, s.header())
+            .body(s.seal(Part::Request, &buf::to_json_bytes(q)?)),
+        None => req.body(buf::to_json_bytes(q)?),
+    };
+    let resp = match with_request_context(req).send().await {
+        Ok(resp) => resp,
+        Err(e) => {
+            outlier::record(label, loc.addr(), false);
+            return Err(e.into());
+        }
+    };
+    conn::count_response(resp.version());
+    if !resp.status().is_success() {
+        let headers = resp.headers().clone();
+        let body = seal::open_response(seal.as_ref(), false, &headers, resp.bytes().await?)?;
+        let msg = serde_json::from_slice::<RpcError>(&body)?;
+        outlier::record(
+            label,
+            loc.addr(),
+            !matches!(msg, RpcError::Spurious(_) | RpcError::Overloaded { .. }),
+        );
+        return Err(msg);
+    }
+    outlier::record(label, loc.addr(), true);
+
+    let mut events = EventStream::new(resp, seal);
+    while let Some(ev) = events.next().await? {
+        match ev.event.as_str() {
+            "progress" => {
+                let _ = tx.send(serde_json::from_str::<ProgressUpdate>(&ev.data)?);
+            }
+            "ok" => return Ok(serde_json::from_str::<R::Response>(&ev.data)?),
+            "error" => return Err(serde_json::from_str::<RpcError>(&ev.data)?),
+            _ => (),
+        }
+    }
+
+    Err(RpcError::spurious("progress stream ended without a result"))
+}
+
+/// Opens a subscription on a replica of the component, resuming after the
+/// event with the given resume token if there is one. See
+/// [`Subscription`][crate::rpc::Subscription].
+pub(crate) async fn http_subscribe<R: RpcComponentKind>(
+    addr: &str,
+    q: Bytes,
+    after: Option<&str>,
+    version: Option<HttpVersion>,
+) -> RpcResult<EventStream> {
+    let label = R::LABEL;
+    schema::check::<R>();
+    let url = format!("http://{}:{}/rpc/{}/subscribe", addr, PORT, label);
+    log::debug!("outgoing subscription: {} -> {}", label, url);
+    let seal = Seal::outgoing(label);
+    let mut req = http_client(version)
+        .post(&url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(reqwest::header::ACCEPT, "text/event-stream");
+    if let Some(after) = after {
+        req = req.header("last-event-id", after);
+    }
+    req = match &seal {
+        Some(s) => req
+            .header(seal::HEADER, s.header())
+            .body(s.seal(Part::Request, &q)),
+        None => req.body(q),
+    };
+    let resp = match with_request_context(req).send().await {
+        Ok(resp) => resp,
+        Err(e) => {
+            outlier::record(label, addr, false);
+            return Err(e.into());
+        }
     };
-    http_call_at::<R>(loc.addr(), q).await
+    conn::count_response(resp.version());
+    if !resp.status().is_success() {
+        let headers = resp.headers().clone();
+        let body = seal::open_response(seal.as_ref(), false, &headers, resp.bytes().await?)?;
+        let msg = serde_json::from_slice::<RpcError>(&body)?;
+        outlier::record(
+            label,
+            addr,
+            !matches!(msg, RpcError::Spurious(_) | RpcError::Overloaded { .. }),
+        );
+        return Err(msg);
+    }
+    outlier::record(label, addr, true);
+    Ok(EventStream::new(resp, seal))
+}
+
+/// A server-sent event.
+pub(crate) struct SseEvent {
+    pub(crate) event: String,
+    pub(crate) id: Option<String>,
+    pub(crate) data: String,
+}
+
+/// The server-sent events in a response, with their data opened if the
+/// request was sealed.
+pub(crate) struct EventStream {
+    resp: reqwest::Response,
+    seal: Option<Seal>,
+    buf: Vec<u8>,
+}
+
+impl EventStream {
+    fn new(resp: reqwest::Response, seal: Option<Seal>) -> EventStream {
+        EventStream {
+            resp,
+            seal,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Wait for the next event, returning `None` at the end of the
+    /// response.
+    pub(crate) async fn next(&mut self) -> RpcResult<Option<SseEvent>> {
+        let mut ev = SseEvent {
+            event: String::new(),
+            id: None,
+            data: String::new(),
+        };
+        loop {
+            while let Some(i) = self.buf.iter().position(|&b| b == b'\n') {
+                let line = self.buf.drain(..=i).collect::<Vec<u8>>();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim_end_matches(['\n', '\r']);
+                if let Some(v) = line.strip_prefix("event:") {
+                    ev.event = v.trim_start().to_owned();
+                } else if let Some(v) = line.strip_prefix("id:") {
+                    ev.id = Some(v.trim_start().to_owned());
+                } else if let Some(v) = line.strip_prefix("data:") {
+                    if !ev.data.is_empty() {
+                        ev.data.push('\n');
+                    }
+                    ev.data.push_str(v.strip_prefix(' ').unwrap_or(v));
+                } else if line.is_empty() && !ev.event.is_empty() {
+                    if let Some(s) = &self.seal
+                        && !ev.data.is_empty()
+                    {
+                        ev.data = s.open_text(Part::Event, &ev.data)?;
+                    }
+                    return Ok(Some(ev));
+                }
+            }
+            // a server that's gone silent, e.g. behind a partition, would
+            // otherwise hold the stream open forever
+            let chunk = tokio::time::timeout(SSE_IDLE_TIMEOUT, self.resp.chunk())
+                .await
+                .map_err(|_| {
+                    RpcError::Misc(format!(
+                        "no events or keep-alives for {}s",
+                        SSE_IDLE_TIMEOUT.as_secs()
+                    ))
+                })?;
+            match chunk? {
+                Some(chunk) => self.buf.extend_from_slice(&chunk),
+                None => return Ok(None),
+            }
+        }
+    }
 }
 
 pub async fn http_call_at<R: RpcComponentKind>(
     addr: &str,
     q: &R::Request,
+    version: Option<HttpVersion>,
 ) -> RpcResult<R::Response> {
-    let label = R::LABEL;
-    let url = format!("http://{}:{}/rpc/{}", addr, PORT, label);
-    log::debug!("outgoing RPC: {} -> {}", label, url);
-    let resp = HTTP_CLIENT
+    schema::check::<R>();
+    let path = format!("/rpc/{}", R::LABEL);
+    post_json::<R::Request, R::Response>(R::LABEL, addr, &path, q, version).await
+}
+
+/// Send a JSON request to a path on another process's RPC server, on behalf
+/// of the component with the given label. The app's HTTP version is used
+/// unless one is given.
+pub(crate) async fn post_json<Q, A>(
+    label: &'static str,
+    addr: &str,
+    path: &str,
+    q: &Q,
+    version: Option<HttpVersion>,
+) -> RpcResult<A>
+where
+    Q: serde::Serialize + ?Sized,
+    A: serde::de::DeserializeOwned,
+{
+    let body = buf::to_json_bytes(q)?;
+    let resp_body = post_bytes(label, addr, path, body, version).await?;
+    let resp_msg = serde_json::from_slice::<A>(&resp_body)?;
+    Ok(resp_msg)
+}
+
+/// The error for a failed attempt at a request, telling timeouts apart from
+/// the current deadline passing.
+fn request_error(label: &str, op: &str, timeout: Duration, e: reqwest::Error) -> RpcError {
+    if !e.is_timeout() {
+        return e.into();
+    }
+    match context::remaining() {
+        Some(r) if r.is_zero() => RpcError::DeadlineExceeded(label.to_owned()),
+        _ => RpcError::RequestTimeout {
+            component: label.to_owned(),
+            op: op.to_owned(),
+            timeout_ms: timeout.as_millis() as u64,
+        },
+    }
+}
+
+/// Send an already serialized request, returning the serialized response.
+pub(crate) async fn post_bytes<B: Into<Bytes>>(
+    label: &'static str,
+    addr: &str,
+    path: &str,
+    body: B,
+    version: Option<HttpVersion>,
+) -> RpcResult<Bytes> {
+    check_deadline(label)?;
+    let url = format!("http://{}:{}{}", addr, PORT, path);
+    if sampling::full() {
+        log::debug!("outgoing RPC: {} -> {}", label, url);
+    }
+    let body = body.into();
+    let op = observe::op_name(&body).unwrap_or("unknown").to_owned();
+    let shaping = shaping::get(label);
+    if let Some(s) = shaping {
+        s.delay(body.len()).await;
+    }
+    let seal = Seal::outgoing(label);
+    let timeout = attempt_timeout();
+    let mut req = http_client(version)
         .post(&url)
-        .json(&q)
-        .timeout(Duration::from_millis(rand::random_range(500..2000)))
-        .send()
-        .await?;
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .timeout(timeout);
+    req = match &seal {
+        Some(s) => req
+            .header(seal::HEADER, s.header())
+            .body(s.seal(Part::Request, &body)),
+        None => req.body(body),
+    };
+    let resp = match with_request_context(req).send().await {
+        Ok(resp) => resp,
+        Err(e) => {
+            outlier::record(label, addr, false);
+            return Err(request_error(label, &op, timeout, e));
+        }
+    };
+    conn::count_response(resp.version());
     let status = resp.status();
+    let headers = resp.headers().clone();
+    let resp_body = resp
+        .bytes()
+        .await
+        .map_err(|e| request_error(label, &op, timeout, e))?;
+    let resp_body = seal::open_response(seal.as_ref(), status.is_success(), &headers, resp_body)?;
     if !status.is_success() {
-        let msg = resp.json::<RpcError>().await?;
+        let mut msg = serde_json::from_slice::<RpcError>(&resp_body)?;
+        outlier::record(
+            label,
+            addr,
+            !matches!(msg, RpcError::Spurious(_) | RpcError::Overloaded { .. }),
+        );
+        if matches!(&msg, RpcError::Misc(m) if *m == format!("no handler for {label}"))
+            && let Some(explained) = explain_missing(label, addr).await
+        {
+            msg = RpcError::Misc(explained);
+        }
         return Err(msg);
     }
-    let resp_msg = resp.json::<R::Response>().await?;
-    Ok(resp_msg)
+    outlier::record(label, addr, true);
+    if let Some(s) = shaping {
+        s.delay(resp_body.len()).await;
+    }
+    Ok(resp_body)
+}
+
+/// Send an already serialized request to a specific replica of the component.
+pub async fn http_call_raw_at<R: RpcComponentKind>(
+    addr: &str,
+    q: Bytes,
+    version: Option<HttpVersion>,
+) -> RpcResult<Bytes> {
+    let path = format!("/rpc/{}", R::LABEL);
+    post_bytes(R::LABEL, addr, &path, q, version).await
 }
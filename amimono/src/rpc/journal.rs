@@ -0,0 +1,308 @@
+//! Request journaling, for replaying recorded traffic while debugging.
+//!
+//! When a journal is enabled for a component with
+//! `AppBuilder::with_journal`, a sample of the requests its RPC server
+//! handles are recorded along with their responses, the op, the caller, and
+//! how long they took. Entries are appended as JSON lines to `journal.jsonl`
+//! in the component's storage directory, or in a temporary directory if the
+//! runtime doesn't provide storage. When the file grows past the configured
+//! size it's rotated to `journal.jsonl.1`, replacing the previous one.
+//!
+//! Journals are served at `/admin/journal/{label}`. `ammn replay` fetches a
+//! component's journal from a deployed target and runs the replay tool,
+//! which is added to every app with a journal, to send the recorded requests
+//! to a local instance and report responses that differ from the recorded
+//! ones. Replayed requests are made by the replay tool, so components with a
+//! caller allowlist must allow it.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::body::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+
+use crate::{
+    AppResult,
+    rpc::{RpcError, RpcResult, auth, http},
+    runtime,
+};
+
+/// The label of the replay tool.
+pub const REPLAY_TOOL: &str = "amimono-replay";
+
+const FILE: &str = "journal.jsonl";
+const ROTATED: &str = "journal.jsonl.1";
+
+/// A recorded request.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Entry {
+    /// When the request arrived, in milliseconds since the Unix epoch.
+    t: u64,
+    op: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    caller: Option<String>,
+    /// How long the request took to handle, in microseconds.
+    us: u64,
+    q: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ok: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    err: Option<RpcError>,
+}
+
+/// A request being handled that was sampled for the journal.
+pub(crate) struct Pending {
+    label: &'static str,
+    caller: Option<String>,
+    t: u64,
+    started: Instant,
+}
+
+/// Decide whether to record a request to the component, returning a
+/// `Pending` to finish once the request is handled if so.
+pub(crate) fn start(label: &str, headers: &axum::http::HeaderMap) -> Option<Pending> {
+    let cf = runtime::config();
+    let journal = cf.journal(label)?;
+    if rand::random::<f64>() >= journal.sample_rate {
+        return None;
+    }
+    let label = cf.component(label)?.label.as_str();
+    let caller = auth::verify(headers.get(auth::HEADER).map(|v| v.as_bytes()))
+        .ok()
+        .flatten()
+        .and_then(|id| id.caller);
+    let t = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    Some(Pending {
+        label,
+        caller,
+        t,
+        started: Instant::now(),
+    })
+}
+
+impl Pending {
+    /// Record the request and its result.
+    pub(crate) fn finish(self, q: &[u8], res: &RpcResult<Bytes>) {
+        let us = self.started.elapsed().as_micros() as u64;
+        let q = to_value(q);
+        let (ok, err) = match res {
+            Ok(a) => (Some(to_value(a)), None),
+            Err(e) => (None, Some(e.clone())),
+        };
+        let entry = Entry {
+            t: self.t,
+            op: op_name(&q).unwrap_or_default(),
+            caller: self.caller,
+            us,
+            q,
+            ok,
+            err,
+        };
+        match serde_json::to_string(&entry) {
+            Ok(line) => {
+                let _ = WRITER.send((self.label, line));
+            }
+            Err(e) => log::warn!("could not serialize journal entry for {}: {e}", self.label),
+        }
+    }
+}
+
+pub(crate) fn to_value(bytes: &[u8]) -> serde_json::Value {
+    serde_json::from_slice(bytes)
+        .unwrap_or_else(|_| serde_json::Value::String(String::from_utf8_lossy(bytes).into_owned()))
+}
+
+/// The op of a serialized request, which is an externally tagged enum, i.e.
+/// `{"op": args}`, or just `"op"` for ops without arguments.
+pub(crate) fn op_name(q: &serde_json::Value) -> Option<String> {
+    match q {
+        serde_json::Value::Object(map) if map.len() == 1 => map.keys().next().cloned(),
+        serde_json::Value::String(s) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+/// Where each component's journal is written, once it's been opened.
+static DIRS: LazyLock<Mutex<HashMap<&'static str, PathBuf>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+static WRITER: LazyLock<mpsc::UnboundedSender<(&'static str, String)>> = LazyLock::new(|| {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(write_entries(rx));
+    tx
+});
+
+async fn journal_dir(label: &'static str) -> PathBuf {
+    let dir = match runtime::provider().storage(label).await {
+        Ok(dir) => dir.join("journal"),
+        Err(_) => std::env::temp_dir().join("amimono-journal").join(label),
+    };
+    DIRS.lock()
+        .expect("lock poisoned")
+        .insert(label, dir.clone());
+    dir
+}
+
+struct Open {
+    dir: PathBuf,
+    file: tokio::fs::File,
+    size: u64,
+}
+
+async fn open(dir: &Path) -> std::io::Result<Open> {
+    tokio::fs::create_dir_all(dir).await?;
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(FILE))
+        .await?;
+    let size = file.metadata().await?.len();
+    Ok(Open {
+        dir: dir.to_owned(),
+        file,
+        size,
+    })
+}
+
+async fn write_entries(mut rx: mpsc::UnboundedReceiver<(&'static str, String)>) {
+    let mut files: HashMap<&'static str, Open> = HashMap::new();
+    while let Some((label, line)) = rx.recv().await {
+        let res = async {
+            if !files.contains_key(label) {
+                let dir = journal_dir(label).await;
+                log::info!("journaling requests to {} in {}", label, dir.display());
+                files.insert(label, open(&dir).await?);
+            }
+            let f = files.get_mut(label).expect("journal just opened");
+            f.file.write_all(line.as_bytes()).await?;
+            f.file.write_all(b"\n").await?;
+            f.size += line.len() as u64 + 1;
+
+            let max = runtime::config()
+                .journal(label)
+                .map(|j| j.max_bytes)
+                .unwrap_or(u64::MAX);
+            if f.size >= max {
+                f.file.flush().await?;
+                tokio::fs::rename(f.dir.join(FILE), f.dir.join(ROTATED)).await?;
+                *f = open(&f.dir).await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        if let Err(e) = res.await {
+            log::warn!("failed to write journal for {label}: {e}");
+            files.remove(label);
+        }
+    }
+}
+
+/// The contents of a component's journal, oldest entries first, if it has
+/// one in this process.
+pub(crate) async fn read(label: &str) -> Option<Vec<u8>> {
+    let dir = DIRS.lock().expect("lock poisoned").get(label).cloned()?;
+    let mut out = tokio::fs::read(dir.join(ROTATED)).await.unwrap_or_default();
+    out.extend(tokio::fs::read(dir.join(FILE)).await.unwrap_or_default());
+    Some(out)
+}
+
+/// The replay tool. Its arguments are the label of the component, a journal
+/// file, and optionally the address of the instance to send requests to,
+/// which is otherwise discovered.
+pub async fn replay(args: &'static [&'static str]) -> AppResult<()> {
+    let (label, path, addr) = match args {
+        [_, label, path] => (*label, *path, None),
+        [_, label, path, addr] => (*label, *path, Some(*addr)),
+        _ => Err(RpcError::Misc(format!(
+            "usage: {REPLAY_TOOL} <component> <journal> [addr]"
+        )))?,
+    };
+    let label = runtime::config()
+        .component(label)
+        .map(|c| c.label.as_str())
+        .ok_or_else(|| RpcError::Misc(format!("unknown component {label}")))?;
+    let addr = match addr {
+        Some(addr) => addr.to_owned(),
+        None => runtime::provider()
+            .discover_running(label)
+            .await
+            .ok()
+            .and_then(|locs| locs.into_iter().next())
+            .map(|loc| loc.addr::<str>().to_owned())
+            .ok_or_else(|| RpcError::Misc(format!("no running instance of {label} found")))?,
+    };
+
+    let journal = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| RpcError::Misc(format!("could not read {path}: {e}")))?;
+    let rpc_path = format!("/rpc/{label}");
+
+    let (mut same, mut differed, mut skipped) = (0, 0, 0);
+    let mut latency = Duration::ZERO;
+    let mut recorded_latency = Duration::ZERO;
+    for line in journal.lines().filter(|l| !l.trim().is_empty()) {
+        let entry = match serde_json::from_str::<Entry>(line) {
+            Ok(e) => e,
+            Err(e) => {
+                log::warn!("skipping invalid journal entry: {e}");
+                skipped += 1;
+                continue;
+            }
+        };
+        let body = serde_json::to_vec(&entry.q)?;
+        let started = Instant::now();
+        let res = http::post_bytes(label, &addr, &rpc_path, body, None).await;
+        latency += started.elapsed();
+        recorded_latency += Duration::from_micros(entry.us);
+
+        let now = match res {
+            Ok(a) => (Some(to_value(&a)), None),
+            Err(e) => (None, Some(e)),
+        };
+        let then = (entry.ok, entry.err);
+        let matches = now.0 == then.0
+            && now.1.as_ref().map(serde_json::to_value).transpose()?
+                == then.1.as_ref().map(serde_json::to_value).transpose()?;
+        if matches {
+            same += 1;
+        } else {
+            differed += 1;
+            log::warn!(
+                "{} at {} differed: recorded {}, got {}",
+                entry.op,
+                entry.t,
+                describe(&then),
+                describe(&now)
+            );
+        }
+    }
+
+    let total = same + differed;
+    println!(
+        "replayed {total} requests to {label} at {addr}: {same} same, {differed} differed, {skipped} skipped"
+    );
+    if total > 0 {
+        println!(
+            "mean latency {:?} (recorded {:?})",
+            latency / total,
+            recorded_latency / total
+        );
+    }
+    Ok(())
+}
+
+/// A recorded or replayed result, for messages about how results differ.
+pub(crate) fn describe(res: &(Option<serde_json::Value>, Option<RpcError>)) -> String {
+    match res {
+        (Some(a), _) => a.to_string(),
+        (None, Some(e)) => format!("error {e}"),
+        (None, None) => "nothing".to_owned(),
+    }
+}
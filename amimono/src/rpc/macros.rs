@@ -9,8 +9,9 @@
 /// # Example
 ///
 /// ```
-/// mod ops {
-///     amimono::rpc_ops! {
+/// # use amimono::rpc::RpcResult;
+/// amimono::rpc_component! {
+///     pub mod ops {
 ///         const LABEL: &'static str = "mapservice";
 ///
 ///         fn add_item(key: String, value: String) -> ();
@@ -33,21 +34,31 @@
 ///         MapService
 ///     }
 ///
-///     async fn add_item(&self, key: String, value: String) -> () {
+///     async fn add_item(&self, key: &String, value: &String) -> RpcResult<()> {
 ///         // ...
+/// #       todo!()
 ///     }
-///     async fn get_item(&self, key: String) -> Option<String> {
+///     async fn get_item(&self, key: &String) -> RpcResult<Option<String>> {
 ///         // ...
+/// #       todo!()
 ///     }
-///     async fn delete_item(&self, key: String) -> () {
+///     async fn delete_item(&self, key: &String) -> RpcResult<()> {
 ///         // ...
+/// #       todo!()
 ///     }
 /// }
 /// ```
 ///
+/// The generated items, `Handler`, `Client`, `Component`, `ComponentKind`,
+/// `Request`, `Response` and so on, are placed in the named module, which
+/// takes the given visibility and attributes, so each component's items are
+/// namespaced and can't collide with another's. The module's body can also be
+/// given on its own, to generate the items wherever the macro is invoked,
+/// e.g. in a module declared separately.
+///
 /// The `MapClient` alias above has an `impl` that behaves like the following:
 ///
-/// ```
+/// ```ignore
 /// use amimono::rpc::RpcResult;
 ///
 /// impl MapClient {
@@ -62,7 +73,7 @@
 /// The component can be installed in an `AppConfig` as follows, using the
 /// `MapComponent` alias defined above:
 ///
-/// ```
+/// ```ignore
 /// use amimono::config::AppBuilder;
 ///
 /// pub fn install(app: &mut AppBuilder) {
@@ -74,16 +85,384 @@
 /// }
 /// ```
 ///
+/// # Long-running operations
+///
+/// Handlers can report progress with
+/// [`report_progress`][crate::rpc::report_progress]. Callers that want to see
+/// the updates use the client's `progress()` method, whose ops return a
+/// [`Progress`][crate::rpc::Progress] instead of the result directly:
+///
+/// ```ignore
+/// let mut op = client.progress().reindex(shard);
+/// while let Some(update) = op.next().await {
+///     log::info!("reindex progress: {:?}", update.fraction);
+/// }
+/// let result = op.await?;
+/// ```
+///
+/// Remote progress updates are delivered as server-sent events.
+///
+/// # Protobuf messages
+///
+/// With the `proto` feature enabled, `prost`-generated messages can be used as
+/// parameter and return types by wrapping them in
+/// [`Proto`][crate::rpc::Proto].
+///
+/// # Restricting callers
+///
+/// Setting `ALLOWED_CALLERS` after `LABEL` restricts which components and
+/// tools may call the component:
+///
+/// ```ignore
+/// amimono::rpc_component! {
+///     const LABEL: &'static str = "ledger";
+///     const ALLOWED_CALLERS: &'static [&'static str] = &["billing", "admin-tool"];
+///
+///     fn post(entry: Entry) -> ();
+/// }
+/// ```
+///
+/// Callers identify themselves with a header on each request, which is a
+/// signed token when `AMIMONO_CALLER_SECRET` is set, and should be in
+/// production. Setting `AMIMONO_CALLER_REQUIRED` to `true` as well refuses
+/// requests without a valid token to every component, with
+/// [`AppError::Unauthenticated`][crate::AppError::Unauthenticated]. Calls
+/// from other callers, or from outside any component, fail with
+/// [`AppError::Forbidden`][crate::AppError::Forbidden] and are recorded as
+/// `rpc.denied` [audit events][crate::audit].
+///
+/// # Storage
+///
+/// Setting `STORAGE` after `LABEL` and `ALLOWED_CALLERS`, if any, makes the
+/// component stateful, like
+/// [`ComponentKind::STORAGE`][crate::component::ComponentKind::STORAGE], so
+/// that its handler can use
+/// [`Component::storage`][crate::component::Component::storage]:
+///
+/// ```ignore
+/// amimono::rpc_component! {
+///     const LABEL: &'static str = "ledger";
+///     const STORAGE: Option<usize> = Some(1 << 30);
+///
+///     fn post(entry: Entry) -> ();
+/// }
+/// ```
+///
+/// # Evolving the API
+///
+/// An op can have a default handler body, so that adding it doesn't break
+/// existing handlers. The body returns the op's `RpcResult`, and sees the
+/// arguments by reference, but not `self`:
+///
+/// ```ignore
+/// fn get_items(keys: Vec<String>) -> Vec<Option<String>> {
+///     Err(AppError::misc("not supported by this map"))
+/// }
+/// ```
+///
+/// Ops marked `optional` default to failing with
+/// [`AppError::Unimplemented`][crate::AppError::Unimplemented], which is also
+/// returned for ops a component doesn't have at all, e.g. when it's running
+/// an older revision. Their client methods return that error as is rather
+/// than wrapped in `Downstream`, so callers can fall back to something else:
+///
+/// ```ignore
+/// optional fn get_items(keys: Vec<String>) -> Vec<Option<String>>;
+/// ```
+///
+/// Adding or removing optional ops isn't reported as a breaking change by
+/// `ammn deploy`.
+///
+/// Types that implement [`JsonSchema`][schemars::JsonSchema] are compared
+/// by their JSON Schemas when checking compatibility, so that changes to
+/// their fields and variants are seen, and other types only by name. The
+/// derive can be used through amimono's re-export:
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize, amimono::schemars::JsonSchema)]
+/// #[schemars(crate = "amimono::schemars")]
+/// pub struct Entry {
+///     amount: i64,
+///     note: Option<String>,
+/// }
+/// ```
+///
+/// # Priorities
+///
+/// Ops that do long-running or bulk work can be marked `batch`, after
+/// `optional` if they're both:
+///
+/// ```ignore
+/// batch fn reindex(shard: u32) -> ();
+/// ```
+///
+/// Components with a
+/// [concurrency limit][crate::config::AppBuilder::with_concurrency_limit]
+/// handle requests for other ops first when they're busy, only let batch
+/// requests use part of their capacity, and shed them first when overloaded.
+/// Priorities have no effect on components without a limit.
+///
+/// # Raw requests
+///
+/// The client's `call_raw` method sends a request that's already serialized,
+/// such as one replayed from logs, and returns the serialized response. On the
+/// serving side, individual ops can be handled without the typed enums with
+/// [`serve_raw`][crate::rpc::serve_raw].
+///
+/// # Contract tests
+///
+/// A client created with `with_recording(path)`, or any client when
+/// `AMIMONO_GOLDEN_DIR` is set, records its calls to a fixture file. The
+/// component's `Component::from_handler` wraps a handler so the fixtures can
+/// be replayed against it in a test with
+/// [`golden::verify`][crate::rpc::golden::verify].
+///
+/// # Subscriptions
+///
+/// Ops marked `subscribe`, which return a `stream` of events, push events to
+/// their callers as they happen, for watch-style APIs that would otherwise
+/// poll:
+///
+/// ```ignore
+/// subscribe fn watch_config(prefix: String) -> stream<ConfigEvent>;
+/// ```
+///
+/// Their handlers return a [`Topic`][crate::rpc::Topic] to subscribe the
+/// caller to, which is usually kept by the handler and published to whenever
+/// something changes, and the client's methods return a
+/// [`Subscription`][crate::rpc::Subscription]:
+///
+/// ```ignore
+/// let mut events = client.watch_config(prefix).await?;
+/// while let Some(ev) = events.next().await {
+///     match ev {
+///         Ok(ev) => apply(ev),
+///         Err(_) => reload().await?,
+///     }
+/// }
+/// ```
+///
+/// Subscribers in the same process read from the topic directly. Remote
+/// subscribers hold a connection open, and reconnect if it's lost, resuming
+/// after the last event they got. Subscription ops are listed in
+/// [`RpcComponentKind::SUBSCRIPTIONS`][crate::rpc::RpcComponentKind::SUBSCRIPTIONS]
+/// rather than `OPS`, and can't be served by the gateway.
+///
+/// # HTTP routes
+///
+/// An op with an `#[rpc(http = "POST /v1/add")]` attribute, right after its
+/// doc comment, is also served on that route by the app's
+/// [gateway][crate::gateway], which takes the op's arguments from the path,
+/// query string, and JSON body of each request. Routes are checked when the
+/// app is built.
+///
 /// For a working example, refer to any of the Amimono example projects.
 #[macro_export]
 macro_rules! rpc_component {
-    {
+    // Ops are normalized one at a time, so that `optional` and `batch` can be
+    // told apart from the attributes before `fn`, into
+    // `[[attrs] [keywords] op (args) [ret] [default body] [http route]]`.
+    // Each op is matched whole by a single arm, so that it only takes one
+    // level of recursion, with up to two keywords taken as idents, since an
+    // optional keyword before `fn` would be ambiguous with it.
+    // A `#[rpc(...)]` attribute is only recognized right after the doc
+    // comment, since it can't be picked out of arbitrary attributes.
+    // The optional consts after `LABEL` are taken one at a time, since an
+    // optional `const` before the ops would be ambiguous with them.
+    // Subscription ops are added to the header as
+    // `[subscribe [attrs] op (args) [event]]`, so the other ops' code doesn't
+    // need to tell them apart.
+    (@header {$($header:tt)*} const ALLOWED_CALLERS: &'static [&'static str] = $callers:expr; $($rest:tt)*) => {
+        ::amimono::rpc_component!(@header {
+            $($header)*
+            const ALLOWED_CALLERS: &'static [&'static str] = $callers;
+        } $($rest)*);
+    };
+    (@header {$($header:tt)*} const STORAGE: Option<usize> = $storage:expr; $($rest:tt)*) => {
+        ::amimono::rpc_component!(@header {
+            $($header)*
+            const STORAGE: Option<usize> = $storage;
+        } $($rest)*);
+    };
+    (@header $header:tt $($ops:tt)*) => {
+        ::amimono::rpc_component!(@parse $header [] $($ops)*);
+    };
+    (@parse $header:tt [$($done:tt)*]) => {
+        ::amimono::rpc_component!(@main $header $($done)*);
+    };
+    (@parse $header:tt [$($done:tt)*]
+        $(#[doc = $doc:literal])* #[rpc(http = $http:literal)] $(#[$meta:meta])*
+        $k1:ident $k2:ident fn $op:ident ($($arg:ident: $arg_ty:ty),*) -> $ret_ty:ty;
+        $($rest:tt)*
+    ) => {
+        ::amimono::rpc_component!(@parse $header [$($done)*
+            [[$(#[doc = $doc])* $(#[$meta])*] [$k1 $k2] $op ($($arg: $arg_ty),*) [$ret_ty] [] [$http]]
+        ] $($rest)*);
+    };
+    (@parse $header:tt [$($done:tt)*]
+        $(#[doc = $doc:literal])* #[rpc(http = $http:literal)] $(#[$meta:meta])*
+        $k1:ident $k2:ident fn $op:ident ($($arg:ident: $arg_ty:ty),*) -> $ret_ty:ty $body:block
+        $($rest:tt)*
+    ) => {
+        ::amimono::rpc_component!(@parse $header [$($done)*
+            [[$(#[doc = $doc])* $(#[$meta])*] [$k1 $k2] $op ($($arg: $arg_ty),*) [$ret_ty] [$body] [$http]]
+        ] $($rest)*);
+    };
+    (@parse $header:tt [$($done:tt)*]
+        $(#[doc = $doc:literal])* #[rpc(http = $http:literal)] $(#[$meta:meta])*
+        $k:ident fn $op:ident ($($arg:ident: $arg_ty:ty),*) -> $ret_ty:ty;
+        $($rest:tt)*
+    ) => {
+        ::amimono::rpc_component!(@parse $header [$($done)*
+            [[$(#[doc = $doc])* $(#[$meta])*] [$k] $op ($($arg: $arg_ty),*) [$ret_ty] [] [$http]]
+        ] $($rest)*);
+    };
+    (@parse $header:tt [$($done:tt)*]
+        $(#[doc = $doc:literal])* #[rpc(http = $http:literal)] $(#[$meta:meta])*
+        $k:ident fn $op:ident ($($arg:ident: $arg_ty:ty),*) -> $ret_ty:ty $body:block
+        $($rest:tt)*
+    ) => {
+        ::amimono::rpc_component!(@parse $header [$($done)*
+            [[$(#[doc = $doc])* $(#[$meta])*] [$k] $op ($($arg: $arg_ty),*) [$ret_ty] [$body] [$http]]
+        ] $($rest)*);
+    };
+    (@parse $header:tt [$($done:tt)*]
+        $(#[doc = $doc:literal])* #[rpc(http = $http:literal)] $(#[$meta:meta])*
+        fn $op:ident ($($arg:ident: $arg_ty:ty),*) -> $ret_ty:ty;
+        $($rest:tt)*
+    ) => {
+        ::amimono::rpc_component!(@parse $header [$($done)*
+            [[$(#[doc = $doc])* $(#[$meta])*] [] $op ($($arg: $arg_ty),*) [$ret_ty] [] [$http]]
+        ] $($rest)*);
+    };
+    (@parse $header:tt [$($done:tt)*]
+        $(#[doc = $doc:literal])* #[rpc(http = $http:literal)] $(#[$meta:meta])*
+        fn $op:ident ($($arg:ident: $arg_ty:ty),*) -> $ret_ty:ty $body:block
+        $($rest:tt)*
+    ) => {
+        ::amimono::rpc_component!(@parse $header [$($done)*
+            [[$(#[doc = $doc])* $(#[$meta])*] [] $op ($($arg: $arg_ty),*) [$ret_ty] [$body] [$http]]
+        ] $($rest)*);
+    };
+    (@parse {$($header:tt)*} $done:tt $(#[$meta:meta])* subscribe
+        fn $op:ident ($($arg:ident: $arg_ty:ty),*) -> stream<$ev_ty:ty>;
+        $($rest:tt)*
+    ) => {
+        ::amimono::rpc_component!(@parse {
+            $($header)*
+            [subscribe [$(#[$meta])*] $op ($($arg: $arg_ty),*) [$ev_ty]]
+        } $done $($rest)*);
+    };
+    (@parse $header:tt [$($done:tt)*]
+        $(#[$meta:meta])*
+        $k1:ident $k2:ident fn $op:ident ($($arg:ident: $arg_ty:ty),*) -> $ret_ty:ty;
+        $($rest:tt)*
+    ) => {
+        ::amimono::rpc_component!(@parse $header [$($done)*
+            [[$(#[$meta])*] [$k1 $k2] $op ($($arg: $arg_ty),*) [$ret_ty] [] []]
+        ] $($rest)*);
+    };
+    (@parse $header:tt [$($done:tt)*]
+        $(#[$meta:meta])*
+        $k1:ident $k2:ident fn $op:ident ($($arg:ident: $arg_ty:ty),*) -> $ret_ty:ty $body:block
+        $($rest:tt)*
+    ) => {
+        ::amimono::rpc_component!(@parse $header [$($done)*
+            [[$(#[$meta])*] [$k1 $k2] $op ($($arg: $arg_ty),*) [$ret_ty] [$body] []]
+        ] $($rest)*);
+    };
+    (@parse $header:tt [$($done:tt)*]
+        $(#[$meta:meta])*
+        $k:ident fn $op:ident ($($arg:ident: $arg_ty:ty),*) -> $ret_ty:ty;
+        $($rest:tt)*
+    ) => {
+        ::amimono::rpc_component!(@parse $header [$($done)*
+            [[$(#[$meta])*] [$k] $op ($($arg: $arg_ty),*) [$ret_ty] [] []]
+        ] $($rest)*);
+    };
+    (@parse $header:tt [$($done:tt)*]
+        $(#[$meta:meta])*
+        $k:ident fn $op:ident ($($arg:ident: $arg_ty:ty),*) -> $ret_ty:ty $body:block
+        $($rest:tt)*
+    ) => {
+        ::amimono::rpc_component!(@parse $header [$($done)*
+            [[$(#[$meta])*] [$k] $op ($($arg: $arg_ty),*) [$ret_ty] [$body] []]
+        ] $($rest)*);
+    };
+    (@parse $header:tt [$($done:tt)*]
+        $(#[$meta:meta])*
+        fn $op:ident ($($arg:ident: $arg_ty:ty),*) -> $ret_ty:ty;
+        $($rest:tt)*
+    ) => {
+        ::amimono::rpc_component!(@parse $header [$($done)*
+            [[$(#[$meta])*] [] $op ($($arg: $arg_ty),*) [$ret_ty] [] []]
+        ] $($rest)*);
+    };
+    (@parse $header:tt [$($done:tt)*]
+        $(#[$meta:meta])*
+        fn $op:ident ($($arg:ident: $arg_ty:ty),*) -> $ret_ty:ty $body:block
+        $($rest:tt)*
+    ) => {
+        ::amimono::rpc_component!(@parse $header [$($done)*
+            [[$(#[$meta])*] [] $op ($($arg: $arg_ty),*) [$ret_ty] [$body] []]
+        ] $($rest)*);
+    };
+
+    // The handler method for an op, with a default body if it has one or is
+    // optional.
+    (@handler [$(#[$meta:meta])*] [$(batch)?] $op:ident ($($arg:ident: $arg_ty:ty),*) [$ret_ty:ty] [] [$label:expr]) => {
+        $(#[$meta])*
+        fn $op(&self, $($arg: &$arg_ty),*)
+        -> impl Future<Output = ::amimono::rpc::RpcResult<$ret_ty>> + Send;
+    };
+    (@handler [$(#[$meta:meta])*] [optional $(batch)?] $op:ident ($($arg:ident: $arg_ty:ty),*) [$ret_ty:ty] [] [$label:expr]) => {
+        $(#[$meta])*
+        #[allow(unused_variables)]
+        fn $op(&self, $($arg: &$arg_ty),*)
+        -> impl Future<Output = ::amimono::rpc::RpcResult<$ret_ty>> + Send {
+            async move {
+                Err(::amimono::AppError::Unimplemented {
+                    component: ($label).to_owned(),
+                    op: stringify!($op).to_owned(),
+                })
+            }
+        }
+    };
+    (@handler [$(#[$meta:meta])*] $kws:tt $op:ident ($($arg:ident: $arg_ty:ty),*) [$ret_ty:ty] [$body:block] [$label:expr]) => {
+        $(#[$meta])*
+        #[allow(unused_variables)]
+        fn $op(&self, $($arg: &$arg_ty),*)
+        -> impl Future<Output = ::amimono::rpc::RpcResult<$ret_ty>> + Send {
+            async move $body
+        }
+    };
+
+    (@optional [optional $(batch)?]) => { true };
+    (@optional [$(batch)?]) => { false };
+
+    (@priority [$(optional)?]) => { ::amimono::rpc::Priority::Interactive };
+    (@priority [$(optional)? batch]) => { ::amimono::rpc::Priority::Batch };
+
+    (@http) => { None };
+    (@http $http:literal) => { Some($http) };
+
+    // Clients of optional ops get `Unimplemented` as is, rather than wrapped
+    // in `Downstream`, so they can easily check for it.
+    (@client_error [optional $(batch)?] $e:ident) => {
+        match $e.root_cause() {
+            e @ ::amimono::AppError::Unimplemented { .. } => e.clone(),
+            _ => $e,
+        }
+    };
+    (@client_error [$(batch)?] $e:ident) => { $e };
+
+    (@main {
         $(#![$topmeta:meta])*
         const LABEL: &'static str = $label:expr;
-
-        $($(#[$meta:meta])*
-        fn $op:ident ($($arg:ident: $arg_ty:ty),*) -> $ret_ty:ty;)*
-    } => {
+        $(const ALLOWED_CALLERS: &'static [&'static str] = $callers:expr;)?
+        $(const STORAGE: Option<usize> = $storage:expr;)?
+        $([subscribe [$(#[$smeta:meta])*] $sub:ident ($($sarg:ident: $sarg_ty:ty),*) [$ev_ty:ty]])*
+    } $([[$(#[$meta:meta])*] $kws:tt $op:ident ($($arg:ident: $arg_ty:ty),*) [$ret_ty:ty] [$($body:block)?] [$($http:literal)?]])*) => {
         #[derive(::serde::Serialize, ::serde::Deserialize)]
         #[allow(non_camel_case_types)]
         pub enum Request {
@@ -96,6 +475,12 @@ macro_rules! rpc_component {
             $($op($ret_ty)),*
         }
 
+        #[derive(::serde::Serialize, ::serde::Deserialize)]
+        #[allow(non_camel_case_types)]
+        pub enum Subscribe {
+            $($sub($($sarg_ty),*)),*
+        }
+
         impl ::amimono::rpc::RpcMessage for Request {
             fn verb(&self) -> &'static str {
                 match self {
@@ -112,12 +497,22 @@ macro_rules! rpc_component {
         }
 
         $(#[$topmeta])*
+        // ops take their args by reference, so `String` args become `&String`
+        #[allow(clippy::ptr_arg)]
         pub trait Handler: Sync + Send + Sized + 'static {
             fn new() -> impl Future<Output = Self> + Send;
 
-            $($(#[$meta])*
-            fn $op(&self, $($arg: &$arg_ty),*)
-            -> impl Future<Output = ::amimono::rpc::RpcResult<$ret_ty>> + Send;)*
+            fn local_dependencies() -> Vec<::amimono::component::LocalDependency> {
+                Vec::new()
+            }
+
+            $(::amimono::rpc_component! {
+                @handler [$(#[$meta])*] $kws $op ($($arg: $arg_ty),*) [$ret_ty] [$($body)?] [$label]
+            })*
+
+            $($(#[$smeta])*
+            fn $sub(&self, $($sarg: &$sarg_ty),*)
+            -> impl Future<Output = ::amimono::rpc::RpcResult<::amimono::rpc::Topic<$ev_ty>>> + Send;)*
         }
 
         $(#[$topmeta])*
@@ -128,11 +523,59 @@ macro_rules! rpc_component {
             type Response = Response;
 
             const LABEL: &'static str = $label;
+            const OPS: &'static [::amimono::rpc::RpcOp] = &[
+                $(::amimono::rpc::RpcOp {
+                    name: stringify!($op),
+                    args: &[$(stringify!($arg_ty)),*],
+                    arg_names: &[$(stringify!($arg)),*],
+                    ret: stringify!($ret_ty),
+                    optional: ::amimono::rpc_component!(@optional $kws),
+                    priority: ::amimono::rpc_component!(@priority $kws),
+                    http: ::amimono::rpc_component!(@http $($http)?),
+                    schemas: || {
+                        #[allow(unused_imports)]
+                        use ::amimono::rpc::{HasJsonSchema as _, NoJsonSchema as _};
+                        ::amimono::rpc::RpcOpSchemas {
+                            args: ::std::vec![$((&::amimono::rpc::SchemaOf::<$arg_ty>::new()).json_schema()),*],
+                            ret: (&::amimono::rpc::SchemaOf::<$ret_ty>::new()).json_schema(),
+                        }
+                    },
+                }),*
+            ];
+            const SUBSCRIPTIONS: &'static [::amimono::rpc::RpcOp] = &[
+                $(::amimono::rpc::RpcOp {
+                    name: stringify!($sub),
+                    args: &[$(stringify!($sarg_ty)),*],
+                    arg_names: &[$(stringify!($sarg)),*],
+                    ret: stringify!($ev_ty),
+                    optional: false,
+                    priority: ::amimono::rpc::Priority::Interactive,
+                    http: None,
+                    schemas: || {
+                        #[allow(unused_imports)]
+                        use ::amimono::rpc::{HasJsonSchema as _, NoJsonSchema as _};
+                        ::amimono::rpc::RpcOpSchemas {
+                            args: ::std::vec![$((&::amimono::rpc::SchemaOf::<$sarg_ty>::new()).json_schema()),*],
+                            ret: (&::amimono::rpc::SchemaOf::<$ev_ty>::new()).json_schema(),
+                        }
+                    },
+                }),*
+            ];
+            $(const ALLOWED_CALLERS: Option<&'static [&'static str]> = Some($callers);)?
+            $(const STORAGE: Option<usize> = $storage;)?
         }
 
         $(#[$topmeta])*
         pub struct Component<H>(H);
 
+        impl<H: Handler> Component<H> {
+            /// Wrap an already constructed handler, e.g. to verify it
+            /// against golden fixtures in a test.
+            pub fn from_handler(handler: H) -> Self {
+                Component(handler)
+            }
+        }
+
         impl<H: Handler> ::amimono::rpc::RpcComponent for Component<H> {
             type Kind = ComponentKind;
 
@@ -140,6 +583,10 @@ macro_rules! rpc_component {
                 Component(H::new().await)
             }
 
+            fn local_dependencies() -> Vec<::amimono::component::LocalDependency> {
+                H::local_dependencies()
+            }
+
             async fn handle(&self, q: &Request)
             -> ::amimono::rpc::RpcResult<Response> {
                 match q {
@@ -151,6 +598,16 @@ macro_rules! rpc_component {
                     })*
                 }
             }
+
+            async fn subscribe(&self, q: &[u8])
+            -> ::amimono::rpc::RpcResult<::std::sync::Arc<dyn ::amimono::rpc::DynTopic>> {
+                match ::amimono::rpc::parse_subscription::<ComponentKind, Subscribe>(q)? {
+                    $(Subscribe::$sub($($sarg),*) => {
+                        let topic = self.0.$sub($(&$sarg),*).await?;
+                        Ok(::std::sync::Arc::new(topic))
+                    })*
+                }
+            }
         }
 
         $(#[$topmeta])*
@@ -180,6 +637,55 @@ macro_rules! rpc_component {
             }
         }
 
+        impl<R: Sync + Clone> Client<R> {
+            pub fn with_affinity<K: ::std::hash::Hash>(&self, key: K) -> Client<R> {
+                Client(self.0.clone().with_affinity(key))
+            }
+
+            pub fn with_recording<P: Into<::std::path::PathBuf>>(&self, path: P) -> Client<R> {
+                Client(self.0.clone().with_recording(path))
+            }
+
+            pub fn with_http_version(&self, version: ::amimono::rpc::HttpVersion) -> Client<R> {
+                Client(self.0.clone().with_http_version(version))
+            }
+
+            pub fn with_request_timeout(&self, timeout: ::std::time::Duration) -> Client<R> {
+                Client(self.0.clone().with_request_timeout(timeout))
+            }
+
+            pub fn with_op_request_timeout(&self, op: &str, timeout: ::std::time::Duration) -> Client<R> {
+                Client(self.0.clone().with_op_request_timeout(op, timeout))
+            }
+
+            pub fn with_operation_timeout(&self, timeout: ::std::time::Duration) -> Client<R> {
+                Client(self.0.clone().with_operation_timeout(timeout))
+            }
+
+            pub fn with_failover(&self, policy: ::amimono::rpc::FailoverPolicy) -> Client<R> {
+                Client(self.0.clone().with_failover(policy))
+            }
+
+            pub fn with_unready_replicas(&self) -> Client<R> {
+                Client(self.0.clone().with_unready_replicas())
+            }
+
+            pub fn with_shard(&self, shard: u32) -> Client<R> {
+                Client(self.0.clone().with_shard(shard))
+            }
+
+            pub fn with_adaptive_limit(&self, policy: ::amimono::rpc::AdaptiveLimit) -> Client<R> {
+                Client(self.0.clone().with_adaptive_limit(policy))
+            }
+
+            pub fn with_observer(
+                &self,
+                observer: ::std::sync::Arc<dyn ::amimono::rpc::CallObserver>,
+            ) -> Client<R> {
+                Client(self.0.clone().with_observer(observer))
+            }
+        }
+
         impl<R: Clone> Client<R> {
             pub fn at<A>(&self, loc: ::amimono::component::Location<A>) -> ClientAt<A, R> {
                 ClientAt {
@@ -187,21 +693,69 @@ macro_rules! rpc_component {
                     inner: self.0.clone(),
                 }
             }
+
+            pub fn progress(&self) -> ProgressClient<R> {
+                ProgressClient(self.0.clone())
+            }
+        }
+
+        impl<R: ::amimono::retry::RetryStrategy<::amimono::rpc::RpcError> + Clone> Client<R> {
+            /// Make a call to every running replica concurrently, through a
+            /// `ClientAt` for each, returning the location and result of
+            /// each.
+            pub async fn for_each_replica<F, Fut, X>(&self, f: F)
+            -> ::amimono::rpc::RpcResult<Vec<(::amimono::component::Location, ::amimono::rpc::RpcResult<X>)>>
+            where
+                F: Fn(ClientAt<String, R>) -> Fut,
+                Fut: ::std::future::Future<Output = ::amimono::rpc::RpcResult<X>>,
+            {
+                self.0.for_each_replica(|loc| f(self.at(loc))).await
+            }
         }
 
         impl<R: ::amimono::retry::RetryStrategy<::amimono::rpc::RpcError>> Client<R> {
+            /// Send an already serialized request. See
+            /// `RpcClient::call_raw_once` for details.
+            pub async fn call_raw(&self, q: ::amimono::rpc::Bytes)
+            -> ::amimono::rpc::RpcResult<::amimono::rpc::Bytes> {
+                self.0.call_raw(q).await
+            }
+
             $($(#[$meta])*
             pub async fn $op(&self, $($arg: $arg_ty),*)
             -> ::amimono::rpc::RpcResult<$ret_ty> {
                 use ::amimono::rpc::RpcMessage;
 
                 let q = Request::$op($($arg),*);
-                match self.0.call(&q).await {
+                match self.0.call_owned(q).await {
                     Ok(Response::$op(a)) => Ok(a),
                     Ok(x) => panic!("got {} but was expecting {}", x.verb(), stringify!($op)),
-                    Err(e) => Err(e)
+                    Err(e) => Err(::amimono::rpc_component!(@client_error $kws e)),
                 }
             })*
+
+            $($(#[$smeta])*
+            pub async fn $sub(&self, $($sarg: $sarg_ty),*)
+            -> ::amimono::rpc::RpcResult<::amimono::rpc::Subscription<$ev_ty>> {
+                self.0.subscribe(&Subscribe::$sub($($sarg),*)).await
+            })*
+        }
+
+        $(#[$topmeta])*
+        pub struct ProgressClient<R = ::amimono::retry::Retry>(::amimono::rpc::RpcClient<ComponentKind, R>);
+
+        impl<R: Sync> ProgressClient<R> {
+            $($(#[$meta])*
+            pub fn $op(&self, $($arg: $arg_ty),*)
+            -> ::amimono::rpc::Progress<$ret_ty> {
+                use ::amimono::rpc::RpcMessage;
+
+                let q = Request::$op($($arg),*);
+                self.0.call_with_progress(q).map(|a| match a {
+                    Response::$op(a) => Ok(a),
+                    x => panic!("got {} but was expecting {}", x.verb(), stringify!($op)),
+                })
+            })*
         }
 
         $(#[$topmeta])*
@@ -219,6 +773,39 @@ macro_rules! rpc_component {
             }
         }
 
+        impl<A: Clone, R: Sync + Clone> ClientAt<A, R> {
+            pub fn with_recording<P: Into<::std::path::PathBuf>>(&self, path: P) -> ClientAt<A, R> {
+                ClientAt { loc: self.loc.clone(), inner: self.inner.clone().with_recording(path) }
+            }
+
+            pub fn with_http_version(&self, version: ::amimono::rpc::HttpVersion) -> ClientAt<A, R> {
+                ClientAt { loc: self.loc.clone(), inner: self.inner.clone().with_http_version(version) }
+            }
+
+            pub fn with_request_timeout(&self, timeout: ::std::time::Duration) -> ClientAt<A, R> {
+                ClientAt { loc: self.loc.clone(), inner: self.inner.clone().with_request_timeout(timeout) }
+            }
+
+            pub fn with_op_request_timeout(&self, op: &str, timeout: ::std::time::Duration) -> ClientAt<A, R> {
+                ClientAt { loc: self.loc.clone(), inner: self.inner.clone().with_op_request_timeout(op, timeout) }
+            }
+
+            pub fn with_operation_timeout(&self, timeout: ::std::time::Duration) -> ClientAt<A, R> {
+                ClientAt { loc: self.loc.clone(), inner: self.inner.clone().with_operation_timeout(timeout) }
+            }
+
+            pub fn with_adaptive_limit(&self, policy: ::amimono::rpc::AdaptiveLimit) -> ClientAt<A, R> {
+                ClientAt { loc: self.loc.clone(), inner: self.inner.clone().with_adaptive_limit(policy) }
+            }
+
+            pub fn with_observer(
+                &self,
+                observer: ::std::sync::Arc<dyn ::amimono::rpc::CallObserver>,
+            ) -> ClientAt<A, R> {
+                ClientAt { loc: self.loc.clone(), inner: self.inner.clone().with_observer(observer) }
+            }
+        }
+
         impl<A: Clone, R: Clone> Clone for ClientAt<A, R> {
             fn clone(&self) -> Self {
                 Self {
@@ -228,7 +815,11 @@ macro_rules! rpc_component {
             }
         }
 
-        impl<A> ClientAt<A> where A: ::std::borrow::Borrow<str> {
+        impl<A, R> ClientAt<A, R>
+        where
+            A: ::std::borrow::Borrow<str>,
+            R: ::amimono::retry::RetryStrategy<::amimono::rpc::RpcError>,
+        {
             $($(#[$meta])*
             pub async fn $op(&self, $($arg: $arg_ty),*)
             -> ::amimono::rpc::RpcResult<$ret_ty> {
@@ -238,9 +829,33 @@ macro_rules! rpc_component {
                 match self.inner.call_at(&self.loc, &q).await {
                     Ok(Response::$op(a)) => Ok(a),
                     Ok(x) => panic!("got {} but was expecting {}", x.verb(), stringify!($op)),
-                    Err(e) => Err(e)
+                    Err(e) => Err(::amimono::rpc_component!(@client_error $kws e)),
                 }
             })*
         }
-    }
+    };
+
+    {
+        $(#[$modmeta:meta])*
+        $vis:vis mod $name:ident {
+            $($body:tt)*
+        }
+    } => {
+        $(#[$modmeta])*
+        $vis mod $name {
+            ::amimono::rpc_component! { $($body)* }
+        }
+    };
+
+    {
+        $(#![$topmeta:meta])*
+        const LABEL: &'static str = $label:expr;
+
+        $($rest:tt)*
+    } => {
+        ::amimono::rpc_component!(@header {
+            $(#![$topmeta])*
+            const LABEL: &'static str = $label;
+        } $($rest)*);
+    };
 }
@@ -5,14 +5,53 @@
 //! this module directly, however they are documented for the sake of
 //! completeness.
 
+mod adaptive;
+mod auth;
+mod buf;
+pub mod capture;
 mod client;
 mod component;
-mod http;
+mod conn;
+mod dispatch;
+mod failover;
+pub mod golden;
+pub(crate) mod http;
+pub mod journal;
 mod macros;
+pub mod observe;
+mod outlier;
+mod priority;
+mod progress;
+#[cfg(feature = "proto")]
+mod proto;
+mod quota;
+mod ramp;
+mod raw;
+pub(crate) mod sampling;
+mod seal;
+mod shaping;
+mod single_flight;
+mod subscribe;
+mod timeout;
 
+pub use adaptive::AdaptiveLimit;
+pub use buf::to_json_bytes;
 pub use client::RpcClient;
-pub use component::{RpcComponent, RpcComponentKind, RpcMessage};
-pub use http::PORT;
+#[doc(hidden)]
+pub use component::{HasJsonSchema, NoJsonSchema, SchemaOf};
+pub use component::{Priority, RpcComponent, RpcComponentKind, RpcMessage, RpcOp, RpcOpSchemas};
+pub use failover::FailoverPolicy;
+pub use http::{HttpVersion, PORT, PROTOCOL_VERSION, server_info};
+pub use observe::{CallObserver, add_call_observer};
+pub use progress::{Progress, ProgressUpdate, report_progress};
+#[cfg(feature = "proto")]
+pub use proto::Proto;
+pub use raw::serve_raw;
+pub use sampling::LogSampling;
+pub use subscribe::{DynTopic, Subscription, Topic, parse_subscription};
+pub use timeout::DEFAULT_REQUEST_TIMEOUT;
+
+pub use axum::body::Bytes;
 
 pub type RpcError = crate::AppError;
 pub type RpcResult<T> = crate::AppResult<T>;
@@ -0,0 +1,211 @@
+//! Hooks for reporting RPC calls to custom telemetry.
+//!
+//! The built-in metrics are served in the OpenMetrics format, which suits
+//! Prometheus. Apps that report to something else, like StatsD or a vendor
+//! agent, can implement [`CallObserver`] and register it, either for every
+//! call made and served by the process with [`add_call_observer`], or for the
+//! calls made by one client with
+//! [`RpcClient::with_observer`][crate::rpc::RpcClient::with_observer].
+//!
+//! Calls made with `call_with_progress` are only observed by the server.
+
+use std::{
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::rpc::{RpcError, RpcResult};
+
+/// Which end of a call is being observed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Side {
+    /// The call is being made by this process.
+    Client,
+    /// The call is being served by this process.
+    Server,
+}
+
+/// Where an attempt at a call was sent.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Destination<'a> {
+    /// The component is running in this process, and its handler was invoked
+    /// directly.
+    Local,
+    /// The attempt was sent over HTTP to the replica at this address.
+    Remote(&'a str),
+    /// The attempt failed before a replica was picked, e.g. because
+    /// discovery failed.
+    Unresolved,
+}
+
+/// A call being observed.
+#[derive(Copy, Clone, Debug)]
+pub struct CallInfo<'a> {
+    pub side: Side,
+    /// The label of the component being called.
+    pub label: &'a str,
+    /// The name of the op being called, or `unknown` if a serialized request
+    /// couldn't be read.
+    pub verb: &'a str,
+}
+
+/// A single attempt at a call, made by a client.
+#[derive(Copy, Clone, Debug)]
+pub struct Attempt<'a> {
+    /// The number of the attempt within the call, starting at 1.
+    pub number: u32,
+    pub destination: Destination<'a>,
+    pub duration: Duration,
+    pub outcome: Result<(), &'a RpcError>,
+}
+
+/// Receives events for RPC calls. All methods do nothing by default.
+///
+/// Observers are called inline on the calling task, so they should hand off
+/// anything slow, like sending packets, to a background task.
+pub trait CallObserver: Send + Sync + 'static {
+    /// Called when a call starts, before its first attempt.
+    fn on_start(&self, _call: &CallInfo) {}
+
+    /// Called after each attempt at a call made by a client, including ones
+    /// that will be retried. Servers don't report attempts.
+    fn on_attempt(&self, _call: &CallInfo, _attempt: &Attempt) {}
+
+    /// Called when a call completes, with the time since it started and its
+    /// final outcome. For clients, errors are the callee's, not wrapped in
+    /// [`RpcError::Downstream`].
+    fn on_complete(&self, _call: &CallInfo, _duration: Duration, _outcome: Result<(), &RpcError>) {}
+}
+
+static OBSERVERS: LazyLock<Mutex<Vec<Arc<dyn CallObserver>>>> =
+    LazyLock::new(|| Mutex::new(Vec::new()));
+
+/// Register an observer for every call made or served by this process. This
+/// should be called before the app starts, e.g. at the top of `main`.
+pub fn add_call_observer<O: CallObserver>(observer: O) {
+    OBSERVERS
+        .lock()
+        .expect("lock poisoned")
+        .push(Arc::new(observer));
+}
+
+/// The name of the op in a serialized request, which is an externally tagged
+/// enum, i.e. `{"op": args}`, or just `"op"` for ops without arguments.
+pub(crate) fn op_name(q: &[u8]) -> Option<&str> {
+    let q = std::str::from_utf8(q).ok()?.trim_start();
+    let q = q.strip_prefix('{').map(str::trim_start).unwrap_or(q);
+    let rest = q.strip_prefix('"')?;
+    rest.get(..rest.find('"')?)
+}
+
+struct Inner {
+    observers: Vec<Arc<dyn CallObserver>>,
+    side: Side,
+    label: String,
+    verb: String,
+    started: Instant,
+    attempts: AtomicU32,
+}
+
+impl Inner {
+    fn info(&self) -> CallInfo<'_> {
+        CallInfo {
+            side: self.side,
+            label: &self.label,
+            verb: &self.verb,
+        }
+    }
+}
+
+/// The observation of a single call. This does nothing if there are no
+/// observers for the call.
+pub(crate) struct Observation(Option<Box<Inner>>);
+
+impl Observation {
+    fn start(extra: Option<&Arc<dyn CallObserver>>, side: Side, label: &str, verb: &str) -> Self {
+        let mut observers = OBSERVERS.lock().expect("lock poisoned").clone();
+        observers.extend(extra.cloned());
+        if observers.is_empty() {
+            return Observation(None);
+        }
+        let inner = Inner {
+            observers,
+            side,
+            label: label.to_owned(),
+            verb: verb.to_owned(),
+            started: Instant::now(),
+            attempts: AtomicU32::new(0),
+        };
+        for o in inner.observers.iter() {
+            o.on_start(&inner.info());
+        }
+        Observation(Some(Box::new(inner)))
+    }
+
+    /// Start observing a call made by a client.
+    pub(crate) fn client(
+        observer: Option<&Arc<dyn CallObserver>>,
+        label: &str,
+        verb: &str,
+    ) -> Self {
+        Self::start(observer, Side::Client, label, verb)
+    }
+
+    /// Start observing a call made by a client with a serialized request.
+    pub(crate) fn client_raw(
+        observer: Option<&Arc<dyn CallObserver>>,
+        label: &str,
+        q: &[u8],
+    ) -> Self {
+        Self::start(
+            observer,
+            Side::Client,
+            label,
+            op_name(q).unwrap_or("unknown"),
+        )
+    }
+
+    /// Start observing a call served by this process.
+    pub(crate) fn server(label: &str, q: &[u8]) -> Self {
+        Self::start(None, Side::Server, label, op_name(q).unwrap_or("unknown"))
+    }
+
+    /// Report an attempt that started at the given time.
+    pub(crate) fn attempt<T>(
+        &self,
+        destination: Destination,
+        started: Instant,
+        res: &RpcResult<T>,
+    ) {
+        let Some(inner) = &self.0 else {
+            return;
+        };
+        let attempt = Attempt {
+            number: inner.attempts.fetch_add(1, Ordering::Relaxed) + 1,
+            destination,
+            duration: started.elapsed(),
+            outcome: res.as_ref().map(|_| ()),
+        };
+        for o in inner.observers.iter() {
+            o.on_attempt(&inner.info(), &attempt);
+        }
+    }
+
+    pub(crate) fn complete<T>(&self, res: &RpcResult<T>) {
+        let Some(inner) = &self.0 else {
+            return;
+        };
+        let outcome = match res {
+            Ok(_) => Ok(()),
+            Err(RpcError::Downstream(label, e)) if *label == inner.label => Err(&**e),
+            Err(e) => Err(e),
+        };
+        let duration = inner.started.elapsed();
+        for o in inner.observers.iter() {
+            o.on_complete(&inner.info(), duration, outcome);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Passive health tracking for RPC endpoints.
+//!
+//! Every HTTP call records whether the endpoint it was sent to responded
+//! properly. Endpoints that fail too often are ejected: they're left out of
+//! the replicas that calls are balanced across, so a single bad replica
+//! doesn't keep receiving its full share of traffic until discovery notices
+//! it's gone. Ejected endpoints are probed in the background, and put back
+//! once they're ready again.
+//!
+//! Only failures that point at the endpoint itself count: connection errors,
+//! timeouts, and spurious errors raised by the endpoint. Errors returned by
+//! handlers, or passed along from further downstream, don't.
+//!
+//! Clients with a [`FailoverPolicy`][crate::rpc::FailoverPolicy] can also
+//! mark an endpoint as suspect after a single failure, which leaves it out
+//! for a short time without waiting for it to be ejected.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{component::Replica, metrics, rpc::http::PORT};
+
+/// Consecutive failures after which an endpoint is ejected.
+const MAX_CONSECUTIVE_FAILURES: u32 = 5;
+
+/// Failure rate above which an endpoint is ejected, once enough calls have
+/// been made to it.
+const MAX_FAILURE_RATE: f64 = 0.5;
+const MIN_CALLS: u32 = 20;
+
+/// Weight of each call in the failure rate's moving average.
+const RATE_ALPHA: f64 = 0.1;
+
+/// How long the first ejection lasts. Each ejection after that without the
+/// endpoint recovering in between doubles, up to `MAX_EJECTION`.
+const BASE_EJECTION: Duration = Duration::from_secs(10);
+const MAX_EJECTION: Duration = Duration::from_secs(300);
+
+#[derive(Default)]
+struct Endpoint {
+    calls: u32,
+    consecutive_failures: u32,
+    consecutive_successes: u32,
+    failure_rate: f64,
+    ejections: u32,
+    ejected_until: Option<Instant>,
+    suspect_until: Option<Instant>,
+}
+
+impl Endpoint {
+    fn is_ejected(&self) -> bool {
+        self.ejected_until.is_some()
+    }
+
+    fn is_suspect(&self) -> bool {
+        self.suspect_until.is_some_and(|t| Instant::now() < t)
+    }
+
+    fn ejection_time(&self) -> Duration {
+        let factor = 1u32 << self.ejections.saturating_sub(1).min(16);
+        (BASE_EJECTION * factor).min(MAX_EJECTION)
+    }
+}
+
+static ENDPOINTS: LazyLock<Mutex<HashMap<String, Endpoint>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Record the outcome of a call to an endpoint of the given component.
+pub(crate) fn record(label: &'static str, addr: &str, ok: bool) {
+    let mut endpoints = ENDPOINTS.lock().expect("lock poisoned");
+    let ep = endpoints.entry(addr.to_owned()).or_default();
+    if ep.is_ejected() {
+        // calls made directly to an ejected endpoint don't affect it, the
+        // background probe decides when it comes back
+        return;
+    }
+
+    ep.calls = ep.calls.saturating_add(1);
+    ep.failure_rate += RATE_ALPHA * ((!ok as u8 as f64) - ep.failure_rate);
+    if ok {
+        ep.consecutive_failures = 0;
+        ep.consecutive_successes = ep.consecutive_successes.saturating_add(1);
+        if ep.consecutive_successes >= MIN_CALLS {
+            ep.ejections = 0;
+        }
+        return;
+    }
+    ep.consecutive_failures += 1;
+    ep.consecutive_successes = 0;
+
+    let too_many = ep.consecutive_failures >= MAX_CONSECUTIVE_FAILURES;
+    let too_often = ep.calls >= MIN_CALLS && ep.failure_rate > MAX_FAILURE_RATE;
+    if too_many || too_often {
+        ep.ejections += 1;
+        let time = ep.ejection_time();
+        ep.ejected_until = Some(Instant::now() + time);
+        log::warn!("ejecting {label} endpoint {addr} for {time:?} after repeated failures");
+        metrics::counter("amimono_rpc_ejections", &[("component", label)]).inc();
+        tokio::spawn(probe(label, addr.to_owned(), time));
+    }
+}
+
+/// Leave an endpoint out of the replicas calls are balanced across for a
+/// while, without ejecting it.
+pub(crate) fn suspect(label: &'static str, addr: &str, time: Duration) {
+    let mut endpoints = ENDPOINTS.lock().expect("lock poisoned");
+    let ep = endpoints.entry(addr.to_owned()).or_default();
+    log::debug!("marking {label} endpoint {addr} as suspect for {time:?}");
+    ep.suspect_until = Some(Instant::now() + time);
+}
+
+/// Remove ejected and suspect endpoints from a list of replicas. If every
+/// replica is left out, they're all returned instead, since sending calls to
+/// a possibly unhealthy replica is better than failing them outright.
+pub(crate) fn filter(replicas: Vec<Replica>) -> Vec<Replica> {
+    let endpoints = ENDPOINTS.lock().expect("lock poisoned");
+    let healthy = replicas
+        .iter()
+        .filter(|r| {
+            let addr: &str = r.location.addr();
+            !endpoints
+                .get(addr)
+                .is_some_and(|ep| ep.is_ejected() || ep.is_suspect())
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    match healthy.is_empty() {
+        true => replicas,
+        false => healthy,
+    }
+}
+
+/// Wait out an ejection, then check the endpoint's readiness until it
+/// passes, backing off between attempts.
+async fn probe(label: &'static str, addr: String, mut wait: Duration) {
+    let client = reqwest::Client::new();
+    let url = format!("http://{addr}:{PORT}/ready");
+    loop {
+        tokio::time::sleep(wait).await;
+        let ready = match client
+            .get(&url)
+            .timeout(Duration::from_secs(2))
+            .send()
+            .await
+        {
+            Ok(resp) => resp.status().is_success(),
+            Err(_) => false,
+        };
+
+        let mut endpoints = ENDPOINTS.lock().expect("lock poisoned");
+        let Some(ep) = endpoints.get_mut(&addr) else {
+            return;
+        };
+        if ready {
+            log::info!("{label} endpoint {addr} is ready again, returning it to the pool");
+            ep.calls = 0;
+            ep.consecutive_failures = 0;
+            ep.failure_rate = 0.0;
+            ep.ejected_until = None;
+            return;
+        }
+        ep.ejections += 1;
+        wait = ep.ejection_time();
+        ep.ejected_until = Some(Instant::now() + wait);
+        log::debug!("{label} endpoint {addr} still not ready, probing again in {wait:?}");
+    }
+}
@@ -0,0 +1,291 @@
+//! Scheduling of incoming requests by the priority of their ops.
+//!
+//! Components with a concurrency limit admit requests through a scheduler.
+//! Batch requests can only hold part of the capacity, waiting interactive
+//! requests are admitted first, and every so often a waiting batch request is
+//! admitted ahead of them, so that neither class starves the other. When the
+//! queue is full, waiting batch requests are shed to make room for
+//! interactive ones. Shed requests fail with
+//! [`AppError::Overloaded`][crate::AppError::Overloaded], telling the caller
+//! how long to wait before trying again, going by how long the requests ahead
+//! of it take.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::oneshot;
+
+use crate::{
+    config::ConcurrencyConfig,
+    metrics,
+    rpc::{Priority, RpcError, RpcResult},
+    runtime,
+};
+
+/// How many interactive requests are admitted in a row while batch requests
+/// are waiting, before one of them is admitted.
+const INTERACTIVE_BURST: u32 = 8;
+
+/// How long requests are assumed to take before any have been handled.
+const INITIAL_LATENCY: Duration = Duration::from_millis(100);
+
+/// The bounds on how long shed callers are asked to wait.
+const MIN_RETRY_AFTER: Duration = Duration::from_millis(50);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+struct State {
+    in_flight: usize,
+    batch_in_flight: usize,
+    interactive: VecDeque<oneshot::Sender<()>>,
+    batch: VecDeque<oneshot::Sender<()>>,
+    /// Interactive requests admitted since a waiting batch request was.
+    skipped: u32,
+    /// How long admitted requests have recently taken, as a moving average.
+    latency: Duration,
+}
+
+struct Scheduler {
+    label: String,
+    cf: ConcurrencyConfig,
+    state: Mutex<State>,
+}
+
+static SCHEDULERS: LazyLock<Mutex<HashMap<String, Option<Arc<Scheduler>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn scheduler(label: &str) -> Option<Arc<Scheduler>> {
+    let mut schedulers = SCHEDULERS.lock().expect("lock poisoned");
+    schedulers
+        .entry(label.to_owned())
+        .or_insert_with(|| {
+            let cf = runtime::config().concurrency_limit(label)?.clone();
+            Some(Arc::new(Scheduler {
+                label: label.to_owned(),
+                cf,
+                state: Mutex::new(State {
+                    in_flight: 0,
+                    batch_in_flight: 0,
+                    interactive: VecDeque::new(),
+                    batch: VecDeque::new(),
+                    skipped: 0,
+                    latency: INITIAL_LATENCY,
+                }),
+            }))
+        })
+        .clone()
+}
+
+/// The priority of an op of the component, which is interactive for ops it
+/// doesn't know.
+pub(crate) fn priority(label: &str, op: Option<&str>) -> Priority {
+    let ops = runtime::config()
+        .component(label)
+        .and_then(|c| c.rpc_ops)
+        .unwrap_or_default();
+    ops.iter()
+        .find(|o| Some(o.name) == op)
+        .map(|o| o.priority)
+        .unwrap_or(Priority::Interactive)
+}
+
+impl Scheduler {
+    fn has_room(&self, st: &State, priority: Priority) -> bool {
+        st.in_flight < self.cf.max_in_flight
+            && (priority == Priority::Interactive || st.batch_in_flight < self.cf.max_batch)
+    }
+
+    fn start(&self, st: &mut State, priority: Priority) {
+        st.in_flight += 1;
+        if priority == Priority::Batch {
+            st.batch_in_flight += 1;
+        }
+    }
+
+    fn finish(&self, st: &mut State, priority: Priority, took: Option<Duration>) {
+        if let Some(took) = took {
+            st.latency = st.latency.mul_f64(0.8) + took.mul_f64(0.2);
+        }
+        st.in_flight -= 1;
+        if priority == Priority::Batch {
+            st.batch_in_flight -= 1;
+        }
+    }
+
+    /// Admit waiting requests while there's room for them.
+    fn admit_waiting(&self, st: &mut State) {
+        loop {
+            let batch_turn = st.skipped >= INTERACTIVE_BURST || st.interactive.is_empty();
+            let priority =
+                if batch_turn && !st.batch.is_empty() && self.has_room(st, Priority::Batch) {
+                    Priority::Batch
+                } else if !st.interactive.is_empty() && self.has_room(st, Priority::Interactive) {
+                    Priority::Interactive
+                } else {
+                    break;
+                };
+            let waiter = match priority {
+                Priority::Interactive => st.interactive.pop_front(),
+                Priority::Batch => st.batch.pop_front(),
+            };
+            let Some(waiter) = waiter else {
+                break;
+            };
+            // waiters that have gone away, e.g. because the caller
+            // disconnected, are skipped
+            if waiter.send(()).is_ok() {
+                self.start(st, priority);
+                st.skipped = match priority {
+                    Priority::Interactive if !st.batch.is_empty() => st.skipped + 1,
+                    _ => 0,
+                };
+            }
+        }
+        self.report(st);
+    }
+
+    fn report(&self, st: &State) {
+        let labels = [("component", self.label.as_str())];
+        metrics::gauge("amimono_rpc_in_flight", &labels).set(st.in_flight as f64);
+        let queued = st.interactive.len() + st.batch.len();
+        metrics::gauge("amimono_rpc_queued", &labels).set(queued as f64);
+    }
+
+    fn shed(&self, st: &State, priority: Priority) -> RpcError {
+        let class = match priority {
+            Priority::Interactive => "interactive",
+            Priority::Batch => "batch",
+        };
+        let labels = [("component", self.label.as_str()), ("priority", class)];
+        metrics::counter("amimono_rpc_shed", &labels).inc();
+        self.overloaded(st)
+    }
+
+    /// The error for a shed request, asking the caller to wait about as long
+    /// as it takes to work through the requests in flight and waiting.
+    fn overloaded(&self, st: &State) -> RpcError {
+        let waiting = st.interactive.len() + st.batch.len();
+        let rounds = 1.0 + waiting as f64 / self.cf.max_in_flight.max(1) as f64;
+        let retry_after = st
+            .latency
+            .mul_f64(rounds)
+            .clamp(MIN_RETRY_AFTER, MAX_RETRY_AFTER);
+        RpcError::Overloaded {
+            component: self.label.clone(),
+            retry_after_ms: retry_after.as_millis() as u64,
+        }
+    }
+}
+
+/// A request's place in the component's capacity, released when dropped.
+pub(crate) struct Permit {
+    sched: Option<Arc<Scheduler>>,
+    priority: Priority,
+    admitted: Instant,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(sched) = &self.sched {
+            let mut st = sched.state.lock().expect("lock poisoned");
+            sched.finish(&mut st, self.priority, Some(self.admitted.elapsed()));
+            sched.admit_waiting(&mut st);
+        }
+    }
+}
+
+/// Releases a waiter's place if it's admitted after it stops waiting.
+struct Waiting {
+    rx: oneshot::Receiver<()>,
+    sched: Arc<Scheduler>,
+    priority: Priority,
+    admitted: bool,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if self.admitted {
+            return;
+        }
+        self.rx.close();
+        if self.rx.try_recv().is_ok() {
+            let mut st = self.sched.state.lock().expect("lock poisoned");
+            self.sched.finish(&mut st, self.priority, None);
+            self.sched.admit_waiting(&mut st);
+        }
+    }
+}
+
+/// Wait for room to handle a request to the component, or fail with an
+/// overloaded error if it's shed. Components without a concurrency limit admit
+/// every request right away.
+pub(crate) async fn admit(label: &str, priority: Priority) -> RpcResult<Permit> {
+    let Some(sched) = scheduler(label) else {
+        return Ok(Permit {
+            sched: None,
+            priority,
+            admitted: Instant::now(),
+        });
+    };
+
+    let rx = {
+        let mut st = sched.state.lock().expect("lock poisoned");
+        let queue_empty = match priority {
+            Priority::Interactive => st.interactive.is_empty(),
+            Priority::Batch => st.batch.is_empty(),
+        };
+        if queue_empty && sched.has_room(&st, priority) {
+            sched.start(&mut st, priority);
+            sched.report(&st);
+            drop(st);
+            return Ok(Permit {
+                sched: Some(sched),
+                priority,
+                admitted: Instant::now(),
+            });
+        }
+
+        if st.interactive.len() + st.batch.len() >= sched.cf.max_queued {
+            // the newest waiting batch request is shed to make room
+            match priority {
+                Priority::Interactive if !st.batch.is_empty() => {
+                    st.batch.pop_back();
+                    sched.shed(&st, Priority::Batch);
+                }
+                _ => return Err(sched.shed(&st, priority)),
+            }
+        }
+        let (tx, rx) = oneshot::channel();
+        match priority {
+            Priority::Interactive => st.interactive.push_back(tx),
+            Priority::Batch => st.batch.push_back(tx),
+        }
+        sched.report(&st);
+        rx
+    };
+
+    let mut waiting = Waiting {
+        rx,
+        sched: sched.clone(),
+        priority,
+        admitted: false,
+    };
+    match (&mut waiting.rx).await {
+        Ok(()) => {
+            // the place is now the permit's to release
+            waiting.admitted = true;
+            Ok(Permit {
+                sched: Some(sched),
+                priority,
+                admitted: Instant::now(),
+            })
+        }
+        // shed to make room for an interactive request
+        Err(_) => {
+            let st = sched.state.lock().expect("lock poisoned");
+            Err(sched.overloaded(&st))
+        }
+    }
+}
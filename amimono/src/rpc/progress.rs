@@ -0,0 +1,139 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{FutureExt, Stream, future::BoxFuture};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::rpc::{RpcError, RpcResult};
+
+/// A progress update sent by a long-running operation.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProgressUpdate {
+    /// How much of the operation has completed, between 0 and 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fraction: Option<f64>,
+
+    /// A human-readable description of the operation's current state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl ProgressUpdate {
+    /// Create an update with a completion fraction.
+    pub fn fraction(fraction: f64) -> ProgressUpdate {
+        ProgressUpdate {
+            fraction: Some(fraction),
+            message: None,
+        }
+    }
+
+    /// Create an update with a message.
+    pub fn message<S: ToString>(message: S) -> ProgressUpdate {
+        ProgressUpdate {
+            fraction: None,
+            message: Some(message.to_string()),
+        }
+    }
+
+    /// Add a message to the update.
+    pub fn with_message<S: ToString>(self, message: S) -> ProgressUpdate {
+        ProgressUpdate {
+            fraction: self.fraction,
+            message: Some(message.to_string()),
+        }
+    }
+}
+
+pub(crate) type ProgressSender = mpsc::UnboundedSender<ProgressUpdate>;
+
+tokio::task_local! {
+    static SINK: ProgressSender;
+}
+
+/// Report progress from within an RPC handler.
+///
+/// If the caller requested progress updates, e.g. with a generated client's
+/// `progress()` method, the update is sent to the caller. Otherwise this does
+/// nothing, so handlers can report progress unconditionally.
+pub fn report_progress(update: ProgressUpdate) {
+    let _ = SINK.try_with(|tx| tx.send(update));
+}
+
+/// Runs a future with progress reports going to the given sender.
+pub(crate) fn scope<F: Future>(tx: ProgressSender, fut: F) -> impl Future<Output = F::Output> {
+    SINK.scope(tx, fut)
+}
+
+/// The pending result of a long-running operation, along with its progress
+/// updates.
+///
+/// Progress updates can be read with [`next`][Progress::next] or by using
+/// this as a `Stream`, and the result can be retrieved by awaiting it
+/// directly. It's not necessary to read the updates to get the result.
+pub struct Progress<T> {
+    updates: mpsc::UnboundedReceiver<ProgressUpdate>,
+    result: BoxFuture<'static, RpcResult<T>>,
+}
+
+impl<T: Send + 'static> Progress<T> {
+    /// Spawn an operation that reports progress to the sender it's given.
+    pub(crate) fn spawn<F, Fut>(f: F) -> Progress<T>
+    where
+        F: FnOnce(ProgressSender) -> Fut,
+        Fut: Future<Output = RpcResult<T>> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let join = tokio::spawn(f(tx));
+        Progress {
+            updates: rx,
+            result: Box::pin(async {
+                match join.await {
+                    Ok(res) => res,
+                    Err(e) => Err(RpcError::from(e)),
+                }
+            }),
+        }
+    }
+
+    /// Wait for the next progress update. Returns `None` once the operation
+    /// has finished.
+    pub async fn next(&mut self) -> Option<ProgressUpdate> {
+        self.updates.recv().await
+    }
+
+    /// Wait for the result of the operation.
+    pub async fn result(self) -> RpcResult<T> {
+        self.result.await
+    }
+
+    /// Transform the result of the operation.
+    pub fn map<U, F>(self, f: F) -> Progress<U>
+    where
+        F: FnOnce(T) -> RpcResult<U> + Send + 'static,
+    {
+        Progress {
+            updates: self.updates,
+            result: self.result.map(|res| res.and_then(f)).boxed(),
+        }
+    }
+}
+
+impl<T: Send + 'static> IntoFuture for Progress<T> {
+    type Output = RpcResult<T>;
+    type IntoFuture = BoxFuture<'static, RpcResult<T>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        self.result
+    }
+}
+
+impl<T> Stream for Progress<T> {
+    type Item = ProgressUpdate;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.updates.poll_recv(cx)
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{self, SeqAccess, Visitor},
+};
+
+/// A protobuf message used as an RPC parameter or return type.
+///
+/// This is a codec wrapper that lets `prost`-generated messages be used in
+/// `rpc_component!` ops, so existing `.proto` contracts can be reused for
+/// payloads. The message is encoded with protobuf and then embedded in the
+/// request or response as a base64 string (for human-readable formats such as
+/// JSON) or as raw bytes (for everything else).
+///
+/// ```ignore
+/// amimono::rpc_component! {
+///     const LABEL: &'static str = "search";
+///
+///     fn search(q: Proto<pb::SearchRequest>) -> Proto<pb::SearchResponse>;
+/// }
+/// ```
+///
+/// Only available with the `proto` feature.
+#[derive(Clone, Default, PartialEq)]
+pub struct Proto<M>(pub M);
+
+impl<M> Proto<M> {
+    /// Unwrap the message.
+    pub fn into_inner(self) -> M {
+        self.0
+    }
+}
+
+impl<M> From<M> for Proto<M> {
+    fn from(value: M) -> Self {
+        Proto(value)
+    }
+}
+
+impl<M> Deref for Proto<M> {
+    type Target = M;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<M> DerefMut for Proto<M> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<M: fmt::Debug> fmt::Debug for Proto<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Proto({:?})", self.0)
+    }
+}
+
+impl<M: prost::Message> Serialize for Proto<M> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let bytes = self.0.encode_to_vec();
+        match s.is_human_readable() {
+            true => s.serialize_str(&BASE64_STANDARD.encode(bytes)),
+            false => s.serialize_bytes(&bytes),
+        }
+    }
+}
+
+impl<'de, M: prost::Message + Default> Deserialize<'de> for Proto<M> {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        match d.is_human_readable() {
+            true => d.deserialize_str(ProtoVisitor(PhantomData)),
+            false => d.deserialize_bytes(ProtoVisitor(PhantomData)),
+        }
+    }
+}
+
+struct ProtoVisitor<M>(PhantomData<M>);
+
+impl<'de, M: prost::Message + Default> Visitor<'de> for ProtoVisitor<M> {
+    type Value = Proto<M>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a protobuf-encoded message")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        let bytes = BASE64_STANDARD
+            .decode(v)
+            .map_err(|e| E::custom(format!("invalid base64: {e}")))?;
+        self.visit_bytes(&bytes)
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        M::decode(v)
+            .map(Proto)
+            .map_err(|e| E::custom(format!("invalid protobuf: {e}")))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::new();
+        while let Some(b) = seq.next_element::<u8>()? {
+            bytes.push(b);
+        }
+        self.visit_bytes(&bytes)
+    }
+}
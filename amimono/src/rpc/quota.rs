@@ -0,0 +1,96 @@
+//! Per-caller quotas on incoming requests.
+//!
+//! Components with quotas, configured with
+//! [`AppBuilder::with_caller_quota`][crate::config::AppBuilder::with_caller_quota],
+//! count the requests each caller has in flight and the rate it makes them
+//! at, and reject requests over the caller's quota with
+//! [`AppError::QuotaExceeded`][crate::AppError::QuotaExceeded] before they
+//! take any of the component's capacity. Callers are told apart by their
+//! identity, and callers that can't be identified share one quota.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::Instant,
+};
+
+use crate::{
+    metrics,
+    rpc::{RpcError, RpcResult},
+    runtime,
+};
+
+type Key = (String, Option<String>);
+
+/// What a caller is using of a component's capacity.
+struct Usage {
+    in_flight: usize,
+    /// Requests the caller can still make right away, refilled at the
+    /// quota's rate up to a second's worth.
+    tokens: f64,
+    refilled: Instant,
+}
+
+static USAGE: LazyLock<Mutex<HashMap<Key, Usage>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// A request's place in its caller's quota, released when dropped.
+pub(crate) struct QuotaPermit {
+    key: Option<Key>,
+}
+
+impl Drop for QuotaPermit {
+    fn drop(&mut self) {
+        if let Some(key) = &self.key
+            && let Some(usage) = USAGE.lock().expect("lock poisoned").get_mut(key)
+        {
+            usage.in_flight -= 1;
+        }
+    }
+}
+
+fn exceeded(label: &str, caller: Option<&str>, quota: String) -> RpcError {
+    let labels = [
+        ("component", label),
+        ("caller", caller.unwrap_or("unknown")),
+    ];
+    metrics::counter("amimono_rpc_quota_exceeded", &labels).inc();
+    RpcError::QuotaExceeded {
+        caller: caller.map(|c| c.to_owned()),
+        component: label.to_owned(),
+        quota,
+    }
+}
+
+/// Count a request from a caller against its quota for the component, or
+/// reject it if the caller is over its quota. Requests to components without
+/// quotas are always admitted.
+pub(crate) fn admit(label: &str, caller: Option<&str>) -> RpcResult<QuotaPermit> {
+    let Some(cf) = runtime::config().caller_quota(label, caller) else {
+        return Ok(QuotaPermit { key: None });
+    };
+    let key = (label.to_owned(), caller.map(|c| c.to_owned()));
+    let mut usage = USAGE.lock().expect("lock poisoned");
+    let now = Instant::now();
+    let usage = usage.entry(key.clone()).or_insert_with(|| Usage {
+        in_flight: 0,
+        tokens: cf.max_rps.unwrap_or(0.0).max(1.0),
+        refilled: now,
+    });
+
+    if let Some(max) = cf.max_in_flight
+        && usage.in_flight >= max
+    {
+        return Err(exceeded(label, caller, format!("{max} requests in flight")));
+    }
+    if let Some(rps) = cf.max_rps {
+        let elapsed = now.duration_since(usage.refilled).as_secs_f64();
+        usage.tokens = (usage.tokens + elapsed * rps).min(rps.max(1.0));
+        usage.refilled = now;
+        if usage.tokens < 1.0 {
+            return Err(exceeded(label, caller, format!("{rps} requests/s")));
+        }
+        usage.tokens -= 1.0;
+    }
+    usage.in_flight += 1;
+    Ok(QuotaPermit { key: Some(key) })
+}
@@ -0,0 +1,67 @@
+//! Slow start for newly discovered endpoints.
+//!
+//! A replica that just became ready still has cold caches and empty
+//! connection pools, and sending it a full share of traffic right away can
+//! make its first requests slow. When a slow start window is configured with
+//! `AppBuilder::with_slow_start`, endpoints that appear in discovery after a
+//! component was first discovered start out with a small share of traffic,
+//! which grows linearly to their full weight over the window.
+//!
+//! Each process ramps endpoints from when it first sees them, so different
+//! callers may be at different points in the ramp. Calls with an affinity key
+//! are ramped too, which moves keys onto the new endpoint gradually.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::Instant,
+};
+
+use crate::{component::Replica, runtime};
+
+/// The share of traffic a new endpoint receives at the start of its ramp.
+const MIN_SHARE: f64 = 0.1;
+
+/// Weights are scaled by this much so that ramped weights keep some
+/// resolution as integers.
+const SCALE: f64 = 100.0;
+
+/// When each endpoint of a component was first seen, or `None` for endpoints
+/// that were present the first time the component was discovered.
+type Endpoints = HashMap<String, Option<Instant>>;
+
+static SEEN: LazyLock<Mutex<HashMap<&'static str, Endpoints>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Adjust the weights of a component's replicas for slow start.
+pub(crate) fn apply(label: &'static str, mut replicas: Vec<Replica>) -> Vec<Replica> {
+    let Some(window) = runtime::config().slow_start() else {
+        return replicas;
+    };
+
+    let now = Instant::now();
+    let mut seen = SEEN.lock().expect("lock poisoned");
+    let first = !seen.contains_key(label);
+    let endpoints = seen.entry(label).or_default();
+
+    // forget endpoints that are gone, so they ramp again if they come back
+    endpoints.retain(|addr, _| replicas.iter().any(|r| r.location.addr::<str>() == addr));
+
+    for r in replicas.iter_mut() {
+        let since = endpoints
+            .entry(r.location.addr::<str>().to_owned())
+            .or_insert_with(|| (!first).then_some(now));
+        let share = match since {
+            Some(t) if now < *t + window => {
+                let progress = now.duration_since(*t).as_secs_f64() / window.as_secs_f64();
+                MIN_SHARE + (1.0 - MIN_SHARE) * progress
+            }
+            _ => 1.0,
+        };
+        if r.weight > 0 {
+            r.weight = (r.weight as f64 * SCALE * share).max(1.0) as u32;
+        }
+    }
+
+    replicas
+}
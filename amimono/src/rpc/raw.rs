@@ -0,0 +1,65 @@
+//! Raw handlers, for ops that need to see their payload before it's parsed.
+//!
+//! A raw handler takes over one op of an RPC component. It receives the op's
+//! arguments exactly as they were sent, and returns its result already
+//! serialized, bypassing the component's typed `Request` and `Response`
+//! enums. Both are JSON, in the same shape serde uses for the enums: a single
+//! argument or return value as itself, and several arguments as an array.
+//! Callers don't need to know an op is handled raw.
+
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use futures::future::BoxFuture;
+
+use crate::{
+    rpc::{RpcComponentKind, RpcError, RpcResult, buf},
+    util::StaticHashMap,
+};
+
+type RawHandler = dyn Fn(Bytes) -> BoxFuture<'static, RpcResult<Bytes>> + Send + Sync;
+
+static RAW_HANDLERS: StaticHashMap<(&'static str, String), RawHandler> = StaticHashMap::new();
+
+static RAW_LABELS: StaticHashMap<&'static str, ()> = StaticHashMap::new();
+
+/// Handle an op of an RPC component with a raw handler. This should be called
+/// before the component starts serving, e.g. from its `Handler::new`. Panics
+/// if the component has no op with the given name.
+pub fn serve_raw<K, F, Fut>(op: &'static str, handler: F)
+where
+    K: RpcComponentKind,
+    F: Fn(Bytes) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = RpcResult<Bytes>> + Send + 'static,
+{
+    if !K::OPS.iter().any(|x| x.name == op) {
+        panic!("component {} has no op {}", K::LABEL, op);
+    }
+    let handler: Arc<RawHandler> = Arc::new(move |q| Box::pin(handler(q)));
+    RAW_HANDLERS.insert((K::LABEL, op.to_owned()), handler);
+    RAW_LABELS.insert(K::LABEL, Arc::new(()));
+}
+
+/// Handle a serialized request with a raw handler, if the component has one
+/// for the request's op. Returns `None` if the request should be handled by
+/// the typed handler instead.
+pub(crate) async fn dispatch(label: &'static str, q: &[u8]) -> Option<RpcResult<Bytes>> {
+    RAW_LABELS.get(label)?;
+
+    // a request is an externally tagged enum, i.e. `{"op": args}`
+    let (op, args) = match serde_json::from_slice::<serde_json::Value>(q) {
+        Ok(serde_json::Value::Object(map)) if map.len() == 1 => map.into_iter().next()?,
+        _ => return None,
+    };
+    let handler = RAW_HANDLERS.get(&(label, op.clone()))?;
+
+    let res = async {
+        let ret = handler(buf::to_json_bytes(&args)?).await?;
+        let ret = serde_json::from_slice::<serde_json::Value>(&ret)
+            .map_err(|e| RpcError::Misc(format!("raw handler returned invalid JSON: {e}")))?;
+        let mut res = serde_json::Map::new();
+        res.insert(op, ret);
+        buf::to_json_bytes(&res)
+    };
+    Some(res.await)
+}
@@ -0,0 +1,219 @@
+//! Sampling of the logs clients write for their calls.
+//!
+//! At high rates, logging every outgoing call at debug and every retry at
+//! warn floods the logs without saying much. For components configured with
+//! [`AppBuilder::with_log_sampling`][crate::config::AppBuilder::with_log_sampling],
+//! clients instead log a summary of their calls every interval, e.g.
+//!
+//! ```text
+//! 1234 calls to calc in last 10s, p50 2ms p99 18ms, 3 errors, 7 retries
+//! ```
+//!
+//! and only log a sample of calls in full. Calls that fail are still logged
+//! when they give up, whether or not they were sampled.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{rpc::RpcResult, runtime};
+
+/// How the calls to a component are logged.
+#[derive(Copy, Clone, Debug)]
+pub struct LogSampling {
+    interval: Duration,
+    rate: f64,
+    max_per_interval: u32,
+}
+
+impl LogSampling {
+    /// Log a summary of calls every `interval`, and one in a hundred calls in
+    /// full, but no more than 10 an interval.
+    pub const fn every(interval: Duration) -> LogSampling {
+        LogSampling {
+            interval,
+            rate: 0.01,
+            max_per_interval: 10,
+        }
+    }
+
+    /// Set the fraction of calls logged in full. Defaults to 0.01.
+    pub const fn with_rate(self, rate: f64) -> LogSampling {
+        LogSampling { rate, ..self }
+    }
+
+    /// Set how many calls are logged in full each interval, at most, however
+    /// many are sampled. Defaults to 10.
+    pub const fn with_max_per_interval(self, max_per_interval: u32) -> LogSampling {
+        LogSampling {
+            max_per_interval,
+            ..self
+        }
+    }
+}
+
+/// How many latencies are kept each interval to estimate percentiles from.
+const RESERVOIR: usize = 1024;
+
+#[derive(Default)]
+struct Window {
+    calls: u64,
+    errors: u64,
+    retries: u64,
+    /// How many calls were logged in full.
+    full: u32,
+    /// A uniform sample of the latencies of calls, in seconds.
+    latencies: Vec<f64>,
+}
+
+/// The calls made to a component in the current interval.
+struct Sampler {
+    label: String,
+    policy: LogSampling,
+    window: Mutex<Window>,
+}
+
+impl Sampler {
+    /// Whether a new call should be logged in full.
+    fn admit(&self) -> bool {
+        let mut window = self.window.lock().expect("lock poisoned");
+        if window.full >= self.policy.max_per_interval
+            || !rand::random_bool(self.policy.rate.clamp(0.0, 1.0))
+        {
+            return false;
+        }
+        window.full += 1;
+        true
+    }
+
+    fn record(&self, duration: Duration, ok: bool) {
+        let mut window = self.window.lock().expect("lock poisoned");
+        window.calls += 1;
+        window.errors += !ok as u64;
+        let latency = duration.as_secs_f64();
+        if window.latencies.len() < RESERVOIR {
+            window.latencies.push(latency);
+        } else {
+            let i = rand::random_range(0..window.calls) as usize;
+            if i < RESERVOIR {
+                window.latencies[i] = latency;
+            }
+        }
+    }
+
+    /// Log a summary of the current interval and start the next one.
+    fn flush(&self) {
+        let window = std::mem::take(&mut *self.window.lock().expect("lock poisoned"));
+        if window.calls == 0 {
+            return;
+        }
+        let mut latencies = window.latencies;
+        latencies.sort_by(f64::total_cmp);
+        let percentile = |p: f64| {
+            let i = ((latencies.len() - 1) as f64 * p).round() as usize;
+            Duration::from_secs_f64(latencies[i])
+        };
+        log::info!(
+            "{} calls to {} in last {:?}, p50 {:?} p99 {:?}, {} errors, {} retries",
+            window.calls,
+            self.label,
+            self.policy.interval,
+            percentile(0.5),
+            percentile(0.99),
+            window.errors,
+            window.retries
+        );
+    }
+}
+
+static SAMPLERS: LazyLock<Mutex<HashMap<String, Arc<Sampler>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The sampler for calls to a component, if they're sampled. The first call
+/// starts the task that logs its summaries.
+fn sampler(label: &str) -> Option<Arc<Sampler>> {
+    let policy = *runtime::config().log_sampling(label)?;
+    let mut samplers = SAMPLERS.lock().expect("lock poisoned");
+    if let Some(sampler) = samplers.get(label) {
+        return Some(sampler.clone());
+    }
+    let sampler = Arc::new(Sampler {
+        label: label.to_owned(),
+        policy,
+        window: Mutex::new(Window::default()),
+    });
+    samplers.insert(label.to_owned(), sampler.clone());
+    let flushed = sampler.clone();
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(policy.interval.max(Duration::from_millis(100)));
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            flushed.flush();
+        }
+    });
+    Some(sampler)
+}
+
+/// The call being made on the current task, and whether it's logged in full.
+struct Call {
+    sampler: Arc<Sampler>,
+    full: bool,
+}
+
+tokio::task_local! {
+    /// `None` while calling a component that isn't sampled, e.g. from the
+    /// in-process handler of one that is.
+    static CALL: Option<Call>;
+}
+
+/// Make a call to a component, counting it towards the component's summary
+/// and deciding whether it's logged in full, if its calls are sampled.
+pub(crate) async fn scope<X, F>(label: &str, verb: &str, call: F) -> RpcResult<X>
+where
+    F: Future<Output = RpcResult<X>>,
+{
+    let Some(sampler) = sampler(label) else {
+        return CALL.scope(None, call).await;
+    };
+    let full = sampler.admit();
+    let started = Instant::now();
+    let res = CALL
+        .scope(
+            Some(Call {
+                sampler: sampler.clone(),
+                full,
+            }),
+            call,
+        )
+        .await;
+    let duration = started.elapsed();
+    sampler.record(duration, res.is_ok());
+    if full {
+        match &res {
+            Ok(_) => log::info!("sampled call to {label}.{verb} took {duration:?}"),
+            Err(e) => log::info!("sampled call to {label}.{verb} failed after {duration:?}: {e}"),
+        }
+    }
+    res
+}
+
+/// Whether the events of the call on the current task should be logged. Calls
+/// to components that aren't sampled always are.
+pub(crate) fn full() -> bool {
+    CALL.try_with(|call| call.as_ref().is_none_or(|call| call.full))
+        .unwrap_or(true)
+}
+
+/// Count a retry of the call on the current task, returning whether it should
+/// be logged.
+pub(crate) fn retry() -> bool {
+    CALL.try_with(|call| {
+        let Some(call) = call else { return true };
+        call.sampler.window.lock().expect("lock poisoned").retries += 1;
+        call.full
+    })
+    .unwrap_or(true)
+}
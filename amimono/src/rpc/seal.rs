@@ -0,0 +1,335 @@
+//! Application-layer encryption of RPC payloads.
+//!
+//! For deployments where traffic between jobs crosses a shared network
+//! without mTLS, request and response bodies can be sealed with
+//! ChaCha20-Poly1305 under shared keys. Keys are configured with environment
+//! variables, which are best filled from the target's secrets:
+//!
+//! * `AMIMONO_PAYLOAD_KEYS` -- the keys this process accepts, as
+//!   comma-separated `id=secret` pairs.
+//! * `AMIMONO_PAYLOAD_KEY_ID` -- the id of the key that outgoing requests are
+//!   sealed with. Requests are sent in plaintext when it isn't set.
+//! * `AMIMONO_PAYLOAD_REQUIRED` -- when `true`, plaintext requests are
+//!   refused.
+//!
+//! The first two can also be set for calls to a single component, by
+//! appending the component's label in uppercase with anything other than
+//! letters and digits replaced by `_`, e.g. `AMIMONO_PAYLOAD_KEYS_LEDGER`.
+//! A component with its own keys doesn't use the default ones.
+//!
+//! A sealed request names its key in a header, and its response is sealed
+//! with the same key. Keys can be rotated without downtime by deploying each
+//! of these steps to every job before moving on to the next:
+//!
+//! 1. Add the new key to `AMIMONO_PAYLOAD_KEYS`.
+//! 2. Set `AMIMONO_PAYLOAD_KEY_ID` to the new key's id.
+//! 3. Remove the old key.
+//!
+//! Sealing is turned on the same way, by adding the first key, then setting
+//! the key id, then setting `AMIMONO_PAYLOAD_REQUIRED`.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use axum::{body::Bytes, response::IntoResponse};
+use ring::{
+    aead::{self, Aad, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce, UnboundKey},
+    hkdf,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::{
+    audit,
+    rpc::{RpcError, RpcResult},
+    runtime,
+};
+
+/// The header naming the label and key a payload is sealed with, as
+/// `label;id`.
+pub(crate) const HEADER: &str = "x-amimono-seal";
+
+/// What a sealed payload is, so that one can't be passed off as another.
+#[derive(Copy, Clone)]
+pub(crate) enum Part {
+    Request,
+    Response,
+    /// A server-sent event on a progress stream.
+    Event,
+}
+
+impl Part {
+    fn tag(self) -> &'static str {
+        match self {
+            Part::Request => "q",
+            Part::Response => "a",
+            Part::Event => "e",
+        }
+    }
+}
+
+struct Keyring {
+    keys: Vec<(String, LessSafeKey)>,
+    /// The index of the key that outgoing requests are sealed with.
+    active: Option<usize>,
+}
+
+static KEYRINGS: LazyLock<Mutex<HashMap<String, Option<Arc<Keyring>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+static REQUIRED: LazyLock<bool> = LazyLock::new(|| {
+    std::env::var("AMIMONO_PAYLOAD_REQUIRED").is_ok_and(|v| v == "true" || v == "1")
+});
+
+fn derive(id: &str, secret: &str) -> LessSafeKey {
+    let salt = hkdf::Salt::new(hkdf::HKDF_SHA256, b"amimono payload seal");
+    let info = [id.as_bytes()];
+    let prk = salt.extract(secret.as_bytes());
+    let okm = prk
+        .expand(&info, &CHACHA20_POLY1305)
+        .expect("key length is valid");
+    LessSafeKey::new(UnboundKey::from(okm))
+}
+
+fn load(label: &str) -> Option<Keyring> {
+    let suffix = label
+        .to_ascii_uppercase()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+    let (keys, active) = match std::env::var(format!("AMIMONO_PAYLOAD_KEYS_{suffix}")) {
+        Ok(keys) => (
+            keys,
+            std::env::var(format!("AMIMONO_PAYLOAD_KEY_ID_{suffix}")),
+        ),
+        Err(_) => (
+            std::env::var("AMIMONO_PAYLOAD_KEYS").ok()?,
+            std::env::var("AMIMONO_PAYLOAD_KEY_ID"),
+        ),
+    };
+
+    let mut ring = Keyring {
+        keys: Vec::new(),
+        active: None,
+    };
+    for entry in keys.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        match entry.split_once('=') {
+            Some((id, secret)) => ring.keys.push((id.to_owned(), derive(id, secret))),
+            None => log::error!("ignoring payload key for {} without an id", label),
+        }
+    }
+    if let Ok(active) = active {
+        ring.active = ring.keys.iter().position(|(id, _)| *id == active);
+        if ring.active.is_none() {
+            log::error!(
+                "payload key {} for {} is not configured, sending plaintext",
+                active,
+                label
+            );
+        }
+    }
+    Some(ring)
+}
+
+fn keyring(label: &str) -> Option<Arc<Keyring>> {
+    let mut rings = KEYRINGS.lock().expect("lock poisoned");
+    rings
+        .entry(label.to_owned())
+        .or_insert_with(|| load(label).map(Arc::new))
+        .clone()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// A key that payloads to and from a component are sealed with.
+pub(crate) struct Seal {
+    ring: Arc<Keyring>,
+    key: usize,
+    label: String,
+}
+
+impl Seal {
+    /// The seal for requests to the component, if they're sealed.
+    pub(crate) fn outgoing(label: &str) -> Option<Seal> {
+        let ring = keyring(label)?;
+        let key = ring.active?;
+        Some(Seal {
+            ring,
+            key,
+            label: label.to_owned(),
+        })
+    }
+
+    /// The value of the header for payloads sealed with this seal.
+    pub(crate) fn header(&self) -> String {
+        format!("{};{}", self.label, self.ring.keys[self.key].0)
+    }
+
+    fn aad(&self, part: Part) -> Aad<String> {
+        Aad::from(format!("{};{}", self.label, part.tag()))
+    }
+
+    /// Seal a payload, as the nonce followed by the ciphertext and tag.
+    pub(crate) fn seal(&self, part: Part, data: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .expect("failed to generate nonce");
+        let mut out = data.to_vec();
+        self.ring.keys[self.key]
+            .1
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                self.aad(part),
+                &mut out,
+            )
+            .expect("payload too large to seal");
+        let mut sealed = nonce.to_vec();
+        sealed.append(&mut out);
+        sealed
+    }
+
+    pub(crate) fn open(&self, part: Part, data: &[u8]) -> RpcResult<Vec<u8>> {
+        let err = || RpcError::Misc(format!("could not open sealed payload for {}", self.label));
+        if data.len() < NONCE_LEN + aead::MAX_TAG_LEN {
+            return Err(err());
+        }
+        let (nonce, data) = data.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| err())?;
+        let mut out = data.to_vec();
+        let len = self.ring.keys[self.key]
+            .1
+            .open_in_place(nonce, self.aad(part), &mut out)
+            .map_err(|_| err())?
+            .len();
+        out.truncate(len);
+        Ok(out)
+    }
+
+    /// Seal a payload as text, for server-sent events.
+    pub(crate) fn seal_text(&self, part: Part, data: &str) -> String {
+        hex(&self.seal(part, data.as_bytes()))
+    }
+
+    pub(crate) fn open_text(&self, part: Part, data: &str) -> RpcResult<String> {
+        let sealed = unhex(data)
+            .ok_or_else(|| RpcError::Misc(format!("malformed sealed event for {}", self.label)))?;
+        String::from_utf8(self.open(part, &sealed)?)
+            .map_err(|_| RpcError::Misc(format!("sealed event for {} is not UTF-8", self.label)))
+    }
+}
+
+/// Open an incoming request, returning the seal to seal its response with,
+/// if it was sealed. `label` is the component the request was sent to, if
+/// the route names one.
+pub(crate) fn open_request(
+    label: Option<&str>,
+    headers: &axum::http::HeaderMap,
+    body: &Bytes,
+) -> RpcResult<(Bytes, Option<Seal>)> {
+    match incoming(label, headers)? {
+        Some(seal) => Ok((seal.open(Part::Request, body)?.into(), Some(seal))),
+        None => Ok((body.clone(), None)),
+    }
+}
+
+/// The seal an incoming request was sealed with, from its headers, or
+/// `None` if it's in plaintext and plaintext is accepted. This is for
+/// requests whose bodies are opened piece by piece, such as pipeline
+/// streams.
+pub(crate) fn incoming(
+    label: Option<&str>,
+    headers: &axum::http::HeaderMap,
+) -> RpcResult<Option<Seal>> {
+    let Some(header) = headers.get(HEADER) else {
+        if *REQUIRED {
+            audit::event("rpc.plaintext_refused")
+                .with("target", label.unwrap_or("actor"))
+                .warning()
+                .record();
+            return Err(RpcError::Misc(
+                "plaintext requests are not accepted".to_owned(),
+            ));
+        }
+        return Ok(None);
+    };
+    let header = header
+        .to_str()
+        .map_err(|_| RpcError::Misc("malformed seal header".to_owned()))?;
+    let Some((sealed_for, id)) = header.split_once(';') else {
+        return Err(RpcError::Misc("malformed seal header".to_owned()));
+    };
+    if label.is_some_and(|l| l != sealed_for) {
+        return Err(RpcError::Misc(format!(
+            "request to {} was sealed for {}",
+            label.unwrap_or_default(),
+            sealed_for
+        )));
+    }
+    // keyrings are cached by label, so only components' are loaded
+    if runtime::config().component(sealed_for).is_none() {
+        return Err(RpcError::Misc(format!(
+            "request was sealed for unknown component {}",
+            sealed_for
+        )));
+    }
+    let unknown = || RpcError::Misc(format!("unknown payload key {} for {}", id, sealed_for));
+    let ring = keyring(sealed_for).ok_or_else(unknown)?;
+    let key = ring
+        .keys
+        .iter()
+        .position(|(k, _)| k == id)
+        .ok_or_else(unknown)?;
+    Ok(Some(Seal {
+        ring,
+        key,
+        label: sealed_for.to_owned(),
+    }))
+}
+
+/// Build the response to a request, sealing it if the request was sealed.
+pub(crate) fn respond(seal: Option<&Seal>, res: RpcResult<Bytes>) -> axum::response::Response {
+    let Some(seal) = seal else {
+        return res.into_response();
+    };
+    let header = [(HEADER, seal.header())];
+    match res {
+        Ok(body) => (header, seal.seal(Part::Response, &body)).into_response(),
+        Err(e) => {
+            let json = serde_json::to_vec(&e).unwrap_or_default();
+            let status = e.into_response().status();
+            (status, header, seal.seal(Part::Response, &json)).into_response()
+        }
+    }
+}
+
+/// Open the body of a response to a request sent with the given seal.
+/// Successful responses to sealed requests must be sealed, but errors
+/// raised before the request could be opened can't be.
+pub(crate) fn open_response(
+    seal: Option<&Seal>,
+    success: bool,
+    headers: &reqwest::header::HeaderMap,
+    body: axum::body::Bytes,
+) -> RpcResult<axum::body::Bytes> {
+    match (seal, headers.contains_key(HEADER)) {
+        (Some(seal), true) => Ok(seal.open(Part::Response, &body)?.into()),
+        (Some(seal), false) if success => Err(RpcError::Misc(format!(
+            "response from {} was not sealed",
+            seal.label
+        ))),
+        (None, true) => Err(RpcError::Misc("unexpected sealed response".to_owned())),
+        _ => Ok(body),
+    }
+}
@@ -0,0 +1,78 @@
+//! Simulated network conditions for local development.
+//!
+//! When the runtime is started with `--simulate-network <file>`, RPC calls to
+//! the components listed in the file skip the in-process fast path and are
+//! sent over loopback HTTP instead, with injected latency and bandwidth
+//! limits. This keeps performance characteristics observed locally closer to
+//! those of the deployed topology. The file has the following format:
+//!
+//! ```toml
+//! # applies to every component not listed below
+//! [default]
+//! latency_ms = 2
+//! jitter_ms = 1
+//!
+//! [component.calc]
+//! latency_ms = 20
+//! jitter_ms = 10
+//! bandwidth_kbps = 8000
+//! ```
+
+use std::{collections::HashMap, sync::LazyLock, time::Duration};
+
+use serde::Deserialize;
+
+use crate::runtime;
+
+#[derive(Deserialize)]
+struct ShapingConfig {
+    default: Option<Shaping>,
+    #[serde(default)]
+    component: HashMap<String, Shaping>,
+}
+
+/// The simulated network conditions for calls to a component.
+#[derive(Clone, Deserialize)]
+pub(crate) struct Shaping {
+    /// Round-trip latency added to every call, split evenly between the
+    /// request and response.
+    #[serde(default)]
+    latency_ms: u64,
+
+    /// Random extra latency of up to this amount.
+    #[serde(default)]
+    jitter_ms: u64,
+
+    /// Simulated link bandwidth, applied to request and response bodies.
+    bandwidth_kbps: Option<u64>,
+}
+
+impl Shaping {
+    /// Sleep for the time it would take `bytes` to travel one way.
+    pub(crate) async fn delay(&self, bytes: usize) {
+        let mut dur = Duration::from_millis(self.latency_ms) / 2;
+        if self.jitter_ms > 0 {
+            dur += Duration::from_millis(rand::random_range(0..=self.jitter_ms)) / 2;
+        }
+        if let Some(kbps) = self.bandwidth_kbps.filter(|&x| x > 0) {
+            dur += Duration::from_secs_f64((bytes * 8) as f64 / (kbps * 1000) as f64);
+        }
+        tokio::time::sleep(dur).await;
+    }
+}
+
+static CONFIG: LazyLock<Option<ShapingConfig>> = LazyLock::new(|| {
+    let path = runtime::args().simulate_network.as_ref()?;
+    let text = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("could not read network simulation config {path}: {e}"));
+    let cf = toml::from_str(&text)
+        .unwrap_or_else(|e| panic!("could not parse network simulation config {path}: {e}"));
+    log::warn!("simulating network conditions from {path}");
+    Some(cf)
+});
+
+/// The simulated network conditions for calls to a component, if any.
+pub(crate) fn get(label: &str) -> Option<&'static Shaping> {
+    let cf = CONFIG.as_ref()?;
+    cf.component.get(label).or(cf.default.as_ref())
+}
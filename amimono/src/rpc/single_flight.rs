@@ -0,0 +1,112 @@
+//! Collapsing of identical concurrent calls.
+//!
+//! For ops configured with
+//! [`AppBuilder::with_single_flight`][crate::config::AppBuilder::with_single_flight],
+//! a call made while an identical one from the same process is in flight
+//! doesn't send a request of its own, and gets the result of the one in
+//! flight instead. Calls are identical when they're to the same component
+//! with the same serialized request. The shared response is passed to the
+//! waiting callers serialized, since responses can't be cloned.
+
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+};
+
+use axum::body::Bytes;
+use tokio::sync::oneshot;
+
+use crate::{
+    metrics,
+    rpc::{RpcComponentKind, RpcMessage, RpcResult},
+    runtime,
+};
+
+type Key = (&'static str, Bytes);
+type Waiter = oneshot::Sender<RpcResult<Bytes>>;
+
+/// The callers waiting on each call in flight.
+static FLIGHTS: LazyLock<Mutex<HashMap<Key, Vec<Waiter>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Ends a flight when the call that started it finishes or is dropped. The
+/// callers still waiting when it's dropped make their own calls.
+struct Flight {
+    key: Option<Key>,
+}
+
+impl Flight {
+    fn land(mut self, res: RpcResult<Bytes>) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        let waiters = FLIGHTS.lock().expect("lock poisoned").remove(&key);
+        for tx in waiters.into_iter().flatten() {
+            let _ = tx.send(res.clone());
+        }
+    }
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            FLIGHTS.lock().expect("lock poisoned").remove(&key);
+        }
+    }
+}
+
+/// Make a call with `f`, unless an identical call is already in flight, in
+/// which case its result is shared. Calls to ops without single-flight
+/// enabled are always made.
+pub(crate) async fn call<T, F, Fut>(q: &T::Request, f: F) -> RpcResult<T::Response>
+where
+    T: RpcComponentKind,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = RpcResult<T::Response>>,
+{
+    let op = q.verb();
+    if !runtime::config().single_flight(T::LABEL, op) {
+        return f().await;
+    }
+    let key = match serde_json::to_vec(q) {
+        Ok(q) => (T::LABEL, Bytes::from(q)),
+        Err(_) => return f().await,
+    };
+
+    let rx = {
+        let mut flights = FLIGHTS.lock().expect("lock poisoned");
+        match flights.get_mut(&key) {
+            Some(waiters) => {
+                let (tx, rx) = oneshot::channel();
+                waiters.push(tx);
+                Some(rx)
+            }
+            None => {
+                flights.insert(key.clone(), Vec::new());
+                None
+            }
+        }
+    };
+
+    if let Some(rx) = rx {
+        // the call in flight was dropped, e.g. because its caller gave up
+        let Ok(res) = rx.await else {
+            return f().await;
+        };
+        metrics::counter(
+            "amimono_rpc_single_flight_shared",
+            &[("component", T::LABEL), ("op", op)],
+        )
+        .inc();
+        return res.and_then(|r| Ok(serde_json::from_slice(&r)?));
+    }
+
+    let flight = Flight { key: Some(key) };
+    let res = f().await;
+    let shared = match &res {
+        Ok(r) => serde_json::to_vec(r).map(Bytes::from).map_err(Into::into),
+        Err(e) => Err(e.clone()),
+    };
+    flight.land(shared);
+    res
+}
@@ -0,0 +1,422 @@
+use std::{
+    any::Any,
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::body::Bytes;
+use futures::{
+    StreamExt,
+    stream::{self, BoxStream},
+};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+};
+
+use crate::{
+    AppError,
+    component::{self, Location},
+    rpc::{HttpVersion, RpcComponentKind, RpcError, RpcResult, http, observe},
+    shard,
+};
+
+/// How many events a topic retains by default, for subscribers resuming
+/// after a reconnect.
+const DEFAULT_RETAINED: usize = 256;
+
+/// How far behind an in-process subscriber can fall before it misses
+/// events, at least.
+const MIN_CAPACITY: usize = 64;
+
+/// The delay before the first attempt at reconnecting a remote subscription,
+/// which doubles with each failed attempt.
+const RECONNECT_MIN: Duration = Duration::from_millis(100);
+
+/// The longest delay between attempts at reconnecting.
+const RECONNECT_MAX: Duration = Duration::from_secs(10);
+
+/// A source of events for a subscription op.
+///
+/// Handlers of subscription ops return a topic, usually one they keep and
+/// publish to whenever something happens, and every subscriber gets the
+/// events published after it subscribed. Cloning a topic gives another
+/// handle to the same topic. Once every handle has been dropped, its
+/// subscriptions end.
+///
+/// The last few events are retained, so that remote subscribers that lose
+/// their connection can resume where they left off without missing any.
+/// Subscribers that fall further behind than that, or that resume with a
+/// different process, e.g. after the server restarted, get an error in
+/// place of the events they missed.
+pub struct Topic<T> {
+    inner: Arc<Inner<T>>,
+}
+
+struct Inner<T> {
+    /// Distinguishes this topic's resume tokens from those of other topics,
+    /// including the same topic in an earlier process.
+    epoch: u64,
+    retained: usize,
+    tx: broadcast::Sender<(u64, T)>,
+    backlog: Mutex<Backlog<T>>,
+}
+
+struct Backlog<T> {
+    /// The sequence number of the next event.
+    next: u64,
+    events: VecDeque<(u64, T)>,
+}
+
+impl<T> Clone for Topic<T> {
+    fn clone(&self) -> Self {
+        Topic {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static> Default for Topic<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone + Send + 'static> Topic<T> {
+    /// Create a topic that retains the last 256 events.
+    pub fn new() -> Topic<T> {
+        Topic::with_retained(DEFAULT_RETAINED)
+    }
+
+    /// Create a topic that retains the last `retained` events.
+    pub fn with_retained(retained: usize) -> Topic<T> {
+        let (tx, _) = broadcast::channel(retained.max(MIN_CAPACITY));
+        Topic {
+            inner: Arc::new(Inner {
+                epoch: rand::random(),
+                retained,
+                tx,
+                backlog: Mutex::new(Backlog {
+                    next: 0,
+                    events: VecDeque::new(),
+                }),
+            }),
+        }
+    }
+
+    /// Publish an event to every current subscriber.
+    pub fn publish(&self, event: T) {
+        let mut backlog = self.inner.backlog.lock().expect("lock poisoned");
+        let seq = backlog.next;
+        backlog.next += 1;
+        if self.inner.retained > 0 {
+            if backlog.events.len() == self.inner.retained {
+                backlog.events.pop_front();
+            }
+            backlog.events.push_back((seq, event.clone()));
+        }
+        // sent with the lock held, so that subscribers resuming from the
+        // backlog see every event exactly once
+        let _ = self.inner.tx.send((seq, event));
+    }
+
+    /// The number of subscribers, in-process or connected remotely.
+    pub fn subscribers(&self) -> usize {
+        self.inner.tx.receiver_count()
+    }
+
+    /// Subscribe to the events published from now on, in-process.
+    pub fn subscribe(&self) -> Subscription<T> {
+        Subscription {
+            source: Source::Local(self.resume(None)),
+        }
+    }
+
+    /// Start reading events after the one with the given resume token.
+    fn resume(&self, after: Option<&str>) -> Cursor<T> {
+        let backlog = self.inner.backlog.lock().expect("lock poisoned");
+        let rx = self.inner.tx.subscribe();
+        let after = match after {
+            None => {
+                return Cursor {
+                    replay: VecDeque::new(),
+                    rx,
+                    last: None,
+                    missed: false,
+                };
+            }
+            Some(token) => parse_token(token).filter(|(epoch, _)| *epoch == self.inner.epoch),
+        };
+        let Some((_, seq)) = after else {
+            // from another process, so there's no telling what was missed
+            return Cursor {
+                replay: VecDeque::new(),
+                rx,
+                last: None,
+                missed: true,
+            };
+        };
+        let first = backlog.events.front().map(|e| e.0).unwrap_or(backlog.next);
+        let replay = backlog.events.iter().filter(|e| e.0 > seq).cloned();
+        Cursor {
+            replay: replay.collect(),
+            rx,
+            last: Some(seq),
+            missed: seq + 1 < first,
+        }
+    }
+}
+
+fn token(epoch: u64, seq: u64) -> String {
+    format!("{epoch:x}-{seq}")
+}
+
+fn parse_token(token: &str) -> Option<(u64, u64)> {
+    let (epoch, seq) = token.split_once('-')?;
+    Some((u64::from_str_radix(epoch, 16).ok()?, seq.parse().ok()?))
+}
+
+fn missed() -> RpcError {
+    RpcError::spurious("subscription missed events")
+}
+
+/// A subscriber's position in a topic.
+struct Cursor<T> {
+    /// Retained events to deliver before reading new ones.
+    replay: VecDeque<(u64, T)>,
+    rx: broadcast::Receiver<(u64, T)>,
+    /// The sequence number of the last event delivered.
+    last: Option<u64>,
+    /// Whether events were missed before the next one.
+    missed: bool,
+}
+
+impl<T: Clone> Cursor<T> {
+    async fn next(&mut self) -> Option<RpcResult<(u64, T)>> {
+        if std::mem::take(&mut self.missed) {
+            return Some(Err(missed()));
+        }
+        if let Some((seq, event)) = self.replay.pop_front() {
+            self.last = Some(seq);
+            return Some(Ok((seq, event)));
+        }
+        loop {
+            match self.rx.recv().await {
+                Ok((seq, _)) if self.last.is_some_and(|last| seq <= last) => continue,
+                Ok((seq, event)) => {
+                    self.last = Some(seq);
+                    return Some(Ok((seq, event)));
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => return Some(Err(missed())),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// A topic with its event type erased, as returned by
+/// [`RpcComponent::subscribe`][crate::rpc::RpcComponent::subscribe].
+pub trait DynTopic: Send + Sync + 'static {
+    /// The topic, to be downcast to a `Topic` of the op's event type.
+    fn as_any(&self) -> &dyn Any;
+
+    /// The events after the one with the given resume token, as pairs of
+    /// their resume token and JSON, with errors in place of missed events.
+    fn serve(&self, after: Option<&str>) -> BoxStream<'static, RpcResult<(String, String)>>;
+}
+
+impl<T: Clone + Serialize + Send + 'static> DynTopic for Topic<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn serve(&self, after: Option<&str>) -> BoxStream<'static, RpcResult<(String, String)>> {
+        let epoch = self.inner.epoch;
+        let cursor = self.resume(after);
+        stream::unfold(cursor, move |mut cursor| async move {
+            let item = cursor.next().await?.and_then(|(seq, event)| {
+                let data = serde_json::to_string(&event)?;
+                Ok((token(epoch, seq), data))
+            });
+            Some((item, cursor))
+        })
+        .boxed()
+    }
+}
+
+/// Parse a serialized request for one of the subscription ops of the
+/// component with the given kind, into its `Subscribe` enum.
+pub fn parse_subscription<K: RpcComponentKind, Q: DeserializeOwned>(q: &[u8]) -> RpcResult<Q> {
+    serde_json::from_slice(q).map_err(|e| match observe::op_name(q) {
+        // e.g. a newer revision subscribing to an op this one doesn't have
+        Some(op) if !K::SUBSCRIPTIONS.iter().any(|x| x.name == op) => RpcError::Unimplemented {
+            component: K::LABEL.to_owned(),
+            op: op.to_owned(),
+        },
+        _ => RpcError::Misc(format!("request parse error: {e}")),
+    })
+}
+
+/// The events of a subscription op, as they're published.
+///
+/// Subscriptions to components in the same process read from the topic
+/// directly. Remote subscriptions hold a connection open to the server,
+/// which pushes events as server-sent events, and reconnect whenever the
+/// connection is lost, resuming after the last event received. If events
+/// were missed along the way, an error is returned in their place, and the
+/// subscription carries on, so watchers should treat an error as a cue to
+/// re-read whatever state they're watching.
+///
+/// The subscription ends when the topic is dropped, or if reconnecting
+/// fails with an error that retrying won't fix, which is returned first.
+pub struct Subscription<T> {
+    source: Source<T>,
+}
+
+enum Source<T> {
+    Local(Cursor<T>),
+    Remote {
+        rx: mpsc::UnboundedReceiver<RpcResult<T>>,
+        task: JoinHandle<()>,
+    },
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        if let Source::Remote { task, .. } = &self.source {
+            task.abort();
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static> Subscription<T> {
+    /// Wait for the next event. Returns `None` once the subscription has
+    /// ended.
+    pub async fn next(&mut self) -> Option<RpcResult<T>> {
+        match &mut self.source {
+            Source::Local(cursor) => Some(cursor.next().await?.map(|(_, event)| event)),
+            Source::Remote { rx, .. } => rx.recv().await,
+        }
+    }
+
+    /// Read the events as a `Stream`.
+    pub fn into_stream(self) -> BoxStream<'static, RpcResult<T>> {
+        stream::unfold(self, |mut sub| async move {
+            let event = sub.next().await?;
+            Some((event, sub))
+        })
+        .boxed()
+    }
+}
+
+impl<T: DeserializeOwned + Send + 'static> Subscription<T> {
+    /// Subscribe to an op of a component in another process. The first
+    /// connection is made before returning, so errors subscribing are
+    /// returned here rather than from the subscription.
+    pub(crate) async fn remote<K: RpcComponentKind>(
+        q: Bytes,
+        affinity: Option<u64>,
+        shard: Option<u32>,
+        unready: bool,
+        version: Option<HttpVersion>,
+    ) -> RpcResult<Subscription<T>> {
+        let events = connect::<K>(q.clone(), affinity, shard, unready, version, None).await?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let forward = forward::<K, T>(events, q, affinity, shard, unready, version, tx);
+        // reconnects are made on behalf of the same caller
+        let task = match component::current() {
+            Some(label) => tokio::spawn(component::scope(label, forward)),
+            None => tokio::spawn(forward),
+        };
+        Ok(Subscription {
+            source: Source::Remote { rx, task },
+        })
+    }
+}
+
+async fn connect<K: RpcComponentKind>(
+    q: Bytes,
+    affinity: Option<u64>,
+    shard: Option<u32>,
+    unready: bool,
+    version: Option<HttpVersion>,
+    after: Option<&str>,
+) -> RpcResult<http::EventStream> {
+    let loc: Location = match shard {
+        Some(shard) => shard::discover::<K>(shard, unready).await?,
+        None => http::discover::<K>(affinity, unready).await?,
+    };
+    http::http_subscribe::<K>(loc.addr(), q, after, version).await
+}
+
+/// Forward a remote subscription's events to the receiver, reconnecting
+/// until the topic ends or the receiver is dropped.
+async fn forward<K: RpcComponentKind, T: DeserializeOwned>(
+    mut events: http::EventStream,
+    q: Bytes,
+    affinity: Option<u64>,
+    shard: Option<u32>,
+    unready: bool,
+    version: Option<HttpVersion>,
+    tx: mpsc::UnboundedSender<RpcResult<T>>,
+) {
+    let mut last: Option<String> = None;
+    loop {
+        loop {
+            let ev = match events.next().await {
+                Ok(Some(ev)) => ev,
+                Ok(None) => break,
+                Err(e) => {
+                    log::debug!("subscription to {} lost: {e}", K::LABEL);
+                    break;
+                }
+            };
+            let sent = match ev.event.as_str() {
+                "event" => {
+                    let event = serde_json::from_str::<T>(&ev.data).map_err(RpcError::from);
+                    if ev.id.is_some() {
+                        last = ev.id;
+                    }
+                    tx.send(event)
+                }
+                "missed" => tx.send(Err(missed())),
+                "end" => return,
+                _ => Ok(()),
+            };
+            if sent.is_err() {
+                return;
+            }
+        }
+
+        let mut delay = RECONNECT_MIN;
+        events = loop {
+            let jitter = rand::random_range(0.5..1.0);
+            tokio::time::sleep(delay.mul_f64(jitter)).await;
+            if tx.is_closed() {
+                return;
+            }
+            delay = (delay * 2).min(RECONNECT_MAX);
+            let after = last.as_deref();
+            match connect::<K>(q.clone(), affinity, shard, unready, version, after).await {
+                Ok(events) => break events,
+                Err(e) if gives_up(&e) => {
+                    let _ = tx.send(Err(RpcError::Downstream(K::LABEL.to_owned(), Box::new(e))));
+                    return;
+                }
+                Err(e) => log::debug!("could not resubscribe to {}: {e}", K::LABEL),
+            }
+        };
+    }
+}
+
+/// Whether reconnecting a subscription after an error would be pointless.
+fn gives_up(e: &RpcError) -> bool {
+    matches!(
+        e.root_cause(),
+        AppError::Forbidden { .. }
+            | AppError::Unauthenticated { .. }
+            | AppError::Unimplemented { .. }
+    )
+}
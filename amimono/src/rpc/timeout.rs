@@ -0,0 +1,69 @@
+//! Timeouts for the calls made by RPC clients.
+//!
+//! Each attempt at a call over HTTP has a request timeout, after which it
+//! fails with [`AppError::RequestTimeout`][crate::AppError::RequestTimeout]
+//! and is retried like a spurious error. A client can also have an operation
+//! timeout, which caps the time spent on a call across all of its attempts,
+//! after which it fails with
+//! [`AppError::OperationTimeout`][crate::AppError::OperationTimeout].
+//! Neither extends the current [deadline][crate::context#deadlines], if there
+//! is one, and request timeouts are shortened to fit within it.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::rpc::{RpcError, RpcResult};
+
+/// The request timeout of clients that don't set their own.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The timeouts of a client.
+#[derive(Clone, Debug)]
+pub(crate) struct Timeouts {
+    pub(crate) request: Duration,
+    /// Request timeouts for specific ops, overriding `request`.
+    pub(crate) ops: BTreeMap<String, Duration>,
+    pub(crate) operation: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            request: DEFAULT_REQUEST_TIMEOUT,
+            ops: BTreeMap::new(),
+            operation: None,
+        }
+    }
+}
+
+impl Timeouts {
+    /// Make a call to an op, with its request timeout applying to each
+    /// attempt, and the operation timeout, if any, to the whole call.
+    pub(crate) async fn run<X, F>(&self, label: &str, op: &str, call: F) -> RpcResult<X>
+    where
+        F: Future<Output = RpcResult<X>>,
+    {
+        let request = self.ops.get(op).copied().unwrap_or(self.request);
+        let call = REQUEST.scope(request, call);
+        let Some(operation) = self.operation else {
+            return call.await;
+        };
+        match tokio::time::timeout(operation, call).await {
+            Ok(res) => res,
+            Err(_) => Err(RpcError::OperationTimeout {
+                component: label.to_owned(),
+                op: op.to_owned(),
+                timeout_ms: operation.as_millis() as u64,
+            }),
+        }
+    }
+}
+
+tokio::task_local! {
+    static REQUEST: Duration;
+}
+
+/// The request timeout of the call on the current task, or the default
+/// outside of one, e.g. for calls made by the gateway.
+pub(crate) fn request() -> Duration {
+    REQUEST.try_with(|t| *t).unwrap_or(DEFAULT_REQUEST_TIMEOUT)
+}
@@ -3,16 +3,22 @@
 //! The runtime provides access to global information about the application,
 //! such as the `AppConfig` and bindings. The runtime is initialized internally.
 
-use std::{net::SocketAddr, path::PathBuf};
+use std::{
+    collections::HashSet, fmt, net::SocketAddr, path::PathBuf, sync::LazyLock, time::Duration,
+};
 
 use futures::future::BoxFuture;
+use rand::Rng;
+use serde::Serialize;
 use std::sync::OnceLock;
 
 use crate::{
+    actor, audit,
     cli::Args,
-    component::Location,
-    config::{AppConfig, ComponentConfig},
+    component::{Location, Replica},
+    config::{AppConfig, ComponentConfig, MaxLifetime},
     error::{Error, Result},
+    health, metrics, rpc, shard, storage, tasks, watchdog,
 };
 
 pub(crate) trait RuntimeProvider: Sync + Send + 'static {
@@ -28,7 +34,72 @@ pub(crate) trait RuntimeProvider: Sync + Send + 'static {
 
     fn myself<'f, 'p: 'f, 'l: 'f>(&'p self, component: &'l str) -> BoxFuture<'f, Result<Location>>;
 
+    fn discover_replicas<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+    ) -> BoxFuture<'f, Result<Vec<Replica>>> {
+        Box::pin(async move {
+            let locs = self.discover_running(component).await?;
+            Ok(locs.into_iter().map(Replica::at).collect())
+        })
+    }
+
+    /// Every running replica of a component, including ones that aren't
+    /// ready, which `discover_running` and `discover_replicas` leave out. By
+    /// default this is `discover_replicas`, for runtimes that don't check
+    /// readiness.
+    fn discover_all_replicas<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+    ) -> BoxFuture<'f, Result<Vec<Replica>>> {
+        self.discover_replicas(component)
+    }
+
+    /// The stable location of the `n`th replica of a component, for
+    /// [`Location::ordinal`]. By default this is the `n`th location from
+    /// `discover_stable`, for runtimes that list replicas in a stable order.
+    fn ordinal<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+        n: usize,
+    ) -> BoxFuture<'f, Result<Location>> {
+        Box::pin(async move {
+            let locs = self.discover_stable(component).await?;
+            let count = locs.len();
+            locs.into_iter()
+                .nth(n)
+                .ok_or_else(|| format!("no replica {n} of {component}, only {count}").into())
+        })
+    }
+
+    /// The ordinal of this process among the replicas of a job, for jobs
+    /// whose replicas run different components. By default replicas have no
+    /// ordinals, and such jobs can't start.
+    fn my_ordinal<'f, 'p: 'f, 'l: 'f>(&'p self, job: &'l str) -> BoxFuture<'f, Result<usize>> {
+        Box::pin(async move {
+            Err(format!(
+                "replicas of {job} have no ordinals in this runtime"
+            ))?
+        })
+    }
+
     fn storage<'f, 'p: 'f, 'l: 'f>(&'p self, component: &'l str) -> BoxFuture<'f, Result<PathBuf>>;
+
+    /// Which of the above this provider supports.
+    fn capabilities(&self) -> Capabilities;
+
+    /// Whether this is the local development runtime, whose storage is
+    /// subject to local retention limits.
+    fn is_dev(&self) -> bool {
+        false
+    }
+
+    /// Whether `/admin/resolve` answers where components are, which is only
+    /// meant for development, e.g. so that scripts can find a component
+    /// without reading the runtime's internals.
+    fn serves_resolver(&self) -> bool {
+        false
+    }
 }
 
 pub(crate) struct NoopRuntime;
@@ -61,10 +132,17 @@ impl RuntimeProvider for NoopRuntime {
     ) -> BoxFuture<'f, Result<PathBuf>> {
         Box::pin(async { Err("storage() called on noop runtime")? })
     }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
 }
 
 static RUNTIME: OnceLock<Runtime> = OnceLock::new();
 
+/// How long a job waits for its actors to be handed over when it exits.
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(10);
+
 struct Runtime {
     cf: AppConfig,
     args: Args,
@@ -93,6 +171,90 @@ pub(crate) fn args() -> &'static Args {
     &get().args
 }
 
+/// The features supported by the runtime an app is running in, which depend
+/// on where it's deployed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Capabilities {
+    /// Stateful components can get a storage directory with
+    /// [`Component::storage`][crate::component::Component::storage].
+    pub storage: bool,
+
+    /// Components can get their own location with
+    /// [`ComponentKind::myself`][crate::component::ComponentKind::myself].
+    pub myself: bool,
+
+    /// Stable discovery also finds replicas that are only briefly
+    /// unreachable, rather than the same replicas as running discovery.
+    pub stable_discovery: bool,
+}
+
+/// Get the features supported by the runtime, so that components can adapt to
+/// what's missing, e.g. by not persisting anything without storage, rather
+/// than finding out from errors.
+pub fn capabilities() -> Capabilities {
+    provider().capabilities()
+}
+
+/// Information about how the running binary was built.
+///
+/// This is generated by `amimono_build::BuildInfo` in a build script and
+/// embedded with [`include_build_info!`][crate::include_build_info]. Apps
+/// that don't embed it only have a revision.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct BuildInfo {
+    pub revision: &'static str,
+
+    /// The commit the binary was built from, with `-dirty` appended if there
+    /// were uncommitted changes.
+    pub git_sha: Option<&'static str>,
+
+    /// When the binary was built, as an RFC 3339 timestamp.
+    pub built_at: Option<&'static str>,
+
+    /// The output of `rustc --version`.
+    pub rustc_version: Option<&'static str>,
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "revision {}", self.revision)?;
+        if let Some(sha) = self.git_sha {
+            write!(f, ", git {sha}")?;
+        }
+        if let Some(at) = self.built_at {
+            write!(f, ", built {at}")?;
+        }
+        if let Some(rustc) = self.rustc_version {
+            write!(f, ", {rustc}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Embed the build info generated by `amimono_build::BuildInfo::emit` in a
+/// build script, as a [`BuildInfo`][crate::runtime::BuildInfo].
+#[macro_export]
+macro_rules! include_build_info {
+    () => {
+        include!(concat!(env!("OUT_DIR"), "/amimono_build_info.rs"))
+    };
+}
+
+/// Embed the config dump generated by `amimono_build::EmbeddedConfig` in a
+/// build script, for
+/// [`AppBuilder::with_embedded_config`][crate::config::AppBuilder::with_embedded_config].
+#[macro_export]
+macro_rules! include_config {
+    () => {
+        include_str!(concat!(env!("OUT_DIR"), "/amimono_config.json"))
+    };
+}
+
+/// Get information about how the running binary was built.
+pub fn build_info() -> BuildInfo {
+    config().build_info()
+}
+
 /// Get a SockAddr to bind to for a given port
 pub fn to_addr(port: u16) -> SocketAddr {
     match &args().bind {
@@ -105,35 +267,190 @@ pub fn to_addr(port: u16) -> SocketAddr {
     }
 }
 
-async fn launch_comps(to_launch: Vec<&ComponentConfig>) -> Result<()> {
+async fn launch_comps(
+    service: &str,
+    to_launch: Vec<&ComponentConfig>,
+    max_lifetime: Option<&MaxLifetime>,
+) -> Result<()> {
+    log::info!("starting {service}: {}", build_info());
+    let info = build_info();
+    audit::event("job.start")
+        .with("git_sha", info.git_sha)
+        .with("built_at", info.built_at)
+        .record();
+
+    for comp in to_launch.iter() {
+        health::register(&comp.label);
+    }
+
+    // make sure the admin endpoints are served even if no component in the
+    // job needs the RPC server
+    LazyLock::force(&rpc::http::HTTP_SERVER);
+
+    storage::start_accounting(&to_launch);
+    watchdog::start(service, &to_launch);
+    shard::start(&to_launch);
+    metrics::push::start(service);
+    rpc::capture::set_service(service);
+
     let joins = to_launch
         .into_iter()
         .map(|comp| {
             log::debug!("spawn {}", comp.label);
-            tokio::spawn((comp.entry)())
+            let own_runtime = config()
+                .component_job(&comp.label)
+                .and_then(|j| config().job(j))
+                .and_then(|j| j.component_runtime(&comp.label));
+            match own_runtime {
+                Some(tokio_cf) => {
+                    let rt = tokio_cf.build(&comp.label).map_err(|e| {
+                        format!("failed to build runtime for {}: {}", comp.label, e)
+                    })?;
+                    // the runtime must outlive the component, which runs for
+                    // the life of the process
+                    Ok(Box::leak(Box::new(rt)).spawn((comp.entry)()))
+                }
+                None => Ok(tokio::spawn((comp.entry)())),
+            }
         })
-        .collect::<Vec<_>>();
+        .collect::<Result<Vec<_>>>()?;
 
     log::info!("components started");
-    for join in joins {
-        join.await
-            .map_err(|e| format!("component task failed: {}", e))?;
+    let run = async {
+        for join in joins {
+            join.await
+                .map_err(|e| format!("component task failed: {}", e))?;
+        }
+        Ok(())
+    };
+    tokio::select! {
+        res = run => res,
+        _ = shutdown_signal() => {
+            log::info!("shutting down {service}");
+            hand_off_actors().await;
+            tasks::abort_everything();
+            Ok(())
+        }
+        _ = lifetime_expired(max_lifetime) => {
+            log::info!("restarting {service}");
+            hand_off_actors().await;
+            tasks::abort_everything();
+            Ok(())
+        }
+    }
+}
+
+/// Resolves when the job has reached its max lifetime and drained, or never
+/// if it doesn't have one.
+async fn lifetime_expired(max: Option<&MaxLifetime>) {
+    let Some(max) = max else {
+        return std::future::pending().await;
+    };
+    let jitter = rand::rng().random_range(Duration::ZERO..=max.jitter.min(max.lifetime));
+    let lifetime = max.lifetime - jitter;
+    log::info!("job will restart after {lifetime:?}");
+    tokio::time::sleep(lifetime).await;
+
+    audit::event("job.expired")
+        .with("lifetime_secs", lifetime.as_secs())
+        .record();
+    health::drain("max lifetime reached");
+    tokio::time::sleep(max.drain).await;
+}
+
+/// Hand the actors hosted by this process over to other replicas before
+/// exiting, giving up after a while so that the job still exits before it's
+/// killed.
+async fn hand_off_actors() {
+    if tokio::time::timeout(HANDOFF_TIMEOUT, actor::hand_off_all())
+        .await
+        .is_err()
+    {
+        log::warn!("timed out handing over actors");
     }
+}
+
+/// Resolves when the process is asked to stop, with SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(e) => {
+                log::warn!("could not listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log::warn!("could not listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
 
-    Ok(())
+    tokio::select! {
+        _ = interrupt => (),
+        _ = terminate => (),
+    }
 }
 
 pub(crate) async fn launch_local() -> Result<()> {
-    launch_comps(config().jobs().flat_map(|j| j.components()).collect()).await
+    launch_comps(
+        "local",
+        config().jobs().flat_map(|j| j.components()).collect(),
+        None,
+    )
+    .await
 }
 
 pub(crate) async fn launch_job(job: &str) -> Result<()> {
     match config().job(job) {
-        Some(j) => launch_comps(j.components().collect()).await,
+        Some(j) => {
+            let max_lifetime = j.max_lifetime().filter(|_| !j.runs_once());
+            let mut comps = j.components().collect::<Vec<_>>();
+            if comps
+                .iter()
+                .any(|c| j.component_replicas(&c.label).is_some())
+            {
+                let n = provider().my_ordinal(job).await.map_err(|e| {
+                    format!("could not tell which of {job}'s components to run: {e}")
+                })?;
+                let (run, skip): (Vec<_>, Vec<_>) =
+                    comps.into_iter().partition(|c| j.runs_on(&c.label, n));
+                comps = run;
+                SKIPPED
+                    .set(skip.iter().map(|c| c.label.clone()).collect())
+                    .expect("SKIPPED already set");
+                match comps.is_empty() {
+                    true => log::info!("replica {n} of {job} runs none of its components"),
+                    false => {
+                        let labels = comps.iter().map(|c| c.label.as_str()).collect::<Vec<_>>();
+                        log::info!("replica {n} of {job} runs {}", labels.join(", "));
+                    }
+                }
+            }
+            launch_comps(j.label(), comps, max_lifetime).await
+        }
         None => Err(format!("no such job: {}", job))?,
     }
 }
 
+/// The components of this process's job that its replica doesn't run.
+static SKIPPED: OnceLock<HashSet<String>> = OnceLock::new();
+
+/// Whether this process runs a component of its job, which it does unless
+/// the component only runs on other replicas.
+pub(crate) fn runs_here(label: &str) -> bool {
+    SKIPPED.get().is_none_or(|skipped| !skipped.contains(label))
+}
+
 pub(crate) async fn launch_tool(tool: &'static str) -> Result<()> {
     let tool_args = {
         let tool_args: Vec<&'static str> = [tool]
@@ -147,7 +464,18 @@ pub(crate) async fn launch_tool(tool: &'static str) -> Result<()> {
     match config().tool(tool) {
         Some(t) => {
             log::info!("starting tool {tool}");
-            t.entry.entry(&tool_args[..]).await?;
+            audit::event("tool.start")
+                .with("args", &tool_args[1..])
+                .record();
+            metrics::push::start(tool);
+            let res = t.entry.entry(&tool_args[..]).await;
+            metrics::push::flush().await;
+            let mut finish = audit::event("tool.finish").with("ok", res.is_ok());
+            if let Err(e) = &res {
+                finish = finish.with("error", e.to_string());
+            }
+            finish.record();
+            res?;
             Ok(())
         }
         None => {
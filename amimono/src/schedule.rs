@@ -0,0 +1,425 @@
+//! Schedules for periodic work.
+//!
+//! A [`Schedule`] describes when something should run: either a standard
+//! five-field cron expression, or every N minutes aligned to the wall clock.
+//! Schedules are evaluated in a timezone, UTC unless set otherwise, and can
+//! have blackout windows during which they don't fire.
+//!
+//! ```ignore
+//! let schedule = Schedule::cron("30 9 * * MON-FRI")?
+//!     .in_timezone("Europe/Paris")?
+//!     .with_blackout(Blackout::daily("12:00", "14:00")?);
+//! ```
+//!
+//! [`Schedule::next_after`] computes the next time a schedule fires, and
+//! doesn't depend on the current time, so schedules can be checked in tests.
+//! [`Schedule::wait`] sleeps until the next fire time. Schedules registered
+//! with [`register`] are listed along with their next fire times at
+//! `/admin/schedules`.
+//!
+//! Daylight saving transitions are handled the way most cron implementations
+//! handle them: local times skipped by a transition don't fire, and local
+//! times repeated by a transition fire once, at their first occurrence.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{LazyLock, Mutex},
+};
+
+use chrono::{
+    Datelike, Duration, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike,
+};
+use chrono_tz::Tz;
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+
+pub use chrono::{DateTime, Utc};
+
+/// How many candidate times are considered before giving up on finding the
+/// next fire time, e.g. for `0 0 31 2 *`, which never fires.
+const MAX_STEPS: usize = 100_000;
+
+const MONTHS: &[&str] = &[
+    "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+];
+
+const WEEKDAYS: &[&str] = &["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+/// When to run something periodically.
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    kind: Kind,
+    tz: Tz,
+    blackouts: Vec<Blackout>,
+}
+
+#[derive(Clone, Debug)]
+enum Kind {
+    Cron(Cron),
+    Every(u32),
+}
+
+/// A parsed cron expression. Each field is a bit set of the values it
+/// matches.
+#[derive(Clone, Debug)]
+struct Cron {
+    expr: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// A window during which a schedule doesn't fire.
+#[derive(Clone, Debug)]
+pub enum Blackout {
+    /// Every day between two local times in the schedule's timezone. The
+    /// window wraps past midnight if the end is before the start.
+    Daily { start: NaiveTime, end: NaiveTime },
+
+    /// Between two instants, e.g. for a planned maintenance.
+    Between {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+}
+
+impl Schedule {
+    /// A schedule from a standard five-field cron expression, i.e. minute,
+    /// hour, day of month, month, and day of week. Fields can be `*`, values,
+    /// ranges, lists, and steps, and months and days of the week can be given
+    /// by their three-letter names. As usual, when both the day of month and
+    /// day of week are restricted, a day matching either fires. The
+    /// `@hourly`, `@daily`, `@weekly`, `@monthly`, and `@yearly` shorthands
+    /// are also accepted.
+    pub fn cron(expr: &str) -> Result<Schedule> {
+        let cron = Cron::parse(expr)
+            .map_err(|e| Error::User(format!("invalid cron expression {expr:?}: {e}")))?;
+        Ok(Schedule {
+            kind: Kind::Cron(cron),
+            tz: Tz::UTC,
+            blackouts: Vec::new(),
+        })
+    }
+
+    /// A schedule that fires every `n` minutes, aligned to the wall clock, so
+    /// that e.g. every 15 minutes fires on the hour and at a quarter past.
+    /// Intervals that don't divide a day evenly restart at midnight. Panics if
+    /// `n` is zero or more than a day.
+    pub fn every_minutes(n: u32) -> Schedule {
+        if n == 0 || n > 24 * 60 {
+            panic!("schedule interval must be between 1 minute and 1 day, got {n} minutes");
+        }
+        Schedule {
+            kind: Kind::Every(n),
+            tz: Tz::UTC,
+            blackouts: Vec::new(),
+        }
+    }
+
+    /// Evaluate the schedule in the given IANA timezone, e.g.
+    /// `America/New_York`.
+    pub fn in_timezone(mut self, tz: &str) -> Result<Schedule> {
+        self.tz = tz
+            .parse()
+            .map_err(|_| Error::User(format!("unknown timezone {tz:?}")))?;
+        Ok(self)
+    }
+
+    /// Don't fire during the given window.
+    pub fn with_blackout(mut self, blackout: Blackout) -> Schedule {
+        self.blackouts.push(blackout);
+        self
+    }
+
+    /// The name of the timezone the schedule is evaluated in.
+    pub fn timezone(&self) -> &str {
+        self.tz.name()
+    }
+
+    /// The first time strictly after `t` that the schedule fires, or `None`
+    /// if it never fires again.
+    pub fn next_after(&self, t: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut local = t.with_timezone(&self.tz).naive_local();
+        for _ in 0..MAX_STEPS {
+            local = self.kind.next_local(local)?;
+            let at = match self.tz.from_local_datetime(&local) {
+                LocalResult::Single(at) => at,
+                LocalResult::Ambiguous(at, _) => at,
+                LocalResult::None => continue,
+            };
+            let at = at.with_timezone(&Utc);
+            if at <= t || self.blackouts.iter().any(|b| b.contains(local, at)) {
+                continue;
+            }
+            return Some(at);
+        }
+        None
+    }
+
+    /// The next time the schedule fires.
+    pub fn next(&self) -> Option<DateTime<Utc>> {
+        self.next_after(Utc::now())
+    }
+
+    /// Sleep until the next time the schedule fires, returning that time, or
+    /// return `None` right away if it never fires again.
+    pub async fn wait(&self) -> Option<DateTime<Utc>> {
+        let at = self.next()?;
+        let delay = (at - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(delay).await;
+        Some(at)
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            Kind::Cron(cron) => write!(f, "cron {:?}", cron.expr)?,
+            Kind::Every(1) => write!(f, "every minute")?,
+            Kind::Every(n) => write!(f, "every {n} minutes")?,
+        }
+        write!(f, " in {}", self.tz.name())?;
+        for b in self.blackouts.iter() {
+            match b {
+                Blackout::Daily { start, end } => write!(
+                    f,
+                    ", except {}-{} daily",
+                    start.format("%H:%M"),
+                    end.format("%H:%M")
+                )?,
+                Blackout::Between { start, end } => {
+                    write!(f, ", except {} to {}", start.to_rfc3339(), end.to_rfc3339())?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Blackout {
+    /// A daily window between two local times, given as `HH:MM`.
+    pub fn daily(start: &str, end: &str) -> Result<Blackout> {
+        let parse = |s: &str| {
+            NaiveTime::parse_from_str(s, "%H:%M")
+                .map_err(|_| Error::User(format!("invalid time {s:?}, expected HH:MM")))
+        };
+        Ok(Blackout::Daily {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+
+    /// A one-off window between two instants.
+    pub fn between(start: DateTime<Utc>, end: DateTime<Utc>) -> Blackout {
+        Blackout::Between { start, end }
+    }
+
+    fn contains(&self, local: NaiveDateTime, at: DateTime<Utc>) -> bool {
+        match self {
+            Blackout::Daily { start, end } => {
+                let t = local.time();
+                match start <= end {
+                    true => *start <= t && t < *end,
+                    false => *start <= t || t < *end,
+                }
+            }
+            Blackout::Between { start, end } => *start <= at && at < *end,
+        }
+    }
+}
+
+impl Kind {
+    /// The first local time strictly after `after` that matches.
+    fn next_local(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut t = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        for _ in 0..MAX_STEPS {
+            match self {
+                Kind::Cron(cron) => match cron.skip(t) {
+                    Some(next) => t = next,
+                    None => return Some(t),
+                },
+                Kind::Every(n) => {
+                    let minute = t.hour() * 60 + t.minute();
+                    if minute.is_multiple_of(*n) {
+                        return Some(t);
+                    }
+                    let next = minute + (n - minute % n);
+                    t = match next < 24 * 60 {
+                        true => t + Duration::minutes((next - minute) as i64),
+                        false => next_day(t.date())?,
+                    };
+                }
+            }
+        }
+        None
+    }
+}
+
+fn next_day(date: NaiveDate) -> Option<NaiveDateTime> {
+    Some(date.succ_opt()?.and_time(NaiveTime::MIN))
+}
+
+fn bit(set: u64, n: u32) -> bool {
+    set & (1 << n) != 0
+}
+
+impl Cron {
+    fn parse(expr: &str) -> std::result::Result<Cron, String> {
+        let expanded = match expr.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other,
+        };
+        let fields = expanded.split_whitespace().collect::<Vec<_>>();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("expected 5 fields, got {}", fields.len()));
+        };
+        let mut weekdays =
+            parse_field(weekday, 0, 7, WEEKDAYS).map_err(|e| format!("day of week: {e}"))?;
+        // 7 is also Sunday
+        if bit(weekdays, 7) {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Cron {
+            expr: expr.trim().to_owned(),
+            minutes: parse_field(minute, 0, 59, &[]).map_err(|e| format!("minute: {e}"))?,
+            hours: parse_field(hour, 0, 23, &[]).map_err(|e| format!("hour: {e}"))?,
+            days: parse_field(day, 1, 31, &[]).map_err(|e| format!("day of month: {e}"))?,
+            months: parse_field(month, 1, 12, MONTHS).map_err(|e| format!("month: {e}"))?,
+            weekdays,
+            any_day: day.starts_with('*'),
+            any_weekday: weekday.starts_with('*'),
+        })
+    }
+
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        let day = bit(self.days, date.day());
+        let weekday = bit(self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+
+    /// The next time worth checking if `t` doesn't match, or `None` if it
+    /// does.
+    fn skip(&self, t: NaiveDateTime) -> Option<NaiveDateTime> {
+        let date = t.date();
+        if !bit(self.months, t.month()) {
+            let (y, m) = match t.month() {
+                12 => (t.year() + 1, 1),
+                m => (t.year(), m + 1),
+            };
+            return NaiveDate::from_ymd_opt(y, m, 1).map(|d| d.and_time(NaiveTime::MIN));
+        }
+        if !self.day_matches(date) {
+            return next_day(date);
+        }
+        if !bit(self.hours, t.hour()) {
+            return Some(date.and_hms_opt(t.hour(), 0, 0)? + Duration::hours(1));
+        }
+        if !bit(self.minutes, t.minute()) {
+            return Some(t + Duration::minutes(1));
+        }
+        None
+    }
+}
+
+/// Parse one field of a cron expression into a bit set of the values it
+/// matches. `names` are alternative names for the values starting at `min`.
+fn parse_field(
+    field: &str,
+    min: u32,
+    max: u32,
+    names: &[&str],
+) -> std::result::Result<u64, String> {
+    let value = |s: &str| -> std::result::Result<u32, String> {
+        if let Some(i) = names.iter().position(|n| n.eq_ignore_ascii_case(s)) {
+            return Ok(min + i as u32);
+        }
+        let n = s
+            .parse::<u32>()
+            .map_err(|_| format!("invalid value {s:?}"))?;
+        if n < min || n > max {
+            return Err(format!("{n} is out of range {min}-{max}"));
+        }
+        Ok(n)
+    };
+
+    let mut set = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step = step
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|&s| s > 0)
+                    .ok_or_else(|| format!("invalid step {step:?}"))?;
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (lo, hi) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((lo, hi)) => (value(lo)?, value(hi)?),
+            // a single value with a step runs to the end of the range
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if lo > hi {
+            return Err(format!("invalid range {range:?}"));
+        }
+        for n in (lo..=hi).step_by(step as usize) {
+            set |= 1 << n;
+        }
+    }
+    Ok(set)
+}
+
+static SCHEDULES: LazyLock<Mutex<BTreeMap<String, Schedule>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// List a schedule on the admin endpoint under the given name, replacing any
+/// schedule already registered with that name.
+pub fn register(name: &str, schedule: &Schedule) {
+    SCHEDULES
+        .lock()
+        .expect("lock poisoned")
+        .insert(name.to_owned(), schedule.clone());
+}
+
+#[derive(Serialize)]
+pub(crate) struct ScheduleReport {
+    schedule: String,
+    timezone: String,
+    next: Option<String>,
+}
+
+/// Every registered schedule, with its next fire time.
+pub(crate) fn report() -> BTreeMap<String, ScheduleReport> {
+    let now = Utc::now();
+    SCHEDULES
+        .lock()
+        .expect("lock poisoned")
+        .iter()
+        .map(|(name, s)| {
+            let report = ScheduleReport {
+                schedule: s.to_string(),
+                timezone: s.timezone().to_owned(),
+                next: s.next_after(now).map(|t| t.to_rfc3339()),
+            };
+            (name.clone(), report)
+        })
+        .collect()
+}
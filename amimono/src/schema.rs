@@ -0,0 +1,203 @@
+//! A built-in schema registry, for seeing API drift between revisions at
+//! runtime.
+//!
+//! Jobs are deployed independently, so a caller can be running a different
+//! revision of a component's API than the replicas it calls, and only find
+//! out from the errors. When the app installs [`SchemaRegistryComponent`] in
+//! a job of its own, every RPC component publishes the signatures of its ops,
+//! with the JSON Schemas of their types, to it when it starts, and every
+//! client checks the ops it was built with against the ones published for the
+//! component before its first call over the network:
+//!
+//! ```ignore
+//! JobBuilder::new()
+//!     .with_label("schemas")
+//!     .install(amimono::schema::SchemaRegistryComponent::installer)
+//! ```
+//!
+//! Mismatches are logged as warnings and counted in the
+//! `amimono_schema_mismatches` metric, by component and kind: `missing` for
+//! ops the client calls that a revision of the component doesn't serve, and
+//! `changed` for ops whose types differ, e.g. with a field or variant only
+//! one side knows about. Checks never fail calls. Like `ammn compat`, types
+//! that implement [`JsonSchema`][schemars::JsonSchema] are compared by their
+//! schemas, and other types by name, as written in the
+//! [`rpc_component!`][crate::rpc_component] invocation.
+//!
+//! The registry runs as a single replica and keeps schemas in memory.
+//! Components publish again every minute, so a restarted registry fills back
+//! up, and revisions that haven't been published for a while are forgotten.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use amimono_schemas::DumpRpcOp;
+
+use crate::{
+    metrics,
+    rpc::{RpcComponentKind, RpcResult},
+    runtime,
+    tasks::TaskSet,
+};
+
+crate::rpc_component! {
+    // the generated clients that this module doesn't use would otherwise be warned about
+    #[allow(dead_code)]
+    mod ops {
+        const LABEL: &'static str = "amimono-schema-registry";
+
+        /// Publish the ops a revision of a component serves.
+        fn publish(component: String, revision: String, ops: Vec<amimono_schemas::DumpRpcOp>) -> ();
+
+        /// Get the ops served by each revision of a component that's been
+        /// published recently.
+        fn get(component: String) -> std::collections::BTreeMap<String, Vec<amimono_schemas::DumpRpcOp>>;
+    }
+}
+
+/// The label of the schema registry component.
+pub const LABEL: &str = "amimono-schema-registry";
+
+/// How often components publish their schemas.
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long the registry remembers a revision that isn't published again.
+const EXPIRY: Duration = Duration::from_secs(300);
+
+/// The built-in schema registry component. See the
+/// [module-level documentation][self].
+pub type SchemaRegistryComponent = ops::Component<SchemaRegistryHandler>;
+
+/// The ops of each revision of a component, and when they were last
+/// published.
+type Revisions = BTreeMap<String, (Instant, Vec<DumpRpcOp>)>;
+
+/// The handler of [`SchemaRegistryComponent`].
+pub struct SchemaRegistryHandler {
+    schemas: Mutex<BTreeMap<String, Revisions>>,
+}
+
+impl ops::Handler for SchemaRegistryHandler {
+    async fn new() -> Self {
+        SchemaRegistryHandler {
+            schemas: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    async fn publish(
+        &self,
+        component: &String,
+        revision: &String,
+        ops: &Vec<DumpRpcOp>,
+    ) -> RpcResult<()> {
+        let mut schemas = self.schemas.lock().expect("lock poisoned");
+        let revisions = schemas.entry(component.clone()).or_default();
+        revisions.insert(revision.clone(), (Instant::now(), ops.clone()));
+        revisions.retain(|_, (published, _)| published.elapsed() < EXPIRY);
+        Ok(())
+    }
+
+    async fn get(&self, component: &String) -> RpcResult<BTreeMap<String, Vec<DumpRpcOp>>> {
+        let schemas = self.schemas.lock().expect("lock poisoned");
+        let Some(revisions) = schemas.get(component) else {
+            return Ok(BTreeMap::new());
+        };
+        Ok(revisions
+            .iter()
+            .filter(|(_, (published, _))| published.elapsed() < EXPIRY)
+            .map(|(rev, (_, ops))| (rev.clone(), ops.clone()))
+            .collect())
+    }
+}
+
+/// Whether the app has a schema registry to publish to and check against.
+fn enabled(label: &str) -> bool {
+    label != LABEL && runtime::config().component_job(LABEL).is_some()
+}
+
+fn schema<T: RpcComponentKind>() -> Vec<DumpRpcOp> {
+    T::OPS.iter().map(crate::dump_rpc_op).collect()
+}
+
+/// Publish the schema of a component served by this process, now and every
+/// so often after, if the app has a schema registry.
+pub(crate) fn publish<T: RpcComponentKind>() {
+    if !enabled(T::LABEL) {
+        return;
+    }
+    TaskSet::of(T::LABEL).spawn("schema", async {
+        let client = ops::Client::new();
+        let ops = schema::<T>();
+        let revision = runtime::config().revision().to_owned();
+        loop {
+            // boxed, since client calls are big futures to keep on the
+            // stack of the task polling them
+            let publish = client.publish(T::LABEL.to_owned(), revision.clone(), ops.clone());
+            let res = Box::pin(publish).await;
+            if let Err(e) = res {
+                log::warn!("{}: could not publish schema: {}", T::LABEL, e);
+            }
+            tokio::time::sleep(PUBLISH_INTERVAL).await;
+        }
+    });
+}
+
+static CHECKED: LazyLock<Mutex<HashSet<&'static str>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Check the schema this process was built with for a component against the
+/// ones published for it, the first time it's called over the network. The
+/// check runs in the background, so the call isn't held up by it.
+pub(crate) fn check<T: RpcComponentKind>() {
+    if !enabled(T::LABEL) || !CHECKED.lock().expect("lock poisoned").insert(T::LABEL) {
+        return;
+    }
+    tokio::spawn(async {
+        let client = ops::Client::new();
+        let published = match Box::pin(client.get(T::LABEL.to_owned())).await {
+            Ok(published) => published,
+            Err(e) => {
+                log::warn!("{}: could not check schema: {}", T::LABEL, e);
+                // try again on the next call
+                CHECKED.lock().expect("lock poisoned").remove(T::LABEL);
+                return;
+            }
+        };
+        let ours = schema::<T>();
+        for (revision, theirs) in published.iter() {
+            for op in ours.iter() {
+                let mismatch = match theirs.iter().find(|o| o.name == op.name) {
+                    None if op.optional => None,
+                    None => Some(("missing", format!("op {} isn't served", signature(op)))),
+                    Some(o) => {
+                        let changes = op.type_changes(o);
+                        let changes = changes.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+                        (!changes.is_empty()).then(|| {
+                            let msg = format!(
+                                "op {} is served with changes: {}",
+                                op.name,
+                                changes.join(", ")
+                            );
+                            ("changed", msg)
+                        })
+                    }
+                };
+                if let Some((kind, msg)) = mismatch {
+                    log::warn!(
+                        "{}: schema mismatch with revision {revision}: {msg}",
+                        T::LABEL
+                    );
+                    let labels = [("component", T::LABEL), ("kind", kind)];
+                    metrics::counter("amimono_schema_mismatches", &labels).inc();
+                }
+            }
+        }
+    });
+}
+
+fn signature(op: &DumpRpcOp) -> String {
+    format!("{}({}) -> {}", op.name, op.args.join(", "), op.ret)
+}
@@ -0,0 +1,129 @@
+//! Settings that can change while the app is running.
+//!
+//! Unlike the app's [config][crate::config], which is fixed for a revision,
+//! settings are string key-value pairs that operators can change at any time,
+//! e.g. to pause a feature or adjust a limit, and that components read when
+//! they need them. Components should have a default for every setting they
+//! read, since settings can be missing or removed.
+//!
+//! On Kubernetes, settings are the data of a ConfigMap in the pod's
+//! namespace, named by the `AMIMONO_SETTINGS_CONFIGMAP` environment variable,
+//! e.g. from the target's `env` in `amimono.toml`. The ConfigMap is watched,
+//! so changes reach every pod within seconds, without restarting it. The
+//! pods' service account needs permission to get, list, and watch
+//! ConfigMaps. Other runtimes don't have settings yet.
+//!
+//! ```ignore
+//! let batch_size = settings::get_or("indexer.batch-size", 100);
+//!
+//! let mut paused = settings::watch("indexer.paused");
+//! while let Some(value) = paused.changed().await {
+//!     log::info!("indexer.paused is now {value:?}");
+//! }
+//! ```
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+    sync::{Arc, LazyLock},
+};
+
+use tokio::sync::watch;
+
+use crate::{audit, metrics};
+
+type Values = Arc<BTreeMap<String, String>>;
+
+static SETTINGS: LazyLock<watch::Sender<Values>> =
+    LazyLock::new(|| watch::Sender::new(Arc::default()));
+
+/// The current value of a setting, if it's set.
+pub fn get(key: &str) -> Option<String> {
+    SETTINGS.borrow().get(key).cloned()
+}
+
+/// The current value of a setting, parsed, or `default` if it's not set or
+/// can't be parsed.
+pub fn get_or<T: FromStr>(key: &str, default: T) -> T {
+    let Some(value) = get(key) else {
+        return default;
+    };
+    match value.parse() {
+        Ok(value) => value,
+        Err(_) => {
+            log::warn!("ignoring setting {key}={value:?}, which can't be parsed");
+            default
+        }
+    }
+}
+
+/// All of the current settings.
+pub fn all() -> BTreeMap<String, String> {
+    (**SETTINGS.borrow()).clone()
+}
+
+/// Watch a setting for changes.
+pub fn watch(key: &str) -> Watch {
+    let rx = SETTINGS.subscribe();
+    let last = rx.borrow().get(key).cloned();
+    Watch {
+        key: key.to_owned(),
+        rx,
+        last,
+    }
+}
+
+/// A setting being watched for changes, from [`watch`].
+pub struct Watch {
+    key: String,
+    rx: watch::Receiver<Values>,
+    last: Option<String>,
+}
+
+impl Watch {
+    /// The setting's value as of the last change seen.
+    pub fn get(&self) -> Option<&str> {
+        self.last.as_deref()
+    }
+
+    /// Wait for the setting to change, returning its new value, which is
+    /// `None` if it was removed.
+    pub async fn changed(&mut self) -> Option<String> {
+        loop {
+            if self.rx.changed().await.is_err() {
+                // the sender is static, so this never happens
+                std::future::pending::<()>().await;
+            }
+            let value = self.rx.borrow_and_update().get(&self.key).cloned();
+            if value != self.last {
+                self.last = value.clone();
+                return value;
+            }
+        }
+    }
+}
+
+/// Replace all of the settings with those from `source`, notifying
+/// watchers of the ones that changed.
+pub(crate) fn replace(source: &str, values: BTreeMap<String, String>) {
+    let changed = {
+        let old = SETTINGS.borrow();
+        old.keys()
+            .chain(values.keys())
+            .filter(|k| old.get(*k) != values.get(*k))
+            .cloned()
+            .collect::<BTreeSet<String>>()
+    };
+    if changed.is_empty() {
+        return;
+    }
+    let changed = changed.into_iter().collect::<Vec<_>>();
+    // values aren't logged, since they could be sensitive
+    log::info!("settings changed in {}: {}", source, changed.join(", "));
+    audit::event("settings.changed")
+        .with("source", source)
+        .with("keys", &changed)
+        .record();
+    metrics::counter("amimono_settings_updates", &[]).inc();
+    SETTINGS.send_replace(Arc::new(values));
+}
@@ -0,0 +1,395 @@
+//! Sharded components, whose keys are split into shards that are each owned
+//! by one of their replicas.
+//!
+//! A stateful component that partitions its data among its replicas, e.g.
+//! by account, needs calls for a key to reach the replica that holds it.
+//! Apps shard such a component with
+//! [`AppBuilder::with_sharding`][crate::config::AppBuilder::with_sharding],
+//! and callers map keys to shards and send calls to their owners:
+//!
+//! ```ignore
+//! // in the app's config
+//! app.with_sharding("accounts", ShardingConfig::new(64));
+//!
+//! // anywhere else
+//! let shard = amimono::shard::of("accounts", &account_id);
+//! let balance = accounts.with_shard(shard).balance(account_id).await?;
+//!
+//! // in the component, e.g. to load the data of the shards it owns
+//! let owned = amimono::shard::owned("accounts");
+//! ```
+//!
+//! Every [`CLAIM_INTERVAL`], each replica claims the shards that rendezvous
+//! hashing assigns it among the replicas it discovers, and serves its claims
+//! at `/admin/shards/{label}`. Discovering a sharded component fills in each
+//! replica's [`shards`][crate::component::Replica::shards] with the claims
+//! it last reported, which are fetched from it and cached for
+//! [`REPORT_TTL`], and calls for a shard are sent to the replica that claims
+//! it, or to the one hashing picks if no replica does.
+//!
+//! When replicas are added or removed, shards move. A replica keeps claiming
+//! a shard it's losing until the new owner claims it, so that some replica
+//! always serves it, and while two replicas claim a shard, calls go to the
+//! one hashing picks. Callers and replicas only agree on owners once they've
+//! seen the same claims, so a replica that gets a call for a shard it
+//! doesn't claim forwards it to the replica it thinks owns it. Calls are
+//! only forwarded once, and a forwarded call is handled wherever it lands.
+//! Forwarded calls are made by the component itself, so a sharded component
+//! with an allowlist must allow its own label. Moving a shard's data is up to
+//! the component, e.g. by loading it from shared storage when [`owned`]
+//! changes.
+//!
+//! Keys and shards are hashed with 64-bit FNV-1a rather than std's hasher,
+//! whose output may change between Rust releases, so that revisions built
+//! with different toolchains agree on owners during a rollout.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    hash::{Hash, Hasher},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{body::Bytes, http::HeaderMap};
+use fnv::FnvHasher;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    component::{self, ComponentKind, Location, Replica},
+    config::ComponentConfig,
+    metrics,
+    rpc::{PORT, RpcError, RpcResult, http},
+    runtime,
+};
+
+/// How often each replica rechecks which shards it owns.
+pub const CLAIM_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long the shards a replica reported are used before they're fetched
+/// again.
+pub const REPORT_TTL: Duration = Duration::from_secs(2);
+
+/// How long to wait for a replica to report its shards.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The header carrying the shard a call is for.
+const HEADER: &str = "x-amimono-shard";
+
+/// The header marking a call that was forwarded by a replica that didn't own
+/// its shard.
+const FORWARDED_HEADER: &str = "x-amimono-shard-forwarded";
+
+/// The shards this process claims, for each sharded component it runs.
+static CLAIMS: LazyLock<Mutex<HashMap<String, Claims>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The shards other replicas reported, by component and address.
+static REPORTS: LazyLock<Mutex<HashMap<(String, String), Report>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
+tokio::task_local! {
+    /// The shard the calls being made are for, and whether they're being
+    /// forwarded.
+    static OUTGOING: Option<(u32, bool)>;
+}
+
+struct Claims {
+    label: &'static str,
+    /// This process's address, once it's known.
+    addr: Option<String>,
+    shards: BTreeSet<u32>,
+}
+
+struct Report {
+    /// `None` if the replica couldn't be asked.
+    shards: Option<BTreeSet<u32>>,
+    fetched: Instant,
+    refreshing: bool,
+}
+
+/// The shards a replica owns, as served at `/admin/shards/{label}`.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ShardReport {
+    pub(crate) shards: BTreeSet<u32>,
+}
+
+/// The shard a key belongs to in a sharded component, by the FNV-1a hash of
+/// what the key feeds to [`Hash`]. Panics if the component isn't sharded.
+pub fn of<K: Hash>(label: &str, key: K) -> u32 {
+    let Some(sharding) = runtime::config().sharding(label) else {
+        panic!("component {label} is not sharded");
+    };
+    let mut hasher = FnvHasher::default();
+    key.hash(&mut hasher);
+    (hasher.finish() % sharding.shards as u64) as u32
+}
+
+/// Whether this process owns a shard of a component.
+pub fn owns(label: &str, shard: u32) -> bool {
+    let claims = CLAIMS.lock().expect("lock poisoned");
+    claims.get(label).is_some_and(|c| c.shards.contains(&shard))
+}
+
+/// The shards of a component this process owns, which are none if it
+/// doesn't run the component or hasn't discovered itself yet.
+pub fn owned(label: &str) -> BTreeSet<u32> {
+    let claims = CLAIMS.lock().expect("lock poisoned");
+    claims
+        .get(label)
+        .map(|c| c.shards.clone())
+        .unwrap_or_default()
+}
+
+/// The shards this process owns, for the admin endpoint, if it runs the
+/// component and it's sharded.
+pub(crate) fn report(label: &str) -> Option<ShardReport> {
+    let claims = CLAIMS.lock().expect("lock poisoned");
+    claims.get(label).map(|c| ShardReport {
+        shards: c.shards.clone(),
+    })
+}
+
+/// Start claiming shards for the sharded components being launched.
+pub(crate) fn start(to_launch: &[&ComponentConfig]) {
+    for comp in to_launch {
+        let Some(sharding) = runtime::config().sharding(&comp.label) else {
+            continue;
+        };
+        // once per sharded component, for forwarding calls on its behalf
+        let label: &'static str = String::leak(comp.label.clone());
+        let claims = Claims {
+            label,
+            addr: None,
+            shards: BTreeSet::new(),
+        };
+        CLAIMS
+            .lock()
+            .expect("lock poisoned")
+            .insert(label.to_owned(), claims);
+        let shards = sharding.shards;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLAIM_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = claim(label, shards).await {
+                    log::warn!("could not check which shards of {label} are owned here: {e}");
+                }
+            }
+        });
+    }
+}
+
+/// Recheck which shards of a component this process owns.
+async fn claim(label: &'static str, shards: u32) -> crate::error::Result<()> {
+    let myself = runtime::provider().myself(label).await?;
+    let addr = myself.addr::<str>().to_owned();
+    let replicas = runtime::provider().discover_replicas(label).await?;
+    let replicas = annotate(label, replicas).await;
+    let held = owned(label);
+
+    let mut claimed = BTreeSet::new();
+    // a replica that isn't discovered, e.g. because it's not ready yet, gets
+    // no calls, so it shouldn't own anything
+    if replicas.iter().any(|r| r.location.addr::<str>() == addr) {
+        for shard in 0..shards {
+            let Some(hashed) = hashed(label, shard, &replicas) else {
+                continue;
+            };
+            // keep a shard that's moving away until its new owner claims it
+            if hashed.location.addr::<str>() == addr
+                || (held.contains(&shard) && !claims(hashed, shard))
+            {
+                claimed.insert(shard);
+            }
+        }
+    }
+
+    let gained = claimed.difference(&held).count();
+    let released = held.difference(&claimed).count();
+    if gained > 0 || released > 0 {
+        log::info!(
+            "{label} now owns {} shards here, gaining {gained} and releasing {released}",
+            claimed.len()
+        );
+    }
+    metrics::gauge("amimono_shards_owned", &[("component", label)]).set(claimed.len() as f64);
+    let mut all = CLAIMS.lock().expect("lock poisoned");
+    if let Some(c) = all.get_mut(label) {
+        c.addr = Some(addr);
+        c.shards = claimed;
+    }
+    Ok(())
+}
+
+/// Fill in the shards each replica of a sharded component owns. Replicas
+/// that have never reported are asked before returning, while stale reports
+/// are used as they are and refreshed in the background.
+pub(crate) async fn annotate(label: &str, mut replicas: Vec<Replica>) -> Vec<Replica> {
+    if runtime::config().sharding(label).is_none() {
+        return replicas;
+    }
+    let local = {
+        let claims = CLAIMS.lock().expect("lock poisoned");
+        claims
+            .get(label)
+            .and_then(|c| Some((c.addr.clone()?, c.shards.clone())))
+    };
+    let unknown = {
+        let mut reports = REPORTS.lock().expect("lock poisoned");
+        let mut unknown = Vec::new();
+        for r in replicas.iter() {
+            let addr = r.location.addr::<str>();
+            if local.as_ref().is_some_and(|(a, _)| a == addr) {
+                continue;
+            }
+            match reports.get_mut(&(label.to_owned(), addr.to_owned())) {
+                Some(report) if report.fetched.elapsed() >= REPORT_TTL && !report.refreshing => {
+                    report.refreshing = true;
+                    let (label, addr) = (label.to_owned(), addr.to_owned());
+                    tokio::spawn(async move { fetch(&label, &addr).await });
+                }
+                Some(_) => (),
+                None => unknown.push(addr.to_owned()),
+            }
+        }
+        unknown
+    };
+    futures::future::join_all(unknown.iter().map(|addr| fetch(label, addr))).await;
+
+    let reports = REPORTS.lock().expect("lock poisoned");
+    for r in replicas.iter_mut() {
+        let addr = r.location.addr::<str>();
+        r.shards = match &local {
+            Some((a, shards)) if a == addr => Some(shards.clone()),
+            _ => reports
+                .get(&(label.to_owned(), addr.to_owned()))
+                .and_then(|report| report.shards.clone()),
+        };
+    }
+    replicas
+}
+
+/// Ask a replica which shards of a component it owns.
+async fn fetch(label: &str, addr: &str) {
+    let url = format!("http://{addr}:{PORT}/admin/shards/{label}");
+    let res = CLIENT.get(&url).timeout(REPORT_TIMEOUT).send().await;
+    let shards = match res {
+        Ok(resp) if resp.status().is_success() => match resp.json::<ShardReport>().await {
+            Ok(report) => Some(report.shards),
+            Err(_) => None,
+        },
+        _ => None,
+    };
+    if shards.is_none() {
+        log::debug!("could not get the shards of {label} owned by {addr}");
+    }
+    let report = Report {
+        shards,
+        fetched: Instant::now(),
+        refreshing: false,
+    };
+    let mut reports = REPORTS.lock().expect("lock poisoned");
+    reports.insert((label.to_owned(), addr.to_owned()), report);
+}
+
+fn claims(replica: &Replica, shard: u32) -> bool {
+    replica.shards.as_ref().is_some_and(|s| s.contains(&shard))
+}
+
+/// A replica's score for a shard. The replica with the highest score owns
+/// the shard. Unlike affinity, this ignores weights, so that slow start
+/// doesn't move shards around.
+fn score(label: &str, shard: u32, replica: &Replica) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(label.as_bytes());
+    hasher.write(&shard.to_le_bytes());
+    hasher.write(replica.location.addr::<str>().as_bytes());
+    hasher.finish()
+}
+
+/// The replica rendezvous hashing assigns a shard to.
+fn hashed<'r>(label: &str, shard: u32, replicas: &'r [Replica]) -> Option<&'r Replica> {
+    replicas
+        .iter()
+        .filter(|r| r.weight > 0)
+        .max_by_key(|r| score(label, shard, r))
+}
+
+/// The replica that owns a shard: the one hashing picks if it claims the
+/// shard or no replica does, and otherwise whichever claims it, e.g. a
+/// previous owner that's still handing it over.
+pub(crate) fn owner<'r>(label: &str, shard: u32, replicas: &'r [Replica]) -> Option<&'r Replica> {
+    let hashed = hashed(label, shard, replicas)?;
+    if claims(hashed, shard) {
+        return Some(hashed);
+    }
+    replicas
+        .iter()
+        .filter(|r| r.weight > 0 && claims(r, shard))
+        .max_by_key(|r| score(label, shard, r))
+        .or(Some(hashed))
+}
+
+/// Pick the replica of a component to send a call for a shard to.
+pub(crate) async fn discover<R: ComponentKind>(shard: u32, unready: bool) -> RpcResult<Location> {
+    let replicas = http::balanced_replicas::<R>(unready).await?;
+    match owner(R::LABEL, shard, &replicas) {
+        Some(r) => Ok(r.location.clone()),
+        None => Err(RpcError::Misc("discovery endpoints empty".to_owned())),
+    }
+}
+
+/// Make calls for a shard, if there is one, so that they carry it.
+pub(crate) async fn outgoing<F: Future>(shard: Option<u32>, fut: F) -> F::Output {
+    OUTGOING.scope(shard.map(|s| (s, false)), fut).await
+}
+
+/// Add the shard the current call is for to its headers.
+pub(crate) fn with_headers(mut req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    if let Ok(Some((shard, forwarded))) = OUTGOING.try_with(|o| *o) {
+        req = req.header(HEADER, shard);
+        if forwarded {
+            req = req.header(FORWARDED_HEADER, "1");
+        }
+    }
+    req
+}
+
+/// Forward a call for a shard this process doesn't own to the replica that
+/// does, returning its response, or `None` if the call should be handled
+/// here.
+pub(crate) async fn forward(
+    label: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Option<RpcResult<Bytes>> {
+    if headers.contains_key(FORWARDED_HEADER) {
+        return None;
+    }
+    let shard = headers.get(HEADER)?.to_str().ok()?.parse::<u32>().ok()?;
+    let (label, addr) = {
+        let claims = CLAIMS.lock().expect("lock poisoned");
+        let c = claims.get(label)?;
+        if c.shards.contains(&shard) {
+            return None;
+        }
+        (c.label, c.addr.clone()?)
+    };
+    let replicas = runtime::provider().discover_replicas(label).await.ok()?;
+    let replicas = annotate(label, replicas).await;
+    let to = owner(label, shard, &replicas)?
+        .location
+        .addr::<str>()
+        .to_owned();
+    if to == addr {
+        return None;
+    }
+    log::debug!("forwarding call for shard {shard} of {label} to {to}");
+    metrics::counter("amimono_shard_forwarded", &[("component", label)]).inc();
+    let path = format!("/rpc/{label}");
+    let call = http::post_bytes(label, &to, &path, Bytes::copy_from_slice(body), None);
+    let call = OUTGOING.scope(Some((shard, true)), call);
+    Some(component::scope(label, call).await)
+}
@@ -0,0 +1,367 @@
+//! Warm standby replicas for stateful components.
+//!
+//! A stateful component that keeps a lot in memory, like an index built
+//! from its storage, can take a long time to recover when a replica fails
+//! and its replacement starts from scratch. Jobs configured with
+//! [`JobBuilder::with_standby`][crate::config::JobBuilder::with_standby] run
+//! standby replicas alongside the serving ones, which keep copies of the
+//! serving replicas' state and take over from them when they fail:
+//!
+//! ```ignore
+//! // in the app's config
+//! JobBuilder::new()
+//!     .with_label("index")
+//!     .with_replicas(2)
+//!     .with_standby(1)
+//!     .install(index::Component::installer)
+//!
+//! // in the component's startup, once it has its state
+//! amimono::standby::replicate(index.clone());
+//! ```
+//!
+//! The component provides the replication hook by implementing
+//! [`Replicated`] for its state. Every [`INTERVAL`][Replicated::INTERVAL],
+//! each standby asks every serving replica for a
+//! [snapshot][Replicated::snapshot] of its state, over the same transport
+//! as RPC calls, and keeps the latest one. Standbys run the component as
+//! usual, but don't receive any traffic.
+//!
+//! Which replicas serve is decided from discovery, by every replica and
+//! caller alike: replicas are ordered by name, or by address in runtimes that
+//! don't name them, and the first ones serve, as many as the job's replicas.
+//! When a serving replica fails and is no longer discovered, the first
+//! standby moves up into its place, and [restores][Replicated::restore] the
+//! snapshot it has of the failed replica. When the failed replica comes
+//! back, it takes its place back, restoring the freshest state it can get
+//! from the standbys, including the one that took over from it, which goes
+//! back to being a standby.
+//!
+//! Callers and replicas only agree on which replicas serve once they've all
+//! seen the same replicas, so around a failure, a replica can briefly
+//! receive traffic before it has restored its state, or after it's gone
+//! back to being a standby. A standby's copy of the state is as old as its
+//! last snapshot, so changes since then are lost on promotion unless the
+//! component also persists them.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppError, AppResult, audit,
+    component::{ComponentKind, Replica},
+    metrics,
+    rpc::http,
+    runtime,
+    tasks::TaskSet,
+    util::StaticHashMap,
+};
+
+/// State of a component that's replicated to its job's standbys.
+pub trait Replicated: Send + Sync + 'static {
+    /// The component whose state this is.
+    type Host: ComponentKind;
+
+    /// How often standbys take a new snapshot of the serving replicas.
+    const INTERVAL: Duration = Duration::from_secs(5);
+
+    /// Take a snapshot of the state, serialized however the component likes.
+    /// Called on serving replicas.
+    fn snapshot(&self) -> impl Future<Output = AppResult<Vec<u8>>> + Send;
+
+    /// Replace the state with a snapshot taken by another replica. Called
+    /// when a standby is promoted, and when a serving replica comes back.
+    fn restore(&self, snapshot: Vec<u8>) -> impl Future<Output = AppResult<()>> + Send;
+}
+
+/// A snapshot kept by a standby.
+struct Held {
+    taken: Instant,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Role {
+    /// Whether this replica was serving when its role was last checked, or
+    /// `None` before the first check.
+    serving: Option<bool>,
+    /// The serving replicas when the role was last checked.
+    serving_replicas: Vec<String>,
+    /// The latest snapshots of the serving replicas, by name.
+    held: HashMap<String, Held>,
+    /// The replica this one took over from, if it did, until the replica
+    /// has its state back.
+    covering: Option<String>,
+}
+
+/// The replicated state of a component in this process.
+struct Follower<R> {
+    state: Arc<R>,
+    role: Mutex<Role>,
+}
+
+/// A request for the state a standby has of the replica `of`.
+#[derive(Serialize, Deserialize)]
+struct HeldRequest {
+    of: String,
+}
+
+/// The state a standby has of a replica, and how old it is.
+#[derive(Serialize, Deserialize)]
+struct HeldState {
+    age_ms: u64,
+    data: Vec<u8>,
+}
+
+trait Source: Send + Sync {
+    fn snapshot(&self) -> BoxFuture<'_, AppResult<Vec<u8>>>;
+
+    fn held_json<'h>(&'h self, body: &[u8]) -> BoxFuture<'h, AppResult<Vec<u8>>>;
+}
+
+static SOURCES: StaticHashMap<&'static str, dyn Source> = StaticHashMap::new();
+
+/// Replicate a component's state to its job's standbys, and restore it when
+/// this replica takes over from another. This should be called once by the
+/// `Host` component while it's starting, and does nothing if its job doesn't
+/// have standbys.
+pub fn replicate<R: Replicated>(state: Arc<R>) {
+    let label = R::Host::LABEL;
+    if serving_count(label).is_none() || SOURCES.get(label).is_some() {
+        return;
+    }
+    let follower = Arc::new(Follower {
+        state,
+        role: Mutex::new(Role::default()),
+    });
+    SOURCES.insert(label, follower.clone());
+    TaskSet::of(label).spawn("standby", async move {
+        loop {
+            if let Err(e) = follower.check().await {
+                log::warn!("{label}: could not replicate state: {e}");
+            }
+            tokio::time::sleep(R::INTERVAL).await;
+        }
+    });
+}
+
+/// How many replicas of a component serve, if its job has standbys.
+fn serving_count(label: &str) -> Option<usize> {
+    let cf = runtime::config();
+    let job = cf.component_job(label).and_then(|j| cf.job(j))?;
+    match job.standby() {
+        0 => None,
+        n => Some((job.replicas() - n) as usize),
+    }
+}
+
+fn name(r: &Replica) -> String {
+    r.name
+        .clone()
+        .unwrap_or_else(|| r.location.addr::<str>().to_owned())
+}
+
+/// Order replicas by which serve first: draining replicas last, and the
+/// rest by name.
+fn rank(replicas: &mut [Replica]) {
+    replicas.sort_by_cached_key(|r| (r.draining, name(r)));
+}
+
+/// Leave a component's standbys out of its replicas, so that they don't
+/// receive traffic. Replicas of jobs without standbys all serve.
+pub(crate) fn serving(label: &str, mut replicas: Vec<Replica>) -> Vec<Replica> {
+    let Some(n) = serving_count(label) else {
+        return replicas;
+    };
+    rank(&mut replicas);
+    replicas.truncate(n);
+    replicas
+}
+
+impl<R: Replicated> Follower<R> {
+    /// Check this replica's role, taking over state if it changed, and pull
+    /// snapshots from the serving replicas if it's a standby.
+    async fn check(&self) -> AppResult<()> {
+        let label = R::Host::LABEL;
+        let mut replicas = runtime::provider().discover_replicas(label).await?;
+        let myself = R::Host::myself().await?;
+        let Some(me) = replicas
+            .iter()
+            .find(|r| r.location.addr::<str>() == myself.addr::<str>())
+        else {
+            // not discovered yet, or being replaced
+            return Ok(());
+        };
+        let me = name(me);
+        rank(&mut replicas);
+        let n = serving_count(label).unwrap_or(replicas.len());
+        let (serving, standby) = replicas.split_at(n.min(replicas.len()));
+        let serving_names = serving.iter().map(name).collect::<Vec<_>>();
+        let is_serving = serving_names.contains(&me);
+
+        let (was_serving, before) = {
+            let role = self.role.lock().expect("lock poisoned");
+            (role.serving, role.serving_replicas.clone())
+        };
+        match (was_serving, is_serving) {
+            (None, true) => self.catch_up(&me, standby).await,
+            (Some(false), true) => self.promote(&me, &before, &serving_names).await,
+            (Some(true), false) => {
+                log::info!("{label}: {me} is a standby again");
+                self.role.lock().expect("lock poisoned").held.clear();
+            }
+            _ => (),
+        }
+        {
+            let mut role = self.role.lock().expect("lock poisoned");
+            role.serving = Some(is_serving);
+            role.serving_replicas = serving_names;
+        }
+        if !is_serving {
+            self.pull(serving).await;
+        }
+        Ok(())
+    }
+
+    /// Take a snapshot of each serving replica.
+    async fn pull(&self, serving: &[Replica]) {
+        let label = R::Host::LABEL;
+        let path = format!("/standby/{label}/snapshot");
+        let pulled = futures::future::join_all(serving.iter().map(async |r| {
+            let res = http::post_bytes(label, r.location.addr(), &path, Vec::new(), None).await;
+            (name(r), res)
+        }))
+        .await;
+
+        let mut role = self.role.lock().expect("lock poisoned");
+        role.held.retain(|k, _| pulled.iter().any(|(n, _)| n == k));
+        for (from, res) in pulled {
+            match res {
+                Ok(data) => {
+                    if role.covering.as_ref() == Some(&from) {
+                        role.covering = None;
+                    }
+                    let held = Held {
+                        taken: Instant::now(),
+                        data: data.into(),
+                    };
+                    role.held.insert(from, held);
+                }
+                Err(e) => log::warn!("{label}: could not take a snapshot of {from}: {e}"),
+            }
+        }
+    }
+
+    /// Take over from a serving replica that failed, restoring the snapshot
+    /// of it.
+    async fn promote(&self, me: &str, before: &[String], after: &[String]) {
+        let label = R::Host::LABEL;
+        // replicas moving up take over from the replicas that are gone in
+        // order, so that each failed replica's state is restored once
+        let failed = before.iter().filter(|r| !after.contains(r));
+        let promoted = after.iter().filter(|r| !before.contains(r));
+        let Some((from, _)) = failed.zip(promoted).find(|(_, p)| *p == me) else {
+            log::info!("{label}: {me} is serving, with no state to take over");
+            return;
+        };
+        let held = self.role.lock().expect("lock poisoned").held.remove(from);
+        let Some(held) = held else {
+            log::warn!("{label}: {me} is taking over from {from}, but has no snapshot of it");
+            return;
+        };
+
+        log::info!(
+            "{label}: {me} is taking over from {from}, with a snapshot from {:?} ago",
+            held.taken.elapsed()
+        );
+        metrics::counter("amimono_standby_promotions", &[("component", label)]).inc();
+        audit::event("standby.promote")
+            .with("component", label)
+            .with("replica", me)
+            .with("from", from)
+            .record();
+        match self.state.restore(held.data).await {
+            Ok(()) => self.role.lock().expect("lock poisoned").covering = Some(from.clone()),
+            Err(e) => log::error!("{label}: could not restore the state of {from}: {e}"),
+        }
+    }
+
+    /// Restore the freshest state the standbys have of this replica, when
+    /// it starts serving, in case it's coming back after failing.
+    async fn catch_up(&self, me: &str, standby: &[Replica]) {
+        let label = R::Host::LABEL;
+        let path = format!("/standby/{label}/held");
+        let req = HeldRequest { of: me.to_owned() };
+        let states = futures::future::join_all(standby.iter().map(async |r| {
+            http::post_json::<_, Option<HeldState>>(label, r.location.addr(), &path, &req, None)
+                .await
+                .inspect_err(|e| log::warn!("{label}: could not ask {} for state: {e}", name(r)))
+                .ok()
+                .flatten()
+        }))
+        .await;
+        let Some(freshest) = states.into_iter().flatten().min_by_key(|s| s.age_ms) else {
+            return;
+        };
+        log::info!(
+            "{label}: {me} is restoring its state from {}ms ago",
+            freshest.age_ms
+        );
+        if let Err(e) = self.state.restore(freshest.data).await {
+            log::error!("{label}: could not restore state: {e}");
+        }
+    }
+}
+
+impl<R: Replicated> Source for Follower<R> {
+    fn snapshot(&self) -> BoxFuture<'_, AppResult<Vec<u8>>> {
+        Box::pin(self.state.snapshot())
+    }
+
+    fn held_json<'h>(&'h self, body: &[u8]) -> BoxFuture<'h, AppResult<Vec<u8>>> {
+        let req = serde_json::from_slice::<HeldRequest>(body);
+        Box::pin(async move {
+            let req =
+                req.map_err(|e| AppError::misc(format!("standby request parse error: {e}")))?;
+            let covering = {
+                let mut role = self.role.lock().expect("lock poisoned");
+                if role.covering.as_ref() != Some(&req.of) {
+                    let held = role.held.get(&req.of).map(|h| HeldState {
+                        age_ms: h.taken.elapsed().as_millis() as u64,
+                        data: h.data.clone(),
+                    });
+                    return Ok(serde_json::to_vec(&held)?);
+                }
+                role.covering.take()
+            };
+            // this replica took over from the one asking, so its own state
+            // is the freshest
+            log::info!("{}: handing state back to {:?}", R::Host::LABEL, covering);
+            let held = HeldState {
+                age_ms: 0,
+                data: self.state.snapshot().await?,
+            };
+            Ok(serde_json::to_vec(&Some(held))?)
+        })
+    }
+}
+
+/// Handle a request sent to this process over HTTP: a `snapshot` of a
+/// serving replica's state, or the state a standby `held` of a replica.
+pub(crate) async fn handle(label: &str, mode: &str, body: &[u8]) -> AppResult<Vec<u8>> {
+    let Some(source) = SOURCES.get(label) else {
+        return Err(AppError::misc(format!(
+            "{label} is not replicated to standbys here"
+        )));
+    };
+    match mode {
+        "snapshot" => source.snapshot().await,
+        "held" => source.held_json(body).await,
+        _ => Err(AppError::misc(format!("unknown standby request {mode}"))),
+    }
+}
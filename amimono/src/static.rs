@@ -1,64 +1,428 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    component::Location,
+    component::{Location, Replica},
+    config::JobConfig,
     error::{Error, Result},
+    rpc::PORT,
     runtime::{self, RuntimeProvider},
 };
 
+/// The static runtime's `amimono.toml`. Each job lists where it's placed,
+/// either as a flat list of addresses or as named replicas with metadata:
+///
+/// ```toml
+/// [job.calc]
+/// locations = ["10.0.0.1"]
+///
+/// [job.driver.replica.driver-a]
+/// addr = "10.0.0.2"
+/// zone = "east"
+///
+/// [job.driver.replica.driver-b]
+/// addr = "10.0.0.3"
+/// zone = "west"
+/// weight = 2
+/// draining = true
+/// ports = { rpc = 9099 }
+/// ```
+///
+/// Components can be given locations of their own, in the same forms, for
+/// when one component of a job is reached at a different address, such as
+/// through a proxy. Components without their own entry are found at their
+/// job's locations:
+///
+/// ```toml
+/// [component.calc-api]
+/// locations = ["10.0.0.9"]
+/// ```
+///
+/// A job's replicas are numbered in the order they're listed, plain
+/// locations first, which decides which replicas run components that only
+/// run on some of them.
+///
+/// Either can name a `health` path, which each replica is probed at on the
+/// RPC port. Replicas whose probe fails are left out of discovery until it
+/// passes again:
+///
+/// ```toml
+/// [job.calc]
+/// locations = ["10.0.0.1", "10.0.0.4"]
+/// health = "/ready"
+/// ```
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct StaticConfig {
     job: HashMap<String, StaticJobConfig>,
+    #[serde(default)]
+    component: HashMap<String, StaticJobConfig>,
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct StaticJobConfig {
+    #[serde(default)]
     locations: Vec<String>,
+    #[serde(default)]
+    replica: BTreeMap<String, StaticReplicaConfig>,
+    health: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StaticReplicaConfig {
+    addr: String,
+    zone: Option<String>,
+    #[serde(default = "default_weight")]
+    weight: u32,
+    #[serde(default)]
+    draining: bool,
+    #[serde(default)]
+    ports: BTreeMap<String, u16>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl StaticConfig {
+    /// Checks the config against the app, to catch mistakes that the format
+    /// alone can't express.
+    fn validate(&self) -> std::result::Result<(), String> {
+        let cf = runtime::config();
+        for (label, job) in self.job.iter() {
+            if cf.job(label).is_none() {
+                let known = cf.jobs().map(|j| j.label()).collect::<Vec<_>>().join(", ");
+                return Err(format!("unknown job {label:?} (known jobs: {known})"));
+            }
+            job.validate(&format!("job {label:?}"))?;
+        }
+        for (label, comp) in self.component.iter() {
+            if cf.component(label).is_none() {
+                let known = cf
+                    .jobs()
+                    .flat_map(|j| j.components())
+                    .map(|c| c.label.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                return Err(format!(
+                    "unknown component {label:?} (known components: {known})"
+                ));
+            }
+            comp.validate(&format!("component {label:?}"))?;
+        }
+        Ok(())
+    }
+}
+
+impl StaticJobConfig {
+    fn validate(&self, what: &str) -> std::result::Result<(), String> {
+        if self.locations.is_empty() && self.replica.is_empty() {
+            return Err(format!("{what} has no locations or replicas"));
+        }
+        let mut seen = HashMap::new();
+        let addrs = self
+            .locations
+            .iter()
+            .map(|addr| ("locations", addr))
+            .chain(self.replica.iter().map(|(n, r)| (n.as_str(), &r.addr)));
+        for (name, addr) in addrs {
+            if addr.is_empty() {
+                return Err(format!("{what} replica {name:?} has an empty addr"));
+            }
+            if let Some(other) = seen.insert(addr, name) {
+                return Err(format!(
+                    "{what} lists {addr:?} more than once ({other:?} and {name:?})"
+                ));
+            }
+        }
+        if let Some(path) = self.health.as_ref().filter(|p| !p.starts_with('/')) {
+            return Err(format!("{what} health path {path:?} must start with /"));
+        }
+        for (name, replica) in self.replica.iter() {
+            if replica.weight == 0 && !replica.draining {
+                return Err(format!(
+                    "{what} replica {name:?} has weight 0, use draining = true instead"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn replicas(&self) -> Vec<Replica> {
+        let plain = self
+            .locations
+            .iter()
+            .map(|addr| Replica::at(Location::stable(addr.clone())));
+        let named = self.replica.iter().map(|(name, r)| Replica {
+            location: Location::stable(r.addr.clone()),
+            name: Some(name.clone()),
+            zone: r.zone.clone(),
+            weight: r.weight,
+            draining: r.draining,
+            ready: true,
+            ports: r.ports.clone(),
+            shards: None,
+        });
+        plain.chain(named).collect()
+    }
+}
+
+/// How long a passing probe is trusted before the replica is probed again.
+const PROBE_TTL: Duration = Duration::from_secs(5);
+
+/// How long a failing replica waits to be probed again. This doubles with
+/// each failure in a row, up to `MAX_PROBE_BACKOFF`, so that replicas that
+/// are down for good aren't probed on every discovery.
+const PROBE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_PROBE_BACKOFF: Duration = Duration::from_secs(30);
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The cached results of probing replicas' health paths, by URL.
+#[derive(Default)]
+struct Probes {
+    client: reqwest::Client,
+    results: Mutex<HashMap<String, Probe>>,
+}
+
+struct Probe {
+    failures: u32,
+    checked: Instant,
+    /// Whether the probe is being refreshed in the background.
+    refreshing: bool,
+}
+
+impl Probe {
+    fn is_ready(&self) -> bool {
+        self.failures == 0
+    }
+
+    fn is_stale(&self) -> bool {
+        let ttl = match self.failures {
+            0 => PROBE_TTL,
+            n => PROBE_BACKOFF
+                .saturating_mul(1 << (n - 1).min(16))
+                .min(MAX_PROBE_BACKOFF),
+        };
+        self.checked.elapsed() >= ttl
+    }
+}
+
+impl Probes {
+    /// Whether each URL's probe passes. URLs that have never been probed
+    /// are probed before returning, while stale results are used as they
+    /// are and refreshed in the background, so that discovery only waits
+    /// on replicas it knows nothing about.
+    async fn check(self: &Arc<Self>, urls: &[String]) -> Vec<bool> {
+        let unknown = {
+            let mut results = self.results.lock().expect("lock poisoned");
+            let mut unknown = Vec::new();
+            for url in urls {
+                match results.get_mut(url) {
+                    Some(probe) if probe.is_stale() && !probe.refreshing => {
+                        probe.refreshing = true;
+                        let this = self.clone();
+                        let url = url.clone();
+                        tokio::spawn(async move { this.probe(&url).await });
+                    }
+                    Some(_) => (),
+                    None => unknown.push(url),
+                }
+            }
+            unknown
+        };
+        futures::future::join_all(unknown.into_iter().map(|url| self.probe(url))).await;
+
+        let results = self.results.lock().expect("lock poisoned");
+        urls.iter()
+            .map(|url| results.get(url).is_some_and(|p| p.is_ready()))
+            .collect()
+    }
+
+    async fn probe(&self, url: &str) {
+        let ready = match self.client.get(url).timeout(PROBE_TIMEOUT).send().await {
+            Ok(resp) => resp.status().is_success(),
+            Err(_) => false,
+        };
+        let mut results = self.results.lock().expect("lock poisoned");
+        let prev = results.get(url).map(|p| p.failures);
+        let failures = match (ready, prev) {
+            (true, Some(n)) if n > 0 => {
+                log::info!("health probe of {url} passed, replica is ready again");
+                0
+            }
+            (true, _) => 0,
+            (false, None | Some(0)) => {
+                log::warn!("health probe of {url} failed, replica is not ready");
+                1
+            }
+            (false, Some(n)) => n + 1,
+        };
+        let probe = Probe {
+            failures,
+            checked: Instant::now(),
+            refreshing: false,
+        };
+        results.insert(url.to_owned(), probe);
+    }
 }
 
 pub struct StaticRuntime {
     root: PathBuf,
     myself: Location,
+    probes: Arc<Probes>,
 }
 
 impl StaticRuntime {
     pub fn open(root: PathBuf, myself: Location) -> StaticRuntime {
-        StaticRuntime { root, myself }
+        StaticRuntime {
+            root,
+            myself,
+            probes: Arc::new(Probes::default()),
+        }
     }
 
     async fn config(&self) -> Result<StaticConfig> {
         let config_path = self.root.join("amimono.toml");
-        let config = tokio::fs::read(&config_path)
+        let config = tokio::fs::read_to_string(&config_path)
             .await
-            .map_err(|_| "could not read config")?;
-        toml::from_slice(&config[..]).map_err(|_| Error::from("could not parse config"))
+            .map_err(|e| format!("could not read {}: {e}", config_path.display()))?;
+        let config: StaticConfig = toml::from_str(&config)
+            .map_err(|e| format!("could not parse {}: {e}", config_path.display()))?;
+        config
+            .validate()
+            .map_err(|e| Error::User(format!("invalid {}: {e}", config_path.display())))?;
+        Ok(config)
     }
 
-    async fn discover_inner(&self, component: &str) -> Result<Vec<Location>> {
+    /// Where a component is placed, and its job if it's placed at the job's
+    /// replicas rather than at locations of its own.
+    fn placed<'c>(
+        config: &'c StaticConfig,
+        component: &str,
+    ) -> Result<(&'c StaticJobConfig, Option<&'static JobConfig>)> {
         let job = runtime::config()
             .component_job(component)
             .ok_or("component has no job")?;
+        match config.component.get(component) {
+            Some(overridden) => Ok((overridden, None)),
+            None => {
+                let placed = config.job.get(job).ok_or("static config missing job")?;
+                Ok((placed, runtime::config().job(job)))
+            }
+        }
+    }
+
+    async fn replicas_inner(&self, component: &str) -> Result<Vec<Replica>> {
+        let config = self.config().await?;
+        let (placed, job_cf) = Self::placed(&config, component)?;
+        let mut replicas = placed.replicas();
+        if let Some(job_cf) = job_cf {
+            replicas = replicas
+                .into_iter()
+                .enumerate()
+                .filter(|(n, _)| job_cf.runs_on(component, *n))
+                .map(|(_, r)| r)
+                .collect();
+        }
+        if let Some(path) = &placed.health {
+            let urls = replicas
+                .iter()
+                .map(|r| format!("http://{}:{}{}", r.location.addr::<str>(), PORT, path))
+                .collect::<Vec<_>>();
+            let ready = self.probes.check(&urls).await;
+            for (r, ready) in replicas.iter_mut().zip(ready) {
+                r.ready = ready;
+            }
+        }
+        Ok(replicas)
+    }
+
+    async fn discover_running_inner(&self, component: &str) -> Result<Vec<Location>> {
         let res = self
-            .config()
+            .replicas_inner(component)
             .await?
-            .job
-            .get(job)
-            .ok_or("static config missing job")?
-            .locations
-            .iter()
-            .cloned()
-            .map(|x| Location::stable(x))
+            .into_iter()
+            .filter(|r| r.ready && !r.draining)
+            .map(|r| r.location)
             .collect();
         Ok(res)
     }
 
+    async fn discover_stable_inner(&self, component: &str) -> Result<Vec<Location>> {
+        let res = self
+            .replicas_inner(component)
+            .await?
+            .into_iter()
+            .map(|r| r.location)
+            .collect();
+        Ok(res)
+    }
+
+    async fn discover_replicas_inner(&self, component: &str) -> Result<Vec<Replica>> {
+        let res = self
+            .replicas_inner(component)
+            .await?
+            .into_iter()
+            .filter(|r| r.ready && !r.draining)
+            .collect();
+        Ok(res)
+    }
+
+    async fn discover_all_replicas_inner(&self, component: &str) -> Result<Vec<Replica>> {
+        let res = self
+            .replicas_inner(component)
+            .await?
+            .into_iter()
+            .filter(|r| !r.draining)
+            .collect();
+        Ok(res)
+    }
+
+    /// The `n`th of a component's locations, counting the job's replicas
+    /// that don't run it, so that ordinals match the job's.
+    async fn ordinal_inner(&self, component: &str, n: usize) -> Result<Location> {
+        let config = self.config().await?;
+        let (placed, job_cf) = Self::placed(&config, component)?;
+        let replicas = placed.replicas();
+        let count = replicas.len();
+        let r = replicas
+            .into_iter()
+            .nth(n)
+            .ok_or_else(|| format!("no replica {n} of {component}, only {count}"))?;
+        if let Some(job_cf) = job_cf.filter(|j| !j.runs_on(component, n)) {
+            Err(format!(
+                "{component} does not run on replica {n} of {}",
+                job_cf.label()
+            ))?;
+        }
+        Ok(r.location)
+    }
+
     async fn myself_inner(&self, _component: &str) -> Result<Location> {
         Ok(self.myself.clone())
     }
 
+    async fn my_ordinal_inner(&self, job: &str) -> Result<usize> {
+        let config = self.config().await?;
+        let placed = config.job.get(job).ok_or("static config missing job")?;
+        let myself: &str = self.myself.addr();
+        placed
+            .replicas()
+            .iter()
+            .position(|r| r.location.addr::<str>() == myself)
+            .ok_or_else(|| format!("{myself} is not one of the replicas of {job}").into())
+    }
+
     async fn storage_inner(&self, component: &str) -> Result<PathBuf> {
         let myself: &str = self.myself.addr();
         let dir = self.root.join("storage").join(myself).join(component);
@@ -74,21 +438,59 @@ impl RuntimeProvider for StaticRuntime {
         &'p self,
         component: &'l str,
     ) -> BoxFuture<'f, Result<Vec<Location>>> {
-        Box::pin(self.discover_inner(component))
+        Box::pin(self.discover_running_inner(component))
     }
 
     fn discover_stable<'f, 'p: 'f, 'l: 'f>(
         &'p self,
         component: &'l str,
     ) -> BoxFuture<'f, Result<Vec<Location>>> {
-        Box::pin(self.discover_inner(component))
+        Box::pin(self.discover_stable_inner(component))
+    }
+
+    fn discover_replicas<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+    ) -> BoxFuture<'f, Result<Vec<Replica>>> {
+        Box::pin(self.discover_replicas_inner(component))
+    }
+
+    fn discover_all_replicas<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+    ) -> BoxFuture<'f, Result<Vec<Replica>>> {
+        Box::pin(self.discover_all_replicas_inner(component))
+    }
+
+    fn ordinal<'f, 'p: 'f, 'l: 'f>(
+        &'p self,
+        component: &'l str,
+        n: usize,
+    ) -> BoxFuture<'f, Result<Location>> {
+        Box::pin(self.ordinal_inner(component, n))
     }
 
     fn myself<'f, 'p: 'f, 'l: 'f>(&'p self, component: &'l str) -> BoxFuture<'f, Result<Location>> {
         Box::pin(self.myself_inner(component))
     }
 
+    fn my_ordinal<'f, 'p: 'f, 'l: 'f>(&'p self, job: &'l str) -> BoxFuture<'f, Result<usize>> {
+        Box::pin(self.my_ordinal_inner(job))
+    }
+
     fn storage<'f, 'p: 'f, 'l: 'f>(&'p self, component: &'l str) -> BoxFuture<'f, Result<PathBuf>> {
         Box::pin(self.storage_inner(component))
     }
+
+    fn capabilities(&self) -> runtime::Capabilities {
+        runtime::Capabilities {
+            storage: true,
+            myself: true,
+            stable_discovery: true,
+        }
+    }
+
+    fn serves_resolver(&self) -> bool {
+        true
+    }
 }
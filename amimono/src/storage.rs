@@ -0,0 +1,241 @@
+//! Storage accounting for stateful components.
+//!
+//! The runtime periodically measures the size of each local stateful
+//! component's storage directory, reports it as a metric, and compares it
+//! against the size requested with `ComponentKind::STORAGE`. When running
+//! locally, it also removes files past the component's retention limits, if
+//! it has any.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+
+use crate::{
+    config::{ComponentConfig, RetentionConfig},
+    error::{Error, Result},
+    health, metrics, runtime,
+};
+
+const INTERVAL: Duration = Duration::from_secs(30);
+
+/// The fraction of the quota at which a warning is logged.
+const WARN_THRESHOLD: f64 = 0.8;
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StorageUsage {
+    used: u64,
+    quota: Option<u64>,
+    hard_limit: bool,
+}
+
+impl StorageUsage {
+    fn fraction(&self) -> Option<f64> {
+        self.quota.map(|q| self.used as f64 / q as f64)
+    }
+
+    fn exceeded(&self) -> bool {
+        self.fraction().map(|f| f > 1.0).unwrap_or(false)
+    }
+}
+
+static USAGE: LazyLock<Mutex<BTreeMap<String, StorageUsage>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// The most recently measured usage of every local stateful component.
+pub(crate) fn usage() -> BTreeMap<String, StorageUsage> {
+    USAGE.lock().expect("lock poisoned").clone()
+}
+
+/// Fails if the component has a hard storage limit that it has exceeded.
+pub(crate) fn check_quota(label: &str) -> Result<()> {
+    match USAGE.lock().expect("lock poisoned").get(label) {
+        Some(u) if u.hard_limit && u.exceeded() => Err(Error::User(format!(
+            "{label} storage quota exceeded: {} of {} bytes",
+            u.used,
+            u.quota.unwrap_or(0)
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Start measuring storage for the stateful components in the list.
+pub(crate) fn start_accounting(comps: &[&ComponentConfig]) {
+    for comp in comps.iter().filter(|c| c.is_stateful) {
+        let label = runtime::config()
+            .component(&comp.label)
+            .map(|c| c.label.as_str())
+            .unwrap_or_else(|| panic!("component {} not in config", comp.label));
+        let quota = comp.storage.filter(|&n| n > 0).map(|n| n as u64);
+        let hard_limit = comp.storage_hard_limit;
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(INTERVAL).await;
+                if let Err(e) = gc(label).await {
+                    log::warn!("failed to apply storage retention for {label}: {e}");
+                }
+                if let Err(e) = measure(label, quota, hard_limit).await {
+                    log::warn!("failed to measure storage for {label}: {e}");
+                }
+            }
+        });
+    }
+}
+
+async fn measure(label: &'static str, quota: Option<u64>, hard_limit: bool) -> Result<()> {
+    let dir = runtime::provider().storage(label).await?;
+    let used = tokio::task::spawn_blocking(move || dir_size(&dir))
+        .await
+        .map_err(|e| format!("storage measurement failed: {e}"))?
+        .map_err(|e| format!("storage measurement failed: {e}"))?;
+
+    metrics::gauge("amimono_storage_used_bytes", &[("component", label)]).set(used as f64);
+    if let Some(q) = quota {
+        metrics::gauge("amimono_storage_quota_bytes", &[("component", label)]).set(q as f64);
+    }
+
+    let usage = StorageUsage {
+        used,
+        quota,
+        hard_limit,
+    };
+    let prev = USAGE
+        .lock()
+        .expect("lock poisoned")
+        .insert(label.to_owned(), usage.clone());
+    let prev_fraction = prev.and_then(|p| p.fraction()).unwrap_or(0.0);
+
+    if let Some(f) = usage.fraction() {
+        let q = quota.unwrap_or(0);
+        if f > 1.0 && prev_fraction <= 1.0 {
+            log::warn!("{label} exceeded its storage quota: {used} of {q} bytes");
+            if hard_limit {
+                health::set_for(label, health::Status::unhealthy("storage quota exceeded"));
+            }
+        } else if f > WARN_THRESHOLD && prev_fraction <= WARN_THRESHOLD {
+            log::warn!("{label} is using {:.0}% of its storage quota", f * 100.0);
+        } else if f <= 1.0 && prev_fraction > 1.0 {
+            log::info!("{label} is back within its storage quota");
+            if hard_limit {
+                health::set_for(label, health::Status::Healthy);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the files in a component's storage that are past its local
+/// retention limits, returning how many bytes were freed.
+pub(crate) async fn gc(label: &'static str) -> Result<u64> {
+    if !runtime::provider().is_dev() {
+        return Ok(0);
+    }
+    let Some(retention) = runtime::config().local_retention(label).cloned() else {
+        return Ok(0);
+    };
+    let dir = runtime::provider().storage(label).await?;
+    let (files, freed) = tokio::task::spawn_blocking(move || prune(&dir, &retention))
+        .await
+        .map_err(|e| format!("storage retention failed: {e}"))?
+        .map_err(|e| format!("storage retention failed: {e}"))?;
+    if files > 0 {
+        log::info!("{label}: removed {files} files ({freed} bytes) past its retention limits");
+        metrics::counter("amimono_storage_gc_bytes", &[("component", label)]).add(freed);
+    }
+    Ok(freed)
+}
+
+/// Files whose names start with this are the runtime's own, such as the
+/// storage schema version, and are never removed by retention.
+const RESERVED_PREFIX: &str = ".amimono";
+
+/// Remove files older than the max age, and then the least recently
+/// modified files until the rest fit in the max size. Returns how many files
+/// were removed, and their total size.
+fn prune(dir: &Path, retention: &RetentionConfig) -> std::io::Result<(usize, u64)> {
+    let mut files = Vec::new();
+    list_files(dir, &mut files)?;
+    // oldest first
+    files.sort_by_key(|(_, _, modified)| *modified);
+
+    let now = SystemTime::now();
+    let mut kept = files.iter().map(|(_, size, _)| size).sum::<u64>();
+    let (mut removed, mut freed) = (0, 0);
+    for (path, size, modified) in files.iter() {
+        let expired = retention
+            .max_age
+            .is_some_and(|age| now.duration_since(*modified).unwrap_or_default() > age);
+        let over = retention.max_size.is_some_and(|max| kept > max);
+        if !expired && !over {
+            // everything after this is newer
+            break;
+        }
+        match std::fs::remove_file(path) {
+            Ok(()) => {
+                kept -= size;
+                removed += 1;
+                freed += size;
+            }
+            Err(e) => log::warn!("failed to remove {}: {e}", path.display()),
+        }
+    }
+    remove_empty_dirs(dir)?;
+    Ok((removed, freed))
+}
+
+/// List the files under a directory, with their sizes and modification times.
+/// Symlinks are listed as files rather than followed.
+fn list_files(dir: &Path, out: &mut Vec<(PathBuf, u64, SystemTime)>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(RESERVED_PREFIX)
+        {
+            continue;
+        }
+        let md = entry.path().symlink_metadata()?;
+        if md.is_dir() {
+            list_files(&entry.path(), out)?;
+        } else {
+            out.push((entry.path(), md.len(), md.modified()?));
+        }
+    }
+    Ok(())
+}
+
+/// Remove the empty directories under a directory, but not the directory
+/// itself.
+fn remove_empty_dirs(dir: &Path) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.symlink_metadata()?.is_dir() {
+            remove_empty_dirs(&path)?;
+            if std::fs::read_dir(&path)?.next().is_none() {
+                std::fs::remove_dir(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut total = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let md = entry.metadata()?;
+        if md.is_dir() {
+            total += dir_size(&entry.path())?;
+        } else {
+            total += md.len();
+        }
+    }
+    Ok(total)
+}
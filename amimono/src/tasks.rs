@@ -0,0 +1,150 @@
+//! Background tasks owned by a component.
+//!
+//! Tasks started with `tokio::spawn` outlive their component, aren't told
+//! when the job shuts down, and die silently when they panic. Tasks started
+//! through the component's [`TaskSet`] instead run as part of the component:
+//! [`component::current`][crate::component::current] is set for them, they
+//! are aborted when the component is restarted or the job shuts down, they
+//! can be restarted after panicking according to a [`RestartPolicy`], and
+//! their panics are reported through [`health`][crate::health].
+//!
+//! ```ignore
+//! let tasks = amimono::tasks::TaskSet::current();
+//! tasks.spawn_restarting("compactor", RestartPolicy::always(), || compact_forever());
+//! ```
+
+use std::{
+    collections::HashMap,
+    panic::AssertUnwindSafe,
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use futures::FutureExt;
+use tokio::task::AbortHandle;
+
+use crate::{component, config::RestartPolicy, health, metrics, watchdog};
+
+#[derive(Default)]
+struct Tasks {
+    next: AtomicU64,
+    running: Mutex<HashMap<u64, (String, AbortHandle)>>,
+}
+
+static SETS: LazyLock<Mutex<HashMap<&'static str, Arc<Tasks>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The background tasks of a component.
+#[derive(Clone)]
+pub struct TaskSet {
+    label: &'static str,
+    tasks: Arc<Tasks>,
+}
+
+impl TaskSet {
+    /// The task set of the current component. This must be called from
+    /// within the component, i.e. from its `main`, an RPC handler, or one of
+    /// its tasks, and panics otherwise.
+    pub fn current() -> TaskSet {
+        match component::current() {
+            Some(label) => TaskSet::of(label),
+            None => panic!("TaskSet::current() called outside of a component"),
+        }
+    }
+
+    pub(crate) fn of(label: &'static str) -> TaskSet {
+        let mut sets = SETS.lock().expect("lock poisoned");
+        let tasks = sets.entry(label).or_default().clone();
+        TaskSet { label, tasks }
+    }
+
+    /// Start a task that runs once. If it panics, the component is reported
+    /// degraded.
+    pub fn spawn<F>(&self, name: &str, fut: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut fut = Some(fut);
+        self.spawn_restarting(name, RestartPolicy::never(), move || {
+            fut.take().expect("task restarted without a restart policy")
+        });
+    }
+
+    /// Start a task that's started again by calling `f` whenever it panics,
+    /// according to the restart policy. The component is reported degraded
+    /// while a task waits to be restarted, and once a task has panicked too
+    /// many times. Tasks that return aren't restarted.
+    pub fn spawn_restarting<F, Fut>(&self, name: &str, policy: RestartPolicy, mut f: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let label = self.label;
+        let id = self.tasks.next.fetch_add(1, Ordering::Relaxed);
+        let tasks = self.tasks.clone();
+        let task_name = name.to_owned();
+        let run = async move {
+            let mut restarts = 0;
+            loop {
+                if AssertUnwindSafe(f()).catch_unwind().await.is_ok() {
+                    break;
+                }
+                let labels = [("component", label), ("task", task_name.as_str())];
+                metrics::counter("amimono_task_panics", &labels).inc();
+
+                if policy.max_restarts.is_some_and(|n| restarts >= n) {
+                    log::error!("{label}: task {task_name} panicked after {restarts} restarts");
+                    let reason = format!("task {task_name} failed");
+                    health::set_for(label, health::Status::degraded(reason));
+                    break;
+                }
+
+                let delay = policy.delay(restarts);
+                restarts += 1;
+                log::error!(
+                    "{label}: task {task_name} panicked, restarting in {delay:?} (restart {restarts})"
+                );
+                let restarting = health::Status::degraded(format!("task {task_name} restarting"));
+                health::set_for(label, restarting.clone());
+                tokio::time::sleep(delay).await;
+                if health::get(label) == Some(restarting) {
+                    health::set_for(label, health::Status::Healthy);
+                }
+            }
+            tasks.running.lock().expect("lock poisoned").remove(&id);
+        };
+
+        // the task is only tracked until it finishes, so it's added before
+        // it can run
+        let mut running = self.tasks.running.lock().expect("lock poisoned");
+        let join = watchdog::spawn_for(label, run);
+        running.insert(id, (name.to_owned(), join.abort_handle()));
+    }
+
+    /// The names of the tasks that are still running.
+    pub fn running(&self) -> Vec<String> {
+        let running = self.tasks.running.lock().expect("lock poisoned");
+        running.values().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Abort every task in the set.
+    pub fn abort_all(&self) {
+        let running = std::mem::take(&mut *self.tasks.running.lock().expect("lock poisoned"));
+        if !running.is_empty() {
+            log::debug!("{}: aborting {} tasks", self.label, running.len());
+        }
+        for (_, (_, handle)) in running {
+            handle.abort();
+        }
+    }
+}
+
+/// Abort the tasks of every component, when the job shuts down.
+pub(crate) fn abort_everything() {
+    let sets = SETS.lock().expect("lock poisoned").clone();
+    for (label, tasks) in sets {
+        TaskSet { label, tasks }.abort_all();
+    }
+}
@@ -29,6 +29,15 @@ impl<K: Hash + Eq, V: ?Sized> StaticHashMap<K, V> {
     pub fn insert(&self, k: K, v: Arc<V>) -> Option<Arc<V>> {
         self.inner.lock().expect("lock poisoned").insert(k, v)
     }
+
+    pub fn values(&self) -> Vec<Arc<V>> {
+        self.inner
+            .lock()
+            .expect("lock poisoned")
+            .values()
+            .cloned()
+            .collect()
+    }
 }
 
 impl<K: Hash + Eq, V: Default> StaticHashMap<K, V> {
@@ -0,0 +1,203 @@
+//! A watchdog for the memory and tasks used by a job's components.
+//!
+//! Components of a job share one process, so a component that leaks memory
+//! or tasks slows down or takes out every other component in the job. The
+//! runtime periodically samples the job's resident memory and the number of
+//! tasks each component has running, reports them as metrics and on
+//! `/admin/resources`, and logs a warning when the job goes over its
+//! [memory budget][crate::config::JobBuilder::with_memory_budget] or a
+//! component goes over its
+//! [task budget][crate::config::AppBuilder::with_task_budget].
+//!
+//! Tasks are attributed to the component that spawned them when they're
+//! started through its [`TaskSet`][crate::tasks::TaskSet], by its actors, or
+//! with [`spawn`] instead of `tokio::spawn`. Memory can't be attributed to
+//! components, and is only measured for the whole process, on Linux.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+use crate::{component, config::ComponentConfig, metrics, runtime, util::StaticHashMap};
+
+const INTERVAL: Duration = Duration::from_secs(15);
+
+/// The fraction of the memory budget at which a warning is logged.
+const WARN_THRESHOLD: f64 = 0.8;
+
+static TASKS: StaticHashMap<&'static str, AtomicUsize> = StaticHashMap::new();
+
+/// Counts a task against its component for as long as it's alive.
+struct TaskGuard(&'static str);
+
+impl TaskGuard {
+    fn new(label: &'static str) -> TaskGuard {
+        TASKS.get_or_insert(label).fetch_add(1, Ordering::Relaxed);
+        TaskGuard(label)
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        TASKS.get_or_insert(self.0).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Spawn a task on behalf of a component, with
+/// [`component::current`][crate::component::current] set for it, counting
+/// it against the component's tasks until it finishes or is aborted.
+pub(crate) fn spawn_for<F>(label: &'static str, fut: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let guard = TaskGuard::new(label);
+    tokio::spawn(component::scope(label, async move {
+        let _guard = guard;
+        fut.await
+    }))
+}
+
+/// Spawn a task like `tokio::spawn`, but on behalf of the current component,
+/// so that the watchdog counts it against the component's task budget.
+/// Tasks spawned outside of a component aren't counted.
+pub fn spawn<F>(fut: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match component::current() {
+        Some(label) => spawn_for(label, fut),
+        None => tokio::spawn(fut),
+    }
+}
+
+#[derive(Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ResourceUsage {
+    /// The process's resident memory, in bytes, if it could be measured.
+    rss: Option<u64>,
+    memory_budget: Option<u64>,
+    /// Every task alive in the process, including ones not attributed to a
+    /// component.
+    tasks: usize,
+    components: BTreeMap<String, ComponentTasks>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ComponentTasks {
+    tasks: usize,
+    budget: Option<usize>,
+}
+
+static USAGE: LazyLock<Mutex<ResourceUsage>> =
+    LazyLock::new(|| Mutex::new(ResourceUsage::default()));
+
+/// The most recently sampled usage of the job.
+pub(crate) fn usage() -> ResourceUsage {
+    USAGE.lock().expect("lock poisoned").clone()
+}
+
+/// Start sampling the usage of the job and the components in the list.
+pub(crate) fn start(service: &str, comps: &[&ComponentConfig]) {
+    let labels = comps
+        .iter()
+        .map(|c| {
+            runtime::config()
+                .component(&c.label)
+                .map(|c| c.label.as_str())
+                .unwrap_or_else(|| panic!("component {} not in config", c.label))
+        })
+        .collect::<Vec<&'static str>>();
+    let memory_budget = runtime::config()
+        .job(service)
+        .and_then(|j| j.memory_budget())
+        .filter(|_| !runtime::provider().is_dev());
+    let service = service.to_owned();
+    tokio::spawn(async move {
+        loop {
+            sample(&service, &labels, memory_budget);
+            tokio::time::sleep(INTERVAL).await;
+        }
+    });
+}
+
+fn sample(service: &str, labels: &[&'static str], memory_budget: Option<u64>) {
+    let rss = rss();
+    let tasks = tokio::runtime::Handle::current()
+        .metrics()
+        .num_alive_tasks();
+    if let Some(rss) = rss {
+        metrics::gauge("amimono_job_rss_bytes", &[("job", service)]).set(rss as f64);
+    }
+    if let Some(budget) = memory_budget {
+        metrics::gauge("amimono_job_memory_budget_bytes", &[("job", service)]).set(budget as f64);
+    }
+    metrics::gauge("amimono_job_tasks", &[("job", service)]).set(tasks as f64);
+
+    let components = labels
+        .iter()
+        .map(|&label| {
+            let tasks = TASKS.get_or_insert(label).load(Ordering::Relaxed);
+            metrics::gauge("amimono_component_tasks", &[("component", label)]).set(tasks as f64);
+            let budget = runtime::config().task_budget(label);
+            (label.to_owned(), ComponentTasks { tasks, budget })
+        })
+        .collect::<BTreeMap<_, _>>();
+    let usage = ResourceUsage {
+        rss,
+        memory_budget,
+        tasks,
+        components,
+    };
+    let prev = std::mem::replace(&mut *USAGE.lock().expect("lock poisoned"), usage.clone());
+
+    if let (Some(rss), Some(budget)) = (usage.rss, usage.memory_budget) {
+        let f = rss as f64 / budget as f64;
+        let prev_f = prev.rss.map(|p| p as f64 / budget as f64).unwrap_or(0.0);
+        if f > 1.0 && prev_f <= 1.0 {
+            log::warn!("{service} exceeded its memory budget: {rss} of {budget} bytes");
+        } else if f > WARN_THRESHOLD && prev_f <= WARN_THRESHOLD {
+            log::warn!("{service} is using {:.0}% of its memory budget", f * 100.0);
+        } else if f <= 1.0 && prev_f > 1.0 {
+            log::info!("{service} is back within its memory budget");
+        }
+    }
+    for (label, comp) in usage.components.iter() {
+        let Some(budget) = comp.budget else {
+            continue;
+        };
+        let was_over = prev.components.get(label).is_some_and(|p| p.tasks > budget);
+        if comp.tasks > budget && !was_over {
+            log::warn!(
+                "{label} exceeded its task budget: {} tasks running, budget {budget}",
+                comp.tasks
+            );
+        } else if comp.tasks <= budget && was_over {
+            log::info!("{label} is back within its task budget");
+        }
+    }
+}
+
+/// The resident memory of this process, in bytes, where it can be measured.
+#[cfg(target_os = "linux")]
+fn rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rss() -> Option<u64> {
+    None
+}
@@ -1,5 +1,6 @@
 fn main() {
-    let rev = amimono_build::AppDigest::new()
+    let mut digest = amimono_build::AppDigest::new();
+    let rev = digest
         .add_glob("../amimono/src/**/*.rs")
         .add_path("../amimono/Cargo.toml")
         .add_glob("../amimono-build/src/**/*.rs")
@@ -8,5 +9,7 @@ fn main() {
         .add_path("Cargo.toml")
         .compute();
 
-    println!("cargo:rustc-env=APP_REVISION={}", rev);
+    amimono_build::BuildInfo::new(&rev).emit();
+    amimono_build::EmbeddedConfig::new(&rev, "../.amimono/config.json").emit();
+    digest.rerun_if_changed();
 }
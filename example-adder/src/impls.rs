@@ -139,15 +139,19 @@ pub mod driver {
         {
             set_instance(()).await;
 
-            match Self::storage().await {
-                Ok(path) => {
-                    log::info!("storage path: {:?}", path);
-                    if let Err(e) = std::fs::write(path.join("hello.txt"), "hello") {
-                        log::warn!("failed to write to storage: {:?}", e);
+            if !amimono::runtime::capabilities().storage {
+                log::info!("no storage in this runtime, not writing to it");
+            } else {
+                match Self::storage().await {
+                    Ok(path) => {
+                        log::info!("storage path: {:?}", path);
+                        if let Err(e) = std::fs::write(path.join("hello.txt"), "hello") {
+                            log::warn!("failed to write to storage: {:?}", e);
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("failed to get storage path: {:?}", e);
                     }
-                }
-                Err(e) => {
-                    log::warn!("failed to get storage path: {:?}", e);
                 }
             }
 
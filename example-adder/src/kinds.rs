@@ -1,5 +1,5 @@
-pub mod calc {
-    amimono::rpc_component! {
+amimono::rpc_component! {
+    pub mod calc {
         //! A generic calculator service.
 
         const LABEL: &'static str = "calc";
@@ -12,8 +12,8 @@ pub mod calc {
     }
 }
 
-pub mod adder {
-    amimono::rpc_component! {
+amimono::rpc_component! {
+    pub mod adder {
         const LABEL: &'static str = "adder";
 
         /// Adds two numbers via the calc service
@@ -21,8 +21,8 @@ pub mod adder {
     }
 }
 
-pub mod doubler {
-    amimono::rpc_component! {
+amimono::rpc_component! {
+    pub mod doubler {
         const LABEL: &'static str = "doubler";
 
         /// Doubles two numbers via the calc service
@@ -4,10 +4,14 @@ mod kinds;
 use amimono::{
     component::Component,
     config::{AppBuilder, AppConfig, JobBuilder},
+    runtime::BuildInfo,
 };
 
+const BUILD_INFO: BuildInfo = amimono::include_build_info!();
+
 pub fn configure() -> AppConfig {
-    AppBuilder::new(env!("APP_REVISION"))
+    AppBuilder::from_build_info(BUILD_INFO)
+        .with_embedded_config(amimono::include_config!())
         .add_job(
             JobBuilder::new()
                 .with_label("calc")
@@ -24,6 +28,8 @@ pub fn configure() -> AppConfig {
 }
 
 fn main() {
-    env_logger::init();
+    let logger = env_logger::Builder::from_default_env().build();
+    let level = logger.filter();
+    amimono::context::init_logger(logger, level);
     amimono::entry(configure());
 }